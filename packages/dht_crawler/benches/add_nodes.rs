@@ -0,0 +1,79 @@
+use criterion::{
+    criterion_group,
+    criterion_main,
+    Criterion,
+};
+use dht_crawler::routing::{
+    Node,
+    RoutingTable,
+};
+use krpc_encoding::NodeID;
+use std::net::SocketAddrV4;
+
+/// Nodes in a `find_node` response.
+const RESPONSE_NODES: usize = 8;
+
+/// Nodes seen while bootstrapping, as ids and addresses so fresh nodes can be
+/// made for every iteration.
+fn bootstrap_trace() -> Vec<(NodeID, SocketAddrV4)> {
+    (0..10_000u32)
+        .map(|idx| (NodeID::random(), SocketAddrV4::new((idx << 8).into(), 6881)))
+        .collect()
+}
+
+fn responses(trace: &[(NodeID, SocketAddrV4)]) -> Vec<Vec<Node>> {
+    trace
+        .chunks(RESPONSE_NODES)
+        .map(|response| {
+            response
+                .iter()
+                .map(|(id, address)| {
+                    let node = Node::new(id.clone(), *address);
+                    node.mark_successful_request();
+                    node
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn add_node(c: &mut Criterion) {
+    let trace = bootstrap_trace();
+    let own_id = NodeID::random();
+
+    c.bench_function("bootstrap add_node", move |b| {
+        b.iter_with_setup(
+            || responses(&trace),
+            |responses| {
+                let mut table = RoutingTable::new(own_id.clone());
+                for node in responses.into_iter().flatten() {
+                    table.add_node(node);
+                }
+
+                table.len()
+            },
+        )
+    });
+}
+
+fn add_nodes(c: &mut Criterion) {
+    let trace = bootstrap_trace();
+    let own_id = NodeID::random();
+
+    c.bench_function("bootstrap add_nodes", move |b| {
+        b.iter_with_setup(
+            || responses(&trace),
+            |responses| {
+                let mut table = RoutingTable::new(own_id.clone());
+                for response in responses {
+                    table.add_nodes(response);
+                }
+
+                table.len()
+            },
+        )
+    });
+}
+
+criterion_group!(benches, add_node, add_nodes);
+criterion_main!(benches);
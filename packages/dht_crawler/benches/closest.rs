@@ -0,0 +1,63 @@
+use criterion::{
+    criterion_group,
+    criterion_main,
+    Criterion,
+};
+use dht_crawler::routing::{
+    Node,
+    RoutingTable,
+};
+use krpc_encoding::NodeID;
+use std::net::SocketAddrV4;
+
+/// Number of closest nodes taken from the table.
+const K: usize = 8;
+
+/// Builds a table from 10,000 nodes at random ids. Only the nodes which fit in
+/// the table's buckets are kept.
+fn table() -> RoutingTable {
+    let mut table = RoutingTable::new(NodeID::random());
+    for idx in 0..10_000u32 {
+        let node = Node::new(NodeID::random(), SocketAddrV4::new((idx << 8).into(), 6881));
+        node.mark_successful_request();
+        table.add_node(node);
+    }
+
+    table
+}
+
+fn collect_and_sort(c: &mut Criterion) {
+    let table = table();
+    let target = NodeID::random();
+
+    c.bench_function("closest collect and sort", move |b| {
+        b.iter(|| {
+            let mut nodes: Vec<&Node> = table.nodes().collect();
+            nodes.sort_by_key(|node| node.id.distance(&target));
+            nodes.truncate(K);
+
+            nodes.len()
+        })
+    });
+}
+
+fn iter_closest(c: &mut Criterion) {
+    let table = table();
+    let target = NodeID::random();
+
+    c.bench_function("closest iter_closest", move |b| {
+        b.iter(|| table.iter_closest(&target).take(K).count())
+    });
+}
+
+fn iter_closest_exact(c: &mut Criterion) {
+    let table = table();
+    let target = NodeID::random();
+
+    c.bench_function("closest iter_closest_exact", move |b| {
+        b.iter(|| table.iter_closest_exact(&target).take(K).count())
+    });
+}
+
+criterion_group!(benches, collect_and_sort, iter_closest, iter_closest_exact);
+criterion_main!(benches);
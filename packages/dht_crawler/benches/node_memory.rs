@@ -0,0 +1,67 @@
+use criterion::{
+    criterion_group,
+    criterion_main,
+    Benchmark,
+    Criterion,
+    Throughput,
+};
+use dht_crawler::routing::Node;
+use krpc_encoding::NodeID;
+use std::{
+    cmp,
+    fs,
+    mem,
+    net::SocketAddrV4,
+};
+
+/// Nodes kept by a deep crawl.
+const CRAWL_NODES: u32 = 500_000;
+
+fn synthetic_nodes() -> Vec<Node> {
+    (0..CRAWL_NODES)
+        .map(|idx| {
+            let node = Node::new(NodeID::random(), SocketAddrV4::new(idx.into(), 6881));
+            node.mark_successful_request();
+            node
+        })
+        .collect()
+}
+
+/// Resident set size of the process in bytes, on Linux.
+fn resident_bytes() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+
+    Some(pages * 4096)
+}
+
+/// Loads the nodes once to measure the resident set size before and after.
+/// The sizes are part of the benchmark's id, and criterion reports the bytes
+/// the nodes took as the throughput of a load.
+fn load_nodes(c: &mut Criterion) {
+    let before = resident_bytes().unwrap_or(0);
+    let nodes = synthetic_nodes();
+    let after = resident_bytes().unwrap_or(0);
+    drop(nodes);
+
+    let id = format!(
+        "{} bytes inline, {} MiB resident before, {} MiB after",
+        mem::size_of::<Node>(),
+        before >> 20,
+        after >> 20,
+    );
+    let resident = cmp::min(after.saturating_sub(before), u64::from(u32::max_value()));
+
+    c.bench(
+        "load 500k nodes",
+        Benchmark::new(id, |b| b.iter(synthetic_nodes))
+            .throughput(Throughput::Bytes(resident as u32)),
+    );
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = load_nodes
+}
+criterion_main!(benches);
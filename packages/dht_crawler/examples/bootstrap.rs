@@ -0,0 +1,92 @@
+//! Bootstraps a node bound to the given address, or any address by default,
+//! from the given routers, host names or socket addresses, or the
+//! well-known routers by default. Shows how far it got on a single line.
+//!
+//! ```sh
+//! cargo run --example bootstrap -- 0.0.0.0:6881 router.bittorrent.com:6881 10.0.0.1:6881
+//! ```
+
+use dht_crawler::{
+    bootstrap::{
+        well_known,
+        Router,
+    },
+    dht::BootstrapEvent,
+    Dht,
+};
+use failure::Error;
+use futures::{
+    future,
+    StreamExt,
+};
+use std::{
+    env,
+    io::{
+        self,
+        Write,
+    },
+    net::SocketAddr,
+};
+use tokio::runtime::current_thread::Runtime;
+
+fn main() -> Result<(), Error> {
+    let bind: SocketAddr = match env::args().nth(1) {
+        Some(bind) => bind.parse()?,
+        None => "0.0.0.0:0".parse()?,
+    };
+
+    let mut routers: Vec<String> = env::args().skip(2).collect();
+    if routers.is_empty() {
+        routers = well_known::ALL
+            .iter()
+            .map(|(host, port)| format!("{}:{}", host, port))
+            .collect();
+    }
+    for router in &routers {
+        router.parse::<Router>()?;
+    }
+    let routers: Vec<&str> = routers.iter().map(String::as_str).collect();
+
+    let (dht, dht_future) = Dht::start(bind)?;
+    let mut runtime = Runtime::new()?;
+    runtime.spawn(dht_future);
+
+    let mut routers_reached = 0;
+    let mut depth = 1;
+    let events = dht.bootstrap_with_progress(&routers).for_each(|event| {
+        let line = match event {
+            BootstrapEvent::RouterResolved { router, address } => {
+                routers_reached += 1;
+                format!("resolved {} to {}", router, address)
+            }
+            BootstrapEvent::RouterUnreachable { router } => {
+                format!("{} is unreachable", router)
+            }
+            BootstrapEvent::NodesDiscovered { total, .. } => {
+                format!("{} nodes, {} buckets", total, depth)
+            }
+            BootstrapEvent::BucketSplit { depth: new_depth } => {
+                depth = new_depth;
+                format!("{} buckets", depth)
+            }
+            BootstrapEvent::Completed { nodes, duration } => format!(
+                "bootstrapped with {} nodes in {}.{:03}s\n",
+                nodes,
+                duration.as_secs(),
+                duration.subsec_millis()
+            ),
+            BootstrapEvent::Failed(failure) => format!(
+                "bootstrap failed, {} routers resolved: {:?}\n",
+                routers_reached, failure
+            ),
+        };
+
+        print!("\r\x1b[K{}", line);
+        let _ = io::stdout().flush();
+
+        future::ready(())
+    });
+    runtime.block_on(events);
+
+    Ok(())
+}
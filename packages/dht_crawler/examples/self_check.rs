@@ -0,0 +1,43 @@
+//! Checks whether a node bound to the given address, or any address by
+//! default, can reach the DHT through the well-known routers.
+//!
+//! ```sh
+//! cargo run --example self_check -- 0.0.0.0:6881
+//! ```
+
+use dht_crawler::{
+    Bootstrap,
+    Dht,
+};
+use failure::Error;
+use std::{
+    env,
+    net::SocketAddr,
+};
+use tokio::runtime::current_thread::Runtime;
+
+fn main() -> Result<(), Error> {
+    let bind: SocketAddr = match env::args().nth(1) {
+        Some(bind) => bind.parse()?,
+        None => "0.0.0.0:0".parse()?,
+    };
+
+    let (dht, dht_future) = Dht::start(bind)?;
+    let mut runtime = Runtime::new()?;
+    runtime.spawn(dht_future);
+
+    let bootstrap = runtime.block_on(Bootstrap::from_well_known());
+    let report = runtime.block_on(dht.self_check(&bootstrap));
+    print!("{}", report.summary());
+
+    if report.responded == 0 {
+        eprintln!();
+        eprintln!("WARNING: NO NODE RESPONDED. The DHT can't be reached from this socket,");
+        eprintln!(
+            "check that inbound UDP to {} isn't blocked by a firewall.",
+            bind
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,72 @@
+//! Runs a DHT of a few hundred simulated nodes, some of them misbehaving,
+//! and looks up a peer announced by one of them.
+//!
+//! ```sh
+//! cargo run --example simulated_network --features testing
+//! ```
+
+use dht_crawler::{
+    testing::{
+        Behavior,
+        Latency,
+        NetworkConfig,
+        SimulatedNetwork,
+    },
+    Dht,
+};
+use failure::Error;
+use krpc_encoding::NodeID;
+use std::time::{
+    Duration,
+    Instant,
+};
+use tokio::runtime::current_thread::Runtime;
+use tokio_krpc::PortType;
+
+const NODES: usize = 300;
+
+fn main() -> Result<(), Error> {
+    let network = SimulatedNetwork::new(NetworkConfig {
+        packet_loss: 0.02,
+        latency: Latency::Uniform {
+            min: Duration::from_millis(5),
+            max: Duration::from_millis(40),
+        },
+        query_timeout: Duration::from_millis(300),
+        seed: 42,
+    });
+
+    // One in ten nodes misbehaves.
+    let nodes: Vec<Dht> = (0..NODES)
+        .map(|idx| match idx % 30 {
+            7 => network.add_node_with_behavior(Behavior::Silent),
+            17 => network.add_node_with_behavior(Behavior::WrongResponses),
+            27 => network.add_node_with_behavior(Behavior::Malformed),
+            _ => network.add_node(),
+        })
+        .collect();
+    let addresses = network.addresses();
+
+    let mut runtime = Runtime::new()?;
+    let started = Instant::now();
+    for (idx, dht) in nodes.iter().enumerate().skip(1) {
+        let seeds = &addresses[idx.saturating_sub(3)..idx];
+        if let Err(err) = runtime.block_on(dht.bootstrap_from(seeds)) {
+            eprintln!("Node {} Failed To Bootstrap: {}", idx, err);
+        }
+    }
+    println!("Bootstrapped {} nodes in {:?}", NODES, started.elapsed());
+
+    let estimate = runtime.block_on(nodes[0].estimate_network_size(8))?;
+    println!(
+        "Estimated {:.0} nodes (95% interval {:.0} to {:.0})",
+        estimate.estimate, estimate.interval.0, estimate.interval.1
+    );
+
+    let info_hash = NodeID::random();
+    runtime.block_on(nodes[1].announce(info_hash.clone(), PortType::Port(51413)))?;
+    let peers = runtime.block_on(nodes[NODES - 1].get_peers(info_hash))?;
+    println!("Found peers {:?}, announced from {}", peers, addresses[1]);
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+//! Converts the info-hashes found by a crawl between the JSON lines written
+//! by a `JsonLinesSink` and snapshots. Converting to a snapshot appends to
+//! it.
+//!
+//! ```sh
+//! cargo run --example snapshot_convert -- to-snapshot crawl.jsonl crawl.snapshot
+//! cargo run --example snapshot_convert -- to-jsonl crawl.snapshot crawl.jsonl
+//! ```
+
+use dht_crawler::crawler::{
+    jsonl_to_snapshot,
+    snapshot_to_jsonl,
+    SnapshotReader,
+    SnapshotWriter,
+};
+use failure::{
+    err_msg,
+    Error,
+};
+use std::{
+    env,
+    fs::File,
+    io::{
+        BufReader,
+        BufWriter,
+    },
+};
+
+fn main() -> Result<(), Error> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let events = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["to-snapshot", input, output] => {
+            let mut writer = SnapshotWriter::open(output)?;
+            let events = jsonl_to_snapshot(BufReader::new(File::open(input)?), &mut writer)?;
+            writer.sync()?;
+
+            events
+        }
+        ["to-jsonl", input, output] => snapshot_to_jsonl(
+            SnapshotReader::open(input)?,
+            BufWriter::new(File::create(output)?),
+        )?,
+        _ => {
+            return Err(err_msg(
+                "usage: snapshot_convert to-snapshot|to-jsonl <input> <output>",
+            ))
+        }
+    };
+
+    println!("converted {} events", events);
+
+    Ok(())
+}
@@ -0,0 +1,354 @@
+//! Addresses to bootstrap a node from when it knows no other nodes yet.
+
+use crate::errors::{
+    Error,
+    ErrorKind,
+};
+use futures::{
+    channel::oneshot,
+    future,
+    FutureExt,
+};
+use std::{
+    future::Future,
+    io,
+    net::{
+        SocketAddr,
+        SocketAddrV4,
+        ToSocketAddrs,
+    },
+    pin::Pin,
+    str::FromStr,
+    thread,
+    time::Duration,
+};
+use tokio::prelude::FutureExt as TokioFutureExt;
+
+/// Routers run by BitTorrent clients to bootstrap their users' nodes, as
+/// host and port to be resolved with [`resolve_routers`].
+///
+/// [`resolve_routers`]: super::resolve_routers
+pub mod well_known {
+    pub const BITTORRENT: (&str, u16) = ("router.bittorrent.com", 6881);
+    pub const UTORRENT: (&str, u16) = ("router.utorrent.com", 6881);
+    pub const TRANSMISSION: (&str, u16) = ("dht.transmissionbt.com", 6881);
+    pub const AELITIS: (&str, u16) = ("dht.aelitis.com", 6881);
+
+    pub const ALL: [(&str, u16); 4] = [BITTORRENT, UTORRENT, TRANSMISSION, AELITIS];
+}
+
+/// Time to wait for a router's name to resolve before leaving it out.
+pub const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Future returned by [`Resolver::resolve`]. Boxed because traits can't
+/// have async methods yet.
+pub type ResolveFuture = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send>>;
+
+/// Resolves host names to addresses.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture;
+}
+
+/// Resolves with the resolver of the system. It blocks, so each name is
+/// resolved on a thread of its own instead of the executor's.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture {
+        let (sender, receiver) = oneshot::channel();
+        let host = host.to_string();
+        let spawned = thread::Builder::new()
+            .name("resolve".to_string())
+            .spawn(move || {
+                let result: io::Result<Vec<SocketAddr>> = (host.as_str(), port)
+                    .to_socket_addrs()
+                    .map(|addresses| addresses.collect());
+                let _ = sender.send(result);
+            });
+
+        if let Err(err) = spawned {
+            let result: io::Result<Vec<SocketAddr>> = Err(err);
+            return Box::pin(future::ready(result));
+        }
+
+        Box::pin(receiver.map(|result| {
+            result.unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "resolver gone")))
+        }))
+    }
+}
+
+/// Resolves `names` with the [`SystemResolver`]. See
+/// [`resolve_routers_with`].
+pub async fn resolve_routers<'a>(names: &'a [(&'a str, u16)]) -> Vec<SocketAddr> {
+    resolve_routers_with(&SystemResolver, names, RESOLVE_TIMEOUT).await
+}
+
+/// Resolves every name of `names` at once to its IPv4 and IPv6 addresses,
+/// without duplicates. Names which fail to resolve or take longer than
+/// `timeout` are left out.
+pub async fn resolve_routers_with<'a>(
+    resolver: &'a dyn Resolver,
+    names: &'a [(&'a str, u16)],
+    timeout: Duration,
+) -> Vec<SocketAddr> {
+    let results = future::join_all(
+        names
+            .iter()
+            .map(|(host, port)| resolver.resolve(host, *port).timeout(timeout)),
+    )
+    .await;
+
+    let mut addresses = Vec::new();
+    for result in results {
+        if let Ok(Ok(resolved)) = result {
+            for address in resolved {
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+            }
+        }
+    }
+
+    addresses
+}
+
+/// A router given either as a socket address or as a host name to resolve.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Router {
+    Address(SocketAddr),
+    Host(String, u16),
+}
+
+impl FromStr for Router {
+    type Err = Error;
+
+    /// Parses `ip:port`, `[ipv6]:port` or `host:port`.
+    fn from_str(router: &str) -> Result<Router, Error> {
+        if let Ok(address) = router.parse() {
+            return Ok(Router::Address(address));
+        }
+
+        let invalid = || ErrorKind::InvalidRouter {
+            router: router.to_string(),
+        };
+        let colon = router.rfind(':').ok_or_else(invalid)?;
+        let (host, port) = (&router[..colon], &router[colon + 1..]);
+        let port: u16 = port.parse().map_err(|_| invalid())?;
+        if host.is_empty() || host.contains(':') || host.contains(char::is_whitespace) {
+            Err(invalid())?;
+        }
+
+        Ok(Router::Host(host.to_string(), port))
+    }
+}
+
+/// Resolves the host names of `routers` with `resolver` like
+/// [`resolve_routers_with`]. Socket addresses are kept as they are.
+pub async fn resolve_mixed<'a>(
+    resolver: &'a dyn Resolver,
+    routers: &'a [Router],
+    timeout: Duration,
+) -> Vec<SocketAddr> {
+    let names: Vec<(&str, u16)> = routers
+        .iter()
+        .filter_map(|router| match router {
+            Router::Host(host, port) => Some((host.as_str(), *port)),
+            Router::Address(_) => None,
+        })
+        .collect();
+
+    let mut addresses: Vec<SocketAddr> = routers
+        .iter()
+        .filter_map(|router| match router {
+            Router::Address(address) => Some(*address),
+            Router::Host(..) => None,
+        })
+        .collect();
+    for address in resolve_routers_with(resolver, &names, timeout).await {
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+
+    addresses
+}
+
+/// Seed nodes passed to [`Dht::bootstrap`].
+///
+/// [`Dht::bootstrap`]: crate::Dht::bootstrap
+#[derive(Clone, Debug, Default)]
+pub struct Bootstrap {
+    seeds: Vec<SocketAddr>,
+}
+
+impl Bootstrap {
+    pub fn new(seeds: Vec<SocketAddr>) -> Bootstrap {
+        Bootstrap { seeds }
+    }
+
+    /// Resolves the [`well_known`] routers with [`resolve_routers`]. Routers
+    /// which don't resolve are left out, so the result is empty without a
+    /// network.
+    pub async fn well_known_seeds() -> Vec<SocketAddr> {
+        resolve_routers(&well_known::ALL).await
+    }
+
+    /// Bootstraps from the [`well_known`] routers, resolved now.
+    pub async fn from_well_known() -> Bootstrap {
+        Bootstrap::new(Bootstrap::well_known_seeds().await)
+    }
+
+    /// Bootstraps from `routers`, their host names resolved with the
+    /// [`SystemResolver`] without blocking.
+    pub async fn from_routers(routers: &[Router]) -> Bootstrap {
+        Bootstrap::new(resolve_mixed(&SystemResolver, routers, RESOLVE_TIMEOUT).await)
+    }
+
+    pub fn seeds(&self) -> &[SocketAddr] {
+        &self.seeds
+    }
+
+    /// Seeds with an IPv4 address, the only ones a [`Dht`] can query.
+    ///
+    /// [`Dht`]: crate::Dht
+    pub fn v4_seeds(&self) -> Vec<SocketAddrV4> {
+        self.seeds
+            .iter()
+            .filter_map(|seed| match seed {
+                SocketAddr::V4(seed) => Some(*seed),
+                SocketAddr::V6(_) => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        resolve_mixed,
+        Bootstrap,
+        ResolveFuture,
+        Resolver,
+        Router,
+        RESOLVE_TIMEOUT,
+    };
+    use futures::future;
+    use std::{
+        io,
+        net::SocketAddr,
+        time::Duration,
+    };
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Resolves `good.example` to an IPv4 and an IPv6 address, never
+    /// answers for `slow.example` and fails every other name.
+    struct MockResolver;
+
+    impl Resolver for MockResolver {
+        fn resolve(&self, host: &str, port: u16) -> ResolveFuture {
+            let result = match host {
+                "good.example" => Ok(vec![
+                    SocketAddr::new([10, 0, 0, 1].into(), port),
+                    SocketAddr::new("2001:db8::1".parse().unwrap(), port),
+                ]),
+                "slow.example" => {
+                    return Box::pin(future::pending::<io::Result<Vec<SocketAddr>>>());
+                }
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "no such host")),
+            };
+
+            Box::pin(future::ready(result))
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn well_known_seeds() {
+        let seeds = Runtime::new()
+            .unwrap()
+            .block_on(Bootstrap::well_known_seeds());
+
+        assert!(!seeds.is_empty());
+        assert!(seeds
+            .iter()
+            .all(|seed| seed.port() == 6881 && !seed.ip().is_unspecified()));
+    }
+
+    #[test]
+    fn v4_seeds() {
+        let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let bootstrap = Bootstrap::new(vec![v6, v4]);
+
+        assert_eq!(bootstrap.seeds(), &[v6, v4]);
+        assert_eq!(bootstrap.v4_seeds(), vec!["10.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn routers_parsed() {
+        assert_eq!(
+            "10.0.0.1:6881".parse::<Router>().unwrap(),
+            Router::Address("10.0.0.1:6881".parse().unwrap())
+        );
+        assert_eq!(
+            "[2001:db8::1]:6881".parse::<Router>().unwrap(),
+            Router::Address("[2001:db8::1]:6881".parse().unwrap())
+        );
+        assert_eq!(
+            "router.bittorrent.com:6881".parse::<Router>().unwrap(),
+            Router::Host("router.bittorrent.com".to_string(), 6881)
+        );
+
+        for invalid in &[
+            "router.bittorrent.com",
+            "router.bittorrent.com:port",
+            ":6881",
+            "2001:db8::1:6881:x",
+            "not a router:6881",
+        ] {
+            assert!(invalid.parse::<Router>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn failing_names_left_out() {
+        let routers: Vec<Router> = [
+            "bad.example:6881",
+            "good.example:6881",
+            "10.0.1.1:6881",
+            "slow.example:6881",
+            "good.example:6882",
+        ]
+        .iter()
+        .map(|router| router.parse().unwrap())
+        .collect();
+
+        let addresses = Runtime::new().unwrap().block_on(resolve_mixed(
+            &MockResolver,
+            &routers,
+            Duration::from_millis(50),
+        ));
+
+        let expected: Vec<SocketAddr> = [
+            "10.0.1.1:6881",
+            "10.0.0.1:6881",
+            "[2001:db8::1]:6881",
+            "10.0.0.1:6882",
+            "[2001:db8::1]:6882",
+        ]
+        .iter()
+        .map(|address| address.parse().unwrap())
+        .collect();
+        assert_eq!(addresses, expected);
+        assert!(addresses.iter().any(SocketAddr::is_ipv6));
+
+        // Addresses alone need no resolver.
+        let routers = vec![Router::Address("10.0.1.1:6881".parse().unwrap())];
+        let addresses = Runtime::new().unwrap().block_on(resolve_mixed(
+            &MockResolver,
+            &routers,
+            RESOLVE_TIMEOUT,
+        ));
+        assert_eq!(addresses, vec!["10.0.1.1:6881".parse().unwrap()]);
+    }
+}
@@ -0,0 +1,105 @@
+//! Graph of which nodes returned which other nodes during a crawl, for
+//! studying the topology of the DHT. Enabled with the `graph` feature.
+
+use krpc_encoding::NodeID;
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    fmt::Write,
+};
+
+/// Directed graph with an edge from every node queried to each node it
+/// returned. Nodes are keyed by their hex encoded ids so output is ordered.
+#[derive(Debug, Default, Clone)]
+pub struct CrawlGraph {
+    edges: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl CrawlGraph {
+    pub fn new() -> CrawlGraph {
+        CrawlGraph::default()
+    }
+
+    /// Records that `queried` returned the nodes in `reported`. Edges seen
+    /// before aren't added again.
+    pub fn add_observation(&mut self, queried: NodeID, reported: Vec<NodeID>) {
+        let reported_by = self
+            .edges
+            .entry(queried.to_string())
+            .or_insert_with(BTreeSet::new);
+
+        reported_by.extend(reported.iter().map(NodeID::to_string));
+    }
+
+    /// Number of distinct nodes queried or reported.
+    pub fn node_count(&self) -> usize {
+        self.edges
+            .iter()
+            .flat_map(|(queried, reported)| Some(queried).into_iter().chain(reported))
+            .collect::<BTreeSet<_>>()
+            .len()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.edges.values().map(BTreeSet::len).sum()
+    }
+
+    /// Graphviz representation of the graph. Queried nodes which didn't
+    /// return any nodes are listed on their own.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph crawl {\n");
+
+        for (queried, reported) in &self.edges {
+            if reported.is_empty() {
+                writeln!(dot, "    \"{}\";", queried).unwrap();
+            }
+
+            for node in reported {
+                writeln!(dot, "    \"{}\" -> \"{}\";", queried, node).unwrap();
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CrawlGraph;
+    use krpc_encoding::NodeID;
+    use num_bigint::BigUint;
+
+    fn id(n: u8) -> NodeID {
+        NodeID::new(BigUint::from(n))
+    }
+
+    #[test]
+    fn to_dot() {
+        let mut graph = CrawlGraph::new();
+        graph.add_observation(id(1), vec![id(2), id(3)]);
+        graph.add_observation(id(2), vec![id(3), id(1)]);
+        graph.add_observation(id(3), Vec::new());
+        graph.add_observation(id(1), vec![id(2)]);
+
+        assert_eq!(graph.node_count(), 3);
+        assert_eq!(graph.edge_count(), 4);
+
+        let dot = graph.to_dot();
+        let lines: Vec<&str> = dot.lines().collect();
+        assert_eq!(lines.first(), Some(&"digraph crawl {"));
+        assert_eq!(lines.last(), Some(&"}"));
+        assert!(lines[1..lines.len() - 1]
+            .iter()
+            .all(|line| line.starts_with("    \"") && line.ends_with("\";")));
+
+        let edge = |from: u8, to: u8| format!("    \"{}\" -> \"{}\";", id(from), id(to));
+        for (from, to) in &[(1, 2), (1, 3), (2, 1), (2, 3)] {
+            assert!(lines.contains(&edge(*from, *to).as_str()), "{}", dot);
+        }
+        assert!(lines.contains(&format!("    \"{}\";", id(3)).as_str()));
+        assert_eq!(lines.len(), 7);
+    }
+}
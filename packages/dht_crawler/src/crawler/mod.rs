@@ -0,0 +1,737 @@
+//! Crawls the DHT for info-hashes using the `sample_infohashes` query from
+//! [BEP-0051].
+//!
+//! [BEP-0051]: http://www.bittorrent.org/beps/bep_0051.html
+
+use self::{
+    scheduler::{
+        IntervalTracker,
+        SamplerScheduler,
+    },
+    sink::SinkForwarder,
+    throttle::AdaptiveThrottle,
+};
+use crate::{
+    errors::Result,
+    routing::RoutingTable,
+    shutdown::{
+        Shutdown,
+        TaskGuard,
+    },
+};
+use futures::{
+    future,
+    stream,
+    Stream,
+    StreamExt,
+};
+use krpc_encoding::NodeID;
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+        VecDeque,
+    },
+    net::SocketAddrV4,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::{
+    prelude::FutureExt,
+    timer::Delay,
+};
+use tokio_krpc::Transport;
+
+#[cfg(feature = "graph")]
+mod graph;
+mod scheduler;
+mod sink;
+mod snapshot;
+mod throttle;
+
+#[cfg(feature = "graph")]
+pub use self::graph::CrawlGraph;
+pub use self::{
+    scheduler::{
+        IntervalTracker,
+        SamplerScheduler,
+        DEFAULT_SAMPLE_INTERVAL,
+    },
+    sink::{
+        InfoHashEvent,
+        InfoHashSink,
+        JsonLinesConfig,
+        JsonLinesSink,
+        MemorySink,
+        SinkConfig,
+        StoreFuture,
+    },
+    snapshot::{
+        jsonl_to_snapshot,
+        snapshot_to_jsonl,
+        SnapshotReader,
+        SnapshotSink,
+        SnapshotWriter,
+        FORMAT_VERSION,
+        MAGIC,
+    },
+    throttle::AdaptiveThrottle,
+};
+
+/// Predicate used to leave info-hashes out of the crawl output. Info-hashes
+/// for which the predicate returns `true` are skipped.
+pub type InfoHashFilter = Arc<dyn Fn(&NodeID) -> bool + Send + Sync>;
+
+/// Options for [`Crawler::run`].
+#[derive(Clone, Debug)]
+pub struct CrawlConfig {
+    /// Nodes this many hops away from the routing table are queried, but
+    /// the nodes they return aren't. Nodes in the routing table are zero
+    /// hops away. The whole reachable DHT is crawled when `None`.
+    pub max_hops: Option<usize>,
+
+    /// Time to wait before querying a node again when it didn't send an
+    /// `interval` or didn't respond.
+    pub default_interval: Duration,
+
+    /// Paces queries to stay under this rate, see [`AdaptiveThrottle`].
+    /// Queries are sent as fast as responses come in when `None`.
+    pub max_queries_per_second: Option<f64>,
+}
+
+impl Default for CrawlConfig {
+    fn default() -> CrawlConfig {
+        CrawlConfig {
+            max_hops: None,
+            default_interval: DEFAULT_SAMPLE_INTERVAL,
+            max_queries_per_second: None,
+        }
+    }
+}
+
+/// Walks the DHT asking every node it learns about for a sample of the
+/// info-hashes it stores.
+pub struct Crawler {
+    id: NodeID,
+    send_transport: Arc<dyn Transport>,
+    routing_table: Arc<Mutex<RoutingTable>>,
+    config: CrawlConfig,
+    filter: Option<InfoHashFilter>,
+    sink: Option<Arc<SinkForwarder>>,
+
+    #[cfg(feature = "graph")]
+    graph: Option<Arc<Mutex<CrawlGraph>>>,
+
+    /// Number of info-hashes emitted by streams returned from [`run`].
+    infohashes_found: Arc<AtomicUsize>,
+
+    /// Ends the streams returned from [`run`]. Triggered by [`shutdown`] or
+    /// by shutting down the [`Dht`] the crawler was created from.
+    shutdown: Shutdown,
+}
+
+impl Crawler {
+    pub(crate) fn new(
+        id: NodeID,
+        send_transport: Arc<dyn Transport>,
+        routing_table: Arc<Mutex<RoutingTable>>,
+        shutdown: Shutdown,
+    ) -> Crawler {
+        Crawler {
+            id,
+            send_transport,
+            routing_table,
+            config: CrawlConfig::default(),
+            filter: None,
+            sink: None,
+            #[cfg(feature = "graph")]
+            graph: None,
+            infohashes_found: Arc::new(AtomicUsize::new(0)),
+            shutdown,
+        }
+    }
+
+    pub fn with_config(mut self, config: CrawlConfig) -> Crawler {
+        self.config = config;
+        self
+    }
+
+    /// Skips info-hashes for which `filter` returns `true`. Skipped
+    /// info-hashes aren't counted by [`infohashes_found`].
+    pub fn with_filter(mut self, filter: InfoHashFilter) -> Crawler {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Stores every info-hash emitted in `sink`. Sink errors don't stop the
+    /// crawl, events are buffered and retried as described by `config`.
+    pub fn with_sink(mut self, sink: Arc<dyn InfoHashSink>, config: SinkConfig) -> Crawler {
+        self.sink = Some(Arc::new(SinkForwarder::new(sink, config)));
+        self
+    }
+
+    /// Records which nodes each queried node returned in `graph`.
+    #[cfg(feature = "graph")]
+    pub fn with_graph(mut self, graph: Arc<Mutex<CrawlGraph>>) -> Crawler {
+        self.graph = Some(graph);
+        self
+    }
+
+    /// Number of events dropped because the sink kept failing and the buffer
+    /// filled up.
+    pub fn sink_events_dropped(&self) -> usize {
+        self.sink.as_ref().map_or(0, |sink| sink.dropped())
+    }
+
+    /// Number of info-hashes emitted so far.
+    pub fn infohashes_found(&self) -> usize {
+        self.infohashes_found.load(Ordering::Relaxed)
+    }
+
+    /// Starts crawling from the nodes in the routing table. Nodes are queried
+    /// again once the `interval` they returned has passed, see
+    /// [`SamplerScheduler`]. Nodes added to the routing table later are
+    /// picked up whenever no node can be queried. The returned stream runs
+    /// until [`shutdown`] and only ends early if no node was ever found.
+    pub fn run(&self) -> Result<impl Stream<Item = NodeID>> {
+        Ok(self.events()?.map(|event| event.info_hash))
+    }
+
+    /// Crawls like [`run`] for `duration` and counts how many nodes sampled
+    /// each info-hash, which tells how widely an info-hash is stored. A node
+    /// sampling the same info-hash again is only counted once.
+    pub async fn run_with_counts(&self, duration: Duration) -> Result<HashMap<NodeID, u32>> {
+        let deadline = Instant::now() + duration;
+        let mut events = Box::pin(self.events()?);
+        let mut observed = HashSet::new();
+        let mut counts = HashMap::new();
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            let event = match events.next().timeout(deadline - now).await {
+                Ok(Some(event)) => event,
+                Ok(None) | Err(_) => break,
+            };
+
+            if observed.insert((event.info_hash.clone(), event.source)) {
+                *counts.entry(event.info_hash).or_insert(0) += 1;
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// Info-hashes found by a crawl along with the node which sampled them,
+    /// after the filter and the sink.
+    fn events(&self) -> Result<impl Stream<Item = InfoHashEvent>> {
+        let mut state = CrawlState::new(
+            self.id.clone(),
+            self.send_transport.clone(),
+            self.routing_table.clone(),
+            CrawlQueue::new(&self.config),
+            self.config
+                .max_queries_per_second
+                .map(AdaptiveThrottle::new),
+            self.shutdown.clone(),
+        );
+        #[cfg(feature = "graph")]
+        {
+            state.graph = self.graph.clone();
+        }
+        state.add_routing_table_nodes(Instant::now())?;
+
+        let sink = self.sink.clone();
+
+        Ok(filter_infohashes(
+            stream::unfold(state, next_infohash),
+            self.filter.clone(),
+            self.infohashes_found.clone(),
+        )
+        .then(move |event| {
+            let sink = sink.clone();
+
+            async move {
+                if let Some(sink) = sink {
+                    sink.forward(event.clone(), Instant::now()).await;
+                }
+
+                event
+            }
+        }))
+    }
+
+    /// Ends every stream returned from [`run`], abandoning the queries they
+    /// have in flight. Resolves once each of them has ended or been dropped.
+    /// The node the crawler was created from keeps running.
+    pub async fn shutdown(&self) {
+        self.shutdown.trigger();
+        self.shutdown.tasks_finished().await;
+    }
+}
+
+struct CrawlState {
+    id: NodeID,
+    send_transport: Arc<dyn Transport>,
+    routing_table: Arc<Mutex<RoutingTable>>,
+    queue: CrawlQueue,
+    throttle: Option<AdaptiveThrottle>,
+
+    /// Info-hashes received but not yet emitted.
+    found: VecDeque<InfoHashEvent>,
+
+    #[cfg(feature = "graph")]
+    graph: Option<Arc<Mutex<CrawlGraph>>>,
+
+    shutdown: Shutdown,
+    _task: TaskGuard,
+}
+
+impl CrawlState {
+    fn new(
+        id: NodeID,
+        send_transport: Arc<dyn Transport>,
+        routing_table: Arc<Mutex<RoutingTable>>,
+        queue: CrawlQueue,
+        throttle: Option<AdaptiveThrottle>,
+        shutdown: Shutdown,
+    ) -> CrawlState {
+        CrawlState {
+            id,
+            send_transport,
+            routing_table,
+            queue,
+            throttle,
+            found: VecDeque::new(),
+            #[cfg(feature = "graph")]
+            graph: None,
+            _task: shutdown.register_task(),
+            shutdown,
+        }
+    }
+
+    /// Queues the nodes in the routing table which aren't known yet.
+    fn add_routing_table_nodes(&mut self, now: Instant) -> Result<()> {
+        let routing_table = self.routing_table.lock()?;
+        for node in routing_table.nodes() {
+            self.queue.enqueue(node.address, 0, now);
+            self.queue.set_node_id(node.address, node.id.clone());
+        }
+        self.queue.intervals.prune(now);
+
+        Ok(())
+    }
+
+    /// Queries `addr`, `hops` away from the routing table.
+    async fn query(&mut self, addr: SocketAddrV4, hops: usize) -> Result<()> {
+        let result = self
+            .send_transport
+            .sample_infohashes(self.id.clone(), addr.into(), NodeID::random())
+            .timeout(Duration::from_secs(3))
+            .await;
+
+        let now = Instant::now();
+        let response = match result {
+            Ok(Ok(response)) => response,
+            Ok(Err(err)) => {
+                self.queue.scheduler.record_failure(addr, now);
+                return Err(err.into());
+            }
+            Err(err) => {
+                self.queue.scheduler.record_failure(addr, now);
+                return Err(err.into());
+            }
+        };
+
+        self.queue.scheduler.record_response(
+            addr,
+            response.interval,
+            response.num,
+            response.samples.len(),
+            now,
+        );
+        #[cfg(feature = "graph")]
+        {
+            if let Some(graph) = &self.graph {
+                graph.lock()?.add_observation(
+                    response.id.clone(),
+                    response
+                        .nodes
+                        .iter()
+                        .map(|node| node.node_id.clone())
+                        .collect(),
+                );
+            }
+        }
+
+        let client_version = response.version().map(<[u8]>::to_vec);
+        self.queue.set_node_id(addr, response.id.clone());
+        if let Some(interval) = response.interval {
+            self.queue.intervals.record_at(response.id, interval, now);
+        }
+
+        self.found
+            .extend(response.samples.into_iter().map(|info_hash| {
+                InfoHashEvent::new(info_hash, addr).with_client_version(client_version.clone())
+            }));
+
+        self.queue.enqueue_discovered(
+            hops,
+            response.nodes.into_iter().map(|node| node.address),
+            now,
+        );
+
+        Ok(())
+    }
+}
+
+/// Nodes to query, with the number of hops they are away from the routing
+/// table.
+struct CrawlQueue {
+    scheduler: SamplerScheduler,
+    max_hops: Option<usize>,
+
+    /// Intervals sent by nodes, followed even when a node shows up at
+    /// another address.
+    intervals: IntervalTracker,
+
+    /// Ids of the nodes at known addresses, from the routing table or from
+    /// their own responses.
+    node_ids: HashMap<SocketAddrV4, NodeID>,
+}
+
+impl CrawlQueue {
+    fn new(config: &CrawlConfig) -> CrawlQueue {
+        CrawlQueue {
+            scheduler: SamplerScheduler::new(config.default_interval),
+            max_hops: config.max_hops,
+            intervals: IntervalTracker::new(),
+            node_ids: HashMap::new(),
+        }
+    }
+
+    fn set_node_id(&mut self, addr: SocketAddrV4, id: NodeID) {
+        self.node_ids.insert(addr, id);
+    }
+
+    /// Queues `addr` unless it has been queued before.
+    fn enqueue(&mut self, addr: SocketAddrV4, hops: usize, now: Instant) {
+        self.scheduler.add_node(addr, hops, now);
+    }
+
+    /// Queues nodes returned by a node `hops` away, unless that node is
+    /// already at the hop limit.
+    fn enqueue_discovered<I: IntoIterator<Item = SocketAddrV4>>(
+        &mut self,
+        hops: usize,
+        addrs: I,
+        now: Instant,
+    ) {
+        if self.max_hops.map_or(false, |max_hops| hops >= max_hops) {
+            return;
+        }
+
+        for addr in addrs {
+            self.enqueue(addr, hops + 1, now);
+        }
+    }
+
+    /// Next node to query at `now`, if any. Nodes whose id sent an interval
+    /// which hasn't passed yet are put back until it has.
+    fn pop(&mut self, now: Instant) -> Option<(SocketAddrV4, usize)> {
+        while let Some((addr, hops)) = self.scheduler.next_ready(now) {
+            let ready_at = self
+                .node_ids
+                .get(&addr)
+                .and_then(|id| self.intervals.ready_at(id));
+
+            match ready_at {
+                Some(ready_at) if ready_at > now => self.scheduler.defer(addr, ready_at),
+                _ => return Some((addr, hops)),
+            }
+        }
+
+        None
+    }
+}
+
+async fn next_infohash(mut state: CrawlState) -> Option<(InfoHashEvent, CrawlState)> {
+    loop {
+        if state.shutdown.is_triggered() {
+            return None;
+        }
+
+        if let Some(event) = state.found.pop_front() {
+            return Some((event, state));
+        }
+
+        let shutdown = state.shutdown.clone();
+        let now = Instant::now();
+        let next = match state.queue.pop(now) {
+            Some(next) => Some(next),
+            None => {
+                state
+                    .add_routing_table_nodes(now)
+                    .unwrap_or_else(|err| eprintln!("Error While Crawling: {}", err));
+                state.queue.pop(now)
+            }
+        };
+
+        let (addr, hops) = match next {
+            Some(next) => next,
+            None => {
+                let wakeup = state.queue.scheduler.next_wakeup()?;
+                shutdown.run_until_triggered(Delay::new(wakeup)).await?;
+                continue;
+            }
+        };
+
+        let resume = state
+            .throttle
+            .as_mut()
+            .map(|throttle| Instant::now() + throttle.tick());
+
+        shutdown
+            .run_until_triggered(state.query(addr, hops))
+            .await?
+            .unwrap_or_else(|err| eprintln!("Error While Crawling {}: {}", addr, err));
+
+        if let Some(resume) = resume {
+            if resume > Instant::now() {
+                shutdown.run_until_triggered(Delay::new(resume)).await?;
+            }
+        }
+    }
+}
+
+/// Drops info-hashes rejected by `filter` and counts the remaining ones in
+/// `infohashes_found`.
+fn filter_infohashes<S>(
+    infohashes: S,
+    filter: Option<InfoHashFilter>,
+    infohashes_found: Arc<AtomicUsize>,
+) -> impl Stream<Item = InfoHashEvent>
+where
+    S: Stream<Item = InfoHashEvent>,
+{
+    infohashes
+        .filter(move |event| {
+            future::ready(
+                filter
+                    .as_ref()
+                    .map_or(true, |filter| !filter(&event.info_hash)),
+            )
+        })
+        .map(move |event| {
+            infohashes_found.fetch_add(1, Ordering::Relaxed);
+            event
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        filter_infohashes,
+        CrawlConfig,
+        CrawlQueue,
+        InfoHashEvent,
+        InfoHashFilter,
+    };
+    use crate::{
+        testing::{
+            NetworkConfig,
+            SimulatedNetwork,
+        },
+        Dht,
+    };
+    use failure::Error;
+    use futures::{
+        executor::block_on,
+        stream,
+        StreamExt,
+    };
+    use krpc_encoding::NodeID;
+    use num_bigint::BigUint;
+    use rand::{
+        rngs::StdRng,
+        seq::sample_slice,
+        SeedableRng,
+    };
+    use std::{
+        cmp,
+        collections::HashSet,
+        net::{
+            Ipv4Addr,
+            SocketAddrV4,
+        },
+        sync::{
+            atomic::{
+                AtomicUsize,
+                Ordering,
+            },
+            Arc,
+        },
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+    use tokio::runtime::current_thread::Runtime;
+
+    fn events(infohashes: &[NodeID]) -> Vec<InfoHashEvent> {
+        infohashes
+            .iter()
+            .map(|info_hash| {
+                InfoHashEvent::new(info_hash.clone(), "10.0.0.1:6881".parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn filter_skips_rejected_infohashes() {
+        let infohashes: Vec<NodeID> = (0u8..10).map(|i| NodeID::new(BigUint::from(i))).collect();
+        let odd: HashSet<NodeID> = infohashes.iter().skip(1).step_by(2).cloned().collect();
+        let filter: InfoHashFilter = Arc::new(move |info_hash: &NodeID| odd.contains(info_hash));
+        let infohashes_found = Arc::new(AtomicUsize::new(0));
+
+        let emitted: Vec<NodeID> = block_on(
+            filter_infohashes(
+                stream::iter(events(&infohashes)),
+                Some(filter),
+                infohashes_found.clone(),
+            )
+            .map(|event| event.info_hash)
+            .collect(),
+        );
+
+        let expected: Vec<NodeID> = infohashes.into_iter().step_by(2).collect();
+        assert_eq!(emitted, expected);
+        assert_eq!(infohashes_found.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn no_filter_emits_everything() {
+        let infohashes: Vec<NodeID> = (0u8..4).map(|i| NodeID::new(BigUint::from(i))).collect();
+        let infohashes_found = Arc::new(AtomicUsize::new(0));
+
+        let emitted: Vec<NodeID> = block_on(
+            filter_infohashes(
+                stream::iter(events(&infohashes)),
+                None,
+                infohashes_found.clone(),
+            )
+            .map(|event| event.info_hash)
+            .collect(),
+        );
+
+        assert_eq!(emitted, infohashes);
+        assert_eq!(infohashes_found.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn counts_nodes_storing_infohash() -> Result<(), Error> {
+        let network = SimulatedNetwork::new(NetworkConfig::default());
+        let nodes: Vec<Dht> = (0..30).map(|_| network.add_node()).collect();
+        let addresses = network.addresses();
+        let mut rng = StdRng::from_seed([3; 32]);
+        let mut runtime = Runtime::new()?;
+
+        for (idx, dht) in nodes.iter().enumerate().skip(1) {
+            let seeds = sample_slice(&mut rng, &addresses[..idx], cmp::min(idx, 3));
+            runtime.block_on(dht.bootstrap_from(&seeds))?;
+        }
+
+        let info_hash = NodeID::random();
+        let other = NodeID::random();
+        let peer: SocketAddrV4 = "10.0.0.1:6881".parse()?;
+        for dht in &nodes[1..6] {
+            dht.store_peer(info_hash.clone(), peer);
+        }
+        nodes[7].store_peer(other.clone(), peer);
+
+        let counts =
+            runtime.block_on(nodes[0].crawler().run_with_counts(Duration::from_secs(2)))?;
+
+        assert_eq!(counts.get(&info_hash), Some(&5));
+        assert_eq!(counts.get(&other), Some(&1));
+        assert_eq!(counts.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_hops_limits_depth() {
+        // Node `n` only knows about node `n + 1`.
+        let addr = |n: u16| SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1000 + n);
+
+        let now = Instant::now();
+        let mut queue = CrawlQueue::new(&CrawlConfig {
+            max_hops: Some(2),
+            ..CrawlConfig::default()
+        });
+        queue.enqueue(addr(0), 0, now);
+
+        let mut queried = Vec::new();
+        while let Some((node, hops)) = queue.pop(now) {
+            queried.push((node, hops));
+            queue.enqueue_discovered(
+                hops,
+                vec![SocketAddrV4::new(*node.ip(), node.port() + 1)],
+                now,
+            );
+        }
+
+        assert_eq!(queried, vec![(addr(0), 0), (addr(1), 1), (addr(2), 2)]);
+    }
+
+    #[test]
+    fn interval_follows_node_id() {
+        let addr = |n: u16| SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1000 + n);
+        let id = NodeID::random();
+
+        let now = Instant::now();
+        let mut queue = CrawlQueue::new(&CrawlConfig::default());
+        queue.enqueue(addr(0), 0, now);
+        queue.enqueue(addr(1), 0, now);
+
+        // The node at `addr(0)` asks for a minute, then shows up at `addr(1)`.
+        queue.set_node_id(addr(0), id.clone());
+        queue.intervals.record_at(id.clone(), 60, now);
+        queue.set_node_id(addr(1), id);
+
+        assert_eq!(queue.pop(now), None);
+        assert_eq!(queue.pop(now + Duration::from_secs(59)), None);
+
+        let later = now + Duration::from_secs(61);
+        let mut ready = vec![queue.pop(later), queue.pop(later)];
+        ready.sort_by_key(|next| next.map(|(addr, _)| addr.port()));
+        assert_eq!(ready, vec![Some((addr(0), 0)), Some((addr(1), 0))]);
+    }
+
+    #[test]
+    fn unlimited_hops() {
+        let addr = |n: u16| SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1000 + n);
+
+        let now = Instant::now();
+        let mut queue = CrawlQueue::new(&CrawlConfig::default());
+        queue.enqueue(addr(0), 0, now);
+        queue.enqueue_discovered(100, vec![addr(1), addr(0)], now);
+
+        assert_eq!(queue.pop(now), Some((addr(0), 0)));
+        assert_eq!(queue.pop(now), Some((addr(1), 101)));
+        assert_eq!(queue.pop(now), None);
+    }
+}
@@ -0,0 +1,423 @@
+//! Decides when each node is asked for samples, following the `interval` and
+//! `num` fields of [BEP-0051] responses.
+//!
+//! [BEP-0051]: http://www.bittorrent.org/beps/bep_0051.html
+
+use krpc_encoding::NodeID;
+use std::{
+    cmp::Reverse,
+    collections::{
+        BinaryHeap,
+        HashMap,
+    },
+    net::{
+        Ipv4Addr,
+        SocketAddrV4,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// Time to wait before querying a node again if it didn't say how long to
+/// wait, or didn't respond.
+pub const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Heap key of a node. `SocketAddrV4` isn't `Ord`.
+type AddrKey = (u32, u16);
+
+fn addr_key(addr: SocketAddrV4) -> AddrKey {
+    (u32::from(*addr.ip()), addr.port())
+}
+
+fn key_addr((ip, port): AddrKey) -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::from(ip), port)
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ScheduleState {
+    /// Waiting for `next_query`.
+    Waiting,
+
+    /// Can be queried now.
+    Ready,
+
+    /// Handed out by [`SamplerScheduler::next_ready`], waiting for the
+    /// result.
+    Querying,
+}
+
+#[derive(Debug)]
+struct NodeSchedule {
+    next_query: Instant,
+    state: ScheduleState,
+
+    /// Hops away from the routing table the node was found.
+    hops: usize,
+
+    /// Number of info-hashes the node last said it stores.
+    num: Option<u32>,
+
+    /// Number of samples received from the node so far.
+    samples_collected: usize,
+}
+
+impl NodeSchedule {
+    /// Nodes which are expected to have more info-hashes we haven't seen
+    /// are queried first. Nodes which haven't said how many they store yet
+    /// go before all others.
+    fn priority(&self) -> u64 {
+        match self.num {
+            Some(num) => u64::from(num).saturating_sub(self.samples_collected as u64),
+            None => u64::max_value(),
+        }
+    }
+}
+
+/// Queue of nodes to send `sample_infohashes` queries to. Every node is only
+/// handed out after the `interval` it last returned has passed. Among the
+/// nodes which can be queried, those with many info-hashes and few samples
+/// collected so far are handed out first.
+#[derive(Debug)]
+pub struct SamplerScheduler {
+    default_interval: Duration,
+    nodes: HashMap<SocketAddrV4, NodeSchedule>,
+
+    /// Nodes waiting for their next query, earliest first. Holds stale
+    /// entries for nodes rescheduled since they were pushed.
+    waiting: BinaryHeap<Reverse<(Instant, AddrKey)>>,
+
+    /// Nodes which can be queried now, highest priority first.
+    ready: BinaryHeap<(u64, Reverse<AddrKey>)>,
+}
+
+impl SamplerScheduler {
+    pub fn new(default_interval: Duration) -> SamplerScheduler {
+        SamplerScheduler {
+            default_interval,
+            nodes: HashMap::new(),
+            waiting: BinaryHeap::new(),
+            ready: BinaryHeap::new(),
+        }
+    }
+
+    /// Adds a node which can be queried from `now`, `hops` away from the
+    /// routing table. Returns `false` and does nothing if the node is
+    /// already known.
+    pub fn add_node(&mut self, addr: SocketAddrV4, hops: usize, now: Instant) -> bool {
+        if self.nodes.contains_key(&addr) {
+            return false;
+        }
+
+        self.nodes.insert(
+            addr,
+            NodeSchedule {
+                next_query: now,
+                state: ScheduleState::Waiting,
+                hops,
+                num: None,
+                samples_collected: 0,
+            },
+        );
+        self.waiting.push(Reverse((now, addr_key(addr))));
+
+        true
+    }
+
+    /// Hands out the node which should be queried next along with its hop
+    /// count, if any can be queried at `now`. The node isn't handed out again
+    /// until [`record_response`] or [`record_failure`] is called for it.
+    pub fn next_ready(&mut self, now: Instant) -> Option<(SocketAddrV4, usize)> {
+        self.promote(now);
+
+        while let Some((_, Reverse(key))) = self.ready.pop() {
+            let addr = key_addr(key);
+            if let Some(node) = self.nodes.get_mut(&addr) {
+                if node.state == ScheduleState::Ready {
+                    node.state = ScheduleState::Querying;
+                    return Some((addr, node.hops));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Records a response to a query sent to `addr` at about `now`. The node
+    /// is handed out again after `interval` seconds or after the default
+    /// interval if the node didn't send one.
+    pub fn record_response(
+        &mut self,
+        addr: SocketAddrV4,
+        interval: Option<u16>,
+        num: Option<u32>,
+        samples: usize,
+        now: Instant,
+    ) {
+        let interval = interval
+            .map(|seconds| Duration::from_secs(seconds.into()))
+            .unwrap_or(self.default_interval);
+
+        if let Some(node) = self.nodes.get_mut(&addr) {
+            node.num = num.or(node.num);
+            node.samples_collected += samples;
+        }
+
+        self.schedule(addr, now + interval);
+    }
+
+    /// Records that the query sent to `addr` failed. The node is handed out
+    /// again after the default interval.
+    pub fn record_failure(&mut self, addr: SocketAddrV4, now: Instant) {
+        let next_query = now + self.default_interval;
+        self.schedule(addr, next_query);
+    }
+
+    /// Hands out the node at `addr`, which was handed out without being
+    /// queried, again at `next_query`.
+    pub fn defer(&mut self, addr: SocketAddrV4, next_query: Instant) {
+        self.schedule(addr, next_query);
+    }
+
+    /// Earliest time at which a node which can't be queried yet can be, or
+    /// `None` if no node is waiting.
+    pub fn next_wakeup(&mut self) -> Option<Instant> {
+        while let Some(&Reverse((time, key))) = self.waiting.peek() {
+            match self.nodes.get(&key_addr(key)) {
+                Some(node) if node.state == ScheduleState::Waiting && node.next_query == time => {
+                    return Some(time);
+                }
+                _ => {
+                    self.waiting.pop();
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Number of nodes known to the scheduler.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn schedule(&mut self, addr: SocketAddrV4, next_query: Instant) {
+        if let Some(node) = self.nodes.get_mut(&addr) {
+            node.next_query = next_query;
+            node.state = ScheduleState::Waiting;
+            self.waiting.push(Reverse((next_query, addr_key(addr))));
+        }
+    }
+
+    /// Moves nodes whose time has come from `waiting` to `ready`.
+    fn promote(&mut self, now: Instant) {
+        while let Some(&Reverse((time, key))) = self.waiting.peek() {
+            if time > now {
+                break;
+            }
+
+            self.waiting.pop();
+            if let Some(node) = self.nodes.get_mut(&key_addr(key)) {
+                if node.state == ScheduleState::Waiting && node.next_query == time {
+                    node.state = ScheduleState::Ready;
+                    self.ready.push((node.priority(), Reverse(key)));
+                }
+            }
+        }
+    }
+}
+
+/// Times until which nodes asked not to be queried again, from the `interval`
+/// of their `sample_infohashes` responses. Unlike [`SamplerScheduler`], nodes
+/// are tracked by id so the interval still applies after a node's address
+/// changes.
+#[derive(Debug, Default)]
+pub struct IntervalTracker {
+    intervals: HashMap<NodeID, Instant>,
+}
+
+impl IntervalTracker {
+    pub fn new() -> IntervalTracker {
+        IntervalTracker::default()
+    }
+
+    /// Records that the node with `id` asked not to be queried for `secs`
+    /// seconds from now.
+    pub fn record(&mut self, id: NodeID, secs: u16) {
+        self.record_at(id, secs, Instant::now())
+    }
+
+    /// Like [`record`](IntervalTracker::record) for an interval received at
+    /// `now`.
+    pub fn record_at(&mut self, id: NodeID, secs: u16, now: Instant) {
+        self.intervals
+            .insert(id, now + Duration::from_secs(secs.into()));
+    }
+
+    /// Whether the interval of the node with `id` has passed. Nodes which
+    /// never sent an interval are always ready.
+    pub fn is_ready(&self, id: &NodeID) -> bool {
+        self.is_ready_at(id, Instant::now())
+    }
+
+    pub fn is_ready_at(&self, id: &NodeID, now: Instant) -> bool {
+        self.ready_at(id).map_or(true, |ready_at| ready_at <= now)
+    }
+
+    /// Time at which the node with `id` can be queried again, if it sent an
+    /// interval.
+    pub fn ready_at(&self, id: &NodeID) -> Option<Instant> {
+        self.intervals.get(id).cloned()
+    }
+
+    /// Forgets the nodes which are ready at `now`.
+    pub fn prune(&mut self, now: Instant) {
+        self.intervals.retain(|_, ready_at| *ready_at > now);
+    }
+
+    /// Number of nodes whose interval hasn't been pruned yet.
+    pub fn len(&self) -> usize {
+        self.intervals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        IntervalTracker,
+        SamplerScheduler,
+    };
+    use krpc_encoding::NodeID;
+    use std::{
+        net::{
+            Ipv4Addr,
+            SocketAddrV4,
+        },
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+
+    fn addr(n: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1000 + n)
+    }
+
+    #[test]
+    fn respects_interval() {
+        let start = Instant::now();
+        let mut scheduler = SamplerScheduler::new(Duration::from_secs(60));
+        scheduler.add_node(addr(0), 0, start);
+
+        assert_eq!(scheduler.next_ready(start), Some((addr(0), 0)));
+        assert_eq!(scheduler.next_ready(start), None);
+
+        scheduler.record_response(addr(0), Some(3600), Some(100), 20, start);
+
+        for minutes in &[1, 10, 59] {
+            assert_eq!(
+                scheduler.next_ready(start + Duration::from_secs(60 * minutes)),
+                None
+            );
+        }
+        assert_eq!(
+            scheduler.next_wakeup(),
+            Some(start + Duration::from_secs(3600))
+        );
+        assert_eq!(
+            scheduler.next_ready(start + Duration::from_secs(3600)),
+            Some((addr(0), 0))
+        );
+    }
+
+    #[test]
+    fn default_interval_when_absent() {
+        let start = Instant::now();
+        let mut scheduler = SamplerScheduler::new(Duration::from_secs(60));
+        scheduler.add_node(addr(0), 0, start);
+        scheduler.add_node(addr(1), 0, start);
+        scheduler.next_ready(start);
+        scheduler.next_ready(start);
+
+        scheduler.record_response(addr(0), None, None, 0, start);
+        scheduler.record_failure(addr(1), start);
+
+        assert_eq!(scheduler.next_ready(start + Duration::from_secs(59)), None);
+        assert!(scheduler
+            .next_ready(start + Duration::from_secs(60))
+            .is_some());
+        assert!(scheduler
+            .next_ready(start + Duration::from_secs(60))
+            .is_some());
+    }
+
+    #[test]
+    fn high_num_drained_first() {
+        let start = Instant::now();
+        let mut scheduler = SamplerScheduler::new(Duration::from_secs(60));
+
+        // Node n stores 100 * n info-hashes.
+        for n in 1..=3 {
+            scheduler.add_node(addr(n), 0, start);
+        }
+        while let Some((node, _)) = scheduler.next_ready(start) {
+            let n = u32::from(node.port() - 1000);
+            scheduler.record_response(node, Some(60), Some(100 * n), 20, start);
+        }
+
+        let later = start + Duration::from_secs(60);
+        let mut order = Vec::new();
+        while let Some((node, _)) = scheduler.next_ready(later) {
+            order.push(node);
+            scheduler.record_response(node, Some(60), None, 20, later);
+        }
+        assert_eq!(order, vec![addr(3), addr(2), addr(1)]);
+
+        // Node 3 still has the most samples left after another round.
+        let later = later + Duration::from_secs(60);
+        assert_eq!(scheduler.next_ready(later), Some((addr(3), 0)));
+    }
+
+    #[test]
+    fn interval_tracker() {
+        let start = Instant::now();
+        let mut tracker = IntervalTracker::new();
+        let id = NodeID::random();
+        assert!(tracker.is_ready_at(&id, start));
+
+        tracker.record_at(id.clone(), 60, start);
+        assert!(!tracker.is_ready_at(&id, start));
+        assert!(!tracker.is_ready_at(&id, start + Duration::from_secs(59)));
+        assert!(tracker.is_ready_at(&id, start + Duration::from_secs(61)));
+
+        tracker.prune(start + Duration::from_secs(30));
+        assert_eq!(tracker.len(), 1);
+        tracker.prune(start + Duration::from_secs(61));
+        assert!(tracker.is_empty());
+    }
+
+    #[test]
+    fn unknown_nodes_first() {
+        let start = Instant::now();
+        let mut scheduler = SamplerScheduler::new(Duration::from_secs(60));
+        scheduler.add_node(addr(0), 0, start);
+        scheduler.next_ready(start);
+        scheduler.record_response(addr(0), Some(0), Some(1000), 0, start);
+
+        assert!(!scheduler.add_node(addr(0), 1, start));
+        scheduler.add_node(addr(1), 1, start);
+
+        assert_eq!(scheduler.next_ready(start), Some((addr(1), 1)));
+        assert_eq!(scheduler.next_ready(start), Some((addr(0), 0)));
+        assert_eq!(scheduler.len(), 2);
+    }
+}
@@ -0,0 +1,901 @@
+//! Destinations for info-hashes found by the [`Crawler`].
+
+use crate::errors::{
+    ErrorKind,
+    Result,
+};
+use chrono::{
+    DateTime,
+    Utc,
+};
+use futures::future::{
+    self,
+    Future,
+};
+use krpc_encoding::NodeID;
+use serde_json::json;
+use std::{
+    cmp,
+    collections::{
+        HashSet,
+        VecDeque,
+    },
+    fs::{
+        File,
+        OpenOptions,
+    },
+    io::{
+        self,
+        Write,
+    },
+    mem,
+    net::SocketAddrV4,
+    path::Path,
+    pin::Pin,
+    sync::{
+        mpsc::{
+            self,
+            Receiver,
+            RecvTimeoutError,
+            SyncSender,
+            TrySendError,
+        },
+        Arc,
+        Mutex,
+        MutexGuard,
+    },
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// An info-hash discovered while crawling.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InfoHashEvent {
+    pub info_hash: NodeID,
+
+    /// Node whose `sample_infohashes` response contained the info-hash.
+    pub source: SocketAddrV4,
+
+    pub discovered_at: DateTime<Utc>,
+
+    client_version: Option<Vec<u8>>,
+}
+
+impl InfoHashEvent {
+    pub fn new(info_hash: NodeID, source: SocketAddrV4) -> InfoHashEvent {
+        InfoHashEvent {
+            info_hash,
+            source,
+            discovered_at: Utc::now(),
+            client_version: None,
+        }
+    }
+
+    pub fn with_client_version(mut self, client_version: Option<Vec<u8>>) -> InfoHashEvent {
+        self.client_version = client_version;
+        self
+    }
+
+    /// Client version the source sent in the `v` field of its response.
+    pub fn client_version(&self) -> Option<&[u8]> {
+        self.client_version.as_ref().map(Vec::as_slice)
+    }
+
+    /// Encodes the event as written by [`JsonLinesSink`]. The client version
+    /// is hex encoded and left out when unknown.
+    pub(crate) fn to_json_line(&self) -> String {
+        let mut value = json!({
+            "info_hash": self.info_hash.to_string(),
+            "source": self.source.to_string(),
+            "discovered_at": self.discovered_at.to_rfc3339(),
+        });
+        if let Some(client_version) = &self.client_version {
+            value["client_version"] = json!(to_hex(client_version));
+        }
+
+        let mut line = value.to_string();
+        line.push('\n');
+
+        line
+    }
+
+    /// Decodes a line written by [`JsonLinesSink`].
+    pub(crate) fn from_json_line(line: &str) -> Result<InfoHashEvent> {
+        let invalid = || ErrorKind::InvalidEvent {
+            line: line.trim_end().to_string(),
+        };
+        let value: serde_json::Value = serde_json::from_str(line).map_err(|_| invalid())?;
+        let field = |name: &str| value[name].as_str().ok_or_else(invalid);
+
+        let client_version = match &value["client_version"] {
+            serde_json::Value::Null => None,
+            version => Some(from_hex(version.as_str().ok_or_else(invalid)?).ok_or_else(invalid)?),
+        };
+
+        Ok(InfoHashEvent {
+            info_hash: field("info_hash")?.parse().map_err(|_| invalid())?,
+            source: field("source")?.parse().map_err(|_| invalid())?,
+            discovered_at: DateTime::parse_from_rfc3339(field("discovered_at")?)
+                .map_err(|_| invalid())?
+                .with_timezone(&Utc),
+            client_version,
+        })
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(encoded: &str) -> Option<Vec<u8>> {
+    if encoded.len() % 2 != 0 || !encoded.is_ascii() {
+        return None;
+    }
+
+    (0..encoded.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&encoded[idx..idx + 2], 16).ok())
+        .collect()
+}
+
+/// Future returned by [`InfoHashSink::store`]. Boxed because traits can't
+/// have async methods yet.
+pub type StoreFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Storage backend for info-hashes found by the [`Crawler`].
+pub trait InfoHashSink: Send + Sync {
+    /// Stores `event`. Failed events are retried later by the crawler.
+    fn store(&self, event: InfoHashEvent) -> StoreFuture<'_>;
+}
+
+/// Options for [`JsonLinesSink`].
+#[derive(Clone, Debug)]
+pub struct JsonLinesConfig {
+    /// Buffered events are written out when the oldest of them has waited
+    /// this long, even if no other event arrives.
+    pub flush_interval: Duration,
+
+    /// Buffered events are written out once this many are waiting.
+    pub flush_size: usize,
+}
+
+impl Default for JsonLinesConfig {
+    fn default() -> JsonLinesConfig {
+        JsonLinesConfig {
+            flush_interval: Duration::from_secs(5),
+            flush_size: 256,
+        }
+    }
+}
+
+/// Number of events [`JsonLinesSink`] keeps waiting for its writer thread
+/// before stores fail with [`ErrorKind::SinkFull`].
+const JSON_LINES_BACKLOG: usize = 4096;
+
+/// Appends events to a file, one JSON object per line. Events are written in
+/// batches, see [`JsonLinesConfig`]. Buffered events are written and the file
+/// is synced to disk by [`close`] or when the sink is dropped.
+///
+/// The file is written from a thread of its own, so storing an event never
+/// blocks the executor on the disk.
+pub struct JsonLinesSink {
+    commands: Mutex<SyncSender<Command>>,
+
+    /// Error of the last write made by the writer thread on its own, reported
+    /// by the next store.
+    failure: Arc<Mutex<Option<io::Error>>>,
+}
+
+/// Sent by [`JsonLinesSink`] to its writer thread.
+enum Command {
+    Store { line: String, at: Instant },
+    Flush(SyncSender<Result<()>>),
+    Close(SyncSender<Result<()>>),
+    Buffered(SyncSender<usize>),
+}
+
+/// Owns the file of a [`JsonLinesSink`] on the writer thread.
+struct JsonLinesWriter {
+    config: JsonLinesConfig,
+    file: File,
+    batch: Batch,
+    failure: Arc<Mutex<Option<io::Error>>>,
+}
+
+/// Encoded events waiting to be written.
+struct Batch {
+    buf: Vec<u8>,
+    len: usize,
+
+    /// When the first event currently in the batch was added.
+    started: Option<Instant>,
+}
+
+impl Batch {
+    fn new() -> Batch {
+        Batch {
+            buf: Vec::new(),
+            len: 0,
+            started: None,
+        }
+    }
+
+    fn push(&mut self, line: &str, now: Instant) {
+        self.buf.extend_from_slice(line.as_bytes());
+        self.len += 1;
+        self.started.get_or_insert(now);
+    }
+
+    fn is_due(&self, config: &JsonLinesConfig, now: Instant) -> bool {
+        match self.started {
+            None => false,
+            Some(started) => {
+                self.len >= config.flush_size
+                    || now.duration_since(started) >= config.flush_interval
+            }
+        }
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        self.len = 0;
+        self.started = None;
+
+        mem::replace(&mut self.buf, Vec::new())
+    }
+}
+
+impl JsonLinesWriter {
+    fn run(mut self, commands: Receiver<Command>) {
+        loop {
+            let command = match self.batch.started {
+                None => commands.recv().ok(),
+                Some(started) => {
+                    let deadline = started + self.config.flush_interval;
+                    let now = Instant::now();
+                    let wait = if deadline > now {
+                        deadline - now
+                    } else {
+                        Duration::from_secs(0)
+                    };
+
+                    match commands.recv_timeout(wait) {
+                        Ok(command) => Some(command),
+                        Err(RecvTimeoutError::Timeout) => {
+                            self.flush_in_background();
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => None,
+                    }
+                }
+            };
+
+            match command {
+                Some(Command::Store { line, at }) => {
+                    self.batch.push(&line, at);
+                    if self.batch.is_due(&self.config, at) {
+                        self.flush_in_background();
+                    }
+                }
+                Some(Command::Flush(reply)) => {
+                    let _ = reply.send(self.flush());
+                }
+                Some(Command::Close(reply)) => {
+                    let _ = reply.send(self.close());
+                }
+                Some(Command::Buffered(reply)) => {
+                    let _ = reply.send(self.batch.len);
+                }
+                None => {
+                    self.close().unwrap_or_else(|err| {
+                        eprintln!("Error While Closing Info-Hash Sink: {}", err)
+                    });
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Writes the batch out. It is kept when the write fails, and tried again
+    /// once another interval has passed.
+    fn write(&mut self) -> io::Result<()> {
+        if self.batch.buf.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(err) = self.file.write_all(&self.batch.buf) {
+            self.batch.started = Some(Instant::now());
+            return Err(err);
+        }
+        self.batch.take();
+
+        Ok(())
+    }
+
+    /// Writes the batch out when it is due, leaving failures to be reported
+    /// by the next store.
+    fn flush_in_background(&mut self) {
+        if let Err(err) = self.write() {
+            *self
+                .failure
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(err);
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write()
+            .map_err(|cause| ErrorKind::StoreError { cause })?;
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.flush()?;
+        self.file
+            .sync_all()
+            .map_err(|cause| ErrorKind::StoreError { cause })?;
+
+        Ok(())
+    }
+}
+
+impl JsonLinesSink {
+    /// Opens `path` for appending, creating it if it doesn't exist.
+    pub fn create<P: AsRef<Path>>(path: P, config: JsonLinesConfig) -> Result<JsonLinesSink> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|cause| ErrorKind::StoreError { cause })?;
+
+        let failure = Arc::new(Mutex::new(None));
+        let writer = JsonLinesWriter {
+            config,
+            file,
+            batch: Batch::new(),
+            failure: failure.clone(),
+        };
+        let (commands, receiver) = mpsc::sync_channel(JSON_LINES_BACKLOG);
+        thread::Builder::new()
+            .name("info-hash sink".to_string())
+            .spawn(move || writer.run(receiver))
+            .map_err(|cause| ErrorKind::StoreError { cause })?;
+
+        Ok(JsonLinesSink {
+            commands: Mutex::new(commands),
+            failure,
+        })
+    }
+
+    /// Writes buffered events to the file.
+    pub fn flush(&self) -> Result<()> {
+        let (reply, result) = mpsc::sync_channel(1);
+        self.send(Command::Flush(reply))?;
+
+        result.recv().map_err(|_| writer_gone())?
+    }
+
+    /// Writes buffered events to the file and syncs it to disk.
+    pub fn close(&self) -> Result<()> {
+        let (reply, result) = mpsc::sync_channel(1);
+        self.send(Command::Close(reply))?;
+
+        result.recv().map_err(|_| writer_gone())?
+    }
+
+    /// Number of events stored but not written to the file yet.
+    pub fn buffered(&self) -> Result<usize> {
+        let (reply, result) = mpsc::sync_channel(1);
+        self.send(Command::Buffered(reply))?;
+
+        Ok(result.recv().map_err(|_| writer_gone())?)
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        self.commands
+            .lock()?
+            .send(command)
+            .map_err(|_| writer_gone())?;
+
+        Ok(())
+    }
+
+    fn store_at(&self, event: &InfoHashEvent, now: Instant) -> Result<()> {
+        let failure = self
+            .failure
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        if let Some(cause) = failure {
+            Err(ErrorKind::StoreError { cause })?;
+        }
+
+        let command = Command::Store {
+            line: event.to_json_line(),
+            at: now,
+        };
+        self.commands
+            .lock()?
+            .try_send(command)
+            .map_err(|err| match err {
+                TrySendError::Full(_) => ErrorKind::SinkFull {
+                    capacity: JSON_LINES_BACKLOG,
+                },
+                TrySendError::Disconnected(_) => writer_gone(),
+            })?;
+
+        Ok(())
+    }
+}
+
+fn writer_gone() -> ErrorKind {
+    ErrorKind::StoreError {
+        cause: io::Error::new(io::ErrorKind::BrokenPipe, "writer thread gone"),
+    }
+}
+
+impl InfoHashSink for JsonLinesSink {
+    fn store(&self, event: InfoHashEvent) -> StoreFuture<'_> {
+        Box::pin(future::ready(self.store_at(&event, Instant::now())))
+    }
+}
+
+impl Drop for JsonLinesSink {
+    /// Waits for the writer thread to write buffered events and sync the
+    /// file. The thread exits once the sink is gone.
+    fn drop(&mut self) {
+        self.close()
+            .unwrap_or_else(|err| eprintln!("Error While Closing Info-Hash Sink: {}", err));
+    }
+}
+
+/// Keeps events in memory until they are taken with [`drain`]. Each
+/// info-hash is only kept once, even across drains. Stores fail once
+/// `capacity` events are waiting to be drained.
+pub struct MemorySink {
+    capacity: usize,
+    state: Mutex<MemoryState>,
+}
+
+struct MemoryState {
+    seen: HashSet<NodeID>,
+    events: Vec<InfoHashEvent>,
+}
+
+impl MemorySink {
+    pub fn new(capacity: usize) -> MemorySink {
+        MemorySink {
+            capacity,
+            state: Mutex::new(MemoryState {
+                seen: HashSet::new(),
+                events: Vec::new(),
+            }),
+        }
+    }
+
+    /// Takes every event stored since the last drain, oldest first.
+    pub fn drain(&self) -> Vec<InfoHashEvent> {
+        let mut state = self.lock();
+
+        mem::replace(&mut state.events, Vec::new())
+    }
+
+    /// Number of events waiting to be drained.
+    pub fn len(&self) -> usize {
+        self.lock().events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn store_now(&self, event: InfoHashEvent) -> Result<()> {
+        let mut state = self.lock();
+        if state.seen.contains(&event.info_hash) {
+            return Ok(());
+        }
+
+        if state.events.len() >= self.capacity {
+            Err(ErrorKind::SinkFull {
+                capacity: self.capacity,
+            })?;
+        }
+
+        state.seen.insert(event.info_hash.clone());
+        state.events.push(event);
+
+        Ok(())
+    }
+}
+
+impl InfoHashSink for MemorySink {
+    fn store(&self, event: InfoHashEvent) -> StoreFuture<'_> {
+        Box::pin(future::ready(self.store_now(event)))
+    }
+}
+
+/// Options controlling how the [`Crawler`] hands events to its sink.
+#[derive(Clone, Debug)]
+pub struct SinkConfig {
+    /// Maximum number of events kept while the sink is failing. The oldest
+    /// events are dropped once this many are waiting.
+    pub buffer: usize,
+
+    /// Time to wait before retrying after the sink first fails. Doubled after
+    /// every consecutive failure.
+    pub initial_backoff: Duration,
+
+    /// Upper bound on the time between retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for SinkConfig {
+    fn default() -> SinkConfig {
+        SinkConfig {
+            buffer: 1024,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Passes events from the crawl to an [`InfoHashSink`]. While the sink is
+/// failing, events are buffered instead of waiting for it so the crawl keeps
+/// going.
+pub(crate) struct SinkForwarder {
+    sink: Arc<dyn InfoHashSink>,
+    pending: Mutex<Pending>,
+}
+
+/// Events waiting for the sink and retry bookkeeping.
+struct Pending {
+    config: SinkConfig,
+    events: VecDeque<InfoHashEvent>,
+
+    /// Set while backing off after a failure.
+    retry_at: Option<Instant>,
+    backoff: Option<Duration>,
+
+    /// Number of events dropped because the buffer was full.
+    dropped: usize,
+}
+
+impl Pending {
+    /// Buffers `event`, dropping the oldest event if the buffer is full.
+    /// Returns whether the sink should be tried at `now`.
+    fn push(&mut self, event: InfoHashEvent, now: Instant) -> bool {
+        if self.events.len() >= self.config.buffer {
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+
+        self.events.push_back(event);
+
+        self.retry_at.map_or(true, |retry_at| now >= retry_at)
+    }
+
+    fn succeeded(&mut self) {
+        self.retry_at = None;
+        self.backoff = None;
+    }
+
+    /// Puts `event` back at the front of the buffer and backs off.
+    fn failed(&mut self, event: InfoHashEvent, now: Instant) {
+        self.events.push_front(event);
+
+        let backoff = match self.backoff {
+            None => self.config.initial_backoff,
+            Some(backoff) => cmp::min(backoff * 2, self.config.max_backoff),
+        };
+
+        self.backoff = Some(backoff);
+        self.retry_at = Some(now + backoff);
+    }
+}
+
+impl SinkForwarder {
+    pub fn new(sink: Arc<dyn InfoHashSink>, config: SinkConfig) -> SinkForwarder {
+        SinkForwarder {
+            sink,
+            pending: Mutex::new(Pending {
+                config,
+                events: VecDeque::new(),
+                retry_at: None,
+                backoff: None,
+                dropped: 0,
+            }),
+        }
+    }
+
+    /// Queues `event` and, unless backing off, stores every queued event.
+    /// Returns without waiting for the backoff to elapse.
+    pub async fn forward(&self, event: InfoHashEvent, now: Instant) {
+        if !self.lock().push(event, now) {
+            return;
+        }
+
+        loop {
+            let event = match self.lock().events.pop_front() {
+                Some(event) => event,
+                None => return,
+            };
+
+            match self.sink.store(event.clone()).await {
+                Ok(()) => self.lock().succeeded(),
+                Err(err) => {
+                    eprintln!("Error While Storing {}: {}", event.info_hash, err);
+                    self.lock().failed(event, now);
+                    return;
+                }
+            };
+        }
+    }
+
+    /// Number of events waiting for the sink.
+    pub fn pending(&self) -> usize {
+        self.lock().events.len()
+    }
+
+    /// Number of events dropped because the sink failed for too long.
+    pub fn dropped(&self) -> usize {
+        self.lock().dropped
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Pending> {
+        self.pending
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        InfoHashEvent,
+        InfoHashSink,
+        JsonLinesConfig,
+        JsonLinesSink,
+        MemorySink,
+        SinkConfig,
+        SinkForwarder,
+        StoreFuture,
+    };
+    use crate::errors::ErrorKind;
+    use failure::Error;
+    use futures::{
+        executor::block_on,
+        future,
+    };
+    use krpc_encoding::NodeID;
+    use num_bigint::BigUint;
+    use std::{
+        env,
+        fs,
+        path::PathBuf,
+        process,
+        sync::{
+            atomic::{
+                AtomicBool,
+                AtomicUsize,
+                Ordering,
+            },
+            Arc,
+            Mutex,
+        },
+        thread,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+
+    fn event(id: u8) -> InfoHashEvent {
+        InfoHashEvent::new(
+            NodeID::new(BigUint::from(id)),
+            "10.0.0.1:6881".parse().unwrap(),
+        )
+    }
+
+    fn sink_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("dht_crawler-{}-{}.jsonl", name, process::id()));
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    fn lines(path: &PathBuf) -> Result<usize, Error> {
+        Ok(fs::read_to_string(path)?.lines().count())
+    }
+
+    /// Records which events it was asked to store and fails while `failing`
+    /// is set.
+    struct FlakySink {
+        failing: AtomicBool,
+        attempts: AtomicUsize,
+        stored: Mutex<Vec<InfoHashEvent>>,
+    }
+
+    impl FlakySink {
+        fn new() -> FlakySink {
+            FlakySink {
+                failing: AtomicBool::new(true),
+                attempts: AtomicUsize::new(0),
+                stored: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    impl InfoHashSink for FlakySink {
+        fn store(&self, event: InfoHashEvent) -> StoreFuture<'_> {
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+
+            let result = if self.failing.load(Ordering::Relaxed) {
+                Err(ErrorKind::SinkFull { capacity: 0 }.into())
+            } else {
+                self.stored.lock().unwrap().push(event);
+                Ok(())
+            };
+
+            Box::pin(future::ready(result))
+        }
+    }
+
+    #[test]
+    fn json_lines_batched() -> Result<(), Error> {
+        let path = sink_path("batched");
+        let config = JsonLinesConfig {
+            flush_interval: Duration::from_secs(60),
+            flush_size: 3,
+        };
+        let sink = JsonLinesSink::create(&path, config)?;
+        let start = Instant::now();
+
+        // Asking for the number of buffered events waits for the writer
+        // thread to handle the stores before it.
+        sink.store_at(&event(1), start)?;
+        sink.store_at(&event(2), start)?;
+        assert_eq!(sink.buffered()?, 2);
+        assert_eq!(lines(&path)?, 0);
+
+        sink.store_at(&event(3), start)?;
+        assert_eq!(sink.buffered()?, 0);
+        assert_eq!(lines(&path)?, 3);
+
+        sink.store_at(&event(4), start)?;
+        assert_eq!(sink.buffered()?, 1);
+        assert_eq!(lines(&path)?, 3);
+
+        sink.store_at(&event(5), start + Duration::from_secs(60))?;
+        assert_eq!(sink.buffered()?, 0);
+        assert_eq!(lines(&path)?, 5);
+
+        let first = fs::read_to_string(&path)?;
+        let first = first.lines().next().unwrap();
+        assert!(first.contains(&format!("\"info_hash\":\"{}\"", event(1).info_hash)));
+        assert!(first.contains("\"source\":\"10.0.0.1:6881\""));
+
+        fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn json_lines_flushed_on_close() -> Result<(), Error> {
+        let path = sink_path("close");
+        let sink = JsonLinesSink::create(&path, JsonLinesConfig::default())?;
+
+        block_on(sink.store(event(1)))?;
+        assert_eq!(sink.buffered()?, 1);
+        assert_eq!(lines(&path)?, 0);
+
+        sink.close()?;
+        assert_eq!(lines(&path)?, 1);
+
+        block_on(sink.store(event(2)))?;
+        drop(sink);
+        assert_eq!(lines(&path)?, 2);
+
+        fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn json_lines_flushed_when_idle() -> Result<(), Error> {
+        let path = sink_path("idle");
+        let config = JsonLinesConfig {
+            flush_interval: Duration::from_millis(50),
+            flush_size: 256,
+        };
+        let sink = JsonLinesSink::create(&path, config)?;
+
+        // Written by the writer thread once the interval passes, without
+        // another store or a flush.
+        block_on(sink.store(event(1)))?;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while lines(&path)? == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(lines(&path)?, 1);
+        assert_eq!(sink.buffered()?, 0);
+
+        drop(sink);
+        fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn memory_sink_deduplicates() -> Result<(), Error> {
+        let sink = MemorySink::new(2);
+
+        block_on(sink.store(event(1)))?;
+        block_on(sink.store(event(1)))?;
+        block_on(sink.store(event(2)))?;
+        assert!(block_on(sink.store(event(3))).is_err());
+
+        let drained = sink.drain();
+        assert_eq!(drained.len(), 2);
+        assert_eq!(drained[0].info_hash, event(1).info_hash);
+        assert_eq!(drained[1].info_hash, event(2).info_hash);
+        assert_eq!(sink.len(), 0);
+
+        block_on(sink.store(event(1)))?;
+        block_on(sink.store(event(3)))?;
+        assert_eq!(sink.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn failing_sink_buffers() {
+        let sink = Arc::new(FlakySink::new());
+        let config = SinkConfig {
+            buffer: 4,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(4),
+        };
+        let forwarder = SinkForwarder::new(sink.clone(), config);
+        let start = Instant::now();
+
+        // Every forward returns right away even though nothing is stored.
+        for id in 0..10 {
+            block_on(forwarder.forward(event(id), start));
+        }
+
+        assert_eq!(sink.attempts.load(Ordering::Relaxed), 1);
+        assert_eq!(forwarder.pending(), 4);
+        assert_eq!(forwarder.dropped(), 6);
+
+        // Retried once the backoff elapses, then backs off for longer.
+        let retry = start + Duration::from_secs(1);
+        block_on(forwarder.forward(event(10), retry));
+        assert_eq!(sink.attempts.load(Ordering::Relaxed), 2);
+
+        block_on(forwarder.forward(event(11), retry + Duration::from_secs(1)));
+        assert_eq!(sink.attempts.load(Ordering::Relaxed), 2);
+
+        sink.failing.store(false, Ordering::Relaxed);
+        block_on(forwarder.forward(event(12), retry + Duration::from_secs(2)));
+        assert_eq!(forwarder.pending(), 0);
+
+        let stored: Vec<NodeID> = sink
+            .stored
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|event| event.info_hash.clone())
+            .collect();
+        let expected: Vec<NodeID> = (9..=12).map(|id| event(id).info_hash).collect();
+        assert_eq!(stored, expected);
+    }
+}
@@ -0,0 +1,724 @@
+//! Compact binary snapshots of the info-hashes found by the [`Crawler`], for
+//! crawls long enough that [`JsonLinesSink`] files get out of hand.
+//!
+//! A snapshot starts with [`MAGIC`], [`FORMAT_VERSION`] and the time the
+//! first event was discovered as a big endian `i64` of seconds since the Unix
+//! epoch. Every event follows as a record prefixed with its length as a
+//! varint. A record holds a flags byte, the seconds since the event before it
+//! (or the time in the header) as a zigzag encoded varint, the 20 byte
+//! info-hash, the source as 6 bytes for IPv4 or 18 for IPv6 and, if flagged,
+//! the client version prefixed with its length as a varint. Events can be
+//! discovered in any order.
+//!
+//! A record cut short by a crash is skipped when reading and cut off when the
+//! snapshot is opened for appending again.
+//!
+//! [`Crawler`]: crate::Crawler
+//! [`JsonLinesSink`]: super::JsonLinesSink
+
+use super::sink::{
+    InfoHashEvent,
+    InfoHashSink,
+    StoreFuture,
+};
+use crate::errors::{
+    Error,
+    ErrorKind,
+    Result,
+};
+use byteorder::{
+    BigEndian,
+    ByteOrder,
+};
+use chrono::{
+    TimeZone,
+    Utc,
+};
+use futures::future;
+use krpc_encoding::NodeID;
+use std::{
+    fs::{
+        File,
+        OpenOptions,
+    },
+    io::{
+        self,
+        BufRead,
+        BufReader,
+        BufWriter,
+        Read,
+        Seek,
+        SeekFrom,
+        Write,
+    },
+    net::{
+        Ipv4Addr,
+        Ipv6Addr,
+        SocketAddrV4,
+        SocketAddrV6,
+    },
+    path::Path,
+    sync::Mutex,
+};
+
+/// First bytes of every snapshot.
+pub const MAGIC: [u8; 7] = *b"DHTSNAP";
+
+/// Version of the format written, the only one read.
+pub const FORMAT_VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 16;
+
+/// Longest record read. Records are at most 49 bytes plus the client
+/// version.
+const MAX_RECORD_LEN: u64 = 1024;
+
+const FLAG_IPV6: u8 = 1;
+const FLAG_CLIENT_VERSION: u8 = 2;
+
+fn file_error(cause: io::Error) -> Error {
+    ErrorKind::SnapshotFileError { cause }.into()
+}
+
+/// Appends events to a snapshot. Records are buffered until [`flush`] or
+/// [`sync`], or until the writer is dropped.
+///
+/// [`flush`]: SnapshotWriter::flush
+/// [`sync`]: SnapshotWriter::sync
+pub struct SnapshotWriter {
+    file: BufWriter<File>,
+
+    /// Time of the last record, or the time in the header before any record.
+    /// `None` until the header is written.
+    time: Option<i64>,
+
+    /// Re-used to encode records.
+    record: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    /// Opens `path` for appending, creating it if it doesn't exist. A record
+    /// left incomplete by a crash is cut off, so new records follow the last
+    /// complete one. Fails if the file isn't a snapshot.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SnapshotWriter> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .map_err(file_error)?;
+
+        let (time, end) = {
+            let mut reader = SnapshotReader::new(BufReader::new(&file))?;
+            while let Some(record) = reader.next_record()? {
+                // Only the time matters here, which is read even from a record
+                // which is otherwise malformed.
+                if let Some(time) = &mut reader.time {
+                    let _ = decode_record(&record, time);
+                }
+            }
+
+            (reader.time, reader.complete_len())
+        };
+
+        file.set_len(end).map_err(file_error)?;
+        file.seek(SeekFrom::Start(end)).map_err(file_error)?;
+
+        Ok(SnapshotWriter {
+            file: BufWriter::new(file),
+            time,
+            record: Vec::new(),
+        })
+    }
+
+    /// Appends `event`. Times are kept to the second.
+    pub fn append(&mut self, event: &InfoHashEvent) -> Result<()> {
+        let discovered_at = event.discovered_at.timestamp();
+        let previous = match self.time {
+            Some(time) => time,
+            None => {
+                let mut header = [0u8; HEADER_LEN];
+                header[..MAGIC.len()].copy_from_slice(&MAGIC);
+                header[MAGIC.len()] = FORMAT_VERSION;
+                BigEndian::write_i64(&mut header[MAGIC.len() + 1..], discovered_at);
+                self.file.write_all(&header).map_err(file_error)?;
+                self.time = Some(discovered_at);
+
+                discovered_at
+            }
+        };
+
+        let delta = discovered_at
+            .checked_sub(previous)
+            .ok_or(ErrorKind::SnapshotTimestampOutOfRange)?;
+
+        self.record.clear();
+        encode_record(event, delta, &mut self.record);
+
+        let mut prefix = Vec::with_capacity(2);
+        write_varint(&mut prefix, self.record.len() as u64);
+        self.file.write_all(&prefix).map_err(file_error)?;
+        self.file.write_all(&self.record).map_err(file_error)?;
+        self.time = Some(discovered_at);
+
+        Ok(())
+    }
+
+    /// Writes buffered records to the file.
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush().map_err(file_error)
+    }
+
+    /// Writes buffered records to the file and syncs it to disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        self.file.get_ref().sync_all().map_err(file_error)
+    }
+}
+
+/// Reads the events of a snapshot, oldest first. An incomplete last record
+/// ends the events without an error. A malformed record is an error, after
+/// which reading goes on with the next record unless the lengths can't be
+/// trusted anymore.
+pub struct SnapshotReader<R> {
+    input: R,
+
+    /// Time in the header, `None` if there is no complete header.
+    start: Option<i64>,
+
+    /// Time of the last record read, which the next record's is relative to.
+    time: Option<i64>,
+
+    /// Length of the complete records read so far, with their prefixes.
+    complete: u64,
+
+    done: bool,
+}
+
+impl SnapshotReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SnapshotReader<BufReader<File>>> {
+        let file = File::open(path).map_err(file_error)?;
+
+        SnapshotReader::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> SnapshotReader<R> {
+    /// Reads the header from `input`. An empty `input` is an empty snapshot.
+    pub fn new(mut input: R) -> Result<SnapshotReader<R>> {
+        let mut header = [0u8; HEADER_LEN];
+        let start = match input.read_exact(&mut header) {
+            Ok(()) => {
+                if header[..MAGIC.len()] != MAGIC || header[MAGIC.len()] != FORMAT_VERSION {
+                    Err(ErrorKind::InvalidSnapshot)?;
+                }
+
+                Some(BigEndian::read_i64(&header[MAGIC.len() + 1..]))
+            }
+            // Nothing was appended yet, or the header was cut short.
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(cause) => return Err(file_error(cause)),
+        };
+
+        Ok(SnapshotReader {
+            input,
+            start,
+            time: start,
+            complete: 0,
+            done: false,
+        })
+    }
+
+    /// Length of the header and the complete records read so far.
+    fn complete_len(&self) -> u64 {
+        match self.start {
+            Some(_) => HEADER_LEN as u64 + self.complete,
+            None => 0,
+        }
+    }
+
+    /// Reads the next record, or `None` at the end of the snapshot, including
+    /// when the last record is incomplete.
+    fn next_record(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.start.is_none() {
+            return Ok(None);
+        }
+
+        let mut len = 0u64;
+        let mut prefix_len = 0;
+        loop {
+            let mut byte = [0u8];
+            match self.input.read_exact(&mut byte) {
+                Ok(()) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(cause) => return Err(file_error(cause)),
+            }
+
+            len |= u64::from(byte[0] & 0x7f) << (7 * prefix_len);
+            prefix_len += 1;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+
+            if len > MAX_RECORD_LEN {
+                Err(ErrorKind::InvalidSnapshot)?;
+            }
+        }
+
+        if len > MAX_RECORD_LEN {
+            Err(ErrorKind::InvalidSnapshot)?;
+        }
+
+        let mut record = vec![0u8; len as usize];
+        match self.input.read_exact(&mut record) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(cause) => return Err(file_error(cause)),
+        }
+        self.complete += prefix_len + len;
+
+        Ok(Some(record))
+    }
+}
+
+impl<R: Read> Iterator for SnapshotReader<R> {
+    type Item = Result<InfoHashEvent>;
+
+    fn next(&mut self) -> Option<Result<InfoHashEvent>> {
+        if self.done {
+            return None;
+        }
+
+        match self.next_record() {
+            Ok(Some(record)) => Some(decode_record(&record, self.time.as_mut()?)),
+            Ok(None) => None,
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// [`InfoHashSink`] appending to a snapshot. Buffered records are written
+/// and the file is synced to disk by [`close`] or when the sink is dropped.
+///
+/// [`close`]: SnapshotSink::close
+pub struct SnapshotSink {
+    writer: Mutex<SnapshotWriter>,
+}
+
+impl SnapshotSink {
+    /// Opens `path` like [`SnapshotWriter::open`].
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SnapshotSink> {
+        Ok(SnapshotSink {
+            writer: Mutex::new(SnapshotWriter::open(path)?),
+        })
+    }
+
+    /// Writes buffered records and syncs the file to disk.
+    pub fn close(&self) -> Result<()> {
+        self.writer.lock()?.sync()
+    }
+
+    fn store_now(&self, event: &InfoHashEvent) -> Result<()> {
+        self.writer.lock()?.append(event)
+    }
+}
+
+impl InfoHashSink for SnapshotSink {
+    fn store(&self, event: InfoHashEvent) -> StoreFuture<'_> {
+        Box::pin(future::ready(self.store_now(&event)))
+    }
+}
+
+impl Drop for SnapshotSink {
+    fn drop(&mut self) {
+        let writer = match self.writer.get_mut() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        writer
+            .sync()
+            .unwrap_or_else(|err| eprintln!("Error While Closing Snapshot Sink: {}", err));
+    }
+}
+
+/// Appends the events of the JSON lines in `input`, as written by
+/// [`JsonLinesSink`], to `writer`. Returns the number of events.
+///
+/// [`JsonLinesSink`]: super::JsonLinesSink
+pub fn jsonl_to_snapshot<R: BufRead>(input: R, writer: &mut SnapshotWriter) -> Result<usize> {
+    let mut events = 0;
+    for line in input.lines() {
+        let line = line.map_err(|cause| ErrorKind::StoreError { cause })?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        writer.append(&InfoHashEvent::from_json_line(&line)?)?;
+        events += 1;
+    }
+    writer.flush()?;
+
+    Ok(events)
+}
+
+/// Writes the events of `reader` to `output` as JSON lines, like
+/// [`JsonLinesSink`]. Returns the number of events.
+///
+/// [`JsonLinesSink`]: super::JsonLinesSink
+pub fn snapshot_to_jsonl<R: Read, W: Write>(
+    reader: SnapshotReader<R>,
+    mut output: W,
+) -> Result<usize> {
+    let mut events = 0;
+    for event in reader {
+        output
+            .write_all(event?.to_json_line().as_bytes())
+            .map_err(|cause| ErrorKind::StoreError { cause })?;
+        events += 1;
+    }
+    output
+        .flush()
+        .map_err(|cause| ErrorKind::StoreError { cause })?;
+
+    Ok(events)
+}
+
+fn encode_record(event: &InfoHashEvent, delta: i64, buf: &mut Vec<u8>) {
+    let flags = match event.client_version() {
+        Some(_) => FLAG_CLIENT_VERSION,
+        None => 0,
+    };
+
+    buf.push(flags);
+    write_varint(buf, zigzag(delta));
+    buf.extend_from_slice(&event.info_hash.to_bytes());
+    buf.extend_from_slice(&event.source.ip().octets());
+
+    let mut port = [0u8; 2];
+    BigEndian::write_u16(&mut port, event.source.port());
+    buf.extend_from_slice(&port);
+
+    if let Some(client_version) = event.client_version() {
+        write_varint(buf, client_version.len() as u64);
+        buf.extend_from_slice(client_version);
+    }
+}
+
+/// Decodes `record`, moving `time` on to the time of its event. The time
+/// comes right after the flags, so it moves on unless the record is cut
+/// short before.
+fn decode_record(mut record: &[u8], time: &mut i64) -> Result<InfoHashEvent> {
+    let flags = take(&mut record, 1)?[0];
+    let delta = unzigzag(read_varint(&mut record)?);
+    *time = time.checked_add(delta).ok_or(ErrorKind::InvalidSnapshot)?;
+    let discovered_at = Utc
+        .timestamp_opt(*time, 0)
+        .single()
+        .ok_or(ErrorKind::InvalidSnapshot)?;
+
+    if flags & !(FLAG_IPV6 | FLAG_CLIENT_VERSION) != 0 {
+        Err(ErrorKind::InvalidSnapshot)?;
+    }
+
+    let info_hash =
+        NodeID::try_from_bytes(take(&mut record, 20)?).map_err(|_| ErrorKind::InvalidSnapshot)?;
+
+    if flags & FLAG_IPV6 != 0 {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(take(&mut record, 16)?);
+        let port = BigEndian::read_u16(take(&mut record, 2)?);
+
+        Err(ErrorKind::UnsupportedAddressTypeError {
+            addr: SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0),
+        })?;
+    }
+
+    let ip = take(&mut record, 4)?;
+    let source = SocketAddrV4::new(
+        Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]),
+        BigEndian::read_u16(take(&mut record, 2)?),
+    );
+
+    let client_version = if flags & FLAG_CLIENT_VERSION != 0 {
+        let len = read_varint(&mut record)?;
+        Some(take(&mut record, len as usize)?.to_vec())
+    } else {
+        None
+    };
+
+    if !record.is_empty() {
+        Err(ErrorKind::InvalidSnapshot)?;
+    }
+
+    let mut event = InfoHashEvent::new(info_hash, source).with_client_version(client_version);
+    event.discovered_at = discovered_at;
+
+    Ok(event)
+}
+
+/// Maps small negative and positive numbers to small varints.
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+/// Splits the first `len` bytes off `record`.
+fn take<'a>(record: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if record.len() < len {
+        Err(ErrorKind::InvalidSnapshot)?;
+    }
+
+    let (taken, rest) = record.split_at(len);
+    *record = rest;
+
+    Ok(taken)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    buf.push(value as u8);
+}
+
+fn read_varint(record: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = take(record, 1)?[0];
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(ErrorKind::InvalidSnapshot.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        jsonl_to_snapshot,
+        snapshot_to_jsonl,
+        unzigzag,
+        zigzag,
+        SnapshotReader,
+        SnapshotWriter,
+        HEADER_LEN,
+    };
+    use crate::crawler::InfoHashEvent;
+    use chrono::{
+        TimeZone,
+        Utc,
+    };
+    use failure::Error;
+    use krpc_encoding::NodeID;
+    use std::{
+        env,
+        fs::{
+            self,
+            OpenOptions,
+        },
+        io::Cursor,
+        path::PathBuf,
+        process,
+    };
+
+    /// An event discovered `id` seconds into the crawl, with a client version
+    /// for odd ids.
+    fn event(id: u8) -> InfoHashEvent {
+        let version = if id % 2 == 1 {
+            Some(b"LT\x01\x02".to_vec())
+        } else {
+            None
+        };
+
+        let mut event = InfoHashEvent::new(
+            NodeID::random(),
+            format!("10.0.0.{}:6881", id).parse().unwrap(),
+        )
+        .with_client_version(version);
+        event.discovered_at = Utc.timestamp(1_560_000_000 + i64::from(id), 0);
+
+        event
+    }
+
+    fn snapshot_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("dht_crawler-{}-{}.snapshot", name, process::id()));
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    fn read_all(path: &PathBuf) -> Result<Vec<InfoHashEvent>, Error> {
+        let events: Result<Vec<InfoHashEvent>, _> = SnapshotReader::open(path)?.collect();
+
+        Ok(events?)
+    }
+
+    #[test]
+    fn round_trip() -> Result<(), Error> {
+        let path = snapshot_path("round-trip");
+        let events: Vec<InfoHashEvent> = (0..6).map(event).collect();
+
+        let mut writer = SnapshotWriter::open(&path)?;
+        for event in &events[..4] {
+            writer.append(event)?;
+        }
+        writer.sync()?;
+        drop(writer);
+
+        // Appending resumes after the records already written.
+        let mut writer = SnapshotWriter::open(&path)?;
+        for event in &events[4..] {
+            writer.append(event)?;
+        }
+        writer.sync()?;
+
+        assert_eq!(read_all(&path)?, events);
+
+        // 29 bytes an event without a client version, 34 with one.
+        assert_eq!(
+            fs::metadata(&path)?.len(),
+            HEADER_LEN as u64 + 3 * 29 + 3 * 34
+        );
+
+        fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn out_of_order_events_kept() -> Result<(), Error> {
+        let path = snapshot_path("out-of-order");
+        let events: Vec<InfoHashEvent> = [5, 2, 0, 7, 7, 1].iter().cloned().map(event).collect();
+
+        let mut writer = SnapshotWriter::open(&path)?;
+        for event in &events[..3] {
+            writer.append(event)?;
+        }
+        drop(writer);
+
+        // Times of the records after reopening follow the last one.
+        let mut writer = SnapshotWriter::open(&path)?;
+        for event in &events[3..] {
+            writer.append(event)?;
+        }
+        writer.sync()?;
+        assert_eq!(read_all(&path)?, events);
+
+        fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn torn_write_recovered() -> Result<(), Error> {
+        let path = snapshot_path("torn");
+        let events: Vec<InfoHashEvent> = (0..5).map(event).collect();
+
+        let mut writer = SnapshotWriter::open(&path)?;
+        for event in &events {
+            writer.append(event)?;
+        }
+        writer.sync()?;
+        drop(writer);
+
+        // Cut the last record in the middle, like a crash while writing it.
+        let len = fs::metadata(&path)?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(len - 10)?;
+        assert_eq!(read_all(&path)?, &events[..4]);
+
+        // The rest of the record is dropped before appending.
+        let mut writer = SnapshotWriter::open(&path)?;
+        let resumed = event(7);
+        writer.append(&resumed)?;
+        writer.sync()?;
+
+        let mut expected = events[..4].to_vec();
+        expected.push(resumed);
+        assert_eq!(read_all(&path)?, expected);
+
+        fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn torn_header_is_empty() -> Result<(), Error> {
+        let path = snapshot_path("torn-header");
+        fs::write(&path, b"DHTSN")?;
+        assert!(read_all(&path)?.is_empty());
+
+        let mut writer = SnapshotWriter::open(&path)?;
+        writer.append(&event(0))?;
+        writer.sync()?;
+        assert_eq!(read_all(&path)?.len(), 1);
+
+        fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn other_files_rejected() -> Result<(), Error> {
+        let path = snapshot_path("other");
+        let line = event(1).to_json_line();
+        fs::write(&path, &line)?;
+
+        assert!(SnapshotWriter::open(&path).is_err());
+        assert!(SnapshotReader::open(&path).is_err());
+        assert_eq!(fs::read_to_string(&path)?, line);
+
+        fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn converts_json_lines() -> Result<(), Error> {
+        let path = snapshot_path("convert");
+        let json_lines: String = [3, 1, 4, 0, 2]
+            .iter()
+            .map(|id| event(*id).to_json_line())
+            .collect();
+
+        let mut writer = SnapshotWriter::open(&path)?;
+        assert_eq!(jsonl_to_snapshot(Cursor::new(&json_lines), &mut writer)?, 5);
+        drop(writer);
+
+        let mut converted = Vec::new();
+        assert_eq!(
+            snapshot_to_jsonl(SnapshotReader::open(&path)?, &mut converted)?,
+            5
+        );
+        assert_eq!(String::from_utf8(converted)?, json_lines);
+
+        let mut writer = SnapshotWriter::open(&path)?;
+        assert!(jsonl_to_snapshot(Cursor::new("{\"info_hash\":\"nope\"}\n"), &mut writer).is_err());
+
+        fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn zigzag_round_trip() {
+        for value in &[0, 1, -1, 63, -64, i64::max_value(), i64::min_value()] {
+            assert_eq!(unzigzag(zigzag(*value)), *value);
+        }
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    collections::VecDeque,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// Queries sent longer ago than this aren't counted in the current rate.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Keeps queries under a target rate, measured over the last second.
+///
+/// Call [`tick`] whenever a query is sent and wait for the returned delay
+/// before sending the next one. The delay spreads the queries in the window
+/// so they would have been sent at the target rate, which paces queries
+/// evenly instead of sending a second's worth in bursts.
+///
+/// [`tick`]: AdaptiveThrottle::tick
+#[derive(Debug)]
+pub struct AdaptiveThrottle {
+    target_qps: f64,
+
+    /// When each query in the window was sent, oldest first.
+    sent: VecDeque<Instant>,
+}
+
+impl AdaptiveThrottle {
+    pub fn new(target_qps: f64) -> AdaptiveThrottle {
+        assert!(target_qps > 0.0, "target_qps must be positive");
+
+        AdaptiveThrottle {
+            target_qps,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Records a query sent now and returns how long to wait before sending
+    /// the next one. Zero when the queries of the last second are already
+    /// further apart than the target rate needs.
+    pub fn tick(&mut self) -> Duration {
+        self.tick_at(Instant::now())
+    }
+
+    /// Queries sent per second, over the last second.
+    pub fn current_qps(&self) -> f64 {
+        self.sent.len() as f64
+    }
+
+    fn tick_at(&mut self, now: Instant) -> Duration {
+        while let Some(sent) = self.sent.front() {
+            if now.duration_since(*sent) < WINDOW {
+                break;
+            }
+            self.sent.pop_front();
+        }
+        self.sent.push_back(now);
+
+        // At the target rate, the queries in the window would be spread over
+        // this long after the oldest one.
+        let spread = Duration::from_nanos((self.sent.len() as f64 / self.target_qps * 1e9) as u64);
+        let resume = self.sent[0] + spread;
+
+        if resume > now {
+            resume - now
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AdaptiveThrottle;
+    use std::time::{
+        Duration,
+        Instant,
+    };
+
+    /// Sends 100 queries waiting as told, each taking `query_time`, and
+    /// returns the rate they were sent at.
+    fn measured_qps(target_qps: f64, query_time: Duration) -> f64 {
+        let mut throttle = AdaptiveThrottle::new(target_qps);
+        let start = Instant::now();
+        let mut now = start;
+
+        for _ in 0..99 {
+            let delay = throttle.tick_at(now);
+            now += delay.max(query_time);
+        }
+        throttle.tick_at(now);
+
+        let elapsed = now.duration_since(start);
+        99.0 / (elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9)
+    }
+
+    #[test]
+    fn rate_near_target() {
+        for target_qps in &[0.5, 5.0, 50.0, 400.0] {
+            let qps = measured_qps(*target_qps, Duration::from_micros(100));
+            assert!(
+                (qps - target_qps).abs() <= target_qps * 0.1,
+                "target {} measured {}",
+                target_qps,
+                qps
+            );
+        }
+    }
+
+    #[test]
+    fn no_delay_under_target() {
+        let mut throttle = AdaptiveThrottle::new(100.0);
+        let start = Instant::now();
+
+        // The delay is over by the time of the next query.
+        for idx in 0..50 {
+            let delay = throttle.tick_at(start + Duration::from_millis(20 * idx));
+            assert!(delay <= Duration::from_millis(10), "{:?}", delay);
+        }
+        assert_eq!(throttle.current_qps(), 50.0);
+
+        // Slow queries aren't made up for with bursts later.
+        let qps = measured_qps(50.0, Duration::from_millis(40));
+        assert!((qps - 25.0).abs() < 0.01, "{}", qps);
+    }
+}
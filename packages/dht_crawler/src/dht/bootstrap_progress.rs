@@ -0,0 +1,426 @@
+//! Bootstraps step by step, reporting how far it got. Bootstrapping can
+//! take tens of seconds, which looks like a hang without feedback.
+
+use super::{
+    lookups::LOOKUP_TIMEOUT,
+    Dht,
+};
+use crate::{
+    bootstrap::{
+        resolve_mixed,
+        Router,
+        SystemResolver,
+        RESOLVE_TIMEOUT,
+    },
+    lookup::{
+        Lookup,
+        LookupConfig,
+    },
+    routing::Node,
+};
+use futures::{
+    future,
+    stream,
+    Stream,
+};
+use std::{
+    collections::VecDeque,
+    net::{
+        SocketAddr,
+        SocketAddrV4,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::prelude::FutureExt;
+
+/// Step of a bootstrap emitted by [`Dht::bootstrap_with_progress`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum BootstrapEvent {
+    /// `router` resolved to `address`, once for each of its IPv4 addresses.
+    RouterResolved {
+        router: String,
+        address: SocketAddrV4,
+    },
+
+    /// `router` didn't resolve to any IPv4 address, or none of its
+    /// addresses answered.
+    RouterUnreachable { router: String },
+
+    /// `count` nodes were added to the routing table, which now holds
+    /// `total`.
+    NodesDiscovered { count: usize, total: usize },
+
+    /// The routing table split a bucket and now has `depth` buckets.
+    BucketSplit { depth: usize },
+
+    /// The lookup of our own id converged. Last event of the stream.
+    Completed { nodes: usize, duration: Duration },
+
+    /// Bootstrapping gave up. Last event of the stream.
+    Failed(BootstrapFailure),
+}
+
+/// Why [`Dht::bootstrap_with_progress`] gave up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootstrapFailure {
+    /// None of the routers answered, so there is nobody to look up our id
+    /// through.
+    NoRoutersReachable,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Resolving,
+    QueryingRouters,
+    LookingUp,
+    Done,
+}
+
+/// State of a bootstrap between events of its stream.
+struct BootstrapProgress {
+    dht: Dht,
+    stage: Stage,
+    started: Instant,
+
+    /// Routers still to be resolved.
+    routers: VecDeque<String>,
+
+    /// Addresses resolved, along with the router each belongs to.
+    seeds: Vec<(String, SocketAddrV4)>,
+
+    /// Lookup of our own id, once the routers answered.
+    lookup: Lookup,
+    deadline: Instant,
+
+    /// Size and depth of the routing table when last reported.
+    nodes: usize,
+    depth: usize,
+
+    /// Events not emitted yet.
+    ready: VecDeque<BootstrapEvent>,
+}
+
+impl BootstrapProgress {
+    async fn resolve(&mut self, router: String) {
+        let addresses: Vec<SocketAddrV4> = match router.parse::<Router>() {
+            Ok(parsed) => resolve_mixed(&SystemResolver, &[parsed], RESOLVE_TIMEOUT)
+                .await
+                .into_iter()
+                .filter_map(|address| match address {
+                    SocketAddr::V4(address) => Some(address),
+                    SocketAddr::V6(_) => None,
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        if addresses.is_empty() {
+            self.ready
+                .push_back(BootstrapEvent::RouterUnreachable { router });
+            return;
+        }
+
+        for address in addresses {
+            self.ready.push_back(BootstrapEvent::RouterResolved {
+                router: router.clone(),
+                address,
+            });
+            self.seeds.push((router.clone(), address));
+        }
+    }
+
+    /// Asks every resolved address for the nodes closest to our id and adds
+    /// the ones which answered to the routing table. Returns whether any
+    /// did.
+    async fn query_routers(&mut self) -> bool {
+        let dht = &self.dht;
+        let results = future::join_all(self.seeds.iter().map(|(_, seed)| {
+            dht.send_transport
+                .find_node(dht.id(), (*seed).into(), dht.id())
+                .timeout(LookupConfig::default().round_timeout)
+        }))
+        .await;
+
+        let mut responders = Vec::new();
+        let mut reached: Vec<&str> = Vec::new();
+        for ((router, seed), result) in self.seeds.iter().zip(results) {
+            if let Ok(Ok(response)) = result {
+                let responder = Node::new(response.id, *seed);
+                responder.mark_successful_request();
+                responders.push(responder);
+                reached.push(router);
+            }
+        }
+
+        let mut unreachable: Vec<&str> = Vec::new();
+        for (router, _) in &self.seeds {
+            if !reached.contains(&router.as_str()) && !unreachable.contains(&router.as_str()) {
+                unreachable.push(router);
+            }
+        }
+        for router in unreachable {
+            self.ready.push_back(BootstrapEvent::RouterUnreachable {
+                router: router.to_string(),
+            });
+        }
+
+        if responders.is_empty() {
+            return false;
+        }
+
+        if let Ok(mut routing_table) = self.dht.routing_table.lock() {
+            routing_table.add_nodes(responders);
+        }
+
+        true
+    }
+
+    /// Reports the nodes and buckets the routing table gained since last
+    /// time.
+    fn report_growth(&mut self) {
+        let (nodes, depth) = match self.dht.routing_table.lock() {
+            Ok(routing_table) => (routing_table.len(), routing_table.depth()),
+            Err(_) => return,
+        };
+
+        if nodes > self.nodes {
+            self.ready.push_back(BootstrapEvent::NodesDiscovered {
+                count: nodes - self.nodes,
+                total: nodes,
+            });
+        }
+        for depth in self.depth + 1..=depth {
+            self.ready.push_back(BootstrapEvent::BucketSplit { depth });
+        }
+
+        self.nodes = nodes;
+        self.depth = depth;
+    }
+}
+
+async fn next_event(mut state: BootstrapProgress) -> Option<(BootstrapEvent, BootstrapProgress)> {
+    loop {
+        if let Some(event) = state.ready.pop_front() {
+            return Some((event, state));
+        }
+
+        if state.dht.shutdown.is_triggered() {
+            return None;
+        }
+
+        match state.stage {
+            Stage::Resolving => match state.routers.pop_front() {
+                Some(router) => state.resolve(router).await,
+                None => state.stage = Stage::QueryingRouters,
+            },
+            Stage::QueryingRouters => {
+                if state.query_routers().await {
+                    state.report_growth();
+                    if state.dht.seed_node_lookup(&mut state.lookup).is_err() {
+                        return None;
+                    }
+
+                    state.deadline = Instant::now() + LOOKUP_TIMEOUT;
+                    state.stage = Stage::LookingUp;
+                } else {
+                    state
+                        .ready
+                        .push_back(BootstrapEvent::Failed(BootstrapFailure::NoRoutersReachable));
+                    state.stage = Stage::Done;
+                }
+            }
+            Stage::LookingUp => {
+                let deadline = state.deadline;
+                let more = state
+                    .dht
+                    .node_lookup_round(&mut state.lookup, deadline)
+                    .await;
+                state.report_growth();
+
+                if !more {
+                    if state.dht.evict_queued().await.is_err() {
+                        return None;
+                    }
+
+                    state.ready.push_back(BootstrapEvent::Completed {
+                        nodes: state.nodes,
+                        duration: state.started.elapsed(),
+                    });
+                    state.stage = Stage::Done;
+                }
+            }
+            Stage::Done => return None,
+        }
+    }
+}
+
+impl Dht {
+    /// Bootstraps like [`bootstrap`] from `routers`, given as `host:port` or
+    /// `ip:port`, reporting each step. The routers are resolved and asked for
+    /// the nodes closest to our id, then our id is looked up through the
+    /// ones which answered. The stream ends after
+    /// [`BootstrapEvent::Completed`], or [`BootstrapEvent::Failed`] when no
+    /// router answered. Host names are resolved with the
+    /// [`SystemResolver`].
+    ///
+    /// [`bootstrap`]: Dht::bootstrap
+    /// [`SystemResolver`]: crate::bootstrap::SystemResolver
+    pub fn bootstrap_with_progress(&self, routers: &[&str]) -> impl Stream<Item = BootstrapEvent> {
+        let (nodes, depth) = match self.routing_table.lock() {
+            Ok(routing_table) => (routing_table.len(), routing_table.depth()),
+            Err(_) => (0, 1),
+        };
+
+        let state = BootstrapProgress {
+            dht: self.clone(),
+            stage: Stage::Resolving,
+            started: Instant::now(),
+            routers: routers.iter().map(|router| router.to_string()).collect(),
+            seeds: Vec::new(),
+            lookup: Lookup::new(self.id(), LookupConfig::default()),
+            deadline: Instant::now() + LOOKUP_TIMEOUT,
+            nodes,
+            depth,
+            ready: VecDeque::new(),
+        };
+
+        stream::unfold(state, next_event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BootstrapEvent,
+        BootstrapFailure,
+    };
+    use crate::{
+        testing::{
+            MockTransport,
+            Reply,
+        },
+        Dht,
+    };
+    use failure::Error;
+    use futures::StreamExt;
+    use krpc_encoding::{
+        NodeID,
+        NodeInfo,
+        Response,
+    };
+    use std::{
+        net::SocketAddrV4,
+        sync::Arc,
+    };
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn all_routers_down() -> Result<(), Error> {
+        // Nothing answers.
+        let dht = Dht::with_transport(NodeID::random(), Arc::new(MockTransport::new()));
+
+        let events: Vec<BootstrapEvent> = Runtime::new()?.block_on(
+            dht.bootstrap_with_progress(&["10.0.0.1:6881", "not a router"])
+                .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(
+            events,
+            vec![
+                BootstrapEvent::RouterResolved {
+                    router: "10.0.0.1:6881".to_string(),
+                    address: "10.0.0.1:6881".parse()?,
+                },
+                BootstrapEvent::RouterUnreachable {
+                    router: "not a router".to_string(),
+                },
+                BootstrapEvent::RouterUnreachable {
+                    router: "10.0.0.1:6881".to_string(),
+                },
+                BootstrapEvent::Failed(BootstrapFailure::NoRoutersReachable),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn happy_path() -> Result<(), Error> {
+        let mock = Arc::new(MockTransport::new());
+        let nodes: Vec<NodeInfo> = (1..=20)
+            .map(|idx| {
+                NodeInfo::new(
+                    NodeID::random(),
+                    SocketAddrV4::new([10, 0, idx, 1].into(), 6881),
+                )
+            })
+            .collect();
+        for node in &nodes {
+            let id = node.node_id.clone();
+            mock.respond(node.address.into(), move |_| {
+                Reply::Response(Response::NextHop {
+                    id: id.clone(),
+                    token: None,
+                    nodes: Vec::new(),
+                })
+            });
+        }
+
+        let router_id = NodeID::random();
+        mock.respond("10.0.0.1:6881".parse()?, move |_| {
+            Reply::Response(Response::NextHop {
+                id: router_id.clone(),
+                token: None,
+                nodes: nodes.clone(),
+            })
+        });
+
+        let dht = Dht::with_transport(NodeID::random(), mock);
+        let events: Vec<BootstrapEvent> = Runtime::new()?.block_on(
+            dht.bootstrap_with_progress(&["10.0.0.1:6881"])
+                .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(
+            events[..2],
+            [
+                BootstrapEvent::RouterResolved {
+                    router: "10.0.0.1:6881".to_string(),
+                    address: "10.0.0.1:6881".parse()?,
+                },
+                BootstrapEvent::NodesDiscovered { count: 1, total: 1 },
+            ]
+        );
+
+        // The lookup adds the nodes which answered, enough to split the
+        // first bucket.
+        let mut discovered = 0;
+        let mut last_total = 0;
+        for event in &events[1..events.len() - 1] {
+            match event {
+                BootstrapEvent::NodesDiscovered { count, total } => {
+                    discovered += count;
+                    assert!(*total > last_total);
+                    last_total = *total;
+                }
+                BootstrapEvent::BucketSplit { .. } => {}
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        assert!(discovered > 8);
+        assert!(events.contains(&BootstrapEvent::BucketSplit { depth: 2 }));
+
+        match events.last() {
+            Some(BootstrapEvent::Completed { nodes, .. }) => {
+                assert_eq!(*nodes, discovered);
+                assert_eq!(*nodes, dht.routing_table.lock().unwrap().len());
+            }
+            event => panic!("bootstrap didn't complete: {:?}", event),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use super::{
+    external_address::{
+        Identity,
+        EXTERNAL_IP_VOTES,
+    },
+    Dht,
+};
+use crate::{
+    errors::{
+        ErrorKind,
+        Result,
+    },
+    identity::{
+        IdentityConfig,
+        IdentityStore,
+    },
+};
+use futures::{
+    future,
+    TryStreamExt,
+};
+use krpc_encoding::NodeID;
+use std::{
+    net::SocketAddr,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+use tokio::net::UdpSocket;
+use tokio_krpc::{
+    external_ip::ExternalIpObserver,
+    KRPCNode,
+};
+
+/// Options for a [`Dht`] with a socket of its own. See [`Dht::builder`].
+pub struct DhtBuilder {
+    bind_addr: SocketAddr,
+    id: Option<NodeID>,
+    identity_file: Option<PathBuf>,
+}
+
+impl DhtBuilder {
+    pub(super) fn new(bind_addr: SocketAddr) -> DhtBuilder {
+        DhtBuilder {
+            bind_addr,
+            id: None,
+            identity_file: None,
+        }
+    }
+
+    /// Starts with `id` instead of a random id. Ignored when an
+    /// [`identity_file`] is set.
+    ///
+    /// [`identity_file`]: DhtBuilder::identity_file
+    pub fn id(mut self, id: NodeID) -> DhtBuilder {
+        self.id = Some(id);
+        self
+    }
+
+    /// Keeps the id in the file at `path` across restarts, see
+    /// [`IdentityStore`]. The file is created with a random id if there is
+    /// none. When our external address changes and the id isn't valid for it
+    /// anymore, the new id is written to the file too.
+    ///
+    /// Votes from [`EXTERNAL_IP_VOTES`] nodes are already needed to change
+    /// the external address, so the store believes every change straight
+    /// away.
+    pub fn identity_file<P: AsRef<Path>>(mut self, path: P) -> DhtBuilder {
+        self.identity_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Binds the socket and starts handling inbound messages from other peers
+    /// in the network. Continues to handle while the future is polled, until
+    /// [`Dht::shutdown`]. Dropping the future stops handling too.
+    ///
+    /// Once [`EXTERNAL_IP_VOTES`] nodes agree on our external address, an
+    /// id which isn't valid for it under [BEP-0042] is replaced with
+    /// [`NodeID::secure`] and the routing table starts over.
+    ///
+    /// [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+    pub fn start(self) -> Result<(Dht, impl future::Future<Output = ()>)> {
+        let identity = match self.identity_file {
+            Some(path) => Identity::with_store(IdentityStore::open(
+                path,
+                IdentityConfig {
+                    confirmations: 1,
+                    ..IdentityConfig::default()
+                },
+            )?),
+            None => Identity::new(self.id.unwrap_or_else(NodeID::random)),
+        };
+
+        let socket =
+            UdpSocket::bind(&self.bind_addr).map_err(|cause| ErrorKind::BindError { cause })?;
+        let local_address = match socket.local_addr() {
+            Ok(SocketAddr::V4(address)) => Some(address),
+            _ => None,
+        };
+
+        let observer = Arc::new(Mutex::new(ExternalIpObserver::new(EXTERNAL_IP_VOTES)));
+        let transport = KRPCNode::new(socket).with_external_ip_observer(observer, {
+            let identity = identity.clone();
+            move |address| identity.external_address_changed(address.into())
+        });
+        let (send_transport, request_stream) = transport.serve();
+
+        let mut dht = Dht::with_identity(identity, Arc::new(send_transport));
+        dht.local_address = local_address;
+
+        Ok((dht.clone(), dht.handle_requests(request_stream.err_into())))
+    }
+}
@@ -0,0 +1,104 @@
+use crate::{
+    identity::IdentityStore,
+    routing::RoutingTable,
+};
+use krpc_encoding::NodeID;
+use std::{
+    net::SocketAddrV4,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+
+/// Votes an address needs, each from a different /24 subnet, before it
+/// becomes our external address. See [`ExternalIpObserver`].
+///
+/// [`ExternalIpObserver`]: tokio_krpc::external_ip::ExternalIpObserver
+pub const EXTERNAL_IP_VOTES: usize = 10;
+
+/// Parts of a [`Dht`] which change along with our external address. Shared
+/// with the receive loop, which learns the address before the [`Dht`] is
+/// built.
+///
+/// [`Dht`]: crate::Dht
+#[derive(Clone)]
+pub(super) struct Identity {
+    pub id: Arc<Mutex<NodeID>>,
+    pub routing_table: Arc<Mutex<RoutingTable>>,
+    pub external_address: Arc<Mutex<Option<SocketAddrV4>>>,
+
+    /// Picks new ids in place of [BEP-0042] alone and keeps them across
+    /// restarts, if set.
+    ///
+    /// [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+    pub store: Option<Arc<Mutex<IdentityStore>>>,
+}
+
+impl Identity {
+    pub fn new(id: NodeID) -> Identity {
+        let routing_table = RoutingTable::new(id.clone());
+
+        Identity {
+            id: Arc::new(Mutex::new(id)),
+            routing_table: Arc::new(Mutex::new(routing_table)),
+            external_address: Arc::new(Mutex::new(None)),
+            store: None,
+        }
+    }
+
+    /// Starts with the id kept in `store`.
+    pub fn with_store(store: IdentityStore) -> Identity {
+        let id = store.id().clone();
+
+        Identity {
+            store: Some(Arc::new(Mutex::new(store))),
+            ..Identity::new(id)
+        }
+    }
+
+    /// Records a new consensus on our external address. An id which isn't
+    /// valid for the new IP under [BEP-0042] is replaced with one which is,
+    /// and the routing table, laid out around the old id, starts over.
+    ///
+    /// [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+    pub fn external_address_changed(&self, address: SocketAddrV4) {
+        *self
+            .external_address
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(address);
+
+        let new_id = {
+            let mut id = self
+                .id
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let new_id = match &self.store {
+                Some(store) => {
+                    let mut store = store
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                    // The store switches to the new id even when the file
+                    // can't be written, only the next restart loses it.
+                    let _ = store.observe_external_ip(*address.ip());
+                    store.id().clone()
+                }
+                None if id.is_secure_for(*address.ip()) => return,
+                None => NodeID::secure(*address.ip()),
+            };
+            if *id == new_id {
+                return;
+            }
+
+            *id = new_id;
+            id.clone()
+        };
+
+        // Not locked along with the id, so nothing holding the routing table
+        // while reading the id can deadlock with us.
+        self.routing_table
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clear_and_reset(new_id);
+    }
+}
@@ -1,6 +1,12 @@
 use crate::{
     addr::AsV4Address,
-    dht::Dht,
+    dht::{
+        quotas::{
+            AnnounceQuotas,
+            Stored,
+        },
+        Dht,
+    },
     errors::{
         Error,
         ErrorKind,
@@ -12,6 +18,7 @@ use crate::{
     },
 };
 use futures::{
+    Future,
     TryStream,
     TryStreamExt,
 };
@@ -24,35 +31,59 @@ use krpc_encoding::{
     Query,
     Response,
 };
+use rand::{
+    self,
+    seq::sample_iter,
+};
 use std::{
+    collections::HashMap,
     net::{
+        IpAddr,
         SocketAddr,
         SocketAddrV4,
     },
     ops::DerefMut,
+    time::Instant,
 };
 use tokio_krpc::InboundQuery;
 
+/// Most info-hashes sent in a `sample_infohashes` response, about as many
+/// as fit in a UDP packet along with the nodes.
+const MAX_SAMPLES: usize = 20;
+
+/// Seconds a node sampling us is asked to wait before sampling again.
+const SAMPLE_INTERVAL_SECS: u16 = 60;
+
 impl Dht {
-    pub(super) async fn handle_requests<
-        S: TryStream<Ok = (InboundQuery, SocketAddr), Error = Error>,
-    >(
+    pub(super) fn handle_requests<S: TryStream<Ok = (InboundQuery, SocketAddr), Error = Error>>(
         self,
         stream: S,
-    ) {
-        let mut stream = stream.into_stream().boxed();
+    ) -> impl Future<Output = ()> {
+        let task = self.shutdown.register_task();
+
+        async move {
+            let _task = task;
+            let mut stream = stream.into_stream().boxed();
 
-        loop {
-            let (head, tail) = stream.into_future().await;
-            if let Some(result) = head {
-                self.process_request(result)
+            loop {
+                let (head, tail) = match self
+                    .shutdown
+                    .run_until_triggered(stream.into_future())
                     .await
-                    .unwrap_or_else(|err| eprintln!("Error While Handling Requests: {}", err));
-            } else {
-                return;
-            }
+                {
+                    Some(next) => next,
+                    None => return,
+                };
+                if let Some(result) = head {
+                    self.process_request(result)
+                        .await
+                        .unwrap_or_else(|err| eprintln!("Error While Handling Requests: {}", err));
+                } else {
+                    return;
+                }
 
-            stream = tail
+                stream = tail
+            }
         }
     }
 
@@ -64,46 +95,74 @@ impl Dht {
         Ok(())
     }
 
-    fn handle_request(&self, request: InboundQuery, from: SocketAddrV4) -> Envelope {
-        let result = match request.query {
-            Query::Ping { id } => self.handle_ping(from, id, request.read_only),
-            Query::FindNode { id, target } => {
-                self.handle_find_node(from, id, target, request.read_only)
-            }
-            Query::GetPeers { id, info_hash } => {
-                self.handle_get_peers(from, id, info_hash, request.read_only)
-            }
-            Query::AnnouncePeer {
-                id,
-                implied_port,
-                port,
-                info_hash,
-                token,
-            } => self.handle_announce_peer(
-                from,
-                id,
-                implied_port,
-                port,
-                info_hash,
-                token,
-                request.read_only,
-            ),
-            _ => Err(ErrorKind::UnimplementedRequestType.into()),
-        };
+    /// Answers `request` sent by the node at `from`. A query retransmitted
+    /// within [`RESPONSE_CACHE_TTL`] gets the same response again.
+    ///
+    /// [`RESPONSE_CACHE_TTL`]: crate::dht::RESPONSE_CACHE_TTL
+    pub(crate) fn handle_request(&self, request: InboundQuery, from: SocketAddrV4) -> Envelope {
+        let now = Instant::now();
+        self.reachability
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .record_inbound(now);
 
-        let message_type = match result {
+        if let Some(response) = self.lock_response_cache().get(
+            from,
+            &request.transaction_id,
+            &request.query,
+            request.read_only,
+            now,
+        ) {
+            return response;
+        }
+
+        let query = request.query.clone();
+        let message_type = match self.answer(request.query, from, request.read_only) {
             Ok(response) => Message::Response { response },
             Err(err) => Message::Error {
                 error: err.as_request_error(),
             },
         };
 
-        Envelope {
+        let response = Envelope {
             ip: None,
             transaction_id: request.transaction_id,
             version: None,
             message_type,
             read_only: false,
+        };
+        self.lock_response_cache()
+            .insert(from, query, request.read_only, response.clone(), now);
+
+        response
+    }
+
+    /// Response to `query` sent by the node at `from`, or the error to send
+    /// back.
+    pub(crate) fn answer(
+        &self,
+        query: Query,
+        from: SocketAddrV4,
+        read_only: bool,
+    ) -> Result<Response> {
+        match query {
+            Query::Ping { id } => self.handle_ping(from, id, read_only),
+            Query::FindNode { id, target } => self.handle_find_node(from, id, target, read_only),
+            Query::GetPeers { id, info_hash } => {
+                self.handle_get_peers(from, id, info_hash, read_only)
+            }
+            Query::AnnouncePeer {
+                id,
+                implied_port,
+                port,
+                info_hash,
+                token,
+            } => {
+                self.handle_announce_peer(from, id, implied_port, port, info_hash, token, read_only)
+            }
+            Query::SampleInfoHashes { id, target } => {
+                self.handle_sample_infohashes(from, id, target, read_only)
+            }
         }
     }
 
@@ -111,9 +170,7 @@ impl Dht {
         let mut routing_table = self.routing_table.lock()?;
         record_request(&mut routing_table, id, from, read_only)?;
 
-        Ok(Response::OnlyID {
-            id: self.id.clone(),
-        })
+        Ok(Response::OnlyID { id: self.id() })
     }
 
     fn handle_find_node(
@@ -132,7 +189,7 @@ impl Dht {
         };
 
         Ok(Response::NextHop {
-            id: self.id.clone(),
+            id: self.id(),
             token: None,
             nodes,
         })
@@ -148,28 +205,62 @@ impl Dht {
         let mut routing_table = self.routing_table.lock()?;
         record_request(&mut routing_table, id, from, read_only)?;
 
+        self.rotate_tokens(&mut routing_table)?;
         let token_bytes = routing_table.generate_token(&from).to_vec();
         let token = Some(token_bytes);
-        let torrents = self.torrents.lock()?;
+        let mut torrents = self.torrents.lock()?;
+        expire_peers(
+            &mut torrents,
+            &mut self.announce_quotas.lock()?,
+            Instant::now(),
+        );
         let torrent = torrents.get(&info_hash);
 
         if let Some(peers) = torrent {
             Ok(Response::GetPeers {
-                id: self.id.clone(),
+                id: self.id(),
                 token,
                 peers: peers.iter().map(|peer| Addr::from(peer.clone())).collect(),
+                nodes: Vec::new(),
             })
         } else {
             let nodes = routing_table.find_nodes(&info_hash);
 
             Ok(Response::NextHop {
-                id: self.id.clone(),
+                id: self.id(),
                 token,
                 nodes,
             })
         }
     }
 
+    fn handle_sample_infohashes(
+        &self,
+        from: SocketAddrV4,
+        id: NodeID,
+        target: NodeID,
+        read_only: bool,
+    ) -> Result<Response> {
+        let mut routing_table = self.routing_table.lock()?;
+        record_request(&mut routing_table, id, from, read_only)?;
+
+        let torrents = self.torrents.lock()?;
+        let samples = sample_iter(
+            &mut rand::thread_rng(),
+            torrents.keys().cloned(),
+            MAX_SAMPLES,
+        )
+        .unwrap_or_else(|all| all);
+
+        Ok(Response::Samples {
+            id: self.id(),
+            interval: Some(SAMPLE_INTERVAL_SECS),
+            nodes: routing_table.find_nodes(&target),
+            num: Some(torrents.len() as u32),
+            samples,
+        })
+    }
+
     fn handle_announce_peer(
         &self,
         mut from: SocketAddrV4,
@@ -180,12 +271,26 @@ impl Dht {
         token: Vec<u8>,
         read_only: bool,
     ) -> Result<Response> {
+        // Read only nodes aren't supposed to announce. See BEP-0043.
+        if read_only {
+            return Err(ErrorKind::ReadOnlyAnnounce)?;
+        }
+
         let mut routing_table = self.routing_table.lock()?;
 
+        self.rotate_tokens(&mut routing_table)?;
         if !routing_table.verify_token(&token, &from) {
             return Err(ErrorKind::InvalidToken)?;
         };
 
+        // The token is only used up once the announce is accepted, so a
+        // rejected announce can be retried with it.
+        let mut used_tokens = self.used_tokens.lock()?;
+        let token_ip = IpAddr::V4(*from.ip());
+        if used_tokens.is_replayed(&token, token_ip) {
+            return Err(ErrorKind::TokenReused)?;
+        }
+
         let addr = if implied_port {
             from
         } else {
@@ -201,15 +306,66 @@ impl Dht {
         record_request(&mut routing_table, id, from, read_only)?;
 
         let mut torrents = self.torrents.lock()?;
+        let mut quotas = self.announce_quotas.lock()?;
+        let now = Instant::now();
+        expire_peers(&mut torrents, &mut quotas, now);
 
-        torrents
-            .entry(info_hash)
-            .or_insert_with(Vec::new)
-            .push(addr);
+        // Announcing a peer which is already stored doesn't use quota.
+        match quotas.try_store(addr, &info_hash, now) {
+            Stored::OverQuota => return Err(ErrorKind::AnnounceQuotaExceeded)?,
+            Stored::Refreshed => {}
+            Stored::Replaced(old) => {
+                remove_peer(&mut torrents, &info_hash, &old);
+                torrents
+                    .entry(info_hash)
+                    .or_insert_with(Vec::new)
+                    .push(addr);
+            }
+            Stored::New => torrents
+                .entry(info_hash)
+                .or_insert_with(Vec::new)
+                .push(addr),
+        }
+        used_tokens.record_use(&token, token_ip);
 
-        Ok(Response::OnlyID {
-            id: self.id.clone(),
-        })
+        Ok(Response::OnlyID { id: self.id() })
+    }
+
+    /// Changes the token secret once for every [`TOKEN_WINDOW`] passed since
+    /// it last changed, forgetting the tokens which expired.
+    fn rotate_tokens(&self, routing_table: &mut RoutingTable) -> Result<()> {
+        let windows = self.used_tokens.lock()?.sweep(Instant::now());
+        for _ in 0..windows {
+            routing_table.update_token();
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes the peers which weren't announced again for [`PEER_TTL`].
+///
+/// [`PEER_TTL`]: crate::dht::PEER_TTL
+fn expire_peers(
+    torrents: &mut HashMap<NodeID, Vec<SocketAddrV4>>,
+    quotas: &mut AnnounceQuotas,
+    now: Instant,
+) {
+    for (info_hash, peer) in quotas.expire(now) {
+        remove_peer(torrents, &info_hash, &peer);
+    }
+}
+
+fn remove_peer(
+    torrents: &mut HashMap<NodeID, Vec<SocketAddrV4>>,
+    info_hash: &NodeID,
+    peer: &SocketAddrV4,
+) {
+    if let Some(peers) = torrents.get_mut(info_hash) {
+        peers.retain(|stored| stored != peer);
+        if peers.is_empty() {
+            torrents.remove(info_hash);
+        }
     }
 }
 
@@ -228,3 +384,355 @@ fn record_request<T: DerefMut<Target = RoutingTable>>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        dht::AnnounceQuotaConfig,
+        Dht,
+    };
+    use failure::Error;
+    use krpc_encoding::{
+        Envelope,
+        KRPCError,
+        Message,
+        NodeID,
+        Query,
+        Response,
+    };
+    use std::net::SocketAddrV4;
+    use tokio_krpc::InboundQuery;
+
+    fn make_dht() -> Result<Dht, Error> {
+        let (dht, _) = Dht::start("127.0.0.1:0".parse()?)?;
+
+        Ok(dht)
+    }
+
+    fn ping(id: &NodeID, read_only: bool) -> InboundQuery {
+        InboundQuery::new(b"aa".to_vec(), Query::Ping { id: id.clone() }, read_only)
+    }
+
+    #[test]
+    fn samples_stored_infohashes() -> Result<(), Error> {
+        let dht = make_dht()?;
+        let from: SocketAddrV4 = "10.0.0.1:6881".parse()?;
+        let stored: Vec<NodeID> = (0..25).map(|_| NodeID::random()).collect();
+        for info_hash in &stored {
+            dht.store_peer(info_hash.clone(), from);
+        }
+
+        let query = InboundQuery::new(
+            b"aa".to_vec(),
+            Query::SampleInfoHashes {
+                id: NodeID::random(),
+                target: NodeID::random(),
+            },
+            false,
+        );
+        let response = dht.handle_request(query, from);
+
+        match response.message_type {
+            Message::Response {
+                response: Response::Samples { num, samples, .. },
+            } => {
+                assert_eq!(num, Some(25));
+                assert_eq!(samples.len(), 20);
+                assert!(samples.iter().all(|sample| stored.contains(sample)));
+            }
+            message => panic!("unexpected message {:?}", message),
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn retransmitted_get_peers_answered_from_cache() -> Result<(), Error> {
+        let dht = make_dht()?;
+        let from: SocketAddrV4 = "10.0.0.1:6881".parse()?;
+        let info_hash = NodeID::random();
+        dht.store_peer(info_hash.clone(), "10.0.1.1:6881".parse()?);
+
+        let get_peers = || {
+            InboundQuery::new(
+                b"aa".to_vec(),
+                Query::GetPeers {
+                    id: NodeID::random(),
+                    info_hash: info_hash.clone(),
+                },
+                false,
+            )
+        };
+        let query = get_peers();
+        let retransmit = InboundQuery::new(b"aa".to_vec(), query.query.clone(), false);
+        let response = dht.handle_request(query, from);
+
+        // Peers stored since aren't looked up for the retransmit.
+        dht.store_peer(info_hash.clone(), "10.0.2.1:6881".parse()?);
+        let cached = dht.handle_request(retransmit, from);
+        assert_eq!(cached.encode()?, response.encode()?);
+        match cached.message_type {
+            Message::Response {
+                response: Response::GetPeers { peers, .. },
+            } => assert_eq!(peers.len(), 1),
+            message => panic!("unexpected message {:?}", message),
+        };
+        assert_eq!(dht.response_cache_stats().hits, 1);
+
+        // A new query with the same transaction id is answered afresh.
+        match dht.handle_request(get_peers(), from).message_type {
+            Message::Response {
+                response: Response::GetPeers { peers, .. },
+            } => assert_eq!(peers.len(), 2),
+            message => panic!("unexpected message {:?}", message),
+        };
+        let stats = dht.response_cache_stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ping_recorded() -> Result<(), Error> {
+        let dht = make_dht()?;
+        let id = NodeID::random();
+        let from: SocketAddrV4 = "10.0.0.1:6881".parse()?;
+
+        dht.handle_request(ping(&id, false), from);
+
+        let routing_table = dht.routing_table.lock().unwrap();
+        assert!(routing_table.get_node(&id).is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn inbound_queries_counted() -> Result<(), Error> {
+        let dht = make_dht()?;
+        let from: SocketAddrV4 = "10.0.0.1:6881".parse()?;
+        assert_eq!(dht.reachability().last_inbound, None);
+
+        dht.handle_request(ping(&NodeID::random(), false), from);
+        dht.handle_request(ping(&NodeID::random(), true), from);
+
+        let reachability = dht.reachability();
+        assert_eq!(reachability.inbound_queries_per_min, 2);
+        assert!(reachability.last_inbound.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_ping_not_recorded() -> Result<(), Error> {
+        let dht = make_dht()?;
+        let id = NodeID::random();
+        let from: SocketAddrV4 = "10.0.0.1:6881".parse()?;
+
+        let response = dht.handle_request(ping(&id, true), from);
+
+        match response.message_type {
+            Message::Response { .. } => {}
+            message => panic!("unexpected message {:?}", message),
+        };
+
+        let routing_table = dht.routing_table.lock().unwrap();
+        assert!(routing_table.get_node(&id).is_none());
+
+        Ok(())
+    }
+
+    fn announce(dht: &Dht, from: SocketAddrV4, info_hash: &NodeID) -> Envelope {
+        let token = dht
+            .routing_table
+            .lock()
+            .unwrap()
+            .generate_token(&from)
+            .to_vec();
+
+        announce_with_token(dht, from, info_hash, token)
+    }
+
+    fn announce_with_token(
+        dht: &Dht,
+        from: SocketAddrV4,
+        info_hash: &NodeID,
+        token: Vec<u8>,
+    ) -> Envelope {
+        let query = Query::AnnouncePeer {
+            id: NodeID::random(),
+            implied_port: true,
+            port: None,
+            info_hash: info_hash.clone(),
+            token,
+        };
+
+        dht.handle_request(InboundQuery::new(b"aa".to_vec(), query, false), from)
+    }
+
+    #[test]
+    fn announce_flood_rejected() -> Result<(), Error> {
+        let dht = make_dht()?;
+        dht.set_announce_quotas(AnnounceQuotaConfig {
+            info_hashes_per_window: 10,
+            ..AnnounceQuotaConfig::default()
+        });
+        let flooder: SocketAddrV4 = "10.0.0.1:6881".parse()?;
+        let client: SocketAddrV4 = "10.0.1.1:6881".parse()?;
+
+        for _ in 0..10 {
+            match announce(&dht, flooder, &NodeID::random()).message_type {
+                Message::Response { .. } => {}
+                message => panic!("unexpected message {:?}", message),
+            };
+        }
+
+        match announce(&dht, flooder, &NodeID::random()).message_type {
+            Message::Error { error } => {
+                assert_eq!(error, KRPCError::new(201, "Announce quota exceeded"))
+            }
+            message => panic!("unexpected message {:?}", message),
+        };
+        assert_eq!(dht.rejected_announces(), 1);
+
+        let info_hashes: Vec<NodeID> = (0..3).map(|_| NodeID::random()).collect();
+        for info_hash in &info_hashes {
+            // Announcing again is free.
+            for _ in 0..20 {
+                match announce(&dht, client, info_hash).message_type {
+                    Message::Response { .. } => {}
+                    message => panic!("unexpected message {:?}", message),
+                };
+            }
+
+            assert_eq!(dht.torrents.lock().unwrap()[info_hash], vec![client]);
+        }
+        assert_eq!(dht.rejected_announces(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn token_replay_rejected() -> Result<(), Error> {
+        let dht = make_dht()?;
+        let from: SocketAddrV4 = "10.0.0.1:6881".parse()?;
+        let info_hash = NodeID::random();
+        let token = dht
+            .routing_table
+            .lock()
+            .unwrap()
+            .generate_token(&from)
+            .to_vec();
+
+        match announce_with_token(&dht, from, &info_hash, token.clone()).message_type {
+            Message::Response { .. } => {}
+            message => panic!("unexpected message {:?}", message),
+        };
+
+        match announce_with_token(&dht, from, &NodeID::random(), token).message_type {
+            Message::Error { error } => {
+                assert_eq!(error, KRPCError::new(203, "Token already used"))
+            }
+            message => panic!("unexpected message {:?}", message),
+        };
+        assert_eq!(dht.replayed_tokens(), 1);
+        assert_eq!(dht.torrents.lock().unwrap().len(), 1);
+
+        // Announcing again with a new token is fine.
+        match announce(&dht, from, &info_hash).message_type {
+            Message::Response { .. } => {}
+            message => panic!("unexpected message {:?}", message),
+        };
+        assert_eq!(dht.replayed_tokens(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_announce_keeps_token() -> Result<(), Error> {
+        let dht = make_dht()?;
+        dht.set_announce_quotas(AnnounceQuotaConfig {
+            info_hashes_per_window: 1,
+            ..AnnounceQuotaConfig::default()
+        });
+        let from: SocketAddrV4 = "10.0.0.1:6881".parse()?;
+        match announce(&dht, from, &NodeID::random()).message_type {
+            Message::Response { .. } => {}
+            message => panic!("unexpected message {:?}", message),
+        };
+
+        let token = dht
+            .routing_table
+            .lock()
+            .unwrap()
+            .generate_token(&from)
+            .to_vec();
+        let without_port = Query::AnnouncePeer {
+            id: NodeID::random(),
+            implied_port: false,
+            port: None,
+            info_hash: NodeID::random(),
+            token: token.clone(),
+        };
+        match dht
+            .handle_request(InboundQuery::new(b"aa".to_vec(), without_port, false), from)
+            .message_type
+        {
+            Message::Error { error } => assert_eq!(
+                error,
+                KRPCError::new(203, "Not enough address info provided")
+            ),
+            message => panic!("unexpected message {:?}", message),
+        };
+
+        match announce_with_token(&dht, from, &NodeID::random(), token.clone()).message_type {
+            Message::Error { error } => {
+                assert_eq!(error, KRPCError::new(201, "Announce quota exceeded"))
+            }
+            message => panic!("unexpected message {:?}", message),
+        };
+
+        // Neither rejection used up the token.
+        dht.set_announce_quotas(AnnounceQuotaConfig {
+            info_hashes_per_window: 10,
+            ..AnnounceQuotaConfig::default()
+        });
+        match announce_with_token(&dht, from, &NodeID::random(), token).message_type {
+            Message::Response { .. } => {}
+            message => panic!("unexpected message {:?}", message),
+        };
+        assert_eq!(dht.replayed_tokens(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_announce_rejected() -> Result<(), Error> {
+        let dht = make_dht()?;
+        let from: SocketAddrV4 = "10.0.0.1:6881".parse()?;
+        let info_hash = NodeID::random();
+        let token = dht
+            .routing_table
+            .lock()
+            .unwrap()
+            .generate_token(&from)
+            .to_vec();
+
+        let query = Query::AnnouncePeer {
+            id: NodeID::random(),
+            implied_port: true,
+            port: None,
+            info_hash: info_hash.clone(),
+            token,
+        };
+        let response = dht.handle_request(InboundQuery::new(b"aa".to_vec(), query, true), from);
+
+        match response.message_type {
+            Message::Error { .. } => {}
+            message => panic!("unexpected message {:?}", message),
+        };
+
+        assert!(dht.torrents.lock().unwrap().get(&info_hash).is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,353 @@
+//! Keeps the NAT binding of the DHT socket warm and tracks whether other nodes
+//! can still reach us.
+
+use crate::{
+    dht::Dht,
+    errors::Result,
+    routing::NodeState,
+};
+use futures::future;
+use krpc_encoding::NodeInfo;
+use std::{
+    cmp,
+    collections::VecDeque,
+    future::Future,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::{
+    prelude::FutureExt,
+    timer::Delay,
+};
+use tokio_krpc::QueryOptions;
+
+/// Below the UDP mapping timeout of most NATs.
+pub const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(25);
+
+/// Time to wait for a keep-alive ping to be answered.
+const PING_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Window over which inbound queries are counted.
+const REACHABILITY_WINDOW: Duration = Duration::from_secs(60);
+
+/// Options for [`Dht::keep_alive`].
+#[derive(Clone, Debug)]
+pub struct KeepAliveConfig {
+    /// Time between rounds of pings.
+    pub interval: Duration,
+
+    /// Number of good nodes pinged each round. Successive rounds ping
+    /// different nodes.
+    pub nodes_per_round: usize,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> KeepAliveConfig {
+        KeepAliveConfig {
+            interval: DEFAULT_KEEP_ALIVE_INTERVAL,
+            nodes_per_round: 3,
+        }
+    }
+}
+
+/// How often other nodes have been sending us queries. Few inbound queries
+/// usually means our NAT mapping has expired and we can't be reached anymore.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reachability {
+    /// Number of queries received over the last minute.
+    pub inbound_queries_per_min: usize,
+
+    /// When the last query was received.
+    pub last_inbound: Option<Instant>,
+}
+
+/// Records when inbound queries arrive.
+pub(crate) struct ReachabilityTracker {
+    /// Arrival times of queries within the last [`REACHABILITY_WINDOW`],
+    /// oldest first.
+    inbound: VecDeque<Instant>,
+    last_inbound: Option<Instant>,
+}
+
+impl ReachabilityTracker {
+    pub fn new() -> ReachabilityTracker {
+        ReachabilityTracker {
+            inbound: VecDeque::new(),
+            last_inbound: None,
+        }
+    }
+
+    pub fn record_inbound(&mut self, now: Instant) {
+        self.inbound.push_back(now);
+        self.last_inbound = Some(now);
+        self.prune(now);
+    }
+
+    pub fn reachability(&mut self, now: Instant) -> Reachability {
+        self.prune(now);
+
+        Reachability {
+            inbound_queries_per_min: self.inbound.len(),
+            last_inbound: self.last_inbound,
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(oldest) = self.inbound.front() {
+            if now.duration_since(*oldest) < REACHABILITY_WINDOW {
+                break;
+            }
+
+            self.inbound.pop_front();
+        }
+    }
+}
+
+/// Decides when keep-alive rounds happen and which nodes they ping.
+pub(crate) struct KeepAliveSchedule {
+    config: KeepAliveConfig,
+    next_round: Instant,
+
+    /// Position in the list of good nodes the next round starts from.
+    cursor: usize,
+}
+
+impl KeepAliveSchedule {
+    pub fn new(config: KeepAliveConfig, now: Instant) -> KeepAliveSchedule {
+        KeepAliveSchedule {
+            next_round: now + config.interval,
+            config,
+            cursor: 0,
+        }
+    }
+
+    pub fn next_round(&self) -> Instant {
+        self.next_round
+    }
+
+    /// Returns the nodes to ping if a round is due at `now`.
+    pub fn poll_round(&mut self, now: Instant, good_nodes: &[NodeInfo]) -> Option<Vec<NodeInfo>> {
+        if now < self.next_round {
+            return None;
+        }
+
+        self.next_round = now + self.config.interval;
+
+        if good_nodes.is_empty() {
+            return Some(Vec::new());
+        }
+
+        let count = cmp::min(self.config.nodes_per_round, good_nodes.len());
+        let start = self.cursor % good_nodes.len();
+        self.cursor = start + count;
+
+        Some(
+            good_nodes
+                .iter()
+                .cycle()
+                .skip(start)
+                .take(count)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+impl Dht {
+    /// Pings a few good nodes from the routing table every
+    /// [`KeepAliveConfig::interval`] so the NAT in front of us keeps
+    /// forwarding queries from other nodes. Runs until dropped or
+    /// [`shutdown`].
+    pub fn keep_alive(self, config: KeepAliveConfig) -> impl Future<Output = ()> {
+        let task = self.shutdown.register_task();
+
+        async move {
+            let _task = task;
+            let mut schedule = KeepAliveSchedule::new(config, Instant::now());
+
+            loop {
+                if self
+                    .shutdown
+                    .run_until_triggered(Delay::new(schedule.next_round()))
+                    .await
+                    .is_none()
+                {
+                    return;
+                }
+
+                let good_nodes = match self.good_nodes() {
+                    Ok(good_nodes) => good_nodes,
+                    Err(err) => {
+                        eprintln!("Error While Keeping Alive: {}", err);
+                        return;
+                    }
+                };
+
+                if let Some(nodes) = schedule.poll_round(Instant::now(), &good_nodes) {
+                    future::join_all(nodes.iter().map(|node| self.keep_alive_ping(node))).await;
+                }
+            }
+        }
+    }
+
+    /// Query rates seen recently. See [`Reachability`].
+    pub fn reachability(&self) -> Reachability {
+        self.reachability
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .reachability(Instant::now())
+    }
+
+    fn good_nodes(&self) -> Result<Vec<NodeInfo>> {
+        let routing_table = self.routing_table.lock()?;
+
+        Ok(routing_table
+            .nodes()
+            .filter(|node| node.state() == NodeState::Good)
+            .map(|node| node.into())
+            .collect())
+    }
+
+    async fn keep_alive_ping<'a>(&'a self, node: &'a NodeInfo) {
+        let result = self
+            .send_transport
+            .ping_with_options(
+                self.id(),
+                node.address.into(),
+                QueryOptions {
+                    timeout: PING_TIMEOUT,
+                    expected_id: Some(node.node_id.clone()),
+                },
+            )
+            .timeout(PING_TIMEOUT)
+            .await;
+
+        let mut routing_table = match self.routing_table.lock() {
+            Ok(routing_table) => routing_table,
+            Err(_) => return,
+        };
+
+        if let Some(table_node) = routing_table.get_node_mut(&node.node_id) {
+            match result {
+                Ok(Ok(_)) => table_node.mark_successful_request(),
+                _ => table_node.mark_failed_request(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        KeepAliveConfig,
+        KeepAliveSchedule,
+        ReachabilityTracker,
+    };
+    use krpc_encoding::{
+        NodeID,
+        NodeInfo,
+    };
+    use std::{
+        net::SocketAddrV4,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+
+    fn nodes(count: u16) -> Vec<NodeInfo> {
+        (0..count)
+            .map(|port| {
+                NodeInfo::new(
+                    NodeID::random(),
+                    SocketAddrV4::new([10, 0, 0, 1].into(), port),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rounds_scheduled() {
+        let start = Instant::now();
+        let config = KeepAliveConfig {
+            interval: Duration::from_secs(25),
+            nodes_per_round: 2,
+        };
+        let mut schedule = KeepAliveSchedule::new(config, start);
+        let nodes = nodes(5);
+
+        assert_eq!(
+            schedule.poll_round(start + Duration::from_secs(24), &nodes),
+            None
+        );
+
+        let round = start + Duration::from_secs(25);
+        assert_eq!(
+            schedule.poll_round(round, &nodes),
+            Some(nodes[0..2].to_vec())
+        );
+        assert_eq!(schedule.next_round(), round + Duration::from_secs(25));
+        assert_eq!(schedule.poll_round(round, &nodes), None);
+    }
+
+    #[test]
+    fn rounds_rotate() {
+        let start = Instant::now();
+        let config = KeepAliveConfig {
+            interval: Duration::from_secs(1),
+            nodes_per_round: 2,
+        };
+        let mut schedule = KeepAliveSchedule::new(config, start);
+        let nodes = nodes(3);
+
+        let rounds: Vec<Vec<NodeInfo>> = (1..=3)
+            .filter_map(|secs| schedule.poll_round(start + Duration::from_secs(secs), &nodes))
+            .collect();
+
+        assert_eq!(
+            rounds,
+            vec![
+                vec![nodes[0].clone(), nodes[1].clone()],
+                vec![nodes[2].clone(), nodes[0].clone()],
+                vec![nodes[1].clone(), nodes[2].clone()],
+            ]
+        );
+    }
+
+    #[test]
+    fn round_without_good_nodes() {
+        let start = Instant::now();
+        let mut schedule = KeepAliveSchedule::new(KeepAliveConfig::default(), start);
+
+        assert_eq!(
+            schedule.poll_round(start + Duration::from_secs(25), &[]),
+            Some(Vec::new())
+        );
+    }
+
+    #[test]
+    fn reachability() {
+        let start = Instant::now();
+        let mut tracker = ReachabilityTracker::new();
+
+        let reachability = tracker.reachability(start);
+        assert_eq!(reachability.inbound_queries_per_min, 0);
+        assert_eq!(reachability.last_inbound, None);
+
+        for secs in 0..10 {
+            tracker.record_inbound(start + Duration::from_secs(secs * 10));
+        }
+
+        let last = start + Duration::from_secs(90);
+        let reachability = tracker.reachability(last);
+        assert_eq!(reachability.inbound_queries_per_min, 6);
+        assert_eq!(reachability.last_inbound, Some(last));
+
+        // Gone dark.
+        let reachability = tracker.reachability(last + Duration::from_secs(120));
+        assert_eq!(reachability.inbound_queries_per_min, 0);
+        assert_eq!(reachability.last_inbound, Some(last));
+    }
+}
@@ -0,0 +1,963 @@
+use crate::{
+    bootstrap::Bootstrap,
+    dht::Dht,
+    errors::{
+        Error,
+        ErrorKind,
+        Result,
+    },
+    estimate::{
+        NetworkSizeEstimate,
+        ESTIMATE_RANK,
+    },
+    lookup::{
+        Lookup,
+        LookupConfig,
+    },
+    routing::{
+        AddNodeOutcome,
+        Node,
+        RoutingTable,
+    },
+    trace,
+};
+use futures::future;
+use krpc_encoding::{
+    NodeID,
+    NodeInfo,
+};
+use std::{
+    cmp,
+    collections::HashMap,
+    net::SocketAddrV4,
+    sync::atomic::Ordering,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::{
+    prelude::FutureExt,
+    timer::timeout::Elapsed,
+};
+use tokio_krpc::{
+    responses::GetPeersResponse,
+    send_errors,
+    PortType,
+    QueryOptions,
+};
+
+/// Time after which a lookup gives up and returns what it has found so far.
+pub(super) const LOOKUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A node which answered a ping from [`Dht::closest_live_nodes`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiveNode {
+    pub node: NodeInfo,
+
+    /// Round trip time of the ping.
+    pub rtt: Duration,
+}
+
+/// Result of a `get_peers` lookup.
+struct PeerLookup {
+    /// Peers returned by any node, without duplicates.
+    peers: Vec<SocketAddrV4>,
+
+    /// Closest nodes which responded along with the token each sent.
+    closest: Vec<(NodeInfo, Vec<u8>)>,
+}
+
+impl Dht {
+    /// Finds the K nodes closest to `target` in the DHT by iteratively
+    /// querying closer and closer nodes with `find_node`.
+    pub async fn lookup_node(&self, target: NodeID) -> Result<Vec<NodeInfo>> {
+        self.lookup_node_with_config(target, LookupConfig::default(), LOOKUP_TIMEOUT)
+            .await
+    }
+
+    /// Like [`lookup_node`] with explicit lookup parameters. If `timeout`
+    /// elapses before the lookup converges, the closest nodes found so far
+    /// are returned. Dropping the future cancels the lookup.
+    pub async fn lookup_node_with_config(
+        &self,
+        target: NodeID,
+        config: LookupConfig,
+        timeout: Duration,
+    ) -> Result<Vec<NodeInfo>> {
+        let span = operation_span!("lookup", target = %target);
+
+        trace::instrument(self.run_node_lookup(target, config, timeout), span).await
+    }
+
+    async fn run_node_lookup(
+        &self,
+        target: NodeID,
+        config: LookupConfig,
+        timeout: Duration,
+    ) -> Result<Vec<NodeInfo>> {
+        let mut lookup = Lookup::new(target, config);
+        self.drive_node_lookup(&mut lookup, timeout).await?;
+
+        Ok(lookup.closest())
+    }
+
+    /// Runs `lookup` with `find_node` queries until it converges or
+    /// `timeout` elapses.
+    pub(super) async fn drive_node_lookup<'a>(
+        &'a self,
+        lookup: &'a mut Lookup,
+        timeout: Duration,
+    ) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        self.seed_node_lookup(lookup)?;
+        while self.node_lookup_round(lookup, deadline).await {}
+        self.evict_queued().await?;
+
+        Ok(())
+    }
+
+    /// Adds the nodes of the routing table closest to the target of
+    /// `lookup` as its candidates.
+    pub(super) fn seed_node_lookup(&self, lookup: &mut Lookup) -> Result<()> {
+        let target = lookup.target().clone();
+
+        // Extra seeds to fall back on when some of the closest don't respond.
+        let seed_count = lookup.config().k * 2;
+        if let Some(address) = self.external_address() {
+            lookup.set_own_address(address);
+        }
+
+        let seeds: Vec<(NodeInfo, f64, Option<Duration>)> = {
+            let routing_table = self.routing_table.lock()?;
+            let seeds: Box<dyn Iterator<Item = &Node> + '_> = match &lookup.config().weights {
+                Some(weights) => Box::new(routing_table.iter_closest_weighted(
+                    &target,
+                    weights.rtt,
+                    weights.reliability,
+                )),
+                None => Box::new(routing_table.iter_closest(&target)),
+            };
+
+            seeds
+                .take(seed_count)
+                .map(|node| (node.into(), node.reliability(), node.smoothed_rtt()))
+                .collect()
+        };
+        for (node, reliability, rtt) in seeds {
+            lookup.add_candidate_with_history(node, reliability, rtt);
+        }
+
+        Ok(())
+    }
+
+    /// Sends the next `find_node` queries of `lookup` and waits for them.
+    /// Returns `false` instead once the lookup converged or `deadline`
+    /// passed.
+    pub(super) async fn node_lookup_round<'a>(
+        &'a self,
+        lookup: &'a mut Lookup,
+        deadline: Instant,
+    ) -> bool {
+        let now = Instant::now();
+        if lookup.is_finished() || now >= deadline {
+            return false;
+        }
+
+        let queries = lookup.next_queries();
+        if queries.is_empty() {
+            return false;
+        }
+
+        let target = lookup.target().clone();
+        let query_timeout = cmp::min(deadline - now, lookup.config().round_timeout);
+        let results = future::join_all(
+            queries
+                .iter()
+                .map(|node| self.find_nodes_from(node, target.clone(), query_timeout)),
+        )
+        .await;
+
+        for (node, result) in queries.into_iter().zip(results) {
+            match result {
+                Ok(nodes) => lookup.handle_response(&node.node_id, nodes),
+                Err(_) => lookup.handle_failure(&node.node_id),
+            }
+        }
+
+        true
+    }
+
+    /// Finds the `k` nodes closest to `key` which are reachable right now.
+    /// After a lookup of `key` the closest nodes found are pinged and every
+    /// node which doesn't answer is replaced by the next closest one. Fewer
+    /// than `k` nodes are returned when the lookup didn't find `k` live
+    /// ones. Nodes are ordered by distance to `key`, closest first.
+    pub async fn closest_live_nodes(&self, key: NodeID, k: usize) -> Result<Vec<LiveNode>> {
+        let span = operation_span!("closest_live_nodes", key = %key, k = k as u64);
+
+        trace::instrument(self.run_closest_live_nodes(key, k), span).await
+    }
+
+    async fn run_closest_live_nodes(&self, key: NodeID, k: usize) -> Result<Vec<LiveNode>> {
+        let config = LookupConfig {
+            k,
+            ..LookupConfig::default()
+        };
+        let ping_timeout = config.round_timeout;
+        let mut lookup = Lookup::new(key, config);
+        self.drive_node_lookup(&mut lookup, LOOKUP_TIMEOUT).await?;
+
+        let mut candidates = lookup.candidates().into_iter();
+        let mut live = Vec::with_capacity(k);
+
+        while live.len() < k {
+            let batch: Vec<NodeInfo> = candidates.by_ref().take(k - live.len()).collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let results =
+                future::join_all(batch.iter().map(|node| self.ping_node(node, ping_timeout))).await;
+
+            for (node, result) in batch.into_iter().zip(results) {
+                if let Ok(rtt) = result {
+                    live.push(LiveNode { node, rtt });
+                }
+            }
+        }
+        self.evict_queued().await?;
+
+        // Candidates were taken closest first but a batch may fill the gaps
+        // left by an earlier one.
+        let key = lookup.target();
+        live.sort_by_key(|live| live.node.node_id.distance(key));
+
+        Ok(live)
+    }
+
+    /// Pings `node`, returning the round trip time. Only an answer with the
+    /// node's own id counts. The routing table is updated either way.
+    async fn ping_node<'a>(&'a self, node: &'a NodeInfo, timeout: Duration) -> Result<Duration> {
+        let started = Instant::now();
+        let result = self
+            .send_transport
+            .ping_with_options(
+                self.id(),
+                node.address.into(),
+                QueryOptions {
+                    timeout,
+                    expected_id: Some(node.node_id.clone()),
+                },
+            )
+            .timeout(timeout)
+            .await;
+        let rtt = started.elapsed();
+
+        let failure: Option<Error> = match result {
+            Ok(Ok(ref id)) if *id == node.node_id => None,
+            Ok(Ok(_)) => Some(ErrorKind::NodeIDMismatch.into()),
+            Ok(Err(err)) => Some(err.into()),
+            Err(elapsed) => Some(elapsed.into()),
+        };
+
+        if let Some(err) = failure {
+            if let Some(node) = self.routing_table.lock()?.get_node(&node.node_id) {
+                node.mark_failed_request();
+            }
+
+            return Err(err);
+        }
+
+        let responder = Node::new(node.node_id.clone(), node.address);
+        responder.mark_successful_request();
+        responder.record_rtt(rtt);
+        self.insert_node(responder)?;
+
+        Ok(rtt)
+    }
+
+    /// Adds `node` to the routing table. When its bucket is full the node is
+    /// queued as a replacement, and its bucket is probed by the next
+    /// [`evict_queued`] rather than while a lookup round waits on it.
+    ///
+    /// [`evict_queued`]: Dht::evict_queued
+    fn insert_node(&self, node: Node) -> Result<()> {
+        let id = node.id.clone();
+        if self.routing_table.lock()?.add_node(node) == AddNodeOutcome::Queued {
+            self.queued_evictions.lock()?.push(id);
+        }
+
+        Ok(())
+    }
+
+    /// Pings the questionable node seen least recently in the bucket of each
+    /// replacement queued since last time, all at once, so replacements take
+    /// the place of nodes which are gone. See
+    /// [`RoutingTable::try_evict_questionable`].
+    pub(super) async fn evict_queued(&self) -> Result<()> {
+        let queued: Vec<NodeID> = self.queued_evictions.lock()?.drain(..).collect();
+        let results = future::join_all(queued.into_iter().map(|id| {
+            RoutingTable::try_evict_questionable(
+                self.routing_table.clone(),
+                self.send_transport.clone(),
+                id,
+            )
+        }))
+        .await;
+
+        for result in results {
+            result?;
+        }
+
+        Ok(())
+    }
+
+    /// Bootstraps the routing table from `seeds`: asks each of them for the
+    /// nodes closest to our id, then looks up our id through them. Unlike
+    /// [`bootstrap_routing_table`] no node is queried more than once.
+    pub async fn bootstrap_from(&self, seeds: &[SocketAddrV4]) -> Result<Vec<NodeInfo>> {
+        let span = operation_span!("bootstrap", seeds = seeds.len() as u64);
+
+        trace::instrument(self.run_bootstrap(seeds), span).await
+    }
+
+    /// Bootstraps like [`bootstrap_from`] from the IPv4 seeds of
+    /// `bootstrap`, like the ones from [`Bootstrap::from_well_known`].
+    pub async fn bootstrap<'a>(&'a self, bootstrap: &'a Bootstrap) -> Result<Vec<NodeInfo>> {
+        self.bootstrap_from(&bootstrap.v4_seeds()).await
+    }
+
+    async fn run_bootstrap<'a>(&'a self, seeds: &'a [SocketAddrV4]) -> Result<Vec<NodeInfo>> {
+        let results = future::join_all(seeds.iter().map(|seed| {
+            self.send_transport
+                .find_node(self.id(), (*seed).into(), self.id())
+                .timeout(LookupConfig::default().round_timeout)
+        }))
+        .await;
+
+        let responders = seeds
+            .iter()
+            .zip(results)
+            .filter_map(|(seed, result)| match result {
+                Ok(Ok(response)) => Some(Node::new(response.id, *seed)),
+                _ => None,
+            })
+            .inspect(Node::mark_successful_request);
+        self.routing_table.lock()?.add_nodes(responders);
+
+        self.lookup_node(self.id()).await
+    }
+
+    /// Gets a list of peers seeding `info_hash`, from the peers announced to
+    /// this node and those returned by a `get_peers` lookup.
+    pub async fn get_peers(&self, info_hash: NodeID) -> Result<Vec<SocketAddrV4>> {
+        let mut peers = self
+            .torrents
+            .lock()?
+            .get(&info_hash)
+            .cloned()
+            .unwrap_or_else(Vec::new);
+
+        for peer in self.lookup_peers(info_hash).await?.peers {
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+
+        Ok(peers)
+    }
+
+    /// Parses the info-hash out of `magnet` and gets its peers like
+    /// [`get_peers`]. See [`NodeID::from_magnet`] for the links accepted.
+    pub async fn get_peers_for_magnet(&self, magnet: &str) -> Result<Vec<SocketAddrV4>> {
+        let info_hash =
+            NodeID::from_magnet(magnet).map_err(|cause| ErrorKind::InvalidMagnet { cause })?;
+
+        self.get_peers(info_hash).await
+    }
+
+    /// Announces that we have information about an info_hash on `port` to
+    /// the closest nodes found by a `get_peers` lookup. Fails if none of them
+    /// accepted the announce.
+    pub async fn announce(&self, info_hash: NodeID, port: PortType) -> Result<()> {
+        let span = operation_span!("announce", info_hash = %info_hash, port = ?port);
+
+        trace::instrument(self.run_announce(info_hash, port), span).await
+    }
+
+    async fn run_announce(&self, info_hash: NodeID, port: PortType) -> Result<()> {
+        let lookup = self.lookup_peers(info_hash.clone()).await?;
+
+        let results = future::join_all(lookup.closest.into_iter().map(|(node, token)| {
+            self.send_transport
+                .announce_peer(
+                    self.id(),
+                    token,
+                    node.address.into(),
+                    info_hash.clone(),
+                    port,
+                )
+                .timeout(LookupConfig::default().round_timeout)
+        }))
+        .await;
+
+        let accepted = results.iter().any(|result| match result {
+            Ok(Ok(_)) => true,
+            _ => false,
+        });
+        if !accepted {
+            Err(ErrorKind::AnnounceFailed)?;
+        }
+
+        Ok(())
+    }
+
+    /// Iteratively queries nodes closer and closer to `info_hash` with
+    /// `get_peers`, collecting the peers and tokens they return.
+    async fn lookup_peers(&self, info_hash: NodeID) -> Result<PeerLookup> {
+        let span = operation_span!("get_peers", info_hash = %info_hash);
+
+        trace::instrument(self.run_peer_lookup(info_hash), span).await
+    }
+
+    async fn run_peer_lookup(&self, info_hash: NodeID) -> Result<PeerLookup> {
+        let config = LookupConfig::default();
+        let deadline = Instant::now() + LOOKUP_TIMEOUT;
+        let mut lookup = Lookup::new(info_hash.clone(), config);
+        self.seed_node_lookup(&mut lookup)?;
+
+        let mut peers = Vec::new();
+        let mut tokens = HashMap::new();
+
+        while !lookup.is_finished() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            let queries = lookup.next_queries();
+            if queries.is_empty() {
+                break;
+            }
+
+            let query_timeout = cmp::min(deadline - now, lookup.config().round_timeout);
+            let results = future::join_all(
+                queries
+                    .iter()
+                    .map(|node| self.get_peers_from(node, info_hash.clone(), query_timeout)),
+            )
+            .await;
+
+            for (node, result) in queries.into_iter().zip(results) {
+                match result {
+                    Ok(response) => {
+                        for peer in response.peers {
+                            if !peers.contains(&peer) {
+                                peers.push(peer);
+                            }
+                        }
+
+                        if let Some(token) = response.token {
+                            tokens.insert(node.node_id.clone(), token);
+                        }
+
+                        let nodes = self.remove_own_id(response.nodes);
+                        lookup.handle_response(&node.node_id, nodes);
+                    }
+                    Err(_) => lookup.handle_failure(&node.node_id),
+                }
+            }
+        }
+        self.evict_queued().await?;
+
+        let closest = lookup
+            .closest()
+            .into_iter()
+            .filter_map(|node| {
+                let token = tokens.remove(&node.node_id)?;
+                Some((node, token))
+            })
+            .collect();
+
+        Ok(PeerLookup { peers, closest })
+    }
+
+    /// Estimates the number of nodes in the DHT from the distance of the
+    /// [`ESTIMATE_RANK`]th closest node found by each of `samples` lookups
+    /// of random targets. See [`NetworkSizeEstimate`]. Lookups finding fewer
+    /// nodes are left out.
+    pub async fn estimate_network_size(&self, samples: usize) -> Result<NetworkSizeEstimate> {
+        let mut distances = Vec::with_capacity(samples);
+
+        for _ in 0..samples {
+            let target = NodeID::random();
+            let closest = self.lookup_node(target.clone()).await?;
+
+            if let Some(node) = closest.get(ESTIMATE_RANK - 1) {
+                distances.push(node.node_id.distance(&target));
+            }
+        }
+
+        Ok(
+            NetworkSizeEstimate::from_distances(ESTIMATE_RANK, distances)
+                .ok_or(ErrorKind::EstimateUnavailable)?,
+        )
+    }
+
+    /// Sends a `find_node` query to `node` and records it in the routing
+    /// table if it responds.
+    async fn find_nodes_from<'a>(
+        &'a self,
+        node: &'a NodeInfo,
+        target: NodeID,
+        timeout: Duration,
+    ) -> Result<Vec<NodeInfo>> {
+        let started = Instant::now();
+        let result = self
+            .send_transport
+            .find_node_with_options(
+                self.id(),
+                node.address.into(),
+                target,
+                QueryOptions {
+                    timeout,
+                    expected_id: Some(node.node_id.clone()),
+                },
+            )
+            .timeout(timeout)
+            .await;
+        let response = self.record_query(node, started, result)?;
+
+        Ok(self.remove_own_id(response.nodes))
+    }
+
+    /// Sends a `get_peers` query to `node` and records it in the routing
+    /// table if it responds.
+    async fn get_peers_from<'a>(
+        &'a self,
+        node: &'a NodeInfo,
+        info_hash: NodeID,
+        timeout: Duration,
+    ) -> Result<GetPeersResponse> {
+        let started = Instant::now();
+        let response = self
+            .send_transport
+            .get_peers_with_options(
+                self.id(),
+                node.address.into(),
+                info_hash,
+                QueryOptions {
+                    timeout,
+                    expected_id: Some(node.node_id.clone()),
+                },
+            )
+            .timeout(timeout)
+            .await;
+
+        self.record_query(node, started, result)
+    }
+
+    /// Records the outcome of a query sent to `node` at `started` in the
+    /// routing table and passes the response on. A node which failed to
+    /// answer is marked as such if it's in the table. The history of a node
+    /// already in the table is kept, so its reliability and round trip time
+    /// build up over many lookups. Other nodes which answered are added.
+    fn record_query<T>(
+        &self,
+        node: &NodeInfo,
+        started: Instant,
+        result: std::result::Result<send_errors::Result<T>, Elapsed>,
+    ) -> Result<T> {
+        let response = match result {
+            Ok(Ok(response)) => response,
+            Ok(Err(err)) => {
+                if let Some(node) = self.routing_table.lock()?.get_node(&node.node_id) {
+                    match err.kind() {
+                        send_errors::ErrorKind::Unreachable { .. } => node.mark_unreachable(),
+                        _ => node.mark_failed_request(),
+                    }
+                }
+
+                return Err(err.into());
+            }
+            Err(elapsed) => {
+                if let Some(node) = self.routing_table.lock()?.get_node(&node.node_id) {
+                    node.mark_failed_request();
+                }
+
+                return Err(elapsed.into());
+            }
+        };
+
+        let known = match self.routing_table.lock()?.get_node(&node.node_id) {
+            Some(known) => {
+                known.mark_successful_request();
+                known.record_rtt(started.elapsed());
+                true
+            }
+            None => false,
+        };
+        if !known {
+            let responder = Node::new(node.node_id.clone(), node.address);
+            responder.mark_successful_request();
+            responder.record_rtt(started.elapsed());
+            self.insert_node(responder)?;
+        }
+
+        Ok(response)
+    }
+
+    /// Removes nodes with our id from a response. Being returned at our own
+    /// address is expected and counted, a node at any other address claiming
+    /// our id is spoofing it and counted separately.
+    fn remove_own_id(&self, nodes: Vec<NodeInfo>) -> Vec<NodeInfo> {
+        let own_id = self.id();
+        let external_address = self.external_address();
+
+        nodes
+            .into_iter()
+            .filter(|node| {
+                if node.node_id != own_id {
+                    return true;
+                }
+
+                match external_address {
+                    Some(address) if address != node.address => {
+                        trace_event!(address = %node.address, "spoofed own id");
+                        self.spoofed_own_ids.fetch_add(1, Ordering::Relaxed);
+                    }
+                    _ => {
+                        self.own_id_echoes.fetch_add(1, Ordering::Relaxed);
+                    }
+                };
+
+                false
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::{
+            Error as DhtError,
+            ErrorKind,
+        },
+        lookup::{
+            LookupConfig,
+            SelectionWeights,
+        },
+        routing::Node,
+        testing::{
+            Behavior,
+            MockTransport,
+            NetworkConfig,
+            Reply,
+            SimulatedNetwork,
+        },
+        Dht,
+    };
+    use failure::Error;
+    use futures::executor::block_on;
+    use krpc_encoding::{
+        errors::ErrorKind as EncodingErrorKind,
+        NodeID,
+        NodeInfo,
+        Query,
+        Response,
+    };
+    use num_bigint::BigUint;
+    use std::{
+        net::{
+            SocketAddr,
+            SocketAddrV4,
+        },
+        sync::Arc,
+        time::Duration,
+    };
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn own_id_removed() -> Result<(), Error> {
+        let (dht, _) = Dht::start("127.0.0.1:0".parse()?)?;
+        let external: SocketAddrV4 = "10.0.0.1:6881".parse()?;
+        dht.set_external_address(external);
+
+        let other = NodeInfo::new(NodeID::random(), "10.0.0.3:6881".parse()?);
+        let nodes = vec![
+            NodeInfo::new(dht.id(), external),
+            other.clone(),
+            NodeInfo::new(dht.id(), "10.0.0.4:6881".parse()?),
+        ];
+
+        assert_eq!(dht.remove_own_id(nodes), vec![other]);
+        assert_eq!(dht.own_id_echoes(), 1);
+        assert_eq!(dht.spoofed_own_ids(), 1);
+
+        Ok(())
+    }
+
+    /// Answers `find_node` queries to `node` with `nodes` and pings only if
+    /// `alive`.
+    fn script_node(mock: &MockTransport, node: &NodeInfo, nodes: Vec<NodeInfo>, alive: bool) {
+        let id = node.node_id.clone();
+        mock.respond(node.address.into(), move |query| match query {
+            Query::FindNode { .. } => Reply::Response(Response::NextHop {
+                id: id.clone(),
+                token: None,
+                nodes: nodes.clone(),
+            }),
+            Query::Ping { .. } if alive => Reply::Response(Response::OnlyID { id: id.clone() }),
+            _ => Reply::Timeout,
+        });
+    }
+
+    #[test]
+    fn closest_live_nodes_replaces_dead() -> Result<(), Error> {
+        let key = NodeID::new(BigUint::from(0u8));
+        let mock = Arc::new(MockTransport::new());
+
+        // Nodes 1 to 3 answer the lookup but are gone by the time they are
+        // pinged.
+        let nodes: Vec<NodeInfo> = (1..=8u8)
+            .map(|idx| {
+                NodeInfo::new(
+                    NodeID::new(BigUint::from(idx)),
+                    SocketAddrV4::new([10, 0, idx, 1].into(), 6881),
+                )
+            })
+            .collect();
+        for (idx, node) in nodes.iter().enumerate() {
+            script_node(&mock, node, Vec::new(), idx >= 3);
+        }
+
+        let seed = NodeInfo::new(
+            NodeID::new(BigUint::from(1u8) << 159),
+            "10.0.100.1:6881".parse()?,
+        );
+        script_node(&mock, &seed, nodes.clone(), false);
+
+        let dht = Dht::with_transport(NodeID::random(), mock.clone());
+        {
+            let node = Node::new(seed.node_id.clone(), seed.address);
+            node.mark_successful_request();
+            dht.routing_table
+                .lock()
+                .map_err(DhtError::from)?
+                .add_node(node);
+        }
+
+        let mut runtime = Runtime::new()?;
+        let live = runtime.block_on(dht.closest_live_nodes(key.clone(), 3))?;
+        let live_nodes: Vec<NodeInfo> = live.iter().map(|live| live.node.clone()).collect();
+        assert_eq!(live_nodes, nodes[3..6].to_vec());
+
+        // Every node queried is expected to answer with the id it's known by.
+        let calls = mock.take_calls();
+        assert!(calls.iter().all(|call| call.expected_id.is_some()));
+        let pinged: Vec<SocketAddrV4> = calls
+            .into_iter()
+            .filter_map(|call| match (call.query, call.address) {
+                (Query::Ping { .. }, SocketAddr::V4(address)) => Some(address),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pinged.len(), 6);
+        assert!(nodes[..6].iter().all(|node| pinged.contains(&node.address)));
+
+        // Only five nodes are alive.
+        let live = runtime.block_on(dht.closest_live_nodes(key, 8))?;
+        let live_nodes: Vec<NodeInfo> = live.into_iter().map(|live| live.node).collect();
+        assert_eq!(live_nodes, nodes[3..].to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn queued_node_evicts_questionable() -> Result<(), Error> {
+        let mock = Arc::new(MockTransport::new());
+        let dht = Dht::with_transport(NodeID::random(), mock.clone());
+
+        // The only bucket is full of questionable nodes, none of which
+        // answers.
+        let mut stale = Vec::new();
+        for idx in 0..8u8 {
+            let id = NodeID::random();
+            let address = SocketAddrV4::new([10, 0, idx, 1].into(), 6881);
+            dht.routing_table
+                .lock()
+                .map_err(DhtError::from)?
+                .add_node(Node::new(id.clone(), address));
+            stale.push(id);
+        }
+
+        let node = NodeInfo::new(NodeID::random(), "10.0.8.1:6881".parse()?);
+        let id = node.node_id.clone();
+        mock.respond(node.address.into(), move |_| {
+            Reply::Response(Response::OnlyID { id: id.clone() })
+        });
+
+        let mut runtime = Runtime::new()?;
+        runtime.block_on(dht.ping_node(&node, Duration::from_secs(1)))?;
+
+        // Queued behind the stale nodes until the bucket is probed.
+        assert!(dht
+            .routing_table
+            .lock()
+            .map_err(DhtError::from)?
+            .get_node(&node.node_id)
+            .is_none());
+        runtime.block_on(dht.evict_queued())?;
+
+        let routing_table = dht.routing_table.lock().map_err(DhtError::from)?;
+        assert!(routing_table.get_node(&node.node_id).is_some());
+        assert_eq!(routing_table.len(), 8);
+        assert_eq!(
+            stale
+                .iter()
+                .filter(|id| routing_table.get_node(id).is_some())
+                .count(),
+            7
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_peers_updates_known_nodes() -> Result<(), Error> {
+        let mock = Arc::new(MockTransport::new());
+        let dht = Dht::with_transport(NodeID::random(), mock.clone());
+
+        let silent = NodeInfo::new(NodeID::random(), "10.0.1.1:6881".parse()?);
+        let answering = NodeInfo::new(NodeID::random(), "10.0.2.1:6881".parse()?);
+        let id = answering.node_id.clone();
+        mock.respond(answering.address.into(), move |_| {
+            Reply::Response(Response::NextHop {
+                id: id.clone(),
+                token: Some(b"token".to_vec()),
+                nodes: Vec::new(),
+            })
+        });
+        dht.routing_table
+            .lock()
+            .map_err(DhtError::from)?
+            .add_nodes(vec![
+                Node::new(silent.node_id.clone(), silent.address),
+                Node::new(answering.node_id.clone(), answering.address),
+            ]);
+
+        Runtime::new()?.block_on(dht.get_peers(NodeID::random()))?;
+
+        let routing_table = dht.routing_table.lock().map_err(DhtError::from)?;
+        assert_eq!(
+            routing_table
+                .get_node(&silent.node_id)
+                .unwrap()
+                .failed_requests(),
+            1
+        );
+
+        let answering = routing_table.get_node(&answering.node_id).unwrap();
+        assert_eq!(answering.failed_requests(), 0);
+        assert!(answering.smoothed_rtt().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn weighted_lookup_times_out_less() -> Result<(), Error> {
+        let network = SimulatedNetwork::new(NetworkConfig {
+            query_timeout: Duration::from_millis(200),
+            ..NetworkConfig::default()
+        });
+        let nodes: Vec<Dht> = (0..40).map(|_| network.add_node()).collect();
+        let addresses = network.addresses();
+        let mut runtime = Runtime::new()?;
+        for idx in 1..nodes.len() {
+            runtime.block_on(nodes[idx].bootstrap_from(&addresses[..idx]))?;
+        }
+
+        // Seeds 8 nodes as far from the target. The 4 closest of them often
+        // failed before and are gone by now.
+        let target = nodes[0].id();
+        let mut far: Vec<(BigUint, usize)> = nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, node)| (node.id().distance(&target), idx))
+            .filter(|(distance, _)| distance.bits() == 160)
+            .collect();
+        assert!(far.len() >= 8);
+        far.sort();
+        let seeds: Vec<usize> = far.into_iter().take(8).map(|(_, idx)| idx).collect();
+        for &idx in &seeds[..4] {
+            network.set_behavior(addresses[idx], Behavior::Silent);
+        }
+
+        let mut timeouts = |weights| -> Result<u8, Error> {
+            let client = network.add_node();
+            let seed_nodes: Vec<Node> = seeds
+                .iter()
+                .enumerate()
+                .map(|(rank, &idx)| {
+                    let node = Node::new(nodes[idx].id(), addresses[idx]);
+                    node.mark_successful_request();
+                    if rank < 4 {
+                        node.record_rtt(Duration::from_millis(900));
+                        node.mark_failed_request();
+                    } else {
+                        node.record_rtt(Duration::from_millis(60));
+                        node.mark_successful_request();
+                    }
+                    node
+                })
+                .collect();
+            client
+                .routing_table
+                .lock()
+                .map_err(DhtError::from)?
+                .add_nodes(seed_nodes);
+
+            let config = LookupConfig {
+                weights,
+                ..LookupConfig::default()
+            };
+            let closest = runtime.block_on(client.lookup_node_with_config(
+                target.clone(),
+                config,
+                Duration::from_secs(10),
+            ))?;
+            assert_eq!(closest[0].node_id, target);
+
+            let routing_table = client.routing_table.lock().map_err(DhtError::from)?;
+            Ok(seeds[..4]
+                .iter()
+                .filter_map(|&idx| routing_table.get_node(&nodes[idx].id()))
+                .map(|node| node.failed_requests().saturating_sub(1))
+                .sum())
+        };
+
+        let unweighted = timeouts(None)?;
+        assert!(unweighted >= 3);
+        assert!(timeouts(Some(SelectionWeights::default()))? < unweighted);
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_peers_for_invalid_magnet() -> Result<(), Error> {
+        let (dht, _) = Dht::start("127.0.0.1:0".parse()?)?;
+        let err = block_on(dht.get_peers_for_magnet("magnet:?dn=Sintel")).unwrap_err();
+
+        match err.kind() {
+            ErrorKind::InvalidMagnet { cause } => match cause.kind() {
+                EncodingErrorKind::MagnetMissingInfoHash => {}
+                kind => panic!("unexpected error {}", kind),
+            },
+            kind => panic!("unexpected error {}", kind),
+        };
+
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+//! Order in which keys were last used, to find the least recently used one
+//! in O(1) amortised time. Using a key again leaves its older position
+//! behind, which is skipped once it reaches the front.
+
+use std::collections::VecDeque;
+
+/// Positions of keys, least recently used first. Each position is stamped,
+/// and the owner keeps the stamp of the latest position of every key it
+/// still has, so positions left behind can be told apart.
+pub(crate) struct LruOrder<K> {
+    order: VecDeque<(u64, K)>,
+    next_stamp: u64,
+}
+
+impl<K> LruOrder<K> {
+    pub fn new() -> LruOrder<K> {
+        LruOrder {
+            order: VecDeque::new(),
+            next_stamp: 0,
+        }
+    }
+
+    /// Makes `key` the most recently used. Returns the stamp to keep for it.
+    pub fn touch(&mut self, key: K) -> u64 {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        self.order.push_back((stamp, key));
+
+        stamp
+    }
+
+    /// The least recently used key. `stamp_of` gives the stamp kept for a key,
+    /// or `None` if the owner no longer has it.
+    pub fn front(&mut self, stamp_of: impl Fn(&K) -> Option<u64>) -> Option<&K> {
+        while let Some((stamp, key)) = self.order.front() {
+            if stamp_of(key) == Some(*stamp) {
+                break;
+            }
+
+            self.order.pop_front();
+        }
+
+        self.order.front().map(|(_, key)| key)
+    }
+
+    /// Takes the least recently used key, see [`LruOrder::front`].
+    pub fn pop(&mut self, stamp_of: impl Fn(&K) -> Option<u64>) -> Option<K> {
+        self.front(stamp_of)?;
+
+        self.order.pop_front().map(|(_, key)| key)
+    }
+
+    /// Drops the positions left behind once they outnumber the `live` keys,
+    /// so the order doesn't grow with keys used over and over.
+    pub fn compact(&mut self, live: usize, stamp_of: impl Fn(&K) -> Option<u64>) {
+        if self.order.len() > 2 * live + 16 {
+            self.order
+                .retain(|(stamp, key)| stamp_of(key) == Some(*stamp));
+        }
+    }
+
+    #[cfg(test)]
+    pub fn positions(&self) -> usize {
+        self.order.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LruOrder;
+    use std::collections::HashMap;
+
+    #[test]
+    fn least_recently_used_first() {
+        let mut order = LruOrder::new();
+        let mut stamps = HashMap::new();
+
+        for key in &["a", "b", "c"] {
+            stamps.insert(*key, order.touch(*key));
+        }
+        stamps.insert("a", order.touch("a"));
+        stamps.remove("b");
+
+        let popped: Vec<_> = (0..3)
+            .filter_map(|_| {
+                let key = order.pop(|key| stamps.get(key).cloned())?;
+                stamps.remove(key);
+                Some(key)
+            })
+            .collect();
+        assert_eq!(popped, vec!["c", "a"]);
+    }
+
+    #[test]
+    fn compacted() {
+        let mut order = LruOrder::new();
+        let mut stamp = 0;
+
+        for _ in 0..1000 {
+            stamp = order.touch("a");
+            order.compact(1, |_| Some(stamp));
+        }
+
+        assert!(order.positions() <= 2 + 16 + 1);
+        assert_eq!(order.pop(|_| Some(stamp)), Some("a"));
+    }
+}
@@ -1,75 +1,220 @@
+use self::{
+    external_address::Identity,
+    keep_alive::ReachabilityTracker,
+    quotas::AnnounceQuotas,
+    reannounce::Registration,
+    response_cache::ResponseCache,
+    tokens::UsedTokens,
+};
 use crate::{
-    errors::{
-        ErrorKind,
-        Result,
-    },
+    crawler::Crawler,
+    errors::Result,
     routing::{
         Node,
         RoutingTable,
     },
+    shutdown::Shutdown,
 };
-use futures::{
-    future,
-    TryStreamExt,
-};
+use futures::future;
 use krpc_encoding::{
     NodeID,
     NodeInfo,
 };
 use std::{
     collections::HashMap,
+    future::Future,
     net::{
         SocketAddr,
         SocketAddrV4,
     },
     pin::Pin,
     sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
         Arc,
         Mutex,
+        MutexGuard,
+    },
+    time::{
+        Duration,
+        Instant,
     },
-    time::Duration,
-};
-use tokio::{
-    net::UdpSocket,
-    prelude::FutureExt,
-};
-use tokio_krpc::{
-    KRPCNode,
-    PortType,
-    SendTransport,
 };
+use tokio::prelude::FutureExt;
+use tokio_krpc::Transport;
 
+mod bootstrap_progress;
+mod builder;
+mod external_address;
 mod handler;
+mod keep_alive;
+mod lookups;
+mod lru;
+mod quotas;
+mod reannounce;
+mod response_cache;
+mod self_check;
+#[cfg(feature = "tower")]
+mod service;
+mod subtree;
+mod tokens;
+
+#[cfg(feature = "tower")]
+pub use self::service::{
+    DhtQueryService,
+    HandlerFuture,
+    QueryHandler,
+};
+pub use self::{
+    bootstrap_progress::{
+        BootstrapEvent,
+        BootstrapFailure,
+    },
+    builder::DhtBuilder,
+    external_address::EXTERNAL_IP_VOTES,
+    keep_alive::{
+        KeepAliveConfig,
+        Reachability,
+        DEFAULT_KEEP_ALIVE_INTERVAL,
+    },
+    lookups::LiveNode,
+    quotas::{
+        AnnounceQuotaConfig,
+        ANNOUNCE_QUOTA_WINDOW,
+        PEER_TTL,
+    },
+    reannounce::{
+        AnnounceHandle,
+        AnnounceStatus,
+        ReannounceConfig,
+        DEFAULT_REANNOUNCE_INTERVAL,
+    },
+    response_cache::{
+        ResponseCacheStats,
+        RESPONSE_CACHE_CAPACITY,
+        RESPONSE_CACHE_TTL,
+    },
+    self_check::{
+        SelfCheckProblem,
+        SelfCheckReport,
+        SELF_CHECK_QUERIES,
+        SELF_CHECK_TIMEOUT,
+    },
+    subtree::DEFAULT_SUBTREE_LOOKUPS,
+    tokens::TOKEN_WINDOW,
+};
 
 /// BitTorrent DHT node
 #[derive(Clone)]
 pub struct Dht {
-    id: NodeID,
+    /// Replaced once our external address is known, see
+    /// [`EXTERNAL_IP_VOTES`].
+    id: Arc<Mutex<NodeID>>,
     torrents: Arc<Mutex<HashMap<NodeID, Vec<SocketAddrV4>>>>,
-    send_transport: Arc<SendTransport>,
+    send_transport: Arc<dyn Transport>,
     routing_table: Arc<Mutex<RoutingTable>>,
+
+    /// Nodes queued as replacements in full buckets, whose buckets are
+    /// probed for questionable nodes once the lookup adding them is done.
+    queued_evictions: Arc<Mutex<Vec<NodeID>>>,
+
+    reachability: Arc<Mutex<ReachabilityTracker>>,
+
+    /// Limits on the peers stored from announces of each address.
+    announce_quotas: Arc<Mutex<AnnounceQuotas>>,
+
+    /// Tokens already used to announce.
+    used_tokens: Arc<Mutex<UsedTokens>>,
+
+    /// Info-hashes kept announced by [`register_announce`].
+    announces: Arc<Mutex<Vec<Registration>>>,
+
+    /// Responses sent again for retransmitted queries.
+    response_cache: Arc<Mutex<ResponseCache>>,
+
+    /// Our address as seen by other nodes.
+    external_address: Arc<Mutex<Option<SocketAddrV4>>>,
+
+    /// Address the socket is bound to, when the node has a socket of its own.
+    local_address: Option<SocketAddrV4>,
+
+    /// Number of times other nodes returned us in `find_node` responses.
+    own_id_echoes: Arc<AtomicUsize>,
+
+    /// Number of nodes returned in `find_node` responses with our id at an
+    /// address other than ours.
+    spoofed_own_ids: Arc<AtomicUsize>,
+
+    /// Stops the tasks started by this node. See [`shutdown`].
+    shutdown: Shutdown,
 }
 
 impl Dht {
-    /// Start handling inbound messages from other peers in the network.
-    /// Continues to handle while the future is polled.
+    /// Start handling inbound messages from other peers in the network with
+    /// a random id. See [`DhtBuilder::start`].
     pub fn start(bind_addr: SocketAddr) -> Result<(Dht, impl future::Future<Output = ()>)> {
-        let socket = UdpSocket::bind(&bind_addr).map_err(|cause| ErrorKind::BindError { cause })?;
-        let transport = KRPCNode::new(socket);
-        let (send_transport, request_stream) = transport.serve();
+        Dht::builder(bind_addr).start()
+    }
 
-        let id = NodeID::random();
-        let torrents = HashMap::new();
-        let routing_table = RoutingTable::new(id.clone());
+    /// Like [`start`] with a known id. See [`DhtBuilder::identity_file`] for
+    /// keeping it across restarts.
+    pub fn start_with_id(
+        bind_addr: SocketAddr,
+        id: NodeID,
+    ) -> Result<(Dht, impl future::Future<Output = ()>)> {
+        Dht::builder(bind_addr).id(id).start()
+    }
 
-        let dht = Dht {
+    /// Options for a node bound to `bind_addr`.
+    pub fn builder(bind_addr: SocketAddr) -> DhtBuilder {
+        DhtBuilder::new(bind_addr)
+    }
+
+    /// Creates a node sending its queries through `send_transport`, like a
+    /// mock in tests, instead of a UDP socket of its own. Nothing answers
+    /// inbound queries unless the transport passes them on.
+    pub fn with_transport(id: NodeID, send_transport: Arc<dyn Transport>) -> Dht {
+        Dht::with_identity(Identity::new(id), send_transport)
+    }
+
+    fn with_identity(identity: Identity, send_transport: Arc<dyn Transport>) -> Dht {
+        let Identity {
             id,
-            torrents: Arc::new(Mutex::new(torrents)),
-            send_transport: Arc::new(send_transport),
-            routing_table: Arc::new(Mutex::new(routing_table)),
-        };
+            routing_table,
+            external_address,
+            ..
+        } = identity;
+
+        Dht {
+            id,
+            torrents: Arc::new(Mutex::new(HashMap::new())),
+            send_transport,
+            routing_table,
+            queued_evictions: Arc::new(Mutex::new(Vec::new())),
+            reachability: Arc::new(Mutex::new(ReachabilityTracker::new())),
+            announce_quotas: Arc::new(Mutex::new(AnnounceQuotas::new(
+                AnnounceQuotaConfig::default(),
+            ))),
+            used_tokens: Arc::new(Mutex::new(UsedTokens::new(Instant::now()))),
+            announces: Arc::new(Mutex::new(Vec::new())),
+            response_cache: Arc::new(Mutex::new(ResponseCache::new(RESPONSE_CACHE_CAPACITY))),
+            external_address,
+            local_address: None,
+            own_id_echoes: Arc::new(AtomicUsize::new(0)),
+            spoofed_own_ids: Arc::new(AtomicUsize::new(0)),
+            shutdown: Shutdown::new(),
+        }
+    }
 
-        Ok((dht.clone(), dht.handle_requests(request_stream.err_into())))
+    /// Node id of this node. Changes when our external address does, see
+    /// [`start_with_id`].
+    pub fn id(&self) -> NodeID {
+        self.id
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
     }
 
     /// Bootstraps the routing table by finding nodes near our node id and
@@ -77,7 +222,7 @@ impl Dht {
     pub async fn bootstrap_routing_table(&self, addrs: Vec<SocketAddrV4>) -> Result<()> {
         let send_transport = self.send_transport.clone();
         let routing_table_arc = self.routing_table.clone();
-        let id = self.id.clone();
+        let id = self.id();
 
         future::join_all(addrs.into_iter().map(move |addr| {
             Self::discover_nodes_of(
@@ -95,7 +240,7 @@ impl Dht {
     async fn discover_nodes_of(
         addr: SocketAddrV4,
         self_id: NodeID,
-        send_transport: Arc<SendTransport>,
+        send_transport: Arc<dyn Transport>,
         routing_table_arc: Arc<Mutex<RoutingTable>>,
     ) -> Result<()> {
         let response = send_transport
@@ -103,7 +248,7 @@ impl Dht {
             .timeout(Duration::from_secs(3))
             .await??;
 
-        let mut node = Node::new(response.id, addr.into());
+        let node = Node::new(response.id, addr.into());
         node.mark_successful_request();
 
         {
@@ -129,7 +274,7 @@ impl Dht {
     async fn discover_neighbors_of(
         node: NodeInfo,
         self_id: NodeID,
-        send_transport: Arc<SendTransport>,
+        send_transport: Arc<dyn Transport>,
         routing_table_arc: Arc<Mutex<RoutingTable>>,
     ) {
         Self::discover_nodes_of(node.address, self_id, send_transport, routing_table_arc)
@@ -137,19 +282,133 @@ impl Dht {
             .unwrap_or_else(|e| eprintln!("Error While Bootstrapping {}", e));
     }
 
-    /// Gets a list of peers seeding `info_hash`.
-    pub async fn get_peers(&self, _info_hash: NodeID) -> Result<Vec<SocketAddrV4>> {
-        // TODO:
-        // * Return From torrents Table if Exists
-        // * Fetch By Calling get_nodes otherwise
-        unimplemented!()
+    /// Sets our address as seen by other nodes, learned for example through
+    /// STUN. Nodes started with [`start`] set it themselves once enough
+    /// nodes agree on the `ip` field of their [BEP-0042] messages. Lookups
+    /// never query this address, and nodes returned with our id anywhere
+    /// else are counted in [`spoofed_own_ids`].
+    ///
+    /// [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+    pub fn set_external_address(&self, address: SocketAddrV4) {
+        *self
+            .external_address
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(address);
     }
 
-    /// Announces that we have information about an info_hash on `port`.
-    pub async fn announce(&self, _info_hash: NodeID, _port: PortType) -> Result<()> {
-        // TODO:
-        // * Send Announce to all Peers With Tokens
-        unimplemented!()
+    /// Our address as seen by other nodes, if known.
+    pub fn external_address(&self) -> Option<SocketAddrV4> {
+        *self
+            .external_address
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Stores `peer` for `info_hash` as if it was announced to this node.
+    #[cfg(test)]
+    pub(crate) fn store_peer(&self, info_hash: NodeID, peer: SocketAddrV4) {
+        self.torrents
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(info_hash)
+            .or_insert_with(Vec::new)
+            .push(peer);
+    }
+
+    /// Number of times other nodes returned us as one of the nodes close to a
+    /// lookup target.
+    pub fn own_id_echoes(&self) -> usize {
+        self.own_id_echoes.load(Ordering::Relaxed)
+    }
+
+    /// Number of times other nodes returned a node with our id at an address
+    /// other than our [`external_address`]. Only counted once the external
+    /// address is known.
+    pub fn spoofed_own_ids(&self) -> usize {
+        self.spoofed_own_ids.load(Ordering::Relaxed)
+    }
+
+    /// Replaces the limits on peers stored from announces. Quota already
+    /// used is kept.
+    pub fn set_announce_quotas(&self, config: AnnounceQuotaConfig) {
+        self.announce_quotas
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .set_config(config);
+    }
+
+    /// Number of announces rejected for going over quota.
+    pub fn rejected_announces(&self) -> usize {
+        self.announce_quotas
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .rejected()
+    }
+
+    /// Number of announces rejected for using a token which was already used
+    /// from the same address.
+    pub fn replayed_tokens(&self) -> usize {
+        self.used_tokens
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .replayed()
+    }
+
+    /// Queries answered with a cached response because they were
+    /// retransmitted, and queries answered from scratch.
+    pub fn response_cache_stats(&self) -> ResponseCacheStats {
+        self.lock_response_cache().stats()
+    }
+
+    fn lock_response_cache(&self) -> MutexGuard<'_, ResponseCache> {
+        self.response_cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Creates a [`Crawler`] which starts from the nodes in this node's
+    /// routing table.
+    pub fn crawler(&self) -> Crawler {
+        Crawler::new(
+            self.id(),
+            self.send_transport.clone(),
+            self.routing_table.clone(),
+            self.shutdown.child(),
+        )
+    }
+
+    /// Refreshes the buckets of the routing table which didn't change within
+    /// `interval`, like every [`DEFAULT_REFRESH_INTERVAL`]. See
+    /// [`RoutingTable::refresh_task`]. Runs until dropped or [`shutdown`].
+    ///
+    /// [`DEFAULT_REFRESH_INTERVAL`]: crate::routing::DEFAULT_REFRESH_INTERVAL
+    pub fn refresh_buckets(self, interval: Duration) -> impl Future<Output = ()> {
+        // Registered before the future is first polled, so a shutdown right
+        // after spawning it still waits for it.
+        let task = self.shutdown.register_task();
+
+        async move {
+            let _task = task;
+
+            self.shutdown
+                .run_until_triggered(RoutingTable::refresh_task(
+                    self.routing_table.clone(),
+                    self.send_transport.clone(),
+                    interval,
+                ))
+                .await;
+        }
+    }
+
+    /// Stops handling requests, [`keep_alive`], [`refresh_buckets`] and the
+    /// streams of every
+    /// [`Crawler`] created from this node. Queries in flight and any sent
+    /// afterwards fail with [`ErrorKind::ShuttingDown`] from the transport.
+    /// Resolves once all those tasks have exited.
+    pub async fn shutdown(&self) {
+        self.shutdown.trigger();
+        self.send_transport.shutdown();
+        self.shutdown.tasks_finished().await;
     }
 }
 
@@ -160,11 +419,62 @@ mod tests {
             AsV4Address,
             IntoSocketAddr,
         },
+        dht::{
+            KeepAliveConfig,
+            EXTERNAL_IP_VOTES,
+        },
         errors::Error as DhtError,
+        identity::{
+            IdentityConfig,
+            IdentityStore,
+        },
+        routing::Node,
+        testing::{
+            MockTransport,
+            NetworkConfig,
+            SimulatedNetwork,
+        },
         Dht,
     };
     use failure::Error;
-    use tokio::runtime::current_thread::Runtime;
+    use futures::{
+        executor::block_on,
+        future::{
+            self,
+            Future,
+        },
+        task::noop_waker,
+    };
+    use krpc_encoding::{
+        Addr,
+        Envelope,
+        Message,
+        NodeID,
+        Query,
+    };
+    use std::{
+        env,
+        fs,
+        io,
+        net::{
+            Ipv4Addr,
+            UdpSocket,
+        },
+        path::PathBuf,
+        process,
+        sync::Arc,
+        task::Context,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+    use tokio::{
+        prelude::FutureExt,
+        runtime::current_thread::Runtime,
+        timer::Delay,
+    };
+    use tokio_krpc::send_errors::ErrorKind;
 
     #[test]
     #[ignore]
@@ -187,4 +497,228 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn shutdown() -> Result<(), Error> {
+        let silent = UdpSocket::bind("127.0.0.1:0")?;
+        let silent_addr = silent.local_addr()?;
+
+        let (dht, dht_future) = Dht::start("127.0.0.1:0".parse()?)?;
+
+        let mut runtime = Runtime::new()?;
+        runtime.spawn(dht_future);
+        runtime.spawn(dht.clone().keep_alive(KeepAliveConfig::default()));
+
+        let pings =
+            future::join_all((0..4).map(|_| dht.send_transport.ping(dht.id(), silent_addr)));
+        let shutdown = async {
+            Delay::new(Instant::now() + Duration::from_millis(100)).await;
+            dht.shutdown().await;
+        };
+
+        let started = Instant::now();
+        let (results, _) =
+            runtime.block_on(future::join(pings, shutdown).timeout(Duration::from_secs(5)))?;
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        for result in results {
+            match result {
+                Err(err) => match err.kind() {
+                    ErrorKind::ShuttingDown => {}
+                    kind => panic!("unexpected error {}", kind),
+                },
+                Ok(_) => panic!("silent node responded"),
+            };
+        }
+
+        // Every spawned task has exited.
+        runtime.run()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_waits_for_unpolled_tasks() {
+        let dht = Dht::with_transport(NodeID::random(), Arc::new(MockTransport::new()));
+
+        // Counted as soon as it is created, not once it first runs.
+        let task = dht.clone().keep_alive(KeepAliveConfig::default());
+
+        let waker = noop_waker();
+        let mut shutdown = Box::pin(dht.shutdown());
+        assert!(shutdown
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+
+        drop(task);
+        block_on(shutdown);
+    }
+
+    #[test]
+    fn refresh_fills_stale_bucket() -> Result<(), Error> {
+        let network = SimulatedNetwork::new(NetworkConfig::default());
+        let nodes: Vec<Dht> = (0..30).map(|_| network.add_node()).collect();
+        let addresses = network.addresses();
+        let mut runtime = Runtime::new()?;
+
+        for (idx, dht) in nodes.iter().enumerate().skip(2) {
+            runtime.block_on(dht.bootstrap_from(&addresses[1..idx]))?;
+        }
+
+        // The node only knows a single other node.
+        let dht = &nodes[0];
+        let seed = Node::new(nodes[1].id(), addresses[1]);
+        seed.mark_successful_request();
+        dht.routing_table
+            .lock()
+            .map_err(DhtError::from)?
+            .add_node(seed);
+
+        // Nothing happens before the interval elapses.
+        let _ = runtime.block_on(
+            dht.clone()
+                .refresh_buckets(Duration::from_secs(1))
+                .timeout(Duration::from_millis(50)),
+        );
+        assert_eq!(dht.routing_table.lock().map_err(DhtError::from)?.len(), 1);
+
+        let _ = runtime.block_on(
+            dht.clone()
+                .refresh_buckets(Duration::from_millis(100))
+                .timeout(Duration::from_millis(500)),
+        );
+        assert!(dht.routing_table.lock().map_err(DhtError::from)?.len() > 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn external_ip_consensus_regenerates_id() -> Result<(), Error> {
+        let (dht, dht_future) = Dht::start("127.0.0.1:0".parse()?)?;
+        let old_id = dht.id();
+
+        let known = Node::new(NodeID::random(), "10.0.0.1:6881".parse()?);
+        known.mark_successful_request();
+        dht.routing_table
+            .lock()
+            .map_err(DhtError::from)?
+            .add_node(known);
+
+        let mut runtime = Runtime::new()?;
+        runtime.spawn(dht_future);
+
+        let external: Addr = "124.31.75.21:6881".parse()?;
+        report_external_address(&mut runtime, &dht, external)?;
+        assert_eq!(dht.external_address(), Some(*external));
+
+        let id = dht.id();
+        assert_ne!(id, old_id);
+        assert!(id.is_secure_for(*external.ip()));
+
+        let routing_table = dht.routing_table.lock().map_err(DhtError::from)?;
+        assert_eq!(routing_table.id(), &id);
+        assert_eq!(routing_table.len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn identity_file_kept_across_restarts() -> Result<(), Error> {
+        let path = identity_path("dht-restart");
+
+        let (first, _) = Dht::builder("127.0.0.1:0".parse()?)
+            .identity_file(&path)
+            .start()?;
+        let (second, _) = Dht::builder("127.0.0.1:0".parse()?)
+            .identity_file(&path)
+            .start()?;
+        assert_eq!(first.id(), second.id());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn identity_file_migrated_on_consensus() -> Result<(), Error> {
+        let path = identity_path("dht-migrate");
+
+        let (dht, dht_future) = Dht::builder("127.0.0.1:0".parse()?)
+            .identity_file(&path)
+            .start()?;
+        let old_id = dht.id();
+
+        let mut runtime = Runtime::new()?;
+        runtime.spawn(dht_future);
+
+        let external: Addr = "124.31.75.21:6881".parse()?;
+        report_external_address(&mut runtime, &dht, external)?;
+
+        let id = dht.id();
+        assert_ne!(id, old_id);
+        assert!(id.is_secure_for(*external.ip()));
+        assert_eq!(dht.routing_table.lock().map_err(DhtError::from)?.id(), &id);
+
+        let store = IdentityStore::open(&path, IdentityConfig::default())?;
+        assert_eq!(store.id(), &id);
+        assert_eq!(store.external_ip(), Some(*external.ip()));
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    fn identity_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("dht_crawler-{}-{}.json", name, process::id()));
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    /// Pings `dht` from enough nodes which all report `external` as its
+    /// address to reach a consensus. Nodes in the same /24 subnet only vote
+    /// once, so the pings come from loopback addresses in different ones.
+    fn report_external_address(
+        runtime: &mut Runtime,
+        dht: &Dht,
+        external: Addr,
+    ) -> Result<(), Error> {
+        let dht_address = dht.local_address.expect("bound to an IPv4 address");
+
+        for subnet in 0..EXTERNAL_IP_VOTES {
+            let ping = Envelope {
+                ip: Some(external),
+                transaction_id: vec![subnet as u8],
+                version: None,
+                message_type: Message::Query {
+                    query: Query::Ping {
+                        id: NodeID::random(),
+                    },
+                },
+                read_only: false,
+            };
+            let peer = UdpSocket::bind((Ipv4Addr::new(127, 0, subnet as u8, 1), 0))?;
+            peer.set_nonblocking(true)?;
+            peer.send_to(&ping.encode()?, dht_address)?;
+
+            runtime.block_on(receive(&peer).timeout(Duration::from_secs(1)))??;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the response to a query sent from the non-blocking `socket`
+    /// while the runtime keeps serving the node.
+    async fn receive(socket: &UdpSocket) -> io::Result<()> {
+        let mut buffer = [0u8; 1500];
+
+        loop {
+            match socket.recv_from(&mut buffer) {
+                Ok(_) => return Ok(()),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    Delay::new(Instant::now() + Duration::from_millis(10)).await
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
 }
@@ -0,0 +1,413 @@
+//! Limits how many peers a single address can store through `announce_peer`
+//! so one client can't fill the peer store with fake announces.
+
+use super::lru::LruOrder;
+use krpc_encoding::NodeID;
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    net::{
+        Ipv4Addr,
+        SocketAddrV4,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// Window over which the distinct info-hashes announced from an address are
+/// counted.
+pub const ANNOUNCE_QUOTA_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// How long an announced peer is stored after its last announce.
+pub const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Options for [`Dht::set_announce_quotas`].
+///
+/// [`Dht::set_announce_quotas`]: crate::Dht::set_announce_quotas
+#[derive(Clone, Debug)]
+pub struct AnnounceQuotaConfig {
+    /// Most distinct info-hashes an address may announce per
+    /// [`ANNOUNCE_QUOTA_WINDOW`].
+    pub info_hashes_per_window: usize,
+
+    /// Most peers an address may have stored.
+    pub peers_per_address: usize,
+
+    /// Most addresses quotas are kept for. The address which announced least
+    /// recently is forgotten to make room for another, along with the quota
+    /// it used.
+    pub max_tracked_addresses: usize,
+}
+
+impl Default for AnnounceQuotaConfig {
+    fn default() -> AnnounceQuotaConfig {
+        AnnounceQuotaConfig {
+            info_hashes_per_window: 50,
+            peers_per_address: 200,
+            max_tracked_addresses: 10_000,
+        }
+    }
+}
+
+/// Quota used by a single address.
+struct AddressQuota {
+    /// Start of the window `info_hashes` were announced in.
+    window_start: Instant,
+
+    /// Distinct info-hashes announced since `window_start`.
+    info_hashes: HashSet<NodeID>,
+
+    /// Peers stored for this address.
+    stored_peers: usize,
+
+    /// Stamp of the address in [`AnnounceQuotas::address_order`].
+    stamp: u64,
+}
+
+/// Peer stored for an info-hash from an address.
+struct StoredPeer {
+    port: u16,
+    last_announce: Instant,
+
+    /// Stamp of the peer in [`AnnounceQuotas::peer_order`].
+    stamp: u64,
+}
+
+/// What storing an announced peer did.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Stored {
+    /// The peer is new and used quota.
+    New,
+
+    /// The peer was already stored and announcing it kept it for longer.
+    Refreshed,
+
+    /// The peer replaced the one stored from the same address on another
+    /// port, which gave its quota to the new peer.
+    Replaced(SocketAddrV4),
+
+    /// The address is over quota and the peer wasn't stored.
+    OverQuota,
+}
+
+/// Tracks the quota used by each address which announced, along with the
+/// peers stored for it so their quota is given back once they expire.
+pub(crate) struct AnnounceQuotas {
+    config: AnnounceQuotaConfig,
+    addresses: HashMap<Ipv4Addr, AddressQuota>,
+
+    /// Addresses by when they last announced.
+    address_order: LruOrder<Ipv4Addr>,
+
+    peers: HashMap<(NodeID, Ipv4Addr), StoredPeer>,
+
+    /// Peers by when they were last announced, so the ones expiring first
+    /// are in front.
+    peer_order: LruOrder<(NodeID, Ipv4Addr)>,
+
+    /// Announces rejected for going over quota.
+    rejected: usize,
+}
+
+impl AnnounceQuotas {
+    pub fn new(config: AnnounceQuotaConfig) -> AnnounceQuotas {
+        AnnounceQuotas {
+            config,
+            addresses: HashMap::new(),
+            address_order: LruOrder::new(),
+            peers: HashMap::new(),
+            peer_order: LruOrder::new(),
+            rejected: 0,
+        }
+    }
+
+    pub fn set_config(&mut self, config: AnnounceQuotaConfig) {
+        self.config = config;
+    }
+
+    /// Stores `peer` announced for `info_hash`, using the quota of its
+    /// address if it isn't stored already. Only one port is stored for each
+    /// address and info-hash.
+    pub fn try_store(&mut self, peer: SocketAddrV4, info_hash: &NodeID, now: Instant) -> Stored {
+        let address = *peer.ip();
+        if !self.addresses.contains_key(&address) {
+            self.make_room();
+        }
+
+        let stamp = self.address_order.touch(address);
+        let quota = self
+            .addresses
+            .entry(address)
+            .or_insert_with(|| AddressQuota {
+                window_start: now,
+                info_hashes: HashSet::new(),
+                stored_peers: 0,
+                stamp,
+            });
+        quota.stamp = stamp;
+
+        let addresses = &self.addresses;
+        self.address_order.compact(addresses.len(), |address| {
+            addresses.get(address).map(|quota| quota.stamp)
+        });
+
+        let key = (info_hash.clone(), address);
+        let stored = match self.peers.get(&key) {
+            Some(stored) if stored.port == peer.port() => Stored::Refreshed,
+            Some(stored) => Stored::Replaced(SocketAddrV4::new(address, stored.port)),
+            None => {
+                let quota = self.addresses.get_mut(&address).unwrap();
+                if now.duration_since(quota.window_start) >= ANNOUNCE_QUOTA_WINDOW {
+                    quota.window_start = now;
+                    quota.info_hashes.clear();
+                }
+
+                let new_info_hash = !quota.info_hashes.contains(info_hash);
+                if quota.stored_peers >= self.config.peers_per_address
+                    || (new_info_hash
+                        && quota.info_hashes.len() >= self.config.info_hashes_per_window)
+                {
+                    self.rejected += 1;
+                    return Stored::OverQuota;
+                }
+
+                if new_info_hash {
+                    quota.info_hashes.insert(info_hash.clone());
+                }
+                quota.stored_peers += 1;
+
+                Stored::New
+            }
+        };
+
+        let stamp = self.peer_order.touch(key.clone());
+        self.peers.insert(
+            key,
+            StoredPeer {
+                port: peer.port(),
+                last_announce: now,
+                stamp,
+            },
+        );
+
+        let peers = &self.peers;
+        self.peer_order
+            .compact(peers.len(), |key| peers.get(key).map(|peer| peer.stamp));
+
+        stored
+    }
+
+    /// Forgets the peers last announced [`PEER_TTL`] or longer before `now`,
+    /// giving their quota back. Returns the info-hash and address of each so
+    /// they can be removed from the peer store.
+    pub fn expire(&mut self, now: Instant) -> Vec<(NodeID, SocketAddrV4)> {
+        let mut expired = Vec::new();
+
+        loop {
+            let peers = &self.peers;
+            let key = match self
+                .peer_order
+                .front(|key| peers.get(key).map(|peer| peer.stamp))
+            {
+                Some(key) => key.clone(),
+                None => break,
+            };
+
+            if now.duration_since(self.peers[&key].last_announce) < PEER_TTL {
+                break;
+            }
+
+            let peers = &self.peers;
+            self.peer_order
+                .pop(|key| peers.get(key).map(|peer| peer.stamp));
+
+            let stored = self.peers.remove(&key).unwrap();
+            let (info_hash, address) = key;
+            if let Some(quota) = self.addresses.get_mut(&address) {
+                quota.stored_peers = quota.stored_peers.saturating_sub(1);
+            }
+
+            expired.push((info_hash, SocketAddrV4::new(address, stored.port)));
+        }
+
+        expired
+    }
+
+    /// Forgets the addresses which announced least recently until there is
+    /// room for another.
+    fn make_room(&mut self) {
+        while !self.addresses.is_empty()
+            && self.addresses.len() >= self.config.max_tracked_addresses
+        {
+            let addresses = &self.addresses;
+            let oldest = self
+                .address_order
+                .pop(|address| addresses.get(address).map(|quota| quota.stamp));
+
+            match oldest {
+                Some(oldest) => self.addresses.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+
+    /// Number of addresses quotas are kept for.
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.rejected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AnnounceQuotaConfig,
+        AnnounceQuotas,
+        Stored,
+        ANNOUNCE_QUOTA_WINDOW,
+        PEER_TTL,
+    };
+    use krpc_encoding::NodeID;
+    use std::{
+        net::{
+            Ipv4Addr,
+            SocketAddrV4,
+        },
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+
+    #[test]
+    fn flooder_cut_off() {
+        let config = AnnounceQuotaConfig::default();
+        let mut quotas = AnnounceQuotas::new(config.clone());
+        let now = Instant::now();
+        let flooder = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+        let client = SocketAddrV4::new(Ipv4Addr::new(10, 0, 1, 1), 6881);
+
+        let accepted = (0..1000)
+            .filter(|_| quotas.try_store(flooder, &NodeID::random(), now) == Stored::New)
+            .count();
+        assert_eq!(accepted, config.info_hashes_per_window);
+        assert_eq!(quotas.rejected(), 1000 - accepted);
+
+        for _ in 0..3 {
+            assert_eq!(
+                quotas.try_store(client, &NodeID::random(), now),
+                Stored::New
+            );
+        }
+        assert_eq!(quotas.rejected(), 1000 - accepted);
+    }
+
+    #[test]
+    fn info_hash_quota_expires() {
+        let config = AnnounceQuotaConfig {
+            info_hashes_per_window: 1,
+            ..AnnounceQuotaConfig::default()
+        };
+        let mut quotas = AnnounceQuotas::new(config);
+        let now = Instant::now();
+        let address = Ipv4Addr::new(10, 0, 0, 1);
+        let info_hash = NodeID::random();
+
+        let peer = SocketAddrV4::new(address, 6881);
+        assert_eq!(quotas.try_store(peer, &info_hash, now), Stored::New);
+        let other_port = SocketAddrV4::new(address, 6882);
+        assert_eq!(
+            quotas.try_store(other_port, &info_hash, now),
+            Stored::Replaced(peer)
+        );
+        assert_eq!(
+            quotas.try_store(peer, &NodeID::random(), now),
+            Stored::OverQuota
+        );
+
+        let later = now + ANNOUNCE_QUOTA_WINDOW + Duration::from_secs(1);
+        assert_eq!(
+            quotas.try_store(peer, &NodeID::random(), later),
+            Stored::New
+        );
+    }
+
+    #[test]
+    fn stored_peers_limited() {
+        let config = AnnounceQuotaConfig {
+            peers_per_address: 5,
+            ..AnnounceQuotaConfig::default()
+        };
+        let mut quotas = AnnounceQuotas::new(config);
+        let now = Instant::now();
+        let peer = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+
+        let accepted = (0..10)
+            .filter(|_| quotas.try_store(peer, &NodeID::random(), now) == Stored::New)
+            .count();
+        assert_eq!(accepted, 5);
+    }
+
+    #[test]
+    fn quota_freed_by_expiry() {
+        let config = AnnounceQuotaConfig {
+            peers_per_address: 2,
+            ..AnnounceQuotaConfig::default()
+        };
+        let mut quotas = AnnounceQuotas::new(config);
+        let start = Instant::now();
+        let peer = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+        let (first, second) = (NodeID::random(), NodeID::random());
+
+        assert_eq!(quotas.try_store(peer, &first, start), Stored::New);
+        let later = start + PEER_TTL / 2;
+        assert_eq!(quotas.try_store(peer, &second, later), Stored::New);
+        assert_eq!(
+            quotas.try_store(peer, &NodeID::random(), later),
+            Stored::OverQuota
+        );
+
+        // Announcing again keeps the first peer past its original expiry.
+        assert_eq!(quotas.try_store(peer, &first, later), Stored::Refreshed);
+        assert!(quotas.expire(start + PEER_TTL).is_empty());
+
+        let expiry = later + PEER_TTL;
+        let expired = quotas.expire(expiry);
+        assert_eq!(expired.len(), 2);
+        assert!(expired.contains(&(first, peer)));
+        assert!(expired.contains(&(second, peer)));
+
+        assert_eq!(
+            quotas.try_store(peer, &NodeID::random(), expiry),
+            Stored::New
+        );
+    }
+
+    #[test]
+    fn tracked_addresses_bounded() {
+        let config = AnnounceQuotaConfig {
+            max_tracked_addresses: 100,
+            ..AnnounceQuotaConfig::default()
+        };
+        let mut quotas = AnnounceQuotas::new(config);
+        let start = Instant::now();
+
+        for host in 0..1000u32 {
+            let now = start + Duration::from_millis(host.into());
+            let peer = SocketAddrV4::new(host.into(), 6881);
+            assert_eq!(quotas.try_store(peer, &NodeID::random(), now), Stored::New);
+            assert!(quotas.len() <= 100);
+        }
+
+        // The most recent addresses are the ones kept.
+        assert!(quotas.addresses.contains_key(&Ipv4Addr::from(999)));
+        assert!(!quotas.addresses.contains_key(&Ipv4Addr::from(0)));
+    }
+}
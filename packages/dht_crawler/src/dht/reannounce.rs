@@ -0,0 +1,405 @@
+//! Announces info-hashes again before the nodes storing them expire us.
+//! Nodes forget announced peers after about 30 minutes, so a single
+//! [`Dht::announce`] doesn't keep us reachable for long.
+
+use super::Dht;
+use crate::shutdown::Shutdown;
+use krpc_encoding::NodeID;
+use rand::Rng;
+use std::{
+    cmp,
+    future::Future,
+    sync::{
+        Arc,
+        Mutex,
+        MutexGuard,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::timer::Delay;
+use tokio_krpc::PortType;
+
+/// Well under the time nodes keep announced peers.
+pub const DEFAULT_REANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Options for [`Dht::register_announce_with_config`].
+#[derive(Clone, Debug)]
+pub struct ReannounceConfig {
+    /// Time between successful announces.
+    pub interval: Duration,
+
+    /// Fraction of `interval` each round is moved by at random, so
+    /// info-hashes registered together don't keep announcing together.
+    pub jitter: f64,
+
+    /// Time to wait before retrying a failed announce. Doubles with each
+    /// failure in a row, up to `interval`.
+    pub retry_delay: Duration,
+}
+
+impl Default for ReannounceConfig {
+    fn default() -> ReannounceConfig {
+        ReannounceConfig {
+            interval: DEFAULT_REANNOUNCE_INTERVAL,
+            jitter: 0.1,
+            retry_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// How the announces of a registered info-hash went.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AnnounceStatus {
+    /// When an announce was last accepted.
+    pub last_success: Option<Instant>,
+
+    /// Why the last failed announce failed.
+    pub last_error: Option<String>,
+
+    /// Announces failed since the last one accepted.
+    pub consecutive_failures: u32,
+}
+
+/// Keeps an info-hash announced. Announces stop once the handle is dropped
+/// or [`cancel`] is called.
+///
+/// [`cancel`]: AnnounceHandle::cancel
+pub struct AnnounceHandle {
+    info_hash: NodeID,
+    status: Arc<Mutex<AnnounceStatus>>,
+    shutdown: Shutdown,
+}
+
+impl AnnounceHandle {
+    pub fn info_hash(&self) -> &NodeID {
+        &self.info_hash
+    }
+
+    pub fn status(&self) -> AnnounceStatus {
+        lock_status(&self.status).clone()
+    }
+
+    /// Stops announcing, abandoning an announce in progress.
+    pub fn cancel(&self) {
+        self.shutdown.trigger();
+    }
+}
+
+impl Drop for AnnounceHandle {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// An info-hash in the registry of a [`Dht`].
+pub(crate) struct Registration {
+    info_hash: NodeID,
+    port: PortType,
+    status: Arc<Mutex<AnnounceStatus>>,
+}
+
+/// Decides when the announces of an info-hash happen.
+pub(crate) struct ReannounceSchedule {
+    config: ReannounceConfig,
+    next_round: Instant,
+    failures: u32,
+}
+
+impl ReannounceSchedule {
+    /// The first round is due at `now`.
+    pub fn new(config: ReannounceConfig, now: Instant) -> ReannounceSchedule {
+        assert!(
+            config.jitter >= 0.0 && config.jitter < 1.0,
+            "jitter must be in [0, 1)"
+        );
+
+        ReannounceSchedule {
+            config,
+            next_round: now,
+            failures: 0,
+        }
+    }
+
+    pub fn next_round(&self) -> Instant {
+        self.next_round
+    }
+
+    pub fn record_success<R: Rng>(&mut self, now: Instant, rng: &mut R) {
+        self.failures = 0;
+
+        let jitter = if self.config.jitter > 0.0 {
+            rng.gen_range(-self.config.jitter, self.config.jitter)
+        } else {
+            0.0
+        };
+        let interval = self.config.interval;
+        let secs =
+            (interval.as_secs() as f64 + f64::from(interval.subsec_nanos()) / 1e9) * (1.0 + jitter);
+
+        self.next_round = now + Duration::from_nanos((secs * 1e9) as u64);
+    }
+
+    pub fn record_failure(&mut self, now: Instant) {
+        let backoff = self
+            .config
+            .retry_delay
+            .checked_mul(1 << cmp::min(self.failures, 16))
+            .map_or(self.config.interval, |backoff| {
+                cmp::min(backoff, self.config.interval)
+            });
+
+        self.failures += 1;
+        self.next_round = now + backoff;
+    }
+}
+
+fn lock_status(status: &Mutex<AnnounceStatus>) -> MutexGuard<'_, AnnounceStatus> {
+    status
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Dht {
+    /// Keeps `info_hash` announced on `port` every
+    /// [`DEFAULT_REANNOUNCE_INTERVAL`]. See [`register_announce_with_config`].
+    ///
+    /// [`register_announce_with_config`]: Dht::register_announce_with_config
+    pub fn register_announce(
+        &self,
+        info_hash: NodeID,
+        port: PortType,
+    ) -> (AnnounceHandle, impl Future<Output = ()>) {
+        self.register_announce_with_config(info_hash, port, ReannounceConfig::default())
+    }
+
+    /// Records `info_hash` in the registry of announced info-hashes and
+    /// returns a future announcing it right away, then again every
+    /// `config.interval`, give or take the jitter. Failed announces are
+    /// retried with backoff. The future runs until the returned handle is
+    /// dropped or cancelled, or [`shutdown`], then removes `info_hash` from
+    /// the registry.
+    pub fn register_announce_with_config(
+        &self,
+        info_hash: NodeID,
+        port: PortType,
+        config: ReannounceConfig,
+    ) -> (AnnounceHandle, impl Future<Output = ()>) {
+        let status = Arc::new(Mutex::new(AnnounceStatus::default()));
+        let shutdown = self.shutdown.child();
+        let handle = AnnounceHandle {
+            info_hash: info_hash.clone(),
+            status: status.clone(),
+            shutdown: shutdown.clone(),
+        };
+
+        self.lock_announces().push(Registration {
+            info_hash: info_hash.clone(),
+            port,
+            status: status.clone(),
+        });
+
+        let dht = self.clone();
+        let guard = shutdown.register_task();
+        let task = async move {
+            let _task = guard;
+            dht.reannounce(info_hash, port, config, &status, &shutdown)
+                .await;
+
+            dht.lock_announces()
+                .retain(|registration| !Arc::ptr_eq(&registration.status, &status));
+        };
+
+        (handle, task)
+    }
+
+    /// Info-hashes registered with [`register_announce`] and how their
+    /// announces went.
+    ///
+    /// [`register_announce`]: Dht::register_announce
+    pub fn registered_announces(&self) -> Vec<(NodeID, PortType, AnnounceStatus)> {
+        self.lock_announces()
+            .iter()
+            .map(|registration| {
+                (
+                    registration.info_hash.clone(),
+                    registration.port,
+                    lock_status(&registration.status).clone(),
+                )
+            })
+            .collect()
+    }
+
+    async fn reannounce<'a>(
+        &'a self,
+        info_hash: NodeID,
+        port: PortType,
+        config: ReannounceConfig,
+        status: &'a Mutex<AnnounceStatus>,
+        shutdown: &'a Shutdown,
+    ) {
+        let mut schedule = ReannounceSchedule::new(config, Instant::now());
+
+        loop {
+            if shutdown
+                .run_until_triggered(Delay::new(schedule.next_round()))
+                .await
+                .is_none()
+            {
+                return;
+            }
+
+            let result = match shutdown
+                .run_until_triggered(self.announce(info_hash.clone(), port))
+                .await
+            {
+                Some(result) => result,
+                None => return,
+            };
+
+            let now = Instant::now();
+            let mut status = lock_status(status);
+            match result {
+                Ok(()) => {
+                    schedule.record_success(now, &mut rand::thread_rng());
+                    status.last_success = Some(now);
+                    status.consecutive_failures = 0;
+                }
+                Err(err) => {
+                    schedule.record_failure(now);
+                    status.last_error = Some(err.to_string());
+                    status.consecutive_failures += 1;
+                }
+            };
+        }
+    }
+
+    fn lock_announces(&self) -> MutexGuard<'_, Vec<Registration>> {
+        self.announces
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ReannounceConfig,
+        ReannounceSchedule,
+    };
+    use crate::{
+        testing::MockTransport,
+        Dht,
+    };
+    use failure::Error;
+    use krpc_encoding::NodeID;
+    use rand::{
+        rngs::StdRng,
+        SeedableRng,
+    };
+    use std::{
+        sync::Arc,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+    use tokio::{
+        runtime::current_thread::Runtime,
+        timer::Delay,
+    };
+    use tokio_krpc::PortType;
+
+    #[test]
+    fn rounds_scheduled_with_jitter() {
+        let start = Instant::now();
+        let mut rng = StdRng::from_seed([1; 32]);
+        let mut schedule = ReannounceSchedule::new(ReannounceConfig::default(), start);
+        assert_eq!(schedule.next_round(), start);
+
+        let mut offsets = Vec::new();
+        for _ in 0..100 {
+            schedule.record_success(start, &mut rng);
+            let offset = schedule.next_round() - start;
+            assert!(offset >= Duration::from_secs(810), "{:?}", offset);
+            assert!(offset <= Duration::from_secs(990), "{:?}", offset);
+            offsets.push(offset);
+        }
+
+        offsets.sort();
+        offsets.dedup();
+        assert!(offsets.len() > 90);
+
+        let mut schedule = ReannounceSchedule::new(
+            ReannounceConfig {
+                jitter: 0.0,
+                ..ReannounceConfig::default()
+            },
+            start,
+        );
+        schedule.record_success(start, &mut rng);
+        assert_eq!(schedule.next_round(), start + Duration::from_secs(900));
+    }
+
+    #[test]
+    fn failures_back_off() {
+        let start = Instant::now();
+        let mut rng = StdRng::from_seed([1; 32]);
+        let mut schedule = ReannounceSchedule::new(ReannounceConfig::default(), start);
+
+        let delays: Vec<u64> = (0..7)
+            .map(|_| {
+                schedule.record_failure(start);
+                (schedule.next_round() - start).as_secs()
+            })
+            .collect();
+        assert_eq!(delays, vec![30, 60, 120, 240, 480, 900, 900]);
+
+        // Success resets the backoff.
+        schedule.record_success(start, &mut rng);
+        schedule.record_failure(start);
+        assert_eq!(schedule.next_round(), start + Duration::from_secs(30));
+
+        for _ in 0..100 {
+            schedule.record_failure(start);
+        }
+        assert_eq!(schedule.next_round(), start + Duration::from_secs(900));
+    }
+
+    #[test]
+    fn cancelled() -> Result<(), Error> {
+        let dht = Dht::with_transport(NodeID::random(), Arc::new(MockTransport::new()));
+        let info_hash = NodeID::random();
+        let mut runtime = Runtime::new()?;
+
+        let (handle, task) = dht.register_announce(info_hash.clone(), PortType::Implied);
+        runtime.spawn(task);
+        assert_eq!(dht.registered_announces().len(), 1);
+
+        // Nobody to announce to.
+        runtime.block_on(Delay::new(Instant::now() + Duration::from_millis(100)));
+        let status = handle.status();
+        assert_eq!(status.last_success, None);
+        assert_eq!(status.consecutive_failures, 1);
+        assert!(status.last_error.is_some());
+
+        assert_eq!(
+            dht.registered_announces(),
+            vec![(info_hash, PortType::Implied, status)]
+        );
+
+        handle.cancel();
+        runtime.run()?;
+        assert!(dht.registered_announces().is_empty());
+
+        // Dropping the handle cancels too.
+        let (handle, task) = dht.register_announce(NodeID::random(), PortType::Port(6881));
+        drop(handle);
+        runtime.block_on(task);
+        assert!(dht.registered_announces().is_empty());
+
+        Ok(())
+    }
+}
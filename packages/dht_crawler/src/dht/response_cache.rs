@@ -0,0 +1,291 @@
+//! Remembers the responses sent recently, so a query retransmitted because
+//! our response was slow or lost is answered again without redoing the
+//! work.
+
+use super::lru::LruOrder;
+use krpc_encoding::{
+    Envelope,
+    Query,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddrV4,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// How long a response is sent again for a retransmitted query. Nodes give
+/// up on a query well before.
+pub const RESPONSE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Most responses remembered. The one used least recently is dropped to
+/// make room.
+pub const RESPONSE_CACHE_CAPACITY: usize = 1024;
+
+/// Hits and misses of the cache of responses, see
+/// [`Dht::response_cache_stats`].
+///
+/// [`Dht::response_cache_stats`]: crate::Dht::response_cache_stats
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResponseCacheStats {
+    /// Queries answered with a cached response.
+    pub hits: usize,
+
+    /// Queries answered from scratch.
+    pub misses: usize,
+}
+
+struct CachedResponse {
+    query: Query,
+    read_only: bool,
+    response: Envelope,
+    sent: Instant,
+
+    /// Stamp of the response in [`ResponseCache::order`].
+    stamp: u64,
+}
+
+type CacheKey = (SocketAddrV4, Vec<u8>);
+
+/// Responses sent recently, by querying address and transaction id.
+pub(crate) struct ResponseCache {
+    entries: HashMap<CacheKey, CachedResponse>,
+
+    /// Keys of `entries` by when they were last used.
+    order: LruOrder<CacheKey>,
+
+    capacity: usize,
+    stats: ResponseCacheStats,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> ResponseCache {
+        ResponseCache {
+            entries: HashMap::new(),
+            order: LruOrder::new(),
+            capacity,
+            stats: ResponseCacheStats::default(),
+        }
+    }
+
+    /// The response sent to `from` for the same query with the same
+    /// `transaction_id` less than [`RESPONSE_CACHE_TTL`] before `now`. A
+    /// response to another query reusing the transaction id is dropped.
+    pub fn get(
+        &mut self,
+        from: SocketAddrV4,
+        transaction_id: &[u8],
+        query: &Query,
+        read_only: bool,
+        now: Instant,
+    ) -> Option<Envelope> {
+        let key = (from, transaction_id.to_vec());
+        let fresh = match self.entries.get(&key) {
+            Some(cached) => {
+                now.duration_since(cached.sent) < RESPONSE_CACHE_TTL
+                    && cached.query == *query
+                    && cached.read_only == read_only
+            }
+            None => false,
+        };
+
+        if !fresh {
+            self.entries.remove(&key);
+            self.stats.misses += 1;
+            return None;
+        }
+
+        self.stats.hits += 1;
+        let stamp = self.order.touch(key.clone());
+        let cached = self.entries.get_mut(&key)?;
+        cached.stamp = stamp;
+        let response = cached.response.clone();
+        self.compact();
+
+        Some(response)
+    }
+
+    /// Remembers `response` sent to `from` at `now` for `query`.
+    pub fn insert(
+        &mut self,
+        from: SocketAddrV4,
+        query: Query,
+        read_only: bool,
+        response: Envelope,
+        now: Instant,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = (from, response.transaction_id.clone());
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.evict();
+        }
+
+        let stamp = self.order.touch(key.clone());
+        self.entries.insert(
+            key,
+            CachedResponse {
+                query,
+                read_only,
+                response,
+                sent: now,
+                stamp,
+            },
+        );
+        self.compact();
+    }
+
+    /// Drops the response used least recently.
+    fn evict(&mut self) {
+        let entries = &self.entries;
+        let oldest = self
+            .order
+            .pop(|key| entries.get(key).map(|cached| cached.stamp));
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+
+    fn compact(&mut self) {
+        let entries = &self.entries;
+        self.order.compact(entries.len(), |key| {
+            entries.get(key).map(|cached| cached.stamp)
+        });
+    }
+
+    pub fn stats(&self) -> ResponseCacheStats {
+        self.stats
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ResponseCache,
+        ResponseCacheStats,
+        RESPONSE_CACHE_TTL,
+    };
+    use krpc_encoding::{
+        Envelope,
+        NodeID,
+        Query,
+        Response,
+    };
+    use std::{
+        net::SocketAddrV4,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+
+    fn ping() -> Query {
+        Query::Ping {
+            id: NodeID::random(),
+        }
+    }
+
+    fn response(transaction_id: &[u8]) -> Envelope {
+        Envelope::response(
+            transaction_id.to_vec(),
+            Response::OnlyID {
+                id: NodeID::random(),
+            },
+        )
+    }
+
+    #[test]
+    fn retransmit_hits() {
+        let start = Instant::now();
+        let from: SocketAddrV4 = "10.0.0.1:6881".parse().unwrap();
+        let mut cache = ResponseCache::new(16);
+        let query = ping();
+        assert_eq!(cache.get(from, b"aa", &query, false, start), None);
+
+        let sent = response(b"aa");
+        cache.insert(from, query.clone(), false, sent.clone(), start);
+        assert_eq!(cache.get(from, b"aa", &query, false, start), Some(sent));
+
+        // Another node or transaction id misses.
+        let other: SocketAddrV4 = "10.0.0.2:6881".parse().unwrap();
+        assert_eq!(cache.get(other, b"aa", &query, false, start), None);
+        assert_eq!(cache.get(from, b"ab", &query, false, start), None);
+
+        assert_eq!(cache.stats(), ResponseCacheStats { hits: 1, misses: 3 });
+
+        // Too late for a retransmit.
+        assert_eq!(
+            cache.get(from, b"aa", &query, false, start + RESPONSE_CACHE_TTL),
+            None
+        );
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn reused_transaction_id_invalidates() {
+        let start = Instant::now();
+        let from: SocketAddrV4 = "10.0.0.1:6881".parse().unwrap();
+        let mut cache = ResponseCache::new(16);
+        let query = ping();
+        cache.insert(from, query.clone(), false, response(b"aa"), start);
+
+        assert_eq!(cache.get(from, b"aa", &ping(), false, start), None);
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.get(from, b"aa", &query, false, start), None);
+    }
+
+    #[test]
+    fn least_recently_used_evicted() {
+        let start = Instant::now();
+        let mut cache = ResponseCache::new(2);
+        let queries: Vec<(SocketAddrV4, Query)> = (0..3)
+            .map(|idx| (SocketAddrV4::new([10, 0, idx, 1].into(), 6881), ping()))
+            .collect();
+
+        for (from, query) in &queries[..2] {
+            cache.insert(*from, query.clone(), false, response(b"aa"), start);
+        }
+        let (first, first_query) = &queries[0];
+        let now = start + Duration::from_secs(1);
+        assert!(cache.get(*first, b"aa", first_query, false, now).is_some());
+
+        let (third, third_query) = &queries[2];
+        cache.insert(*third, third_query.clone(), false, response(b"aa"), now);
+
+        assert_eq!(cache.len(), 2);
+        let (second, second_query) = &queries[1];
+        assert!(cache
+            .get(*second, b"aa", second_query, false, now)
+            .is_none());
+        assert!(cache.get(*first, b"aa", first_query, false, now).is_some());
+        assert!(cache.get(*third, b"aa", third_query, false, now).is_some());
+    }
+
+    #[test]
+    fn order_bounded() {
+        let start = Instant::now();
+        let from: SocketAddrV4 = "10.0.0.1:6881".parse().unwrap();
+        let mut cache = ResponseCache::new(16);
+        let query = ping();
+
+        for idx in 0..10_000u32 {
+            let transaction_id = idx.to_be_bytes();
+            cache.insert(from, query.clone(), false, response(&transaction_id), start);
+            assert!(cache
+                .get(from, &transaction_id, &query, false, start)
+                .is_some());
+        }
+
+        assert_eq!(cache.len(), 16);
+        assert!(cache.order.positions() <= 2 * 16 + 16 + 1);
+    }
+}
@@ -0,0 +1,308 @@
+//! Checks at startup whether the node can talk to the DHT at all, since a
+//! firewall dropping inbound UDP otherwise only shows as lookups finding no
+//! nodes.
+
+use super::Dht;
+use crate::bootstrap::Bootstrap;
+use futures::future;
+use serde_derive::Serialize;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::SocketAddrV4,
+    time::Duration,
+};
+use tokio::prelude::FutureExt;
+
+/// Most seeds queried by [`Dht::self_check`].
+pub const SELF_CHECK_QUERIES: usize = 5;
+
+/// How long [`Dht::self_check`] waits for each seed to respond.
+pub const SELF_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Something [`Dht::self_check`] found wrong with the node's setup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum SelfCheckProblem {
+    /// No seed responded. Inbound UDP is likely dropped by a firewall, or
+    /// the seeds can't be reached at all.
+    NoResponses,
+
+    /// The socket is bound to a private address. [BEP-0042] ids have to be
+    /// derived from the public address, which only other nodes can tell.
+    ///
+    /// [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+    PrivateLocalAddress,
+
+    /// Other nodes see us at another address than the socket is bound to,
+    /// so queries from nodes we never contacted may not get through.
+    BehindNat,
+}
+
+impl SelfCheckProblem {
+    pub fn description(self) -> &'static str {
+        match self {
+            SelfCheckProblem::NoResponses => {
+                "no node responded, inbound UDP is probably blocked by a firewall"
+            }
+            SelfCheckProblem::PrivateLocalAddress => {
+                "bound to a private address, ids should come from the external one"
+            }
+            SelfCheckProblem::BehindNat => {
+                "seen at another address than bound, unsolicited queries may be dropped"
+            }
+        }
+    }
+}
+
+/// Outcome of [`Dht::self_check`].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SelfCheckReport {
+    /// Seeds queried.
+    pub queried: usize,
+
+    /// Seeds which responded.
+    pub responded: usize,
+
+    /// Address the socket is bound to, if the node has a socket of its own.
+    pub local_address: Option<SocketAddrV4>,
+
+    /// Our address reported by most responding nodes in the `ip` field of
+    /// their responses.
+    pub external_address: Option<SocketAddrV4>,
+
+    pub problems: Vec<SelfCheckProblem>,
+}
+
+impl SelfCheckReport {
+    fn new(
+        queried: usize,
+        responded: usize,
+        local_address: Option<SocketAddrV4>,
+        external_address: Option<SocketAddrV4>,
+    ) -> SelfCheckReport {
+        let mut problems = Vec::new();
+        if responded == 0 {
+            problems.push(SelfCheckProblem::NoResponses);
+        }
+
+        if let Some(local) = local_address {
+            let ip = local.ip();
+            if ip.is_private() || ip.is_link_local() {
+                problems.push(SelfCheckProblem::PrivateLocalAddress);
+            }
+
+            let differs = match external_address {
+                Some(external) if ip.is_unspecified() => external.port() != local.port(),
+                Some(external) => external != local,
+                None => false,
+            };
+            if differs {
+                problems.push(SelfCheckProblem::BehindNat);
+            }
+        }
+
+        SelfCheckReport {
+            queried,
+            responded,
+            local_address,
+            external_address,
+            problems,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Describes the report over a few lines, for printing at startup.
+    pub fn summary(&self) -> String {
+        let mut summary = format!("{} of {} nodes responded\n", self.responded, self.queried);
+
+        let format_address = |address: Option<SocketAddrV4>| match address {
+            Some(address) => address.to_string(),
+            None => "unknown".to_string(),
+        };
+        let _ = writeln!(
+            summary,
+            "local address: {}",
+            format_address(self.local_address)
+        );
+        let _ = writeln!(
+            summary,
+            "external address: {}",
+            format_address(self.external_address)
+        );
+
+        for problem in &self.problems {
+            let _ = writeln!(summary, "problem: {}", problem.description());
+        }
+
+        summary
+    }
+}
+
+impl Dht {
+    /// Queries up to [`SELF_CHECK_QUERIES`] seeds of `bootstrap` for our own
+    /// id and reports whether any responded and what address they saw us
+    /// at. The routing table isn't changed. Meant to be run before
+    /// bootstrapping to tell a blocked socket apart from an empty network.
+    pub async fn self_check<'a>(&'a self, bootstrap: &'a Bootstrap) -> SelfCheckReport {
+        let seeds: Vec<SocketAddrV4> = bootstrap
+            .v4_seeds()
+            .into_iter()
+            .take(SELF_CHECK_QUERIES)
+            .collect();
+
+        let responses = future::join_all(seeds.iter().map(|seed| {
+            self.send_transport
+                .find_node(self.id(), (*seed).into(), self.id())
+                .timeout(SELF_CHECK_TIMEOUT)
+        }))
+        .await;
+
+        let mut responded = 0;
+        let mut votes: HashMap<SocketAddrV4, usize> = HashMap::new();
+        for response in responses {
+            if let Ok(Ok(response)) = response {
+                responded += 1;
+                if let Some(ip) = response.envelope().ip {
+                    *votes.entry(ip.into()).or_default() += 1;
+                }
+            }
+        }
+
+        let external_address = votes
+            .into_iter()
+            .max_by_key(|(_, votes)| *votes)
+            .map(|(address, _)| address);
+
+        SelfCheckReport::new(seeds.len(), responded, self.local_address, external_address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SelfCheckProblem;
+    use crate::{
+        testing::{
+            MockTransport,
+            Reply,
+        },
+        Bootstrap,
+        Dht,
+    };
+    use failure::Error;
+    use krpc_encoding::{
+        Envelope,
+        NodeID,
+        Response,
+    };
+    use std::{
+        net::{
+            SocketAddr,
+            SocketAddrV4,
+        },
+        sync::Arc,
+    };
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Seeds which respond, reporting us at `external` if given.
+    fn responding(mock: &MockTransport, count: u8, external: Option<SocketAddrV4>) -> Bootstrap {
+        let seeds: Vec<SocketAddr> = (0..count)
+            .map(|idx| SocketAddrV4::new([10, 0, idx, 1].into(), 6881).into())
+            .collect();
+
+        for seed in &seeds {
+            mock.respond(*seed, move |_| {
+                let mut envelope = Envelope::response(
+                    b"aa".to_vec(),
+                    Response::NextHop {
+                        id: NodeID::random(),
+                        token: None,
+                        nodes: Vec::new(),
+                    },
+                );
+                envelope.ip = external.map(Into::into);
+
+                Reply::Envelope(envelope)
+            });
+        }
+
+        Bootstrap::new(seeds)
+    }
+
+    fn dht_at(mock: Arc<MockTransport>, local: &str) -> Dht {
+        let mut dht = Dht::with_transport(NodeID::random(), mock);
+        dht.local_address = Some(local.parse().unwrap());
+        dht
+    }
+
+    #[test]
+    fn healthy() -> Result<(), Error> {
+        let mock = Arc::new(MockTransport::new());
+        let local: SocketAddrV4 = "124.31.75.21:6881".parse()?;
+        let bootstrap = responding(&mock, 3, Some(local));
+        let dht = dht_at(mock, "124.31.75.21:6881");
+
+        let report = Runtime::new()?.block_on(dht.self_check(&bootstrap));
+
+        assert_eq!((report.queried, report.responded), (3, 3));
+        assert_eq!(report.external_address, Some(local));
+        assert!(report.is_ok(), "{}", report.summary());
+
+        Ok(())
+    }
+
+    #[test]
+    fn firewalled() -> Result<(), Error> {
+        let mock = Arc::new(MockTransport::new());
+        // Nothing answers the seeds.
+        let bootstrap = Bootstrap::new(vec!["10.0.0.1:6881".parse()?, "10.0.1.1:6881".parse()?]);
+        let dht = dht_at(mock, "124.31.75.21:6881");
+
+        let report = Runtime::new()?.block_on(dht.self_check(&bootstrap));
+
+        assert_eq!((report.queried, report.responded), (2, 0));
+        assert_eq!(report.problems, vec![SelfCheckProblem::NoResponses]);
+        assert!(report.summary().contains("firewall"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn private_behind_nat() -> Result<(), Error> {
+        let mock = Arc::new(MockTransport::new());
+        let external: SocketAddrV4 = "124.31.75.21:40000".parse()?;
+        let bootstrap = responding(&mock, 8, Some(external));
+        let dht = dht_at(mock, "192.168.1.10:6881");
+
+        let report = Runtime::new()?.block_on(dht.self_check(&bootstrap));
+
+        assert_eq!(report.queried, super::SELF_CHECK_QUERIES);
+        assert_eq!(report.external_address, Some(external));
+        assert_eq!(
+            report.problems,
+            vec![
+                SelfCheckProblem::PrivateLocalAddress,
+                SelfCheckProblem::BehindNat
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn unspecified_bind_compares_ports() -> Result<(), Error> {
+        let mock = Arc::new(MockTransport::new());
+        let bootstrap = responding(&mock, 2, Some("124.31.75.21:6881".parse()?));
+        let dht = dht_at(mock.clone(), "0.0.0.0:6881");
+        assert!(Runtime::new()?.block_on(dht.self_check(&bootstrap)).is_ok());
+
+        let bootstrap = responding(&mock, 2, None);
+        let report = Runtime::new()?.block_on(dht.self_check(&bootstrap));
+        assert_eq!(report.external_address, None);
+        assert!(report.is_ok());
+
+        Ok(())
+    }
+}
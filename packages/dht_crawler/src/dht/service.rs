@@ -0,0 +1,187 @@
+//! Exposes query handling as a [`tower::Service`] so the middleware of
+//! Tower, like concurrency limits, timeouts and rate limits, can be put in
+//! front of it.
+
+use crate::{
+    addr::AsV4Address,
+    dht::Dht,
+    errors::{
+        Error,
+        Result,
+    },
+};
+use futures::future;
+use krpc_encoding::{
+    Query,
+    Response,
+};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+use tower::Service;
+
+/// Future returned by [`QueryHandler::handle`]. Boxed because traits can't
+/// have async methods yet.
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<Response>> + Send>>;
+
+/// Answers queries sent by other nodes.
+pub trait QueryHandler: Send {
+    fn handle(&self, from: SocketAddr, query: Query) -> HandlerFuture;
+}
+
+impl QueryHandler for Dht {
+    fn handle(&self, from: SocketAddr, query: Query) -> HandlerFuture {
+        let result = from
+            .into_v4()
+            .and_then(|from| self.answer(query, from, false));
+
+        Box::pin(future::ready(result))
+    }
+}
+
+/// [`Service`] answering queries with a [`QueryHandler`]. Always ready, any
+/// backpressure comes from the middleware wrapping it.
+pub struct DhtQueryService {
+    handler: Box<dyn QueryHandler>,
+}
+
+impl DhtQueryService {
+    pub fn new(handler: Box<dyn QueryHandler>) -> DhtQueryService {
+        DhtQueryService { handler }
+    }
+}
+
+impl Service<(SocketAddr, Query)> for DhtQueryService {
+    type Response = Response;
+    type Error = Error;
+    type Future = HandlerFuture;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, (from, query): (SocketAddr, Query)) -> HandlerFuture {
+        self.handler.handle(from, query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DhtQueryService,
+        HandlerFuture,
+        QueryHandler,
+    };
+    use crate::Dht;
+    use failure::Error;
+    use futures::{
+        channel::oneshot,
+        executor::block_on,
+        task::noop_waker_ref,
+        FutureExt,
+    };
+    use krpc_encoding::{
+        NodeID,
+        Query,
+        Response,
+    };
+    use std::{
+        net::SocketAddr,
+        sync::{
+            Arc,
+            Mutex,
+        },
+        task::{
+            Context,
+            Poll,
+        },
+    };
+    use tower::{
+        limit::ConcurrencyLimit,
+        Service,
+    };
+
+    /// Answers each query once it's released through `pending`.
+    #[derive(Clone, Default)]
+    struct GatedHandler {
+        pending: Arc<Mutex<Vec<oneshot::Sender<()>>>>,
+    }
+
+    impl QueryHandler for GatedHandler {
+        fn handle(&self, _from: SocketAddr, _query: Query) -> HandlerFuture {
+            let (sender, receiver) = oneshot::channel();
+            self.pending.lock().unwrap().push(sender);
+
+            Box::pin(receiver.map(|_| {
+                Ok(Response::OnlyID {
+                    id: NodeID::random(),
+                })
+            }))
+        }
+    }
+
+    fn ping() -> (SocketAddr, Query) {
+        (
+            "10.0.0.1:6881".parse().unwrap(),
+            Query::Ping {
+                id: NodeID::random(),
+            },
+        )
+    }
+
+    #[test]
+    fn concurrency_limited() {
+        let handler = GatedHandler::default();
+        let mut service = ConcurrencyLimit::new(DhtQueryService::new(Box::new(handler.clone())), 2);
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let mut calls = Vec::new();
+        for _ in 0..2 {
+            match service.poll_ready(&mut cx) {
+                Poll::Ready(Ok(())) => calls.push(service.call(ping())),
+                _ => panic!("service not ready"),
+            }
+        }
+
+        // The third query waits for one of the first two.
+        assert!(service.poll_ready(&mut cx).is_pending());
+        assert_eq!(handler.pending.lock().unwrap().len(), 2);
+        for call in &mut calls {
+            assert!(call.poll_unpin(&mut cx).is_pending());
+        }
+
+        let sender = handler.pending.lock().unwrap().remove(0);
+        sender.send(()).unwrap();
+        match calls[0].poll_unpin(&mut cx) {
+            Poll::Ready(Ok(Response::OnlyID { .. })) => {}
+            _ => panic!("first query not answered"),
+        }
+        drop(calls.remove(0));
+
+        match service.poll_ready(&mut cx) {
+            Poll::Ready(Ok(())) => calls.push(service.call(ping())),
+            _ => panic!("service not ready after a query was answered"),
+        }
+        assert_eq!(handler.pending.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn dht_answers() -> Result<(), Error> {
+        let (dht, _) = Dht::start("127.0.0.1:0".parse()?)?;
+        let id = dht.id();
+        let mut service = DhtQueryService::new(Box::new(dht));
+
+        match block_on(service.call(ping()))? {
+            Response::OnlyID { id: response_id } => assert_eq!(response_id, id),
+            response => panic!("unexpected response {:?}", response),
+        };
+
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+//! Enumerates the nodes whose ids share a prefix, for studying one region of
+//! the keyspace instead of crawling all of it.
+
+use super::{
+    lookups::LOOKUP_TIMEOUT,
+    Dht,
+};
+use crate::{
+    lookup::{
+        Lookup,
+        LookupConfig,
+    },
+    routing::Node,
+};
+use futures::{
+    stream,
+    Stream,
+};
+use krpc_encoding::{
+    NodeID,
+    NodeInfo,
+};
+use num_bigint::BigUint;
+use std::{
+    cmp,
+    collections::{
+        HashSet,
+        VecDeque,
+    },
+};
+
+/// Lookups done by [`Dht::enumerate_subtree`].
+pub const DEFAULT_SUBTREE_LOOKUPS: usize = 1024;
+
+/// Ids from `start` up to `start + 2^(160 - bits)`, the ids starting with
+/// the first `bits` bits of `start`.
+#[derive(Clone, Debug)]
+struct Subtree {
+    start: BigUint,
+    bits: u32,
+}
+
+impl Subtree {
+    fn new(prefix: &[u8], bits: u32) -> Subtree {
+        assert!(bits <= 160, "prefix_bits must be at most 160");
+        assert!(
+            prefix.len() * 8 >= bits as usize,
+            "prefix is shorter than prefix_bits"
+        );
+
+        let mut bytes = [0u8; 20];
+        let len = cmp::min(prefix.len(), bytes.len());
+        bytes[..len].copy_from_slice(&prefix[..len]);
+
+        let shift = 160 - bits as usize;
+        let start = (BigUint::from_bytes_be(&bytes) >> shift) << shift;
+
+        Subtree { start, bits }
+    }
+
+    fn width(&self) -> BigUint {
+        BigUint::from(1u8) << (160 - self.bits as usize)
+    }
+
+    fn contains(&self, id: &NodeID) -> bool {
+        let id: &BigUint = id;
+
+        *id >= self.start && *id < &self.start + self.width()
+    }
+
+    /// Random id in the subtree.
+    fn random_target(&self) -> NodeID {
+        let random = NodeID::random();
+        let offset: &BigUint = &random;
+
+        NodeID::new(&self.start + offset % self.width())
+    }
+
+    /// The two subtrees one bit longer, or `None` for a single id.
+    fn halves(&self) -> Option<(Subtree, Subtree)> {
+        if self.bits == 160 {
+            return None;
+        }
+
+        let bits = self.bits + 1;
+        let upper = &self.start + (self.width() >> 1);
+
+        Some((
+            Subtree {
+                start: self.start.clone(),
+                bits,
+            },
+            Subtree { start: upper, bits },
+        ))
+    }
+}
+
+/// State of an enumeration between items of its stream.
+struct Enumeration {
+    dht: Dht,
+    root: Subtree,
+
+    /// Subtrees still to be looked up, widest first.
+    pending: VecDeque<Subtree>,
+
+    /// Every node in `root` found so far.
+    found: HashSet<NodeID>,
+
+    /// Nodes found but not emitted yet.
+    ready: VecDeque<NodeInfo>,
+
+    lookups_left: usize,
+}
+
+impl Enumeration {
+    /// Looks up a random id in `subtree`. Returns how many nodes of the
+    /// subtree were found for the first time.
+    async fn explore<'a>(&'a mut self, subtree: &'a Subtree) -> usize {
+        let mut lookup = Lookup::new(subtree.random_target(), LookupConfig::default());
+        if self
+            .dht
+            .drive_node_lookup(&mut lookup, LOOKUP_TIMEOUT)
+            .await
+            .is_err()
+        {
+            return 0;
+        }
+
+        let mut new = 0;
+        let mut outside = Vec::new();
+        for node in lookup.candidates() {
+            if !self.root.contains(&node.node_id) {
+                outside.push(Node::new(node.node_id, node.address));
+                continue;
+            }
+
+            if self.found.insert(node.node_id.clone()) {
+                if subtree.contains(&node.node_id) {
+                    new += 1;
+                }
+                self.ready.push_back(node);
+            }
+        }
+
+        if let Ok(mut routing_table) = self.dht.routing_table.lock() {
+            routing_table.add_nodes(outside);
+        }
+
+        new
+    }
+}
+
+async fn next_node(mut state: Enumeration) -> Option<(NodeInfo, Enumeration)> {
+    loop {
+        if let Some(node) = state.ready.pop_front() {
+            return Some((node, state));
+        }
+
+        if state.lookups_left == 0 || state.dht.shutdown.is_triggered() {
+            return None;
+        }
+
+        let subtree = state.pending.pop_front()?;
+        state.lookups_left -= 1;
+
+        // A lookup only finds the nodes closest to its target. While it
+        // finds new ones there may be more in the subtree, so both halves
+        // are looked up in turn.
+        if state.explore(&subtree).await > 0 {
+            if let Some((lower, upper)) = subtree.halves() {
+                state.pending.push_back(lower);
+                state.pending.push_back(upper);
+            }
+        }
+    }
+}
+
+impl Dht {
+    /// Finds the nodes whose ids start with the first `prefix_bits` bits of
+    /// `prefix` with up to [`DEFAULT_SUBTREE_LOOKUPS`] lookups. See
+    /// [`enumerate_subtree_with_budget`].
+    ///
+    /// [`enumerate_subtree_with_budget`]: Dht::enumerate_subtree_with_budget
+    pub fn enumerate_subtree(
+        &self,
+        prefix: &[u8],
+        prefix_bits: u32,
+    ) -> impl Stream<Item = NodeInfo> {
+        self.enumerate_subtree_with_budget(prefix, prefix_bits, DEFAULT_SUBTREE_LOOKUPS)
+    }
+
+    /// Finds the nodes whose ids start with the first `prefix_bits` bits of
+    /// `prefix`, each emitted once. A random id of the subtree is looked up,
+    /// then each half of it whenever a lookup found new nodes, until lookups
+    /// stop finding any or `max_lookups` were done. Nodes outside the
+    /// subtree are added to the routing table instead.
+    ///
+    /// Panics if `prefix_bits` is over 160 or `prefix` is shorter.
+    pub fn enumerate_subtree_with_budget(
+        &self,
+        prefix: &[u8],
+        prefix_bits: u32,
+        max_lookups: usize,
+    ) -> impl Stream<Item = NodeInfo> {
+        let root = Subtree::new(prefix, prefix_bits);
+        let mut pending = VecDeque::new();
+        pending.push_back(root.clone());
+
+        let state = Enumeration {
+            dht: self.clone(),
+            root,
+            pending,
+            found: HashSet::new(),
+            ready: VecDeque::new(),
+            lookups_left: max_lookups,
+        };
+
+        stream::unfold(state, next_node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Subtree;
+    use crate::{
+        testing::{
+            NetworkConfig,
+            SimulatedNetwork,
+        },
+        Dht,
+    };
+    use failure::Error;
+    use futures::StreamExt;
+    use krpc_encoding::{
+        NodeID,
+        NodeInfo,
+    };
+    use num_bigint::BigUint;
+    use rand::{
+        rngs::StdRng,
+        seq::sample_slice,
+        SeedableRng,
+    };
+    use std::{
+        cmp,
+        collections::HashSet,
+    };
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn subtree_ranges() {
+        let subtree = Subtree::new(&[0xab, 0xcd], 12);
+        assert_eq!(subtree.start, BigUint::from(0xabcu16) << 148);
+
+        let inside = NodeID::from_hex_str("abc0000000000000000000000000000000000000").unwrap();
+        let last = NodeID::from_hex_str("abcfffffffffffffffffffffffffffffffffffff").unwrap();
+        let outside = NodeID::from_hex_str("abd0000000000000000000000000000000000000").unwrap();
+        assert!(subtree.contains(&inside));
+        assert!(subtree.contains(&last));
+        assert!(!subtree.contains(&outside));
+
+        for _ in 0..100 {
+            assert!(subtree.contains(&subtree.random_target()));
+        }
+
+        let (lower, upper) = subtree.halves().unwrap();
+        assert!(lower.contains(&inside) && !upper.contains(&inside));
+        assert!(upper.contains(&last) && !lower.contains(&last));
+
+        assert!(Subtree::new(&[0; 20], 160).halves().is_none());
+    }
+
+    #[test]
+    fn finds_nodes_in_subtree() -> Result<(), Error> {
+        let network = SimulatedNetwork::new(NetworkConfig::default());
+        let nodes: Vec<Dht> = (0..200).map(|_| network.add_node()).collect();
+        let addresses = network.addresses();
+        let mut rng = StdRng::from_seed([5; 32]);
+        let mut runtime = Runtime::new()?;
+
+        for (idx, dht) in nodes.iter().enumerate().skip(1) {
+            let seeds = sample_slice(&mut rng, &addresses[..idx], cmp::min(idx, 3));
+            runtime.block_on(dht.bootstrap_from(&seeds))?;
+        }
+
+        // Ids starting with the bits 101.
+        let subtree = Subtree::new(&[0xa0], 3);
+        let crawler = &nodes[0];
+        let expected: HashSet<NodeID> = nodes
+            .iter()
+            .map(|dht| dht.id())
+            .filter(|id| subtree.contains(id) && id != crawler.id())
+            .collect();
+
+        let found: Vec<NodeInfo> =
+            runtime.block_on(crawler.enumerate_subtree(&[0xa0], 3).collect::<Vec<_>>());
+        let found_ids: HashSet<NodeID> = found.iter().map(|node| node.node_id.clone()).collect();
+
+        assert_eq!(found_ids.len(), found.len());
+        assert!(found_ids.iter().all(|id| subtree.contains(id)));
+        assert!(
+            found_ids.intersection(&expected).count() * 100 >= expected.len() * 95,
+            "found {} of {}",
+            found_ids.intersection(&expected).count(),
+            expected.len()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! Remembers the tokens used to announce so each can only be used once from
+//! an address, as implied by [BEP-0005]. Every `get_peers` response carries
+//! a new token, so a node announcing again asks for another.
+//!
+//! [BEP-0005]: http://www.bittorrent.org/beps/bep_0005.html
+
+use std::{
+    collections::HashSet,
+    mem,
+    net::IpAddr,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// How often the token secret changes. Tokens generated with the current or
+/// the last secret are accepted, so a token is valid for up to two windows.
+pub const TOKEN_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Tokens used to announce in the current and the last [`TOKEN_WINDOW`].
+pub(crate) struct UsedTokens {
+    current: HashSet<(Vec<u8>, IpAddr)>,
+    last: HashSet<(Vec<u8>, IpAddr)>,
+
+    /// Start of the current window.
+    window_start: Instant,
+
+    /// Announces rejected for reusing a token.
+    replayed: usize,
+}
+
+impl UsedTokens {
+    pub fn new(now: Instant) -> UsedTokens {
+        UsedTokens {
+            current: HashSet::new(),
+            last: HashSet::new(),
+            window_start: now,
+            replayed: 0,
+        }
+    }
+
+    /// Whether `token` was already used from `ip`, in which case it
+    /// shouldn't be accepted. Counted as a replay if so.
+    pub fn is_replayed(&mut self, token: &[u8], ip: IpAddr) -> bool {
+        let key = (token.to_vec(), ip);
+        if self.last.contains(&key) || self.current.contains(&key) {
+            self.replayed += 1;
+            return true;
+        }
+
+        false
+    }
+
+    /// Records that `token` was used from `ip`, once the announce it came
+    /// with was accepted.
+    pub fn record_use(&mut self, token: &[u8], ip: IpAddr) {
+        self.current.insert((token.to_vec(), ip));
+    }
+
+    /// Starts the windows which passed by `now`. Tokens used more than two
+    /// windows ago are forgotten, by then they have expired anyway. Returns
+    /// the number of windows started, at most two, so the token secret can
+    /// be changed as many times.
+    pub fn sweep(&mut self, now: Instant) -> usize {
+        let mut started = 0;
+
+        while started < 2 && now.duration_since(self.window_start) >= TOKEN_WINDOW {
+            self.last = mem::replace(&mut self.current, HashSet::new());
+            self.window_start += TOKEN_WINDOW;
+            started += 1;
+        }
+
+        // After a long quiet period the next window starts now.
+        if now.duration_since(self.window_start) >= TOKEN_WINDOW {
+            self.window_start = now;
+        }
+
+        started
+    }
+
+    pub fn replayed(&self) -> usize {
+        self.replayed
+    }
+
+    /// Number of token uses remembered.
+    pub fn len(&self) -> usize {
+        self.current.len() + self.last.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        UsedTokens,
+        TOKEN_WINDOW,
+    };
+    use std::{
+        net::IpAddr,
+        time::Instant,
+    };
+
+    #[test]
+    fn token_used_once() {
+        let mut tokens = UsedTokens::new(Instant::now());
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        assert!(!tokens.is_replayed(b"token", ip));
+        tokens.record_use(b"token", ip);
+        assert!(tokens.is_replayed(b"token", ip));
+        assert!(!tokens.is_replayed(b"token", other));
+        assert!(!tokens.is_replayed(b"other", ip));
+        assert_eq!(tokens.replayed(), 1);
+    }
+
+    #[test]
+    fn old_uses_forgotten() {
+        let start = Instant::now();
+        let mut tokens = UsedTokens::new(start);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        tokens.record_use(b"token", ip);
+
+        // Still valid in the next window, so still remembered.
+        assert_eq!(tokens.sweep(start + TOKEN_WINDOW), 1);
+        assert!(tokens.is_replayed(b"token", ip));
+
+        assert_eq!(tokens.sweep(start + TOKEN_WINDOW * 2), 1);
+        assert_eq!(tokens.len(), 0);
+    }
+
+    #[test]
+    fn sweep_after_quiet_period() {
+        let start = Instant::now();
+        let mut tokens = UsedTokens::new(start);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        tokens.record_use(b"token", ip);
+
+        let later = start + TOKEN_WINDOW * 10;
+        assert_eq!(tokens.sweep(later), 2);
+        assert_eq!(tokens.len(), 0);
+        assert_eq!(tokens.sweep(later + TOKEN_WINDOW / 2), 0);
+        assert_eq!(tokens.sweep(later + TOKEN_WINDOW), 1);
+    }
+}
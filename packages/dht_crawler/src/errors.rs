@@ -33,9 +33,18 @@ pub enum ErrorKind {
     #[fail(display = "Invalid Token")]
     InvalidToken,
 
+    #[fail(display = "Token was already used to announce")]
+    TokenReused,
+
     #[fail(display = "Insufficient address information provided")]
     InsufficientAddress,
 
+    #[fail(display = "Announce from read only node")]
+    ReadOnlyAnnounce,
+
+    #[fail(display = "Announce over quota of its address")]
+    AnnounceQuotaExceeded,
+
     //// Wrapping Other Errors
     #[fail(display = "Lock poisoned")]
     LockPoisoned,
@@ -55,11 +64,92 @@ pub enum ErrorKind {
         cause: tokio_krpc::send_errors::Error,
     },
 
+    #[fail(display = "Failed to store info-hash")]
+    StoreError {
+        #[fail(cause)]
+        cause: io::Error,
+    },
+
+    #[fail(display = "Info-hash sink full, holding {} events", capacity)]
+    SinkFull { capacity: usize },
+
+    #[fail(display = "Malformed info-hash event {}", line)]
+    InvalidEvent { line: String },
+
+    #[fail(display = "Failed to read or write snapshot")]
+    SnapshotFileError {
+        #[fail(cause)]
+        cause: io::Error,
+    },
+
+    #[fail(display = "Snapshot is malformed")]
+    InvalidSnapshot,
+
+    #[fail(display = "Event discovered too long before or after the event before it")]
+    SnapshotTimestampOutOfRange,
+
+    #[fail(display = "Failed to talk to peer")]
+    PeerIOError {
+        #[fail(cause)]
+        cause: io::Error,
+    },
+
+    #[fail(display = "No lookup found enough nodes to estimate the network size")]
+    EstimateUnavailable,
+
+    #[fail(display = "No node accepted the announce")]
+    AnnounceFailed,
+
+    #[fail(display = "Node answered with another id")]
+    NodeIDMismatch,
+
+    #[fail(display = "Peer sent an invalid message")]
+    InvalidPeerMessage,
+
+    #[fail(display = "Peer doesn't support ut_metadata")]
+    MetadataUnsupported,
+
+    #[fail(display = "Peer rejected request for metadata piece {}", piece)]
+    MetadataRejected { piece: u32 },
+
+    #[fail(display = "Metadata doesn't match info-hash")]
+    MetadataHashMismatch,
+
+    #[fail(display = "Invalid magnet link")]
+    InvalidMagnet {
+        #[fail(cause)]
+        cause: proto::errors::Error,
+    },
+
+    #[fail(display = "Failed to encode or decode message")]
+    EncodingError {
+        #[fail(cause)]
+        cause: proto::errors::Error,
+    },
+
     #[fail(display = "Failed to bind")]
     BindError {
         #[fail(cause)]
         cause: io::Error,
     },
+
+    #[fail(display = "Failed to read or write identity file")]
+    IdentityFileError {
+        #[fail(cause)]
+        cause: io::Error,
+    },
+
+    #[fail(display = "Identity file is malformed")]
+    InvalidIdentityFile,
+
+    #[fail(display = "Saved routing table is malformed")]
+    InvalidRoutingTable,
+
+    #[fail(display = "Saved routing table has unsupported version {}", version)]
+    UnsupportedRoutingTableVersion { version: u64 },
+
+    #[fail(display = "Invalid router {}, expected host:port", router)]
+    InvalidRouter { router: String },
 }
 
 impl Fail for Error {
@@ -73,11 +163,18 @@ impl Fail for Error {
 }
 
 impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        self.inner.get_context()
+    }
+
     pub fn as_request_error(&self) -> proto::KRPCError {
         let (code, message) = match self.inner.get_context() {
             ErrorKind::UnimplementedRequestType => (204, "Unimplemented"),
             ErrorKind::InvalidToken => (203, "Invalid Token"),
+            ErrorKind::TokenReused => (203, "Token already used"),
             ErrorKind::InsufficientAddress => (203, "Not enough address info provided"),
+            ErrorKind::ReadOnlyAnnounce => (203, "Read only nodes can't announce"),
+            ErrorKind::AnnounceQuotaExceeded => (201, "Announce quota exceeded"),
             _ => (202, "Server Error"),
         };
 
@@ -130,3 +227,9 @@ impl From<tokio_krpc::send_errors::Error> for Error {
         ErrorKind::SendTransportError { cause }.into()
     }
 }
+
+impl From<proto::errors::Error> for Error {
+    fn from(cause: proto::errors::Error) -> Self {
+        ErrorKind::EncodingError { cause }.into()
+    }
+}
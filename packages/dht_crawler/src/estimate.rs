@@ -0,0 +1,173 @@
+//! Estimating the number of nodes in the DHT from how densely node ids are
+//! packed around random targets.
+//!
+//! If `N` nodes have ids spread uniformly over the 160-bit keyspace, the
+//! `k`th closest of them to any target is expected at an XOR distance of about
+//! `k * 2^160 / N`, so every lookup gives an estimate of `N`.
+
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use std::cmp::Ordering;
+
+/// Rank of the responder whose distance is used by
+/// [`Dht::estimate_network_size`].
+pub const ESTIMATE_RANK: usize = 8;
+
+/// z-score of a two-sided 95% confidence interval.
+const Z_95: f64 = 1.96;
+
+/// Result of [`Dht::estimate_network_size`].
+#[derive(Debug, Clone)]
+pub struct NetworkSizeEstimate {
+    /// `k * 2^160 / median(distances)`.
+    pub estimate: f64,
+
+    /// 95% confidence interval of the mean of the per-lookup estimates
+    /// `k * 2^160 / distance`.
+    pub interval: (f64, f64),
+
+    /// XOR distance of the `k`th closest responder of every lookup, in the
+    /// order the lookups ran.
+    pub distances: Vec<BigUint>,
+}
+
+impl NetworkSizeEstimate {
+    /// Computes the estimate from the distances of the `k`th closest nodes to
+    /// a number of random targets. Returns `None` without any usable
+    /// distances.
+    pub fn from_distances(k: usize, distances: Vec<BigUint>) -> Option<NetworkSizeEstimate> {
+        let mut sorted: Vec<f64> = distances
+            .iter()
+            .map(distance_to_f64)
+            .filter(|distance| *distance > 0.0)
+            .collect();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+        let middle = sorted.len() / 2;
+        let median = if sorted.len() % 2 == 0 {
+            (sorted[middle - 1] + sorted[middle]) / 2.0
+        } else {
+            sorted[middle]
+        };
+
+        let scale = k as f64 * keyspace_size();
+        let samples: Vec<f64> = sorted.iter().map(|distance| scale / distance).collect();
+        let count = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / count;
+        let variance = if samples.len() > 1 {
+            samples
+                .iter()
+                .map(|sample| (sample - mean).powi(2))
+                .sum::<f64>()
+                / (count - 1.0)
+        } else {
+            0.0
+        };
+        let margin = Z_95 * (variance / count).sqrt();
+
+        Some(NetworkSizeEstimate {
+            estimate: scale / median,
+            interval: ((mean - margin).max(0.0), mean + margin),
+            distances,
+        })
+    }
+}
+
+/// 2^160, the size of the keyspace.
+fn keyspace_size() -> f64 {
+    2f64.powi(160)
+}
+
+/// Converts a distance of up to 160 bits to the nearest `f64`, keeping its
+/// top 64 bits and adjusting the exponent for the rest.
+pub fn distance_to_f64(distance: &BigUint) -> f64 {
+    let bits = distance.bits();
+    if bits <= 64 {
+        return distance.to_u64().unwrap_or(0) as f64;
+    }
+
+    let shift = bits - 64;
+    let top = (distance >> shift).to_u64().unwrap_or(0);
+
+    top as f64 * 2f64.powi(shift as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        distance_to_f64,
+        keyspace_size,
+        NetworkSizeEstimate,
+        ESTIMATE_RANK,
+    };
+    use krpc_encoding::NodeID;
+    use num_bigint::BigUint;
+    use rand::{
+        rngs::StdRng,
+        Rng,
+        SeedableRng,
+    };
+
+    #[test]
+    fn converts_distances() {
+        assert_eq!(distance_to_f64(&BigUint::from(0u8)), 0.0);
+        assert_eq!(distance_to_f64(&BigUint::from(12345u32)), 12345.0);
+        assert_eq!(
+            distance_to_f64(&(BigUint::from(1u8) << 160)),
+            keyspace_size()
+        );
+        assert_eq!(
+            distance_to_f64(&(BigUint::from(3u8) << 150)),
+            3.0 * 2f64.powi(150)
+        );
+
+        let max = (BigUint::from(1u8) << 160) - 1u8;
+        let relative_error = (distance_to_f64(&max) - keyspace_size()).abs() / keyspace_size();
+        assert!(relative_error < 1e-15);
+    }
+
+    #[test]
+    fn estimates_known_sizes() {
+        let mut rng = StdRng::from_seed([7; 32]);
+
+        for &size in &[500usize, 5000] {
+            let ids: Vec<NodeID> = (0..size)
+                .map(|_| NodeID::from(rng.gen::<[u8; 20]>()))
+                .collect();
+
+            let distances = (0..63)
+                .map(|_| {
+                    let target = NodeID::from(rng.gen::<[u8; 20]>());
+                    let mut distances: Vec<BigUint> =
+                        ids.iter().map(|id| id.distance(&target)).collect();
+                    distances.sort();
+                    distances.swap_remove(ESTIMATE_RANK - 1)
+                })
+                .collect();
+
+            let estimate = NetworkSizeEstimate::from_distances(ESTIMATE_RANK, distances).unwrap();
+            let size = size as f64;
+
+            assert!(
+                (estimate.estimate - size).abs() < size * 0.25,
+                "estimated {} nodes of {}",
+                estimate.estimate,
+                size
+            );
+            assert!(estimate.interval.0 < estimate.interval.1);
+            assert!(estimate.interval.0 < size * 1.25 && estimate.interval.1 > size * 0.75);
+            assert_eq!(estimate.distances.len(), 63);
+        }
+    }
+
+    #[test]
+    fn needs_distances() {
+        assert!(NetworkSizeEstimate::from_distances(ESTIMATE_RANK, Vec::new()).is_none());
+        assert!(
+            NetworkSizeEstimate::from_distances(ESTIMATE_RANK, vec![BigUint::from(0u8)]).is_none()
+        );
+    }
+}
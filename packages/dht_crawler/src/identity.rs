@@ -0,0 +1,292 @@
+//! Keeps a node's id across restarts so the nodes which know us keep routing
+//! to us, changing it only when our external IP really changes and the old
+//! id isn't valid for the new IP under [BEP-0042].
+//!
+//! [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+
+use crate::errors::{
+    ErrorKind,
+    Result,
+};
+use krpc_encoding::NodeID;
+use serde_json::{
+    json,
+    Value,
+};
+use std::{
+    fs,
+    io,
+    net::Ipv4Addr,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+/// Options for an [`IdentityStore`].
+#[derive(Clone, Debug)]
+pub struct IdentityConfig {
+    /// Derive ids from the external IP as described by BEP-0042. Without
+    /// this the id is kept whatever the IP.
+    pub secure_ids: bool,
+
+    /// Times in a row another external IP must be reported before it is
+    /// believed, so a few wrong or flapping reports don't change the id.
+    pub confirmations: usize,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> IdentityConfig {
+        IdentityConfig {
+            secure_ids: true,
+            confirmations: 3,
+        }
+    }
+}
+
+/// Node id and the external IP it was chosen for, persisted to a small JSON
+/// file.
+///
+/// [`DhtBuilder::identity_file`] starts a node with [`id`] and passes each
+/// new consensus on our external IP to [`observe_external_ip`], moving the
+/// node and its routing table to the new id when one is returned.
+///
+/// [`DhtBuilder::identity_file`]: crate::dht::DhtBuilder::identity_file
+/// [`id`]: IdentityStore::id
+/// [`observe_external_ip`]: IdentityStore::observe_external_ip
+pub struct IdentityStore {
+    path: PathBuf,
+    config: IdentityConfig,
+    id: NodeID,
+
+    /// IP `id` was chosen for, if one was observed yet.
+    external_ip: Option<Ipv4Addr>,
+
+    /// Another IP reported since the last report of `external_ip` and how
+    /// many times in a row.
+    candidate: Option<(Ipv4Addr, usize)>,
+}
+
+impl IdentityStore {
+    /// Loads the identity stored at `path`. A random id is generated and
+    /// stored when there is no file yet.
+    pub fn open<P: AsRef<Path>>(path: P, config: IdentityConfig) -> Result<IdentityStore> {
+        let path = path.as_ref().to_path_buf();
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => Some(contents),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(cause) => Err(ErrorKind::IdentityFileError { cause })?,
+        };
+
+        let (id, external_ip) = match &contents {
+            Some(contents) => parse(contents)?,
+            None => (NodeID::random(), None),
+        };
+
+        let store = IdentityStore {
+            path,
+            config,
+            id,
+            external_ip,
+            candidate: None,
+        };
+        if contents.is_none() {
+            store.save()?;
+        }
+
+        Ok(store)
+    }
+
+    pub fn id(&self) -> &NodeID {
+        &self.id
+    }
+
+    /// External IP the id was chosen for.
+    pub fn external_ip(&self) -> Option<Ipv4Addr> {
+        self.external_ip
+    }
+
+    /// Records a report of our external IP, like a vote from the `ip` field
+    /// of a response. Once another IP than the stored one is reported
+    /// [`IdentityConfig::confirmations`] times in a row it replaces the
+    /// stored IP. The first IP reported is believed straight away. When the
+    /// id isn't valid for the new IP a new one is generated and returned.
+    pub fn observe_external_ip(&mut self, ip: Ipv4Addr) -> Result<Option<NodeID>> {
+        if self.external_ip == Some(ip) {
+            self.candidate = None;
+            return Ok(None);
+        }
+
+        if self.external_ip.is_some() {
+            let reports = match self.candidate {
+                Some((candidate, reports)) if candidate == ip => reports + 1,
+                _ => 1,
+            };
+
+            if reports < self.config.confirmations {
+                self.candidate = Some((ip, reports));
+                return Ok(None);
+            }
+        }
+
+        self.candidate = None;
+        self.external_ip = Some(ip);
+
+        let regenerated = if self.config.secure_ids && !self.id.is_secure_for(ip) {
+            self.id = NodeID::secure(ip);
+            Some(self.id.clone())
+        } else {
+            None
+        };
+        self.save()?;
+
+        Ok(regenerated)
+    }
+
+    /// Replaces the file, through a temporary file so a crash can't leave it
+    /// half written.
+    fn save(&self) -> Result<()> {
+        let contents = json!({
+            "id": self.id.to_string(),
+            "external_ip": self.external_ip.map(|ip| ip.to_string()),
+        })
+        .to_string();
+
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, contents)
+            .and_then(|()| fs::rename(&temporary, &self.path))
+            .map_err(|cause| ErrorKind::IdentityFileError { cause })?;
+
+        Ok(())
+    }
+}
+
+fn parse(contents: &str) -> Result<(NodeID, Option<Ipv4Addr>)> {
+    let value: Value =
+        serde_json::from_str(contents).map_err(|_| ErrorKind::InvalidIdentityFile)?;
+
+    let id = value["id"]
+        .as_str()
+        .and_then(|id| NodeID::from_hex_str(id).ok())
+        .ok_or(ErrorKind::InvalidIdentityFile)?;
+
+    let external_ip = match &value["external_ip"] {
+        Value::Null => None,
+        Value::String(ip) => Some(ip.parse().map_err(|_| ErrorKind::InvalidIdentityFile)?),
+        _ => Err(ErrorKind::InvalidIdentityFile)?,
+    };
+
+    Ok((id, external_ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        IdentityConfig,
+        IdentityStore,
+    };
+    use failure::Error;
+    use std::{
+        env,
+        fs,
+        net::Ipv4Addr,
+        path::PathBuf,
+        process,
+    };
+
+    fn identity_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("dht_crawler-{}-{}.json", name, process::id()));
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    #[test]
+    fn fresh_start() -> Result<(), Error> {
+        let path = identity_path("identity-fresh");
+        let ip: Ipv4Addr = "124.31.75.21".parse()?;
+
+        let mut store = IdentityStore::open(&path, IdentityConfig::default())?;
+        assert!(path.exists());
+        assert_eq!(store.external_ip(), None);
+
+        // The random id is replaced by one valid for the first IP seen.
+        let id = store.observe_external_ip(ip)?.unwrap();
+        assert!(id.is_secure_for(ip));
+        assert_eq!(store.id(), &id);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn stable_restart() -> Result<(), Error> {
+        let path = identity_path("identity-stable");
+        let ip: Ipv4Addr = "124.31.75.21".parse()?;
+
+        let id = {
+            let mut store = IdentityStore::open(&path, IdentityConfig::default())?;
+            store.observe_external_ip(ip)?;
+            store.id().clone()
+        };
+
+        let mut store = IdentityStore::open(&path, IdentityConfig::default())?;
+        assert_eq!(store.id(), &id);
+        assert_eq!(store.external_ip(), Some(ip));
+        assert_eq!(store.observe_external_ip(ip)?, None);
+        assert_eq!(store.id(), &id);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn ip_change_migrates_id() -> Result<(), Error> {
+        let path = identity_path("identity-migrate");
+        let old_ip: Ipv4Addr = "124.31.75.21".parse()?;
+        let new_ip: Ipv4Addr = "21.75.31.124".parse()?;
+
+        let mut store = IdentityStore::open(&path, IdentityConfig::default())?;
+        store.observe_external_ip(old_ip)?;
+        let old_id = store.id().clone();
+
+        // Flapping reports are ignored.
+        assert_eq!(store.observe_external_ip(new_ip)?, None);
+        assert_eq!(store.observe_external_ip(new_ip)?, None);
+        assert_eq!(store.observe_external_ip(old_ip)?, None);
+        assert_eq!(store.observe_external_ip(new_ip)?, None);
+        assert_eq!(store.observe_external_ip(new_ip)?, None);
+        assert_eq!(store.id(), &old_id);
+
+        let new_id = store.observe_external_ip(new_ip)?.unwrap();
+        assert_ne!(new_id, old_id);
+        assert!(new_id.is_secure_for(new_ip));
+
+        let store = IdentityStore::open(&path, IdentityConfig::default())?;
+        assert_eq!(store.id(), &new_id);
+        assert_eq!(store.external_ip(), Some(new_ip));
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn id_kept_without_secure_ids() -> Result<(), Error> {
+        let path = identity_path("identity-insecure");
+        let config = IdentityConfig {
+            secure_ids: false,
+            confirmations: 1,
+        };
+
+        let mut store = IdentityStore::open(&path, config)?;
+        let id = store.id().clone();
+        assert_eq!(store.observe_external_ip("124.31.75.21".parse()?)?, None);
+        assert_eq!(store.observe_external_ip("21.75.31.124".parse()?)?, None);
+        assert_eq!(store.id(), &id);
+        assert_eq!(store.external_ip(), Some("21.75.31.124".parse()?));
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -8,9 +8,26 @@
 //! node, which is used to contact other nodes in the DHT to get the location of
 //! peers to download from using the BitTorrent protocol.
 
+#[macro_use]
+mod trace;
+
 pub mod addr;
+pub mod bootstrap;
+pub mod crawler;
 pub mod dht;
 pub mod errors;
+pub mod estimate;
+pub mod identity;
+pub mod lookup;
+pub mod metadata;
 pub mod routing;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+mod shutdown;
 
-pub use crate::dht::Dht;
+pub use crate::{
+    bootstrap::Bootstrap,
+    crawler::Crawler,
+    dht::Dht,
+};
@@ -0,0 +1,514 @@
+//! State machine for iterative Kademlia lookups. The state machine doesn't
+//! send anything itself. A driver asks it which nodes to query next and feeds
+//! it the results.
+
+pub use crate::routing::SelectionWeights;
+
+use crate::routing::{
+    selection_order,
+    INITIAL_RELIABILITY,
+};
+use krpc_encoding::{
+    NodeID,
+    NodeInfo,
+};
+use num_bigint::BigUint;
+use std::{
+    cmp::{
+        self,
+        Ordering,
+    },
+    collections::HashSet,
+    net::SocketAddrV4,
+    time::Duration,
+};
+
+/// Parameters controlling an iterative lookup.
+#[derive(Clone, Debug)]
+pub struct LookupConfig {
+    /// Maximum number of queries in flight at once. [BEP-0005] recommends 3.
+    /// With 1 the lookup queries one node at a time.
+    ///
+    /// [BEP-0005]: http://www.bittorrent.org/beps/bep_0005.html
+    pub alpha: usize,
+
+    /// Number of closest nodes the lookup converges on.
+    pub k: usize,
+
+    /// Maximum number of nodes queried over the whole lookup.
+    pub max_queries: usize,
+
+    /// Time to wait for the nodes queried in a round to respond.
+    pub round_timeout: Duration,
+
+    /// Maximum number of rounds of queries over the whole lookup.
+    pub max_rounds: usize,
+
+    /// How nodes at about the same distance are ordered when choosing which
+    /// to query next. With `None` they are queried strictly closest first.
+    pub weights: Option<SelectionWeights>,
+}
+
+impl Default for LookupConfig {
+    fn default() -> LookupConfig {
+        LookupConfig {
+            alpha: 3,
+            k: 8,
+            max_queries: 256,
+            round_timeout: Duration::from_secs(3),
+            max_rounds: 32,
+            weights: Some(SelectionWeights::default()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum CandidateState {
+    Unqueried,
+    InFlight,
+    Responded,
+    Failed,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    node: NodeInfo,
+    distance: BigUint,
+    state: CandidateState,
+
+    /// [`SelectionWeights::penalty`] of the node.
+    penalty: f64,
+}
+
+/// Iterative lookup converging on the nodes closest to a target.
+///
+/// The lookup finishes once the `k` closest nodes which haven't failed have
+/// all responded or once `max_queries` queries have been sent or
+/// `max_rounds` rounds started, and all of them answered. Every call to
+/// [`next_queries`] returning some nodes starts a round.
+#[derive(Debug)]
+pub struct Lookup {
+    target: NodeID,
+    config: LookupConfig,
+
+    /// Every node learned about, ordered by distance to `target` closest
+    /// first.
+    candidates: Vec<Candidate>,
+
+    /// Identifiers of all nodes in `candidates`.
+    seen: HashSet<NodeID>,
+
+    /// Our own external address. Other nodes often return us as one of the
+    /// nodes close to the target.
+    own_address: Option<SocketAddrV4>,
+
+    queries_sent: usize,
+    rounds: usize,
+}
+
+impl Lookup {
+    pub fn new(target: NodeID, config: LookupConfig) -> Lookup {
+        Lookup {
+            target,
+            config,
+            candidates: Vec::new(),
+            seen: HashSet::new(),
+            own_address: None,
+            queries_sent: 0,
+            rounds: 0,
+        }
+    }
+
+    pub fn target(&self) -> &NodeID {
+        &self.target
+    }
+
+    pub fn config(&self) -> &LookupConfig {
+        &self.config
+    }
+
+    /// Never query nodes at `address`. Should be set to our own external
+    /// address before any candidates are added.
+    pub fn set_own_address(&mut self, address: SocketAddrV4) {
+        self.own_address = Some(address);
+    }
+
+    /// Adds nodes which could be queried. Nodes already known to the lookup
+    /// and nodes at our own address are ignored.
+    pub fn add_candidates<I: IntoIterator<Item = NodeInfo>>(&mut self, nodes: I) {
+        for node in nodes {
+            self.add_candidate_with_history(node, INITIAL_RELIABILITY, None);
+        }
+    }
+
+    /// Adds a node which could be queried along with what is known about
+    /// it, like the [`reliability`] and [`smoothed_rtt`] of the node in the
+    /// routing table. Nodes already known to the lookup and nodes at our own
+    /// address are ignored.
+    ///
+    /// [`reliability`]: crate::routing::Node::reliability
+    /// [`smoothed_rtt`]: crate::routing::Node::smoothed_rtt
+    pub fn add_candidate_with_history(
+        &mut self,
+        node: NodeInfo,
+        reliability: f64,
+        rtt: Option<Duration>,
+    ) {
+        if Some(node.address) == self.own_address {
+            return;
+        }
+
+        if !self.seen.insert(node.node_id.clone()) {
+            return;
+        }
+
+        let distance = self.target.distance(&node.node_id);
+        let idx = match self
+            .candidates
+            .binary_search_by(|candidate| candidate.distance.cmp(&distance))
+        {
+            Ok(idx) | Err(idx) => idx,
+        };
+        let penalty = match &self.config.weights {
+            Some(weights) => weights.penalty(reliability, rtt),
+            None => 0.0,
+        };
+
+        self.candidates.insert(
+            idx,
+            Candidate {
+                node,
+                distance,
+                state: CandidateState::Unqueried,
+                penalty,
+            },
+        );
+    }
+
+    /// Picks the nodes which should be queried next and marks them as in
+    /// flight. Returns an empty list when nothing should be sent until more
+    /// results arrive.
+    pub fn next_queries(&mut self) -> Vec<NodeInfo> {
+        let mut queries = Vec::new();
+        if self.is_finished() {
+            return queries;
+        }
+
+        let limit = cmp::min(
+            self.config.alpha.saturating_sub(self.in_flight()),
+            self.config.max_queries.saturating_sub(self.queries_sent),
+        );
+
+        // Only the `k` closest nodes are queried. Among them, the ones
+        // likely to answer quickly go first.
+        let mut unqueried: Vec<usize> = self
+            .candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, candidate)| candidate.state != CandidateState::Failed)
+            .take(self.config.k)
+            .filter(|(_, candidate)| candidate.state == CandidateState::Unqueried)
+            .map(|(idx, _)| idx)
+            .collect();
+        if self.config.weights.is_some() {
+            let candidates = &self.candidates;
+            unqueried.sort_by(|a, b| {
+                let (a, b) = (&candidates[*a], &candidates[*b]);
+                selection_order((&a.distance, a.penalty), (&b.distance, b.penalty))
+            });
+        }
+
+        for idx in unqueried.into_iter().take(limit) {
+            let candidate = &mut self.candidates[idx];
+            candidate.state = CandidateState::InFlight;
+            queries.push(candidate.node.clone());
+        }
+
+        self.queries_sent += queries.len();
+        if !queries.is_empty() {
+            self.rounds += 1;
+        }
+
+        queries
+    }
+
+    /// Records that the node with `id` responded with `nodes`.
+    pub fn handle_response(&mut self, id: &NodeID, nodes: Vec<NodeInfo>) {
+        self.set_state(id, CandidateState::Responded);
+        self.add_candidates(nodes);
+    }
+
+    /// Records that the query to the node with `id` failed or timed out.
+    pub fn handle_failure(&mut self, id: &NodeID) {
+        self.set_state(id, CandidateState::Failed);
+    }
+
+    fn set_state(&mut self, id: &NodeID, state: CandidateState) {
+        if let Some(candidate) = self
+            .candidates
+            .iter_mut()
+            .find(|candidate| &candidate.node.node_id == id)
+        {
+            candidate.state = state;
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.state == CandidateState::InFlight)
+            .count()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        if self.in_flight() > 0 {
+            return false;
+        }
+
+        if self.queries_sent >= self.config.max_queries || self.rounds >= self.config.max_rounds {
+            return true;
+        }
+
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.state != CandidateState::Failed)
+            .take(self.config.k)
+            .all(|candidate| candidate.state == CandidateState::Responded)
+    }
+
+    /// Every node which hasn't failed to respond so far, whether it was
+    /// queried or not, closest first.
+    pub fn candidates(&self) -> Vec<NodeInfo> {
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.state != CandidateState::Failed)
+            .map(|candidate| candidate.node.clone())
+            .collect()
+    }
+
+    /// The `k` closest nodes which have responded so far. Before the lookup
+    /// is finished this is a partial result.
+    pub fn closest(&self) -> Vec<NodeInfo> {
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.state == CandidateState::Responded)
+            .take(self.config.k)
+            .map(|candidate| candidate.node.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Lookup,
+        LookupConfig,
+    };
+    use krpc_encoding::{
+        NodeID,
+        NodeInfo,
+    };
+    use num_bigint::BigUint;
+    use std::{
+        collections::HashMap,
+        net::{
+            Ipv4Addr,
+            SocketAddrV4,
+        },
+        time::Duration,
+    };
+
+    fn node(id: u8) -> NodeInfo {
+        NodeInfo::new(
+            NodeID::new(BigUint::from(id)),
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1000 + u16::from(id)),
+        )
+    }
+
+    fn nodes(ids: &[u8]) -> Vec<NodeInfo> {
+        ids.iter().cloned().map(node).collect()
+    }
+
+    /// Network where node `n` knows about nodes `n - 1`, `n - 2` and `n - 3`.
+    /// Nodes with ids in `dead` never respond.
+    fn network(max_id: u8, dead: &[u8]) -> HashMap<NodeID, Vec<NodeInfo>> {
+        (1..=max_id)
+            .filter(|id| !dead.contains(id))
+            .map(|id| {
+                let known = (1..=3).filter(|d| id > *d).map(|d| node(id - d)).collect();
+                (node(id).node_id, known)
+            })
+            .collect()
+    }
+
+    /// Drives `lookup` until it finishes answering queries from `network`.
+    fn run(mut lookup: Lookup, network: &HashMap<NodeID, Vec<NodeInfo>>) -> Lookup {
+        let alpha = lookup.config.alpha;
+
+        while !lookup.is_finished() {
+            let queries = lookup.next_queries();
+            assert!(!queries.is_empty());
+            assert!(queries.len() <= alpha);
+
+            for query in queries {
+                match network.get(&query.node_id) {
+                    Some(known) => lookup.handle_response(&query.node_id, known.clone()),
+                    None => lookup.handle_failure(&query.node_id),
+                }
+            }
+        }
+
+        lookup
+    }
+
+    fn target() -> NodeID {
+        NodeID::new(BigUint::from(0u8))
+    }
+
+    #[test]
+    fn converges_on_closest() {
+        let mut lookup = Lookup::new(target(), LookupConfig::default());
+        lookup.add_candidates(nodes(&[40]));
+
+        let lookup = run(lookup, &network(40, &[]));
+
+        assert_eq!(lookup.closest(), nodes(&[1, 2, 3, 4, 5, 6, 7, 8]));
+    }
+
+    #[test]
+    fn skips_dead_nodes() {
+        let mut lookup = Lookup::new(target(), LookupConfig::default());
+        lookup.add_candidates(nodes(&[40]));
+
+        let lookup = run(lookup, &network(40, &[1, 2, 3]));
+
+        assert_eq!(lookup.closest(), nodes(&[4, 5, 6, 7, 8, 9, 10, 11]));
+    }
+
+    #[test]
+    fn stops_at_budget() {
+        let config = LookupConfig {
+            max_queries: 5,
+            ..LookupConfig::default()
+        };
+        let mut lookup = Lookup::new(target(), config);
+        lookup.add_candidates(nodes(&[40]));
+
+        let lookup = run(lookup, &network(40, &[]));
+
+        assert_eq!(lookup.closest().len(), 5);
+    }
+
+    #[test]
+    fn converges_with_any_alpha() {
+        for &alpha in &[1, 3, 8] {
+            let config = LookupConfig {
+                alpha,
+                ..LookupConfig::default()
+            };
+            let mut lookup = Lookup::new(target(), config);
+            lookup.add_candidates(nodes(&[40]));
+
+            let lookup = run(lookup, &network(40, &[2]));
+
+            assert_eq!(
+                lookup.closest(),
+                nodes(&[1, 3, 4, 5, 6, 7, 8, 9]),
+                "alpha {}",
+                alpha
+            );
+        }
+    }
+
+    #[test]
+    fn stops_after_max_rounds() {
+        let config = LookupConfig {
+            alpha: 1,
+            max_rounds: 4,
+            ..LookupConfig::default()
+        };
+        let mut lookup = Lookup::new(target(), config);
+        lookup.add_candidates(nodes(&[40]));
+
+        let lookup = run(lookup, &network(40, &[]));
+
+        assert_eq!(lookup.closest().len(), 4);
+    }
+
+    #[test]
+    fn closer_tier_first() {
+        let mut lookup = Lookup::new(target(), LookupConfig::default());
+        lookup.add_candidate_with_history(node(7), 0.0, None);
+        lookup.add_candidate_with_history(node(8), 0.0, None);
+        for id in 9..=11 {
+            lookup.add_candidate_with_history(node(id), 1.0, Some(Duration::from_millis(10)));
+        }
+
+        // 9 and 10 go before 8 in the same tier, but however unreliable 7 is
+        // in a closer one.
+        assert_eq!(lookup.next_queries(), nodes(&[7, 9, 10]));
+    }
+
+    #[test]
+    fn respects_alpha() {
+        let mut lookup = Lookup::new(target(), LookupConfig::default());
+        lookup.add_candidates(nodes(&[1, 2, 3, 4, 5]));
+
+        assert_eq!(lookup.next_queries(), nodes(&[1, 2, 3]));
+        assert_eq!(lookup.next_queries(), Vec::new());
+        assert_eq!(lookup.in_flight(), 3);
+
+        lookup.handle_failure(&node(1).node_id);
+        assert_eq!(lookup.next_queries(), nodes(&[4]));
+    }
+
+    #[test]
+    fn ignores_duplicate_candidates() {
+        let mut lookup = Lookup::new(target(), LookupConfig::default());
+        lookup.add_candidates(nodes(&[1, 2]));
+        lookup.add_candidates(nodes(&[2, 1, 3]));
+
+        assert_eq!(lookup.next_queries(), nodes(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn skips_own_address() {
+        let own = node(5);
+        let mut lookup = Lookup::new(target(), LookupConfig::default());
+        lookup.set_own_address(own.address);
+        lookup.add_candidates(nodes(&[1, 2, 3, 4, 5]));
+
+        while !lookup.is_finished() {
+            let queries = lookup.next_queries();
+            if queries.is_empty() {
+                break;
+            }
+
+            for query in queries {
+                assert_ne!(query.address, own.address);
+                lookup.handle_response(&query.node_id, vec![own.clone()]);
+            }
+        }
+
+        assert!(!lookup.closest().contains(&own));
+    }
+
+    #[test]
+    fn partial_results() {
+        let mut lookup = Lookup::new(target(), LookupConfig::default());
+        lookup.add_candidates(nodes(&[1, 2, 3]));
+        lookup.next_queries();
+        lookup.handle_response(&node(2).node_id, Vec::new());
+
+        assert!(!lookup.is_finished());
+        assert_eq!(lookup.closest(), nodes(&[2]));
+    }
+
+    #[test]
+    fn empty_lookup_is_finished() {
+        let lookup = Lookup::new(target(), LookupConfig::default());
+
+        assert!(lookup.is_finished());
+        assert_eq!(lookup.closest(), Vec::new());
+    }
+}
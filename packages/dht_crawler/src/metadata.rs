@@ -0,0 +1,460 @@
+//! Downloads the info dictionary of a torrent from a peer using the
+//! `ut_metadata` extension from [BEP-0009].
+//!
+//! [BEP-0009]: http://www.bittorrent.org/beps/bep_0009.html
+
+use crate::errors::{
+    ErrorKind,
+    Result,
+};
+use crypto::{
+    digest::Digest,
+    sha1::Sha1,
+};
+use krpc_encoding::{
+    ExtensionHandshake,
+    MetadataMessage,
+    NodeID,
+    METADATA_PIECE_SIZE,
+    UT_METADATA,
+};
+use std::{
+    io,
+    net::SocketAddr,
+    time::Duration,
+};
+use tokio::{
+    io::{
+        AsyncReadExt,
+        AsyncWriteExt,
+    },
+    net::TcpStream,
+    prelude::FutureExt,
+};
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+
+/// Protocol name, reserved bytes, info-hash and peer id.
+const HANDSHAKE_LEN: usize = 1 + 19 + 8 + 20 + 20;
+
+/// Message id of [BEP-0010] extension messages.
+///
+/// [BEP-0010]: http://www.bittorrent.org/beps/bep_0010.html
+const EXTENDED: u8 = 20;
+
+/// Extension message id of the extension handshake.
+const EXTENSION_HANDSHAKE: u8 = 0;
+
+/// Extension message id peers use for `ut_metadata` messages sent to us.
+const LOCAL_UT_METADATA: u8 = 1;
+
+/// Largest info dictionary accepted.
+const MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+
+/// Largest peer wire message accepted. Bitfields of large torrents are the
+/// biggest messages sent before any metadata.
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+/// Time after which a fetch is abandoned.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fetches the metadata of `info_hash` from a single peer.
+pub struct MetadataFetcher {
+    info_hash: NodeID,
+    peer_addr: SocketAddr,
+    peer_id: [u8; 20],
+}
+
+impl MetadataFetcher {
+    pub fn new(info_hash: NodeID, peer_addr: SocketAddr) -> MetadataFetcher {
+        MetadataFetcher {
+            info_hash,
+            peer_addr,
+            peer_id: rand::random(),
+        }
+    }
+
+    /// Connects to the peer and downloads every piece of the metadata. The
+    /// result is the bencoded info dictionary, checked against the
+    /// info-hash.
+    pub async fn fetch(&self) -> Result<Vec<u8>> {
+        self.fetch_metadata().timeout(FETCH_TIMEOUT).await?
+    }
+
+    async fn fetch_metadata(&self) -> Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(&self.peer_addr)
+            .await
+            .map_err(peer_error)?;
+
+        self.handshake(&mut stream).await?;
+
+        let mut handshake = ExtensionHandshake::default();
+        handshake
+            .m
+            .insert(UT_METADATA.to_string(), LOCAL_UT_METADATA);
+        send_extended(&mut stream, EXTENSION_HANDSHAKE, &handshake.encode()?).await?;
+
+        let (remote_id, metadata_size) = loop {
+            let (extension_id, payload) = read_extended(&mut stream).await?;
+            if extension_id != EXTENSION_HANDSHAKE {
+                continue;
+            }
+
+            let handshake = ExtensionHandshake::decode(&payload)?;
+            match (handshake.m.get(UT_METADATA), handshake.metadata_size) {
+                (Some(&remote_id), Some(metadata_size)) if remote_id != 0 => {
+                    break (remote_id, metadata_size)
+                }
+                _ => return Err(ErrorKind::MetadataUnsupported.into()),
+            };
+        };
+
+        let mut pieces = MetadataPieces::new(metadata_size)?;
+        for piece in 0..pieces.count() {
+            let request = MetadataMessage::Request { piece };
+            send_extended(&mut stream, remote_id, &request.encode()?).await?;
+        }
+
+        while !pieces.is_complete() {
+            let (extension_id, payload) = read_extended(&mut stream).await?;
+            if extension_id != LOCAL_UT_METADATA {
+                continue;
+            }
+
+            match MetadataMessage::decode(&payload)? {
+                MetadataMessage::Data {
+                    piece,
+                    total_size,
+                    data,
+                } => pieces.add(piece, total_size, &data)?,
+                MetadataMessage::Reject { piece } => Err(ErrorKind::MetadataRejected { piece })?,
+
+                // We don't have the metadata to share.
+                MetadataMessage::Request { piece } => {
+                    let reject = MetadataMessage::Reject { piece };
+                    send_extended(&mut stream, remote_id, &reject.encode()?).await?;
+                }
+            };
+        }
+
+        let metadata = pieces.into_bytes();
+        if sha1(&metadata) != self.info_hash.to_bytes() {
+            Err(ErrorKind::MetadataHashMismatch)?;
+        }
+
+        Ok(metadata)
+    }
+
+    /// Exchanges BitTorrent handshakes, advertising support for extension
+    /// messages.
+    async fn handshake<'a>(&'a self, stream: &'a mut TcpStream) -> Result<()> {
+        let mut reserved = [0u8; 8];
+        reserved[5] |= 0x10;
+
+        let mut handshake = Vec::with_capacity(HANDSHAKE_LEN);
+        handshake.push(PROTOCOL.len() as u8);
+        handshake.extend_from_slice(PROTOCOL);
+        handshake.extend_from_slice(&reserved);
+        handshake.extend_from_slice(&self.info_hash.to_bytes());
+        handshake.extend_from_slice(&self.peer_id);
+        stream.write_all(&handshake).await.map_err(peer_error)?;
+
+        let mut response = [0u8; HANDSHAKE_LEN];
+        stream.read_exact(&mut response).await.map_err(peer_error)?;
+
+        if response[0] as usize != PROTOCOL.len() || &response[1..20] != PROTOCOL {
+            Err(ErrorKind::InvalidPeerMessage)?;
+        }
+
+        if response[28..48] != self.info_hash.to_bytes()[..] {
+            Err(ErrorKind::InvalidPeerMessage)?;
+        }
+
+        if response[25] & 0x10 == 0 {
+            Err(ErrorKind::MetadataUnsupported)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Pieces of the metadata received so far.
+struct MetadataPieces {
+    buf: Vec<u8>,
+    received: Vec<bool>,
+}
+
+impl MetadataPieces {
+    fn new(total_size: u32) -> Result<MetadataPieces> {
+        let total_size = total_size as usize;
+        if total_size == 0 || total_size > MAX_METADATA_SIZE {
+            Err(ErrorKind::InvalidPeerMessage)?;
+        }
+
+        let count = (total_size + METADATA_PIECE_SIZE - 1) / METADATA_PIECE_SIZE;
+
+        Ok(MetadataPieces {
+            buf: vec![0u8; total_size],
+            received: vec![false; count],
+        })
+    }
+
+    fn count(&self) -> u32 {
+        self.received.len() as u32
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received.iter().all(|received| *received)
+    }
+
+    fn add(&mut self, piece: u32, total_size: u32, data: &[u8]) -> Result<()> {
+        let piece = piece as usize;
+        if total_size as usize != self.buf.len() || piece >= self.received.len() {
+            Err(ErrorKind::InvalidPeerMessage)?;
+        }
+
+        let start = piece * METADATA_PIECE_SIZE;
+        let end = std::cmp::min(start + METADATA_PIECE_SIZE, self.buf.len());
+        if data.len() != end - start {
+            Err(ErrorKind::InvalidPeerMessage)?;
+        }
+
+        self.buf[start..end].copy_from_slice(data);
+        self.received[piece] = true;
+
+        Ok(())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+fn peer_error(cause: io::Error) -> ErrorKind {
+    ErrorKind::PeerIOError { cause }
+}
+
+fn sha1(bytes: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.input(bytes);
+
+    let mut output = [0u8; 20];
+    hasher.result(&mut output);
+
+    output
+}
+
+async fn send_extended<'a>(
+    stream: &'a mut TcpStream,
+    extension_id: u8,
+    payload: &'a [u8],
+) -> Result<()> {
+    let len = (payload.len() + 2) as u32;
+
+    let mut message = Vec::with_capacity(payload.len() + 6);
+    message.extend_from_slice(&len.to_be_bytes());
+    message.push(EXTENDED);
+    message.push(extension_id);
+    message.extend_from_slice(payload);
+
+    stream.write_all(&message).await.map_err(peer_error)?;
+
+    Ok(())
+}
+
+/// Reads messages until an extension message arrives, returning its
+/// extension id and payload. Other messages are skipped.
+async fn read_extended(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+    loop {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).await.map_err(peer_error)?;
+
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_MESSAGE_LEN {
+            Err(ErrorKind::InvalidPeerMessage)?;
+        }
+
+        // Keep-alive.
+        if len == 0 {
+            continue;
+        }
+
+        let mut message = vec![0u8; len];
+        stream.read_exact(&mut message).await.map_err(peer_error)?;
+
+        if message[0] != EXTENDED {
+            continue;
+        }
+
+        if message.len() < 2 {
+            Err(ErrorKind::InvalidPeerMessage)?;
+        }
+
+        return Ok((message[1], message.split_off(2)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        sha1,
+        MetadataFetcher,
+        MetadataPieces,
+        EXTENDED,
+        HANDSHAKE_LEN,
+        LOCAL_UT_METADATA,
+    };
+    use crate::errors::ErrorKind;
+    use failure::Error;
+    use krpc_encoding::{
+        ExtensionHandshake,
+        MetadataMessage,
+        NodeID,
+        METADATA_PIECE_SIZE,
+    };
+    use serde_bytes::ByteBuf;
+    use std::{
+        io::{
+            Read,
+            Write,
+        },
+        net::{
+            TcpListener,
+            TcpStream,
+        },
+        thread,
+    };
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Id the mock peer expects `ut_metadata` messages with.
+    const PEER_UT_METADATA: u8 = 3;
+
+    fn metadata() -> Vec<u8> {
+        (0..METADATA_PIECE_SIZE + 100).map(|i| i as u8).collect()
+    }
+
+    fn write_extended(stream: &mut TcpStream, extension_id: u8, payload: &[u8]) {
+        let len = (payload.len() + 2) as u32;
+        stream.write_all(&len.to_be_bytes()).unwrap();
+        stream.write_all(&[EXTENDED, extension_id]).unwrap();
+        stream.write_all(payload).unwrap();
+    }
+
+    fn read_message(stream: &mut TcpStream) -> Vec<u8> {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).unwrap();
+
+        let mut message = vec![0u8; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut message).unwrap();
+
+        message
+    }
+
+    /// Serves `metadata` in two pieces to a single connection.
+    fn serve(listener: TcpListener, metadata: Vec<u8>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut handshake = [0u8; HANDSHAKE_LEN];
+            stream.read_exact(&mut handshake).unwrap();
+            assert_ne!(handshake[25] & 0x10, 0);
+            stream.write_all(&handshake).unwrap();
+
+            // Unrelated messages the fetcher should skip.
+            stream.write_all(&[0, 0, 0, 0]).unwrap();
+            stream.write_all(&[0, 0, 0, 1, 2]).unwrap();
+
+            let their_handshake = read_message(&mut stream);
+            assert_eq!(their_handshake[..2], [EXTENDED, 0]);
+
+            let mut ours = ExtensionHandshake::default();
+            ours.m.insert("ut_metadata".to_string(), PEER_UT_METADATA);
+            ours.metadata_size = Some(metadata.len() as u32);
+            write_extended(&mut stream, 0, &ours.encode().unwrap());
+
+            for _ in 0..2 {
+                let request = read_message(&mut stream);
+                assert_eq!(request[..2], [EXTENDED, PEER_UT_METADATA]);
+
+                let piece = match MetadataMessage::decode(&request[2..]).unwrap() {
+                    MetadataMessage::Request { piece } => piece,
+                    message => panic!("unexpected message {:?}", message),
+                };
+
+                let start = piece as usize * METADATA_PIECE_SIZE;
+                let end = std::cmp::min(start + METADATA_PIECE_SIZE, metadata.len());
+                let data = MetadataMessage::Data {
+                    piece,
+                    total_size: metadata.len() as u32,
+                    data: ByteBuf::from(metadata[start..end].to_vec()),
+                };
+                write_extended(&mut stream, LOCAL_UT_METADATA, &data.encode().unwrap());
+            }
+        })
+    }
+
+    #[test]
+    fn fetch() -> Result<(), Error> {
+        let metadata = metadata();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let peer_addr = listener.local_addr()?;
+        let peer = serve(listener, metadata.clone());
+
+        let fetcher = MetadataFetcher::new(NodeID::from(sha1(&metadata)), peer_addr);
+        let mut runtime = Runtime::new()?;
+        let fetched = runtime.block_on(fetcher.fetch())?;
+
+        assert_eq!(fetched, metadata);
+        peer.join().unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    fn fetch_hash_mismatch() -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let peer_addr = listener.local_addr()?;
+        let peer = serve(listener, metadata());
+
+        let fetcher = MetadataFetcher::new(NodeID::random(), peer_addr);
+        let mut runtime = Runtime::new()?;
+
+        match runtime.block_on(fetcher.fetch()) {
+            Err(err) => match err.kind() {
+                ErrorKind::MetadataHashMismatch => {}
+                kind => panic!("unexpected error {}", kind),
+            },
+            Ok(_) => panic!("accepted metadata not matching the info-hash"),
+        };
+        peer.join().unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    fn pieces_validated() -> Result<(), Error> {
+        let mut pieces = MetadataPieces::new(METADATA_PIECE_SIZE as u32 + 1)?;
+        assert_eq!(pieces.count(), 2);
+
+        assert!(pieces.add(0, 1, &[0u8; METADATA_PIECE_SIZE]).is_err());
+        assert!(pieces.add(2, METADATA_PIECE_SIZE as u32 + 1, &[0]).is_err());
+        assert!(pieces
+            .add(1, METADATA_PIECE_SIZE as u32 + 1, &[0, 0])
+            .is_err());
+
+        pieces.add(1, METADATA_PIECE_SIZE as u32 + 1, &[7])?;
+        assert!(!pieces.is_complete());
+
+        pieces.add(
+            0,
+            METADATA_PIECE_SIZE as u32 + 1,
+            &[1u8; METADATA_PIECE_SIZE],
+        )?;
+        assert!(pieces.is_complete());
+        assert_eq!(pieces.into_bytes()[METADATA_PIECE_SIZE], 7);
+
+        assert!(MetadataPieces::new(0).is_err());
+
+        Ok(())
+    }
+}
@@ -2,6 +2,11 @@ use crate::routing::node::{
     Node,
     NodeState,
 };
+use chrono::{
+    Duration,
+    NaiveDateTime,
+    Utc,
+};
 use krpc_encoding::NodeID;
 use num_bigint::BigUint;
 use std::{
@@ -9,7 +14,52 @@ use std::{
     ops::Deref,
 };
 
-const MAX_BUCKET_SIZE: usize = 8;
+pub const MAX_BUCKET_SIZE: usize = 8;
+
+/// Most times the initial bucket can be split. A bucket this deep spans a
+/// single id.
+pub const MAX_DEPTH: usize = 160;
+
+/// Most nodes waiting in a bucket's replacements. Kept small so nodes close
+/// to our id can't be queued in bulk.
+pub const MAX_PENDING: usize = 4;
+
+/// Minutes after which a node waiting in the replacements is dropped, since
+/// it was likely gone by the time a place opens.
+pub const PENDING_TIMEOUT_MINUTES: i64 = 15;
+
+/// What [`Bucket::add_node`] did with a node.
+#[derive(Debug)]
+pub enum AddOutcome {
+    /// The node was added to the bucket.
+    Added,
+
+    /// The node took the place of this bad node.
+    Replaced(Node),
+
+    /// The bucket was full so the node was queued as a replacement.
+    Pending,
+
+    /// A node with the same id was already in the bucket.
+    Present,
+}
+
+/// A node waiting for a place in a full bucket.
+#[derive(Debug)]
+pub struct PendingNode {
+    pub node: Node,
+
+    /// When the node was queued.
+    pub queued: NaiveDateTime,
+}
+
+impl Deref for PendingNode {
+    type Target = Node;
+
+    fn deref(&self) -> &Node {
+        &self.node
+    }
+}
 
 #[derive(Debug)]
 pub struct Bucket {
@@ -21,6 +71,17 @@ pub struct Bucket {
 
     /// Nodes in the bucket. These nodes could be in any state.
     pub nodes: Vec<Node>,
+
+    /// Most nodes kept in the bucket, its k. Defaults to [`MAX_BUCKET_SIZE`].
+    pub capacity: usize,
+
+    /// Nodes seen while the bucket was full, oldest first, at most
+    /// [`MAX_PENDING`] of them. They take the place of nodes removed from the
+    /// bucket or failing verification.
+    pub replacements: Vec<PendingNode>,
+
+    /// Last time a node was added to or removed from the bucket.
+    pub last_changed: NaiveDateTime,
 }
 
 impl Bucket {
@@ -29,6 +90,9 @@ impl Bucket {
             start,
             end,
             nodes: Vec::new(),
+            capacity: MAX_BUCKET_SIZE,
+            replacements: Vec::new(),
+            last_changed: Utc::now().naive_utc(),
         }
     }
 
@@ -48,15 +112,33 @@ impl Bucket {
         NodeID::new(self.start.deref() + (self.end.deref() - self.start.deref()) / 2u8)
     }
 
-    pub fn split(&mut self) -> Bucket {
+    /// Number of times the initial bucket was split to make this bucket.
+    pub fn depth(&self) -> usize {
+        let width = self.end.deref() - self.start.deref();
+
+        (MAX_DEPTH + 1).saturating_sub(width.bits())
+    }
+
+    /// Moves the upper half of the bucket and the nodes in it to a new
+    /// bucket with the same capacity. Returns `None` without changing
+    /// anything when the bucket is [`MAX_DEPTH`] deep and can't be split any
+    /// further.
+    pub fn split(&mut self) -> Option<Bucket> {
+        if self.depth() >= MAX_DEPTH {
+            return None;
+        }
+
         let midpoint = self.midpoint();
 
         let next_bucket_end = mem::replace(&mut self.end, midpoint.clone());
         let mut next_bucket = Bucket::new(midpoint, next_bucket_end);
+        next_bucket.capacity = self.capacity;
 
-        let previous_bucket_nodes = Vec::with_capacity(MAX_BUCKET_SIZE);
+        let previous_bucket_nodes = Vec::with_capacity(self.capacity);
         let mut all_nodes = mem::replace(&mut self.nodes, previous_bucket_nodes);
 
+        self.last_changed = Utc::now().naive_utc();
+
         for node in all_nodes.drain(..) {
             let nodes = if self.could_hold_node(&node.id) {
                 &mut self.nodes
@@ -67,25 +149,48 @@ impl Bucket {
             nodes.push(node);
         }
 
-        next_bucket
+        let all_replacements = mem::replace(&mut self.replacements, Vec::new());
+        for node in all_replacements {
+            let replacements = if self.could_hold_node(&node.id) {
+                &mut self.replacements
+            } else {
+                &mut next_bucket.replacements
+            };
+
+            replacements.push(node);
+        }
+
+        Some(next_bucket)
+    }
+
+    /// Changes how many nodes the bucket holds. Nodes over the new capacity
+    /// become replacements, those seen least recently first.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+
+        while self.nodes.len() > capacity {
+            let node = self.nodes.remove(0);
+            self.add_replacement(node);
+        }
     }
 
     pub fn is_full(&self) -> bool {
-        self.good_nodes().count() >= MAX_BUCKET_SIZE
+        self.good_nodes().count() >= self.capacity
     }
 
-    pub fn add_node(&mut self, node: Node) {
+    pub fn add_node(&mut self, node: Node) -> AddOutcome {
         if !self.could_hold_node(&node.id) {
             panic!("Called add_node on a bucket which can't hold a node");
         }
 
         if self.nodes.iter().find(|n| n.id == node.id).is_some() {
-            return;
+            return AddOutcome::Present;
         }
 
-        if self.nodes.len() < MAX_BUCKET_SIZE {
+        if self.nodes.len() < self.capacity {
             self.nodes.push(node);
-            return;
+            self.last_changed = Utc::now().naive_utc();
+            return AddOutcome::Added;
         }
 
         let bad_node_opt = self
@@ -94,14 +199,143 @@ impl Bucket {
             .find(|node| node.state() == NodeState::Bad);
 
         if let Some(bad_node) = bad_node_opt {
-            mem::replace(bad_node, node);
+            let replaced = mem::replace(bad_node, node);
+            self.last_changed = Utc::now().naive_utc();
+            return AddOutcome::Replaced(replaced);
         }
+
+        self.add_replacement(node);
+        AddOutcome::Pending
     }
 
-    pub fn good_nodes(&self) -> impl Iterator<Item = &Node> {
+    fn add_replacement(&mut self, node: Node) {
+        let now = Utc::now().naive_utc();
+        self.drop_stale_replacements(now);
+
+        if self.replacements.iter().any(|n| n.id == node.id) {
+            return;
+        }
+
+        if self.replacements.len() >= MAX_PENDING {
+            self.replacements.remove(0);
+        }
+
+        self.replacements.push(PendingNode { node, queued: now });
+    }
+
+    /// Drops the replacements queued longer than [`PENDING_TIMEOUT_MINUTES`]
+    /// ago. They are oldest first, so only a prefix is dropped.
+    fn drop_stale_replacements(&mut self, now: NaiveDateTime) {
+        let timeout = Duration::minutes(PENDING_TIMEOUT_MINUTES);
+        let stale = self
+            .replacements
+            .iter()
+            .take_while(|pending| now.signed_duration_since(pending.queued) > timeout)
+            .count();
+
+        self.replacements.drain(..stale);
+    }
+
+    /// Moves the oldest replacement which isn't stale into the bucket.
+    fn promote_replacement(&mut self) {
+        self.drop_stale_replacements(Utc::now().naive_utc());
+
+        if !self.replacements.is_empty() {
+            self.nodes.push(self.replacements.remove(0).node);
+        }
+    }
+
+    /// Removes the node with `id` from the bucket or its replacements. A
+    /// node removed from the bucket is replaced by the oldest replacement.
+    pub fn remove(&mut self, id: &NodeID) -> Option<Node> {
+        if let Some(idx) = self.replacements.iter().position(|node| &node.id == id) {
+            return Some(self.replacements.remove(idx).node);
+        }
+
+        let idx = self.nodes.iter().position(|node| &node.id == id)?;
+        let removed = self.nodes.remove(idx);
+        self.promote_replacement();
+        self.last_changed = Utc::now().naive_utc();
+
+        Some(removed)
+    }
+
+    /// Counts a failed verification of the node with `id`, a questionable
+    /// node pinged because the bucket has replacements waiting. The oldest
+    /// replacement takes its place and the node is returned. Without any
+    /// replacement the node stays.
+    pub fn fail_verification(&mut self, id: &NodeID) -> Option<Node> {
+        self.drop_stale_replacements(Utc::now().naive_utc());
+
+        let idx = self.nodes.iter().position(|node| &node.id == id)?;
+        self.nodes[idx].mark_failed_request();
+        if self.replacements.is_empty() {
+            return None;
+        }
+
+        let replacement = self.replacements.remove(0).node;
+        self.last_changed = Utc::now().naive_utc();
+
+        Some(mem::replace(&mut self.nodes[idx], replacement))
+    }
+
+    /// The questionable node seen least recently, which should be pinged
+    /// before it's evicted.
+    pub fn oldest_questionable(&self) -> Option<&Node> {
         self.nodes
             .iter()
-            .filter(|node| node.state() == NodeState::Good)
+            .filter(|node| node.state() == NodeState::Questionable)
+            .min_by_key(|node| node.last_seen())
+    }
+
+    /// Moves the node with `id` to the tail of the bucket, where the nodes
+    /// seen most recently are as described in [BEP-0005]. Returns whether
+    /// the node is in the bucket.
+    ///
+    /// [BEP-0005]: http://www.bittorrent.org/beps/bep_0005.html
+    pub fn touch(&mut self, id: &NodeID) -> bool {
+        match self.nodes.iter().position(|node| &node.id == id) {
+            Some(idx) => {
+                let node = self.nodes.remove(idx);
+                self.nodes.push(node);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The node at the head of the bucket, seen least recently. The first
+    /// candidate for eviction.
+    pub fn oldest_node(&self) -> Option<&Node> {
+        self.nodes.first()
+    }
+
+    /// The node at the tail of the bucket, seen most recently.
+    pub fn newest_node(&self) -> Option<&Node> {
+        self.nodes.last()
+    }
+
+    /// Iterates over every node in the bucket regardless of its state.
+    pub fn iter(&self) -> impl Iterator<Item = &Node> {
+        self.nodes.iter()
+    }
+
+    /// Nodes which responded to us within the last 15 minutes, or queried us
+    /// within the last 15 minutes after ever responding, as defined in
+    /// [BEP-0005]. See [`Node::state`].
+    ///
+    /// [BEP-0005]: http://www.bittorrent.org/beps/bep_0005.html
+    pub fn good_nodes(&self) -> impl Iterator<Item = &Node> {
+        self.nodes_in_state(NodeState::Good)
+    }
+
+    /// Nodes not heard from for 15 minutes which didn't fail to answer yet.
+    pub fn questionable_nodes(&self) -> impl Iterator<Item = &Node> {
+        self.nodes_in_state(NodeState::Questionable)
+    }
+
+    fn nodes_in_state(&self, state: NodeState) -> impl Iterator<Item = &Node> {
+        self.nodes.iter().filter(move |node| node.state() == state)
     }
 
     pub fn get(&self, id: &NodeID) -> Option<&Node> {
@@ -119,8 +353,13 @@ mod tests {
         BigUint,
         Bucket,
         NodeID,
+        MAX_DEPTH,
     };
     use crate::routing::node::Node;
+    use chrono::{
+        Duration,
+        Utc,
+    };
     use num_traits as num;
 
     #[test]
@@ -183,7 +422,7 @@ mod tests {
 
         assert_eq!(bucket.nodes.len(), 6);
 
-        let next_bucket = bucket.split();
+        let next_bucket = bucket.split().unwrap();
 
         for i in (10 as u8)..13 {
             let id = NodeID::new(BigUint::from(i));
@@ -200,6 +439,23 @@ mod tests {
         }
     }
 
+    #[test]
+    fn split_until_single_id() {
+        let mut bucket = Bucket::initial_bucket();
+        assert_eq!(bucket.depth(), 0);
+
+        for depth in 1..=MAX_DEPTH {
+            let next_bucket = bucket.split().unwrap();
+            assert_eq!(bucket.depth(), depth);
+            assert_eq!(next_bucket.depth(), depth);
+            assert_eq!(bucket.end, next_bucket.start);
+        }
+
+        assert_eq!(&*bucket.end - &*bucket.start, BigUint::from(1u8));
+        assert!(bucket.split().is_none());
+        assert_eq!(bucket.depth(), MAX_DEPTH);
+    }
+
     #[test]
     fn get_empty() {
         let bucket = Bucket::initial_bucket();
@@ -215,4 +471,91 @@ mod tests {
 
         assert!(bucket.get(&id).is_some());
     }
+
+    #[test]
+    fn touch_moves_to_tail() {
+        let mut bucket = Bucket::initial_bucket();
+        for i in 0..5 {
+            bucket.add_node(Node::new_with_id(i));
+        }
+
+        let first = NodeID::new(BigUint::from(0u8));
+        assert!(bucket.touch(&first));
+
+        let order: Vec<NodeID> = bucket.iter().map(|node| node.id.clone()).collect();
+        let expected: Vec<NodeID> = [1u8, 2, 3, 4, 0]
+            .iter()
+            .map(|i| NodeID::new(BigUint::from(*i)))
+            .collect();
+        assert_eq!(order, expected);
+
+        assert!(!bucket.touch(&NodeID::new(BigUint::from(5u8))));
+    }
+
+    #[test]
+    fn good_nodes_by_age() {
+        let now = Utc::now().naive_utc();
+        let ago = |minutes| Some(now - Duration::minutes(minutes));
+        let history = [
+            // Responded recently.
+            (ago(5), None, 0),
+            // Responded long ago, queried us recently.
+            (ago(60), ago(5), 0),
+            // Silent for too long.
+            (ago(20), ago(20), 0),
+            // Queried us recently but never responded.
+            (None, ago(5), 0),
+            // Responded recently, then failed to twice.
+            (ago(5), None, 2),
+            // Responded just in time.
+            (ago(14), None, 0),
+        ];
+
+        let mut bucket = Bucket::initial_bucket();
+        let address = "127.0.0.1:3000".parse().unwrap();
+        for (i, (to, from, failed)) in history.iter().enumerate() {
+            let id = NodeID::new(BigUint::from(i));
+            bucket.add_node(Node::with_history(id, address, *to, *from, *failed));
+        }
+
+        let ids = |nodes: Vec<&Node>| -> Vec<NodeID> {
+            nodes.into_iter().map(|node| node.id.clone()).collect()
+        };
+        let expected = |ids: &[u8]| -> Vec<NodeID> {
+            ids.iter().map(|i| NodeID::new(BigUint::from(*i))).collect()
+        };
+        assert_eq!(ids(bucket.good_nodes().collect()), expected(&[0, 1, 5]));
+        assert_eq!(
+            ids(bucket.questionable_nodes().collect()),
+            expected(&[2, 3])
+        );
+    }
+
+    #[test]
+    fn oldest_and_newest() {
+        let id = |i: u8| NodeID::new(BigUint::from(i));
+        let ends = |bucket: &Bucket| {
+            (
+                bucket.oldest_node().map(|node| node.id.clone()),
+                bucket.newest_node().map(|node| node.id.clone()),
+            )
+        };
+
+        let mut bucket = Bucket::initial_bucket();
+        assert_eq!(ends(&bucket), (None, None));
+
+        for i in 0..5 {
+            bucket.add_node(Node::new_with_id(i));
+            assert_eq!(ends(&bucket), (Some(id(0)), Some(id(i))));
+        }
+
+        bucket.touch(&id(2));
+        assert_eq!(ends(&bucket), (Some(id(0)), Some(id(2))));
+
+        bucket.touch(&id(0));
+        assert_eq!(ends(&bucket), (Some(id(1)), Some(id(0))));
+
+        bucket.touch(&id(1));
+        assert_eq!(ends(&bucket), (Some(id(3)), Some(id(1))));
+    }
 }
@@ -0,0 +1,118 @@
+//! Serializable snapshot of the routing table used to debug its health.
+
+use crate::routing::{
+    bucket::Bucket,
+    node::{
+        Node,
+        NodeState,
+    },
+};
+use chrono::{
+    NaiveDateTime,
+    Utc,
+};
+use serde_derive::Serialize;
+use std::net::SocketAddrV4;
+
+#[derive(Debug, Serialize)]
+pub struct TableDump {
+    pub total_nodes: usize,
+
+    /// Number of buckets in the table.
+    pub depth: usize,
+
+    /// Fraction of buckets holding as many nodes as they can.
+    pub full_bucket_fraction: f64,
+
+    pub buckets: Vec<BucketDump>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BucketDump {
+    /// Inclusive start key of the bucket, hex encoded.
+    pub start: String,
+
+    /// Exclusive end key of the bucket, hex encoded.
+    pub end: String,
+
+    pub good: usize,
+    pub questionable: usize,
+    pub bad: usize,
+
+    pub last_changed: NaiveDateTime,
+
+    pub nodes: Vec<NodeDump>,
+
+    /// Nodes waiting in the bucket's replacements.
+    pub pending: usize,
+
+    /// Seconds each replacement has been waiting, oldest first.
+    pub pending_ages_secs: Vec<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NodeDump {
+    pub id: String,
+    pub address: SocketAddrV4,
+    pub state: NodeState,
+    pub last_request_to: Option<NaiveDateTime>,
+    pub last_request_from: Option<NaiveDateTime>,
+
+    /// Round trip time of the last successful request in milliseconds.
+    pub rtt_ms: Option<u64>,
+}
+
+impl TableDump {
+    pub(crate) fn new<'a, I: Iterator<Item = &'a Bucket>>(buckets: I) -> TableDump {
+        let buckets: Vec<&Bucket> = buckets.collect();
+        let full_buckets = buckets
+            .iter()
+            .filter(|bucket| bucket.nodes.len() >= bucket.capacity)
+            .count();
+        let buckets: Vec<BucketDump> = buckets.into_iter().map(BucketDump::new).collect();
+
+        TableDump {
+            total_nodes: buckets.iter().map(|bucket| bucket.nodes.len()).sum(),
+            depth: buckets.len(),
+            full_bucket_fraction: full_buckets as f64 / buckets.len() as f64,
+            buckets,
+        }
+    }
+}
+
+impl BucketDump {
+    fn new(bucket: &Bucket) -> BucketDump {
+        let nodes: Vec<NodeDump> = bucket.iter().map(NodeDump::new).collect();
+        let count = |state| nodes.iter().filter(|node| node.state == state).count();
+        let now = Utc::now().naive_utc();
+
+        BucketDump {
+            start: format!("{:040x}", *bucket.start),
+            end: format!("{:040x}", *bucket.end),
+            good: count(NodeState::Good),
+            questionable: count(NodeState::Questionable),
+            bad: count(NodeState::Bad),
+            last_changed: bucket.last_changed,
+            nodes,
+            pending: bucket.replacements.len(),
+            pending_ages_secs: bucket
+                .replacements
+                .iter()
+                .map(|pending| now.signed_duration_since(pending.queued).num_seconds())
+                .collect(),
+        }
+    }
+}
+
+impl NodeDump {
+    fn new(node: &Node) -> NodeDump {
+        NodeDump {
+            id: node.id.to_string(),
+            address: node.address,
+            state: node.state(),
+            last_request_to: node.last_request_to(),
+            last_request_from: node.last_request_from(),
+            rtt_ms: node.rtt().map(|rtt| rtt.as_millis() as u64),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Pings questionable nodes before evicting them, as [BEP-0005] requires.
+//! A node which answers stays, so long lived nodes aren't pushed out by
+//! newer ones just because nobody talked to them for a while.
+//!
+//! [BEP-0005]: http://www.bittorrent.org/beps/bep_0005.html
+
+use super::RoutingTable;
+use crate::errors::Result;
+use krpc_encoding::NodeID;
+use std::{
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::prelude::FutureExt;
+use tokio_krpc::{
+    QueryOptions,
+    Transport,
+};
+
+/// Time a questionable node has to answer before it's evicted. Shorter than
+/// usual since the node was likely gone already.
+const EVICT_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+impl RoutingTable {
+    /// Pings the questionable node seen least recently in the bucket which
+    /// could hold `near`, such as a node just queued as a replacement. A node
+    /// answering with its id is marked as good again. Otherwise it fails
+    /// verification, see [`RoutingTable::fail_verification`], and the oldest
+    /// replacement of the bucket takes its place. Returns whether a node was
+    /// replaced, `false` as well when the bucket has no questionable node.
+    pub async fn try_evict_questionable(
+        table: Arc<Mutex<RoutingTable>>,
+        transport: Arc<dyn Transport>,
+        near: NodeID,
+    ) -> Result<bool> {
+        let (id, node) = {
+            let table = table.lock()?;
+            match table.oldest_questionable(&near) {
+                Some(node) => (table.id().clone(), node),
+                None => return Ok(false),
+            }
+        };
+
+        let result = transport
+            .ping_with_options(
+                id,
+                node.address.into(),
+                QueryOptions {
+                    timeout: EVICT_PING_TIMEOUT,
+                    expected_id: Some(node.node_id.clone()),
+                },
+            )
+            .timeout(EVICT_PING_TIMEOUT)
+            .await;
+
+        let mut table = table.lock()?;
+        match result {
+            Ok(Ok(id)) if id == node.node_id => {
+                if let Some(table_node) = table.get_node_mut(&node.node_id) {
+                    table_node.mark_successful_request();
+                }
+
+                Ok(false)
+            }
+            _ => Ok(table.fail_verification(&node.node_id).is_some()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        routing::{
+            Node,
+            NodeState,
+            RoutingTable,
+        },
+        testing::{
+            MockTransport,
+            Reply,
+        },
+    };
+    use failure::Error;
+    use krpc_encoding::{
+        NodeID,
+        Response,
+    };
+    use std::{
+        net::SocketAddrV4,
+        sync::{
+            Arc,
+            Mutex,
+        },
+    };
+    use tokio::runtime::current_thread::Runtime;
+
+    fn address(idx: u8) -> SocketAddrV4 {
+        SocketAddrV4::new([10, 0, idx, 1].into(), 6881)
+    }
+
+    /// A table whose only bucket holds 8 questionable nodes at `address(0)`
+    /// to `address(7)`, oldest first, and a replacement at `address(8)`.
+    fn full_table() -> (RoutingTable, Vec<NodeID>) {
+        let mut table = RoutingTable::new(NodeID::random());
+        let ids: Vec<NodeID> = (0..9).map(|_| NodeID::random()).collect();
+        for (idx, id) in ids.iter().enumerate() {
+            table.add_node(Node::new(id.clone(), address(idx as u8)));
+        }
+
+        (table, ids)
+    }
+
+    #[test]
+    fn responsive_node_retained() -> Result<(), Error> {
+        let (table, ids) = full_table();
+        let table = Arc::new(Mutex::new(table));
+        let mock = Arc::new(MockTransport::new());
+        let id = ids[0].clone();
+        mock.respond(address(0).into(), move |_| {
+            Reply::Response(Response::OnlyID { id: id.clone() })
+        });
+
+        let evicted = Runtime::new()?.block_on(RoutingTable::try_evict_questionable(
+            table.clone(),
+            mock.clone(),
+            ids[8].clone(),
+        ))?;
+
+        assert!(!evicted);
+        // The node has to answer with the id it's known by.
+        let calls = mock.take_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].expected_id, Some(ids[0].clone()));
+        let table = table.lock().unwrap();
+        assert_eq!(
+            table.get_node(&ids[0]).map(Node::state),
+            Some(NodeState::Good)
+        );
+        assert!(table.get_node(&ids[8]).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unresponsive_node_replaced() -> Result<(), Error> {
+        let (table, ids) = full_table();
+        let table = Arc::new(Mutex::new(table));
+        // Nothing answers.
+        let mock = Arc::new(MockTransport::new());
+
+        let evicted = Runtime::new()?.block_on(RoutingTable::try_evict_questionable(
+            table.clone(),
+            mock.clone(),
+            ids[8].clone(),
+        ))?;
+
+        assert!(evicted);
+        {
+            let table = table.lock().unwrap();
+            assert!(table.get_node(&ids[0]).is_none());
+            assert!(table.get_node(&ids[8]).is_some());
+            assert_eq!(table.len(), 8);
+        }
+
+        // Without a replacement waiting the node stays.
+        let evicted = Runtime::new()?.block_on(RoutingTable::try_evict_questionable(
+            table.clone(),
+            mock.clone(),
+            ids[1].clone(),
+        ))?;
+        assert!(!evicted);
+        assert_eq!(table.lock().unwrap().len(), 8);
+
+        // A node answering with another id is replaced as well.
+        let replacement = NodeID::random();
+        table
+            .lock()
+            .unwrap()
+            .add_node(Node::new(replacement.clone(), address(9)));
+        for idx in 0..9 {
+            mock.respond(address(idx).into(), |_| {
+                Reply::Response(Response::OnlyID {
+                    id: NodeID::random(),
+                })
+            });
+        }
+        let evicted = Runtime::new()?.block_on(RoutingTable::try_evict_questionable(
+            table.clone(),
+            mock,
+            replacement.clone(),
+        ))?;
+        assert!(evicted);
+        let table = table.lock().unwrap();
+        assert!(table.get_node(&replacement).is_some());
+        assert_eq!(table.len(), 8);
+
+        Ok(())
+    }
+}
@@ -1,11 +1,35 @@
 mod bucket;
+mod dump;
+mod evict;
 mod node;
+mod persist;
+mod refresh;
+mod selection;
 mod table;
 
 pub use self::{
-    node::Node,
+    dump::{
+        BucketDump,
+        NodeDump,
+        TableDump,
+    },
+    node::{
+        Node,
+        NodeState,
+    },
+    refresh::DEFAULT_REFRESH_INTERVAL,
+    selection::SelectionWeights,
     table::{
+        AddNodeOutcome,
+        AddNodesSummary,
+        BootstrapCallback,
+        BucketSizePolicy,
         FindNodeResult,
         RoutingTable,
+        RoutingTableConfig,
     },
 };
+pub(crate) use self::{
+    node::INITIAL_RELIABILITY,
+    selection::selection_order,
+};
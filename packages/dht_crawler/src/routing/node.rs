@@ -6,22 +6,100 @@ use krpc_encoding::{
     NodeID,
     NodeInfo,
 };
-use std::net::SocketAddrV4;
+#[cfg(loom)]
+use loom::sync::atomic::{
+    AtomicU32,
+    AtomicU8,
+    Ordering,
+};
+use serde_derive::Serialize;
+#[cfg(not(loom))]
+use std::sync::atomic::{
+    AtomicU32,
+    AtomicU8,
+    Ordering,
+};
+use std::{
+    cmp,
+    net::SocketAddrV4,
+    time::Duration,
+};
+
+/// Number of failed requests in a row after which a node is bad.
+const MAX_FAILED_REQUESTS: u8 = 2;
+
+/// Transitions kept in the top two bits of [`Node::state`]. A good node
+/// which wasn't seen for a while is questionable regardless.
+const QUESTIONABLE: u8 = 0;
+const GOOD: u8 = 1;
+const BAD: u8 = 2;
+const STATE_SHIFT: u32 = 6;
+
+/// Bits of [`Node::state`] counting failed requests. The count saturates.
+const FAILURES_MASK: u8 = (1 << STATE_SHIFT) - 1;
+
+/// Stored in place of a timestamp which isn't known.
+const NEVER: u32 = u32::max_value();
 
-#[derive(Debug, PartialEq)]
+/// Stored in place of a round trip time which isn't known. Longer round trip
+/// times, over an hour, are stored as one microsecond less.
+const NO_RTT: u32 = u32::max_value();
+
+/// Reliability of a node nothing is known about yet.
+pub(crate) const INITIAL_RELIABILITY: f64 = 0.5;
+
+/// Reliability is stored in millionths.
+const RELIABILITY_SCALE: f64 = 1_000_000.0;
+
+/// Weight of the newest sample in the smoothed reliability and round trip
+/// time, like TCP's smoothed RTT.
+const SMOOTHING: f64 = 0.125;
+
+/// A node in the routing table. Its state is kept in atomics so it can be
+/// updated through a shared reference, without holding a write lock on the
+/// whole table. Crawls keep hundreds of thousands of nodes, so the state is
+/// packed in 32 bits per field.
+#[derive(Debug)]
 pub struct Node {
     pub id: NodeID,
     pub address: SocketAddrV4,
 
     /// Last time a message was sent from ourselves to this node and a response
-    /// was received successfully.
-    last_request_to: Option<NaiveDateTime>,
-
-    /// Last time a valid request was received from this node.
-    last_request_from: Option<NaiveDateTime>,
+    /// was received successfully. Seconds since the Unix epoch or [`NEVER`].
+    last_request_to: AtomicU32,
+
+    /// Last time a valid request was received from this node. Seconds since
+    /// the Unix epoch or [`NEVER`].
+    last_request_from: AtomicU32,
+
+    /// Round trip time of the last successful request to this node in
+    /// microseconds or [`NO_RTT`].
+    rtt: AtomicU32,
+
+    /// Exponentially weighted average of the round trip times, in
+    /// microseconds or [`NO_RTT`].
+    smoothed_rtt: AtomicU32,
+
+    /// Exponentially weighted fraction of the requests to this node which
+    /// succeeded, in millionths.
+    reliability: AtomicU32,
+
+    /// Last transition, [`GOOD`], [`QUESTIONABLE`] or [`BAD`], above the
+    /// number of failed requests from us to the node since
+    /// `last_request_to`. Both change together in one `compare_exchange`, so
+    /// a node is never bad with a clean record or good after failing.
+    state: AtomicU8,
+}
 
-    /// Number of failed requests from us to the node since `last_request_to`.
-    failed_requests: u8,
+impl PartialEq for Node {
+    fn eq(&self, other: &Node) -> bool {
+        self.id == other.id
+            && self.address == other.address
+            && self.last_request_to() == other.last_request_to()
+            && self.last_request_from() == other.last_request_from()
+            && self.failed_requests() == other.failed_requests()
+            && self.rtt() == other.rtt()
+    }
 }
 
 impl<'a> Into<NodeInfo> for &'a Node {
@@ -36,7 +114,8 @@ impl Into<NodeInfo> for Node {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum NodeState {
     /// A good node is a node has responded to one of our queries within the
     /// last 15 minutes. A node is also good if it has ever responded to one
@@ -57,33 +136,166 @@ impl Node {
         Node {
             id,
             address,
-            last_request_to: None,
-            last_request_from: None,
-            failed_requests: 0,
+            last_request_to: AtomicU32::new(NEVER),
+            last_request_from: AtomicU32::new(NEVER),
+            rtt: AtomicU32::new(NO_RTT),
+            smoothed_rtt: AtomicU32::new(NO_RTT),
+            reliability: AtomicU32::new((INITIAL_RELIABILITY * RELIABILITY_SCALE) as u32),
+            state: AtomicU8::new(pack(QUESTIONABLE, 0)),
+        }
+    }
+
+    /// Recreates a node with the activity recorded for it earlier, like when
+    /// loading a saved table.
+    pub(crate) fn with_history(
+        id: NodeID,
+        address: SocketAddrV4,
+        last_request_to: Option<NaiveDateTime>,
+        last_request_from: Option<NaiveDateTime>,
+        failed_requests: u8,
+    ) -> Node {
+        let node = Node::new(id, address);
+        node.last_request_to
+            .store(last_request_to.map_or(NEVER, to_secs), Ordering::Relaxed);
+        node.last_request_from
+            .store(last_request_from.map_or(NEVER, to_secs), Ordering::Relaxed);
+        let state = if failed_requests >= MAX_FAILED_REQUESTS {
+            BAD
+        } else if last_request_to.is_some() {
+            GOOD
+        } else {
+            QUESTIONABLE
+        };
+        node.state
+            .store(pack(state, failed_requests), Ordering::Relaxed);
+
+        node
+    }
+
+    pub fn mark_successful_request(&self) {
+        self.last_request_to
+            .store(to_secs(Utc::now().naive_utc()), Ordering::Relaxed);
+        self.transition(|_, _| (GOOD, 0));
+        self.record_reliability(1.0);
+    }
+
+    pub fn record_rtt(&self, rtt: Duration) {
+        let micros = rtt.as_micros();
+        let micros = if micros < u128::from(NO_RTT) {
+            micros as u32
+        } else {
+            NO_RTT - 1
+        };
+
+        self.rtt.store(micros, Ordering::Relaxed);
+        update_atomic(&self.smoothed_rtt, |smoothed| match smoothed {
+            NO_RTT => micros,
+            smoothed => smooth(f64::from(smoothed), f64::from(micros)) as u32,
+        });
+    }
+
+    /// Counts a failed request. Concurrent failures are all counted.
+    pub fn mark_failed_request(&self) {
+        self.transition(|state, failed| {
+            let failed = failed.saturating_add(1);
+            if failed >= MAX_FAILED_REQUESTS {
+                (BAD, failed)
+            } else {
+                (state, failed)
+            }
+        });
+        self.record_reliability(0.0);
+    }
+
+    /// Marks the node as bad right away. Used when the node's address can't be
+    /// reached at all.
+    pub fn mark_unreachable(&self) {
+        self.transition(|_, failed| (BAD, failed.max(MAX_FAILED_REQUESTS)));
+        self.record_reliability(0.0);
+    }
+
+    pub fn mark_successful_request_from(&self) {
+        self.last_request_from
+            .store(to_secs(Utc::now().naive_utc()), Ordering::Relaxed);
+    }
+
+    pub fn last_request_to(&self) -> Option<NaiveDateTime> {
+        from_secs(self.last_request_to.load(Ordering::Relaxed))
+    }
+
+    pub fn last_request_from(&self) -> Option<NaiveDateTime> {
+        from_secs(self.last_request_from.load(Ordering::Relaxed))
+    }
+
+    /// Last time a request to or from the node succeeded.
+    pub fn last_seen(&self) -> Option<NaiveDateTime> {
+        cmp::max(self.last_request_to(), self.last_request_from())
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt.load(Ordering::Relaxed) {
+            NO_RTT => None,
+            micros => Some(Duration::from_micros(micros.into())),
+        }
+    }
+
+    /// Exponentially weighted average of the round trip times of the
+    /// successful requests to this node.
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        match self.smoothed_rtt.load(Ordering::Relaxed) {
+            NO_RTT => None,
+            micros => Some(Duration::from_micros(micros.into())),
         }
     }
 
-    pub fn mark_successful_request(&mut self) {
-        self.failed_requests = 0;
-        self.last_request_to = Some(Utc::now().naive_utc());
+    /// Exponentially weighted fraction of the requests to this node which
+    /// succeeded, between 0 and 1. Recent requests count the most. Starts at
+    /// 0.5 for a node which was never queried.
+    pub fn reliability(&self) -> f64 {
+        f64::from(self.reliability.load(Ordering::Relaxed)) / RELIABILITY_SCALE
     }
 
-    pub fn mark_failed_request(&mut self) {
-        self.failed_requests += 1;
+    /// Folds the outcome of a request, 1 for a success and 0 for a failure,
+    /// into the reliability.
+    fn record_reliability(&self, outcome: f64) {
+        update_atomic(&self.reliability, |reliability| {
+            let reliability = f64::from(reliability) / RELIABILITY_SCALE;
+            (smooth(reliability, outcome) * RELIABILITY_SCALE).round() as u32
+        });
     }
 
-    pub fn mark_successful_request_from(&mut self) {
-        self.last_request_from = Some(Utc::now().naive_utc());
+    pub(crate) fn failed_requests(&self) -> u8 {
+        self.state.load(Ordering::Relaxed) & FAILURES_MASK
+    }
+
+    /// Replaces the transition and the failure count with `update` applied
+    /// to them, retrying if another thread changes them in between.
+    fn transition(&self, update: impl Fn(u8, u8) -> (u8, u8)) {
+        let mut current = self.state.load(Ordering::Relaxed);
+        loop {
+            let (state, failed) = update(current >> STATE_SHIFT, current & FAILURES_MASK);
+            match self.state.compare_exchange_weak(
+                current,
+                pack(state, failed),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
     }
 
     pub fn state(&self) -> NodeState {
         let now = Utc::now().naive_utc();
 
-        if self.failed_requests >= 2 {
-            return NodeState::Bad;
+        match self.state.load(Ordering::Relaxed) >> STATE_SHIFT {
+            BAD => return NodeState::Bad,
+            GOOD => {}
+            _ => return NodeState::Questionable,
         };
 
-        match (self.last_request_from, self.last_request_to) {
+        match (self.last_request_from(), self.last_request_to()) {
             (Some(last_request_from), Some(..))
                 if now.signed_duration_since(last_request_from).num_minutes() < 15 =>
             {
@@ -102,25 +314,77 @@ impl Node {
     pub fn new_with_id(id: u8) -> Node {
         use num_bigint::BigUint;
 
-        let addr: SocketAddrV4 = "127.0.0.1:3000".parse().unwrap();
+        // A /24 subnet for each id, so tables take every one of them.
+        let addr = SocketAddrV4::new([10, 0, id, 1].into(), 3000);
+
+        Node::new(NodeID::new(BigUint::from(id)), addr)
+    }
+}
+
+/// Packs a transition and a failure count into the byte of [`Node::state`].
+fn pack(state: u8, failed_requests: u8) -> u8 {
+    (state << STATE_SHIFT) | cmp::min(failed_requests, FAILURES_MASK)
+}
 
-        Node::new(NodeID::new(BigUint::from(id)), addr.clone())
+/// Moves the average `smoothed` towards `sample`.
+fn smooth(smoothed: f64, sample: f64) -> f64 {
+    smoothed + (sample - smoothed) * SMOOTHING
+}
+
+/// Replaces `value` with `update` applied to it, retrying if another thread
+/// changes it in between.
+fn update_atomic(value: &AtomicU32, update: impl Fn(u32) -> u32) {
+    let mut current = value.load(Ordering::Relaxed);
+    loop {
+        match value.compare_exchange_weak(
+            current,
+            update(current),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => return,
+            Err(actual) => current = actual,
+        }
     }
 }
 
+/// Seconds since the Unix epoch of `time`, rounded down. Times outside of
+/// 1970 to 2106 are clamped.
+fn to_secs(time: NaiveDateTime) -> u32 {
+    cmp::min(cmp::max(time.timestamp(), 0), i64::from(NEVER - 1)) as u32
+}
+
+fn from_secs(secs: u32) -> Option<NaiveDateTime> {
+    if secs == NEVER {
+        return None;
+    }
+
+    NaiveDateTime::from_timestamp_opt(secs.into(), 0)
+}
+
 #[cfg(test)]
 mod tests {
     use super::{
         Node,
-        NodeID,
         NodeState,
+        FAILURES_MASK,
+        MAX_FAILED_REQUESTS,
     };
     use chrono::{
         prelude::*,
         Duration,
     };
-    use failure::Error;
-    use num_bigint::BigUint;
+    use std::{
+        mem,
+        sync::Arc,
+        thread,
+    };
+
+    #[test]
+    fn packed() {
+        // The id and the address take 40 bytes on their own.
+        assert!(mem::size_of::<Node>() <= 64, "{}", mem::size_of::<Node>());
+    }
 
     #[test]
     fn starting_state() {
@@ -131,7 +395,7 @@ mod tests {
 
     #[test]
     fn good_state_request() {
-        let mut node = Node::new_with_id(10);
+        let node = Node::new_with_id(10);
         node.mark_successful_request();
 
         assert_eq!(node.state(), NodeState::Good);
@@ -139,7 +403,7 @@ mod tests {
 
     #[test]
     fn response_only_questionable() {
-        let mut node = Node::new_with_id(10);
+        let node = Node::new_with_id(10);
         node.mark_successful_request_from();
 
         assert_eq!(node.state(), NodeState::Questionable);
@@ -147,7 +411,7 @@ mod tests {
 
     #[test]
     fn bad_state() {
-        let mut node = Node::new_with_id(10);
+        let node = Node::new_with_id(10);
         node.mark_failed_request();
         assert_eq!(node.state(), NodeState::Questionable);
 
@@ -156,19 +420,198 @@ mod tests {
     }
 
     #[test]
-    fn request_response_good() -> Result<(), Error> {
+    fn unreachable_is_bad() {
+        let node = Node::new_with_id(10);
+        node.mark_successful_request();
+        node.mark_unreachable();
+
+        assert_eq!(node.state(), NodeState::Bad);
+    }
+
+    #[test]
+    fn request_response_good() {
         let epoch = NaiveDate::from_ymd(1970, 1, 1).and_hms_milli(0, 0, 1, 980);
 
-        let node = Node {
-            id: NodeID::new(BigUint::from(10u8)),
-            address: "127.0.0.1:3000".parse()?,
-            last_request_to: Some(epoch),
-            last_request_from: Some(Utc::now().naive_utc() - Duration::minutes(10)),
-            failed_requests: 0,
+        let node = Node::new_with_id(10);
+        let node = Node::with_history(
+            node.id,
+            node.address,
+            Some(epoch),
+            Some(Utc::now().naive_utc() - Duration::minutes(10)),
+            0,
+        );
+
+        // Kept to the second.
+        assert_eq!(
+            node.last_request_to(),
+            Some(NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 1))
+        );
+        assert_eq!(node.state(), NodeState::Good);
+    }
+
+    #[test]
+    fn reliability_and_rtt_smoothed() {
+        let node = Node::new_with_id(10);
+        assert!((node.reliability() - 0.5).abs() < 1e-6);
+        assert_eq!(node.smoothed_rtt(), None);
+
+        for _ in 0..20 {
+            node.mark_successful_request();
+        }
+        assert!(node.reliability() > 0.95, "{}", node.reliability());
+
+        // A single failure costs a fraction of the score, several in a row
+        // most of it.
+        node.mark_failed_request();
+        assert!((node.reliability() - 0.85).abs() < 0.05);
+        for _ in 0..10 {
+            node.mark_failed_request();
+        }
+        assert!(node.reliability() < 0.3, "{}", node.reliability());
+
+        node.record_rtt(std::time::Duration::from_millis(80));
+        assert_eq!(
+            node.smoothed_rtt(),
+            Some(std::time::Duration::from_millis(80))
+        );
+        node.record_rtt(std::time::Duration::from_millis(800));
+        assert_eq!(node.rtt(), Some(std::time::Duration::from_millis(800)));
+        assert_eq!(
+            node.smoothed_rtt(),
+            Some(std::time::Duration::from_millis(170))
+        );
+    }
+
+    #[test]
+    fn concurrent_failures_counted() {
+        let node = Arc::new(Node::new_with_id(10));
+        node.mark_successful_request();
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let node = node.clone();
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        node.mark_failed_request();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(node.failed_requests(), 40);
+        assert_eq!(node.state(), NodeState::Bad);
+
+        // Saturates instead of wrapping back to a node which seems healthy.
+        for _ in 0..200 {
+            node.mark_failed_request();
+        }
+        assert_eq!(node.failed_requests(), FAILURES_MASK);
+        assert_eq!(node.state(), NodeState::Bad);
+    }
+
+    #[test]
+    fn concurrent_success_and_failure() {
+        let node = Arc::new(Node::new_with_id(10));
+
+        let failing = {
+            let node = node.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    node.mark_unreachable();
+                }
+            })
         };
+        for _ in 0..1000 {
+            node.mark_successful_request();
+            node.record_rtt(std::time::Duration::from_millis(5));
+        }
+        failing.join().unwrap();
 
-        assert_eq!(node.state(), NodeState::Good);
+        assert!(node.failed_requests() == 0 || node.failed_requests() >= MAX_FAILED_REQUESTS);
+        assert!(node.last_request_to().is_some());
+        assert_eq!(node.rtt(), Some(std::time::Duration::from_millis(5)));
+    }
+}
 
-        Ok(())
+/// Checked with loom, which runs each model under every interleaving of its
+/// atomic operations. Run with
+/// `RUSTFLAGS="--cfg loom" cargo test --release --lib loom_models`.
+#[cfg(all(test, loom))]
+mod loom_models {
+    use super::{
+        Node,
+        NodeState,
+        MAX_FAILED_REQUESTS,
+        RELIABILITY_SCALE,
+    };
+    use loom::{
+        sync::Arc,
+        thread,
+    };
+
+    #[test]
+    fn failures_counted() {
+        loom::model(|| {
+            let node = Arc::new(Node::new_with_id(10));
+            let other = {
+                let node = node.clone();
+                thread::spawn(move || node.mark_failed_request())
+            };
+
+            node.mark_failed_request();
+            other.join().unwrap();
+
+            assert_eq!(node.failed_requests(), 2);
+            assert_eq!(node.state(), NodeState::Bad);
+        });
+    }
+
+    #[test]
+    fn success_and_unreachable() {
+        loom::model(|| {
+            let node = Arc::new(Node::new_with_id(10));
+            let other = {
+                let node = node.clone();
+                thread::spawn(move || node.mark_unreachable())
+            };
+
+            node.mark_successful_request();
+            other.join().unwrap();
+
+            // Either transition wins as a whole, never a count in between
+            // or a state which doesn't match the count.
+            let failed = node.failed_requests();
+            assert!(failed == 0 || failed == MAX_FAILED_REQUESTS, "{}", failed);
+            assert_eq!(
+                node.state() == NodeState::Bad,
+                failed == MAX_FAILED_REQUESTS
+            );
+            assert!(node.last_request_to().is_some());
+        });
+    }
+
+    #[test]
+    fn reliability_updates_not_lost() {
+        loom::model(|| {
+            let node = Arc::new(Node::new_with_id(10));
+            let other = {
+                let node = node.clone();
+                thread::spawn(move || node.mark_failed_request())
+            };
+
+            node.mark_successful_request();
+            other.join().unwrap();
+
+            // 0.5 smoothed towards 1 then 0, or towards 0 then 1.
+            let reliability = (node.reliability() * RELIABILITY_SCALE).round() as u32;
+            assert!(
+                reliability == 492_188 || reliability == 507_813,
+                "{}",
+                reliability
+            );
+        });
     }
 }
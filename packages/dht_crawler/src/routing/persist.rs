@@ -0,0 +1,200 @@
+//! Routing table saved between runs so a restarted node doesn't have to
+//! bootstrap from scratch. Saved tables are JSON objects with a `version`
+//! field. Tables saved by older versions are migrated to the current schema
+//! one version at a time when loaded.
+
+use crate::{
+    errors::{
+        ErrorKind,
+        Result,
+    },
+    routing::node::Node,
+};
+use chrono::NaiveDateTime;
+use serde_derive::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::Value;
+use std::net::SocketAddrV4;
+
+/// Version of the schema tables are saved with.
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// Table saved before versions and node activity were saved. It has no
+/// `version` field.
+#[derive(Deserialize, Debug)]
+pub struct V0RoutingTable {
+    /// Hex encoded id of the table.
+    pub id: String,
+    pub nodes: Vec<V0Node>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct V0Node {
+    pub id: String,
+    pub address: SocketAddrV4,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct V1RoutingTable {
+    pub version: u64,
+
+    /// Hex encoded id of the table.
+    pub id: String,
+    pub nodes: Vec<V1Node>,
+}
+
+/// A node along with the activity its state is derived from.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct V1Node {
+    pub id: String,
+    pub address: SocketAddrV4,
+    pub last_request_to: Option<NaiveDateTime>,
+    pub last_request_from: Option<NaiveDateTime>,
+    pub failed_requests: u8,
+}
+
+impl<'a> From<&'a Node> for V1Node {
+    fn from(node: &'a Node) -> V1Node {
+        V1Node {
+            id: node.id.to_string(),
+            address: node.address,
+            last_request_to: node.last_request_to(),
+            last_request_from: node.last_request_from(),
+            failed_requests: node.failed_requests(),
+        }
+    }
+}
+
+/// Nothing is known about the activity of v0 nodes, so they are all
+/// questionable.
+pub fn migrate_v0_to_v1(data: V0RoutingTable) -> V1RoutingTable {
+    V1RoutingTable {
+        version: 1,
+        id: data.id,
+        nodes: data
+            .nodes
+            .into_iter()
+            .map(|node| V1Node {
+                id: node.id,
+                address: node.address,
+                last_request_to: None,
+                last_request_from: None,
+                failed_requests: 0,
+            })
+            .collect(),
+    }
+}
+
+/// Parses a saved table of any known version, migrated to the current one.
+pub fn parse(bytes: &[u8]) -> Result<V1RoutingTable> {
+    let value: Value = serde_json::from_slice(bytes).map_err(|_| ErrorKind::InvalidRoutingTable)?;
+    let version = match &value["version"] {
+        Value::Null => 0,
+        version => version.as_u64().ok_or(ErrorKind::InvalidRoutingTable)?,
+    };
+
+    let table = match version {
+        0 => migrate_v0_to_v1(from_value(value)?),
+        1 => from_value(value)?,
+        version => Err(ErrorKind::UnsupportedRoutingTableVersion { version })?,
+    };
+
+    Ok(table)
+}
+
+fn from_value<T: serde::de::DeserializeOwned>(value: Value) -> Result<T> {
+    Ok(serde_json::from_value(value).map_err(|_| ErrorKind::InvalidRoutingTable)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::ErrorKind,
+        routing::{
+            Node,
+            NodeState,
+            RoutingTable,
+        },
+    };
+    use failure::Error;
+    use krpc_encoding::NodeID;
+    use serde_json::json;
+    use std::net::SocketAddrV4;
+
+    #[test]
+    fn save_and_load() -> Result<(), Error> {
+        let mut table = RoutingTable::new(NodeID::random());
+        let good = Node::new(NodeID::random(), "10.0.0.1:6881".parse()?);
+        good.mark_successful_request();
+        let good_id = good.id.clone();
+        table.add_node(good);
+
+        let bad = Node::new(NodeID::random(), "10.0.1.1:6881".parse()?);
+        bad.mark_unreachable();
+        let bad_id = bad.id.clone();
+        table.add_node(bad);
+
+        let loaded = RoutingTable::load(&table.save())?;
+        assert_eq!(loaded.id, table.id);
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get_node(&good_id).unwrap().state(), NodeState::Good);
+        assert_eq!(loaded.get_node(&bad_id).unwrap().state(), NodeState::Bad);
+
+        Ok(())
+    }
+
+    #[test]
+    fn v0_nodes_questionable() -> Result<(), Error> {
+        let id = NodeID::random();
+        let nodes: Vec<(NodeID, SocketAddrV4)> = (0..3u8)
+            .map(|idx| {
+                (
+                    NodeID::random(),
+                    SocketAddrV4::new([10, 0, idx, 1].into(), 6881),
+                )
+            })
+            .collect();
+        let saved = json!({
+            "id": id.to_string(),
+            "nodes": nodes
+                .iter()
+                .map(|(id, address)| json!({ "id": id.to_string(), "address": address }))
+                .collect::<Vec<_>>(),
+        });
+
+        let table = RoutingTable::load(saved.to_string().as_bytes())?;
+        assert_eq!(table.id, id);
+        assert_eq!(table.len(), nodes.len());
+        for (id, address) in &nodes {
+            let node = table.get_node(id).unwrap();
+            assert_eq!(node.address, *address);
+            assert_eq!(node.state(), NodeState::Questionable);
+        }
+
+        // Saved again with the current version.
+        let saved: serde_json::Value = serde_json::from_slice(&table.save())?;
+        assert_eq!(saved["version"], 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_version_rejected() {
+        let saved = json!({ "version": 7, "id": NodeID::random().to_string(), "nodes": [] });
+
+        match RoutingTable::load(saved.to_string().as_bytes())
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::UnsupportedRoutingTableVersion { version: 7 } => {}
+            kind => panic!("unexpected error {}", kind),
+        };
+
+        match RoutingTable::load(b"{\"version\": 1}").unwrap_err().kind() {
+            ErrorKind::InvalidRoutingTable => {}
+            kind => panic!("unexpected error {}", kind),
+        };
+    }
+}
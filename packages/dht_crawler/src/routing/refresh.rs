@@ -0,0 +1,129 @@
+//! Refreshes buckets nobody changed for a while, as described in [BEP-0005].
+//! Nodes in those buckets may have left without us noticing, and buckets
+//! which are rarely looked up in don't learn about new nodes otherwise.
+//!
+//! [BEP-0005]: http://www.bittorrent.org/beps/bep_0005.html
+
+use super::{
+    Node,
+    RoutingTable,
+};
+use futures::future;
+use krpc_encoding::{
+    NodeID,
+    NodeInfo,
+};
+use std::{
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::{
+    prelude::FutureExt,
+    timer::Delay,
+};
+use tokio_krpc::{
+    QueryOptions,
+    Transport,
+};
+
+/// Buckets unchanged for this long are refreshed, as suggested by
+/// [BEP-0005](http://www.bittorrent.org/beps/bep_0005.html).
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Nodes asked for the refresh target of each stale bucket.
+const REFRESH_QUERIES: usize = 3;
+
+/// Time to wait for a refresh query to be answered.
+const REFRESH_TIMEOUT: Duration = Duration::from_secs(3);
+
+impl RoutingTable {
+    /// Every `interval`, asks the nodes closest to the [`refresh_target`] of
+    /// each bucket unchanged for `interval` for the nodes they know around
+    /// it, and adds the returned nodes to `table`. Runs until dropped.
+    ///
+    /// [`refresh_target`]: RoutingTable::refresh_target
+    pub async fn refresh_task(
+        table: Arc<Mutex<RoutingTable>>,
+        transport: Arc<dyn Transport>,
+        interval: Duration,
+    ) {
+        loop {
+            Delay::new(Instant::now() + interval).await;
+
+            let (id, queries) = match table.lock() {
+                Ok(table) => (table.id().clone(), table.refresh_queries(interval)),
+                Err(_) => return,
+            };
+
+            let results = future::join_all(queries.iter().map(|(target, node)| {
+                transport
+                    .find_node_with_options(
+                        id.clone(),
+                        node.address.into(),
+                        target.clone(),
+                        QueryOptions {
+                            timeout: REFRESH_TIMEOUT,
+                            expected_id: Some(node.node_id.clone()),
+                        },
+                    )
+                    .timeout(REFRESH_TIMEOUT)
+            }))
+            .await;
+
+            let mut table = match table.lock() {
+                Ok(table) => table,
+                Err(_) => return,
+            };
+
+            let mut found = Vec::new();
+            for ((_, queried), result) in queries.iter().zip(results) {
+                let table_node = table.get_node_mut(&queried.node_id);
+                match result {
+                    Ok(Ok(response)) => {
+                        if let Some(table_node) = table_node {
+                            table_node.mark_successful_request();
+                        }
+                        found.extend(
+                            response
+                                .nodes
+                                .into_iter()
+                                .map(|node| Node::new(node.node_id, node.address)),
+                        );
+                    }
+                    _ => {
+                        if let Some(table_node) = table_node {
+                            table_node.mark_failed_request();
+                        }
+                    }
+                };
+            }
+
+            trace_event!(
+                queries = queries.len() as u64,
+                found = found.len() as u64,
+                "refresh buckets"
+            );
+            table.add_nodes(found);
+        }
+    }
+
+    /// Target and node to query for each bucket unchanged for `threshold`.
+    fn refresh_queries(&self, threshold: Duration) -> Vec<(NodeID, NodeInfo)> {
+        self.stale_buckets(threshold)
+            .into_iter()
+            .flat_map(|bucket_idx| {
+                let target = self.refresh_target(bucket_idx);
+
+                self.find_closest_k(&target, REFRESH_QUERIES)
+                    .into_iter()
+                    .map(move |node| (target.clone(), node))
+            })
+            .collect()
+    }
+}
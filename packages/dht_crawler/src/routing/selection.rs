@@ -0,0 +1,69 @@
+//! Order in which nodes are tried, weighing their distance to a target
+//! against how quickly and how often they answered before. Shared by
+//! [`RoutingTable::iter_closest_weighted`] and lookups.
+//!
+//! [`RoutingTable::iter_closest_weighted`]: super::RoutingTable::iter_closest_weighted
+
+use num_bigint::BigUint;
+use std::{
+    cmp::Ordering,
+    time::Duration,
+};
+
+/// Round trip time assumed for nodes which never answered us.
+const UNKNOWN_RTT: Duration = Duration::from_secs(1);
+
+/// How much a node's round trip time and past failures count against it
+/// when choosing which node to query next.
+///
+/// Nodes are only reordered within a distance tier, the nodes whose distance
+/// to the target has the same highest bit. A node in a closer tier is always
+/// queried first, so every round still gets closer to the target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SelectionWeights {
+    /// Penalty per second of smoothed round trip time.
+    pub rtt: f64,
+
+    /// Penalty for a node which never answers, relative to one which always
+    /// does.
+    pub reliability: f64,
+}
+
+impl Default for SelectionWeights {
+    fn default() -> SelectionWeights {
+        SelectionWeights {
+            rtt: 1.0,
+            reliability: 1.0,
+        }
+    }
+}
+
+impl SelectionWeights {
+    /// Penalty of a node with `reliability` between 0 and 1 and `rtt`, lower
+    /// is better. A node which never answered counts as taking
+    /// [`UNKNOWN_RTT`].
+    pub fn penalty(&self, reliability: f64, rtt: Option<Duration>) -> f64 {
+        let rtt = rtt.unwrap_or(UNKNOWN_RTT);
+        let seconds = rtt.as_secs() as f64 + f64::from(rtt.subsec_nanos()) / 1e9;
+
+        self.rtt * seconds + self.reliability * (1.0 - reliability)
+    }
+}
+
+/// Orders two nodes by their distance to the target and
+/// [`SelectionWeights::penalty`], the one to query first being less. The
+/// distance tier comes first, then the penalty, then the exact distance.
+pub(crate) fn selection_order(
+    (distance, penalty): (&BigUint, f64),
+    (other_distance, other_penalty): (&BigUint, f64),
+) -> Ordering {
+    distance
+        .bits()
+        .cmp(&other_distance.bits())
+        .then_with(|| {
+            penalty
+                .partial_cmp(&other_penalty)
+                .unwrap_or(Ordering::Equal)
+        })
+        .then_with(|| distance.cmp(other_distance))
+}
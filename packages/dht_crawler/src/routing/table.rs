@@ -1,6 +1,34 @@
-use crate::routing::{
-    bucket::Bucket,
-    node::Node,
+use crate::{
+    errors::{
+        ErrorKind,
+        Result,
+    },
+    routing::{
+        bucket::{
+            AddOutcome,
+            Bucket,
+            MAX_BUCKET_SIZE,
+        },
+        dump::TableDump,
+        node::{
+            Node,
+            NodeState,
+        },
+        persist::{
+            self,
+            V1Node,
+            V1RoutingTable,
+            SCHEMA_VERSION,
+        },
+        selection::{
+            selection_order,
+            SelectionWeights,
+        },
+    },
+};
+use chrono::{
+    self,
+    Utc,
 };
 use crypto::{
     digest::Digest,
@@ -8,20 +36,131 @@ use crypto::{
 };
 use krpc_encoding::{
     self as proto,
+    Addr,
     NodeID,
     NodeInfo,
 };
+use num_bigint::BigUint;
 use rand;
+#[cfg(feature = "debug")]
+use serde_json::json;
 use std::{
     cmp,
+    collections::{
+        BinaryHeap,
+        HashMap,
+        HashSet,
+    },
+    fmt,
     net::SocketAddrV4,
+    ops::Deref,
+    sync::Arc,
+    time::Duration,
 };
 
+/// Number of buckets closest to the table's own id which must be full for
+/// the table to be bootstrapped.
+const BOOTSTRAPPED_BUCKETS: usize = MAX_BUCKET_SIZE;
+
+/// Called by a [`RoutingTable`] once it is bootstrapped.
+pub type BootstrapCallback = Arc<dyn Fn() + Send + Sync>;
+
 pub enum FindNodeResult {
     Node(NodeInfo),
     Nodes(Vec<NodeInfo>),
 }
 
+/// What [`RoutingTable::add_node`] did with a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddNodeOutcome {
+    /// The node is in the table, possibly in place of a bad node.
+    Added,
+
+    /// The node's bucket is full so the node was queued in its replacements.
+    /// Its questionable nodes should be pinged, and the ones which don't
+    /// answer passed to [`RoutingTable::fail_verification`].
+    Queued,
+
+    /// The node was already in the table, has the table's own id or is from
+    /// a full /24 subnet.
+    Rejected,
+}
+
+/// What [`RoutingTable::add_nodes`] did with a batch of nodes.
+#[derive(Debug, Default, Clone)]
+pub struct AddNodesSummary {
+    /// Nodes added to the table.
+    pub added: usize,
+
+    /// Nodes ignored because they have the table's own id, were already in
+    /// the table or the batch, or are from a full /24 subnet.
+    pub rejected: usize,
+
+    /// Nodes kept as replacements for full buckets.
+    pub pending: usize,
+
+    /// Questionable nodes in the buckets with new replacements. They should
+    /// be pinged so the ones which don't answer make way for a replacement.
+    pub to_ping: Vec<NodeInfo>,
+}
+
+/// Limits on the nodes a [`RoutingTable`] accepts.
+#[derive(Clone)]
+pub struct RoutingTableConfig {
+    /// Most nodes kept from a single /24 subnet, so many nodes run by an
+    /// attacker from one network can't fill the table.
+    pub max_per_subnet_24: usize,
+
+    /// Number of nodes kept in each bucket.
+    pub bucket_size: BucketSizePolicy,
+
+    /// Called the first time the table is
+    /// [bootstrapped](RoutingTable::is_bootstrapped) after adding nodes, to
+    /// stop bootstrapping without polling the table.
+    pub on_full: Option<BootstrapCallback>,
+}
+
+impl Default for RoutingTableConfig {
+    fn default() -> RoutingTableConfig {
+        RoutingTableConfig {
+            max_per_subnet_24: 1,
+            bucket_size: BucketSizePolicy::default(),
+            on_full: None,
+        }
+    }
+}
+
+impl fmt::Debug for RoutingTableConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RoutingTableConfig")
+            .field("max_per_subnet_24", &self.max_per_subnet_24)
+            .field("bucket_size", &self.bucket_size)
+            .field("on_full", &self.on_full.is_some())
+            .finish()
+    }
+}
+
+/// Number of nodes, k, kept in the buckets of a [`RoutingTable`]. A larger
+/// k for the bucket holding the table's own id makes lookups of ids close to
+/// it more accurate.
+#[derive(Debug, Clone)]
+pub struct BucketSizePolicy {
+    /// k of every bucket except the one holding the table's own id.
+    pub default_k: usize,
+
+    /// k of the bucket holding the table's own id.
+    pub local_k: usize,
+}
+
+impl Default for BucketSizePolicy {
+    fn default() -> BucketSizePolicy {
+        BucketSizePolicy {
+            default_k: MAX_BUCKET_SIZE,
+            local_k: MAX_BUCKET_SIZE,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct RoutingTable {
     /// Node identifier of the node which the table is based around. There will
@@ -37,42 +176,374 @@ pub struct RoutingTable {
 
     /// Last secret. Tokens generated with this secret are also valid.
     last_token_secret: [u8; 4],
+
+    config: RoutingTableConfig,
+
+    /// Whether [`RoutingTableConfig::on_full`] was called.
+    notified_full: bool,
 }
 
 impl RoutingTable {
     pub fn new(id: NodeID) -> RoutingTable {
+        RoutingTable::with_config(id, RoutingTableConfig::default())
+    }
+
+    /// Creates a table with buckets sized by `policy`.
+    pub fn new_with_policy(id: NodeID, policy: BucketSizePolicy) -> RoutingTable {
+        RoutingTable::with_config(
+            id,
+            RoutingTableConfig {
+                bucket_size: policy,
+                ..RoutingTableConfig::default()
+            },
+        )
+    }
+
+    pub fn with_config(id: NodeID, config: RoutingTableConfig) -> RoutingTable {
+        let mut initial_bucket = Bucket::initial_bucket();
+        initial_bucket.capacity = config.bucket_size.local_k;
+
         let mut buckets = Vec::new();
-        buckets.push(Bucket::initial_bucket());
+        buckets.push(initial_bucket);
 
         RoutingTable {
             id,
             buckets,
             token_secret: rand::random(),
             last_token_secret: rand::random(),
+            config,
+            notified_full: false,
+        }
+    }
+
+    /// Id of the node owning the table.
+    pub fn id(&self) -> &NodeID {
+        &self.id
+    }
+
+    /// Forgets every node and starts over around `new_id`, like after our
+    /// external address changed and [BEP-0042] requires a new id. The table
+    /// is reset in place so it can stay shared. The token secrets and the
+    /// config are kept.
+    ///
+    /// [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+    pub fn clear_and_reset(&mut self, new_id: NodeID) {
+        let mut initial_bucket = Bucket::initial_bucket();
+        initial_bucket.capacity = self.config.bucket_size.local_k;
+
+        self.id = new_id;
+        self.buckets = vec![initial_bucket];
+        self.notified_full = false;
+    }
+
+    /// Builds a table from the contents of a µTorrent `dht.dat` file, keeping
+    /// the id saved in it or picking a random one if there is none. Nothing
+    /// is known about the saved nodes yet so they are questionable, but they
+    /// split the bucket holding our own id like good nodes so fewer are lost
+    /// to replacements.
+    pub fn from_utorrent_dat(bytes: &[u8]) -> Result<RoutingTable> {
+        let dat = proto::DhtDat::decode(bytes)?;
+        let mut table = RoutingTable::new(dat.id.unwrap_or_else(NodeID::random));
+        for node in dat.nodes {
+            table.add_saved_node(Node::new(node.node_id, node.address));
+        }
+        table.check_invariants();
+
+        Ok(table)
+    }
+
+    /// Adds a node loaded from a file. The bucket holding our own id is split
+    /// when it holds as many nodes as it may, whatever their state.
+    fn add_saved_node(&mut self, node: Node) {
+        if node.id == self.id || !self.accepts_address(&node.id, &node.address) {
+            return;
+        }
+
+        let bucket_idx = self.get_bucket_idx(&node.id);
+        let bucket_idx = self.split_if(bucket_idx, &node.id, |bucket| {
+            bucket.nodes.len() >= bucket.capacity
+        });
+        self.buckets[bucket_idx].add_node(node);
+    }
+
+    /// Serializes the table's id and nodes, along with what is known about
+    /// them, to be loaded with [`load`] after a restart.
+    pub fn save(&self) -> Vec<u8> {
+        let saved = V1RoutingTable {
+            version: SCHEMA_VERSION,
+            id: self.id.to_string(),
+            nodes: self.nodes().map(V1Node::from).collect(),
+        };
+
+        serde_json::to_vec(&saved).expect("failed to serialize routing table")
+    }
+
+    /// Loads a table saved with [`save`] by this or an older version. The
+    /// nodes are added like with [`add_nodes`].
+    pub fn load(bytes: &[u8]) -> Result<RoutingTable> {
+        let saved = persist::parse(bytes)?;
+        let parse_id =
+            |id: &str| NodeID::from_hex_str(id).map_err(|_| ErrorKind::InvalidRoutingTable);
+
+        let mut nodes = Vec::with_capacity(saved.nodes.len());
+        for node in saved.nodes {
+            nodes.push(Node::with_history(
+                parse_id(&node.id)?,
+                node.address,
+                node.last_request_to,
+                node.last_request_from,
+                node.failed_requests,
+            ));
         }
+
+        let mut table = RoutingTable::new(parse_id(&saved.id)?);
+        table.add_nodes(nodes);
+
+        Ok(table)
     }
 
-    /// Adds a node to the routing table.
-    pub fn add_node(&mut self, node: Node) {
+    /// Adds a node to the routing table. Nodes with the table's own id and
+    /// nodes from a /24 subnet which already has
+    /// [`max_per_subnet_24`](RoutingTableConfig::max_per_subnet_24) nodes in
+    /// the table are ignored.
+    ///
+    /// A full bucket holding our own id is split in half. Other full buckets,
+    /// and a bucket which can't be split any further, aren't split, and the
+    /// node waits in the bucket's replacements for a questionable or bad
+    /// node to leave. A node already in the table is moved to the tail of its
+    /// bucket instead, see [`Bucket::touch`].
+    pub fn add_node(&mut self, node: Node) -> AddNodeOutcome {
+        if node.id == self.id || !self.accepts_address(&node.id, &node.address) {
+            return AddNodeOutcome::Rejected;
+        }
+
         let bucket_idx = self.get_bucket_idx(&node.id);
+        // A node seen again moves to the tail of its bucket.
+        if self.buckets[bucket_idx].touch(&node.id) {
+            return AddNodeOutcome::Rejected;
+        }
+
+        let bucket_idx = self.split_for(bucket_idx, &node.id);
+
+        #[cfg(feature = "trace")]
+        let (id, address) = (node.id.clone(), node.address);
+
+        let outcome = match self.buckets[bucket_idx].add_node(node) {
+            AddOutcome::Added | AddOutcome::Replaced(_) => AddNodeOutcome::Added,
+            AddOutcome::Pending => AddNodeOutcome::Queued,
+            AddOutcome::Present => AddNodeOutcome::Rejected,
+        };
+        self.check_invariants();
+        self.notify_if_bootstrapped();
+
+        trace_event!(
+            id = %id,
+            address = %address,
+            outcome = ?outcome,
+            "add node"
+        );
+
+        outcome
+    }
+
+    /// Adds every node in `nodes` like [`add_node`], but faster for large
+    /// batches such as the nodes from many `find_node` responses. The nodes
+    /// are sorted by id so the buckets are walked once in order, and nodes
+    /// per /24 subnet are counted once for the whole batch.
+    pub fn add_nodes(&mut self, nodes: impl IntoIterator<Item = Node>) -> AddNodesSummary {
+        let mut summary = AddNodesSummary::default();
+
+        let mut nodes: Vec<Node> = nodes.into_iter().collect();
+        let received = nodes.len();
+        nodes.sort_by(|a, b| (*a.id).cmp(&*b.id));
+        nodes.dedup_by(|a, b| a.id == b.id);
+        summary.rejected += received - nodes.len();
+
+        let mut per_subnet: HashMap<[u8; 3], usize> = HashMap::new();
+        for node in self.nodes() {
+            *per_subnet.entry(subnet24(&node.address)).or_default() += 1;
+        }
+
+        // Start of every bucket which got a replacement.
+        let mut pending_buckets: Vec<NodeID> = Vec::new();
+        let mut bucket_idx = 0;
+
+        for node in nodes {
+            // Ids are ascending so their buckets are too.
+            while !self.buckets[bucket_idx].could_hold_node(&node.id) {
+                bucket_idx += 1;
+            }
+
+            let subnet = subnet24(&node.address);
+            let subnet_full =
+                per_subnet.get(&subnet).cloned().unwrap_or(0) >= self.config.max_per_subnet_24;
 
-        let bucket_to_add_to_idx = if self.buckets[bucket_idx].is_full() {
-            if !self.buckets[bucket_idx].could_hold_node(&node.id) {
-                return;
+            // A node seen again moves to the tail of its bucket, like in
+            // `add_node`.
+            if node.id == self.id || self.buckets[bucket_idx].touch(&node.id) || subnet_full {
+                summary.rejected += 1;
+                continue;
             }
 
-            let (prev_bucket_idx, next_bucket_idx) = self.split_bucket(bucket_idx);
+            // The node's bucket after splitting holds every id after it too.
+            bucket_idx = self.split_for(bucket_idx, &node.id);
+
+            #[cfg(feature = "trace")]
+            let (id, address) = (node.id.clone(), node.address);
+
+            let bucket = &mut self.buckets[bucket_idx];
+            let added = match bucket.add_node(node) {
+                AddOutcome::Added => true,
+                AddOutcome::Replaced(bad) => {
+                    if let Some(count) = per_subnet.get_mut(&subnet24(&bad.address)) {
+                        *count -= 1;
+                    }
+                    true
+                }
+                AddOutcome::Pending => {
+                    summary.pending += 1;
+                    if pending_buckets.last() != Some(&bucket.start) {
+                        pending_buckets.push(bucket.start.clone());
+                    }
+                    false
+                }
+                AddOutcome::Present => {
+                    summary.rejected += 1;
+                    false
+                }
+            };
+
+            if added {
+                summary.added += 1;
+                *per_subnet.entry(subnet).or_default() += 1;
+            }
+
+            trace_event!(id = %id, address = %address, added = added, "add node");
+        }
+
+        self.check_invariants();
+        self.notify_if_bootstrapped();
+
+        for start in pending_buckets {
+            let bucket = &self.buckets[self.get_bucket_idx(&start)];
+            summary.to_ping.extend(
+                bucket
+                    .iter()
+                    .filter(|node| node.state() == NodeState::Questionable)
+                    .map(Into::<NodeInfo>::into),
+            );
+        }
+
+        summary
+    }
+
+    /// Splits the bucket at `bucket_idx`, which could hold `id`, if it's
+    /// full. Returns the index of the bucket which could hold `id` afterwards.
+    fn split_for(&mut self, bucket_idx: usize, id: &NodeID) -> usize {
+        self.split_if(bucket_idx, id, Bucket::is_full)
+    }
+
+    /// Splits the bucket at `bucket_idx`, which could hold `id`, once if
+    /// `full` holds for it and it holds our own id. Buckets elsewhere are
+    /// never split, so the table only grows deeper around our own id, by at
+    /// most [`MAX_DEPTH`] buckets. Returns the index of the bucket which
+    /// could hold `id` afterwards.
+    ///
+    /// [`MAX_DEPTH`]: crate::routing::bucket::MAX_DEPTH
+    fn split_if(
+        &mut self,
+        bucket_idx: usize,
+        id: &NodeID,
+        full: impl Fn(&Bucket) -> bool,
+    ) -> usize {
+        let bucket = &self.buckets[bucket_idx];
+        if !full(bucket) || !bucket.could_hold_node(&self.id) {
+            return bucket_idx;
+        }
 
-            if self.buckets[prev_bucket_idx].could_hold_node(&node.id) {
+        match self.split_bucket(bucket_idx) {
+            Some((prev_bucket_idx, _)) if self.buckets[prev_bucket_idx].could_hold_node(id) => {
                 prev_bucket_idx
-            } else {
-                next_bucket_idx
             }
-        } else {
-            bucket_idx
+            Some((_, next_bucket_idx)) => next_bucket_idx,
+            None => bucket_idx,
+        }
+    }
+
+    /// Removes the node with `id` from the table, returning it. The oldest
+    /// replacement waiting for a place in the node's bucket takes its place.
+    pub fn remove_node(&mut self, id: &NodeID) -> Option<Node> {
+        let bucket_idx = self.get_bucket_idx(id);
+        let removed = self.buckets[bucket_idx].remove(id);
+        self.check_invariants();
+
+        trace_event!(id = %id, removed = removed.is_some(), "remove node");
+
+        removed
+    }
+
+    /// Records that the node with `id` didn't answer a ping checking whether
+    /// it's still there. When its bucket has replacements waiting, the oldest
+    /// takes its place and the node is returned.
+    pub fn fail_verification(&mut self, id: &NodeID) -> Option<Node> {
+        let bucket_idx = self.get_bucket_idx(id);
+        let replaced = self.buckets[bucket_idx].fail_verification(id);
+        self.check_invariants();
+
+        trace_event!(id = %id, replaced = replaced.is_some(), "fail verification");
+
+        replaced
+    }
+
+    /// Removes every node not seen within `max_age`, or never seen at all,
+    /// and returns how many were removed. Replacements are dropped too since
+    /// they are at least as stale, so the table refills from fresh nodes.
+    /// Meant for after the process was suspended for a long time.
+    pub fn prune_by_age(&mut self, max_age: Duration) -> usize {
+        let max_age = match chrono::Duration::from_std(max_age) {
+            Ok(max_age) => max_age,
+            Err(_) => chrono::Duration::max_value(),
+        };
+        let now = Utc::now().naive_utc();
+        let is_fresh = |node: &Node| match node.last_seen() {
+            Some(last_seen) => now.signed_duration_since(last_seen) <= max_age,
+            None => false,
         };
 
-        &mut self.buckets[bucket_to_add_to_idx].add_node(node);
+        let mut removed = 0;
+        for bucket in &mut self.buckets {
+            let before = bucket.nodes.len();
+            bucket.nodes.retain(&is_fresh);
+            removed += before - bucket.nodes.len();
+            bucket.replacements.clear();
+        }
+        self.check_invariants();
+
+        trace_event!(removed = removed, "prune by age");
+
+        removed
+    }
+
+    /// Adds every node from `other` with [`add_node`]. Nodes already in this
+    /// table keep their state. Returns the number of nodes which weren't in
+    /// this table before.
+    pub fn merge(&mut self, other: RoutingTable) -> usize {
+        let mut added = 0;
+
+        for node in other.buckets.into_iter().flat_map(|bucket| bucket.nodes) {
+            if self.get_node(&node.id).is_some() {
+                continue;
+            }
+
+            let id = node.id.clone();
+            self.add_node(node);
+
+            if self.get_node(&id).is_some() {
+                added += 1;
+            }
+        }
+
+        added
     }
 
     /// Finds the node with `id`, or about the `k` nearest good nodes to the
@@ -83,17 +554,113 @@ impl RoutingTable {
         let bucket = &self.buckets[bucket_idx];
 
         match bucket.get(id) {
-            None => FindNodeResult::Nodes(bucket.good_nodes().map(|node| node.into()).collect()),
+            None => FindNodeResult::Nodes(self.find_nodes(id)),
             Some(node) => FindNodeResult::Node((node as &Node).into()),
         }
     }
 
-    /// Finds nodes in the same bucket as `id` in the routing table.
+    /// Finds the `k` nodes closest to `id` as returned in `find_node` and
+    /// `get_peers` responses. Good nodes come first, closest first. Only when
+    /// there are fewer than `k` good nodes is the rest filled with the
+    /// closest questionable nodes, which are likely still around. Bad nodes
+    /// are never handed out.
     pub fn find_nodes(&self, id: &NodeID) -> Vec<NodeInfo> {
-        let bucket_idx = self.get_bucket_idx(id);
-        let bucket = &self.buckets[bucket_idx];
+        let mut nodes = self.find_closest_k(id, MAX_BUCKET_SIZE);
+        if nodes.len() < MAX_BUCKET_SIZE {
+            let questionable = self.buckets.iter().flat_map(Bucket::questionable_nodes);
+            nodes.extend(closest_k(questionable, id, MAX_BUCKET_SIZE - nodes.len()));
+        }
+
+        nodes
+    }
+
+    /// Finds the `k` good nodes closest to `id` across all buckets, closest
+    /// first. Fewer are returned only if the table has fewer good nodes.
+    pub fn find_closest_k(&self, id: &NodeID, k: usize) -> Vec<NodeInfo> {
+        closest_k(self.buckets.iter().flat_map(Bucket::good_nodes), id, k)
+    }
+
+    /// Iterates over good nodes roughly in order of distance to `target`,
+    /// closest first. Buckets are visited in order of distance to `target`
+    /// but the nodes within a bucket aren't ordered. See
+    /// [`iter_closest_exact`] for an exact ordering.
+    pub fn iter_closest(&self, target: &NodeID) -> impl Iterator<Item = &Node> {
+        self.buckets_by_distance(target)
+            .into_iter()
+            .flat_map(move |bucket_idx| self.buckets[bucket_idx].good_nodes())
+    }
+
+    /// Like [`iter_closest`] but nodes are yielded exactly in order of
+    /// distance to `target`. Only the nodes within a single bucket are sorted
+    /// at a time.
+    pub fn iter_closest_exact(&self, target: &NodeID) -> impl Iterator<Item = &Node> {
+        let target = target.clone();
+
+        self.buckets_by_distance(&target)
+            .into_iter()
+            .flat_map(move |bucket_idx| {
+                let mut nodes: Vec<&Node> = self.buckets[bucket_idx].good_nodes().collect();
+                nodes.sort_by_key(|node| node.id.distance(&target));
+
+                nodes
+            })
+    }
+
+    /// Iterates over good nodes ordered like a lookup chooses which to query
+    /// first. Nodes whose distance to `target` has the same highest bit are
+    /// ordered by their penalty for a slow [`smoothed_rtt`], weighted by
+    /// `rtt_weight` per second, and for a low [`reliability`], weighted by
+    /// `reliability_weight`. A node whose distance has a lower highest bit
+    /// always comes first. See [`SelectionWeights`].
+    ///
+    /// [`smoothed_rtt`]: Node::smoothed_rtt
+    /// [`reliability`]: Node::reliability
+    pub fn iter_closest_weighted(
+        &self,
+        target: &NodeID,
+        rtt_weight: f64,
+        reliability_weight: f64,
+    ) -> impl Iterator<Item = &Node> {
+        let weights = SelectionWeights {
+            rtt: rtt_weight,
+            reliability: reliability_weight,
+        };
+
+        let mut nodes: Vec<(BigUint, f64, &Node)> = self
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.good_nodes())
+            .map(|node| {
+                let penalty = weights.penalty(node.reliability(), node.smoothed_rtt());
+                (node.id.distance(target), penalty, node)
+            })
+            .collect();
+        nodes.sort_by(|(a, a_penalty, _), (b, b_penalty, _)| {
+            selection_order((a, *a_penalty), (b, *b_penalty))
+        });
 
-        bucket.good_nodes().map(|node| node.into()).collect()
+        nodes.into_iter().map(|(_, _, node)| node)
+    }
+
+    /// Indices of buckets ordered by distance to `target`. Buckets span
+    /// aligned power of two ranges so every node in a bucket is closer to
+    /// `target` than every node in the buckets after it.
+    fn buckets_by_distance(&self, target: &NodeID) -> Vec<usize> {
+        let mut distances: Vec<(BigUint, usize)> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(idx, bucket)| {
+                let size = bucket.end.deref() - bucket.start.deref();
+                let distance = bucket.start.distance(target);
+                let closest = &distance - (&distance % &size);
+
+                (closest, idx)
+            })
+            .collect();
+
+        distances.sort();
+        distances.into_iter().map(|(_, idx)| idx).collect()
     }
 
     /// Gets the node with `id` from the table.
@@ -104,6 +671,62 @@ impl RoutingTable {
         bucket.get(id)
     }
 
+    /// Gets a mutable reference to the node with `id` from the table.
+    pub fn get_node_mut(&mut self, id: &NodeID) -> Option<&mut Node> {
+        let bucket_idx = self.get_bucket_idx(id);
+        let bucket = &mut self.buckets[bucket_idx];
+
+        bucket.get_mut(id)
+    }
+
+    /// Moves the node with `id` to `new_addr`, like after its NAT mapping
+    /// changed. The node keeps its state and its place in its bucket.
+    /// Returns whether the node was found.
+    pub fn update_node_addr(&mut self, id: &NodeID, new_addr: Addr) -> bool {
+        match self.get_node_mut(id) {
+            Some(node) => {
+                node.address = new_addr.into();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Picks a random id in the bucket at `bucket_idx`. Looking up this id
+    /// refreshes the bucket.
+    pub fn refresh_target(&self, bucket_idx: usize) -> NodeID {
+        let bucket = &self.buckets[bucket_idx];
+
+        NodeID::random_in_range(&bucket.start, &bucket.end)
+    }
+
+    /// The questionable node seen least recently in the bucket which could
+    /// hold `id`. See [`RoutingTable::try_evict_questionable`].
+    pub fn oldest_questionable(&self, id: &NodeID) -> Option<NodeInfo> {
+        self.buckets[self.get_bucket_idx(id)]
+            .oldest_questionable()
+            .map(|node| node.into())
+    }
+
+    /// Indices of the buckets which didn't change within `threshold`. These
+    /// should be refreshed by looking up their [`refresh_target`].
+    ///
+    /// [`refresh_target`]: RoutingTable::refresh_target
+    pub fn stale_buckets(&self, threshold: Duration) -> Vec<usize> {
+        let threshold = match chrono::Duration::from_std(threshold) {
+            Ok(threshold) => threshold,
+            Err(_) => chrono::Duration::max_value(),
+        };
+        let now = Utc::now().naive_utc();
+
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| now.signed_duration_since(bucket.last_changed) > threshold)
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
     /// Gets the index of the bucket which can hold `id`.
     fn get_bucket_idx(&self, id: &NodeID) -> usize {
         self.buckets
@@ -117,17 +740,73 @@ impl RoutingTable {
             .expect("No bucket was found for NodeID.")
     }
 
-    /// Splits the bucket at `idx` into two buckets.
-    fn split_bucket(&mut self, idx: usize) -> (usize, usize) {
+    /// Splits the bucket at `idx` into two buckets. Returns `None` when the
+    /// bucket is too deep to split.
+    fn split_bucket(&mut self, idx: usize) -> Option<(usize, usize)> {
         let next_bucket = {
             let bucket = &mut self.buckets[idx];
-            bucket.split()
+            bucket.split()?
         };
 
         let next_bucket_idx = idx + 1;
         self.buckets.insert(next_bucket_idx, next_bucket);
+        self.check_invariants();
+
+        // Only one of the halves still holds our own id. The other might hold
+        // more nodes than it may keep.
+        for bucket_idx in &[idx, next_bucket_idx] {
+            let bucket = &mut self.buckets[*bucket_idx];
+            let capacity = if bucket.could_hold_node(&self.id) {
+                self.config.bucket_size.local_k
+            } else {
+                self.config.bucket_size.default_k
+            };
+            bucket.set_capacity(capacity);
+        }
+
+        trace_event!(
+            start = %self.buckets[idx].start,
+            depth = self.buckets[idx].depth() as u64,
+            "bucket split"
+        );
+
+        Some((idx, next_bucket_idx))
+    }
+
+    /// Checks that the buckets are contiguous, don't overlap and cover the
+    /// whole key space and that every node is in the bucket covering its id.
+    /// Only checked in debug builds.
+    fn check_invariants(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+
+        let initial = Bucket::initial_bucket();
+        let first = self.buckets.first().expect("table has no buckets");
+        let last = self.buckets.last().expect("table has no buckets");
+        assert_eq!(first.start, initial.start, "buckets don't start at zero");
+        assert_eq!(last.end, initial.end, "buckets don't end at 2^160");
+
+        for pair in self.buckets.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start, "buckets aren't contiguous");
+        }
 
-        (idx, next_bucket_idx)
+        let mut ids = HashSet::new();
+        for bucket in &self.buckets {
+            assert!(*bucket.start < *bucket.end, "bucket covers no ids");
+            assert!(
+                bucket
+                    .nodes
+                    .iter()
+                    .chain(bucket.replacements.iter().map(|pending| &pending.node))
+                    .all(|node| bucket.could_hold_node(&node.id)),
+                "node outside of its bucket"
+            );
+            assert!(
+                bucket.nodes.iter().all(|node| ids.insert(&node.id)),
+                "node in the table twice"
+            );
+        }
     }
 
     pub fn verify_token(&self, token: &[u8], addr: &SocketAddrV4) -> bool {
@@ -135,8 +814,11 @@ impl RoutingTable {
             || verify_token(addr, &self.last_token_secret, token)
     }
 
+    /// Generates a token for `addr`. Every token has its own random nonce,
+    /// so a token can be accepted only once without turning away a node
+    /// which asks for another one to announce again.
     pub fn generate_token(&self, addr: &SocketAddrV4) -> [u8; 20] {
-        generate_token(addr, &self.token_secret)
+        generate_token(addr, &self.token_secret, rand::random())
     }
 
     /// Updates `last_token` and `token` moving `token` to `last_token` and
@@ -148,38 +830,1449 @@ impl RoutingTable {
     }
 
     pub fn get_or_add(&mut self, id: NodeID, address: SocketAddrV4) -> Option<&mut Node> {
+        if id == self.id || !self.accepts_address(&id, &address) {
+            return None;
+        }
+
         let bucket_idx = self.get_bucket_idx(&id);
         let bucket = &mut self.buckets[bucket_idx];
 
         if bucket.get(&id).is_none() {
             bucket.add_node(Node::new(id.clone(), address));
+            self.check_invariants();
+        }
+
+        self.buckets[bucket_idx].get_mut(&id)
+    }
+
+    /// Whether a node with `id` at `address` can be added without going over
+    /// the limit of nodes per /24 subnet. Nodes already in the table are
+    /// always accepted.
+    fn accepts_address(&self, id: &NodeID, address: &SocketAddrV4) -> bool {
+        if self.get_node(id).is_some() {
+            return true;
         }
 
-        bucket.get_mut(&id)
+        let subnet = subnet24(address);
+        let in_subnet = self
+            .nodes()
+            .filter(|node| subnet24(&node.address) == subnet)
+            .count();
+
+        in_subnet < self.config.max_per_subnet_24
+    }
+
+    /// Iterates over every node in the table regardless of its state.
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flat_map(|bucket| bucket.nodes.iter())
     }
 
     pub fn len(&self) -> usize {
         self.buckets.iter().map(|bucket| bucket.nodes.len()).sum()
     }
+
+    /// Number of buckets in the table, one more for each split.
+    pub fn depth(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn good_node_count(&self) -> usize {
+        self.count_in_state(NodeState::Good)
+    }
+
+    pub fn questionable_node_count(&self) -> usize {
+        self.count_in_state(NodeState::Questionable)
+    }
+
+    pub fn bad_node_count(&self) -> usize {
+        self.count_in_state(NodeState::Bad)
+    }
+
+    /// Whether the table knows at least K good nodes. When it doesn't, the
+    /// table should be bootstrapped again.
+    pub fn is_healthy(&self) -> bool {
+        self.good_node_count() >= MAX_BUCKET_SIZE
+    }
+
+    /// Whether the [`BOOTSTRAPPED_BUCKETS`] buckets closest to the table's
+    /// own id are full of good nodes, so lookups of ids near it find the
+    /// closest nodes there are.
+    pub fn is_bootstrapped(&self) -> bool {
+        if self.buckets.len() < BOOTSTRAPPED_BUCKETS {
+            return false;
+        }
+
+        // Buckets can be split anywhere, so a bucket's distance is the
+        // distance of its closest id, found by clearing the bits which vary
+        // inside it.
+        let mut buckets: Vec<&Bucket> = self.buckets.iter().collect();
+        buckets.sort_by_cached_key(|bucket| {
+            let width = bucket.end.deref() - bucket.start.deref();
+            (bucket.start.distance(&self.id) / &width) * width
+        });
+
+        buckets
+            .into_iter()
+            .take(BOOTSTRAPPED_BUCKETS)
+            .all(Bucket::is_full)
+    }
+
+    /// Calls [`RoutingTableConfig::on_full`] the first time the table is
+    /// bootstrapped.
+    fn notify_if_bootstrapped(&mut self) {
+        if self.notified_full || self.config.on_full.is_none() || !self.is_bootstrapped() {
+            return;
+        }
+
+        self.notified_full = true;
+        if let Some(on_full) = &self.config.on_full {
+            on_full();
+        }
+    }
+
+    fn count_in_state(&self, state: NodeState) -> usize {
+        self.nodes().filter(|node| node.state() == state).count()
+    }
+
+    /// Groups nodes by the /24 subnet of their address, keyed by its first
+    /// three octets.
+    pub fn nodes_by_subnet24(&self) -> HashMap<[u8; 3], Vec<&Node>> {
+        let mut subnets: HashMap<[u8; 3], Vec<&Node>> = HashMap::new();
+
+        for node in self.nodes() {
+            subnets
+                .entry(subnet24(&node.address))
+                .or_default()
+                .push(node);
+        }
+
+        subnets
+    }
+
+    /// The /24 subnet with the most nodes and their count. A single subnet
+    /// holding a large share of the table hints at an eclipse attack. Ties go
+    /// to the lowest subnet.
+    pub fn most_represented_subnet(&self) -> Option<([u8; 3], usize)> {
+        self.nodes_by_subnet24()
+            .into_iter()
+            .map(|(subnet, nodes)| (subnet, nodes.len()))
+            .max_by(|(subnet, count), (other_subnet, other_count)| {
+                count
+                    .cmp(other_count)
+                    .then_with(|| other_subnet.cmp(subnet))
+            })
+    }
+
+    /// Describes every bucket on its own line with the range of ids it
+    /// covers, the number of nodes in each state and whether it covers our
+    /// own id.
+    pub fn display_tree(&self) -> String {
+        let mut output = String::new();
+
+        for bucket in &self.buckets {
+            let count = |state| bucket.iter().filter(|node| node.state() == state).count();
+            let own_id = if bucket.could_hold_node(&self.id) {
+                " (own id)"
+            } else {
+                ""
+            };
+
+            output.push_str(&format!(
+                "[{:040x}, {:040x}) good={} questionable={} bad={}{}\n",
+                *bucket.start,
+                *bucket.end,
+                count(NodeState::Good),
+                count(NodeState::Questionable),
+                count(NodeState::Bad),
+                own_id,
+            ));
+        }
+
+        output
+    }
+
+    /// Describes the table as JSON for post-mortem analysis.
+    #[cfg(feature = "debug")]
+    pub fn to_debug_json(&self) -> serde_json::Value {
+        let now = Utc::now().naive_utc();
+
+        let buckets: Vec<serde_json::Value> = self
+            .buckets
+            .iter()
+            .map(|bucket| {
+                let nodes: Vec<serde_json::Value> = bucket
+                    .iter()
+                    .map(|node| {
+                        json!({
+                            "id": node.id.to_string(),
+                            "addr": node.address.to_string(),
+                            "state": node.state(),
+                            "last_seen_secs_ago": node
+                                .last_seen()
+                                .map(|last_seen| now.signed_duration_since(last_seen).num_seconds()),
+                        })
+                    })
+                    .collect();
+
+                json!({
+                    "start": format!("{:040x}", *bucket.start),
+                    "end": format!("{:040x}", *bucket.end),
+                    "nodes": nodes,
+                })
+            })
+            .collect();
+
+        json!({
+            "self_id": self.id.to_string(),
+            "total_nodes": self.len(),
+            "buckets": buckets,
+        })
+    }
+
+    /// Takes a snapshot of every bucket and node in the table.
+    pub fn dump(&self) -> TableDump {
+        TableDump::new(self.buckets.iter())
+    }
+}
+
+/// The `k` of `nodes` closest to `id`, closest first.
+fn closest_k<'a>(nodes: impl Iterator<Item = &'a Node>, id: &NodeID, k: usize) -> Vec<NodeInfo> {
+    if k == 0 {
+        return Vec::new();
+    }
+
+    let nodes: Vec<&Node> = nodes.collect();
+
+    // Max-heap holding the closest `k` nodes seen so far.
+    let mut closest = BinaryHeap::with_capacity(k + 1);
+    for (idx, node) in nodes.iter().enumerate() {
+        closest.push((node.id.distance(id), idx));
+        if closest.len() > k {
+            closest.pop();
+        }
+    }
+
+    closest
+        .into_sorted_vec()
+        .into_iter()
+        .map(|(_, idx)| nodes[idx].into())
+        .collect()
 }
 
-/// Generates a token given an address and secret.
-fn generate_token(addr: &SocketAddrV4, secret: &[u8; 4]) -> [u8; 20] {
+/// Generates a token given an address, secret and nonce. The nonce is
+/// sent at the start of the token so it can be verified without
+/// remembering it.
+fn generate_token(addr: &SocketAddrV4, secret: &[u8; 4], nonce: [u8; 4]) -> [u8; 20] {
     let mut hasher = Sha1::new();
 
     let addr_bytes = proto::addr_to_bytes(addr);
 
     hasher.input(&addr_bytes);
     hasher.input(secret);
+    hasher.input(&nonce);
+
+    let mut digest = [0u8; 20];
+    hasher.result(&mut digest);
 
     let mut output = [0u8; 20];
-    hasher.result(&mut output);
+    output[..nonce.len()].copy_from_slice(&nonce);
+    output[nonce.len()..].copy_from_slice(&digest[..20 - nonce.len()]);
 
     output
 }
 
+/// The /24 subnet `address` belongs to.
+fn subnet24(address: &SocketAddrV4) -> [u8; 3] {
+    let octets = address.ip().octets();
+
+    [octets[0], octets[1], octets[2]]
+}
+
 fn verify_token(addr: &SocketAddrV4, secret: &[u8; 4], token: &[u8]) -> bool {
-    let expected = generate_token(addr, secret);
+    if token.len() != 20 {
+        return false;
+    }
+
+    let mut nonce = [0u8; 4];
+    nonce.copy_from_slice(&token[..4]);
+    let expected = generate_token(addr, secret, nonce);
 
     token == expected
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AddNodeOutcome,
+        BucketSizePolicy,
+        RoutingTable,
+        RoutingTableConfig,
+    };
+    use crate::routing::{
+        bucket::{
+            PendingNode,
+            MAX_DEPTH,
+            MAX_PENDING,
+        },
+        Node,
+        NodeState,
+    };
+    use chrono::{
+        self,
+        Utc,
+    };
+    use failure::Error;
+    use krpc_encoding::{
+        addr_to_bytes,
+        NodeID,
+        NodeInfo,
+    };
+    use num_bigint::BigUint;
+    use rand::{
+        rngs::StdRng,
+        Rng,
+        SeedableRng,
+    };
+    use std::{
+        collections::HashSet,
+        net::{
+            Ipv4Addr,
+            SocketAddrV4,
+        },
+        sync::{
+            atomic::{
+                AtomicUsize,
+                Ordering,
+            },
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[test]
+    fn tokens_verified() {
+        let mut table = RoutingTable::new(NodeID::random());
+        let addr: SocketAddrV4 = "10.0.0.1:6881".parse().unwrap();
+        let other: SocketAddrV4 = "10.0.0.2:6881".parse().unwrap();
+
+        let token = table.generate_token(&addr);
+        let another = table.generate_token(&addr);
+        assert_ne!(token, another);
+        assert!(table.verify_token(&token, &addr));
+        assert!(table.verify_token(&another, &addr));
+        assert!(!table.verify_token(&token, &other));
+        assert!(!table.verify_token(&token[..19], &addr));
+
+        // Tokens of the last secret are still accepted.
+        table.update_token();
+        assert!(table.verify_token(&token, &addr));
+        table.update_token();
+        assert!(!table.verify_token(&token, &addr));
+    }
+
+    #[test]
+    fn refresh_target_in_bucket() {
+        let mut table = RoutingTable::new(NodeID::random());
+        for port in 0..64 {
+            let node = Node::new(NodeID::random(), address(port));
+            node.mark_successful_request();
+            table.add_node(node);
+        }
+
+        assert!(table.buckets.len() > 1);
+
+        for i in 0..10_000 {
+            let bucket_idx = i % table.buckets.len();
+            let target = table.refresh_target(bucket_idx);
+            let bucket = &table.buckets[bucket_idx];
+
+            assert!(*target >= *bucket.start && *target < *bucket.end);
+        }
+    }
+
+    #[test]
+    fn stale_buckets() {
+        let mut table = random_table(64);
+        assert!(table.buckets.len() > 2);
+        assert!(table.stale_buckets(Duration::from_secs(60)).is_empty());
+
+        let an_hour_ago = Utc::now().naive_utc() - chrono::Duration::hours(1);
+        table.buckets[0].last_changed = an_hour_ago;
+        table.buckets[2].last_changed = an_hour_ago;
+        assert_eq!(table.stale_buckets(Duration::from_secs(60)), vec![0, 2]);
+        assert!(table
+            .stale_buckets(Duration::from_secs(2 * 60 * 60))
+            .is_empty());
+    }
+
+    #[test]
+    fn clear_and_reset() {
+        let mut table = random_table(100);
+        assert!(table.len() > 0);
+        assert!(table.depth() > 1);
+
+        let new_id = NodeID::random();
+        table.clear_and_reset(new_id.clone());
+
+        assert_eq!(table.len(), 0);
+        assert_eq!(table.depth(), 1);
+        assert_eq!(table.id(), &new_id);
+        table.check_invariants();
+
+        let node = Node::new(NodeID::random(), "10.0.0.1:6881".parse().unwrap());
+        let id = node.id.clone();
+        table.add_node(node);
+        assert!(table.get_node(&id).is_some());
+    }
+
+    /// Address in a /24 subnet of its own for each `idx`, with `idx` as its
+    /// port, so tests not about the subnet limit don't run into it.
+    fn address(idx: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::from(0x0a00_0001 + (u32::from(idx) << 8)), idx)
+    }
+
+    /// Fills a table with good nodes at random ids.
+    fn random_table(nodes: u16) -> RoutingTable {
+        let mut table = RoutingTable::new(NodeID::random());
+        for port in 0..nodes {
+            let node = Node::new(NodeID::random(), address(port));
+            node.mark_successful_request();
+            table.add_node(node);
+        }
+
+        table
+    }
+
+    #[test]
+    fn find_closest_k_across_buckets() {
+        let own_id = NodeID::new(BigUint::from(0u8));
+        let mut table = RoutingTable::new(own_id.clone());
+
+        // A couple of nodes in each of a few distant buckets so that no single
+        // bucket holds `k` of them.
+        let mut ids = Vec::new();
+        for shift in &[159usize, 158, 150, 120, 80, 40] {
+            for offset in 0..2u8 {
+                let id = NodeID::new((BigUint::from(1u8) << *shift) + offset);
+                let node = Node::new(id.clone(), address(ids.len() as u16));
+                node.mark_successful_request();
+                table.add_node(node);
+                ids.push(id);
+            }
+        }
+        let questionable = NodeID::new(BigUint::from(1u8) << 100);
+        table.add_node(Node::new(questionable.clone(), address(100)));
+        assert!(table.buckets.len() > 1);
+
+        for target in &[own_id, NodeID::random(), ids[0].clone()] {
+            let mut expected = ids.clone();
+            expected.sort_by_key(|id| id.distance(target));
+
+            let found: Vec<NodeID> = table
+                .find_closest_k(target, 8)
+                .into_iter()
+                .map(|node| node.node_id)
+                .collect();
+            assert_eq!(found, &expected[..8]);
+
+            let all: Vec<NodeID> = table
+                .find_closest_k(target, 20)
+                .into_iter()
+                .map(|node| node.node_id)
+                .collect();
+            assert_eq!(all, expected);
+            assert!(!all.contains(&questionable));
+        }
+
+        assert!(table.find_closest_k(&NodeID::random(), 0).is_empty());
+        assert!(RoutingTable::new(NodeID::random())
+            .find_closest_k(&NodeID::random(), 8)
+            .is_empty());
+    }
+
+    #[test]
+    fn find_nodes_questionable_fallback() {
+        let now = Utc::now().naive_utc();
+        let ago = |minutes| Some(now - chrono::Duration::minutes(minutes));
+        let target = NodeID::random();
+        let mut table = RoutingTable::new(NodeID::random());
+
+        let mut added = 0;
+        let mut add = |last_request_to, failed_requests| {
+            let id = NodeID::random();
+            added += 1;
+            table.add_node(Node::with_history(
+                id.clone(),
+                address(added),
+                last_request_to,
+                None,
+                failed_requests,
+            ));
+            id
+        };
+        let by_distance = |mut ids: Vec<NodeID>| {
+            ids.sort_by_key(|id| id.distance(&target));
+            ids
+        };
+        let good = by_distance((0..3).map(|_| add(ago(5), 0)).collect());
+        let questionable = by_distance((0..3).map(|_| add(ago(20), 0)).collect());
+        let bad = add(ago(5), 2);
+
+        let found: Vec<NodeID> = table
+            .find_nodes(&target)
+            .into_iter()
+            .map(|node| node.node_id)
+            .collect();
+        let expected: Vec<NodeID> = good.iter().chain(&questionable).cloned().collect();
+        assert_eq!(found, expected);
+        assert!(!found.contains(&bad));
+        for (id, state) in found.iter().zip(
+            [NodeState::Good; 3]
+                .iter()
+                .chain(&[NodeState::Questionable; 3]),
+        ) {
+            assert_eq!(table.get_node(id).map(Node::state), Some(*state));
+        }
+
+        // Enough good nodes leave the questionable ones out.
+        let mut table = random_table(200);
+        for idx in 200..220 {
+            table.add_node(Node::new(NodeID::random(), address(idx)));
+        }
+        let found = table.find_nodes(&target);
+        assert_eq!(found.len(), 8);
+        assert!(found.iter().all(|node| {
+            table.get_node(&node.node_id).map(Node::state) == Some(NodeState::Good)
+        }));
+    }
+
+    #[test]
+    fn iter_closest_matches_sort() {
+        let table = random_table(1000);
+        let mut targets = vec![NodeID::random(), table.id.clone()];
+        targets.extend(table.nodes().take(3).map(|node| node.id.clone()));
+
+        for target in targets {
+            let mut expected: Vec<&NodeID> = table.nodes().map(|node| &node.id).collect();
+            expected.sort_by_key(|id| id.distance(&target));
+
+            let exact: Vec<&NodeID> = table
+                .iter_closest_exact(&target)
+                .map(|node| &node.id)
+                .collect();
+            assert_eq!(exact, expected);
+
+            // Nodes are grouped by bucket and every group is closer than the
+            // groups after it.
+            let mut groups: Vec<(usize, Vec<BigUint>)> = Vec::new();
+            for node in table.iter_closest(&target) {
+                let bucket_idx = table.get_bucket_idx(&node.id);
+                let distance = node.id.distance(&target);
+
+                match groups.last_mut() {
+                    Some((idx, distances)) if *idx == bucket_idx => distances.push(distance),
+                    _ => groups.push((bucket_idx, vec![distance])),
+                };
+            }
+
+            let yielded: usize = groups.iter().map(|(_, distances)| distances.len()).sum();
+            assert_eq!(yielded, expected.len());
+
+            for pair in groups.windows(2) {
+                assert!(pair[0].1.iter().max() < pair[1].1.iter().min());
+            }
+        }
+    }
+
+    #[test]
+    fn display_tree() {
+        let mut table = RoutingTable::new(NodeID::new(BigUint::from(0u8)));
+        let half = BigUint::from(1u8) << 159;
+        let node = |id: BigUint, idx: u16| Node::new(NodeID::new(id), address(idx));
+
+        for idx in 0..8u8 {
+            let upper = node(half.clone() + idx, idx.into());
+            upper.mark_successful_request();
+            table.add_node(upper);
+        }
+
+        // Splits the full bucket.
+        let good = node(BigUint::from(1u8), 8);
+        good.mark_successful_request();
+        table.add_node(good);
+
+        table.add_node(node(BigUint::from(2u8), 9));
+
+        let bad = node(BigUint::from(3u8), 10);
+        bad.mark_unreachable();
+        table.add_node(bad);
+
+        assert_eq!(
+            table.display_tree(),
+            "[0000000000000000000000000000000000000000, 8000000000000000000000000000000000000000) good=1 questionable=1 bad=1 (own id)\n\
+             [8000000000000000000000000000000000000000, 10000000000000000000000000000000000000000) good=8 questionable=0 bad=0\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "debug")]
+    fn to_debug_json() {
+        let mut table = RoutingTable::new(NodeID::random());
+
+        let good = Node::new_with_id(1);
+        good.mark_successful_request();
+        table.add_node(good);
+        table.add_node(Node::new_with_id(2));
+
+        let json = table.to_debug_json();
+        assert_eq!(json["self_id"], table.id.to_string());
+        assert_eq!(json["total_nodes"], 2);
+
+        let buckets = json["buckets"].as_array().unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0]["start"], format!("{:040x}", 0));
+
+        let nodes = buckets[0]["nodes"].as_array().unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0]["id"], Node::new_with_id(1).id.to_string());
+        assert_eq!(nodes[0]["addr"], Node::new_with_id(1).address.to_string());
+        assert_eq!(nodes[0]["state"], "good");
+        assert!(nodes[0]["last_seen_secs_ago"].as_i64().unwrap() <= 1);
+        assert_eq!(nodes[1]["state"], "questionable");
+        assert!(nodes[1]["last_seen_secs_ago"].is_null());
+    }
+
+    #[test]
+    fn merge() {
+        // Nodes are inserted from the highest id down so every split of the
+        // bucket holding our own id moves a node out of it.
+        let id = |bit: usize| NodeID::new(BigUint::from(1u8) << bit);
+        let table_with = |bits: Vec<usize>| {
+            let mut table = RoutingTable::new(NodeID::new(BigUint::from(0u8)));
+            for bit in bits.into_iter().rev() {
+                let node = Node::new(id(bit), address(bit as u16));
+                node.mark_successful_request();
+                table.add_node(node);
+            }
+
+            table
+        };
+
+        let mut table = table_with((140..160).collect());
+        let mut other = table_with((130..140).chain(150..160).collect());
+        assert_eq!(table.len(), 20);
+        assert_eq!(other.len(), 20);
+
+        for bit in 150..160 {
+            other.get_node_mut(&id(bit)).unwrap().mark_unreachable();
+        }
+
+        assert_eq!(table.merge(other), 10);
+        assert_eq!(table.len(), 30);
+
+        let ids: HashSet<&NodeID> = table.nodes().map(|node| &node.id).collect();
+        assert_eq!(ids.len(), 30);
+
+        for bit in 150..160 {
+            assert_eq!(table.get_node(&id(bit)).unwrap().state(), NodeState::Good);
+        }
+    }
+
+    #[test]
+    fn remove_node_promotes_replacement() {
+        let mut table = RoutingTable::new(NodeID::new(BigUint::from(0u8)));
+        let half = BigUint::from(1u8) << 159;
+        let node = |idx: u8| {
+            let node = Node::new(NodeID::new(half.clone() + idx), address(idx.into()));
+            node.mark_successful_request();
+            node
+        };
+
+        for idx in 0..8 {
+            table.add_node(node(idx));
+        }
+
+        // Splits off a bucket holding the eight nodes above which is still full.
+        table.add_node(node(8));
+
+        let bucket_idx = table.get_bucket_idx(&node(8).id);
+        assert_eq!(table.buckets[bucket_idx].nodes.len(), 8);
+        let replacements = &table.buckets[bucket_idx].replacements;
+        assert_eq!(replacements.len(), 1);
+        assert_eq!(replacements[0].id, node(8).id);
+        assert!(table.get_node(&node(8).id).is_none());
+
+        let removed = table.remove_node(&node(0).id).unwrap();
+        assert_eq!(removed.id, node(0).id);
+
+        let bucket = &table.buckets[bucket_idx];
+        assert_eq!(bucket.nodes.len(), 8);
+        assert!(bucket.replacements.is_empty());
+        assert!(table.get_node(&node(8).id).is_some());
+        assert!(table.get_node(&node(0).id).is_none());
+        assert!(table.remove_node(&node(0).id).is_none());
+    }
+
+    #[test]
+    fn prune_by_age() {
+        let own_id = NodeID::new(BigUint::from(0u8));
+        let mut table = RoutingTable::new(own_id);
+        let day_ago = Utc::now().naive_utc() - chrono::Duration::hours(24);
+
+        // Good nodes split the buckets so none end up as replacements. They
+        // are aged once in the table.
+        for idx in 0..10 {
+            let node = Node::new(
+                NodeID::new(BigUint::from(1u8) << (159 - idx)),
+                SocketAddrV4::new([10, 0, idx as u8, 1].into(), 6881),
+            );
+            node.mark_successful_request();
+            table.add_node(node);
+        }
+        assert_eq!(table.len(), 10);
+
+        for bucket in &mut table.buckets {
+            for node in &mut bucket.nodes {
+                *node = Node::with_history(node.id.clone(), node.address, Some(day_ago), None, 0);
+            }
+        }
+
+        let fresh_id = NodeID::new(BigUint::from(1u8) << 140);
+        let fresh = Node::new(fresh_id.clone(), "10.0.100.1:6881".parse().unwrap());
+        fresh.mark_successful_request();
+        table.add_node(fresh);
+        table
+            .buckets
+            .last_mut()
+            .unwrap()
+            .replacements
+            .push(PendingNode {
+                node: Node::new(
+                    NodeID::new((BigUint::from(1u8) << 159) + 1u8),
+                    "10.0.101.1:6881".parse().unwrap(),
+                ),
+                queued: Utc::now().naive_utc(),
+            });
+
+        assert_eq!(table.prune_by_age(Duration::from_secs(3600)), 10);
+        assert_eq!(table.len(), 1);
+        assert!(table.get_node(&fresh_id).is_some());
+        assert!(table
+            .buckets
+            .iter()
+            .all(|bucket| bucket.replacements.is_empty()));
+    }
+
+    #[test]
+    fn add_nodes_matches_add_node() {
+        let own_id = NodeID::random();
+        let mut nodes: Vec<(NodeID, SocketAddrV4)> = (0..500u32)
+            .map(|idx| (NodeID::random(), SocketAddrV4::new((idx << 8).into(), 6881)))
+            .collect();
+        // Which nodes fit in a full bucket depends on the order they come
+        // in, and batches are added in order of id.
+        nodes.sort_by_key(|(id, _)| id.to_bytes());
+        let good_node = |(id, address): &(NodeID, SocketAddrV4)| {
+            let node = Node::new(id.clone(), *address);
+            node.mark_successful_request();
+            node
+        };
+
+        let mut one_by_one = RoutingTable::new(own_id.clone());
+        for node in &nodes {
+            one_by_one.add_node(good_node(node));
+        }
+
+        let mut batched = RoutingTable::new(own_id);
+        let summary = batched.add_nodes(nodes.iter().map(good_node));
+
+        let ids = |table: &RoutingTable| {
+            table
+                .nodes()
+                .map(|node| node.id.clone())
+                .collect::<HashSet<_>>()
+        };
+        assert_eq!(ids(&batched), ids(&one_by_one));
+        assert_eq!(summary.added, batched.len());
+        assert_eq!(summary.added + summary.pending, nodes.len());
+        assert!(summary.to_ping.is_empty());
+    }
+
+    #[test]
+    fn add_nodes_touches_existing() {
+        let mut table = RoutingTable::new(NodeID::new(BigUint::from(0u8)));
+        let node = |idx: u8| {
+            Node::new(
+                NodeID::new(BigUint::from(idx) + 1u8),
+                SocketAddrV4::new([10, 0, idx, 1].into(), 6881),
+            )
+        };
+        table.add_nodes((0..4).map(node));
+        assert_eq!(
+            table.buckets[0].oldest_node().map(|node| node.id.clone()),
+            Some(node(0).id)
+        );
+
+        let summary = table.add_nodes(vec![node(0)]);
+        assert_eq!(summary.rejected, 1);
+        assert_eq!(
+            table.buckets[0].newest_node().map(|node| node.id.clone()),
+            Some(node(0).id)
+        );
+        assert_eq!(
+            table.buckets[0].oldest_node().map(|node| node.id.clone()),
+            Some(node(1).id)
+        );
+    }
+
+    #[test]
+    fn add_nodes_summary() {
+        let mut table = RoutingTable::new(NodeID::new(BigUint::from(0u8)));
+        let node = |idx: u8| {
+            Node::new(
+                NodeID::new(BigUint::from(idx) + 1u8),
+                SocketAddrV4::new([10, 0, idx, 1].into(), 6881),
+            )
+        };
+
+        // Questionable nodes fill the only bucket without letting it split.
+        let summary = table.add_nodes((0..8).map(node));
+        assert_eq!(summary.added, 8);
+        assert!(summary.to_ping.is_empty());
+
+        let summary = table.add_nodes(vec![
+            node(8),
+            node(9),
+            node(8),
+            node(0),
+            Node::new(table.id.clone(), "10.0.1.2:6881".parse().unwrap()),
+            Node::new(
+                NodeID::new(BigUint::from(100u8)),
+                "10.0.1.2:6881".parse().unwrap(),
+            ),
+        ]);
+        assert_eq!(summary.added, 0);
+        assert_eq!(summary.pending, 2);
+        assert_eq!(summary.rejected, 4);
+
+        let mut to_ping: Vec<NodeID> = summary
+            .to_ping
+            .into_iter()
+            .map(|node| node.node_id)
+            .collect();
+        to_ping.sort_by_key(NodeID::to_bytes);
+        let questionable: Vec<NodeID> = (0..8).map(|idx| node(idx).id).collect();
+        assert_eq!(to_ping, questionable);
+        assert_eq!(table.buckets[0].replacements.len(), 2);
+    }
+
+    #[test]
+    fn pending_queue() {
+        let mut table = RoutingTable::new(NodeID::new(BigUint::from(0u8)));
+        let node = |idx: u8| {
+            Node::new(
+                NodeID::new(BigUint::from(idx) + 1u8),
+                SocketAddrV4::new([10, 0, idx, 1].into(), 6881),
+            )
+        };
+
+        for idx in 0..8 {
+            assert_eq!(table.add_node(node(idx)), AddNodeOutcome::Added);
+        }
+
+        // Only the newest replacements are kept.
+        for idx in 8..14 {
+            assert_eq!(table.add_node(node(idx)), AddNodeOutcome::Queued);
+        }
+        let pending: Vec<NodeID> = table.buckets[0]
+            .replacements
+            .iter()
+            .map(|pending| pending.id.clone())
+            .collect();
+        assert_eq!(
+            pending,
+            (10..14).map(|idx| node(idx).id).collect::<Vec<_>>()
+        );
+
+        let dump = table.dump();
+        assert_eq!(dump.buckets[0].pending, MAX_PENDING);
+        assert!(dump.buckets[0]
+            .pending_ages_secs
+            .iter()
+            .all(|age| *age <= 1));
+
+        let failed = table.fail_verification(&node(3).id).unwrap();
+        assert_eq!(failed.id, node(3).id);
+        assert!(table.get_node(&node(3).id).is_none());
+        assert!(table.get_node(&node(10).id).is_some());
+        assert_eq!(table.len(), 8);
+        assert_eq!(table.buckets[0].replacements.len(), MAX_PENDING - 1);
+
+        // Stale replacements aren't promoted.
+        for pending in &mut table.buckets[0].replacements {
+            pending.queued = pending.queued - chrono::Duration::hours(1);
+        }
+        assert!(table.fail_verification(&node(4).id).is_none());
+        assert!(table.get_node(&node(4).id).is_some());
+        assert!(table.buckets[0].replacements.is_empty());
+    }
+
+    #[test]
+    fn from_utorrent_dat() -> Result<(), Error> {
+        let id = NodeID::random();
+        // Spread over the id space so no bucket away from our own id, which
+        // wouldn't be split, gets more nodes than it holds.
+        let nodes: Vec<NodeInfo> = (0..12u8)
+            .map(|idx| {
+                let mut bytes: [u8; 20] = rand::random();
+                bytes[0] = idx * 20;
+                NodeInfo::new(
+                    NodeID::from(bytes),
+                    SocketAddrV4::new([10, 0, idx, 1].into(), 6881),
+                )
+            })
+            .collect();
+
+        let mut dat = b"d3:agei1565000000e2:id20:".to_vec();
+        dat.extend_from_slice(&id.to_bytes());
+        dat.extend_from_slice(format!("5:nodes{}:", nodes.len() * 26).as_bytes());
+        for node in &nodes {
+            dat.extend_from_slice(&node.node_id.to_bytes());
+            dat.extend_from_slice(&addr_to_bytes(&node.address));
+        }
+        dat.push(b'e');
+
+        let table = RoutingTable::from_utorrent_dat(&dat)?;
+        assert_eq!(table.id, id);
+        assert_eq!(table.len(), nodes.len());
+        assert!(table
+            .buckets
+            .iter()
+            .all(|bucket| bucket.replacements.is_empty()));
+        for node in table.nodes() {
+            assert_eq!(node.state(), NodeState::Questionable);
+            assert!(nodes.contains(&node.into()));
+        }
+
+        assert!(RoutingTable::from_utorrent_dat(b"d5:nodes3:abce").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn own_id_ignored() {
+        let mut table = RoutingTable::new(NodeID::random());
+        let address: SocketAddrV4 = "10.0.0.1:6881".parse().unwrap();
+
+        table.add_node(Node::new(table.id.clone(), address));
+        assert_eq!(table.len(), 0);
+
+        assert!(table.get_or_add(table.id.clone(), address).is_none());
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn node_counts() {
+        let mut table = RoutingTable::new(NodeID::random());
+
+        for id in 1..=3 {
+            let node = Node::new_with_id(id);
+            node.mark_successful_request();
+            table.add_node(node);
+        }
+
+        table.add_node(Node::new_with_id(4));
+        table.add_node(Node::new_with_id(5));
+
+        let bad = Node::new_with_id(6);
+        bad.mark_unreachable();
+        table.add_node(bad);
+
+        assert_eq!(table.good_node_count(), 3);
+        assert_eq!(table.questionable_node_count(), 2);
+        assert_eq!(table.bad_node_count(), 1);
+        assert!(!table.is_healthy());
+    }
+
+    #[test]
+    fn most_represented_subnet() {
+        let mut table = RoutingTable::with_config(
+            NodeID::random(),
+            RoutingTableConfig {
+                max_per_subnet_24: usize::max_value(),
+                ..RoutingTableConfig::default()
+            },
+        );
+        assert_eq!(table.most_represented_subnet(), None);
+
+        for (subnet, host) in (1..=5)
+            .map(|host| (0, host))
+            .chain((1..=2).map(|host| (1, host)))
+        {
+            let address = SocketAddrV4::new(Ipv4Addr::new(10, 0, subnet, host), 6881);
+            table.add_node(Node::new(NodeID::random(), address));
+        }
+
+        let subnets = table.nodes_by_subnet24();
+        assert_eq!(subnets.len(), 2);
+        assert_eq!(subnets[&[10, 0, 1]].len(), 2);
+        assert_eq!(table.most_represented_subnet(), Some(([10, 0, 0], 5)));
+    }
+
+    #[test]
+    fn subnet_limit() {
+        let config = RoutingTableConfig {
+            max_per_subnet_24: 2,
+            ..RoutingTableConfig::default()
+        };
+        let mut table = RoutingTable::with_config(NodeID::random(), config.clone());
+
+        for host in 1..=10 {
+            let address = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, host), 6881);
+            table.add_node(Node::new(NodeID::random(), address));
+        }
+        assert!(table
+            .get_or_add(NodeID::random(), "10.0.0.11:6881".parse().unwrap())
+            .is_none());
+        assert_eq!(table.len(), config.max_per_subnet_24);
+
+        let other_subnet: SocketAddrV4 = "10.0.1.1:6881".parse().unwrap();
+        table.add_node(Node::new(NodeID::random(), other_subnet));
+        assert_eq!(table.len(), config.max_per_subnet_24 + 1);
+        assert!(table.nodes().any(|node| node.address == other_subnet));
+    }
+
+    #[test]
+    fn subnet_limit_default() {
+        let mut table = RoutingTable::new(NodeID::random());
+
+        for host in 1..=10 {
+            let address = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, host), 6881);
+            table.add_node(Node::new(NodeID::random(), address));
+        }
+
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn subnet_limit_batched() {
+        let mut table = RoutingTable::new(NodeID::random());
+        let subnet = |a, b, c| {
+            (1..=4).map(move |host| {
+                Node::new(
+                    NodeID::random(),
+                    SocketAddrV4::new(Ipv4Addr::new(a, b, c, host), 6881),
+                )
+            })
+        };
+
+        let summary = table.add_nodes(subnet(192, 168, 1));
+        assert_eq!((summary.added, summary.rejected), (1, 3));
+
+        // Loopback addresses count against the limit like any other.
+        let summary = table.add_nodes(subnet(127, 0, 0));
+        assert_eq!((summary.added, summary.rejected), (1, 3));
+        assert!(table
+            .get_or_add(NodeID::random(), "127.0.0.5:6881".parse().unwrap())
+            .is_none());
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn update_node_addr() {
+        let mut table = random_table(20);
+        let node = table.nodes().nth(5).unwrap();
+        let id = node.id.clone();
+        let old_addr = node.address;
+        let new_addr: SocketAddrV4 = "10.0.0.1:6881".parse().unwrap();
+
+        let bucket_idx = table.get_bucket_idx(&id);
+        let position = |table: &RoutingTable| {
+            table.buckets[bucket_idx]
+                .iter()
+                .position(|node| node.id == id)
+        };
+        let old_position = position(&table);
+
+        assert!(table.update_node_addr(&id, new_addr.into()));
+        assert!(!table.update_node_addr(&NodeID::random(), new_addr.into()));
+
+        assert_eq!(position(&table), old_position);
+        assert_eq!(table.get_node(&id).unwrap().state(), NodeState::Good);
+
+        let closest = table.find_closest_k(&id, 8);
+        assert_eq!(closest[0].node_id, id);
+        assert_eq!(closest[0].address, new_addr);
+        assert!(closest.iter().all(|node| node.address != old_addr));
+    }
+
+    #[test]
+    fn local_bucket_size() {
+        let policy = BucketSizePolicy {
+            default_k: 8,
+            local_k: 20,
+        };
+        let mut table = RoutingTable::new_with_policy(NodeID::new(BigUint::from(0u8)), policy);
+        let add = |table: &mut RoutingTable, id: BigUint, port: u16| {
+            let node = Node::new(NodeID::new(id), address(port));
+            node.mark_successful_request();
+            table.add_node(node)
+        };
+
+        // Ids in the only bucket, which holds our own id.
+        for i in 1..=15u8 {
+            add(&mut table, BigUint::from(i), i.into());
+        }
+        assert_eq!(table.buckets.len(), 1);
+        assert_eq!(table.buckets[0].nodes.len(), 15);
+
+        for i in 16..=20u8 {
+            add(&mut table, BigUint::from(i), i.into());
+        }
+        assert_eq!(table.len(), 20);
+
+        // Ids in a remote bucket split off the local one.
+        let remote = |i: u16| (BigUint::from(1u8) << 159) + i;
+        for i in 0..8 {
+            add(&mut table, remote(i), 100 + i);
+        }
+        let remote_idx = table.get_bucket_idx(&NodeID::new(remote(0)));
+        assert_eq!(table.buckets[remote_idx].capacity, 8);
+        assert_eq!(table.buckets[remote_idx].nodes.len(), 8);
+        let buckets = table.buckets.len();
+
+        // The full remote bucket doesn't hold our own id so it isn't split.
+        assert_eq!(add(&mut table, remote(8), 108), AddNodeOutcome::Queued);
+        assert_eq!(table.buckets.len(), buckets);
+        assert_eq!(table.len(), 28);
+
+        for bucket in &table.buckets {
+            let holds_own_id = bucket.could_hold_node(&table.id);
+            assert_eq!(bucket.capacity, if holds_own_id { 20 } else { 8 });
+            assert!(bucket.nodes.len() <= bucket.capacity);
+        }
+    }
+
+    #[test]
+    fn uneven_split_overflows_into_replacements() {
+        let policy = BucketSizePolicy {
+            default_k: 8,
+            local_k: 16,
+        };
+        let mut table = RoutingTable::new_with_policy(NodeID::new(BigUint::from(0u8)), policy);
+        let add = |table: &mut RoutingTable, id: BigUint, port: u16| {
+            let node = Node::new(NodeID::new(id), address(port));
+            node.mark_successful_request();
+            table.add_node(node);
+        };
+
+        // Every node on the remote side of the first split.
+        let remote = |i: u16| (BigUint::from(1u8) << 159) + i;
+        for i in 0..16 {
+            add(&mut table, remote(i), 100 + i);
+        }
+        assert_eq!(table.buckets.len(), 1);
+
+        add(&mut table, BigUint::from(1u8), 1);
+        assert_eq!(table.buckets.len(), 2);
+
+        let remote_bucket = &table.buckets[1];
+        assert_eq!(remote_bucket.capacity, 8);
+        let ports: Vec<u16> = remote_bucket
+            .nodes
+            .iter()
+            .map(|node| node.address.port())
+            .collect();
+        assert_eq!(ports, (108..116).collect::<Vec<_>>());
+
+        // The oldest of the overflow made way for the newer ones.
+        let replacements: Vec<u16> = remote_bucket
+            .replacements
+            .iter()
+            .map(|pending| pending.node.address.port())
+            .collect();
+        assert_eq!(replacements.len(), MAX_PENDING);
+        assert_eq!(replacements, (104..108).collect::<Vec<_>>());
+        assert_eq!(table.len(), 9);
+    }
+
+    #[test]
+    fn splits_near_own_id_keep_coverage() {
+        let mut rng = StdRng::from_seed([5; 32]);
+        let own_id = NodeID::random();
+        let mut table = RoutingTable::new(own_id.clone());
+
+        for port in 0..2000 {
+            // Ids sharing a prefix of up to every bit with our own id.
+            let prefix = rng.gen_range(0, MAX_DEPTH + 1);
+            let mask = (BigUint::from(1u8) << (MAX_DEPTH - prefix)) - 1u8;
+            let noise = BigUint::from_bytes_be(&rng.gen::<[u8; 20]>()) & mask;
+            let id = NodeID::new(&*own_id ^ noise);
+
+            let node = Node::new(id.clone(), address(port));
+            node.mark_successful_request();
+            table.add_node(node);
+
+            let bucket = &table.buckets[table.get_bucket_idx(&id)];
+            assert!(
+                id == own_id
+                    || bucket.get(&id).is_some()
+                    || bucket.replacements.iter().any(|node| node.id == id)
+            );
+        }
+
+        assert!(table
+            .buckets
+            .iter()
+            .all(|bucket| bucket.depth() <= MAX_DEPTH));
+        table.check_invariants();
+    }
+
+    #[test]
+    fn healthy_with_k_good_nodes() {
+        let mut table = RoutingTable::new(NodeID::random());
+
+        for id in 1..=8 {
+            let node = Node::new_with_id(id);
+            node.mark_successful_request();
+            table.add_node(node);
+
+            assert_eq!(table.is_healthy(), id == 8);
+        }
+
+        assert_eq!(table.good_node_count(), 8);
+    }
+
+    #[test]
+    fn iter_closest_weighted_within_tiers() {
+        let mut table = RoutingTable::new(NodeID::new(BigUint::from(0u8)));
+        let add = |table: &mut RoutingTable, id: u8, rtt: u64, failures: usize| {
+            let node = Node::new_with_id(id);
+            for _ in 0..failures {
+                node.mark_failed_request();
+            }
+            node.mark_successful_request();
+            node.record_rtt(Duration::from_millis(rtt));
+            table.add_node(node);
+        };
+
+        // 4 to 7 are in one tier, 8 in the next.
+        add(&mut table, 4, 900, 1);
+        add(&mut table, 5, 50, 0);
+        add(&mut table, 6, 50, 1);
+        add(&mut table, 7, 20, 0);
+        add(&mut table, 8, 10, 0);
+
+        let target = NodeID::new(BigUint::from(0u8));
+        let order = |rtt_weight, reliability_weight| -> Vec<NodeID> {
+            table
+                .iter_closest_weighted(&target, rtt_weight, reliability_weight)
+                .map(|node| node.id.clone())
+                .collect()
+        };
+        let ids = |ids: &[u8]| -> Vec<NodeID> {
+            ids.iter()
+                .map(|id| NodeID::new(BigUint::from(*id)))
+                .collect()
+        };
+
+        assert_eq!(order(1.0, 1.0), ids(&[7, 5, 6, 4, 8]));
+        assert_eq!(order(0.0, 0.0), ids(&[4, 5, 6, 7, 8]));
+        assert_eq!(order(0.0, 1.0), ids(&[5, 7, 4, 6, 8]));
+    }
+
+    #[test]
+    fn on_full_called_once() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let config = RoutingTableConfig {
+            on_full: Some(Arc::new(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })),
+            ..RoutingTableConfig::default()
+        };
+        let mut table = RoutingTable::with_config(NodeID::new(BigUint::from(0u8)), config);
+
+        // Eight nodes in each of the eight buckets closest to the table's id,
+        // from the farthest in. The first node of each splits the bucket
+        // holding our own id, and the last node fills the last of them.
+        for idx in 0..64u16 {
+            let bit = 159 - usize::from(idx / 8);
+            let id = NodeID::new((BigUint::from(1u8) << bit) + idx % 8);
+            let node = Node::new(id, address(1000 + idx));
+            node.mark_successful_request();
+            table.add_node(node);
+
+            assert_eq!(table.is_bootstrapped(), idx == 63);
+            assert_eq!(calls.load(Ordering::SeqCst), if idx == 63 { 1 } else { 0 });
+        }
+
+        let more = (0..8u16).map(|idx| {
+            let node = Node::new_with_id(100 + idx as u8);
+            node.mark_successful_request();
+            node
+        });
+        table.add_nodes(more);
+        table.add_node(Node::new_with_id(200));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn dump() {
+        let mut table = RoutingTable::new(NodeID::random());
+
+        let good = Node::new_with_id(1);
+        good.mark_successful_request();
+        good.record_rtt(Duration::from_millis(42));
+        table.add_node(good);
+
+        table.add_node(Node::new_with_id(2));
+
+        let bad = Node::new_with_id(3);
+        bad.mark_unreachable();
+        table.add_node(bad);
+
+        let dump = table.dump();
+        assert_eq!(dump.total_nodes, 3);
+        assert_eq!(dump.depth, 1);
+        assert!(dump.full_bucket_fraction.abs() < std::f64::EPSILON);
+
+        let bucket = &dump.buckets[0];
+        assert_eq!(bucket.start, "0".repeat(40));
+        assert_eq!(bucket.end, format!("1{}", "0".repeat(40)));
+        assert_eq!((bucket.good, bucket.questionable, bucket.bad), (1, 1, 1));
+
+        let node = &bucket.nodes[0];
+        assert_eq!(node.id, NodeID::new(BigUint::from(1u8)).to_string());
+        assert_eq!(node.address, Node::new_with_id(1).address);
+        assert_eq!(node.state, NodeState::Good);
+        assert!(node.last_request_to.is_some());
+        assert_eq!(node.last_request_from, None);
+        assert_eq!(node.rtt_ms, Some(42));
+
+        assert_eq!(bucket.nodes[1].rtt_ms, None);
+    }
+
+    #[test]
+    fn dump_split_table() {
+        let mut table = RoutingTable::new(NodeID::random());
+        for id in 0..9 {
+            let node = Node::new_with_id(id * 16);
+            node.mark_successful_request();
+            table.add_node(node);
+        }
+
+        let dump = table.dump();
+        // Every node falls in the lower half so the ninth node doesn't fit.
+        assert_eq!(dump.depth, 2);
+        assert_eq!(dump.total_nodes, 8);
+        assert_eq!(dump.buckets[0].end, dump.buckets[1].start);
+        assert_eq!(dump.buckets[0].nodes.len(), 8);
+        assert!((dump.full_bucket_fraction - 0.5).abs() < std::f64::EPSILON);
+    }
+
+    /// Property tests of how buckets divide the id space.
+    mod properties {
+        use crate::routing::{
+            bucket::Bucket,
+            Node,
+            RoutingTable,
+        };
+        use krpc_encoding::NodeID;
+        use num_bigint::BigUint;
+        use proptest::prelude::*;
+        use std::{
+            collections::HashSet,
+            net::{
+                Ipv4Addr,
+                SocketAddrV4,
+            },
+            ops::Deref,
+        };
+
+        fn id(bytes: [u8; 20]) -> NodeID {
+            NodeID::new(BigUint::from_bytes_be(&bytes))
+        }
+
+        /// Ids sharing the first `shared_bits` bits with `own_id`, so nodes
+        /// land in the deep buckets near it.
+        fn near(own_id: &NodeID, bytes: [u8; 20], shared_bits: usize) -> NodeID {
+            NodeID::new(&**own_id ^ (BigUint::from_bytes_be(&bytes) >> shared_bits))
+        }
+
+        fn table_with(own_id: [u8; 20], nodes: &[([u8; 20], usize)]) -> RoutingTable {
+            let own_id = id(own_id);
+            let mut table = RoutingTable::new(own_id.clone());
+
+            for (idx, (bytes, shared_bits)) in nodes.iter().enumerate() {
+                // One node per /24 so the subnet limit never applies.
+                let address =
+                    SocketAddrV4::new(Ipv4Addr::from(0x0a00_0000 + ((idx as u32) << 8)), 6881);
+                let node = Node::new(near(&own_id, *bytes, *shared_bits), address);
+                node.mark_successful_request();
+                table.add_node(node);
+            }
+
+            table
+        }
+
+        fn nodes() -> impl Strategy<Value = Vec<([u8; 20], usize)>> {
+            prop::collection::vec((any::<[u8; 20]>(), 0usize..160), 0..200)
+        }
+
+        proptest! {
+            #[test]
+            fn bucket_found_holds_id(
+                own_id in any::<[u8; 20]>(),
+                nodes in nodes(),
+                probes in prop::collection::vec(any::<[u8; 20]>(), 1..20),
+            ) {
+                let table = table_with(own_id, &nodes);
+                let own_id = id(own_id);
+
+                let ids = nodes
+                    .iter()
+                    .map(|(bytes, shared_bits)| near(&own_id, *bytes, *shared_bits))
+                    .chain(probes.into_iter().map(id))
+                    .chain(vec![own_id.clone()]);
+
+                for id in ids {
+                    let bucket = &table.buckets[table.get_bucket_idx(&id)];
+                    prop_assert!(bucket.could_hold_node(&id));
+                }
+            }
+
+            #[test]
+            fn node_in_one_bucket(own_id in any::<[u8; 20]>(), nodes in nodes()) {
+                let table = table_with(own_id, &nodes);
+                let mut seen = HashSet::new();
+
+                for bucket in &table.buckets {
+                    for node in &bucket.nodes {
+                        prop_assert!(seen.insert(node.id.clone()));
+                    }
+                }
+            }
+
+            #[test]
+            fn buckets_tile_id_space(own_id in any::<[u8; 20]>(), nodes in nodes()) {
+                let table = table_with(own_id, &nodes);
+                let initial = Bucket::initial_bucket();
+
+                prop_assert_eq!(&table.buckets[0].start, &initial.start);
+                prop_assert_eq!(&table.buckets[table.buckets.len() - 1].end, &initial.end);
+                for pair in table.buckets.windows(2) {
+                    prop_assert_eq!(&pair[0].end, &pair[1].start);
+                }
+
+                // The ids either side of every boundary are in neighbouring
+                // buckets.
+                for (idx, bucket) in table.buckets.iter().enumerate().skip(1) {
+                    let below = NodeID::new(bucket.start.deref().clone() - 1u8);
+                    prop_assert_eq!(table.get_bucket_idx(&bucket.start), idx);
+                    prop_assert_eq!(table.get_bucket_idx(&below), idx - 1);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Stopping the tasks of a [`Dht`] or [`Crawler`] on request.
+
+use futures::future::{
+    self,
+    Either,
+    Future,
+};
+use std::{
+    mem,
+    sync::{
+        Arc,
+        Mutex,
+        MutexGuard,
+    },
+    task::{
+        Context,
+        Poll,
+        Waker,
+    },
+};
+
+/// Shared by a component and its long running tasks. Tasks register
+/// themselves and return once shutdown is triggered.
+#[derive(Clone)]
+pub(crate) struct Shutdown {
+    state: Arc<Mutex<State>>,
+
+    /// Triggering the parent also triggers this. Tasks registered here are
+    /// waited for by the parent as well.
+    parent: Option<Box<Shutdown>>,
+}
+
+#[derive(Default)]
+struct State {
+    triggered: bool,
+
+    /// Number of live [`TaskGuard`]s.
+    tasks: usize,
+
+    /// Woken whenever `triggered` or `tasks` changes.
+    wakers: Vec<Waker>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            state: Arc::new(Mutex::new(State::default())),
+            parent: None,
+        }
+    }
+
+    /// Creates a handle which can be triggered on its own or through this one.
+    pub fn child(&self) -> Shutdown {
+        Shutdown {
+            state: Arc::new(Mutex::new(State::default())),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    pub fn trigger(&self) {
+        let wakers = {
+            let mut state = self.lock();
+            state.triggered = true;
+            mem::replace(&mut state.wakers, Vec::new())
+        };
+
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.lock().triggered
+            || self
+                .parent
+                .as_ref()
+                .map_or(false, |parent| parent.is_triggered())
+    }
+
+    /// Resolves once this or a parent is triggered.
+    pub async fn triggered(&self) {
+        future::poll_fn(|cx| self.poll_triggered(cx)).await
+    }
+
+    /// Runs `future` until it completes or shutdown is triggered. Returns
+    /// `None` in the latter case.
+    pub async fn run_until_triggered<F: Future>(&self, future: F) -> Option<F::Output> {
+        match future::select(Box::pin(future), Box::pin(self.triggered())).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+
+    /// Marks a task as running until the returned guard is dropped.
+    pub fn register_task(&self) -> TaskGuard {
+        self.add_task();
+
+        TaskGuard {
+            shutdown: self.clone(),
+        }
+    }
+
+    /// Resolves once every task registered here or on a child has exited.
+    pub async fn tasks_finished(&self) {
+        future::poll_fn(|cx| self.poll_until(cx, |state| state.tasks == 0)).await
+    }
+
+    fn poll_triggered(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(parent) = &self.parent {
+            if parent.poll_triggered(cx).is_ready() {
+                return Poll::Ready(());
+            }
+        }
+
+        self.poll_until(cx, |state| state.triggered)
+    }
+
+    fn poll_until(&self, cx: &mut Context<'_>, done: impl Fn(&State) -> bool) -> Poll<()> {
+        let mut state = self.lock();
+        if done(&state) {
+            return Poll::Ready(());
+        }
+
+        // Loops poll this on every iteration, keep a single copy of each
+        // waker.
+        if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+
+        Poll::Pending
+    }
+
+    fn add_task(&self) {
+        self.lock().tasks += 1;
+
+        if let Some(parent) = &self.parent {
+            parent.add_task();
+        }
+    }
+
+    fn remove_task(&self) {
+        let wakers = {
+            let mut state = self.lock();
+            state.tasks -= 1;
+            mem::replace(&mut state.wakers, Vec::new())
+        };
+
+        for waker in wakers {
+            waker.wake();
+        }
+
+        if let Some(parent) = &self.parent {
+            parent.remove_task();
+        }
+    }
+
+    /// Released while dropping tasks, so a poisoned lock mustn't panic.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Held by a running task. Dropping it marks the task as exited, whether it
+/// returned or was aborted.
+pub(crate) struct TaskGuard {
+    shutdown: Shutdown,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.shutdown.remove_task();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shutdown;
+    use futures::{
+        executor::block_on,
+        future::{
+            self,
+            Future,
+        },
+        task::noop_waker,
+    };
+    use std::task::Context;
+
+    #[test]
+    fn child_triggered_by_parent() {
+        let parent = Shutdown::new();
+        let child = parent.child();
+
+        child.trigger();
+        assert!(!parent.is_triggered());
+
+        let other = parent.child();
+        parent.trigger();
+        assert!(other.is_triggered());
+        assert_eq!(
+            block_on(other.run_until_triggered(future::pending::<()>())),
+            None
+        );
+    }
+
+    #[test]
+    fn tasks_finished_waits_for_children() {
+        let parent = Shutdown::new();
+        let child = parent.child();
+        let guard = child.register_task();
+
+        let waker = noop_waker();
+        let mut finished = Box::pin(parent.tasks_finished());
+        assert!(finished
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+
+        drop(guard);
+        block_on(finished);
+    }
+}
@@ -0,0 +1,397 @@
+use super::announce_query;
+use futures::future::{
+    self,
+    BoxFuture,
+    FutureExt,
+};
+use krpc_encoding::{
+    Envelope,
+    KRPCError,
+    NodeID,
+    Query,
+    Response,
+};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    net::SocketAddr,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+        Mutex,
+        MutexGuard,
+    },
+    time::Duration,
+};
+use tokio_krpc::{
+    responses::{
+        FindNodeResponse,
+        GetPeersResponse,
+        NodeIDResponse,
+        ResponseEnvelope,
+        SampleInfoHashesResponse,
+    },
+    send_errors::{
+        ErrorKind,
+        Result,
+    },
+    PortType,
+    QueryOptions,
+    Transport,
+};
+
+/// How a [`MockTransport`] answers a query.
+#[derive(Debug)]
+pub enum Reply {
+    Response(Response),
+    Error(KRPCError),
+
+    /// Answers with a whole envelope, like one with an `ip` field.
+    Envelope(Envelope),
+
+    /// Fails the query with a timeout right away.
+    Timeout,
+}
+
+/// A query sent through a [`MockTransport`].
+#[derive(Debug, PartialEq)]
+pub struct Call {
+    pub address: SocketAddr,
+    pub query: Query,
+
+    /// Id the queried node was expected to answer with, see
+    /// [`QueryOptions::expected_id`].
+    pub expected_id: Option<NodeID>,
+}
+
+type Responder = Box<dyn Fn(&Query) -> Reply + Send + Sync>;
+
+/// [`Transport`] answering queries with scripted replies instead of sending
+/// them anywhere. Queries to addresses without a script time out. Every query
+/// and message sent is recorded.
+pub struct MockTransport {
+    responders: Mutex<HashMap<SocketAddr, Responder>>,
+    calls: Mutex<Vec<Call>>,
+    sent: Mutex<Vec<(SocketAddr, Envelope)>>,
+    next_transaction_id: AtomicUsize,
+    shut_down: AtomicBool,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport {
+            responders: Mutex::new(HashMap::new()),
+            calls: Mutex::new(Vec::new()),
+            sent: Mutex::new(Vec::new()),
+            next_transaction_id: AtomicUsize::new(0),
+            shut_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Answers every later query to `address` with what `respond` returns for
+    /// it, replacing an earlier script for the address.
+    pub fn respond<F>(&self, address: SocketAddr, respond: F)
+    where
+        F: Fn(&Query) -> Reply + Send + Sync + 'static,
+    {
+        lock(&self.responders).insert(address, Box::new(respond));
+    }
+
+    /// Takes the queries sent so far, oldest first.
+    pub fn take_calls(&self) -> Vec<Call> {
+        lock(&self.calls).drain(..).collect()
+    }
+
+    /// Takes the messages passed to [`Transport::send`] so far, oldest first.
+    pub fn take_sent(&self) -> Vec<(SocketAddr, Envelope)> {
+        lock(&self.sent).drain(..).collect()
+    }
+
+    fn request(
+        &self,
+        address: SocketAddr,
+        query: Query,
+        expected_id: Option<NodeID>,
+    ) -> Result<Envelope> {
+        if self.shut_down.load(Ordering::SeqCst) {
+            Err(ErrorKind::ShuttingDown)?;
+        }
+
+        let transaction_id = self.next_transaction_id.fetch_add(1, Ordering::Relaxed) as u32;
+        let reply = match lock(&self.responders).get(&address) {
+            Some(respond) => respond(&query),
+            None => Reply::Timeout,
+        };
+        lock(&self.calls).push(Call {
+            address,
+            query,
+            expected_id,
+        });
+
+        match reply {
+            Reply::Response(response) => Ok(Envelope::response(
+                transaction_id.to_be_bytes().to_vec(),
+                response,
+            )),
+            Reply::Envelope(envelope) => Ok(envelope),
+            Reply::Error(error) => Err(ErrorKind::ReceivedKRPCError { error }.into()),
+            Reply::Timeout => Err(ErrorKind::TransactionTimeout {
+                transaction_id,
+                elapsed: Duration::from_secs(0),
+            }
+            .into()),
+        }
+    }
+}
+
+impl Default for MockTransport {
+    fn default() -> MockTransport {
+        MockTransport::new()
+    }
+}
+
+impl Transport for MockTransport {
+    fn ping(&self, id: NodeID, address: SocketAddr) -> BoxFuture<'_, Result<NodeID>> {
+        self.ping_with_options(id, address, QueryOptions::default())
+    }
+
+    /// Records the expected id of the queried node. Other options are
+    /// ignored.
+    fn ping_with_options(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        options: QueryOptions,
+    ) -> BoxFuture<'_, Result<NodeID>> {
+        let result = self
+            .request(address, Query::Ping { id }, options.expected_id)
+            .and_then(only_response)
+            .and_then(NodeIDResponse::from_response);
+
+        future::ready(result).boxed()
+    }
+
+    fn find_node(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+    ) -> BoxFuture<'_, Result<FindNodeResponse>> {
+        self.find_node_with_options(id, address, target, QueryOptions::default())
+    }
+
+    fn find_node_with_options(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+        options: QueryOptions,
+    ) -> BoxFuture<'_, Result<FindNodeResponse>> {
+        let result = self
+            .request(address, Query::FindNode { id, target }, options.expected_id)
+            .and_then(FindNodeResponse::try_from);
+
+        future::ready(result).boxed()
+    }
+
+    fn get_peers(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        info_hash: NodeID,
+    ) -> BoxFuture<'_, Result<GetPeersResponse>> {
+        self.get_peers_with_options(id, address, info_hash, QueryOptions::default())
+    }
+
+    fn get_peers_with_options(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        info_hash: NodeID,
+        options: QueryOptions,
+    ) -> BoxFuture<'_, Result<GetPeersResponse>> {
+        let result = self
+            .request(
+                address,
+                Query::GetPeers { id, info_hash },
+                options.expected_id,
+            )
+            .and_then(GetPeersResponse::try_from);
+
+        future::ready(result).boxed()
+    }
+
+    fn announce_peer(
+        &self,
+        id: NodeID,
+        token: Vec<u8>,
+        address: SocketAddr,
+        info_hash: NodeID,
+        port_type: PortType,
+    ) -> BoxFuture<'_, Result<NodeID>> {
+        let result = self
+            .request(
+                address,
+                announce_query(id, token, info_hash, port_type),
+                None,
+            )
+            .and_then(only_response)
+            .and_then(NodeIDResponse::from_response);
+
+        future::ready(result).boxed()
+    }
+
+    fn sample_infohashes(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+    ) -> BoxFuture<'_, Result<SampleInfoHashesResponse>> {
+        let result = self
+            .request(address, Query::SampleInfoHashes { id, target }, None)
+            .and_then(SampleInfoHashesResponse::try_from);
+
+        future::ready(result).boxed()
+    }
+
+    fn send(&self, address: SocketAddr, message: Envelope) -> BoxFuture<'_, Result<()>> {
+        lock(&self.sent).push((address, message));
+
+        future::ready(Ok(())).boxed()
+    }
+
+    fn shutdown(&self) {
+        self.shut_down.store(true, Ordering::SeqCst);
+    }
+}
+
+fn only_response(envelope: Envelope) -> Result<Response> {
+    let (response, _) = ResponseEnvelope::split(envelope)?;
+
+    Ok(response)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Call,
+        MockTransport,
+        Reply,
+    };
+    use crate::Dht;
+    use failure::Error;
+    use krpc_encoding::{
+        KRPCError,
+        NodeID,
+        NodeInfo,
+        Query,
+        Response,
+    };
+    use std::sync::Arc;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_krpc::{
+        send_errors::ErrorKind,
+        Transport,
+    };
+
+    #[test]
+    fn scripted_replies() -> Result<(), Error> {
+        let mock = MockTransport::new();
+        let server_id = NodeID::random();
+        let answering = "10.0.0.1:6881".parse()?;
+        let refusing = "10.0.0.2:6881".parse()?;
+        let silent = "10.0.0.3:6881".parse()?;
+        {
+            let server_id = server_id.clone();
+            mock.respond(answering, move |_| {
+                Reply::Response(Response::OnlyID {
+                    id: server_id.clone(),
+                })
+            });
+        }
+        mock.respond(refusing, |_| {
+            Reply::Error(KRPCError::new(0xcc, "Invalid Query"))
+        });
+
+        let own_id = NodeID::random();
+        let mut runtime = Runtime::new()?;
+        let mut ping = |address| runtime.block_on(mock.ping(own_id.clone(), address));
+
+        assert_eq!(ping(answering)?, server_id);
+        match ping(refusing).unwrap_err().kind() {
+            ErrorKind::ReceivedKRPCError { .. } => {}
+            kind => panic!("unexpected error {}", kind),
+        };
+        match ping(silent).unwrap_err().kind() {
+            ErrorKind::TransactionTimeout { .. } => {}
+            kind => panic!("unexpected error {}", kind),
+        };
+
+        let calls = mock.take_calls();
+        let addresses: Vec<_> = calls.iter().map(|call| call.address).collect();
+        assert_eq!(addresses, vec![answering, refusing, silent]);
+        assert_eq!(
+            calls[0],
+            Call {
+                address: answering,
+                query: Query::Ping { id: own_id },
+                expected_id: None,
+            }
+        );
+        assert!(mock.take_calls().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn bootstrap_through_mock() -> Result<(), Error> {
+        let mock = Arc::new(MockTransport::new());
+        let seed = NodeInfo::new(NodeID::random(), "10.0.0.1:6881".parse()?);
+        let found = NodeInfo::new(NodeID::random(), "10.0.1.1:6881".parse()?);
+        {
+            let (seed_id, found) = (seed.node_id.clone(), found.clone());
+            mock.respond(seed.address.into(), move |_| {
+                Reply::Response(Response::NextHop {
+                    id: seed_id.clone(),
+                    token: None,
+                    nodes: vec![found.clone()],
+                })
+            });
+        }
+        {
+            let found_id = found.node_id.clone();
+            mock.respond(found.address.into(), move |_| {
+                Reply::Response(Response::NextHop {
+                    id: found_id.clone(),
+                    token: None,
+                    nodes: Vec::new(),
+                })
+            });
+        }
+
+        let dht = Dht::with_transport(NodeID::random(), mock.clone());
+        let mut runtime = Runtime::new()?;
+        let closest = runtime.block_on(dht.bootstrap_from(&[seed.address]))?;
+
+        assert!(closest.contains(&found));
+        let calls = mock.take_calls();
+        assert!(calls.iter().all(|call| match &call.query {
+            Query::FindNode { id, target } => *id == dht.id() && *target == dht.id(),
+            _ => false,
+        }));
+        assert!(calls
+            .iter()
+            .any(|call| call.address == found.address.into()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+//! Harness for running many DHT nodes in a single process, for integration
+//! tests and examples. Enabled with the `testing` feature. See
+//! `examples/simulated_network.rs`. [`MockTransport`] answers queries of a
+//! single node with scripted replies instead.
+
+mod mock;
+mod network;
+
+pub use self::{
+    mock::{
+        Call,
+        MockTransport,
+        Reply,
+    },
+    network::{
+        Behavior,
+        Latency,
+        NetworkConfig,
+        SimulatedNetwork,
+        SIMULATED_PORT,
+    },
+};
+use krpc_encoding::{
+    NodeID,
+    Query,
+};
+use tokio_krpc::PortType;
+
+fn announce_query(id: NodeID, token: Vec<u8>, info_hash: NodeID, port_type: PortType) -> Query {
+    let (port, implied_port) = match port_type {
+        PortType::Implied => (None, true),
+        PortType::Port(port) => (Some(port), false),
+    };
+
+    Query::AnnouncePeer {
+        id,
+        token,
+        info_hash,
+        port,
+        implied_port,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        NetworkConfig,
+        SimulatedNetwork,
+    };
+    use crate::Dht;
+    use failure::Error;
+    use krpc_encoding::NodeID;
+    use rand::{
+        rngs::StdRng,
+        seq::sample_slice,
+        SeedableRng,
+    };
+    use std::{
+        cmp,
+        net::SocketAddrV4,
+    };
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_krpc::PortType;
+
+    #[test]
+    fn lookup_finds_announced_peer() -> Result<(), Error> {
+        let network = SimulatedNetwork::new(NetworkConfig::default());
+        let nodes: Vec<Dht> = (0..100).map(|_| network.add_node()).collect();
+        let addresses = network.addresses();
+        let mut rng = StdRng::from_seed([3; 32]);
+        let mut runtime = Runtime::new()?;
+
+        // Every node bootstraps off a few of the nodes which joined before it.
+        for (idx, dht) in nodes.iter().enumerate().skip(1) {
+            let seeds = sample_slice(&mut rng, &addresses[..idx], cmp::min(idx, 3));
+            runtime.block_on(dht.bootstrap_from(&seeds))?;
+        }
+
+        let info_hash = NodeID::random();
+        runtime.block_on(nodes[10].announce(info_hash.clone(), PortType::Port(51413)))?;
+        let peers = runtime.block_on(nodes[90].get_peers(info_hash))?;
+
+        assert_eq!(peers, vec![SocketAddrV4::new(*addresses[10].ip(), 51413)]);
+
+        Ok(())
+    }
+}
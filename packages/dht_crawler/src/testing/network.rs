@@ -0,0 +1,577 @@
+use super::announce_query;
+use crate::dht::Dht;
+use futures::future::{
+    self,
+    BoxFuture,
+    FutureExt,
+};
+use krpc_encoding::{
+    Envelope,
+    Message,
+    NodeID,
+    NodeInfo,
+    Query,
+    Response,
+};
+use rand::{
+    rngs::StdRng,
+    Rng,
+    SeedableRng,
+};
+use std::{
+    collections::HashMap,
+    net::{
+        Ipv4Addr,
+        SocketAddr,
+        SocketAddrV4,
+    },
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+        MutexGuard,
+        Weak,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::timer::Delay;
+use tokio_krpc::{
+    responses::{
+        FindNodeResponse,
+        GetPeersResponse,
+        NodeIDResponse,
+        SampleInfoHashesResponse,
+    },
+    send_errors::{
+        ErrorKind,
+        Result,
+    },
+    InboundQuery,
+    PortType,
+    Transport,
+};
+
+/// Port every simulated node is reachable on.
+pub const SIMULATED_PORT: u16 = 6881;
+
+/// One way delay of datagrams.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+
+    /// Uniformly distributed between `min` and `max`.
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+}
+
+impl Latency {
+    fn sample(self, rng: &mut StdRng) -> Duration {
+        match self {
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform { min, max } if max > min => {
+                let spread = (max - min).as_micros() as u64;
+                min + Duration::from_micros(rng.gen_range(0, spread + 1))
+            }
+            Latency::Uniform { min, .. } => min,
+        }
+    }
+}
+
+/// How a simulated node answers queries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Behavior {
+    /// Answers like any other node.
+    Honest,
+
+    /// Never answers.
+    Silent,
+
+    /// Answers every query with a random id and random nodes.
+    WrongResponses,
+
+    /// Answers with truncated bencode which can't be decoded.
+    Malformed,
+}
+
+/// Parameters of a [`SimulatedNetwork`].
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    /// Probability of each datagram being lost.
+    pub packet_loss: f64,
+
+    pub latency: Latency,
+
+    /// Time after which a query without a response fails with
+    /// [`ErrorKind::TransactionTimeout`].
+    pub query_timeout: Duration,
+
+    /// Seeds the choice of node ids, lost datagrams and latencies.
+    pub seed: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig {
+            packet_loss: 0.0,
+            latency: Latency::Fixed(Duration::from_millis(1)),
+            query_timeout: Duration::from_secs(1),
+            seed: 0,
+        }
+    }
+}
+
+/// DHT nodes in a single process, exchanging messages through the network
+/// instead of UDP sockets. Every node is a [`Dht`] answering queries with the
+/// same handlers as a real one, so lookups, crawlers and maintenance tasks
+/// run against it unmodified.
+///
+/// Timers of the tokio runtime polling the nodes simulate latency and
+/// timeouts.
+#[derive(Clone)]
+pub struct SimulatedNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+struct NetworkState {
+    config: NetworkConfig,
+    rng: StdRng,
+    nodes: Vec<SimulatedNode>,
+    by_address: HashMap<SocketAddrV4, usize>,
+}
+
+struct SimulatedNode {
+    address: SocketAddrV4,
+    dht: Dht,
+    behavior: Behavior,
+}
+
+/// Outcome of sending a query.
+enum Delivery {
+    /// The query or its response was lost, or was never sent.
+    Lost,
+
+    /// Encoded response arriving `delay` after the query was sent.
+    Response { delay: Duration, bytes: Vec<u8> },
+}
+
+impl SimulatedNetwork {
+    pub fn new(config: NetworkConfig) -> SimulatedNetwork {
+        let mut seed = [0u8; 32];
+        seed[..8].copy_from_slice(&config.seed.to_le_bytes());
+
+        SimulatedNetwork {
+            state: Arc::new(Mutex::new(NetworkState {
+                config,
+                rng: StdRng::from_seed(seed),
+                nodes: Vec::new(),
+                by_address: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Adds an honest node with a random id. Its routing table starts out
+    /// empty, see [`Dht::bootstrap_from`].
+    pub fn add_node(&self) -> Dht {
+        self.add_node_with_behavior(Behavior::Honest)
+    }
+
+    pub fn add_node_with_behavior(&self, behavior: Behavior) -> Dht {
+        let mut state = self.lock();
+        let index = state.nodes.len();
+        // Every node gets a /24 subnet of its own, so routing tables don't
+        // limit how many of them they keep.
+        let address = SocketAddrV4::new(
+            Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 1)) + ((index as u32) << 8)),
+            SIMULATED_PORT,
+        );
+
+        let transport = SimulatedTransport::new(address, &self.state, state.config.query_timeout);
+        let id = NodeID::from(state.rng.gen::<[u8; 20]>());
+        let dht = Dht::with_transport(id, Arc::new(transport));
+        dht.set_external_address(address);
+
+        state.by_address.insert(address, index);
+        state.nodes.push(SimulatedNode {
+            address,
+            dht: dht.clone(),
+            behavior,
+        });
+
+        dht
+    }
+
+    /// Changes how the node at `address` answers later queries.
+    pub fn set_behavior(&self, address: SocketAddrV4, behavior: Behavior) {
+        let mut state = self.lock();
+        if let Some(&index) = state.by_address.get(&address) {
+            state.nodes[index].behavior = behavior;
+        }
+    }
+
+    /// Addresses of the nodes in the order they were added.
+    pub fn addresses(&self) -> Vec<SocketAddrV4> {
+        self.lock().nodes.iter().map(|node| node.address).collect()
+    }
+
+    pub fn node(&self, address: SocketAddrV4) -> Option<Dht> {
+        let state = self.lock();
+        let index = *state.by_address.get(&address)?;
+
+        Some(state.nodes[index].dht.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().nodes.is_empty()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, NetworkState> {
+        lock(&self.state)
+    }
+}
+
+/// The network is only locked for short synchronous sections. Poisoning
+/// doesn't leave it inconsistent.
+fn lock(state: &Mutex<NetworkState>) -> MutexGuard<'_, NetworkState> {
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl NetworkState {
+    /// Delivers `query` from `from` to the node at `to` and returns what
+    /// comes back.
+    fn deliver(
+        &mut self,
+        from: SocketAddrV4,
+        to: SocketAddrV4,
+        transaction_id: u32,
+        query: Query,
+    ) -> Delivery {
+        let (dht, behavior) = match self.by_address.get(&to) {
+            Some(&index) => (self.nodes[index].dht.clone(), self.nodes[index].behavior),
+            None => return Delivery::Lost,
+        };
+
+        if behavior == Behavior::Silent || self.lost() {
+            return Delivery::Lost;
+        }
+
+        let request = InboundQuery::new(transaction_id.to_be_bytes().to_vec(), query, false);
+        let mut envelope = dht.handle_request(request, from);
+        if behavior == Behavior::WrongResponses {
+            envelope.message_type = Message::Response {
+                response: self.wrong_response(),
+            };
+        }
+
+        let mut bytes = match envelope.encode() {
+            Ok(bytes) => bytes,
+            Err(_) => return Delivery::Lost,
+        };
+        if behavior == Behavior::Malformed {
+            bytes.truncate(bytes.len() / 2);
+        }
+
+        if self.lost() {
+            return Delivery::Lost;
+        }
+
+        let latency = self.config.latency;
+        let delay = latency.sample(&mut self.rng) + latency.sample(&mut self.rng);
+
+        Delivery::Response { delay, bytes }
+    }
+
+    fn lost(&mut self) -> bool {
+        self.config.packet_loss > 0.0 && self.rng.gen::<f64>() < self.config.packet_loss
+    }
+
+    /// Response with a random id pointing at random nodes.
+    fn wrong_response(&mut self) -> Response {
+        let rng = &mut self.rng;
+        let nodes = (0..8)
+            .map(|_| {
+                NodeInfo::new(
+                    NodeID::from(rng.gen::<[u8; 20]>()),
+                    SocketAddrV4::new(Ipv4Addr::from(rng.gen::<u32>()), rng.gen()),
+                )
+            })
+            .collect();
+
+        Response::NextHop {
+            id: NodeID::from(rng.gen::<[u8; 20]>()),
+            token: None,
+            nodes,
+        }
+    }
+}
+
+/// [`Transport`] of a simulated node.
+struct SimulatedTransport {
+    address: SocketAddrV4,
+    network: Weak<Mutex<NetworkState>>,
+    query_timeout: Duration,
+    next_transaction_id: AtomicUsize,
+    shut_down: AtomicBool,
+}
+
+impl SimulatedTransport {
+    fn new(
+        address: SocketAddrV4,
+        network: &Arc<Mutex<NetworkState>>,
+        query_timeout: Duration,
+    ) -> SimulatedTransport {
+        SimulatedTransport {
+            address,
+            network: Arc::downgrade(network),
+            query_timeout,
+            next_transaction_id: AtomicUsize::new(0),
+            shut_down: AtomicBool::new(false),
+        }
+    }
+
+    async fn request(&self, address: SocketAddr, query: Query) -> Result<Response> {
+        if self.shut_down.load(Ordering::SeqCst) {
+            Err(ErrorKind::ShuttingDown)?;
+        }
+
+        let transaction_id = self.next_transaction_id.fetch_add(1, Ordering::Relaxed) as u32;
+        let started = Instant::now();
+        let delivery = match (self.network.upgrade(), address) {
+            (Some(network), SocketAddr::V4(to)) => {
+                lock(&network).deliver(self.address, to, transaction_id, query)
+            }
+            _ => Delivery::Lost,
+        };
+
+        if let Delivery::Response { delay, bytes } = delivery {
+            if delay < self.query_timeout {
+                Delay::new(started + delay).await;
+
+                // Undecodable responses are dropped, like the real transport
+                // does.
+                if let Ok(envelope) = Envelope::decode(&bytes) {
+                    match envelope.message_type {
+                        Message::Response { response } => return Ok(response),
+                        Message::Error { error } => {
+                            return Err(ErrorKind::ReceivedKRPCError { error }.into())
+                        }
+                        Message::Query { .. } => {}
+                    };
+                }
+            }
+        }
+
+        Delay::new(started + self.query_timeout).await;
+
+        Err(ErrorKind::TransactionTimeout {
+            transaction_id,
+            elapsed: started.elapsed(),
+        }
+        .into())
+    }
+}
+
+impl Transport for SimulatedTransport {
+    fn ping(&self, id: NodeID, address: SocketAddr) -> BoxFuture<'_, Result<NodeID>> {
+        async move {
+            let response = self.request(address, Query::Ping { id }).await?;
+            NodeIDResponse::from_response(response)
+        }
+        .boxed()
+    }
+
+    fn find_node(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+    ) -> BoxFuture<'_, Result<FindNodeResponse>> {
+        async move {
+            let response = self
+                .request(address, Query::FindNode { id, target })
+                .await?;
+            FindNodeResponse::from_response(response)
+        }
+        .boxed()
+    }
+
+    fn get_peers(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        info_hash: NodeID,
+    ) -> BoxFuture<'_, Result<GetPeersResponse>> {
+        async move {
+            let response = self
+                .request(address, Query::GetPeers { id, info_hash })
+                .await?;
+            GetPeersResponse::from_response(response)
+        }
+        .boxed()
+    }
+
+    fn announce_peer(
+        &self,
+        id: NodeID,
+        token: Vec<u8>,
+        address: SocketAddr,
+        info_hash: NodeID,
+        port_type: PortType,
+    ) -> BoxFuture<'_, Result<NodeID>> {
+        let query = announce_query(id, token, info_hash, port_type);
+
+        async move { NodeIDResponse::from_response(self.request(address, query).await?) }.boxed()
+    }
+
+    fn sample_infohashes(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+    ) -> BoxFuture<'_, Result<SampleInfoHashesResponse>> {
+        async move {
+            let response = self
+                .request(address, Query::SampleInfoHashes { id, target })
+                .await?;
+            SampleInfoHashesResponse::from_response(response)
+        }
+        .boxed()
+    }
+
+    /// Queries are answered by the network directly so there is never
+    /// anything to send.
+    fn send(&self, _address: SocketAddr, _message: Envelope) -> BoxFuture<'_, Result<()>> {
+        future::ready(Ok(())).boxed()
+    }
+
+    fn shutdown(&self) {
+        self.shut_down.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Behavior,
+        NetworkConfig,
+        SimulatedNetwork,
+        SimulatedTransport,
+    };
+    use failure::Error;
+    use krpc_encoding::NodeID;
+    use std::time::Duration;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_krpc::{
+        send_errors::ErrorKind,
+        Transport,
+    };
+
+    fn config() -> NetworkConfig {
+        NetworkConfig {
+            query_timeout: Duration::from_millis(50),
+            ..NetworkConfig::default()
+        }
+    }
+
+    /// Transport of a client outside the network.
+    fn client(network: &SimulatedNetwork) -> SimulatedTransport {
+        SimulatedTransport::new(
+            "192.168.0.1:6881".parse().unwrap(),
+            &network.state,
+            Duration::from_millis(50),
+        )
+    }
+
+    #[test]
+    fn honest_nodes_answer() -> Result<(), Error> {
+        let network = SimulatedNetwork::new(config());
+        let server = network.add_node();
+        let addresses = network.addresses();
+
+        let transport = client(&network);
+        let mut runtime = Runtime::new()?;
+        let id = runtime.block_on(transport.ping(NodeID::random(), addresses[0].into()))?;
+
+        assert_eq!(id, server.id());
+
+        Ok(())
+    }
+
+    #[test]
+    fn misbehaving_nodes() -> Result<(), Error> {
+        let network = SimulatedNetwork::new(config());
+        for behavior in &[
+            Behavior::Silent,
+            Behavior::Malformed,
+            Behavior::WrongResponses,
+        ] {
+            network.add_node_with_behavior(*behavior);
+        }
+        let addresses = network.addresses();
+
+        let transport = client(&network);
+        let mut runtime = Runtime::new()?;
+        let mut ping = |idx: usize| {
+            runtime
+                .block_on(transport.ping(NodeID::random(), addresses[idx].into()))
+                .unwrap_err()
+        };
+
+        for idx in 0..=1 {
+            match ping(idx).kind() {
+                ErrorKind::TransactionTimeout { .. } => {}
+                kind => panic!("unexpected error {}", kind),
+            };
+        }
+        match ping(2).kind() {
+            ErrorKind::InvalidResponseType { .. } => {}
+            kind => panic!("unexpected error {}", kind),
+        };
+
+        network.set_behavior(addresses[2], Behavior::Honest);
+        assert!(runtime
+            .block_on(transport.ping(NodeID::random(), addresses[2].into()))
+            .is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn lost_packets_time_out() -> Result<(), Error> {
+        let network = SimulatedNetwork::new(NetworkConfig {
+            packet_loss: 1.0,
+            ..config()
+        });
+        network.add_node();
+        let addresses = network.addresses();
+
+        let transport = client(&network);
+        let mut runtime = Runtime::new()?;
+        let err = runtime
+            .block_on(transport.ping(NodeID::random(), addresses[0].into()))
+            .unwrap_err();
+
+        match err.kind() {
+            ErrorKind::TransactionTimeout { elapsed, .. } => {
+                assert!(*elapsed >= Duration::from_millis(50))
+            }
+            kind => panic!("unexpected error {}", kind),
+        };
+
+        Ok(())
+    }
+}
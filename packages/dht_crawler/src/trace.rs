@@ -0,0 +1,269 @@
+//! Spans and events emitted through `tracing` when the `trace` feature is
+//! enabled. Lookups, announces and bootstraps each run in a span holding
+//! their target, routing table changes are events and the transactions
+//! they send are described by events from `tokio_krpc`. Without the feature
+//! the macros expand to nothing and [`Span`] is a placeholder, so call sites
+//! don't need to be feature gated. Ids are written in hex.
+
+use futures::Future;
+#[cfg(feature = "trace")]
+use std::{
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+#[cfg(feature = "trace")]
+pub(crate) use tracing::Span;
+
+#[cfg(not(feature = "trace"))]
+#[derive(Clone, Debug)]
+pub(crate) struct Span;
+
+/// Creates the span of a high level operation.
+#[cfg(feature = "trace")]
+macro_rules! operation_span {
+    ($($args:tt)*) => {
+        tracing::debug_span!($($args)*)
+    };
+}
+
+#[cfg(not(feature = "trace"))]
+macro_rules! operation_span {
+    ($($args:tt)*) => {
+        crate::trace::Span
+    };
+}
+
+/// Emits an event in the current span.
+#[cfg(feature = "trace")]
+macro_rules! trace_event {
+    ($($args:tt)*) => {
+        tracing::debug!($($args)*)
+    };
+}
+
+#[cfg(not(feature = "trace"))]
+macro_rules! trace_event {
+    ($($args:tt)*) => {};
+}
+
+/// Runs `future` in `span`, entering it every time the future is polled.
+#[cfg(feature = "trace")]
+pub(crate) fn instrument<F: Future>(future: F, span: Span) -> Instrumented<F> {
+    Instrumented { future, span }
+}
+
+#[cfg(not(feature = "trace"))]
+pub(crate) fn instrument<F: Future>(future: F, _span: Span) -> F {
+    future
+}
+
+#[cfg(feature = "trace")]
+pub(crate) struct Instrumented<F> {
+    future: F,
+    span: Span,
+}
+
+#[cfg(feature = "trace")]
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // The future is never moved out of `self`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let _entered = this.span.enter();
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        future.poll(cx)
+    }
+}
+
+#[cfg(all(test, feature = "trace"))]
+mod tests {
+    use crate::{
+        testing::{
+            MockTransport,
+            Reply,
+        },
+        Dht,
+    };
+    use failure::Error;
+    use krpc_encoding::{
+        NodeID,
+        NodeInfo,
+        Response,
+    };
+    use std::{
+        collections::HashMap,
+        fmt,
+        sync::{
+            atomic::{
+                AtomicU64,
+                Ordering,
+            },
+            Arc,
+            Mutex,
+        },
+    };
+    use tokio::runtime::current_thread::Runtime;
+    use tracing::{
+        field::{
+            Field,
+            Visit,
+        },
+        span,
+        Event,
+        Metadata,
+        Subscriber,
+    };
+
+    type Fields = HashMap<String, String>;
+
+    struct FieldVisitor<'a>(&'a mut Fields);
+
+    impl<'a> Visit for FieldVisitor<'a> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    #[derive(Debug)]
+    struct RecordedEvent {
+        fields: Fields,
+
+        /// Names of the spans entered when the event was emitted, outermost
+        /// first.
+        spans: Vec<&'static str>,
+    }
+
+    #[derive(Default)]
+    struct Recording {
+        spans: HashMap<u64, (&'static str, Fields)>,
+        entered: Vec<u64>,
+        events: Vec<RecordedEvent>,
+    }
+
+    /// Records every span and event on a single thread.
+    struct Recorder {
+        next_id: AtomicU64,
+        recording: Arc<Mutex<Recording>>,
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attributes: &span::Attributes<'_>) -> span::Id {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let mut fields = Fields::new();
+            attributes.record(&mut FieldVisitor(&mut fields));
+
+            self.recording
+                .lock()
+                .unwrap()
+                .spans
+                .insert(id, (attributes.metadata().name(), fields));
+
+            span::Id::from_u64(id)
+        }
+
+        fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields::new();
+            event.record(&mut FieldVisitor(&mut fields));
+
+            let mut recording = self.recording.lock().unwrap();
+            let spans = recording
+                .entered
+                .iter()
+                .map(|id| recording.spans[id].0)
+                .collect();
+            recording.events.push(RecordedEvent { fields, spans });
+        }
+
+        fn enter(&self, span: &span::Id) {
+            self.recording.lock().unwrap().entered.push(span.into_u64());
+        }
+
+        fn exit(&self, _span: &span::Id) {
+            self.recording.lock().unwrap().entered.pop();
+        }
+    }
+
+    #[test]
+    fn bootstrap_spans() -> Result<(), Error> {
+        let mock = Arc::new(MockTransport::new());
+        let seed = NodeInfo::new(NodeID::random(), "10.0.0.1:6881".parse()?);
+        let found = NodeInfo::new(NodeID::random(), "10.0.1.1:6881".parse()?);
+        {
+            let (seed_id, found) = (seed.node_id.clone(), found.clone());
+            mock.respond(seed.address.into(), move |_| {
+                Reply::Response(Response::NextHop {
+                    id: seed_id.clone(),
+                    token: None,
+                    nodes: vec![found.clone()],
+                })
+            });
+        }
+        {
+            let found_id = found.node_id.clone();
+            mock.respond(found.address.into(), move |_| {
+                Reply::Response(Response::NextHop {
+                    id: found_id.clone(),
+                    token: None,
+                    nodes: Vec::new(),
+                })
+            });
+        }
+
+        let dht = Dht::with_transport(NodeID::random(), mock);
+        let recording = Arc::new(Mutex::new(Recording::default()));
+        let recorder = Recorder {
+            next_id: AtomicU64::new(1),
+            recording: recording.clone(),
+        };
+        let mut runtime = Runtime::new()?;
+        tracing::subscriber::with_default(recorder, || {
+            runtime.block_on(dht.bootstrap_from(&[seed.address]))
+        })?;
+
+        let recording = recording.lock().unwrap();
+        let span_fields = |name| {
+            recording
+                .spans
+                .values()
+                .find(|(span_name, _)| *span_name == name)
+                .map(|(_, fields)| fields)
+                .unwrap_or_else(|| panic!("no {} span", name))
+        };
+        assert_eq!(span_fields("bootstrap")["seeds"], "1");
+        assert_eq!(span_fields("lookup")["target"], dht.id().to_string());
+
+        let added = |id: &NodeID| {
+            recording
+                .events
+                .iter()
+                .find(|event| {
+                    event.fields["message"] == "add node" && event.fields["id"] == id.to_string()
+                })
+                .unwrap_or_else(|| panic!("{} wasn't added", id))
+        };
+        assert_eq!(added(&seed.node_id).spans, vec!["bootstrap"]);
+        assert_eq!(added(&seed.node_id).fields["added"], "true");
+        assert_eq!(added(&found.node_id).spans, vec!["bootstrap", "lookup"]);
+        assert!(recording.entered.is_empty());
+
+        Ok(())
+    }
+}
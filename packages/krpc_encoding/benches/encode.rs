@@ -0,0 +1,49 @@
+use criterion::{
+    criterion_group,
+    criterion_main,
+    Criterion,
+};
+use krpc_encoding::{
+    Envelope,
+    Message,
+    NodeID,
+    Query,
+};
+
+fn find_node() -> Envelope {
+    Envelope {
+        ip: None,
+        transaction_id: vec![0, 0, 175, 218],
+        version: None,
+        message_type: Message::Query {
+            query: Query::FindNode {
+                id: NodeID::random(),
+                target: NodeID::random(),
+            },
+        },
+        read_only: false,
+    }
+}
+
+fn serde_encode(c: &mut Criterion) {
+    let envelope = find_node();
+
+    c.bench_function("find_node encode", move |b| {
+        b.iter(|| envelope.encode().unwrap())
+    });
+}
+
+fn fast_encode(c: &mut Criterion) {
+    let envelope = find_node();
+    let mut buf = Vec::with_capacity(128);
+
+    c.bench_function("find_node encode_into", move |b| {
+        b.iter(|| {
+            buf.clear();
+            envelope.encode_into(&mut buf).unwrap();
+        })
+    });
+}
+
+criterion_group!(benches, serde_encode, fast_encode);
+criterion_main!(benches);
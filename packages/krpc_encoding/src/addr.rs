@@ -25,9 +25,9 @@ use std::{
 
 /// Contact information for a BitTorrent peer
 ///
-/// Implements "Compact IP-address/port info" serialization and
-/// de-serialization.
-#[derive(Eq, PartialEq, Debug)]
+/// Serializes as an `"ip:port"` string. The "Compact IP-address/port info"
+/// format used in messages is implemented by [`Compact`].
+#[derive(Eq, PartialEq, Hash, Debug, Clone, Copy)]
 pub struct Addr(SocketAddrV4);
 
 impl Deref for Addr {
@@ -85,12 +85,20 @@ pub fn from_bytes(v: &[u8]) -> SocketAddrV4 {
     SocketAddrV4::new(ip, port)
 }
 
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Serializes as an `"ip:port"` string. Messages use the compact format from
+/// [`compact`] instead.
 impl Serialize for Addr {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.serialize_bytes(&to_bytes(&self.0))
+        serializer.collect_str(&self.0)
     }
 }
 
@@ -99,14 +107,38 @@ impl<'de> Deserialize<'de> for Addr {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_bytes(NodeInfoVisitor)
+        let encoded = String::deserialize(deserializer)?;
+
+        encoded.parse().map_err(de::Error::custom)
     }
 }
 
-struct NodeInfoVisitor;
+/// Wrapper implementing "Compact IP-address/port info" serialization.
+#[derive(Debug, PartialEq)]
+pub struct Compact(pub Addr);
 
-impl<'de> Visitor<'de> for NodeInfoVisitor {
-    type Value = Addr;
+impl Serialize for Compact {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&to_bytes(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Compact {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(CompactVisitor)
+    }
+}
+
+struct CompactVisitor;
+
+impl<'de> Visitor<'de> for CompactVisitor {
+    type Value = Compact;
 
     fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
         formatter.write_str("a byte array of size 6")
@@ -121,13 +153,76 @@ impl<'de> Visitor<'de> for NodeInfoVisitor {
             return Err(de::Error::invalid_length(len, &self));
         }
 
-        Ok(Addr(from_bytes(v)))
+        Ok(Compact(Addr(from_bytes(v))))
+    }
+}
+
+/// Serializes an optional [`Addr`] in the compact format. Use with
+/// `#[serde(default, with = "addr::compact_option")]`.
+pub mod compact_option {
+    use super::{
+        Addr,
+        Compact,
+    };
+    use serde::{
+        Deserialize,
+        Deserializer,
+        Serialize,
+        Serializer,
+    };
+
+    pub fn serialize<S>(addr: &Option<Addr>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        addr.map(Compact).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Addr>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<Compact>::deserialize(deserializer)?.map(|compact| compact.0))
+    }
+}
+
+/// Serializes a list of [`Addr`] in the compact format, one byte string per
+/// address.
+pub mod compact_vec {
+    use super::{
+        Addr,
+        Compact,
+    };
+    use serde::{
+        Deserialize,
+        Deserializer,
+        Serializer,
+    };
+
+    pub fn serialize<S>(addrs: &Vec<Addr>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(addrs.iter().cloned().map(Compact))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Addr>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<Compact>::deserialize(deserializer)?
+            .into_iter()
+            .map(|compact| compact.0)
+            .collect())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Addr;
+    use super::{
+        Addr,
+        Compact,
+    };
     use serde_test::{
         assert_tokens,
         Token,
@@ -137,15 +232,26 @@ mod tests {
         SocketAddrV4,
     };
 
+    fn addr() -> Addr {
+        Addr::from(SocketAddrV4::new(Ipv4Addr::new(129, 21, 60, 66), 12019))
+    }
+
     #[test]
     fn serde() {
-        let addr = Ipv4Addr::new(129, 21, 60, 66);
-        let port = 12019;
-        let socket_addr = SocketAddrV4::new(addr, port);
+        assert_tokens(&addr(), &[Token::Str("129.21.60.66:12019")]);
+    }
 
+    #[test]
+    fn serde_compact() {
         assert_tokens(
-            &Addr::from(socket_addr),
+            &Compact(addr()),
             &[Token::Bytes(&[129, 21, 60, 66, 0x2e, 0xf3])],
         );
     }
+
+    #[test]
+    fn display() {
+        assert_eq!(addr().to_string(), "129.21.60.66:12019");
+        assert_eq!("129.21.60.66:12019".parse::<Addr>().unwrap(), addr());
+    }
 }
@@ -1,36 +1,15 @@
-use serde::{
-    de::{
-        self,
-        Visitor,
-    },
-    Deserializer,
-};
-use std::fmt;
+use crate::lenient;
+use serde::Deserializer;
 
 pub fn is_false(b: &bool) -> bool {
     return !b;
 }
 
+/// Deserializes `1` as `true` and anything else as `false`. Accepts both
+/// integers and strings of digits.
 pub fn deserialize<'de, D>(deserializer: D) -> Result<bool, D::Error>
 where
     D: Deserializer<'de>,
 {
-    deserializer.deserialize_i64(BooleanVisitor)
-}
-
-struct BooleanVisitor;
-
-impl<'de> Visitor<'de> for BooleanVisitor {
-    type Value = bool;
-
-    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
-        formatter.write_str("a number")
-    }
-
-    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
-    where
-        E: de::Error,
-    {
-        Ok(v == 1)
-    }
+    Ok(lenient::deserialize::<_, i64>(deserializer)? == 1)
 }
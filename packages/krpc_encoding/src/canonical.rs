@@ -0,0 +1,278 @@
+//! Checks that a bencoded value is in its one canonical form. Encodings which
+//! decode to the same value, like integers with leading zeros or
+//! dictionaries with duplicate keys, are decoded differently by different
+//! parsers, so nodes caching what they decode can be made to disagree about
+//! the same bytes.
+
+use crate::errors::{
+    ErrorKind,
+    Result,
+};
+use std::fmt;
+
+/// Deepest nesting of lists and dictionaries accepted.
+const MAX_DEPTH: usize = 32;
+
+/// Way in which bencoded bytes aren't canonical.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// An integer or a string length starts with a zero.
+    LeadingZero,
+
+    /// The integer `-0`.
+    NegativeZero,
+
+    /// A dictionary key appears more than once.
+    DuplicateKey,
+
+    /// A dictionary key is ordered before the key preceding it.
+    UnsortedKeys,
+
+    /// Lists and dictionaries are nested deeper than [`MAX_DEPTH`].
+    TooDeep,
+
+    /// Bytes after the end of the value.
+    TrailingData,
+
+    /// Not valid bencode at all.
+    Malformed,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Violation::LeadingZero => "leading zero",
+            Violation::NegativeZero => "negative zero",
+            Violation::DuplicateKey => "duplicate dictionary key",
+            Violation::UnsortedKeys => "unsorted dictionary keys",
+            Violation::TooDeep => "nested too deep",
+            Violation::TrailingData => "trailing data",
+            Violation::Malformed => "malformed bencode",
+        };
+
+        f.write_str(description)
+    }
+}
+
+/// Fails with [`ErrorKind::NonCanonicalEncoding`] naming the first violation
+/// and its byte offset unless `bytes` is exactly one canonically encoded
+/// value.
+pub fn check_canonical(bytes: &[u8]) -> Result<()> {
+    let end = check_value(bytes, 0, 0)
+        .map_err(|(violation, offset)| ErrorKind::NonCanonicalEncoding { violation, offset })?;
+
+    if end != bytes.len() {
+        Err(ErrorKind::NonCanonicalEncoding {
+            violation: Violation::TrailingData,
+            offset: end,
+        })?;
+    }
+
+    Ok(())
+}
+
+type Checked = std::result::Result<usize, (Violation, usize)>;
+
+/// Checks the value starting at `pos`, returning the offset after its end.
+fn check_value(bytes: &[u8], pos: usize, depth: usize) -> Checked {
+    match bytes.get(pos) {
+        Some(b'i') => check_integer(bytes, pos),
+        Some(b'0'..=b'9') => check_string(bytes, pos).map(|(_, end)| end),
+        Some(b'l') | Some(b'd') if depth >= MAX_DEPTH => Err((Violation::TooDeep, pos)),
+        Some(b'l') => {
+            let mut pos = pos + 1;
+            while bytes.get(pos) != Some(&b'e') {
+                pos = check_value(bytes, pos, depth + 1)?;
+            }
+
+            Ok(pos + 1)
+        }
+        Some(b'd') => check_dictionary(bytes, pos, depth),
+        _ => Err((Violation::Malformed, pos)),
+    }
+}
+
+fn check_integer(bytes: &[u8], start: usize) -> Checked {
+    let digits_start = match bytes.get(start + 1) {
+        Some(b'-') => start + 2,
+        _ => start + 1,
+    };
+    let digits_len = bytes[digits_start.min(bytes.len())..]
+        .iter()
+        .take_while(|byte| byte.is_ascii_digit())
+        .count();
+    let end = digits_start + digits_len;
+
+    if digits_len == 0 || bytes.get(end) != Some(&b'e') {
+        return Err((Violation::Malformed, start));
+    }
+
+    if bytes[digits_start] == b'0' {
+        if digits_start != start + 1 && digits_len == 1 {
+            return Err((Violation::NegativeZero, start));
+        }
+
+        if digits_len > 1 {
+            return Err((Violation::LeadingZero, start));
+        }
+    }
+
+    Ok(end + 1)
+}
+
+/// Checks the string starting at `start`, returning its contents and the
+/// offset after its end.
+fn check_string(
+    bytes: &[u8],
+    start: usize,
+) -> std::result::Result<(&[u8], usize), (Violation, usize)> {
+    let digits_len = bytes[start..]
+        .iter()
+        .take_while(|byte| byte.is_ascii_digit())
+        .count();
+    let colon = start + digits_len;
+
+    if digits_len == 0 || bytes.get(colon) != Some(&b':') {
+        return Err((Violation::Malformed, start));
+    }
+
+    if bytes[start] == b'0' && digits_len > 1 {
+        return Err((Violation::LeadingZero, start));
+    }
+
+    let len: usize = std::str::from_utf8(&bytes[start..colon])
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or((Violation::Malformed, start))?;
+    let end = (colon + 1)
+        .checked_add(len)
+        .filter(|end| *end <= bytes.len())
+        .ok_or((Violation::Malformed, start))?;
+
+    Ok((&bytes[colon + 1..end], end))
+}
+
+fn check_dictionary(bytes: &[u8], start: usize, depth: usize) -> Checked {
+    let mut pos = start + 1;
+    let mut last_key: Option<&[u8]> = None;
+
+    while bytes.get(pos) != Some(&b'e') {
+        match bytes.get(pos) {
+            Some(b'0'..=b'9') => {}
+            _ => return Err((Violation::Malformed, pos)),
+        };
+
+        let (key, key_end) = check_string(bytes, pos)?;
+        if let Some(last_key) = last_key {
+            if key == last_key {
+                return Err((Violation::DuplicateKey, pos));
+            }
+
+            if key < last_key {
+                return Err((Violation::UnsortedKeys, pos));
+            }
+        }
+
+        last_key = Some(key);
+        pos = check_value(bytes, key_end, depth + 1)?;
+    }
+
+    Ok(pos + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_canonical,
+        Violation,
+    };
+    use crate::errors::ErrorKind;
+
+    fn violation(bytes: &[u8]) -> Option<(Violation, usize)> {
+        match check_canonical(bytes) {
+            Ok(()) => None,
+            Err(err) => match err.kind() {
+                ErrorKind::NonCanonicalEncoding { violation, offset } => {
+                    Some((*violation, *offset))
+                }
+                kind => panic!("unexpected error {}", kind),
+            },
+        }
+    }
+
+    #[test]
+    fn canonical_accepted() {
+        for bytes in &[
+            &b"i0e"[..],
+            b"i-42e",
+            b"i42e",
+            b"0:",
+            b"4:spam",
+            b"le",
+            b"l4:spami1ee",
+            b"de",
+            b"d1:ai1e1:bl1:cee",
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe",
+        ] {
+            assert_eq!(violation(bytes), None, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(violation(b"i03e"), Some((Violation::LeadingZero, 0)));
+        assert_eq!(violation(b"i00e"), Some((Violation::LeadingZero, 0)));
+        assert_eq!(violation(b"i-03e"), Some((Violation::LeadingZero, 0)));
+        assert_eq!(violation(b"i-0e"), Some((Violation::NegativeZero, 0)));
+        assert_eq!(violation(b"li1ei-0ee"), Some((Violation::NegativeZero, 4)));
+        assert_eq!(violation(b"ie"), Some((Violation::Malformed, 0)));
+        assert_eq!(violation(b"i-e"), Some((Violation::Malformed, 0)));
+        assert_eq!(violation(b"i+1e"), Some((Violation::Malformed, 0)));
+        assert_eq!(violation(b"i1"), Some((Violation::Malformed, 0)));
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(violation(b"04:spam"), Some((Violation::LeadingZero, 0)));
+        assert_eq!(violation(b"00:"), Some((Violation::LeadingZero, 0)));
+        assert_eq!(violation(b"5:spam"), Some((Violation::Malformed, 0)));
+        assert_eq!(
+            violation(b"99999999999999999999999:a"),
+            Some((Violation::Malformed, 0))
+        );
+    }
+
+    #[test]
+    fn dictionaries() {
+        assert_eq!(
+            violation(b"d1:ai1e1:ai2ee"),
+            Some((Violation::DuplicateKey, 7))
+        );
+        assert_eq!(
+            violation(b"d1:bi1e1:ai2ee"),
+            Some((Violation::UnsortedKeys, 7))
+        );
+        // Keys are compared as raw bytes, a shorter prefix sorts first.
+        assert_eq!(
+            violation(b"d2:aai1e1:ai2ee"),
+            Some((Violation::UnsortedKeys, 8))
+        );
+        assert_eq!(violation(b"di1ei2ee"), Some((Violation::Malformed, 1)));
+        assert_eq!(violation(b"d1:ae"), Some((Violation::Malformed, 4)));
+        assert_eq!(
+            violation(b"d1:ad1:bi1e1:bi1eee"),
+            Some((Violation::DuplicateKey, 11))
+        );
+    }
+
+    #[test]
+    fn structure() {
+        assert_eq!(violation(b"i1ei2e"), Some((Violation::TrailingData, 3)));
+        assert_eq!(violation(b""), Some((Violation::Malformed, 0)));
+        assert_eq!(violation(b"l"), Some((Violation::Malformed, 1)));
+        assert_eq!(violation(b"x"), Some((Violation::Malformed, 0)));
+
+        let deep = format!("{}{}", "l".repeat(40), "e".repeat(40));
+        assert_eq!(violation(deep.as_bytes()), Some((Violation::TooDeep, 32)));
+    }
+}
@@ -0,0 +1,33 @@
+//! Routing table saved by µTorrent in its `dht.dat` file. Only the keys
+//! needed to rebuild the table are read.
+
+use crate::{
+    errors::{
+        ErrorKind,
+        Result,
+    },
+    node_info,
+    NodeID,
+    NodeInfo,
+};
+use serde_bencode;
+use serde_derive::Deserialize;
+
+/// Contents of a `dht.dat` file, a bencoded dictionary.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct DhtDat {
+    /// Id of the node which saved the file.
+    #[serde(default)]
+    pub id: Option<NodeID>,
+
+    /// Nodes from the routing table, in the "Compact node info" format.
+    #[serde(default, deserialize_with = "node_info::deserialize")]
+    pub nodes: Vec<NodeInfo>,
+}
+
+impl DhtDat {
+    pub fn decode(bytes: &[u8]) -> Result<DhtDat> {
+        Ok(serde_bencode::de::from_bytes(bytes)
+            .map_err(|cause| ErrorKind::DecodeError { cause })?)
+    }
+}
@@ -0,0 +1,212 @@
+//! Hand written bencode encoder for outgoing queries. Queries are the bulk of
+//! the messages sent while crawling so they skip serde entirely. The output
+//! must be byte for byte identical to the serde encoding, which means keys are
+//! written in sorted order.
+
+use crate::{
+    addr,
+    Envelope,
+    NodeID,
+    Query,
+};
+use std::io::Write;
+
+pub fn write_query(envelope: &Envelope, query: &Query, buf: &mut Vec<u8>) {
+    buf.push(b'd');
+
+    write_key(buf, b"a");
+    write_arguments(query, buf);
+
+    if let Some(ip) = &envelope.ip {
+        write_key(buf, b"ip");
+        write_bytes(buf, &addr::to_bytes(ip));
+    }
+
+    write_key(buf, b"q");
+    write_bytes(buf, query.name().as_bytes());
+
+    if envelope.read_only {
+        write_key(buf, b"ro");
+        write_int(buf, 1);
+    }
+
+    write_key(buf, b"t");
+    write_bytes(buf, &envelope.transaction_id);
+
+    if let Some(version) = &envelope.version {
+        write_key(buf, b"v");
+        write_bytes(buf, version);
+    }
+
+    write_key(buf, b"y");
+    write_bytes(buf, b"q");
+
+    buf.push(b'e');
+}
+
+fn write_arguments(query: &Query, buf: &mut Vec<u8>) {
+    buf.push(b'd');
+
+    match query {
+        Query::Ping { id } => {
+            write_node_id(buf, b"id", id);
+        }
+        Query::FindNode { id, target } | Query::SampleInfoHashes { id, target } => {
+            write_node_id(buf, b"id", id);
+            write_node_id(buf, b"target", target);
+        }
+        Query::GetPeers { id, info_hash } => {
+            write_node_id(buf, b"id", id);
+            write_node_id(buf, b"info_hash", info_hash);
+        }
+        Query::AnnouncePeer {
+            id,
+            implied_port,
+            port,
+            info_hash,
+            token,
+        } => {
+            write_node_id(buf, b"id", id);
+
+            write_key(buf, b"implied_port");
+            write_int(buf, if *implied_port { 1 } else { 0 });
+
+            write_node_id(buf, b"info_hash", info_hash);
+
+            if let Some(port) = port {
+                write_key(buf, b"port");
+                write_int(buf, i64::from(*port));
+            }
+
+            write_key(buf, b"token");
+            write_bytes(buf, token);
+        }
+    };
+
+    buf.push(b'e');
+}
+
+fn write_node_id(buf: &mut Vec<u8>, key: &[u8], id: &NodeID) {
+    write_key(buf, key);
+    write_bytes(buf, &id.to_bytes());
+}
+
+fn write_key(buf: &mut Vec<u8>, key: &[u8]) {
+    write_bytes(buf, key);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write!(buf, "{}:", bytes.len()).expect("Writing to a Vec can't fail.");
+    buf.extend_from_slice(bytes);
+}
+
+fn write_int(buf: &mut Vec<u8>, value: i64) {
+    write!(buf, "i{}e", value).expect("Writing to a Vec can't fail.");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Addr,
+        Envelope,
+        Message,
+        NodeID,
+        Query,
+    };
+    use rand::{
+        self,
+        Rng,
+    };
+    use std::net::{
+        Ipv4Addr,
+        SocketAddrV4,
+    };
+
+    fn random_query<R: Rng>(rng: &mut R) -> Query {
+        let id = NodeID::random();
+
+        match rng.gen_range(0, 5) {
+            0 => Query::Ping { id },
+            1 => Query::FindNode {
+                id,
+                target: NodeID::random(),
+            },
+            2 => Query::GetPeers {
+                id,
+                info_hash: NodeID::random(),
+            },
+            3 => Query::AnnouncePeer {
+                id,
+                implied_port: rng.gen(),
+                port: if rng.gen() { Some(rng.gen()) } else { None },
+                info_hash: NodeID::random(),
+                token: (0..rng.gen_range(0, 21)).map(|_| rng.gen()).collect(),
+            },
+            _ => Query::SampleInfoHashes {
+                id,
+                target: NodeID::random(),
+            },
+        }
+    }
+
+    fn random_envelope<R: Rng>(rng: &mut R) -> Envelope {
+        Envelope {
+            ip: if rng.gen() {
+                Some(Addr::from(SocketAddrV4::new(
+                    Ipv4Addr::from(rng.gen::<u32>()),
+                    rng.gen(),
+                )))
+            } else {
+                None
+            },
+            transaction_id: (0..rng.gen_range(1, 9)).map(|_| rng.gen()).collect(),
+            version: if rng.gen() {
+                Some(rng.gen::<[u8; 4]>().to_vec().into())
+            } else {
+                None
+            },
+            message_type: Message::Query {
+                query: random_query(rng),
+            },
+            read_only: rng.gen(),
+        }
+    }
+
+    #[test]
+    fn matches_serde_encoding() {
+        let mut rng = rand::thread_rng();
+        let mut buf = Vec::new();
+
+        for _ in 0..1000 {
+            let envelope = random_envelope(&mut rng);
+
+            buf.clear();
+            envelope.encode_into(&mut buf).unwrap();
+
+            assert_eq!(buf, envelope.encode().unwrap(), "{:?}", envelope);
+        }
+    }
+
+    #[test]
+    fn appends_to_buffer() {
+        let envelope = Envelope {
+            ip: None,
+            transaction_id: b"aa".to_vec(),
+            version: None,
+            message_type: Message::Query {
+                query: Query::Ping {
+                    id: b"abcdefghij0123456789".into(),
+                },
+            },
+            read_only: false,
+        };
+
+        let mut buf = b"prefix".to_vec();
+        envelope.encode_into(&mut buf).unwrap();
+
+        assert_eq!(
+            &buf[..],
+            &b"prefixd1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"[..]
+        );
+    }
+}
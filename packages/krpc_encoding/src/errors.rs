@@ -1,3 +1,7 @@
+use crate::{
+    canonical::Violation,
+    KRPCError,
+};
 use failure::{
     Backtrace,
     Context,
@@ -8,17 +12,50 @@ use std::fmt;
 
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
+    #[fail(display = "Received error response {}", error)]
+    ReceivedKRPCError { error: KRPCError },
+
     #[fail(display = "Error while encoding message")]
     EncodeError {
         #[fail(cause)]
         cause: BencodeError,
     },
 
-    #[fail(display = "Error while decoding message")]
+    #[fail(display = "Error while decoding message: {}", cause)]
     DecodeError {
         #[fail(cause)]
         cause: BencodeError,
     },
+
+    #[fail(display = "Non-canonical bencode at byte {}: {}", offset, violation)]
+    NonCanonicalEncoding { violation: Violation, offset: usize },
+
+    #[fail(display = "Invalid node id length {}", len)]
+    InvalidNodeIDLength { len: usize },
+
+    #[fail(display = "Invalid character in node id")]
+    InvalidNodeIDCharacter,
+
+    #[fail(
+        display = "Invalid compact node info length expected={} got={}",
+        expected, got
+    )]
+    InvalidCompactNodeInfo { expected: usize, got: usize },
+
+    #[fail(display = "Invalid ut_metadata message")]
+    InvalidMetadataMessage,
+
+    #[fail(display = "Not a magnet link")]
+    NotAMagnetLink,
+
+    #[fail(display = "Magnet link has no info-hash")]
+    MagnetMissingInfoHash,
+
+    #[fail(display = "Magnet link only has a v2 info-hash, which can't be looked up")]
+    MagnetV2Only,
+
+    #[fail(display = "Invalid magnet link info-hash length {}", len)]
+    InvalidMagnetInfoHashLength { len: usize },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -38,6 +75,12 @@ impl Fail for Error {
     }
 }
 
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        self.inner.get_context()
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Display::fmt(&self.inner, f)
@@ -51,3 +94,9 @@ impl From<ErrorKind> for Error {
         }
     }
 }
+
+impl From<KRPCError> for Error {
+    fn from(error: KRPCError) -> Error {
+        ErrorKind::ReceivedKRPCError { error }.into()
+    }
+}
@@ -0,0 +1,130 @@
+//! Deserializers for integer fields which some buggy clients send as strings
+//! of ASCII digits instead of bencoded integers. Use with
+//! `#[serde(default, deserialize_with = "lenient::deserialize_option")]`.
+//! Values are always encoded as integers.
+
+use serde::{
+    de::{
+        self,
+        Unexpected,
+        Visitor,
+    },
+    Deserializer,
+};
+use std::{
+    convert::TryFrom,
+    fmt,
+    marker::PhantomData,
+    str,
+};
+
+/// Deserializes an integer or a string of ASCII digits into `T`. Values which
+/// don't fit in `T` are rejected.
+pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<i64>,
+{
+    deserializer.deserialize_any(IntegerVisitor(PhantomData))
+}
+
+pub fn deserialize_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<i64>,
+{
+    deserialize(deserializer).map(Some)
+}
+
+struct IntegerVisitor<T>(PhantomData<T>);
+
+impl<'de, T> Visitor<'de> for IntegerVisitor<T>
+where
+    T: TryFrom<i64>,
+{
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("an integer or a string of digits in range")
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        T::try_from(v).map_err(|_| de::Error::invalid_value(Unexpected::Signed(v), &self))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        match i64::try_from(v) {
+            Ok(v) => self.visit_i64(v),
+            Err(_) => Err(de::Error::invalid_value(Unexpected::Unsigned(v), &self)),
+        }
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        let parsed = str::from_utf8(v)
+            .ok()
+            .filter(|s| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit() || b == b'-'))
+            .and_then(|s| s.parse::<i64>().ok());
+
+        match parsed {
+            Some(v) => self.visit_i64(v),
+            None => Err(de::Error::invalid_value(Unexpected::Bytes(v), &self)),
+        }
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        self.visit_bytes(&v)
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        self.visit_bytes(v.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bencode;
+    use serde_derive::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Port {
+        #[serde(deserialize_with = "super::deserialize")]
+        port: u16,
+    }
+
+    fn decode(raw: &[u8]) -> Option<u16> {
+        serde_bencode::de::from_bytes::<Port>(raw)
+            .ok()
+            .map(|decoded| decoded.port)
+    }
+
+    #[test]
+    fn accepts_integers_and_digits() {
+        assert_eq!(decode(b"d4:porti6881ee"), Some(6881));
+        assert_eq!(decode(b"d4:port4:6881e"), Some(6881));
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        assert_eq!(decode(b"d4:porti-1ee"), None);
+        assert_eq!(decode(b"d4:porti65536ee"), None);
+        assert_eq!(decode(b"d4:port2:-1e"), None);
+        assert_eq!(decode(b"d4:port5:70000e"), None);
+        assert_eq!(decode(b"d4:port5:68 81e"), None);
+        assert_eq!(decode(b"d4:port0:e"), None);
+    }
+}
@@ -60,16 +60,34 @@
 
 mod addr;
 mod booleans;
+mod canonical;
+mod dht_dat;
+mod encoder;
 pub mod errors;
+mod lenient;
+mod magnet;
 mod messages;
+mod metadata;
 mod node_id;
 mod node_info;
+mod partial;
+mod samples;
+mod secure_id;
+mod stats;
+mod token;
+mod unknown_fields;
 
 pub use self::{
     addr::{
         to_bytes as addr_to_bytes,
         Addr,
     },
+    canonical::{
+        check_canonical,
+        Violation,
+    },
+    dht_dat::DhtDat,
+    magnet::InfoHash,
     messages::{
         Envelope,
         KRPCError,
@@ -77,6 +95,21 @@ pub use self::{
         Query,
         Response,
     },
+    metadata::{
+        ExtensionHandshake,
+        MetadataMessage,
+        METADATA_PIECE_SIZE,
+        UT_METADATA,
+    },
     node_id::NodeID,
     node_info::NodeInfo,
+    partial::PartialMessage,
+    stats::{
+        decode_stats,
+        DecodeStats,
+    },
+    unknown_fields::{
+        CapturedEnvelope,
+        UnknownFields,
+    },
 };
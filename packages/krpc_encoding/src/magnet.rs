@@ -0,0 +1,240 @@
+//! Info-hashes of magnet links. See [BEP-0009] and [BEP-0052].
+//!
+//! [BEP-0009]: http://www.bittorrent.org/beps/bep_0009.html
+//! [BEP-0052]: http://www.bittorrent.org/beps/bep_0052.html
+
+use crate::{
+    errors::{
+        ErrorKind,
+        Result,
+    },
+    NodeID,
+};
+
+/// Info-hashes are keys in the same space as node ids.
+pub type InfoHash = NodeID;
+
+const MAGNET_PREFIX: &str = "magnet:?";
+
+/// Exact topic prefix of a v1 info-hash.
+const BTIH_PREFIX: &str = "urn:btih:";
+
+/// Exact topic prefix of a v2 multihash, which can't be looked up in the
+/// DHT.
+const BTMH_PREFIX: &str = "urn:btmh:";
+
+impl NodeID {
+    /// Parses the info-hash out of a magnet link like
+    /// `magnet:?xt=urn:btih:<info-hash>&dn=name`. The info-hash may be 40
+    /// hex or 32 base32 characters in either case. Other parameters are
+    /// ignored and when there are several exact topics (`xt`, `xt.1`, ...)
+    /// the first v1 info-hash is used. Parameters may be percent-encoded.
+    pub fn from_magnet(uri: &str) -> Result<InfoHash> {
+        let is_magnet = uri
+            .get(..MAGNET_PREFIX.len())
+            .map_or(false, |prefix| prefix.eq_ignore_ascii_case(MAGNET_PREFIX));
+        if !is_magnet {
+            Err(ErrorKind::NotAMagnetLink)?;
+        }
+
+        let mut has_btmh = false;
+
+        for param in uri[MAGNET_PREFIX.len()..].split('&') {
+            let (key, value) = match param.find('=') {
+                Some(idx) => (&param[..idx], &param[idx + 1..]),
+                None => continue,
+            };
+
+            let key = key.to_ascii_lowercase();
+            if key != "xt" && !key.starts_with("xt.") {
+                continue;
+            }
+
+            let topic = percent_decode(value);
+            let lowercase = topic.to_ascii_lowercase();
+            if lowercase.starts_with(BTIH_PREFIX) {
+                return parse_info_hash(&topic[BTIH_PREFIX.len()..]);
+            } else if lowercase.starts_with(BTMH_PREFIX) {
+                has_btmh = true;
+            }
+        }
+
+        if has_btmh {
+            Err(ErrorKind::MagnetV2Only.into())
+        } else {
+            Err(ErrorKind::MagnetMissingInfoHash.into())
+        }
+    }
+}
+
+fn parse_info_hash(encoded: &str) -> Result<InfoHash> {
+    match encoded.len() {
+        40 => NodeID::from_hex_str(encoded),
+        32 => NodeID::from_base32(encoded),
+        len => Err(ErrorKind::InvalidMagnetInfoHashLength { len }.into()),
+    }
+}
+
+/// Decodes `%XX` escapes. Malformed escapes are kept as they are.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+
+    while idx < bytes.len() {
+        let escaped = bytes[idx] == b'%'
+            && idx + 2 < bytes.len()
+            && bytes[idx + 1].is_ascii_hexdigit()
+            && bytes[idx + 2].is_ascii_hexdigit();
+
+        if escaped {
+            decoded.push((hex_value(bytes[idx + 1]) << 4) | hex_value(bytes[idx + 2]));
+            idx += 3;
+            continue;
+        }
+
+        decoded.push(bytes[idx]);
+        idx += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InfoHash;
+    use crate::errors::ErrorKind;
+
+    const BIG_BUCK_BUNNY: &str = "dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c";
+    const SINTEL: &str = "08ada5a7a6183aae1e09d831df6748d566095a10";
+    const COSMOS_LAUNDROMAT: &str = "c9e15763f722f23e98a29decdfae341b98d53056";
+
+    #[test]
+    fn real_world_magnets() {
+        let magnets = [
+            (
+                "magnet:?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c&dn=Big+Buck+Bunny",
+                BIG_BUCK_BUNNY,
+            ),
+            (
+                "magnet:?xt=urn:btih:DD8255ECDC7CA55FB0BBF81323D87062DB1F6D1C",
+                BIG_BUCK_BUNNY,
+            ),
+            (
+                "magnet:?xt=urn:btih:3WBFL3G4PSSV7MF37AJSHWDQMLNR63I4&dn=Big+Buck+Bunny",
+                BIG_BUCK_BUNNY,
+            ),
+            (
+                "magnet:?xt=urn:btih:3wbfl3g4pssv7mf37ajshwdqmlnr63i4",
+                BIG_BUCK_BUNNY,
+            ),
+            (
+                "magnet:?dn=Sintel&tr=udp%3A%2F%2Fexplodie.org%3A6969&xt=urn:btih:08ada5a7a6183aae1e09d831df6748d566095a10",
+                SINTEL,
+            ),
+            (
+                "magnet:?xt=urn%3Abtih%3A08ada5a7a6183aae1e09d831df6748d566095a10&dn=Sintel",
+                SINTEL,
+            ),
+            (
+                "magnet:?xt=urn%3ABTIH%3ABCW2LJ5GDA5K4HQJ3AY56Z2I2VTASWQQ",
+                SINTEL,
+            ),
+            (
+                "MAGNET:?XT=URN:BTIH:BCW2LJ5GDA5K4HQJ3AY56Z2I2VTASWQQ",
+                SINTEL,
+            ),
+            (
+                "magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056&dn=Cosmos+Laundromat&tr=udp%3A%2F%2Ftracker.leechers-paradise.org%3A6969&tr=wss%3A%2F%2Ftracker.webtorrent.io&ws=https%3A%2F%2Fwebtorrent.io%2Ftorrents%2F",
+                COSMOS_LAUNDROMAT,
+            ),
+            (
+                "magnet:?xt.1=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056&xt.2=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c",
+                COSMOS_LAUNDROMAT,
+            ),
+            (
+                "magnet:?xt=urn:btmh:1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e&xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056",
+                COSMOS_LAUNDROMAT,
+            ),
+            (
+                "magnet:?xt=urn:btih:ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMCW&xl=1234&dn=Cosmos%20Laundromat",
+                COSMOS_LAUNDROMAT,
+            ),
+        ];
+
+        for (magnet, expected) in magnets.iter() {
+            let info_hash = InfoHash::from_magnet(magnet)
+                .unwrap_or_else(|err| panic!("failed to parse {}: {}", magnet, err));
+
+            assert_eq!(
+                info_hash,
+                InfoHash::from_hex_str(expected).unwrap(),
+                "{}",
+                magnet
+            );
+        }
+    }
+
+    #[test]
+    fn not_a_magnet() {
+        match InfoHash::from_magnet(
+            "http://example.com/?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c",
+        )
+        .unwrap_err()
+        .kind()
+        {
+            ErrorKind::NotAMagnetLink => {}
+            kind => panic!("unexpected error {}", kind),
+        };
+    }
+
+    #[test]
+    fn missing_info_hash() {
+        for magnet in &["magnet:?dn=Sintel", "magnet:?", "magnet:?xt=urn:sha1:abc"] {
+            match InfoHash::from_magnet(magnet).unwrap_err().kind() {
+                ErrorKind::MagnetMissingInfoHash => {}
+                kind => panic!("unexpected error {}", kind),
+            };
+        }
+    }
+
+    #[test]
+    fn v2_only() {
+        let magnet = "magnet:?xt=urn:btmh:1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e&dn=bittorrent-v2-test";
+
+        match InfoHash::from_magnet(magnet).unwrap_err().kind() {
+            ErrorKind::MagnetV2Only => {}
+            kind => panic!("unexpected error {}", kind),
+        };
+    }
+
+    #[test]
+    fn wrong_digest_length() {
+        match InfoHash::from_magnet("magnet:?xt=urn:btih:dd8255ecdc7ca55fb0bbf813")
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidMagnetInfoHashLength { len: 24 } => {}
+            kind => panic!("unexpected error {}", kind),
+        };
+    }
+
+    #[test]
+    fn invalid_characters() {
+        match InfoHash::from_magnet("magnet:?xt=urn:btih:zz8255ecdc7ca55fb0bbf81323d87062db1f6d1c")
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidNodeIDCharacter => {}
+            kind => panic!("unexpected error {}", kind),
+        };
+    }
+}
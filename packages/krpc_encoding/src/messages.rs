@@ -1,14 +1,25 @@
 use crate::{
+    addr,
     booleans,
+    canonical,
+    encoder,
     errors::{
         ErrorKind,
         Result,
     },
+    lenient,
     node_info,
+    samples,
+    stats,
+    token,
     Addr,
     NodeID,
     NodeInfo,
 };
+use serde::{
+    de,
+    Deserializer,
+};
 use serde_bencode;
 use serde_bytes::{
     self,
@@ -18,15 +29,19 @@ use serde_derive::{
     Deserialize,
     Serialize,
 };
-use std::fmt;
+use std::{
+    collections::HashSet,
+    fmt,
+};
 
 /// Envelope holding information common to requests and responses
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct Envelope {
     /// Public IP address of the requester. Only sent by peers supporting
     /// [BEP-0042].
     ///
     /// [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+    #[serde(default, with = "addr::compact_option")]
     pub ip: Option<Addr>,
 
     /// Transaction ID generated by the querying node and echoed in the
@@ -54,18 +69,64 @@ pub struct Envelope {
 }
 
 impl Envelope {
+    /// Creates a query without an `ip` or `version` from a node which isn't
+    /// read-only.
+    pub fn query(transaction_id: Vec<u8>, query: Query) -> Envelope {
+        Envelope::new(transaction_id, Message::Query { query })
+    }
+
+    /// Creates a response without an `ip` or `version`.
+    pub fn response(transaction_id: Vec<u8>, response: Response) -> Envelope {
+        Envelope::new(transaction_id, Message::Response { response })
+    }
+
+    fn new(transaction_id: Vec<u8>, message_type: Message) -> Envelope {
+        Envelope {
+            ip: None,
+            transaction_id,
+            version: None,
+            message_type,
+            read_only: false,
+        }
+    }
+
     pub fn decode(bytes: &[u8]) -> Result<Envelope> {
-        Ok(serde_bencode::de::from_bytes(bytes)
-            .map_err(|cause| ErrorKind::DecodeError { cause })?)
+        stats::start_message();
+        let envelope = serde_bencode::de::from_bytes(bytes)
+            .map_err(|cause| ErrorKind::DecodeError { cause })?;
+        stats::finish_message();
+
+        Ok(envelope)
+    }
+
+    /// Like [`decode`] but rejects messages which aren't encoded in the one
+    /// canonical form, see [`check_canonical`].
+    ///
+    /// [`check_canonical`]: crate::check_canonical
+    pub fn decode_strict(bytes: &[u8]) -> Result<Envelope> {
+        canonical::check_canonical(bytes)?;
+
+        Envelope::decode(bytes)
     }
 
     pub fn encode(&self) -> Result<Vec<u8>> {
         Ok(serde_bencode::ser::to_bytes(self).map_err(|cause| ErrorKind::EncodeError { cause })?)
     }
+
+    /// Encodes the message, appending it to `buf`. Queries are written without
+    /// intermediate allocations, other messages fall back to [`encode`].
+    pub fn encode_into(&self, buf: &mut Vec<u8>) -> Result<()> {
+        match &self.message_type {
+            Message::Query { query } => encoder::write_query(self, query, buf),
+            _ => buf.extend_from_slice(&self.encode()?),
+        };
+
+        Ok(())
+    }
 }
 
 /// Messages sent and received by nodes
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(tag = "y")]
 pub enum Message {
     #[serde(rename = "q")]
@@ -92,6 +153,13 @@ pub enum Message {
 pub struct KRPCError(u8, String);
 
 impl KRPCError {
+    /// Error codes defined by BEP-0005.
+    pub const GENERIC_ERROR: u8 = 201;
+    pub const SERVER_ERROR: u8 = 202;
+    /// A malformed packet, invalid arguments or a bad token.
+    pub const PROTOCOL_ERROR: u8 = 203;
+    pub const METHOD_UNKNOWN: u8 = 204;
+
     pub fn new(error_code: u8, message: &str) -> KRPCError {
         KRPCError(error_code, message.to_string())
     }
@@ -103,8 +171,10 @@ impl fmt::Display for KRPCError {
     }
 }
 
+impl std::error::Error for KRPCError {}
+
 /// Possible queries
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(tag = "q", content = "a")]
 pub enum Query {
     /// Most basic query
@@ -157,7 +227,12 @@ pub enum Query {
     /// same IP address as the querying node. Then the queried node should
     /// store the IP address of the querying node and the supplied port
     /// number under the infohash in its store of peer contact information.
-    #[serde(rename = "announce_peer")]
+    ///
+    /// Decoding fails without a `port` unless `implied_port` is set.
+    #[serde(
+        rename = "announce_peer",
+        deserialize_with = "deserialize_announce_peer"
+    )]
     AnnouncePeer {
         /// Node ID of the querying node
         id: NodeID,
@@ -169,7 +244,6 @@ pub enum Query {
         /// peers behind a NAT that may not know their external port, and
         /// supporting uTP, they accept incoming connections on the same port as
         /// the DHT port.
-        #[serde(deserialize_with = "booleans::deserialize")]
         implied_port: bool,
 
         /// Peer's port
@@ -179,7 +253,7 @@ pub enum Query {
         info_hash: NodeID,
 
         /// Token received in response to a previous [Query::GetPeers]
-        #[serde(with = "serde_bytes")]
+        #[serde(serialize_with = "serde_bytes::serialize")]
         token: Vec<u8>,
     },
 
@@ -194,29 +268,128 @@ pub enum Query {
     },
 }
 
+/// Arguments of an `announce_peer` query as decoded, before they are
+/// checked by [`deserialize_announce_peer`].
+#[derive(Deserialize)]
+struct AnnouncePeerArguments {
+    id: NodeID,
+
+    #[serde(deserialize_with = "booleans::deserialize")]
+    implied_port: bool,
+
+    #[serde(default, deserialize_with = "lenient::deserialize_option")]
+    port: Option<u16>,
+
+    info_hash: NodeID,
+
+    #[serde(with = "serde_bytes")]
+    token: Vec<u8>,
+}
+
+/// Decodes the fields of a [`Query::AnnouncePeer`] in order. An announce
+/// without a port which isn't implied either is malformed, see [BEP-0005].
+///
+/// [BEP-0005]: http://www.bittorrent.org/beps/bep_0005.html
+fn deserialize_announce_peer<'de, D>(
+    deserializer: D,
+) -> std::result::Result<(NodeID, bool, Option<u16>, NodeID, Vec<u8>), D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let arguments: AnnouncePeerArguments = serde::Deserialize::deserialize(deserializer)?;
+    if !arguments.implied_port && arguments.port.is_none() {
+        return Err(de::Error::custom(
+            "port required when implied_port is false",
+        ));
+    }
+
+    Ok((
+        arguments.id,
+        arguments.implied_port,
+        arguments.port,
+        arguments.info_hash,
+        arguments.token,
+    ))
+}
+
+impl Query {
+    /// Method names of every query.
+    pub const NAMES: [&'static str; 5] = [
+        "ping",
+        "find_node",
+        "get_peers",
+        "announce_peer",
+        "sample_infohashes",
+    ];
+
+    /// Method name sent in the `q` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Query::Ping { .. } => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::SampleInfoHashes { .. } => "sample_infohashes",
+        }
+    }
+}
+
 /// Possible responses
 ///
-/// See [`Query`] to understand when each variant is used.
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
-#[serde(untagged)]
+/// See [`Query`] to understand when each variant is used. Responses don't
+/// say which query they answer, so decoding picks the variant from the keys
+/// present: `samples`, then `values`, then `nodes`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(untagged, from = "RawResponse")]
 pub enum Response {
-    NextHop {
+    /// Response to [`Query::SampleInfoHashes`]
+    Samples {
+        /// Identifier of queried node
+        id: NodeID,
+
+        /// Number of seconds this node should not be queried again for
+        #[serde(default, deserialize_with = "lenient::deserialize_option")]
+        interval: Option<u16>,
+
+        /// Nodes close to target in request
+        #[serde(with = "node_info")]
+        nodes: Vec<NodeInfo>,
+
+        /// Number of info hashes this peer has
+        #[serde(default, deserialize_with = "lenient::deserialize_option")]
+        num: Option<u32>,
+
+        /// Sample of info-hashes
+        #[serde(with = "samples")]
+        samples: Vec<NodeID>,
+    },
+
+    /// Response to [`Query::GetPeers`] from a node which knows peers for the
+    /// info-hash. Some nodes also return the closest nodes they know.
+    GetPeers {
         /// Identifier of queried node
         id: NodeID,
 
-        /// Token used in [Query::AnnouncePeer]
+        /// Token used in [`Query::AnnouncePeer`]
         ///
         /// Empty when the responder decides we are unfit to send AnnouncePeer
         /// messages by [BEP-0042].
         ///
         /// [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+        #[serde(default, with = "token", skip_serializing_if = "Option::is_none")]
         token: Option<Vec<u8>>,
 
-        #[serde(with = "node_info")]
+        #[serde(rename = "values", with = "addr::compact_vec")]
+        peers: Vec<Addr>,
+
+        /// Nodes close to the info-hash
+        #[serde(default, with = "node_info", skip_serializing_if = "Vec::is_empty")]
         nodes: Vec<NodeInfo>,
     },
 
-    GetPeers {
+    /// Response to [`Query::FindNode`], and to [`Query::GetPeers`] from a
+    /// node which knows no peers for the info-hash.
+    NextHop {
         /// Identifier of queried node
         id: NodeID,
 
@@ -226,10 +399,11 @@ pub enum Response {
         /// messages by [BEP-0042].
         ///
         /// [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+        #[serde(default, with = "token", skip_serializing_if = "Option::is_none")]
         token: Option<Vec<u8>>,
 
-        #[serde(rename = "values")]
-        peers: Vec<Addr>,
+        #[serde(with = "node_info")]
+        nodes: Vec<NodeInfo>,
     },
 
     /// Response to [`Query::Ping`] and [`Query::AnnouncePeer`]
@@ -237,23 +411,119 @@ pub enum Response {
         /// Identifier of queried node
         id: NodeID,
     },
+}
 
-    /// Response to [`Query::SampleInfoHashes`]
-    Samples {
-        /// Identifier of queried node
-        id: NodeID,
+/// Every key of a [`Response`] as decoded. A response is [`Response::Samples`]
+/// when it has `samples`, [`Response::GetPeers`] when it has `values`,
+/// [`Response::NextHop`] when it has `nodes` and [`Response::OnlyID`]
+/// otherwise. Unlike trying each variant in turn, a malformed value fails
+/// decoding instead of falling through to another variant.
+#[derive(Deserialize)]
+struct RawResponse {
+    id: NodeID,
 
-        /// Number of seconds this node should not be queried again for
-        interval: Option<u16>,
+    #[serde(default, with = "token")]
+    token: Option<Vec<u8>>,
 
-        /// Nodes close to target in request
-        #[serde(with = "node_info")]
-        nodes: Vec<NodeInfo>,
+    #[serde(default, deserialize_with = "deserialize_some_peers")]
+    values: Option<Vec<Addr>>,
 
-        /// Number of info hashes this peer has
-        num: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_some_nodes")]
+    nodes: Option<Vec<NodeInfo>>,
 
-        /// Sample of info-hashes
-        samples: Vec<NodeID>,
-    },
+    #[serde(default, deserialize_with = "deserialize_some_samples")]
+    samples: Option<Vec<NodeID>>,
+
+    #[serde(default, deserialize_with = "lenient::deserialize_option")]
+    interval: Option<u16>,
+
+    #[serde(default, deserialize_with = "lenient::deserialize_option")]
+    num: Option<u32>,
+}
+
+fn deserialize_some_peers<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<Vec<Addr>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    addr::compact_vec::deserialize(deserializer).map(Some)
+}
+
+fn deserialize_some_nodes<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<Vec<NodeInfo>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    node_info::deserialize(deserializer).map(Some)
+}
+
+fn deserialize_some_samples<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<Vec<NodeID>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    samples::deserialize(deserializer).map(Some)
+}
+
+impl From<RawResponse> for Response {
+    fn from(raw: RawResponse) -> Response {
+        let RawResponse {
+            id,
+            token,
+            values,
+            nodes,
+            samples,
+            interval,
+            num,
+        } = raw;
+
+        match (samples, values, nodes) {
+            (Some(samples), _, nodes) => Response::Samples {
+                id,
+                interval,
+                nodes: nodes.unwrap_or_default(),
+                num,
+                samples,
+            },
+            (None, Some(peers), nodes) => Response::GetPeers {
+                id,
+                token,
+                peers,
+                nodes: nodes.unwrap_or_default(),
+            },
+            (None, None, Some(nodes)) => Response::NextHop { id, token, nodes },
+            (None, None, None) => Response::OnlyID { id },
+        }
+    }
+}
+
+impl Response {
+    /// Identifier of the responding node.
+    pub fn id(&self) -> &NodeID {
+        match self {
+            Response::Samples { id, .. }
+            | Response::GetPeers { id, .. }
+            | Response::NextHop { id, .. }
+            | Response::OnlyID { id } => id,
+        }
+    }
+
+    /// Removes nodes with the same id as a node before them from `nodes`,
+    /// like when a responder learned of a node from several others. Nodes
+    /// returned more than once are only kept the first time, so lookups
+    /// don't query them twice.
+    pub fn deduplicate_nodes(&mut self) {
+        let nodes = match self {
+            Response::Samples { nodes, .. }
+            | Response::GetPeers { nodes, .. }
+            | Response::NextHop { nodes, .. } => nodes,
+            Response::OnlyID { .. } => return,
+        };
+
+        let mut seen = HashSet::with_capacity(nodes.len());
+        nodes.retain(|node| seen.insert(node.node_id.clone()));
+    }
 }
@@ -0,0 +1,200 @@
+//! Messages of the `ut_metadata` extension from [BEP-0009], used to download
+//! the info dictionary of a torrent from peers, and the extension handshake
+//! from [BEP-0010] used to negotiate it.
+//!
+//! [BEP-0009]: http://www.bittorrent.org/beps/bep_0009.html
+//! [BEP-0010]: http://www.bittorrent.org/beps/bep_0010.html
+
+use crate::errors::{
+    ErrorKind,
+    Result,
+};
+use serde_bencode;
+use serde_bytes::ByteBuf;
+use serde_derive::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    collections::BTreeMap,
+    str,
+};
+
+/// Size of every metadata piece except the last.
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+/// Name of the extension in [`ExtensionHandshake::m`].
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// Deepest nesting accepted in a message header.
+const MAX_HEADER_DEPTH: usize = 8;
+
+/// A `ut_metadata` message.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MetadataMessage {
+    /// Asks for the piece with index `piece`.
+    Request { piece: u32 },
+
+    /// Contents of `piece`. `total_size` is the size of the whole info
+    /// dictionary.
+    Data {
+        piece: u32,
+        total_size: u32,
+        data: ByteBuf,
+    },
+
+    /// The peer doesn't have or won't send `piece`.
+    Reject { piece: u32 },
+}
+
+/// Dictionary at the start of every message. [`MetadataMessage::Data`]
+/// messages are followed by the contents of the piece.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    msg_type: u8,
+    piece: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<u32>,
+}
+
+impl MetadataMessage {
+    pub fn decode(bytes: &[u8]) -> Result<MetadataMessage> {
+        let header_len = value_len(bytes, 0).ok_or(ErrorKind::InvalidMetadataMessage)?;
+        let header: Header = serde_bencode::de::from_bytes(&bytes[..header_len])
+            .map_err(|cause| ErrorKind::DecodeError { cause })?;
+        let rest = &bytes[header_len..];
+
+        let message = match (header.msg_type, header.total_size) {
+            (0, _) if rest.is_empty() => MetadataMessage::Request {
+                piece: header.piece,
+            },
+            (1, Some(total_size)) => MetadataMessage::Data {
+                piece: header.piece,
+                total_size,
+                data: ByteBuf::from(rest.to_vec()),
+            },
+            (2, _) if rest.is_empty() => MetadataMessage::Reject {
+                piece: header.piece,
+            },
+            _ => Err(ErrorKind::InvalidMetadataMessage)?,
+        };
+
+        Ok(message)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let (header, data) = match self {
+            MetadataMessage::Request { piece } => (
+                Header {
+                    msg_type: 0,
+                    piece: *piece,
+                    total_size: None,
+                },
+                None,
+            ),
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => (
+                Header {
+                    msg_type: 1,
+                    piece: *piece,
+                    total_size: Some(*total_size),
+                },
+                Some(data),
+            ),
+            MetadataMessage::Reject { piece } => (
+                Header {
+                    msg_type: 2,
+                    piece: *piece,
+                    total_size: None,
+                },
+                None,
+            ),
+        };
+
+        let mut encoded = serde_bencode::ser::to_bytes(&header)
+            .map_err(|cause| ErrorKind::EncodeError { cause })?;
+        if let Some(data) = data {
+            encoded.extend_from_slice(data);
+        }
+
+        Ok(encoded)
+    }
+}
+
+/// Payload of the extension handshake. Keys other than the ones below are
+/// ignored.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct ExtensionHandshake {
+    /// Names of supported extensions mapped to the message id the sender
+    /// expects them to be sent with.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+
+    /// Size of the info dictionary in bytes. Only sent by peers which have
+    /// the metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<u32>,
+}
+
+impl ExtensionHandshake {
+    pub fn decode(bytes: &[u8]) -> Result<ExtensionHandshake> {
+        Ok(serde_bencode::de::from_bytes(bytes)
+            .map_err(|cause| ErrorKind::DecodeError { cause })?)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(serde_bencode::ser::to_bytes(self).map_err(|cause| ErrorKind::EncodeError { cause })?)
+    }
+}
+
+/// Length of the bencoded value at the start of `bytes` or `None` if it is
+/// malformed, truncated or nested deeper than [`MAX_HEADER_DEPTH`].
+fn value_len(bytes: &[u8], depth: usize) -> Option<usize> {
+    if depth > MAX_HEADER_DEPTH {
+        return None;
+    }
+
+    match *bytes.first()? {
+        b'i' => Some(bytes.iter().position(|&b| b == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut pos = 1;
+            while *bytes.get(pos)? != b'e' {
+                pos += value_len(&bytes[pos..], depth + 1)?;
+            }
+
+            Some(pos + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = bytes.iter().position(|&b| b == b':')?;
+            let len: usize = str::from_utf8(&bytes[..colon]).ok()?.parse().ok()?;
+            let end = (colon + 1).checked_add(len)?;
+
+            if end > bytes.len() {
+                None
+            } else {
+                Some(end)
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::value_len;
+
+    #[test]
+    fn value_lengths() {
+        assert_eq!(value_len(b"i42eabc", 0), Some(4));
+        assert_eq!(value_len(b"4:spamabc", 0), Some(6));
+        assert_eq!(value_len(b"d1:ai1e1:bl1:cee", 0), Some(16));
+        assert_eq!(value_len(b"d1:ai1e", 0), None);
+        assert_eq!(value_len(b"5:spam", 0), None);
+        assert_eq!(value_len(b"llllllllllee", 0), None);
+        assert_eq!(value_len(b"x", 0), None);
+    }
+}
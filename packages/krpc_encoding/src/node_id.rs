@@ -1,6 +1,13 @@
+use crate::errors::{
+    ErrorKind,
+    Result,
+};
 use hex;
 use num_bigint::BigUint;
-use rand;
+use rand::{
+    self,
+    Rng,
+};
 use serde::{
     de::{
         self,
@@ -14,8 +21,13 @@ use serde::{
 use std::{
     fmt,
     ops::Deref,
+    str::FromStr,
 };
 
+/// Alphabet used for base32 encoded ids as found in magnet links. See
+/// RFC-4648.
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
 /// Value representing a key or node ID in the DHT
 #[derive(PartialEq, Eq, Clone, Hash)]
 pub struct NodeID(BigUint);
@@ -25,8 +37,43 @@ impl NodeID {
         NodeID(id)
     }
 
+    /// Generates a random id. The thread local generator used is
+    /// cryptographically secure so ids can't be predicted by other nodes.
     pub fn random() -> NodeID {
-        rand::random::<[u8; 20]>().into()
+        let mut bytes = [0u8; 20];
+        rand::thread_rng().fill(&mut bytes);
+
+        bytes.into()
+    }
+
+    /// Picks an id uniformly at random from `[start, end)`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start` isn't less than `end`.
+    pub fn random_in_range(start: &NodeID, end: &NodeID) -> NodeID {
+        assert!(
+            start.0 < end.0,
+            "random_in_range called with an empty range"
+        );
+
+        let range = &end.0 - &start.0;
+        let bits = range.bits();
+        let mut bytes = vec![0u8; (bits + 7) / 8];
+        let mut rng = rand::thread_rng();
+
+        // Rejection sampling keeps the distribution uniform. Masking off the
+        // bits above the highest bit of the range means at least half of the
+        // draws are accepted.
+        loop {
+            rng.fill(&mut bytes[..]);
+            bytes[0] &= 0xff >> (bytes.len() * 8 - bits);
+
+            let offset = BigUint::from_bytes_be(&bytes);
+            if offset < range {
+                return NodeID(&start.0 + offset);
+            }
+        }
     }
 
     pub fn from_bytes(bytes: &[u8]) -> NodeID {
@@ -40,6 +87,64 @@ impl NodeID {
         NodeID::from_bytes(&bytes)
     }
 
+    /// Like [`from_bytes`], but fails unless there are exactly 20 bytes.
+    ///
+    /// [`from_bytes`]: NodeID::from_bytes
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<NodeID> {
+        if bytes.len() != 20 {
+            Err(ErrorKind::InvalidNodeIDLength { len: bytes.len() })?;
+        }
+
+        Ok(NodeID::from_bytes(bytes))
+    }
+
+    /// Parses a 40 character hex encoded id.
+    pub fn from_hex_str(encoded: &str) -> Result<NodeID> {
+        if encoded.len() != 40 {
+            Err(ErrorKind::InvalidNodeIDLength { len: encoded.len() })?;
+        }
+
+        let bytes = hex::decode(encoded).map_err(|_| ErrorKind::InvalidNodeIDCharacter)?;
+
+        NodeID::try_from_bytes(&bytes)
+    }
+
+    /// Parses a 32 character base32 encoded id. Both upper and lower case
+    /// characters are accepted.
+    pub fn from_base32(encoded: &str) -> Result<NodeID> {
+        if encoded.len() != 32 {
+            Err(ErrorKind::InvalidNodeIDLength { len: encoded.len() })?;
+        }
+
+        let mut bytes = [0u8; 20];
+        let mut idx = 0;
+        let mut buffer: u32 = 0;
+        let mut buffered_bits = 0;
+
+        for c in encoded.bytes() {
+            let value = BASE32_ALPHABET
+                .iter()
+                .position(|a| *a == c.to_ascii_uppercase())
+                .ok_or(ErrorKind::InvalidNodeIDCharacter)?;
+
+            buffer = (buffer << 5) | value as u32;
+            buffered_bits += 5;
+
+            if buffered_bits >= 8 {
+                buffered_bits -= 8;
+                bytes[idx] = (buffer >> buffered_bits) as u8;
+                idx += 1;
+            }
+        }
+
+        Ok(bytes.into())
+    }
+
+    /// XOR distance between two keys as defined by Kademlia.
+    pub fn distance(&self, other: &NodeID) -> BigUint {
+        &self.0 ^ &other.0
+    }
+
     pub fn as_bytes(&self) -> [u8; 20] {
         let mut bytes = self.0.to_bytes_be();
         bytes.resize(20, 0);
@@ -48,6 +153,20 @@ impl NodeID {
 
         output
     }
+
+    /// The 20 bytes of the id as sent on the wire. Unlike [`as_bytes`], ids
+    /// with leading zero bytes survive a round trip through
+    /// [`try_from_bytes`].
+    ///
+    /// [`as_bytes`]: NodeID::as_bytes
+    /// [`try_from_bytes`]: NodeID::try_from_bytes
+    pub fn to_bytes(&self) -> [u8; 20] {
+        let bytes = self.0.to_bytes_be();
+        let mut output = [0u8; 20];
+        output[20 - bytes.len()..].copy_from_slice(&bytes[..]);
+
+        output
+    }
 }
 
 impl Deref for NodeID {
@@ -66,21 +185,40 @@ impl fmt::Debug for NodeID {
 
 impl fmt::Display for NodeID {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", hex::encode(self.as_bytes()))
+        <Self as fmt::LowerHex>::fmt(self, f)
+    }
+}
+
+impl fmt::LowerHex for NodeID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.to_bytes()))
+    }
+}
+
+impl FromStr for NodeID {
+    type Err = crate::errors::Error;
+
+    /// Parses either a hex or a base32 encoded id.
+    fn from_str(s: &str) -> Result<NodeID> {
+        match s.len() {
+            40 => NodeID::from_hex_str(s),
+            32 => NodeID::from_base32(s),
+            len => Err(ErrorKind::InvalidNodeIDLength { len }.into()),
+        }
     }
 }
 
 impl Serialize for NodeID {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.serialize_bytes(&self.as_bytes())
+        serializer.serialize_bytes(&self.to_bytes())
     }
 }
 
 impl<'de> Deserialize<'de> for NodeID {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
@@ -97,7 +235,7 @@ impl<'de> Visitor<'de> for NodeIDVisitor {
         formatter.write_str("a byte array of size 20")
     }
 
-    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    fn visit_bytes<E>(self, v: &[u8]) -> std::result::Result<Self::Value, E>
     where
         E: de::Error,
     {
@@ -106,10 +244,10 @@ impl<'de> Visitor<'de> for NodeIDVisitor {
             return Err(de::Error::invalid_length(len, &self));
         };
 
-        Ok(NodeID::from_bytes(v))
+        NodeID::try_from_bytes(v).map_err(de::Error::custom)
     }
 
-    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> std::result::Result<Self::Value, E>
     where
         E: de::Error,
     {
@@ -138,7 +276,9 @@ impl From<[u8; 20]> for NodeID {
 #[cfg(test)]
 mod tests {
     use super::NodeID;
+    use crate::errors::ErrorKind;
     use num_bigint::BigUint;
+    use std::collections::HashMap;
 
     #[test]
     fn as_bytes() {
@@ -149,4 +289,124 @@ mod tests {
 
         assert_eq!(bytes, expected);
     }
+
+    #[test]
+    fn distance() {
+        let a = NodeID::new(BigUint::from(0b1010u8));
+        let b = NodeID::new(BigUint::from(0b0110u8));
+
+        assert_eq!(a.distance(&b), BigUint::from(0b1100u8));
+        assert_eq!(a.distance(&a), BigUint::from(0u8));
+    }
+
+    #[test]
+    fn as_bytes_round_trip() {
+        let mut bytes = [0u8; 20];
+        bytes[1] = 0xab;
+        bytes[19] = 0x01;
+
+        assert_eq!(NodeID::from(bytes).to_bytes(), bytes);
+    }
+
+    #[test]
+    fn try_from_bytes() {
+        let id = NodeID::try_from_bytes(&[0u8; 20]).unwrap();
+        assert_eq!(id, NodeID::new(BigUint::from(0u8)));
+
+        match NodeID::try_from_bytes(&[0u8; 19]).unwrap_err().kind() {
+            ErrorKind::InvalidNodeIDLength { len: 19 } => {}
+            kind => panic!("unexpected error {:?}", kind),
+        };
+    }
+
+    #[test]
+    fn from_hex_str() {
+        let id = NodeID::from_hex_str("0102030405060708090a0b0c0d0e0f1011121314").unwrap();
+        let expected: Vec<u8> = (1..=20).collect();
+        assert_eq!(&id.to_bytes()[..], &expected[..]);
+
+        match NodeID::from_hex_str("0102").unwrap_err().kind() {
+            ErrorKind::InvalidNodeIDLength { len: 4 } => {}
+            kind => panic!("unexpected error {:?}", kind),
+        };
+
+        match NodeID::from_hex_str("zz02030405060708090a0b0c0d0e0f1011121314")
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidNodeIDCharacter => {}
+            kind => panic!("unexpected error {:?}", kind),
+        };
+    }
+
+    #[test]
+    fn from_str() {
+        let hex: NodeID = "0102030405060708090a0b0c0d0e0f1011121314".parse().unwrap();
+        let base32: NodeID = "AEBAGBAFAYDQQCIKBMGA2DQPCAIREEYU".parse().unwrap();
+        let lower_base32: NodeID = "aebagbafaydqqcikbmga2dqpcaireeyu".parse().unwrap();
+
+        assert_eq!(hex, base32);
+        assert_eq!(hex, lower_base32);
+        assert!("AEBAGBAFAYDQQCIKBMGA2DQPCAIREEY1"
+            .parse::<NodeID>()
+            .is_err());
+        assert!("0102".parse::<NodeID>().is_err());
+    }
+
+    #[test]
+    fn display() {
+        let id = NodeID::new(BigUint::from(1u8));
+
+        assert_eq!(id.to_string(), "0000000000000000000000000000000000000001");
+        assert_eq!(format!("{:x}", id), id.to_string());
+        assert_eq!(NodeID::from_hex_str(&id.to_string()).unwrap(), id);
+    }
+
+    #[test]
+    fn random_in_single_value_range() {
+        let start = NodeID::new(BigUint::from(41u8));
+        let end = NodeID::new(BigUint::from(42u8));
+
+        for _ in 0..100 {
+            assert_eq!(NodeID::random_in_range(&start, &end), start);
+        }
+    }
+
+    #[test]
+    fn random_in_range_is_uniform() {
+        let start = NodeID::new(BigUint::from(10u8));
+        let end = NodeID::new(BigUint::from(14u8));
+        let mut counts = HashMap::new();
+
+        for _ in 0..4000 {
+            let id = NodeID::random_in_range(&start, &end);
+            *counts.entry(id).or_insert(0) += 1;
+        }
+
+        // Both boundaries of the range are reachable and no value is favoured.
+        assert_eq!(counts.len(), 4);
+        for i in 10u8..14 {
+            let count = counts[&NodeID::new(BigUint::from(i))];
+            assert!(count > 800 && count < 1200, "{} drawn {} times", i, count);
+        }
+    }
+
+    #[test]
+    fn random_in_full_range() {
+        let start = NodeID::new(BigUint::from(0u8));
+        let end = NodeID::new(BigUint::from_bytes_be(&[0xffu8; 20]) + 1u8);
+
+        for _ in 0..100 {
+            let id = NodeID::random_in_range(&start, &end);
+            assert!(*id < *end);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn random_in_empty_range() {
+        let start = NodeID::new(BigUint::from(10u8));
+
+        NodeID::random_in_range(&start, &start);
+    }
 }
@@ -1,5 +1,7 @@
 use crate::{
     addr,
+    errors,
+    Addr,
     NodeID,
 };
 use serde::{
@@ -7,18 +9,30 @@ use serde::{
         self,
         Visitor,
     },
+    Deserialize,
     Deserializer,
+    Serialize,
     Serializer,
 };
+use serde_derive::{
+    Deserialize,
+    Serialize,
+};
 use std::{
     fmt,
     net::SocketAddrV4,
 };
 
+/// Length of a node in the "Compact node info" format. A 20 byte id followed
+/// by a 4 byte IPv4 address and a 2 byte port.
+const COMPACT_LEN: usize = 26;
+
 /// Contact information for a node in the DHT network
 ///
-/// Implements "Compact node info" serialization and de-serialization.
-#[derive(Debug, PartialEq, Eq)]
+/// Serializes as a struct with a hex encoded `id` and an `"ip:port"`
+/// `address`. The "Compact node info" format used in messages is implemented
+/// by [`serialize`] and [`deserialize`].
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct NodeInfo {
     pub node_id: NodeID,
     pub address: SocketAddrV4,
@@ -32,22 +46,70 @@ impl NodeInfo {
         }
     }
 
-    fn to_bytes(&self) -> [u8; 26] {
-        let mut output = [0u8; 26];
-        (&mut output[..20]).copy_from_slice(&self.node_id.as_bytes());
+    fn to_bytes(&self) -> [u8; COMPACT_LEN] {
+        let mut output = [0u8; COMPACT_LEN];
+        (&mut output[..20]).copy_from_slice(&self.node_id.to_bytes());
         addr::write_to(&self.address, &mut output[20..]);
 
         output
     }
 
-    fn from_bytes(bytes: &[u8]) -> NodeInfo {
-        let node_id = NodeID::from_bytes(&bytes[..20]);
+    /// Parses "Compact node info". `bytes` must be exactly 26 bytes long.
+    pub fn from_compact_bytes(bytes: &[u8]) -> errors::Result<NodeInfo> {
+        if bytes.len() != COMPACT_LEN {
+            Err(errors::ErrorKind::InvalidCompactNodeInfo {
+                expected: COMPACT_LEN,
+                got: bytes.len(),
+            })?;
+        }
+
+        let node_id = NodeID::try_from_bytes(&bytes[..20])?;
         let address = addr::from_bytes(&bytes[20..]);
 
-        NodeInfo { node_id, address }
+        Ok(NodeInfo { node_id, address })
+    }
+}
+
+impl fmt::Display for NodeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:x}@{}", self.node_id, self.address)
     }
 }
 
+/// Fields of the serialized form of [`NodeInfo`].
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "NodeInfo")]
+struct NodeInfoFields {
+    id: String,
+    address: Addr,
+}
+
+impl Serialize for NodeInfo {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        NodeInfoFields {
+            id: format!("{:x}", self.node_id),
+            address: Addr::from(self.address),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeInfo {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let fields = NodeInfoFields::deserialize(deserializer)?;
+        let node_id = NodeID::from_hex_str(&fields.id).map_err(de::Error::custom)?;
+
+        Ok(NodeInfo::new(node_id, fields.address.into()))
+    }
+}
+
+/// Serializes nodes in the "Compact node info" format.
 pub fn serialize<S>(nodes: &Vec<NodeInfo>, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -81,18 +143,13 @@ impl<'de> Visitor<'de> for NodeInfoVecVisitor {
         E: de::Error,
     {
         let len = v.len();
-        if len % 26 != 0 {
+        if len % COMPACT_LEN != 0 {
             return Err(de::Error::invalid_length(len, &self));
         }
 
-        let mut output: Vec<NodeInfo> = Vec::with_capacity(len / 26);
-
-        for idx in (0..len).step_by(26) {
-            let node_info = NodeInfo::from_bytes(&v[idx..]);
-            output.push(node_info);
-        }
-
-        Ok(output)
+        v.chunks(COMPACT_LEN)
+            .map(|chunk| NodeInfo::from_compact_bytes(chunk).map_err(de::Error::custom))
+            .collect()
     }
 
     fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
@@ -106,20 +163,80 @@ impl<'de> Visitor<'de> for NodeInfoVecVisitor {
 #[cfg(test)]
 mod tests {
     use super::NodeInfo;
+    use crate::errors::ErrorKind;
     use failure::Error;
+    use serde_test::{
+        assert_tokens,
+        Token,
+    };
     use std::{
         net::SocketAddrV4,
         str::FromStr,
     };
 
+    fn node() -> Result<NodeInfo, Error> {
+        Ok(NodeInfo::new(
+            b"abcdefghij0123456789".into(),
+            SocketAddrV4::from_str("129.21.60.68:3454")?,
+        ))
+    }
+
     #[test]
     fn test_to_bytes() -> Result<(), Error> {
-        let node = NodeInfo::new(
-            b"abcdefghij0123456789".into(),
-            SocketAddrV4::from_str("129.21.60.68:3454")?.into(),
+        let bytes = node()?.to_bytes();
+        assert_eq!(&bytes[..20], b"abcdefghij0123456789");
+        assert_eq!(&bytes[20..], &[129, 21, 60, 68, 0x0d, 0x7e]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn from_compact_bytes() -> Result<(), Error> {
+        let bytes = node()?.to_bytes();
+        assert_eq!(NodeInfo::from_compact_bytes(&bytes)?, node()?);
+
+        let mut long = bytes.to_vec();
+        long.push(0);
+
+        for invalid in &[&[][..], &bytes[..25], &long[..]] {
+            let err = NodeInfo::from_compact_bytes(invalid).unwrap_err();
+            match err.kind() {
+                ErrorKind::InvalidCompactNodeInfo { expected: 26, got } => {
+                    assert_eq!(*got, invalid.len())
+                }
+                kind => panic!("unexpected error {}", kind),
+            };
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn serde() -> Result<(), Error> {
+        assert_tokens(
+            &node()?,
+            &[
+                Token::Struct {
+                    name: "NodeInfo",
+                    len: 2,
+                },
+                Token::Str("id"),
+                Token::Str("6162636465666768696a30313233343536373839"),
+                Token::Str("address"),
+                Token::Str("129.21.60.68:3454"),
+                Token::StructEnd,
+            ],
         );
 
-        let _bytes = node.to_bytes();
+        Ok(())
+    }
+
+    #[test]
+    fn display() -> Result<(), Error> {
+        assert_eq!(
+            node()?.to_string(),
+            "6162636465666768696a30313233343536373839@129.21.60.68:3454"
+        );
 
         Ok(())
     }
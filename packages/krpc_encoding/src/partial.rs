@@ -0,0 +1,44 @@
+//! The envelope fields of a message, decoded on their own so a message whose
+//! other fields are invalid can still be answered.
+
+use crate::errors::{
+    ErrorKind,
+    Result,
+};
+use serde_bencode;
+use serde_bytes::ByteBuf;
+use serde_derive::Deserialize;
+
+/// Transaction id, type and method name of a message. The rest of the
+/// message only has to be valid bencode.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct PartialMessage {
+    #[serde(rename = "t")]
+    transaction_id: ByteBuf,
+
+    #[serde(rename = "y")]
+    message_type: ByteBuf,
+
+    #[serde(rename = "q", default)]
+    query_name: Option<ByteBuf>,
+}
+
+impl PartialMessage {
+    pub fn decode(bytes: &[u8]) -> Result<PartialMessage> {
+        Ok(serde_bencode::de::from_bytes(bytes)
+            .map_err(|cause| ErrorKind::DecodeError { cause })?)
+    }
+
+    pub fn transaction_id(&self) -> &[u8] {
+        &self.transaction_id
+    }
+
+    pub fn is_query(&self) -> bool {
+        &self.message_type[..] == b"q"
+    }
+
+    /// Method name of a query, not necessarily one which is known.
+    pub fn query_name(&self) -> Option<&[u8]> {
+        self.query_name.as_ref().map(|name| &name[..])
+    }
+}
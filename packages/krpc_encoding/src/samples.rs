@@ -0,0 +1,64 @@
+use crate::NodeID;
+use serde::{
+    de::{
+        self,
+        Visitor,
+    },
+    Deserializer,
+    Serializer,
+};
+use std::fmt;
+
+/// Serializes info-hash samples as a single string of concatenated 20 byte
+/// info-hashes as described in [BEP-0051].
+///
+/// [BEP-0051]: http://www.bittorrent.org/beps/bep_0051.html
+pub fn serialize<S>(samples: &Vec<NodeID>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let bytes = samples
+        .iter()
+        .flat_map(|sample| sample.to_bytes().to_vec())
+        .collect::<Vec<u8>>();
+
+    serializer.serialize_bytes(&bytes)
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<NodeID>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_bytes(SamplesVisitor)
+}
+
+struct SamplesVisitor;
+
+impl<'de> Visitor<'de> for SamplesVisitor {
+    type Value = Vec<NodeID>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("a byte array with a size which is a multiple of 20")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        let len = v.len();
+        if len % 20 != 0 {
+            return Err(de::Error::invalid_length(len, &self));
+        }
+
+        v.chunks(20)
+            .map(|chunk| NodeID::try_from_bytes(chunk).map_err(de::Error::custom))
+            .collect()
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        self.visit_bytes(&v)
+    }
+}
@@ -0,0 +1,154 @@
+//! Node ids derived from the node's external IP as described in [BEP-0042],
+//! making it expensive to pick ids close to a target.
+//!
+//! [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+
+use crate::NodeID;
+use rand;
+use std::net::Ipv4Addr;
+
+/// Bits of each octet of the IP used for the id.
+const IP_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+
+impl NodeID {
+    /// Generates a random id which is valid for a node with the external
+    /// address `ip`.
+    pub fn secure(ip: Ipv4Addr) -> NodeID {
+        secure_id(ip, rand::random(), rand::random())
+    }
+
+    /// Whether this id is valid for a node with the external address `ip`.
+    /// Any id is valid for local and private addresses.
+    pub fn is_secure_for(&self, ip: Ipv4Addr) -> bool {
+        if is_exempt(ip) {
+            return true;
+        }
+
+        let id = self.to_bytes();
+        let prefix = id_prefix(ip, id[19]);
+
+        id[0] == prefix[0] && id[1] == prefix[1] && id[2] & 0xf8 == prefix[2]
+    }
+}
+
+/// Id for `ip` with the random byte `r` and the rest of the bits taken from
+/// `random`.
+fn secure_id(ip: Ipv4Addr, r: u8, random: [u8; 20]) -> NodeID {
+    let mut id = random;
+    let prefix = id_prefix(ip, r);
+
+    id[0] = prefix[0];
+    id[1] = prefix[1];
+    id[2] = prefix[2] | (id[2] & 0x07);
+    id[19] = r;
+
+    NodeID::from(id)
+}
+
+/// The 21 bits at the start of ids for `ip` with the random byte `r`.
+fn id_prefix(ip: Ipv4Addr, r: u8) -> [u8; 3] {
+    let mut masked = ip.octets();
+    for (octet, mask) in masked.iter_mut().zip(IP_MASK.iter()) {
+        *octet &= mask;
+    }
+    masked[0] |= (r & 0x07) << 5;
+
+    let crc = crc32c(&masked);
+
+    [
+        (crc >> 24) as u8,
+        (crc >> 16) as u8,
+        (crc >> 8) as u8 & 0xf8,
+    ]
+}
+
+/// Addresses which ids aren't checked for.
+fn is_exempt(ip: Ipv4Addr) -> bool {
+    ip.is_private() || ip.is_loopback() || ip.is_link_local()
+}
+
+/// CRC-32C (Castagnoli) checksum.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::secure_id;
+    use crate::NodeID;
+    use std::net::Ipv4Addr;
+
+    /// Examples from BEP-0042.
+    const EXAMPLES: [(&str, u8, &str); 5] = [
+        (
+            "124.31.75.21",
+            1,
+            "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401",
+        ),
+        (
+            "21.75.31.124",
+            86,
+            "5a3ce9c14e7a08645677bbd1cfe7d8f956d53256",
+        ),
+        (
+            "65.23.51.170",
+            22,
+            "a5d43220bc8f112a3d426c84764f8c2a1150e616",
+        ),
+        (
+            "84.124.73.14",
+            65,
+            "1b0321dd1bb1fe518101ceef99462b947a01ff41",
+        ),
+        (
+            "43.213.53.83",
+            90,
+            "e56f6cbf5b7c4be0237986d5243b87aa6d51305a",
+        ),
+    ];
+
+    #[test]
+    fn bep_examples() {
+        for (ip, r, expected) in EXAMPLES.iter() {
+            let ip: Ipv4Addr = ip.parse().unwrap();
+            let expected = NodeID::from_hex_str(expected).unwrap();
+
+            assert!(expected.is_secure_for(ip), "{}", ip);
+            assert_eq!(secure_id(ip, *r, expected.to_bytes()), expected, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn generated_ids_valid() {
+        let ip: Ipv4Addr = "124.31.75.21".parse().unwrap();
+        let other: Ipv4Addr = "21.75.31.124".parse().unwrap();
+
+        for _ in 0..100 {
+            let id = NodeID::secure(ip);
+            assert!(id.is_secure_for(ip));
+            assert!(!id.is_secure_for(other));
+        }
+    }
+
+    #[test]
+    fn private_addresses_exempt() {
+        let id = NodeID::secure("124.31.75.21".parse().unwrap());
+
+        for ip in &["10.0.0.1", "192.168.1.1", "127.0.0.1", "169.254.0.1"] {
+            assert!(id.is_secure_for(ip.parse().unwrap()));
+        }
+    }
+}
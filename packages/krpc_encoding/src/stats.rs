@@ -0,0 +1,90 @@
+//! Counters of problems tolerated while decoding messages.
+
+use lazy_static::lazy_static;
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Mutex,
+    },
+};
+
+static IGNORED_TOKENS: AtomicUsize = AtomicUsize::new(0);
+
+static MESSAGES_WITH_UNKNOWN_KEYS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref UNKNOWN_KEYS: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+}
+
+thread_local! {
+    /// Set while decoding a message whose token was ignored. Untagged enums
+    /// try deserializing the same fields for several variants, so problems
+    /// are only counted once the whole message is decoded.
+    static TOKEN_IGNORED: Cell<bool> = Cell::new(false);
+}
+
+/// Problems tolerated while decoding messages with [`Envelope::decode`] in
+/// this process.
+///
+/// [`Envelope::decode`]: crate::Envelope::decode
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DecodeStats {
+    /// Messages decoded without a token because it wasn't a string.
+    pub ignored_tokens: usize,
+
+    /// Messages decoded with [`Envelope::decode_capturing`] which had keys
+    /// outside the schema.
+    ///
+    /// [`Envelope::decode_capturing`]: crate::Envelope::decode_capturing
+    pub messages_with_unknown_keys: usize,
+
+    /// Number of those messages with each unknown key, named like
+    /// [`UnknownFields::key_names`].
+    ///
+    /// [`UnknownFields::key_names`]: crate::UnknownFields::key_names
+    pub unknown_keys: BTreeMap<String, usize>,
+}
+
+pub fn decode_stats() -> DecodeStats {
+    DecodeStats {
+        ignored_tokens: IGNORED_TOKENS.load(Ordering::Relaxed),
+        messages_with_unknown_keys: MESSAGES_WITH_UNKNOWN_KEYS.load(Ordering::Relaxed),
+        unknown_keys: UNKNOWN_KEYS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone(),
+    }
+}
+
+/// Counts a message with the unknown keys `names`.
+pub(crate) fn record_unknown_keys(names: &[String]) {
+    MESSAGES_WITH_UNKNOWN_KEYS.fetch_add(1, Ordering::Relaxed);
+
+    let mut unknown_keys = UNKNOWN_KEYS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    for name in names {
+        *unknown_keys.entry(name.clone()).or_insert(0) += 1;
+    }
+}
+
+pub(crate) fn record_ignored_token() {
+    TOKEN_IGNORED.with(|ignored| ignored.set(true));
+}
+
+/// Forgets problems recorded while decoding a previous message.
+pub(crate) fn start_message() {
+    TOKEN_IGNORED.with(|ignored| ignored.set(false));
+}
+
+/// Counts problems recorded while decoding a message which was decoded.
+pub(crate) fn finish_message() {
+    if TOKEN_IGNORED.with(|ignored| ignored.replace(false)) {
+        IGNORED_TOKENS.fetch_add(1, Ordering::Relaxed);
+    }
+}
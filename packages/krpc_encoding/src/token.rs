@@ -0,0 +1,121 @@
+//! Tolerant (de)serializer for the `token` of responses. Use with
+//! `#[serde(default, with = "token")]` and skip serializing `None`.
+//!
+//! Any string, including an empty one, is a token. Tokens of other types,
+//! which some broken clients send, are ignored so the rest of the response
+//! can still be used. Ignored tokens are counted in [`DecodeStats`].
+//!
+//! [`DecodeStats`]: crate::DecodeStats
+
+use crate::stats;
+use serde::{
+    de::{
+        self,
+        IgnoredAny,
+        MapAccess,
+        SeqAccess,
+        Visitor,
+    },
+    Deserializer,
+    Serializer,
+};
+use std::fmt;
+
+pub fn serialize<S>(token: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match token {
+        Some(token) => serializer.serialize_bytes(token),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(TokenVisitor)
+}
+
+struct TokenVisitor;
+
+impl TokenVisitor {
+    fn ignore<T, E>(self) -> Result<Option<T>, E> {
+        stats::record_ignored_token();
+        Ok(None)
+    }
+}
+
+impl<'de> Visitor<'de> for TokenVisitor {
+    type Value = Option<Vec<u8>>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("a token")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(Some(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(Some(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        self.visit_bytes(v.as_bytes())
+    }
+
+    fn visit_i64<E>(self, _v: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        self.ignore()
+    }
+
+    fn visit_u64<E>(self, _v: u64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        self.ignore()
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        self.ignore()
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+        self.ignore()
+    }
+
+    fn visit_none<E>(self) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(None)
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(None)
+    }
+}
@@ -0,0 +1,142 @@
+//! Opt-in decoding which keeps the keys of a message that aren't part of
+//! the schema, for studying what other clients send. Keys are kept as
+//! bytes so messages can be encoded again exactly as they were received.
+
+use crate::{
+    errors::{
+        ErrorKind,
+        Result,
+    },
+    stats,
+    Envelope,
+};
+use serde_bencode::value::Value;
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+
+/// Keys of the message dictionary handled by [`Envelope`].
+const ENVELOPE_KEYS: &[&[u8]] = &[b"a", b"e", b"ip", b"q", b"r", b"ro", b"t", b"v", b"y"];
+
+/// Keys of the `r` dictionary handled by [`Response`](crate::Response).
+const RESPONSE_KEYS: &[&[u8]] = &[
+    b"id",
+    b"interval",
+    b"nodes",
+    b"num",
+    b"samples",
+    b"token",
+    b"values",
+];
+
+/// Keys of a message which aren't part of the schema along with their
+/// values.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct UnknownFields {
+    /// Unknown keys of the message dictionary.
+    pub envelope: BTreeMap<Vec<u8>, Value>,
+
+    /// Unknown keys of the `r` dictionary of a response.
+    pub response: BTreeMap<Vec<u8>, Value>,
+}
+
+impl UnknownFields {
+    pub fn is_empty(&self) -> bool {
+        self.envelope.is_empty() && self.response.is_empty()
+    }
+
+    /// Names of every unknown key. Keys of the `r` dictionary are prefixed
+    /// with `r.`.
+    pub fn key_names(&self) -> Vec<String> {
+        let envelope = self
+            .envelope
+            .keys()
+            .map(|key| String::from_utf8_lossy(key).into_owned());
+        let response = self
+            .response
+            .keys()
+            .map(|key| format!("r.{}", String::from_utf8_lossy(key)));
+
+        envelope.chain(response).collect()
+    }
+}
+
+/// A message decoded with [`Envelope::decode_capturing`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedEnvelope {
+    pub envelope: Envelope,
+    pub unknown: UnknownFields,
+}
+
+impl CapturedEnvelope {
+    /// Encodes the message along with its unknown keys. A message which was
+    /// encoded canonically encodes back to the same bytes.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let encoded = self.envelope.encode()?;
+        if self.unknown.is_empty() {
+            return Ok(encoded);
+        }
+
+        let mut dict = match decode_value(&encoded)? {
+            Value::Dict(dict) => dict,
+            _ => unreachable!("messages are encoded as dictionaries"),
+        };
+
+        if !self.unknown.response.is_empty() {
+            if let Some(Value::Dict(response)) = dict.get_mut(&b"r"[..]) {
+                extend(response, &self.unknown.response);
+            }
+        }
+        extend(&mut dict, &self.unknown.envelope);
+
+        serde_bencode::ser::to_bytes(&Value::Dict(dict))
+            .map_err(|cause| ErrorKind::EncodeError { cause }.into())
+    }
+}
+
+impl Envelope {
+    /// Like [`decode`](Envelope::decode) but also keeps the keys of the
+    /// message and of its `r` dictionary which aren't part of the schema.
+    /// Messages with unknown keys are counted in
+    /// [`DecodeStats`](crate::DecodeStats).
+    pub fn decode_capturing(bytes: &[u8]) -> Result<CapturedEnvelope> {
+        let envelope = Envelope::decode(bytes)?;
+
+        let mut unknown = UnknownFields::default();
+        if let Value::Dict(dict) = decode_value(bytes)? {
+            for (key, value) in dict {
+                if key == b"r" {
+                    if let Value::Dict(response) = value {
+                        unknown.response = unknown_keys(response, RESPONSE_KEYS);
+                    }
+                } else if !ENVELOPE_KEYS.contains(&&key[..]) {
+                    unknown.envelope.insert(key, value);
+                }
+            }
+        }
+
+        if !unknown.is_empty() {
+            stats::record_unknown_keys(&unknown.key_names());
+        }
+
+        Ok(CapturedEnvelope { envelope, unknown })
+    }
+}
+
+fn decode_value(bytes: &[u8]) -> Result<Value> {
+    serde_bencode::de::from_bytes(bytes).map_err(|cause| ErrorKind::DecodeError { cause }.into())
+}
+
+fn unknown_keys(dict: HashMap<Vec<u8>, Value>, known: &[&[u8]]) -> BTreeMap<Vec<u8>, Value> {
+    dict.into_iter()
+        .filter(|(key, _)| !known.contains(&&key[..]))
+        .collect()
+}
+
+/// Adds `extra` to `dict` without replacing keys already in it.
+fn extend(dict: &mut HashMap<Vec<u8>, Value>, extra: &BTreeMap<Vec<u8>, Value>) {
+    for (key, value) in extra {
+        dict.entry(key.clone()).or_insert_with(|| value.clone());
+    }
+}
@@ -1,11 +1,25 @@
 use failure::Error;
 use krpc_encoding::{
+    decode_stats,
+    errors::ErrorKind,
+    Addr,
+    DhtDat,
     Envelope,
+    ExtensionHandshake,
     KRPCError,
     Message,
+    MetadataMessage,
+    NodeID,
     NodeInfo,
+    PartialMessage,
     Query,
     Response,
+    Violation,
+};
+use serde_bytes::ByteBuf;
+use serde_test::{
+    assert_tokens,
+    Token,
 };
 use std::{
     net::SocketAddrV4,
@@ -76,6 +90,44 @@ fn ping_response() -> Result<(), Error> {
     test_serialize_deserialize(parsed, raw)
 }
 
+#[test]
+fn query_constructor() {
+    let query = || Query::Ping {
+        id: b"abcdefghij0123456789".into(),
+    };
+
+    assert_eq!(
+        Envelope::query(b"aa".to_vec(), query()),
+        Envelope {
+            ip: None,
+            transaction_id: b"aa".to_vec(),
+            version: None,
+            message_type: Message::Query { query: query() },
+            read_only: false,
+        }
+    );
+}
+
+#[test]
+fn response_constructor() {
+    let response = || Response::OnlyID {
+        id: b"mnopqrstuvwxyz123456".into(),
+    };
+
+    assert_eq!(
+        Envelope::response(b"aa".to_vec(), response()),
+        Envelope {
+            ip: None,
+            transaction_id: b"aa".to_vec(),
+            version: None,
+            message_type: Message::Response {
+                response: response(),
+            },
+            read_only: false,
+        }
+    );
+}
+
 #[test]
 fn error() -> Result<(), Error> {
     let parsed = Envelope {
@@ -114,6 +166,32 @@ fn announce_peer_request() -> Result<(), Error> {
     test_serialize_deserialize(parsed, raw)
 }
 
+#[test]
+fn clone_query() -> Result<(), Error> {
+    let original = Envelope {
+        ip: Some("129.21.63.170:34238".parse()?),
+        transaction_id: b"aa".to_vec(),
+        version: Some(ByteBuf::from(b"RC\x00\x01".to_vec())),
+        message_type: Message::Query {
+            query: Query::AnnouncePeer {
+                id: b"abcdefghij0123456789".into(),
+                implied_port: false,
+                port: Some(6881),
+                info_hash: b"mnopqrstuvwxyz123456".into(),
+                token: b"aoeusnth".to_vec(),
+            },
+        },
+        read_only: true,
+    };
+
+    let cloned = original.clone();
+
+    assert_eq!(cloned, original);
+    assert_eq!(cloned.encode()?, original.encode()?);
+
+    Ok(())
+}
+
 #[test]
 fn get_nodes_response() -> Result<(), Error> {
     let parsed = Envelope {
@@ -281,3 +359,535 @@ fn with_version() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn long_version() -> Result<(), Error> {
+    let raw = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:v8:\xffLT\x00long1:y1:re";
+    let message = Envelope::decode(raw)?;
+
+    assert_eq!(message.version, Some(b"\xffLT\x00long".to_vec().into()));
+    assert_eq!(&message.encode()?[..], &raw[..]);
+
+    Ok(())
+}
+
+/// Addresses are compact inside messages while serializing them on their own
+/// gives an `"ip:port"` string.
+#[test]
+fn addr_formats() -> Result<(), Error> {
+    let addr: Addr = SocketAddrV4::from_str("129.21.60.68:34254")?.into();
+    let parsed = Envelope {
+        ip: Some(addr),
+        transaction_id: b"aa".to_vec(),
+        version: None,
+        message_type: Message::Response {
+            response: Response::GetPeers {
+                id: b"abcdefghij0123456789".into(),
+                token: None,
+                peers: vec![addr],
+                nodes: Vec::new(),
+            },
+        },
+        read_only: false,
+    };
+
+    let serialized = parsed.encode()?;
+    assert!(serialized.starts_with(b"d2:ip6:\x81\x15\x3c\x44\x85\xce"));
+    assert!(serialized
+        .windows(12)
+        .any(|window| window == b"6:valuesl6:\x81"));
+    assert_eq!(Envelope::decode(&serialized)?, parsed);
+
+    assert_tokens(&addr, &[Token::Str("129.21.60.68:34254")]);
+
+    Ok(())
+}
+
+#[test]
+fn get_peers_response_with_nodes() -> Result<(), Error> {
+    let raw = b"d1:rd2:id20:abcdefghij01234567895:nodes26:mnopqrstuvwxyz123456\x7f\x00\x00\x01\x1a\xe15:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+
+    let parsed = Envelope {
+        ip: None,
+        transaction_id: b"aa".to_vec(),
+        version: None,
+        message_type: Message::Response {
+            response: Response::GetPeers {
+                id: b"abcdefghij0123456789".into(),
+                token: Some(b"aoeusnth".to_vec()),
+                peers: vec![
+                    SocketAddrV4::from_str("97.120.106.101:11893")?.into(),
+                    SocketAddrV4::from_str("105.100.104.116:28269")?.into(),
+                ],
+                nodes: vec![NodeInfo::new(
+                    b"mnopqrstuvwxyz123456".into(),
+                    SocketAddrV4::from_str("127.0.0.1:6881")?,
+                )],
+            },
+        },
+        read_only: false,
+    };
+
+    test_serialize_deserialize(parsed, raw)
+}
+
+#[test]
+fn get_peers_response_without_nodes() -> Result<(), Error> {
+    let raw = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.uee1:t2:aa1:y1:re";
+
+    let parsed = Envelope {
+        ip: None,
+        transaction_id: b"aa".to_vec(),
+        version: None,
+        message_type: Message::Response {
+            response: Response::GetPeers {
+                id: b"abcdefghij0123456789".into(),
+                token: Some(b"aoeusnth".to_vec()),
+                peers: vec![SocketAddrV4::from_str("97.120.106.101:11893")?.into()],
+                nodes: Vec::new(),
+            },
+        },
+        read_only: false,
+    };
+
+    test_serialize_deserialize(parsed, raw)
+}
+
+#[test]
+fn malformed_values_rejected() {
+    // The peer isn't 6 bytes long. Decoding used to fall through to
+    // Response::NextHop and drop the values.
+    let raw = b"d1:rd2:id20:abcdefghij01234567895:nodes26:mnopqrstuvwxyz123456\x7f\x00\x00\x01\x1a\xe15:token8:aoeusnth6:valuesl5:axje.ee1:t2:aa1:y1:re";
+
+    assert!(Envelope::decode(raw).is_err());
+}
+
+#[test]
+fn sample_infohashes_response() -> Result<(), Error> {
+    let parsed = Envelope {
+        ip: None,
+        transaction_id: b"aa".to_vec(),
+        version: None,
+        message_type: Message::Response {
+            response: Response::Samples {
+                id: b"abcdefghij0123456789".into(),
+                interval: Some(60),
+                nodes: Vec::new(),
+                num: Some(2),
+                samples: vec![
+                    b"abcdefghij0123456789".into(),
+                    b"mnopqrstuvwxyz123456".into(),
+                ],
+            },
+        },
+        read_only: false,
+    };
+
+    let raw = b"d1:rd2:id20:abcdefghij01234567898:intervali60e5:nodes0:3:numi2e7:samples40:abcdefghij0123456789mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+    test_serialize_deserialize(parsed, raw)
+}
+
+fn announce_peer_query(port: u16) -> Envelope {
+    Envelope {
+        ip: None,
+        transaction_id: b"aa".to_vec(),
+        version: None,
+        message_type: Message::Query {
+            query: Query::AnnouncePeer {
+                id: b"abcdefghij0123456789".into(),
+                implied_port: true,
+                port: Some(port),
+                info_hash: b"mnopqrstuvwxyz123456".into(),
+                token: b"aoeusnth".to_vec(),
+            },
+        },
+        read_only: false,
+    }
+}
+
+/// Some clients send integers as strings of digits. Such messages should
+/// decode while still being encoded with integers.
+#[test]
+fn announce_peer_string_integers() -> Result<(), Error> {
+    let raw = b"d1:ad2:id20:abcdefghij012345678912:implied_port1:19:info_hash20:mnopqrstuvwxyz1234564:port4:68815:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+    let decoded = Envelope::decode(raw)?;
+    assert_eq!(decoded, announce_peer_query(6881));
+
+    let canonical = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+    assert_eq!(&decoded.encode()?[..], &canonical[..]);
+
+    Ok(())
+}
+
+#[test]
+fn announce_peer_invalid_port() {
+    let negative = b"d1:ad2:id20:abcdefghij012345678912:implied_porti0e9:info_hash20:mnopqrstuvwxyz1234564:porti-1e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+    assert!(Envelope::decode(negative).is_err());
+
+    let too_large = b"d1:ad2:id20:abcdefghij012345678912:implied_porti0e9:info_hash20:mnopqrstuvwxyz1234564:port5:700005:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+    assert!(Envelope::decode(too_large).is_err());
+}
+
+#[test]
+fn announce_peer_missing_port() -> Result<(), Error> {
+    let missing = b"d1:ad2:id20:abcdefghij012345678912:implied_porti0e9:info_hash20:mnopqrstuvwxyz1234565:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+    let error = Envelope::decode(missing).unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("port required when implied_port is false"),
+        "{}",
+        error
+    );
+
+    // The port isn't needed when it's implied.
+    let implied = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234565:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+    match Envelope::decode(implied)?.message_type {
+        Message::Query {
+            query:
+                Query::AnnouncePeer {
+                    implied_port: true,
+                    port: None,
+                    ..
+                },
+        } => {}
+        message => panic!("unexpected message {:?}", message),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn sample_infohashes_string_integers() -> Result<(), Error> {
+    let raw = b"d1:rd2:id20:abcdefghij01234567898:interval2:605:nodes0:3:num1:27:samples40:abcdefghij0123456789mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+
+    match Envelope::decode(raw)?.message_type {
+        Message::Response {
+            response: Response::Samples { interval, num, .. },
+        } => {
+            assert_eq!(interval, Some(60));
+            assert_eq!(num, Some(2));
+        }
+        message => panic!("unexpected message {:?}", message),
+    };
+
+    Ok(())
+}
+
+fn test_metadata_message(parsed: MetadataMessage, raw: &[u8]) -> Result<(), Error> {
+    assert_eq!(raw, &parsed.encode()?[..]);
+    assert_eq!(parsed, MetadataMessage::decode(raw)?);
+
+    Ok(())
+}
+
+#[test]
+fn metadata_request() -> Result<(), Error> {
+    test_metadata_message(
+        MetadataMessage::Request { piece: 0 },
+        b"d8:msg_typei0e5:piecei0ee",
+    )
+}
+
+#[test]
+fn metadata_data() -> Result<(), Error> {
+    test_metadata_message(
+        MetadataMessage::Data {
+            piece: 1,
+            total_size: 16390,
+            data: ByteBuf::from(b"d4:spami1ee".to_vec()),
+        },
+        b"d8:msg_typei1e5:piecei1e10:total_sizei16390eed4:spami1ee",
+    )
+}
+
+#[test]
+fn metadata_reject() -> Result<(), Error> {
+    test_metadata_message(
+        MetadataMessage::Reject { piece: 3 },
+        b"d8:msg_typei2e5:piecei3ee",
+    )
+}
+
+#[test]
+fn metadata_invalid() {
+    // Data without total_size.
+    assert!(MetadataMessage::decode(b"d8:msg_typei1e5:piecei0eeabc").is_err());
+
+    // Request followed by data.
+    assert!(MetadataMessage::decode(b"d8:msg_typei0e5:piecei0eeabc").is_err());
+
+    // Unknown message type.
+    assert!(MetadataMessage::decode(b"d8:msg_typei7e5:piecei0ee").is_err());
+
+    // Truncated header.
+    assert!(MetadataMessage::decode(b"d8:msg_typei1e5:piece").is_err());
+}
+
+#[test]
+fn extension_handshake() -> Result<(), Error> {
+    let raw = b"d1:md11:ut_metadatai3e6:ut_pexi1ee13:metadata_sizei31235e1:v4:test6:yourip4:\x7f\x00\x00\x01e";
+    let handshake = ExtensionHandshake::decode(raw)?;
+
+    assert_eq!(handshake.m.get("ut_metadata"), Some(&3));
+    assert_eq!(handshake.m.get("ut_pex"), Some(&1));
+    assert_eq!(handshake.metadata_size, Some(31235));
+
+    let mut ours = ExtensionHandshake::default();
+    ours.m.insert("ut_metadata".to_string(), 1);
+    assert_eq!(&ours.encode()?[..], &b"d1:md11:ut_metadatai1eee"[..]);
+
+    Ok(())
+}
+
+/// Decodes a `find_node` response with `token` as the bencoded token and
+/// returns the decoded token after checking the nodes were recovered.
+fn decode_next_hop_token(token: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let mut raw =
+        b"d1:rd2:id20:abcdefghij01234567895:nodes26:mnopqrstuvwxyz123456\x7f\x00\x00\x01\x1a\xe1"
+            .to_vec();
+    if !token.is_empty() {
+        raw.extend_from_slice(b"5:token");
+        raw.extend_from_slice(token);
+    }
+    raw.extend_from_slice(b"e1:t2:aa1:y1:re");
+
+    match Envelope::decode(&raw)?.message_type {
+        Message::Response {
+            response: Response::NextHop { id, token, nodes },
+        } => {
+            assert_eq!(id, b"abcdefghij0123456789".into());
+            assert_eq!(
+                nodes,
+                vec![NodeInfo::new(
+                    b"mnopqrstuvwxyz123456".into(),
+                    SocketAddrV4::from_str("127.0.0.1:6881")?,
+                )]
+            );
+
+            Ok(token)
+        }
+        message => panic!("unexpected message {:?}", message),
+    }
+}
+
+#[test]
+fn string_tokens() -> Result<(), Error> {
+    assert_eq!(decode_next_hop_token(b"")?, None);
+    assert_eq!(decode_next_hop_token(b"0:")?, Some(Vec::new()));
+    assert_eq!(decode_next_hop_token(b"4:abcd")?, Some(b"abcd".to_vec()));
+    assert_eq!(
+        decode_next_hop_token(b"20:abcdefghij0123456789")?,
+        Some(b"abcdefghij0123456789".to_vec())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn malformed_tokens_ignored() -> Result<(), Error> {
+    let before = decode_stats().ignored_tokens;
+
+    assert_eq!(decode_next_hop_token(b"i42e")?, None);
+    assert_eq!(decode_next_hop_token(b"li1ei2ee")?, None);
+
+    // Other tests may decode concurrently.
+    assert!(decode_stats().ignored_tokens >= before + 2);
+
+    Ok(())
+}
+
+#[test]
+fn empty_token_encoded() -> Result<(), Error> {
+    let raw = b"d1:rd2:id20:abcdefghij01234567895:nodes0:5:token0:e1:t2:aa1:y1:re";
+
+    let parsed = Envelope {
+        ip: None,
+        transaction_id: b"aa".to_vec(),
+        version: None,
+        message_type: Message::Response {
+            response: Response::NextHop {
+                id: b"abcdefghij0123456789".into(),
+                token: Some(Vec::new()),
+                nodes: Vec::new(),
+            },
+        },
+        read_only: false,
+    };
+
+    test_serialize_deserialize(parsed, raw)
+}
+
+#[test]
+fn unknown_keys_round_trip() -> Result<(), Error> {
+    let raw = b"d1:rd2:id20:abcdefghij01234567893:zzzi1ee1:t2:aa1:y1:r2:zz3:fooe";
+
+    let captured = Envelope::decode_capturing(raw)?;
+    assert_eq!(
+        captured.envelope,
+        Envelope {
+            ip: None,
+            transaction_id: b"aa".to_vec(),
+            version: None,
+            message_type: Message::Response {
+                response: Response::OnlyID {
+                    id: b"abcdefghij0123456789".into(),
+                },
+            },
+            read_only: false,
+        }
+    );
+    assert_eq!(
+        captured.unknown.key_names(),
+        vec!["zz".to_string(), "r.zzz".to_string()]
+    );
+    assert_eq!(&captured.encode()?[..], &raw[..]);
+
+    Ok(())
+}
+
+#[test]
+fn unknown_keys_counted() -> Result<(), Error> {
+    let raw = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:q4:zzzzi1ee";
+    let before = decode_stats()
+        .unknown_keys
+        .get("zzzz")
+        .cloned()
+        .unwrap_or(0);
+
+    let captured = Envelope::decode_capturing(raw)?;
+    assert_eq!(&captured.encode()?[..], &raw[..]);
+
+    // Other tests may decode concurrently.
+    let stats = decode_stats();
+    assert!(stats.messages_with_unknown_keys >= 1);
+    assert!(stats.unknown_keys["zzzz"] >= before + 1);
+
+    Ok(())
+}
+
+#[test]
+fn known_keys_not_captured() -> Result<(), Error> {
+    let raw = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+
+    let captured = Envelope::decode_capturing(raw)?;
+    assert!(captured.unknown.is_empty());
+    assert_eq!(&captured.encode()?[..], &raw[..]);
+
+    Ok(())
+}
+
+#[test]
+fn utorrent_dht_dat() -> Result<(), Error> {
+    let dat = DhtDat::decode(include_bytes!("fixtures/dht.dat"))?;
+
+    assert_eq!(
+        dat.id,
+        Some(NodeID::from_hex_str(
+            "a6e21caaaf466768c6095fc28594638deba612e3"
+        )?)
+    );
+    assert_eq!(dat.nodes.len(), 40);
+    assert_eq!(
+        dat.nodes[0],
+        NodeInfo::new(
+            NodeID::from_hex_str("a2d26641ab3845f647944bdd89fd8c183570c31f")?,
+            SocketAddrV4::from_str("85.117.0.223:38105")?,
+        )
+    );
+
+    Ok(())
+}
+
+#[test]
+fn dht_dat_without_nodes() -> Result<(), Error> {
+    let dat = DhtDat::decode(b"d3:agei1ee")?;
+
+    assert_eq!(dat.id, None);
+    assert!(dat.nodes.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn strict_decoding() -> Result<(), Error> {
+    let canonical = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+    assert_eq!(
+        Envelope::decode_strict(canonical)?,
+        Envelope::decode(canonical)?
+    );
+
+    // The same ping with `q` after `t`, which the lenient decoder accepts.
+    let unsorted = b"d1:ad2:id20:abcdefghij0123456789e1:t2:aa1:q4:ping1:y1:qe";
+    assert_eq!(Envelope::decode(unsorted)?, Envelope::decode(canonical)?);
+
+    match Envelope::decode_strict(unsorted).unwrap_err().kind() {
+        ErrorKind::NonCanonicalEncoding {
+            violation: Violation::UnsortedKeys,
+            offset,
+        } => assert_eq!(*offset, 40),
+        kind => panic!("unexpected error {}", kind),
+    };
+
+    Ok(())
+}
+
+#[test]
+fn deduplicate_nodes() -> Result<(), Error> {
+    let ids: Vec<NodeID> = (0..4).map(|_| NodeID::random()).collect();
+    let node = |id: usize, port: u16| {
+        NodeInfo::new(
+            ids[id].clone(),
+            SocketAddrV4::new([10, 0, 0, 1].into(), port),
+        )
+    };
+
+    let mut response = Response::NextHop {
+        id: NodeID::random(),
+        token: None,
+        nodes: vec![node(0, 1), node(1, 2), node(2, 3), node(1, 4), node(3, 5)],
+    };
+    response.deduplicate_nodes();
+
+    match response {
+        Response::NextHop { nodes, .. } => {
+            assert_eq!(nodes, vec![node(0, 1), node(1, 2), node(2, 3), node(3, 5)])
+        }
+        response => panic!("unexpected response {:?}", response),
+    };
+
+    Ok(())
+}
+
+#[test]
+fn partial_message() -> Result<(), Error> {
+    // A get_peers with a 19 byte info-hash.
+    let invalid = b"d1:ad2:id20:abcdefghij01234567899:info_hash19:mnopqrstuvwxyz12345e1:q9:get_peers1:t2:aa1:y1:qe";
+    assert!(Envelope::decode(invalid).is_err());
+
+    let partial = PartialMessage::decode(invalid)?;
+    assert_eq!(partial.transaction_id(), b"aa");
+    assert!(partial.is_query());
+    assert_eq!(partial.query_name(), Some(&b"get_peers"[..]));
+
+    let response = PartialMessage::decode(b"d1:rd2:id20:abcdefghij0123456789e1:t2:bb1:y1:re")?;
+    assert!(!response.is_query());
+    assert_eq!(response.query_name(), None);
+
+    assert!(PartialMessage::decode(b"d1:y1:qe").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn krpc_error_is_std_error() {
+    let error = KRPCError::new(KRPCError::PROTOCOL_ERROR, "Invalid token");
+    let boxed: Box<dyn std::error::Error> = Box::new(error.clone());
+    assert_eq!(boxed.to_string(), error.to_string());
+
+    let err: krpc_encoding::errors::Error = error.clone().into();
+    match err.kind() {
+        ErrorKind::ReceivedKRPCError { error: received } => assert_eq!(received, &error),
+        kind => panic!("unexpected error {}", kind),
+    }
+}
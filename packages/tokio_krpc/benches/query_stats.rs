@@ -0,0 +1,48 @@
+//! Cost of recording the latency of a transaction, to compare with the
+//! microseconds it takes to send and receive one.
+
+use criterion::{
+    black_box,
+    criterion_group,
+    criterion_main,
+    Criterion,
+};
+use std::{
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use tokio_krpc::query_stats::LatencyHistogram;
+
+fn record(c: &mut Criterion) {
+    let histogram = LatencyHistogram::new();
+    let latency = Duration::from_millis(120);
+
+    c.bench_function("latency record", move |b| {
+        b.iter(|| histogram.record(black_box(latency)))
+    });
+}
+
+/// Records from another thread at the same time, like a multi-threaded
+/// runtime would.
+fn record_contended(c: &mut Criterion) {
+    let histogram = Arc::new(LatencyHistogram::new());
+    let latency = Duration::from_millis(120);
+
+    c.bench_function("latency record contended", move |b| {
+        let other = {
+            let histogram = histogram.clone();
+            thread::spawn(move || {
+                for _ in 0..1_000_000 {
+                    histogram.record(latency);
+                }
+            })
+        };
+
+        b.iter(|| histogram.record(black_box(latency)));
+        other.join().unwrap();
+    });
+}
+
+criterion_group!(benches, record, record_contended);
+criterion_main!(benches);
@@ -1,5 +1,8 @@
 use crate::{
-    inbound_response_envelope::InboundResponseEnvelope,
+    inbound_response_envelope::{
+        InboundResponseEnvelope,
+        ResponseType,
+    },
     recv_errors,
     send_errors,
     transaction_id::{
@@ -8,23 +11,137 @@ use crate::{
     },
 };
 
+use futures::future;
+use krpc_encoding::NodeID;
 use std::{
-    collections::HashMap,
+    collections::{
+        hash_map::DefaultHasher,
+        HashMap,
+    },
+    hash::{
+        Hash,
+        Hasher,
+    },
+    mem,
+    net::SocketAddr,
     sync::{
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
         Arc,
         Mutex,
+        MutexGuard,
+    },
+    time::{
+        Duration,
+        Instant,
     },
 };
 use tokio::prelude::{
-    task::Waker,
+    task::{
+        Context,
+        Waker,
+    },
     Poll,
 };
 
+/// Default limit on the number of transactions in flight at once.
+pub const DEFAULT_MAX_TRANSACTIONS: usize = 4096;
+
+/// Transactions older than this are failed and forgotten by
+/// [`ActiveTransactions::collect_garbage`]. Far longer than any query should
+/// take, only transactions whose future was leaked or is never polled live
+/// this long.
+pub const MAX_TRANSACTION_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// Garbage is collected while adding transactions once this many are
+/// tracked...
+const GC_THRESHOLD: usize = 1024;
+
+/// ...at most this often.
+const GC_INTERVAL: Duration = Duration::from_secs(10);
+
 /// A thread-safe container for information about active transactions. Shared
 /// between many [`ResponseFuture`]s and a single [`RecvTransport`].
 #[derive(Clone)]
 pub struct ActiveTransactions {
-    transactions: Arc<Mutex<HashMap<TransactionId, TxState>>>,
+    transactions: Arc<Mutex<HashMap<TransactionId, TxEntry>>>,
+    slots: Arc<Mutex<Slots>>,
+
+    /// Time of the last garbage collection run while adding a transaction.
+    last_gc: Arc<Mutex<Instant>>,
+
+    /// Number of transactions failed by [`collect_garbage`].
+    evicted: Arc<AtomicUsize>,
+
+    /// Set by [`shutdown`]. Transactions without a response fail once set.
+    shut_down: Arc<AtomicBool>,
+
+    /// Number of responses received from another address than the one their
+    /// query was sent to, whether they were accepted or not.
+    mismatched: Arc<AtomicUsize>,
+
+    /// Rejects responses from another address than the one their query was
+    /// sent to instead of marking them.
+    strict_addresses: bool,
+
+    /// Number of responses from another node than the one their query
+    /// expected, whether they were accepted or not.
+    id_mismatched: Arc<AtomicUsize>,
+
+    /// Rejects responses from another node than the one their query
+    /// expected instead of only counting them.
+    strict_node_ids: bool,
+
+    /// Secret mixed with the destination into the high bits of transaction
+    /// ids, so a blind spoofer has to guess those as well.
+    tag_key: u64,
+}
+
+/// Bookkeeping for the limit on transactions in flight.
+struct Slots {
+    in_flight: usize,
+    max: usize,
+
+    /// Wakers of tasks waiting for a slot to be released.
+    waiters: Vec<Waker>,
+}
+
+/// Permission to have a transaction in flight. The slot is released when
+/// this is dropped, including while unwinding from a panic.
+pub struct TransactionSlot {
+    slots: Arc<Mutex<Slots>>,
+}
+
+impl Drop for TransactionSlot {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut slots = self.lock_slots();
+            slots.in_flight -= 1;
+
+            mem::replace(&mut slots.waiters, Vec::new())
+        };
+
+        // Every waiter is woken because some of them might have been dropped
+        // since they registered.
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+struct TxEntry {
+    created_at: Instant,
+
+    /// Address the query was sent to.
+    destination: SocketAddr,
+
+    /// Id of the queried node, when it was known.
+    expected_id: Option<NodeID>,
+
+    state: TxState,
 }
 
 enum TxState {
@@ -36,52 +153,410 @@ enum TxState {
         /// called for this tx yet.
         waker: Option<Waker>,
     },
+
+    /// Failed by [`ActiveTransactions::collect_garbage`]. The next poll
+    /// fails with [`ErrorKind::TransactionTimeout`].
+    Expired,
+
+    /// Failed by [`ActiveTransactions::association_lost`]. The next poll
+    /// fails with [`ErrorKind::ProxyAssociationLost`].
+    AssociationLost,
 }
 
 impl ActiveTransactions {
-    pub fn new() -> ActiveTransactions {
+    pub fn new(max_transactions: usize) -> ActiveTransactions {
         let transactions = Arc::new(Mutex::new(HashMap::new()));
+        let slots = Arc::new(Mutex::new(Slots {
+            in_flight: 0,
+            max: max_transactions,
+            waiters: Vec::new(),
+        }));
+
+        ActiveTransactions {
+            transactions,
+            slots,
+            last_gc: Arc::new(Mutex::new(Instant::now())),
+            evicted: Arc::new(AtomicUsize::new(0)),
+            shut_down: Arc::new(AtomicBool::new(false)),
+            mismatched: Arc::new(AtomicUsize::new(0)),
+            strict_addresses: false,
+            id_mismatched: Arc::new(AtomicUsize::new(0)),
+            strict_node_ids: true,
+            tag_key: rand::random(),
+        }
+    }
+
+    /// Some nodes behind NATs or with several addresses respond from another
+    /// address than the one queried. Such responses are accepted and marked
+    /// by default. When `strict` they are rejected instead and the
+    /// transaction keeps waiting for a response from the queried address.
+    pub fn with_strict_addresses(mut self, strict: bool) -> ActiveTransactions {
+        self.strict_addresses = strict;
+        self
+    }
+
+    /// Responses from another node than the one set with
+    /// [`expect_node_id`] are rejected by default, and the transaction keeps
+    /// waiting for the real one. Some nodes pick a new id when restarting,
+    /// so when not `strict` such responses are only counted.
+    ///
+    /// [`expect_node_id`]: ActiveTransactions::expect_node_id
+    pub fn with_strict_node_ids(mut self, strict: bool) -> ActiveTransactions {
+        self.strict_node_ids = strict;
+        self
+    }
+
+    /// Fails transactions awaiting a response for longer than
+    /// [`MAX_TRANSACTION_AGE`], waking their futures. Expired transactions
+    /// whose future never polls them again, and responses nobody polled, are
+    /// forgotten after another [`MAX_TRANSACTION_AGE`]. Runs automatically
+    /// while adding transactions once many are tracked.
+    pub fn collect_garbage(&self, now: Instant) {
+        let mut wakers = Vec::new();
+
+        {
+            let mut map = self.lock_transactions();
+
+            map.retain(|_, entry| {
+                if now < entry.created_at + MAX_TRANSACTION_AGE {
+                    return true;
+                }
+
+                match &mut entry.state {
+                    TxState::AwaitingResponse { waker } => {
+                        wakers.extend(waker.take());
+                        entry.state = TxState::Expired;
+                        self.evicted.fetch_add(1, Ordering::Relaxed);
+                        true
+                    }
+                    TxState::Expired | TxState::AssociationLost => {
+                        now < entry.created_at + MAX_TRANSACTION_AGE * 2
+                    }
+                    TxState::GotResponse { .. } => false,
+                }
+            });
+        }
+
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Number of transactions failed by [`collect_garbage`] so far.
+    pub fn evicted(&self) -> usize {
+        self.evicted.load(Ordering::Relaxed)
+    }
 
-        ActiveTransactions { transactions }
+    /// Number of responses received from another address than the one their
+    /// query was sent to so far, including rejected ones.
+    pub fn mismatched(&self) -> usize {
+        self.mismatched.load(Ordering::Relaxed)
     }
 
-    /// Adds an un-polled pending transaction to the set of active transactions.
-    pub fn add_transaction(&self, transaction_id: TransactionId) {
-        let mut map = self.transactions.lock().unwrap();
-        map.insert(transaction_id, TxState::AwaitingResponse { waker: None });
+    /// Fails every transaction still waiting for a response, and every one
+    /// added later, with [`ErrorKind::ShuttingDown`]. Tasks waiting for a
+    /// slot are woken.
+    pub fn shutdown(&self) {
+        self.shut_down.store(true, Ordering::SeqCst);
+
+        let wakers: Vec<Waker> = {
+            let mut map = self.lock_transactions();
+
+            map.values_mut()
+                .filter_map(|entry| match &mut entry.state {
+                    TxState::AwaitingResponse { waker } => waker.take(),
+                    TxState::GotResponse { .. } | TxState::Expired | TxState::AssociationLost => {
+                        None
+                    }
+                })
+                .collect()
+        };
+
+        let waiters = {
+            let mut slots = self.lock_slots();
+
+            mem::replace(&mut slots.waiters, Vec::new())
+        };
+
+        for waker in wakers.into_iter().chain(waiters) {
+            waker.wake();
+        }
+    }
+
+    /// Fails every transaction waiting for a response with
+    /// [`ErrorKind::ProxyAssociationLost`], because the proxy relaying them
+    /// dropped the association and their responses can't arrive anymore.
+    /// Transactions added later aren't affected.
+    pub fn association_lost(&self) {
+        let wakers: Vec<Waker> = {
+            let mut map = self.lock_transactions();
+
+            map.values_mut()
+                .filter_map(|entry| match &mut entry.state {
+                    TxState::AwaitingResponse { waker } => {
+                        let waker = waker.take();
+                        entry.state = TxState::AssociationLost;
+                        waker
+                    }
+                    TxState::GotResponse { .. } | TxState::Expired | TxState::AssociationLost => {
+                        None
+                    }
+                })
+                .collect()
+        };
+
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// Waits until fewer than the maximum number of transactions are in
+    /// flight then reserves a slot for a new transaction.
+    pub async fn acquire_slot(&self) -> TransactionSlot {
+        future::poll_fn(|cx| self.poll_slot(cx)).await
+    }
+
+    /// Reserves a slot for a new transaction without waiting.
+    ///
+    /// # Errors
+    ///
+    /// If the maximum number of transactions are already in flight, returns
+    /// [`ErrorKind::TooManyTransactions`].
+    pub fn try_acquire_slot(&self) -> send_errors::Result<TransactionSlot> {
+        let mut slots = self.lock_slots();
+        if slots.in_flight >= slots.max {
+            Err(send_errors::ErrorKind::TooManyTransactions { max: slots.max })?;
+        }
+
+        slots.in_flight += 1;
+
+        Ok(TransactionSlot {
+            slots: self.slots.clone(),
+        })
+    }
+
+    fn poll_slot(&self, cx: &mut Context<'_>) -> Poll<TransactionSlot> {
+        match self.try_acquire_slot() {
+            Ok(slot) => Poll::Ready(slot),
+            Err(_) => {
+                let mut slots = self.lock_slots();
+
+                // A slot might have been released after the attempt above.
+                if slots.in_flight < slots.max {
+                    cx.waker().wake_by_ref();
+                } else {
+                    slots.waiters.push(cx.waker().clone());
+                }
+
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Number of transactions currently holding a slot.
+    pub fn in_flight(&self) -> usize {
+        self.lock_slots().in_flight
+    }
+
+    /// Picks a transaction id no active transaction uses and adds an
+    /// un-polled pending transaction with it, for a query sent to
+    /// `destination`, to the set of active transactions.
+    ///
+    /// The high 16 bits of the id are derived from `destination` and a
+    /// secret, the low 16 bits are random.
+    pub fn next_unique_transaction_id(&self, destination: SocketAddr) -> TransactionId {
+        let tag = self.destination_tag(destination);
+
+        self.add_unique_transaction(Instant::now(), destination, || {
+            tag | TransactionId::from(rand::random::<u16>())
+        })
+    }
+
+    /// Expects the response to `transaction_id` to come from the node with
+    /// `id`. See [`with_strict_node_ids`].
+    ///
+    /// [`with_strict_node_ids`]: ActiveTransactions::with_strict_node_ids
+    pub fn expect_node_id(&self, transaction_id: TransactionId, id: NodeID) {
+        let mut map = self.lock_transactions();
+        if let Some(entry) = map.get_mut(&transaction_id) {
+            entry.expected_id = Some(id);
+        }
+    }
+
+    /// Number of responses from another node than expected, including
+    /// rejected ones.
+    pub fn id_mismatched(&self) -> usize {
+        self.id_mismatched.load(Ordering::Relaxed)
+    }
+
+    /// Transactions and slots are also released while dropping, where a
+    /// poisoned lock mustn't panic.
+    fn lock_transactions(&self) -> MutexGuard<'_, HashMap<TransactionId, TxEntry>> {
+        self.transactions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_slots(&self) -> MutexGuard<'_, Slots> {
+        self.slots
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// High bits of the transaction ids of queries sent to `destination`.
+    fn destination_tag(&self, destination: SocketAddr) -> TransactionId {
+        let mut hasher = DefaultHasher::new();
+        self.tag_key.hash(&mut hasher);
+        destination.hash(&mut hasher);
+
+        (hasher.finish() as TransactionId) & 0xffff_0000
+    }
+
+    /// Adds a transaction with the first id returned by `generate` which
+    /// isn't in use yet.
+    fn add_unique_transaction(
+        &self,
+        now: Instant,
+        destination: SocketAddr,
+        mut generate: impl FnMut() -> TransactionId,
+    ) -> TransactionId {
+        let (transaction_id, tracked) = {
+            let mut map = self.lock_transactions();
+            let mut transaction_id = generate();
+            while map.contains_key(&transaction_id) {
+                transaction_id = generate();
+            }
+
+            map.insert(
+                transaction_id,
+                TxEntry {
+                    created_at: now,
+                    destination,
+                    expected_id: None,
+                    state: TxState::AwaitingResponse { waker: None },
+                },
+            );
+
+            (transaction_id, map.len())
+        };
+
+        if tracked > GC_THRESHOLD {
+            let mut last_gc = self
+                .last_gc
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+            if now >= *last_gc + GC_INTERVAL {
+                *last_gc = now;
+                self.collect_garbage(now);
+            }
+        }
+
+        transaction_id
     }
 
     /// Stops tracking a transaction. Subsequent calls to [`handle_response`],
     /// [`poll_response`]  with `transaction_id` will now fail.
     pub fn drop_transaction(&self, transaction_id: TransactionId) {
-        let mut map = self.transactions.lock().unwrap();
+        // Called while dropping, so a poisoned lock mustn't panic.
+        let mut map = self.lock_transactions();
         map.remove(&transaction_id);
     }
 
     /// Updates transaction associated with `message` such that the next call to
     /// [`poll_response`] for the transaction will return [`Async::Ready`].
-    /// Awakens the associated waker if there is one.
+    /// Awakens the associated waker if there is one. Messages from another
+    /// address than the query was sent to are marked with
+    /// `address_mismatch`.
     ///
     /// # Errors
     ///
     /// If the transaction id associated with `message` isn't known, returns
-    /// failure.
-    pub fn handle_response(&self, message: InboundResponseEnvelope) -> recv_errors::Result<()> {
+    /// failure. With strict addresses, messages from another address than
+    /// the query was sent to are rejected with
+    /// [`ErrorKind::ResponseAddressMismatch`] and the transaction keeps
+    /// waiting.
+    pub fn handle_response(&self, mut message: InboundResponseEnvelope) -> recv_errors::Result<()> {
         let transaction_id = parse_originating_transaction_id(&message.transaction_id)?;
-        let mut map = self.transactions.lock().unwrap();
+        let mut map = self.lock_transactions();
 
-        let current_tx_state = map
+        let entry = map
             .remove(&transaction_id)
             .ok_or_else(|| recv_errors::ErrorKind::UnknownTransactionReceived { transaction_id })?;
 
-        match current_tx_state {
-            TxState::GotResponse { .. } => {
-                // Multiple responses received for a single transaction. This shouldn't happen.
-                map.insert(transaction_id, current_tx_state);
+        let awaiting = match entry.state {
+            TxState::AwaitingResponse { .. } => true,
+            TxState::GotResponse { .. } | TxState::Expired | TxState::AssociationLost => false,
+        };
+
+        if awaiting && message.source != entry.destination {
+            self.mismatched.fetch_add(1, Ordering::Relaxed);
+
+            if self.strict_addresses {
+                let expected = entry.destination;
+                map.insert(transaction_id, entry);
+
+                return Err(recv_errors::ErrorKind::ResponseAddressMismatch {
+                    transaction_id,
+                    expected,
+                    got: message.source,
+                }
+                .into());
+            }
+
+            message.address_mismatch = true;
+        }
+
+        let id_mismatch = match (&entry.expected_id, &message.response) {
+            (Some(expected), ResponseType::Response { response })
+                if awaiting && response.id() != expected =>
+            {
+                Some((expected.clone(), response.id().clone()))
+            }
+            _ => None,
+        };
+
+        if let Some((expected, got)) = id_mismatch {
+            self.id_mismatched.fetch_add(1, Ordering::Relaxed);
+
+            if self.strict_node_ids {
+                map.insert(transaction_id, entry);
+
+                return Err(recv_errors::ErrorKind::ResponseNodeIDMismatch {
+                    transaction_id,
+                    expected,
+                    got,
+                }
+                .into());
+            }
+        }
+
+        match entry.state {
+            TxState::GotResponse { .. } | TxState::Expired | TxState::AssociationLost => {
+                // Multiple responses received for a single transaction, or a
+                // response arriving too late. This shouldn't happen.
+                map.insert(transaction_id, entry);
             }
             TxState::AwaitingResponse { waker } => {
-                map.insert(transaction_id, TxState::GotResponse { response: message });
-                waker.map(|waker| waker.wake());
+                map.insert(
+                    transaction_id,
+                    TxEntry {
+                        created_at: entry.created_at,
+                        destination: entry.destination,
+                        expected_id: entry.expected_id,
+                        state: TxState::GotResponse { response: message },
+                    },
+                );
+
+                // Without this the task polling the transaction is never
+                // polled again and the query hangs.
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
             }
         };
 
@@ -90,30 +565,48 @@ impl ActiveTransactions {
 
     /// Associates `waker` with `transaction_id` and returns [`NotReady`] until
     /// a message with the same `transaction_id` is provided to
-    /// [`handle_response`], then returns that message and awakes the `waker`.
+    /// [`handle_response`], then returns that message. The `waker` from the
+    /// most recent poll is awoken when the message arrives. Fails after
+    /// [`shutdown`] unless the message has already arrived.
     pub fn poll_response(
         &self,
         transaction_id: TransactionId,
         waker: &Waker,
     ) -> Poll<send_errors::Result<InboundResponseEnvelope>> {
-        let mut map = self.transactions.lock().unwrap();
+        let mut map = self.lock_transactions();
 
-        let tx_state = map
+        let entry = map
             .remove(&transaction_id)
             .ok_or_else(|| send_errors::ErrorKind::UnknownTransactionPolled { transaction_id })?;
 
-        match tx_state {
+        match entry.state {
             TxState::GotResponse { response } => Poll::Ready(Ok(response)),
-            TxState::AwaitingResponse { waker: Some(..) } => {
-                map.insert(transaction_id, tx_state);
-
-                Poll::Pending
+            TxState::Expired => Poll::Ready(Err(send_errors::ErrorKind::TransactionTimeout {
+                transaction_id,
+                elapsed: entry.created_at.elapsed(),
+            }
+            .into())),
+            TxState::AssociationLost => {
+                Poll::Ready(Err(send_errors::ErrorKind::ProxyAssociationLost.into()))
             }
-            TxState::AwaitingResponse { waker: None } => {
+            TxState::AwaitingResponse { .. } if self.is_shut_down() => {
+                Poll::Ready(Err(send_errors::ErrorKind::ShuttingDown.into()))
+            }
+            TxState::AwaitingResponse { waker: stored } => {
+                // The future might have moved to another task since it was
+                // last polled, so only the most recent waker is kept.
+                let waker = match stored {
+                    Some(stored) if stored.will_wake(waker) => stored,
+                    _ => waker.clone(),
+                };
+
                 map.insert(
                     transaction_id,
-                    TxState::AwaitingResponse {
-                        waker: Some(waker.clone()),
+                    TxEntry {
+                        created_at: entry.created_at,
+                        destination: entry.destination,
+                        expected_id: entry.expected_id,
+                        state: TxState::AwaitingResponse { waker: Some(waker) },
                     },
                 );
 
@@ -122,3 +615,338 @@ impl ActiveTransactions {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ActiveTransactions,
+        MAX_TRANSACTION_AGE,
+    };
+    use crate::{
+        inbound_response_envelope::{
+            InboundResponseEnvelope,
+            ResponseType,
+        },
+        recv_errors,
+        send_errors::ErrorKind,
+        transaction_id::TransactionId,
+    };
+    use futures::task::noop_waker;
+    use krpc_encoding::{
+        self as proto,
+        NodeID,
+    };
+    use std::{
+        net::SocketAddr,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+    use tokio::prelude::Poll;
+
+    fn destination() -> SocketAddr {
+        "10.0.0.1:6881".parse().unwrap()
+    }
+
+    fn response_from(transaction_id: TransactionId, source: SocketAddr) -> InboundResponseEnvelope {
+        response_with_id(transaction_id, source, NodeID::random())
+    }
+
+    fn response_with_id(
+        transaction_id: TransactionId,
+        source: SocketAddr,
+        id: NodeID,
+    ) -> InboundResponseEnvelope {
+        InboundResponseEnvelope {
+            transaction_id: transaction_id.to_be_bytes().to_vec(),
+            source,
+            address_mismatch: false,
+            version: None,
+            ip: None,
+            read_only: false,
+            response: ResponseType::Response {
+                response: proto::Response::OnlyID { id },
+            },
+        }
+    }
+
+    fn poll_mismatch(transactions: &ActiveTransactions, transaction_id: TransactionId) -> bool {
+        match transactions.poll_response(transaction_id, &noop_waker()) {
+            Poll::Ready(Ok(response)) => response.address_mismatch,
+            _ => panic!("no response for transaction"),
+        }
+    }
+
+    #[test]
+    fn response_from_destination() {
+        let transactions = ActiveTransactions::new(1);
+        let transaction_id = transactions.next_unique_transaction_id(destination());
+
+        transactions
+            .handle_response(response_from(transaction_id, destination()))
+            .unwrap();
+
+        assert!(!poll_mismatch(&transactions, transaction_id));
+        assert_eq!(transactions.mismatched(), 0);
+    }
+
+    #[test]
+    fn mismatched_response_accepted() {
+        let transactions = ActiveTransactions::new(1);
+        let transaction_id = transactions.next_unique_transaction_id(destination());
+
+        transactions
+            .handle_response(response_from(
+                transaction_id,
+                "10.0.0.2:6881".parse().unwrap(),
+            ))
+            .unwrap();
+
+        assert!(poll_mismatch(&transactions, transaction_id));
+        assert_eq!(transactions.mismatched(), 1);
+    }
+
+    #[test]
+    fn mismatched_response_rejected_when_strict() {
+        let transactions = ActiveTransactions::new(1).with_strict_addresses(true);
+        let transaction_id = transactions.next_unique_transaction_id(destination());
+        let other_port: SocketAddr = "10.0.0.1:6882".parse().unwrap();
+
+        let err = transactions
+            .handle_response(response_from(transaction_id, other_port))
+            .unwrap_err();
+        match err.kind() {
+            recv_errors::ErrorKind::ResponseAddressMismatch { expected, got, .. } => {
+                assert_eq!(*expected, destination());
+                assert_eq!(*got, other_port);
+            }
+            kind => panic!("unexpected error {}", kind),
+        };
+        assert_eq!(transactions.mismatched(), 1);
+
+        // Still waiting for the real response.
+        assert!(transactions
+            .poll_response(transaction_id, &noop_waker())
+            .is_pending());
+        transactions
+            .handle_response(response_from(transaction_id, destination()))
+            .unwrap();
+        assert!(!poll_mismatch(&transactions, transaction_id));
+    }
+
+    #[test]
+    fn spoofed_response_rejected() {
+        let transactions = ActiveTransactions::new(1);
+        let transaction_id = transactions.next_unique_transaction_id(destination());
+        let id = NodeID::random();
+        transactions.expect_node_id(transaction_id, id.clone());
+
+        let spoofed = response_with_id(
+            transaction_id,
+            "203.0.113.7:6881".parse().unwrap(),
+            NodeID::random(),
+        );
+        match transactions.handle_response(spoofed).unwrap_err().kind() {
+            recv_errors::ErrorKind::ResponseNodeIDMismatch { expected, .. } => {
+                assert_eq!(*expected, id)
+            }
+            kind => panic!("unexpected error {}", kind),
+        };
+        assert_eq!(transactions.id_mismatched(), 1);
+        assert!(transactions
+            .poll_response(transaction_id, &noop_waker())
+            .is_pending());
+
+        transactions
+            .handle_response(response_with_id(transaction_id, destination(), id.clone()))
+            .unwrap();
+        match transactions.poll_response(transaction_id, &noop_waker()) {
+            Poll::Ready(Ok(InboundResponseEnvelope {
+                response: ResponseType::Response { response },
+                ..
+            })) => assert_eq!(*response.id(), id),
+            _ => panic!("no response for transaction"),
+        };
+    }
+
+    #[test]
+    fn mismatched_id_counted_when_lenient() {
+        let transactions = ActiveTransactions::new(1).with_strict_node_ids(false);
+        let transaction_id = transactions.next_unique_transaction_id(destination());
+        transactions.expect_node_id(transaction_id, NodeID::random());
+
+        // Like a node which restarted with a new id.
+        transactions
+            .handle_response(response_from(transaction_id, destination()))
+            .unwrap();
+
+        assert!(!poll_mismatch(&transactions, transaction_id));
+        assert_eq!(transactions.id_mismatched(), 1);
+    }
+
+    #[test]
+    fn transaction_ids_tagged_by_destination() {
+        let transactions = ActiveTransactions::new(64);
+        let tag = |transaction_id: TransactionId| transaction_id >> 16;
+
+        let first = transactions.next_unique_transaction_id(destination());
+        for _ in 0..32 {
+            let transaction_id = transactions.next_unique_transaction_id(destination());
+            assert_eq!(tag(transaction_id), tag(first));
+        }
+
+        // Telling the tag of an address from another one takes the secret.
+        let others: Vec<TransactionId> = (2..10)
+            .map(|host| {
+                let other = format!("10.0.0.{}:6881", host).parse().unwrap();
+                tag(transactions.next_unique_transaction_id(other))
+            })
+            .collect();
+        assert!(others.iter().any(|other| *other != tag(first)));
+    }
+
+    #[test]
+    fn stale_transactions_collected() {
+        let transactions = ActiveTransactions::new(4);
+        let start = Instant::now();
+        let waker = noop_waker();
+
+        transactions.add_unique_transaction(start, destination(), || 1);
+        transactions.add_unique_transaction(start + Duration::from_secs(60), destination(), || 2);
+        assert!(transactions.poll_response(1, &waker).is_pending());
+        assert!(transactions.poll_response(2, &waker).is_pending());
+
+        transactions.collect_garbage(start + MAX_TRANSACTION_AGE);
+        assert_eq!(transactions.evicted(), 1);
+
+        match transactions.poll_response(1, &waker) {
+            Poll::Ready(Err(err)) => match err.kind() {
+                ErrorKind::TransactionTimeout { transaction_id, .. } => {
+                    assert_eq!(*transaction_id, 1)
+                }
+                kind => panic!("unexpected error {}", kind),
+            },
+            _ => panic!("stale transaction still waiting"),
+        };
+        assert!(transactions.poll_response(2, &waker).is_pending());
+        assert_eq!(transactions.transactions.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn leaked_transactions_forgotten() {
+        let transactions = ActiveTransactions::new(4);
+        let start = Instant::now();
+
+        // Never polled or dropped, like a future passed to `mem::forget`.
+        transactions.add_unique_transaction(start, destination(), || 1);
+
+        transactions.collect_garbage(start + MAX_TRANSACTION_AGE);
+        assert_eq!(transactions.transactions.lock().unwrap().len(), 1);
+
+        transactions.collect_garbage(start + MAX_TRANSACTION_AGE * 2);
+        assert!(transactions.transactions.lock().unwrap().is_empty());
+        assert_eq!(transactions.evicted(), 1);
+    }
+
+    #[test]
+    fn transaction_ids_unique() {
+        let transactions = ActiveTransactions::new(4);
+        let now = Instant::now();
+        let mut candidates = vec![2, 1, 1, 1].into_iter();
+
+        assert_eq!(
+            transactions.add_unique_transaction(now, destination(), || 1),
+            1
+        );
+        assert_eq!(
+            transactions.add_unique_transaction(now, destination(), || candidates.next().unwrap()),
+            2
+        );
+        assert_eq!(candidates.len(), 3);
+
+        transactions.drop_transaction(1);
+        assert_eq!(
+            transactions.add_unique_transaction(now, destination(), || 1),
+            1
+        );
+    }
+
+    #[test]
+    fn slots_limited() {
+        let transactions = ActiveTransactions::new(2);
+
+        let first = transactions.try_acquire_slot().unwrap();
+        let _second = transactions.try_acquire_slot().unwrap();
+        assert_eq!(transactions.in_flight(), 2);
+
+        match transactions.try_acquire_slot() {
+            Err(err) => match err.kind() {
+                ErrorKind::TooManyTransactions { max: 2 } => {}
+                kind => panic!("unexpected error {}", kind),
+            },
+            Ok(_) => panic!("acquired more slots than allowed"),
+        };
+
+        drop(first);
+        assert_eq!(transactions.in_flight(), 1);
+        assert!(transactions.try_acquire_slot().is_ok());
+    }
+
+    #[test]
+    fn shutdown_fails_waiting_transactions() {
+        let transactions = ActiveTransactions::new(2);
+        let first = transactions.next_unique_transaction_id(destination());
+        let second = transactions.next_unique_transaction_id(destination());
+
+        let waker = noop_waker();
+        assert!(transactions.poll_response(first, &waker).is_pending());
+
+        transactions.shutdown();
+
+        for &transaction_id in &[first, second] {
+            match transactions.poll_response(transaction_id, &waker) {
+                Poll::Ready(Err(err)) => match err.kind() {
+                    ErrorKind::ShuttingDown => {}
+                    kind => panic!("unexpected error {}", kind),
+                },
+                _ => panic!("transaction still waiting after shutdown"),
+            };
+        }
+    }
+
+    #[test]
+    fn association_lost_fails_waiting_transactions() {
+        let transactions = ActiveTransactions::new(2);
+        let lost = transactions.next_unique_transaction_id(destination());
+
+        let waker = noop_waker();
+        assert!(transactions.poll_response(lost, &waker).is_pending());
+
+        transactions.association_lost();
+        let later = transactions.next_unique_transaction_id(destination());
+
+        match transactions.poll_response(lost, &waker) {
+            Poll::Ready(Err(err)) => match err.kind() {
+                ErrorKind::ProxyAssociationLost => {}
+                kind => panic!("unexpected error {}", kind),
+            },
+            _ => panic!("transaction still waiting after association was lost"),
+        };
+        assert!(transactions.poll_response(later, &waker).is_pending());
+    }
+
+    #[test]
+    fn slot_released_on_panic() {
+        let transactions = ActiveTransactions::new(1);
+        let cloned = transactions.clone();
+
+        let result = std::panic::catch_unwind(move || {
+            let _slot = cloned.try_acquire_slot().unwrap();
+            panic!("request failed");
+        });
+
+        assert!(result.is_err());
+        assert_eq!(transactions.in_flight(), 0);
+    }
+}
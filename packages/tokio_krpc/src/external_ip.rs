@@ -0,0 +1,189 @@
+//! Learns our external address from the `ip` field other nodes put in their
+//! messages, as described in [BEP-0042]. Ids have to be derived from the
+//! external IP, which a node behind a NAT doesn't know otherwise.
+//!
+//! [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+
+use krpc_encoding::Addr;
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    net::{
+        IpAddr,
+        Ipv4Addr,
+    },
+    sync::Arc,
+};
+
+/// Most addresses voted for at once besides the consensus. Votes for more
+/// are ignored until the ones for the consensus clear them.
+const MAX_CANDIDATES: usize = 16;
+
+/// Called with the new consensus whenever an [`ExternalIpObserver`] changes
+/// its mind. See [`KRPCNode::with_external_ip_observer`].
+///
+/// [`KRPCNode::with_external_ip_observer`]: crate::KRPCNode::with_external_ip_observer
+pub type ConsensusCallback = Arc<dyn Fn(Addr) + Send + Sync>;
+
+/// Tallies the addresses other nodes report for us.
+///
+/// An address becomes the consensus once it got `threshold` votes since the
+/// last vote for the current consensus, so a few wrong or stale reports
+/// don't change it, but every node reporting the same new address after it
+/// really changed does. Each /24 subnet has a single vote, its latest, so a
+/// node sending many messages or many nodes run from the same network can't
+/// outvote the others.
+#[derive(Debug)]
+pub struct ExternalIpObserver {
+    /// Address each voter reported last, see [`voter`].
+    votes: HashMap<IpAddr, Addr>,
+    threshold: usize,
+    consensus: Option<Addr>,
+}
+
+impl ExternalIpObserver {
+    pub fn new(threshold: usize) -> ExternalIpObserver {
+        assert!(threshold > 0, "threshold must be positive");
+
+        ExternalIpObserver {
+            votes: HashMap::new(),
+            threshold,
+            consensus: None,
+        }
+    }
+
+    /// Counts a report of `ip` as our address by a node at `from`. Returns
+    /// the new consensus when this vote changed it.
+    pub fn vote(&mut self, ip: Addr, from: IpAddr) -> Option<Addr> {
+        if self.consensus == Some(ip) {
+            self.votes.clear();
+            return None;
+        }
+
+        let voter = voter(from);
+        if self.votes.get(&voter) == Some(&ip) {
+            return None;
+        }
+
+        let candidates: HashSet<&Addr> = self.votes.values().collect();
+        if !candidates.contains(&ip) && candidates.len() >= MAX_CANDIDATES {
+            return None;
+        }
+
+        self.votes.insert(voter, ip);
+        if self.votes.values().filter(|vote| **vote == ip).count() < self.threshold {
+            return None;
+        }
+
+        self.votes.clear();
+        self.consensus = Some(ip);
+
+        Some(ip)
+    }
+
+    /// Address voted for by at least `threshold` reports, if any was yet.
+    pub fn consensus(&self) -> Option<Addr> {
+        self.consensus
+    }
+}
+
+/// Who votes for a node at `from`: its /24 subnet for IPv4, the address
+/// itself for IPv6.
+fn voter(from: IpAddr) -> IpAddr {
+    match from {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Ipv4Addr::new(a, b, c, 0).into()
+        }
+        IpAddr::V6(ip) => ip.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ExternalIpObserver,
+        MAX_CANDIDATES,
+    };
+    use krpc_encoding::Addr;
+    use std::net::{
+        IpAddr,
+        Ipv4Addr,
+        SocketAddrV4,
+    };
+
+    fn addr(last: u8) -> Addr {
+        SocketAddrV4::new(Ipv4Addr::new(124, 31, 75, last), 6881).into()
+    }
+
+    /// A node in the /24 subnet `subnet`.
+    fn from(subnet: u8) -> IpAddr {
+        Ipv4Addr::new(10, 0, subnet, 1).into()
+    }
+
+    #[test]
+    fn consensus_after_threshold() {
+        let mut observer = ExternalIpObserver::new(5);
+
+        for subnet in 0..4 {
+            assert_eq!(observer.vote(addr(21), from(subnet)), None);
+        }
+        assert_eq!(observer.consensus(), None);
+
+        assert_eq!(observer.vote(addr(21), from(4)), Some(addr(21)));
+        assert_eq!(observer.consensus(), Some(addr(21)));
+
+        // More votes for it don't change anything.
+        assert_eq!(observer.vote(addr(21), from(5)), None);
+    }
+
+    #[test]
+    fn one_vote_per_subnet() {
+        let mut observer = ExternalIpObserver::new(2);
+
+        for last in 1..10 {
+            let from = Ipv4Addr::new(10, 0, 0, last).into();
+            assert_eq!(observer.vote(addr(21), from), None);
+        }
+        assert_eq!(observer.consensus(), None);
+
+        // A subnet changing its mind takes its vote along.
+        observer.vote(addr(22), from(0));
+        assert_eq!(observer.vote(addr(21), from(1)), None);
+        assert_eq!(observer.vote(addr(22), from(2)), Some(addr(22)));
+    }
+
+    #[test]
+    fn stray_votes_cleared() {
+        let mut observer = ExternalIpObserver::new(3);
+        for subnet in 0..3 {
+            observer.vote(addr(21), from(subnet));
+        }
+
+        for _ in 0..10 {
+            assert_eq!(observer.vote(addr(22), from(3)), None);
+            assert_eq!(observer.vote(addr(22), from(4)), None);
+            assert_eq!(observer.vote(addr(21), from(5)), None);
+        }
+        assert_eq!(observer.consensus(), Some(addr(21)));
+
+        for subnet in 3..5 {
+            observer.vote(addr(22), from(subnet));
+        }
+        assert_eq!(observer.vote(addr(22), from(5)), Some(addr(22)));
+    }
+
+    #[test]
+    fn candidates_bounded() {
+        let mut observer = ExternalIpObserver::new(2);
+        for last in 0..MAX_CANDIDATES as u8 {
+            observer.vote(addr(last), from(last));
+        }
+
+        observer.vote(addr(200), from(200));
+        assert_eq!(observer.vote(addr(200), from(201)), None);
+        assert_eq!(observer.vote(addr(0), from(202)), Some(addr(0)));
+    }
+}
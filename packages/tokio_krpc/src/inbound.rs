@@ -1,57 +1,326 @@
 //! Handle incoming responses and queries from other nodes.
 
-use crate::recv_errors::{
-    Error,
-    ErrorKind,
-    Result,
+use crate::{
+    proxy::{
+        self,
+        Association,
+        MAX_HEADER_LEN,
+    },
+    recv_errors::{
+        Error,
+        ErrorKind,
+        InvalidQueryReason,
+        Result,
+    },
+    send_transport::{
+        send_on,
+        SendSocket,
+    },
+    socket_errors::{
+        self,
+        SocketErrorKind,
+    },
+    tap::{
+        Direction,
+        PacketTap,
+    },
 };
 use futures::{
+    lock::Mutex,
     stream,
     TryStream,
 };
-use krpc_encoding::Envelope;
+use krpc_encoding::{
+    check_canonical,
+    errors::{
+        Error as EncodingError,
+        ErrorKind as EncodingErrorKind,
+    },
+    Envelope,
+    KRPCError,
+    Message,
+    PartialMessage,
+    Query,
+};
 use std::{
     self,
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
     net::SocketAddr,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
 };
 use tokio::{
     self,
     net::udp::split::UdpSocketRecvHalf,
 };
 
+/// Longest message received. Relayed messages can be longer by the size of
+/// the relay's header.
+const MAX_MESSAGE_LEN: usize = 1024;
+
+/// Least time between two error replies to invalid queries from an
+/// address, so a node sending garbage can't make us send as much back.
+const ERROR_REPLY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of addresses [`ErrorReplies`] remembers before forgetting the ones
+/// it hasn't replied to within [`ERROR_REPLY_INTERVAL`].
+const MAX_REPLIED_ADDRESSES: usize = 1024;
+
+/// Counts of the messages which couldn't be decoded.
+#[derive(Default)]
+pub(crate) struct RecvCounters {
+    decode_errors: AtomicUsize,
+
+    /// Indexed like [`InvalidQueryReason::ALL`].
+    invalid_queries: [AtomicUsize; 3],
+}
+
+impl RecvCounters {
+    pub fn decode_errors(&self) -> usize {
+        self.decode_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn invalid_queries(&self) -> BTreeMap<InvalidQueryReason, usize> {
+        InvalidQueryReason::ALL
+            .iter()
+            .zip(self.invalid_queries.iter())
+            .map(|(reason, count)| (*reason, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    fn record_invalid_query(&self, reason: InvalidQueryReason) {
+        self.invalid_queries[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Answers queries which couldn't be decoded with an error, so the sender
+/// knows not to retry them.
+pub(crate) struct ErrorReplies {
+    socket: Arc<Mutex<SendSocket>>,
+    version: Option<[u8; 4]>,
+
+    /// When each address was last replied to.
+    replied: HashMap<SocketAddr, Instant>,
+}
+
+impl ErrorReplies {
+    pub fn new(socket: Arc<Mutex<SendSocket>>, version: Option<[u8; 4]>) -> ErrorReplies {
+        ErrorReplies {
+            socket,
+            version,
+            replied: HashMap::new(),
+        }
+    }
+
+    /// Sends the error for `reason` to `to` unless it was replied to
+    /// recently. Failing to send is ignored like an unanswered query.
+    async fn reply<'a>(
+        &'a mut self,
+        to: SocketAddr,
+        transaction_id: &'a [u8],
+        reason: InvalidQueryReason,
+    ) {
+        let now = Instant::now();
+        if let Some(replied) = self.replied.get(&to) {
+            if now.duration_since(*replied) < ERROR_REPLY_INTERVAL {
+                return;
+            }
+        }
+
+        // When that many addresses were replied to within the interval, the
+        // rest wait for them to be forgotten.
+        if self.replied.len() >= MAX_REPLIED_ADDRESSES {
+            self.replied
+                .retain(|_, replied| now.duration_since(*replied) < ERROR_REPLY_INTERVAL);
+            if self.replied.len() >= MAX_REPLIED_ADDRESSES {
+                return;
+            }
+        }
+        self.replied.insert(to, now);
+
+        let (code, message) = reason.error();
+        let envelope = Envelope {
+            ip: None,
+            transaction_id: transaction_id.to_vec(),
+            version: self.version.map(|version| version.to_vec().into()),
+            message_type: Message::Error {
+                error: KRPCError::new(code, message),
+            },
+            read_only: false,
+        };
+
+        let _ = send_on(&self.socket, to, &envelope).await;
+    }
+}
+
+/// Why a query with the envelope `partial` failed to decode with `cause`.
+fn invalid_query_reason(partial: &PartialMessage, cause: &EncodingError) -> InvalidQueryReason {
+    if let EncodingErrorKind::NonCanonicalEncoding { .. } = cause.kind() {
+        return InvalidQueryReason::NonCanonical;
+    }
+
+    match partial.query_name() {
+        Some(name) if Query::NAMES.iter().any(|known| known.as_bytes() == name) => {
+            InvalidQueryReason::InvalidArguments
+        }
+        _ => InvalidQueryReason::UnknownMethod,
+    }
+}
+
+/// State carried between received messages.
+struct RecvState {
+    socket: UdpSocketRecvHalf,
+    buffer: [u8; MAX_MESSAGE_LEN + MAX_HEADER_LEN],
+    tap: Option<PacketTap>,
+
+    /// Proxy every message is relayed through, if any.
+    proxy: Option<Association>,
+
+    counters: Arc<RecvCounters>,
+
+    /// Whether queries which aren't canonically encoded are rejected.
+    strict_queries: bool,
+
+    /// Invalid queries are only counted when `None`, like for read-only
+    /// nodes.
+    error_replies: Option<ErrorReplies>,
+}
+
+/// Receives messages from `recv_socket`. Queries which can't be decoded but
+/// have a transaction id are answered through `error_replies`, if any.
 pub fn receive_inbound_messages(
     recv_socket: UdpSocketRecvHalf,
+    tap: Option<PacketTap>,
+    proxy: Option<Association>,
+    counters: Arc<RecvCounters>,
+    strict_queries: bool,
+    error_replies: Option<ErrorReplies>,
 ) -> impl TryStream<Ok = (Envelope, SocketAddr), Error = Error> {
-    let recv_buffer = [0 as u8; 1024];
+    let state = RecvState {
+        socket: recv_socket,
+        buffer: [0 as u8; MAX_MESSAGE_LEN + MAX_HEADER_LEN],
+        tap,
+        proxy,
+        counters,
+        strict_queries,
+        error_replies,
+    };
 
-    stream::unfold((recv_socket, recv_buffer), |(recv_socket, recv_buffer)| {
-        receive_inbound_message_wrapper(recv_socket, recv_buffer)
-    })
+    stream::unfold(Some(state), |state| receive_inbound_message_wrapper(state))
 }
 
+/// Receives the next message. The stream ends after a fatal socket error is
+/// yielded.
 async fn receive_inbound_message_wrapper(
-    mut recv_socket: UdpSocketRecvHalf,
-    mut recv_buffer: [u8; 1024],
-) -> Option<(
-    Result<(Envelope, SocketAddr)>,
-    (UdpSocketRecvHalf, [u8; 1024]),
-)> {
-    let result = receive_inbound_message(&mut recv_socket, &mut recv_buffer).await;
-
-    Some((result, (recv_socket, recv_buffer)))
-}
-
-async fn receive_inbound_message(
-    recv_socket: &mut UdpSocketRecvHalf,
-    recv_buffer: &mut [u8; 1024],
-) -> Result<(Envelope, SocketAddr)> {
-    let (size, from_addr) = recv_socket
-        .recv_from(recv_buffer)
-        .await
-        .map_err(|cause| ErrorKind::FailedToReceiveMessage { cause })?;
-
-    let envelope = Envelope::decode(&recv_buffer[..size])
-        .map_err(|cause| ErrorKind::ParseInboundMessageError { cause })?;
+    state: Option<RecvState>,
+) -> Option<(Result<(Envelope, SocketAddr)>, Option<RecvState>)> {
+    let mut state = state?;
+    let result = receive_inbound_message(&mut state).await;
+
+    let next_state = match &result {
+        Err(err) if err.is_fatal() => None,
+        _ => Some(state),
+    };
+
+    Some((result, next_state))
+}
+
+async fn receive_inbound_message(state: &mut RecvState) -> Result<(Envelope, SocketAddr)> {
+    let RecvState {
+        socket: recv_socket,
+        buffer: recv_buffer,
+        tap,
+        proxy,
+        counters,
+        strict_queries,
+        error_replies,
+    } = state;
+
+    let (message, from_addr) = loop {
+        let cause = match recv_socket.recv_from(&mut recv_buffer[..]).await {
+            Ok((size, from_addr)) => match proxy {
+                None => break (&recv_buffer[..size], from_addr),
+                // Datagrams which weren't relayed are dropped, so nobody can
+                // get around the proxy.
+                Some(proxy) if proxy.relay() == Some(from_addr) => {
+                    match proxy::read_header(&recv_buffer[..size]) {
+                        Some((source, message)) => break (message, source),
+                        None => continue,
+                    }
+                }
+                Some(_) => continue,
+            },
+            Err(cause) => cause,
+        };
+
+        match socket_errors::classify(&cause) {
+            // ICMP errors from earlier datagrams show up when receiving. They
+            // say nothing about the next inbound message.
+            SocketErrorKind::Transient | SocketErrorKind::Unreachable => continue,
+            SocketErrorKind::Fatal => return Err(ErrorKind::FatalSocketError { cause }.into()),
+            SocketErrorKind::Other => {
+                return Err(ErrorKind::FailedToReceiveMessage { cause }.into());
+            }
+        };
+    };
+
+    if let Some(tap) = tap {
+        tap(Direction::Inbound, message, from_addr);
+    }
+
+    let decoded = Envelope::decode(message).and_then(|envelope| {
+        let is_query = match envelope.message_type {
+            Message::Query { .. } => true,
+            _ => false,
+        };
+
+        if is_query && *strict_queries {
+            check_canonical(message)?;
+        }
+
+        Ok(envelope)
+    });
+
+    let envelope = match decoded {
+        Ok(envelope) => envelope,
+        Err(cause) => {
+            counters.decode_errors.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "trace")]
+            crate::trace::decode_error(from_addr, message.len(), &cause);
+
+            if let Ok(partial) = PartialMessage::decode(message) {
+                if partial.is_query() {
+                    let reason = invalid_query_reason(&partial, &cause);
+                    counters.record_invalid_query(reason);
+
+                    if let Some(error_replies) = error_replies {
+                        error_replies
+                            .reply(from_addr, partial.transaction_id(), reason)
+                            .await;
+                    }
+                }
+            }
+
+            return Err(ErrorKind::ParseInboundMessageError {
+                from: from_addr,
+                len: message.len(),
+                cause,
+            }
+            .into());
+        }
+    };
 
     Ok((envelope, from_addr))
 }
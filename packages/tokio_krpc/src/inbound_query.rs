@@ -1,7 +1,17 @@
+use crate::{
+    recv_errors::{
+        Error,
+        ErrorKind,
+        Result,
+    },
+    responses::message_type,
+};
 use krpc_encoding::{
     self as proto,
+    Addr,
     Query,
 };
+use std::convert::TryFrom;
 
 /// Inbound query originating from another node
 #[derive(Debug)]
@@ -10,6 +20,11 @@ pub struct InboundQuery {
     pub version: Option<Vec<u8>>,
     pub query: Query,
     pub read_only: bool,
+
+    /// Our address as seen by the querying node, see [BEP-0042].
+    ///
+    /// [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+    pub ip: Option<Addr>,
 }
 
 impl InboundQuery {
@@ -19,6 +34,117 @@ impl InboundQuery {
             version: None,
             query,
             read_only,
+            ip: None,
+        }
+    }
+}
+
+impl TryFrom<proto::Envelope> for InboundQuery {
+    type Error = Error;
+
+    fn try_from(envelope: proto::Envelope) -> Result<InboundQuery> {
+        let query = match envelope.message_type {
+            proto::Message::Query { query } => query,
+            message => Err(ErrorKind::UnexpectedMessageType {
+                expected: "query",
+                got: message_type(&message),
+            })?,
+        };
+
+        Ok(InboundQuery {
+            transaction_id: envelope.transaction_id,
+            version: envelope.version.map(|version| version.to_vec()),
+            query,
+            read_only: envelope.read_only,
+            ip: envelope.ip,
+        })
+    }
+}
+
+impl From<InboundQuery> for proto::Envelope {
+    fn from(query: InboundQuery) -> proto::Envelope {
+        proto::Envelope {
+            ip: query.ip,
+            transaction_id: query.transaction_id,
+            version: query.version.map(Into::into),
+            message_type: proto::Message::Query { query: query.query },
+            read_only: query.read_only,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::InboundQuery;
+    use crate::recv_errors::ErrorKind;
+    use krpc_encoding::{
+        Envelope,
+        Message,
+        NodeID,
+        Query,
+        Response,
+    };
+    use std::{
+        convert::TryFrom,
+        net::SocketAddrV4,
+    };
+
+    #[test]
+    fn round_trip() {
+        let queries = vec![
+            Query::Ping {
+                id: NodeID::random(),
+            },
+            Query::FindNode {
+                id: NodeID::random(),
+                target: NodeID::random(),
+            },
+            Query::GetPeers {
+                id: NodeID::random(),
+                info_hash: NodeID::random(),
+            },
+            Query::AnnouncePeer {
+                id: NodeID::random(),
+                implied_port: false,
+                port: Some(51413),
+                info_hash: NodeID::random(),
+                token: b"token".to_vec(),
+            },
+            Query::SampleInfoHashes {
+                id: NodeID::random(),
+                target: NodeID::random(),
+            },
+        ];
+
+        for query in queries {
+            let original = Envelope {
+                ip: Some("124.31.75.21:6881".parse::<SocketAddrV4>().unwrap().into()),
+                transaction_id: b"aa".to_vec(),
+                version: Some(b"LT\x01\x02".to_vec().into()),
+                message_type: Message::Query { query },
+                read_only: true,
+            };
+
+            let inbound = InboundQuery::try_from(original.clone()).unwrap();
+            assert_eq!(inbound.ip, original.ip);
+            assert_eq!(Envelope::from(inbound), original);
+        }
+    }
+
+    #[test]
+    fn response_rejected() {
+        let response = Envelope::response(
+            b"aa".to_vec(),
+            Response::OnlyID {
+                id: NodeID::random(),
+            },
+        );
+
+        match InboundQuery::try_from(response).unwrap_err().kind() {
+            ErrorKind::UnexpectedMessageType { expected, got } => {
+                assert_eq!((*expected, *got), ("query", "response"))
+            }
+            kind => panic!("unexpected error {}", kind),
+        };
+    }
+}
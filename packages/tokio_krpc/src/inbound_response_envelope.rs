@@ -1,9 +1,32 @@
-use krpc_encoding as proto;
+use krpc_encoding::{
+    self as proto,
+    Addr,
+};
+use std::net::SocketAddr;
 
 /// Inbound response sent from another node associated with an earlier query
 /// originating from this node
 pub struct InboundResponseEnvelope {
     pub transaction_id: Vec<u8>,
+
+    /// Address the response was received from.
+    pub source: SocketAddr,
+
+    /// Set by [`ActiveTransactions::handle_response`] when `source` isn't the
+    /// address the query was sent to.
+    ///
+    /// [`ActiveTransactions::handle_response`]: crate::active_transactions::ActiveTransactions::handle_response
+    pub address_mismatch: bool,
+
+    /// Client version string sent by the responding node.
+    pub version: Option<Vec<u8>>,
+
+    /// Our address as seen by the responding node.
+    pub ip: Option<Addr>,
+
+    /// Whether the responding node is read-only.
+    pub read_only: bool,
+
     pub response: ResponseType,
 }
 
@@ -1,23 +1,54 @@
 use crate::{
     active_transactions::ActiveTransactions,
-    inbound::receive_inbound_messages,
+    external_ip::{
+        ConsensusCallback,
+        ExternalIpObserver,
+    },
+    inbound::{
+        receive_inbound_messages,
+        ErrorReplies,
+        RecvCounters,
+    },
     inbound_response_envelope::{
         InboundResponseEnvelope,
         ResponseType,
     },
+    outbound,
+    proxy::{
+        self,
+        errors::Result as ProxyResult,
+        ProxyConfig,
+        ProxyConnection,
+    },
     recv_errors::Error,
+    tap::{
+        Direction,
+        PacketTap,
+    },
     InboundQuery,
     SendTransport,
+    SendTransportConfig,
 };
 use futures::{
-    future,
+    future::{
+        self,
+        BoxFuture,
+        FutureExt,
+    },
     TryStream,
     TryStreamExt,
 };
-use krpc_encoding::Message;
+use krpc_encoding::{
+    Addr,
+    Message,
+};
 use std::{
     self,
     net::SocketAddr,
+    sync::{
+        Arc,
+        Mutex,
+    },
 };
 use tokio::{
     self,
@@ -33,21 +64,97 @@ use tokio::{
 pub struct KRPCNode {
     send_half: UdpSocketSendHalf,
     recv_half: UdpSocketRecvHalf,
+    local_addr: Option<SocketAddr>,
     transactions: ActiveTransactions,
+    config: SendTransportConfig,
+    tap: Option<PacketTap>,
+    proxy: Option<ProxyConnection>,
+    external_ip: Option<(Arc<Mutex<ExternalIpObserver>>, ConsensusCallback)>,
 }
 
 impl KRPCNode {
     pub fn new(socket: UdpSocket) -> KRPCNode {
+        KRPCNode::with_config(socket, SendTransportConfig::default())
+    }
+
+    pub fn with_config(socket: UdpSocket, config: SendTransportConfig) -> KRPCNode {
+        let local_addr = socket.local_addr().ok();
         let (recv_half, send_half) = socket.split();
-        let transactions = ActiveTransactions::new();
+        let transactions = ActiveTransactions::new(config.max_transactions)
+            .with_strict_addresses(config.strict_response_addresses)
+            .with_strict_node_ids(config.strict_node_ids);
 
         KRPCNode {
             send_half,
             recv_half,
+            local_addr,
             transactions,
+            config,
+            tap: None,
+            proxy: None,
+            external_ip: None,
         }
     }
 
+    /// Sets the client version sent in the `v` field of every outgoing
+    /// message. `None` leaves the field out. Defaults to [`DEFAULT_VERSION`].
+    pub fn with_version(mut self, version: Option<[u8; 4]>) -> KRPCNode {
+        self.config.version = version;
+        self
+    }
+
+    /// Calls `tap` with every datagram received and sent along with the
+    /// address of the other node. `tap` runs on the receive loop and the
+    /// sender so it must return quickly. See [`PacketRecorder`] for capturing
+    /// datagrams to a file.
+    pub fn on_packet<F>(mut self, tap: F) -> KRPCNode
+    where
+        F: Fn(Direction, &[u8], SocketAddr) + Send + Sync + 'static,
+    {
+        self.tap = Some(Arc::new(tap));
+        self
+    }
+
+    /// Votes in `observer` for the `ip` of every inbound message which has
+    /// one, and calls `on_change` with the new consensus whenever it
+    /// changes. Ids derived from the old address aren't valid for the new
+    /// one under BEP-0042, so `on_change` is where a new id should be picked,
+    /// like with [`NodeID::secure`]. It runs on the receive loop so it must
+    /// return quickly.
+    ///
+    /// [`NodeID::secure`]: krpc_encoding::NodeID::secure
+    pub fn with_external_ip_observer<F>(
+        mut self,
+        observer: Arc<Mutex<ExternalIpObserver>>,
+        on_change: F,
+    ) -> KRPCNode
+    where
+        F: Fn(Addr) + Send + Sync + 'static,
+    {
+        self.external_ip = Some((observer, Arc::new(on_change)));
+        self
+    }
+
+    /// Sends and receives every datagram through a SOCKS5 `proxy`. Fails if
+    /// the proxy can't be reached or refuses to relay datagrams. If the proxy
+    /// drops the association later, queries in flight fail with
+    /// [`ErrorKind::ProxyAssociationLost`] and a new association is set up
+    /// while serving.
+    ///
+    /// [`ErrorKind::ProxyAssociationLost`]: crate::send_errors::ErrorKind::ProxyAssociationLost
+    pub async fn with_proxy(mut self, proxy: ProxyConfig) -> ProxyResult<KRPCNode> {
+        self.proxy = Some(ProxyConnection::connect(proxy, self.local_addr).await?);
+
+        Ok(self)
+    }
+
+    /// Starts serving. Queries sent with the [`SendTransport`] are only sent
+    /// and their responses only received while the returned stream is polled.
+    /// Inbound queries with invalid arguments or an unknown method are
+    /// answered with a 203 or 204 error before they are yielded as errors,
+    /// see [`InvalidQueryReason`].
+    ///
+    /// [`InvalidQueryReason`]: crate::recv_errors::InvalidQueryReason
     pub fn serve(
         self,
     ) -> (
@@ -55,35 +162,98 @@ impl KRPCNode {
         impl TryStream<Ok = (InboundQuery, SocketAddr), Error = Error>,
     ) {
         let transactions = self.transactions.clone();
+        let association = self.proxy.as_ref().map(|proxy| proxy.association.clone());
+        let recv_counters = Arc::new(RecvCounters::default());
+        let strict_queries = self.config.strict_query_decoding;
+        let external_ip = self.external_ip;
+        let (read_only, version) = (self.config.read_only, self.config.version);
+        let (send_transport, sender) = SendTransport::new(
+            self.send_half,
+            self.local_addr,
+            self.transactions.clone(),
+            self.config,
+            self.tap.clone(),
+            association.clone(),
+            recv_counters.clone(),
+        );
 
-        let query_stream = receive_inbound_messages(self.recv_half)
-            .map_ok(move |(envelope, from_addr)| match envelope.message_type {
-                Message::Response { response } => {
-                    transactions.handle_response(InboundResponseEnvelope {
-                        transaction_id: envelope.transaction_id,
-                        response: ResponseType::Response { response },
-                    })?;
+        // Read-only nodes don't answer queries, not even invalid ones.
+        let error_replies = if read_only {
+            None
+        } else {
+            Some(ErrorReplies::new(send_transport.socket(), version))
+        };
 
-                    Ok(None)
-                }
-                Message::Error { error } => {
-                    transactions.handle_response(InboundResponseEnvelope {
-                        transaction_id: envelope.transaction_id,
-                        response: ResponseType::Error { error },
-                    })?;
+        // The association is kept alive for as long as queries are sent.
+        let sender: BoxFuture<'static, ()> = match self.proxy {
+            Some(connection) => {
+                let maintain = proxy::maintain(connection, self.local_addr, self.transactions);
+                future::select(sender.boxed(), maintain.boxed())
+                    .map(|_| ())
+                    .boxed()
+            }
+            None => sender.boxed(),
+        };
 
-                    Ok(None)
-                }
-                Message::Query { query } => Ok(Some((
-                    InboundQuery::new(envelope.transaction_id, query, envelope.read_only),
-                    from_addr,
-                ))),
-            })
-            .try_filter_map(|result| future::ready(result));
-
-        (
-            SendTransport::new(self.send_half, self.transactions),
-            query_stream,
+        let query_stream = receive_inbound_messages(
+            self.recv_half,
+            self.tap,
+            association,
+            recv_counters,
+            strict_queries,
+            error_replies,
         )
+        .map_ok(move |(envelope, from_addr)| {
+            if let (Some(ip), Some((observer, on_change))) = (envelope.ip, &external_ip) {
+                let changed = observer
+                    .lock()
+                    .ok()
+                    .and_then(|mut observer| observer.vote(ip, from_addr.ip()));
+                if let Some(consensus) = changed {
+                    on_change(consensus);
+                }
+            }
+
+            (envelope, from_addr)
+        })
+        .map_ok(move |(envelope, from_addr)| match envelope.message_type {
+            Message::Response { response } => {
+                transactions.handle_response(InboundResponseEnvelope {
+                    transaction_id: envelope.transaction_id,
+                    source: from_addr,
+                    address_mismatch: false,
+                    version: envelope.version.map(|version| version.to_vec()),
+                    ip: envelope.ip,
+                    read_only: envelope.read_only,
+                    response: ResponseType::Response { response },
+                })?;
+
+                Ok(None)
+            }
+            Message::Error { error } => {
+                transactions.handle_response(InboundResponseEnvelope {
+                    transaction_id: envelope.transaction_id,
+                    source: from_addr,
+                    address_mismatch: false,
+                    version: envelope.version.map(|version| version.to_vec()),
+                    ip: envelope.ip,
+                    read_only: envelope.read_only,
+                    response: ResponseType::Error { error },
+                })?;
+
+                Ok(None)
+            }
+            Message::Query { query } => {
+                let mut query =
+                    InboundQuery::new(envelope.transaction_id, query, envelope.read_only);
+                query.version = envelope.version.map(|version| version.to_vec());
+                query.ip = envelope.ip;
+
+                Ok(Some((query, from_addr)))
+            }
+        })
+        .try_filter_map(|result| future::ready(result));
+
+        (send_transport, outbound::with_sender(query_stream, sender))
     }
 }
@@ -46,21 +46,45 @@
 // TODO: Write Docs for responses module
 
 mod active_transactions;
+pub mod external_ip;
 mod inbound;
 mod inbound_query;
 mod inbound_response_envelope;
 mod krpc_node;
+mod outbound;
 mod port_type;
+pub mod proxy;
+pub mod query_stats;
 pub mod recv_errors;
 mod response_future;
 pub mod responses;
 pub mod send_errors;
 mod send_transport;
+mod socket_errors;
+#[cfg(feature = "stun")]
+pub mod stun;
+pub mod tap;
+#[cfg(feature = "trace")]
+mod trace;
 mod transaction_id;
+mod transport;
 
 pub use self::{
     inbound_query::InboundQuery,
     krpc_node::KRPCNode,
+    outbound::FlowId,
     port_type::PortType,
-    send_transport::SendTransport,
+    send_transport::{
+        QueryOptions,
+        SendTransport,
+        SendTransportConfig,
+        TransportStats,
+        DEFAULT_TIMEOUT,
+        DEFAULT_VERSION,
+    },
+    socket_errors::{
+        SocketErrorPolicy,
+        DEFAULT_MAX_TRANSIENT_RETRIES,
+    },
+    transport::Transport,
 };
@@ -0,0 +1,362 @@
+//! Queue of outgoing queries drained by a single sender. Queries are grouped
+//! into flows which take turns, so a burst of queries from one lookup doesn't
+//! hold back the queries of other lookups.
+
+use crate::{
+    send_errors::{
+        ErrorKind,
+        Result,
+    },
+    send_transport::{
+        self,
+        SendSocket,
+    },
+};
+use futures::{
+    channel::oneshot,
+    future,
+    Future,
+    Stream,
+};
+use krpc_encoding::Envelope;
+use std::{
+    cmp,
+    collections::{
+        HashMap,
+        VecDeque,
+    },
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        Arc,
+        Mutex,
+        MutexGuard,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::{
+    prelude::{
+        task::{
+            Context,
+            Waker,
+        },
+        Poll,
+    },
+    timer::Delay,
+};
+
+/// Identifies a group of queries sharing the outbound queue fairly with other
+/// groups. Usually there is one flow per lookup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowId(u64);
+
+impl FlowId {
+    /// Flow of queries which weren't assigned to any other flow.
+    pub const DEFAULT: FlowId = FlowId(0);
+}
+
+/// Queue which hands out items from each flow in turn.
+pub(crate) struct OutboundQueue<T> {
+    flows: HashMap<FlowId, VecDeque<T>>,
+
+    /// Flows with queued items in the order they will next be served.
+    ready: VecDeque<FlowId>,
+}
+
+impl<T> OutboundQueue<T> {
+    pub fn new() -> OutboundQueue<T> {
+        OutboundQueue {
+            flows: HashMap::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, flow: FlowId, item: T) {
+        let items = self.flows.entry(flow).or_insert_with(VecDeque::new);
+        if items.is_empty() {
+            self.ready.push_back(flow);
+        }
+
+        items.push_back(item);
+    }
+
+    pub fn pop(&mut self) -> Option<(FlowId, T)> {
+        let flow = self.ready.pop_front()?;
+        let items = self.flows.get_mut(&flow)?;
+        let item = items.pop_front()?;
+
+        if items.is_empty() {
+            self.flows.remove(&flow);
+        } else {
+            self.ready.push_back(flow);
+        }
+
+        Some((flow, item))
+    }
+}
+
+/// Spaces events out so no more than a fixed number happen each second.
+pub(crate) struct RateLimiter {
+    interval: Duration,
+    next_allowed: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32, now: Instant) -> RateLimiter {
+        RateLimiter {
+            interval: Duration::from_secs(1) / cmp::max(per_second, 1),
+            next_allowed: now,
+        }
+    }
+
+    /// Takes permission for an event at `now`. Returns how long to wait
+    /// before trying again if the event isn't allowed yet.
+    pub fn acquire(&mut self, now: Instant) -> Option<Duration> {
+        if now < self.next_allowed {
+            return Some(self.next_allowed - now);
+        }
+
+        // Being late for a slot doesn't allow a burst afterwards.
+        let earliest = now.checked_sub(self.interval).unwrap_or(now);
+        self.next_allowed = cmp::max(self.next_allowed, earliest) + self.interval;
+
+        None
+    }
+}
+
+struct OutboundQuery {
+    address: SocketAddr,
+    envelope: Envelope,
+
+    /// Receives the result of sending the query.
+    sent: oneshot::Sender<Result<()>>,
+}
+
+struct SharedState {
+    queue: OutboundQueue<OutboundQuery>,
+    last_flow: u64,
+
+    /// Waker of the sender while it waits for queries.
+    sender_waker: Option<Waker>,
+
+    /// Whether the sender should stop once the queue is drained.
+    closed: bool,
+}
+
+/// Handle for queueing queries for the sender.
+#[derive(Clone)]
+pub(crate) struct Outbound {
+    state: Arc<Mutex<SharedState>>,
+}
+
+impl Outbound {
+    pub fn new() -> Outbound {
+        Outbound {
+            state: Arc::new(Mutex::new(SharedState {
+                queue: OutboundQueue::new(),
+                last_flow: FlowId::DEFAULT.0,
+                sender_waker: None,
+                closed: false,
+            })),
+        }
+    }
+
+    pub fn new_flow(&self) -> FlowId {
+        let mut state = self.lock();
+        state.last_flow += 1;
+
+        FlowId(state.last_flow)
+    }
+
+    /// Queues `envelope` and waits until the sender has sent it.
+    pub async fn send(&self, flow: FlowId, address: SocketAddr, envelope: Envelope) -> Result<()> {
+        let (sent, receiver) = oneshot::channel();
+
+        {
+            let mut state = self.lock();
+            if state.closed {
+                Err(ErrorKind::SenderStopped)?;
+            }
+
+            state.queue.push(
+                flow,
+                OutboundQuery {
+                    address,
+                    envelope,
+                    sent,
+                },
+            );
+
+            if let Some(waker) = state.sender_waker.take() {
+                waker.wake();
+            }
+        }
+
+        match receiver.await {
+            Ok(result) => result,
+            Err(_) => Err(ErrorKind::SenderStopped.into()),
+        }
+    }
+
+    /// Stops the sender after the queries already queued are sent.
+    pub fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+
+        if let Some(waker) = state.sender_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Closed while dropping, so a poisoned lock mustn't panic.
+    fn lock(&self) -> MutexGuard<'_, SharedState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn poll_next(&self, cx: &mut Context<'_>) -> Poll<Option<OutboundQuery>> {
+        let mut state = self.lock();
+
+        if let Some((_, query)) = state.queue.pop() {
+            Poll::Ready(Some(query))
+        } else if state.closed {
+            Poll::Ready(None)
+        } else {
+            state.sender_waker = Some(cx.waker().clone());
+
+            Poll::Pending
+        }
+    }
+}
+
+/// Sends queued queries until `outbound` is closed.
+pub(crate) async fn run_sender(
+    outbound: Outbound,
+    socket: Arc<futures::lock::Mutex<SendSocket>>,
+    mut limiter: Option<RateLimiter>,
+) {
+    while let Some(query) = future::poll_fn(|cx| outbound.poll_next(cx)).await {
+        // Nobody is waiting for the response anymore.
+        if query.sent.is_canceled() {
+            continue;
+        }
+
+        if let Some(limiter) = &mut limiter {
+            while let Some(wait) = limiter.acquire(Instant::now()) {
+                Delay::new(Instant::now() + wait).await;
+            }
+        }
+
+        let result = send_transport::send_on(&socket, query.address, &query.envelope).await;
+
+        // The caller might have stopped waiting while the query was sent.
+        let _ = query.sent.send(result);
+    }
+}
+
+/// Stream which drives a sender future whenever it is polled.
+pub(crate) struct WithSender<S> {
+    stream: Pin<Box<S>>,
+    sender: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+pub(crate) fn with_sender<S: Stream>(
+    stream: S,
+    sender: impl Future<Output = ()> + Send + 'static,
+) -> WithSender<S> {
+    WithSender {
+        stream: Box::pin(stream),
+        sender: Some(Box::pin(sender)),
+    }
+}
+
+impl<S: Stream> Stream for WithSender<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let sender_finished = match &mut self.sender {
+            Some(sender) => sender.as_mut().poll(cx).is_ready(),
+            None => false,
+        };
+
+        if sender_finished {
+            self.sender = None;
+        }
+
+        self.stream.as_mut().poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        FlowId,
+        OutboundQueue,
+        RateLimiter,
+    };
+    use std::time::{
+        Duration,
+        Instant,
+    };
+
+    #[test]
+    fn round_robin() {
+        let mut queue = OutboundQueue::new();
+        let (a, b) = (FlowId(1), FlowId(2));
+
+        queue.push(a, 1);
+        queue.push(a, 2);
+        queue.push(a, 3);
+        queue.push(b, 4);
+
+        let popped: Vec<_> = (0..4).filter_map(|_| queue.pop()).collect();
+        assert_eq!(popped, vec![(a, 1), (b, 4), (a, 2), (a, 3)]);
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn rate_limited() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(100, start);
+
+        assert_eq!(limiter.acquire(start), None);
+        assert_eq!(limiter.acquire(start), Some(Duration::from_millis(10)));
+        assert_eq!(limiter.acquire(start + Duration::from_millis(10)), None);
+    }
+
+    /// Two lookups queue 1000 queries each with a limit of 100 queries a
+    /// second. The second lookup shouldn't wait for the first to finish.
+    #[test]
+    fn fair_progress() {
+        let mut queue = OutboundQueue::new();
+        let (a, b) = (FlowId(1), FlowId(2));
+        for i in 0..1000 {
+            queue.push(a, i);
+        }
+        for i in 0..1000 {
+            queue.push(b, i);
+        }
+
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(100, start);
+        let mut sent = (0, 0);
+
+        for ms in 0..2000 {
+            let now = start + Duration::from_millis(ms);
+            while limiter.acquire(now).is_none() {
+                match queue.pop() {
+                    Some((flow, _)) if flow == a => sent.0 += 1,
+                    Some(_) => sent.1 += 1,
+                    None => panic!("queue drained early"),
+                }
+            }
+        }
+
+        assert_eq!(sent.0 + sent.1, 200);
+        assert!((sent.0 as i32 - sent.1 as i32).abs() <= 1);
+    }
+}
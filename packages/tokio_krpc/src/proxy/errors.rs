@@ -0,0 +1,88 @@
+use failure::{
+    Backtrace,
+    Context,
+    Fail,
+};
+use std::{
+    fmt,
+    io,
+};
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "failed to connect to proxy")]
+    ConnectFailed {
+        #[fail(cause)]
+        cause: io::Error,
+    },
+
+    #[fail(display = "proxy control connection failed")]
+    ControlConnectionFailed {
+        #[fail(cause)]
+        cause: io::Error,
+    },
+
+    #[fail(display = "Proxy sent an invalid reply")]
+    InvalidReply,
+
+    #[fail(display = "Proxy accepts none of the offered authentication methods")]
+    NoAcceptableAuthMethod,
+
+    #[fail(display = "Username or password longer than 255 bytes")]
+    CredentialsTooLong,
+
+    #[fail(display = "Proxy rejected username and password")]
+    AuthenticationFailed,
+
+    #[fail(display = "Proxy refused UDP associate reply={}", reply)]
+    AssociateRefused { reply: u8 },
+
+    #[fail(
+        display = "Unsupported address type in proxy reply atyp={}",
+        address_type
+    )]
+    UnsupportedAddressType { address_type: u8 },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub struct Error {
+    inner: Context<ErrorKind>,
+}
+
+impl Fail for Error {
+    fn cause(&self) -> Option<&dyn Fail> {
+        self.inner.cause()
+    }
+
+    fn backtrace(&self) -> Option<&Backtrace> {
+        self.inner.backtrace()
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        self.inner.get_context()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error {
+            inner: Context::new(kind),
+        }
+    }
+}
+
+impl From<Context<ErrorKind>> for Error {
+    fn from(inner: Context<ErrorKind>) -> Error {
+        Error { inner }
+    }
+}
@@ -0,0 +1,760 @@
+//! Sending and receiving through a SOCKS5 proxy with UDP ASSOCIATE, as
+//! defined in RFC-1928, optionally authenticating with a username and
+//! password as defined in RFC-1929. See [`KRPCNode::with_proxy`].
+//!
+//! Fragmented datagrams aren't supported. Fragments received from the relay
+//! are dropped.
+//!
+//! [`KRPCNode::with_proxy`]: crate::KRPCNode::with_proxy
+
+pub mod errors;
+
+use self::errors::{
+    ErrorKind,
+    Result,
+};
+use crate::active_transactions::ActiveTransactions;
+use std::{
+    cmp,
+    fmt,
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        Ipv6Addr,
+        SocketAddr,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::{
+    io::{
+        AsyncReadExt,
+        AsyncWriteExt,
+    },
+    net::TcpStream,
+    timer::Delay,
+};
+
+const VERSION: u8 = 0x05;
+
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+/// Version of the username/password sub-negotiation.
+const AUTH_VERSION: u8 = 0x01;
+
+const UDP_ASSOCIATE: u8 = 0x03;
+const SUCCEEDED: u8 = 0x00;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Longest header put in front of relayed datagrams: reserved bytes,
+/// fragment number, address type, IPv6 address and port.
+pub(crate) const MAX_HEADER_LEN: usize = 3 + 1 + 16 + 2;
+
+/// Delay before associating again after an attempt failed. Doubles with
+/// every failed attempt...
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// ...up to this long.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// SOCKS5 proxy to relay every datagram through.
+#[derive(Clone, Debug)]
+pub struct ProxyConfig {
+    /// Address of the proxy's TCP port.
+    pub addr: SocketAddr,
+
+    /// Sent when the proxy asks for a username and password. Without them
+    /// only proxies which don't require authentication can be used.
+    pub credentials: Option<Credentials>,
+}
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<hidden>")
+            .finish()
+    }
+}
+
+/// Relay address of the current association, shared by the sender, the
+/// receive loop and [`maintain`].
+#[derive(Clone)]
+pub(crate) struct Association {
+    relay: Arc<Mutex<Option<SocketAddr>>>,
+}
+
+impl Association {
+    fn new(relay: SocketAddr) -> Association {
+        Association {
+            relay: Arc::new(Mutex::new(Some(relay))),
+        }
+    }
+
+    /// Address datagrams are sent to and received from. `None` while the
+    /// association is set up again.
+    pub fn relay(&self) -> Option<SocketAddr> {
+        *self
+            .relay
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn set_relay(&self, relay: Option<SocketAddr>) {
+        *self
+            .relay
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = relay;
+    }
+}
+
+/// An association along with the control connection keeping it alive.
+pub(crate) struct ProxyConnection {
+    pub config: ProxyConfig,
+    pub association: Association,
+    control: TcpStream,
+}
+
+impl ProxyConnection {
+    /// Asks the proxy at `config.addr` to relay datagrams sent from
+    /// `local_addr`.
+    pub async fn connect(
+        config: ProxyConfig,
+        local_addr: Option<SocketAddr>,
+    ) -> Result<ProxyConnection> {
+        let (control, relay) = associate(&config, local_addr).await?;
+
+        Ok(ProxyConnection {
+            config,
+            association: Association::new(relay),
+            control,
+        })
+    }
+}
+
+/// Keeps the association of `connection` alive. Once the proxy closes the
+/// control connection, the association is dead: transactions in flight are
+/// failed with [`ProxyAssociationLost`] and a new association is set up,
+/// backing off while attempts fail. Runs until dropped.
+///
+/// [`ProxyAssociationLost`]: crate::send_errors::ErrorKind::ProxyAssociationLost
+pub(crate) async fn maintain(
+    connection: ProxyConnection,
+    local_addr: Option<SocketAddr>,
+    transactions: ActiveTransactions,
+) {
+    let ProxyConnection {
+        config,
+        association,
+        mut control,
+    } = connection;
+
+    loop {
+        wait_closed(&mut control).await;
+
+        association.set_relay(None);
+        transactions.association_lost();
+
+        let mut delay = MIN_RECONNECT_DELAY;
+        control = loop {
+            match associate(&config, local_addr).await {
+                Ok((control, relay)) => {
+                    association.set_relay(Some(relay));
+                    break control;
+                }
+                Err(_) => {
+                    Delay::new(Instant::now() + delay).await;
+                    delay = cmp::min(delay * 2, MAX_RECONNECT_DELAY);
+                }
+            }
+        };
+    }
+}
+
+/// Waits until the control connection is closed or fails. The proxy isn't
+/// expected to send anything on it after the handshake.
+async fn wait_closed(control: &mut TcpStream) {
+    let mut buffer = [0u8; 64];
+
+    loop {
+        match control.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => continue,
+        }
+    }
+}
+
+/// Connects to the proxy and sets up a UDP association for `local_addr`.
+/// Returns the control connection, which must stay open for as long as the
+/// association is used, and the address of the relay.
+async fn associate(
+    config: &ProxyConfig,
+    local_addr: Option<SocketAddr>,
+) -> Result<(TcpStream, SocketAddr)> {
+    let mut control = TcpStream::connect(&config.addr)
+        .await
+        .map_err(|cause| ErrorKind::ConnectFailed { cause })?;
+
+    authenticate(&mut control, config.credentials.as_ref()).await?;
+
+    // Addresses of all zeros tell the proxy the address datagrams will come
+    // from isn't known.
+    let local_addr = local_addr.unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+    let mut request = vec![VERSION, UDP_ASSOCIATE, 0];
+    write_address(&local_addr, &mut request);
+    send(&mut control, &request).await?;
+
+    let mut reply = [0u8; 4];
+    receive(&mut control, &mut reply).await?;
+    if reply[0] != VERSION {
+        Err(ErrorKind::InvalidReply)?;
+    }
+    if reply[1] != SUCCEEDED {
+        Err(ErrorKind::AssociateRefused { reply: reply[1] })?;
+    }
+
+    let relay = receive_address(&mut control, reply[3]).await?;
+
+    // Proxies reply with an unspecified address when the relay listens on
+    // the same address as the proxy itself.
+    let relay = if relay.ip().is_unspecified() {
+        SocketAddr::new(config.addr.ip(), relay.port())
+    } else {
+        relay
+    };
+
+    Ok((control, relay))
+}
+
+async fn authenticate<'a>(
+    control: &'a mut TcpStream,
+    credentials: Option<&'a Credentials>,
+) -> Result<()> {
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD],
+        None => &[VERSION, 1, NO_AUTHENTICATION],
+    };
+    send(control, greeting).await?;
+
+    let mut reply = [0u8; 2];
+    receive(control, &mut reply).await?;
+    if reply[0] != VERSION {
+        Err(ErrorKind::InvalidReply)?;
+    }
+
+    let credentials = match (reply[1], credentials) {
+        (NO_AUTHENTICATION, _) => return Ok(()),
+        (USERNAME_PASSWORD, Some(credentials)) => credentials,
+        (NO_ACCEPTABLE_METHODS, _) => Err(ErrorKind::NoAcceptableAuthMethod)?,
+        _ => Err(ErrorKind::InvalidReply)?,
+    };
+
+    let (username, password) = (
+        credentials.username.as_bytes(),
+        credentials.password.as_bytes(),
+    );
+    if username.len() > 255 || password.len() > 255 {
+        Err(ErrorKind::CredentialsTooLong)?;
+    }
+
+    let mut request = vec![AUTH_VERSION, username.len() as u8];
+    request.extend_from_slice(username);
+    request.push(password.len() as u8);
+    request.extend_from_slice(password);
+    send(control, &request).await?;
+
+    let mut reply = [0u8; 2];
+    receive(control, &mut reply).await?;
+    if reply[0] != AUTH_VERSION {
+        Err(ErrorKind::InvalidReply)?;
+    }
+    if reply[1] != SUCCEEDED {
+        Err(ErrorKind::AuthenticationFailed)?;
+    }
+
+    Ok(())
+}
+
+async fn receive_address(control: &mut TcpStream, address_type: u8) -> Result<SocketAddr> {
+    let len = match address_type {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        address_type => Err(ErrorKind::UnsupportedAddressType { address_type })?,
+    };
+
+    let mut bytes = vec![0u8; 1 + len + 2];
+    bytes[0] = address_type;
+    receive(control, &mut bytes[1..]).await?;
+
+    match read_address(&bytes) {
+        Some((address, _)) => Ok(address),
+        None => Err(ErrorKind::InvalidReply.into()),
+    }
+}
+
+async fn send<'a>(control: &'a mut TcpStream, bytes: &'a [u8]) -> Result<()> {
+    control
+        .write_all(bytes)
+        .await
+        .map_err(|cause| ErrorKind::ControlConnectionFailed { cause })?;
+
+    Ok(())
+}
+
+async fn receive<'a>(control: &'a mut TcpStream, buffer: &'a mut [u8]) -> Result<()> {
+    control
+        .read_exact(buffer)
+        .await
+        .map_err(|cause| ErrorKind::ControlConnectionFailed { cause })?;
+
+    Ok(())
+}
+
+/// Writes the header which makes the relay forward the datagram following it
+/// to `target`.
+pub(crate) fn write_header(target: &SocketAddr, out: &mut Vec<u8>) {
+    // Reserved bytes, then the fragment number. Datagrams are never
+    // fragmented.
+    out.extend_from_slice(&[0, 0, 0]);
+    write_address(target, out);
+}
+
+/// Splits a datagram received from the relay into the address it was
+/// relayed from and its payload. Returns `None` for fragments and malformed
+/// headers, which should be dropped.
+pub(crate) fn read_header(datagram: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    if datagram.len() < 3 || datagram[2] != 0 {
+        return None;
+    }
+
+    let (source, len) = read_address(&datagram[3..])?;
+
+    Some((source, &datagram[3 + len..]))
+}
+
+fn write_address(address: &SocketAddr, out: &mut Vec<u8>) {
+    match address {
+        SocketAddr::V4(address) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&address.ip().octets());
+        }
+        SocketAddr::V6(address) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&address.ip().octets());
+        }
+    };
+
+    out.extend_from_slice(&address.port().to_be_bytes());
+}
+
+/// Reads an address type followed by an address and port. Returns the
+/// address and the number of bytes it took up.
+fn read_address(bytes: &[u8]) -> Option<(SocketAddr, usize)> {
+    let (ip, len): (IpAddr, usize) = match bytes.first() {
+        Some(&ATYP_IPV4) if bytes.len() >= 1 + 4 + 2 => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(&bytes[1..5]);
+            (Ipv4Addr::from(octets).into(), 1 + 4)
+        }
+        Some(&ATYP_IPV6) if bytes.len() >= 1 + 16 + 2 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&bytes[1..17]);
+            (Ipv6Addr::from(octets).into(), 1 + 16)
+        }
+        _ => return None,
+    };
+
+    let port = u16::from_be_bytes([bytes[len], bytes[len + 1]]);
+
+    Some((SocketAddr::new(ip, port), len + 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        errors::ErrorKind as ProxyErrorKind,
+        read_header,
+        write_address,
+        write_header,
+        Credentials,
+        ProxyConfig,
+        ATYP_IPV4,
+        VERSION,
+    };
+    use crate::{
+        send_errors::ErrorKind,
+        KRPCNode,
+        SendTransport,
+        SendTransportConfig,
+    };
+    use failure::Error;
+    use futures::{
+        future,
+        StreamExt,
+        TryStreamExt,
+    };
+    use krpc_encoding::{
+        Envelope,
+        Message,
+        NodeID,
+        Response,
+    };
+    use std::{
+        io::{
+            Read,
+            Write,
+        },
+        net::{
+            self,
+            Shutdown,
+            SocketAddr,
+            TcpListener,
+            TcpStream,
+        },
+        sync::{
+            atomic::{
+                AtomicBool,
+                Ordering,
+            },
+            mpsc,
+            Arc,
+        },
+        thread,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+    use tokio::{
+        net::UdpSocket,
+        runtime::current_thread::Runtime,
+        timer::Delay,
+    };
+
+    #[test]
+    fn header_round_trip() -> Result<(), Error> {
+        for &target in &["10.0.0.1:6881", "[2001:db8::1]:6881"] {
+            let target: SocketAddr = target.parse()?;
+            let mut datagram = Vec::new();
+            write_header(&target, &mut datagram);
+            datagram.extend_from_slice(b"payload");
+
+            assert_eq!(read_header(&datagram), Some((target, &b"payload"[..])));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn fragments_dropped() -> Result<(), Error> {
+        let mut datagram = Vec::new();
+        write_header(&"10.0.0.1:6881".parse()?, &mut datagram);
+        datagram[2] = 1;
+
+        assert_eq!(read_header(&datagram), None);
+        assert_eq!(read_header(&datagram[..5]), None);
+
+        Ok(())
+    }
+
+    /// Association accepted by a [`TestProxy`].
+    struct Session {
+        control: TcpStream,
+        relaying: Arc<AtomicBool>,
+    }
+
+    impl Session {
+        /// Drops the association.
+        fn close(&self) {
+            self.relaying.store(false, Ordering::SeqCst);
+            self.control.shutdown(Shutdown::Both).unwrap();
+        }
+    }
+
+    /// SOCKS5 server relaying datagrams. Each association set up is sent on
+    /// `sessions`.
+    struct TestProxy {
+        addr: SocketAddr,
+        sessions: mpsc::Receiver<Session>,
+    }
+
+    fn start_proxy(credentials: Option<(&'static str, &'static str)>) -> Result<TestProxy, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let (sender, sessions) = mpsc::channel();
+
+        thread::spawn(move || {
+            for control in listener.incoming() {
+                let mut control = control.unwrap();
+                let (relay, client) = match accept_associate(&mut control, credentials) {
+                    Some(accepted) => accepted,
+                    None => continue,
+                };
+
+                let relaying = Arc::new(AtomicBool::new(true));
+                {
+                    let relaying = relaying.clone();
+                    thread::spawn(move || relay_datagrams(relay, client, &relaying));
+                }
+
+                if sender.send(Session { control, relaying }).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(TestProxy { addr, sessions })
+    }
+
+    /// Runs the server side of the handshake. Returns the relay socket and
+    /// the address the client sends from.
+    fn accept_associate(
+        control: &mut TcpStream,
+        credentials: Option<(&str, &str)>,
+    ) -> Option<(net::UdpSocket, SocketAddr)> {
+        let read = |control: &mut TcpStream, len: usize| {
+            let mut bytes = vec![0u8; len];
+            control.read_exact(&mut bytes).unwrap();
+            bytes
+        };
+
+        let greeting = read(control, 2);
+        assert_eq!(greeting[0], VERSION);
+        let methods = read(control, greeting[1] as usize);
+
+        match credentials {
+            Some((username, password)) => {
+                assert!(methods.contains(&super::USERNAME_PASSWORD));
+                control
+                    .write_all(&[VERSION, super::USERNAME_PASSWORD])
+                    .unwrap();
+
+                let header = read(control, 2);
+                let got_username = read(control, header[1] as usize);
+                let password_len = read(control, 1)[0] as usize;
+                let got_password = read(control, password_len);
+
+                let accepted =
+                    got_username == username.as_bytes() && got_password == password.as_bytes();
+                control
+                    .write_all(&[super::AUTH_VERSION, if accepted { 0 } else { 1 }])
+                    .unwrap();
+                if !accepted {
+                    return None;
+                }
+            }
+            None => control
+                .write_all(&[VERSION, super::NO_AUTHENTICATION])
+                .unwrap(),
+        };
+
+        let request = read(control, 4 + 4 + 2);
+        assert_eq!(request[..4], [VERSION, super::UDP_ASSOCIATE, 0, ATYP_IPV4]);
+        let (client, _) = super::read_address(&request[3..]).unwrap();
+
+        // Replies with an unspecified address, which the client should
+        // replace with the proxy's.
+        let relay = net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        relay
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let mut reply = vec![VERSION, 0, 0];
+        write_address(&relay.local_addr().unwrap(), &mut reply);
+        control.write_all(&reply).unwrap();
+
+        Some((relay, client))
+    }
+
+    fn relay_datagrams(relay: net::UdpSocket, client: SocketAddr, relaying: &AtomicBool) {
+        let mut buffer = [0u8; 2048];
+
+        while relaying.load(Ordering::SeqCst) {
+            let (size, from) = match relay.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+
+            if from == client {
+                let (target, payload) = read_header(&buffer[..size]).unwrap();
+                relay.send_to(payload, target).unwrap();
+            } else {
+                let mut datagram = Vec::new();
+                write_header(&from, &mut datagram);
+                datagram.extend_from_slice(&buffer[..size]);
+                relay.send_to(&datagram, client).unwrap();
+            }
+        }
+    }
+
+    /// Node answering pings with `id`. Sends the address of every query's
+    /// sender on the returned channel.
+    fn answer_pings(id: NodeID) -> Result<(SocketAddr, mpsc::Receiver<SocketAddr>), Error> {
+        let node = net::UdpSocket::bind("127.0.0.1:0")?;
+        let addr = node.local_addr()?;
+        let (sender, senders) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut buffer = [0u8; 1024];
+            loop {
+                let (size, from) = node.recv_from(&mut buffer).unwrap();
+                let query = Envelope::decode(&buffer[..size]).unwrap();
+                let response = Envelope {
+                    ip: None,
+                    transaction_id: query.transaction_id,
+                    version: None,
+                    message_type: Message::Response {
+                        response: Response::OnlyID { id: id.clone() },
+                    },
+                    read_only: false,
+                };
+                node.send_to(&response.encode().unwrap(), from).unwrap();
+
+                if sender.send(from).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok((addr, senders))
+    }
+
+    /// Creates a transport relaying through `proxy` along with a runtime
+    /// which serves it while blocking on a future. Also returns the address
+    /// of the transport's own socket.
+    fn make_proxied_transport(
+        proxy: ProxyConfig,
+        config: SendTransportConfig,
+    ) -> Result<(SendTransport, Runtime, SocketAddr), Error> {
+        let socket = UdpSocket::bind(&"127.0.0.1:0".parse()?)?;
+        let local_addr = socket.local_addr()?;
+
+        let mut runtime = Runtime::new()?;
+        let node = runtime.block_on(KRPCNode::with_config(socket, config).with_proxy(proxy))?;
+        let (send_transport, inbound) = node.serve();
+        runtime.spawn(
+            inbound
+                .map_err(|err| println!("Error in Inbound Requests: {}", err))
+                .for_each(|_| future::ready(())),
+        );
+
+        Ok((send_transport, runtime, local_addr))
+    }
+
+    fn credentials(password: &str) -> Option<Credentials> {
+        Some(Credentials {
+            username: "crawler".to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    #[test]
+    fn ping_through_proxy() -> Result<(), Error> {
+        let proxy = start_proxy(Some(("crawler", "hunter2")))?;
+        let config = ProxyConfig {
+            addr: proxy.addr,
+            credentials: credentials("hunter2"),
+        };
+        let (send_transport, mut runtime, local_addr) =
+            make_proxied_transport(config, SendTransportConfig::default())?;
+
+        let id = NodeID::random();
+        let (node, senders) = answer_pings(id.clone())?;
+        let response = runtime.block_on(send_transport.ping(NodeID::random(), node))?;
+
+        assert_eq!(response, id);
+        let sender = senders.recv_timeout(Duration::from_secs(1))?;
+        assert_ne!(sender, local_addr);
+
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_password_refused() -> Result<(), Error> {
+        let proxy = start_proxy(Some(("crawler", "hunter2")))?;
+        let config = ProxyConfig {
+            addr: proxy.addr,
+            credentials: credentials("wrong"),
+        };
+
+        let err = match make_proxied_transport(config, SendTransportConfig::default()) {
+            Err(err) => err,
+            Ok(_) => panic!("associated with the wrong password"),
+        };
+
+        match err
+            .downcast_ref::<super::errors::Error>()
+            .map(|err| err.kind())
+        {
+            Some(ProxyErrorKind::AuthenticationFailed) => {}
+            kind => panic!("unexpected error {:?}", kind),
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn association_lost() -> Result<(), Error> {
+        let proxy = start_proxy(None)?;
+        let config = ProxyConfig {
+            addr: proxy.addr,
+            credentials: None,
+        };
+        let (send_transport, mut runtime, _) =
+            make_proxied_transport(config, SendTransportConfig::default())?;
+        let session = proxy.sessions.recv_timeout(Duration::from_secs(1))?;
+
+        // Never answers.
+        let silent = net::UdpSocket::bind("127.0.0.1:0")?;
+        let closer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            session.close();
+        });
+
+        let started = Instant::now();
+        let err = runtime
+            .block_on(send_transport.ping(NodeID::random(), silent.local_addr()?))
+            .unwrap_err();
+        closer.join().unwrap();
+
+        match err.kind() {
+            ErrorKind::ProxyAssociationLost => {}
+            kind => panic!("unexpected error {}", kind),
+        };
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Queries get through again once the association is set up again.
+        let id = NodeID::random();
+        let (node, _senders) = answer_pings(id.clone())?;
+        let mut response = None;
+        for _ in 0..50 {
+            match runtime.block_on(send_transport.ping(NodeID::random(), node)) {
+                Ok(id) => {
+                    response = Some(id);
+                    break;
+                }
+                Err(_) => runtime.block_on(Delay::new(Instant::now() + Duration::from_millis(20))),
+            };
+        }
+
+        assert_eq!(response, Some(id));
+        let _reassociated = proxy.sessions.recv_timeout(Duration::from_secs(1))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+//! Response rates and latency distributions of the queries sent by a
+//! [`SendTransport`], for tuning timeouts and concurrency. Recording only
+//! touches a few atomics so it can be done for every transaction.
+//!
+//! [`SendTransport`]: crate::SendTransport
+
+use crate::send_errors::{
+    Error,
+    ErrorKind,
+};
+use krpc_encoding::Query;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::atomic::{
+        AtomicUsize,
+        Ordering,
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the latency buckets in microseconds, about three buckets
+/// per order of magnitude. Latencies above the last bound are counted in an
+/// extra bucket.
+const BUCKET_BOUNDS: [u64; 22] = [
+    1_000, 2_000, 5_000, 10_000, 20_000, 30_000, 50_000, 75_000, 100_000, 150_000, 200_000,
+    300_000, 500_000, 750_000, 1_000_000, 1_500_000, 2_000_000, 3_000_000, 5_000_000, 10_000_000,
+    20_000_000, 30_000_000,
+];
+
+const BUCKETS: usize = BUCKET_BOUNDS.len() + 1;
+
+/// Queries whose statistics are kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum QueryKind {
+    Ping,
+    FindNode,
+    GetPeers,
+    SampleInfoHashes,
+}
+
+impl QueryKind {
+    pub const ALL: [QueryKind; 4] = [
+        QueryKind::Ping,
+        QueryKind::FindNode,
+        QueryKind::GetPeers,
+        QueryKind::SampleInfoHashes,
+    ];
+
+    /// Kind of `query`, if its statistics are kept.
+    pub fn of(query: &Query) -> Option<QueryKind> {
+        match query {
+            Query::Ping { .. } => Some(QueryKind::Ping),
+            Query::FindNode { .. } => Some(QueryKind::FindNode),
+            Query::GetPeers { .. } => Some(QueryKind::GetPeers),
+            Query::SampleInfoHashes { .. } => Some(QueryKind::SampleInfoHashes),
+            Query::AnnouncePeer { .. } => None,
+        }
+    }
+
+    /// Method name sent in the `q` field.
+    pub fn name(self) -> &'static str {
+        match self {
+            QueryKind::Ping => "ping",
+            QueryKind::FindNode => "find_node",
+            QueryKind::GetPeers => "get_peers",
+            QueryKind::SampleInfoHashes => "sample_infohashes",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Histogram of latencies with fixed buckets, recorded through atomics.
+#[derive(Default)]
+pub struct LatencyHistogram {
+    counts: [AtomicUsize; BUCKETS],
+}
+
+impl LatencyHistogram {
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram::default()
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u128::from(u64::max_value())) as u64;
+        let bucket = match BUCKET_BOUNDS.binary_search(&micros) {
+            Ok(bucket) | Err(bucket) => bucket,
+        };
+
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        LatencySnapshot {
+            counts: self
+                .counts
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+
+    pub fn reset(&self) {
+        for count in self.counts.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Copy of a [`LatencyHistogram`] at some point in time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    counts: Vec<usize>,
+}
+
+impl LatencySnapshot {
+    /// Number of latencies recorded.
+    pub fn count(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// Latency under which a fraction `quantile` of the recorded latencies
+    /// are, rounded up to the bound of its bucket. Latencies above the
+    /// largest bound are reported as that bound. `None` when nothing was
+    /// recorded.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let rank = ((quantile * count as f64).ceil() as usize)
+            .max(1)
+            .min(count);
+        let mut seen = 0;
+        let bucket = self
+            .counts
+            .iter()
+            .position(|bucket_count| {
+                seen += bucket_count;
+                seen >= rank
+            })
+            .unwrap_or(BUCKETS - 1);
+
+        let bound = BUCKET_BOUNDS[bucket.min(BUCKET_BOUNDS.len() - 1)];
+        Some(Duration::from_micros(bound))
+    }
+}
+
+/// Number of queries of a [`QueryKind`] sent and how they ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryStats {
+    /// Queries sent, or being sent. Queries whose future was dropped before
+    /// they finished are only counted here.
+    pub sent: usize,
+
+    pub responded: usize,
+
+    /// Queries failed with [`ErrorKind::TransactionTimeout`].
+    pub timed_out: usize,
+
+    /// Queries failed in any other way, including error responses.
+    pub errored: usize,
+
+    /// Time until each response.
+    pub latency: LatencySnapshot,
+}
+
+impl fmt::Display for QueryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "sent={} responded={} timed_out={} errored={}",
+            self.sent, self.responded, self.timed_out, self.errored
+        )?;
+
+        for (name, quantile) in &[("p50", 0.5), ("p90", 0.9), ("p99", 0.99)] {
+            match self.latency.quantile(*quantile) {
+                Some(latency) => write!(f, " {}={:?}", name, latency)?,
+                None => write!(f, " {}=-", name)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct QueryCounters {
+    sent: AtomicUsize,
+    responded: AtomicUsize,
+    timed_out: AtomicUsize,
+    errored: AtomicUsize,
+    latency: LatencyHistogram,
+}
+
+/// Counters for every [`QueryKind`].
+#[derive(Default)]
+pub(crate) struct QueryRecorder {
+    kinds: [QueryCounters; 4],
+}
+
+impl QueryRecorder {
+    pub fn record_sent(&self, kind: QueryKind) {
+        self.kinds[kind.index()]
+            .sent
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records how a query sent `elapsed` ago ended.
+    pub fn record_result<T>(&self, kind: QueryKind, result: &Result<T, Error>, elapsed: Duration) {
+        let counters = &self.kinds[kind.index()];
+
+        match result {
+            Ok(_) => {
+                counters.responded.fetch_add(1, Ordering::Relaxed);
+                counters.latency.record(elapsed);
+            }
+            Err(err) => {
+                let counter = match err.kind() {
+                    ErrorKind::TransactionTimeout { .. } => &counters.timed_out,
+                    _ => &counters.errored,
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        };
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<QueryKind, QueryStats> {
+        QueryKind::ALL
+            .iter()
+            .map(|kind| {
+                let counters = &self.kinds[kind.index()];
+                let stats = QueryStats {
+                    sent: counters.sent.load(Ordering::Relaxed),
+                    responded: counters.responded.load(Ordering::Relaxed),
+                    timed_out: counters.timed_out.load(Ordering::Relaxed),
+                    errored: counters.errored.load(Ordering::Relaxed),
+                    latency: counters.latency.snapshot(),
+                };
+
+                (*kind, stats)
+            })
+            .collect()
+    }
+
+    /// Zeroes every counter. Queries finishing meanwhile may be partly
+    /// counted.
+    pub fn reset(&self) {
+        for counters in self.kinds.iter() {
+            counters.sent.store(0, Ordering::Relaxed);
+            counters.responded.store(0, Ordering::Relaxed);
+            counters.timed_out.store(0, Ordering::Relaxed);
+            counters.errored.store(0, Ordering::Relaxed);
+            counters.latency.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        LatencyHistogram,
+        QueryKind,
+        QueryRecorder,
+    };
+    use crate::send_errors::{
+        Error,
+        ErrorKind,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn quantiles() {
+        let histogram = LatencyHistogram::new();
+        assert_eq!(histogram.snapshot().quantile(0.5), None);
+
+        for millis in 1..=100 {
+            histogram.record(Duration::from_millis(millis));
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count(), 100);
+        assert_eq!(snapshot.quantile(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(snapshot.quantile(0.5), Some(Duration::from_millis(50)));
+        assert_eq!(snapshot.quantile(0.9), Some(Duration::from_millis(100)));
+        assert_eq!(snapshot.quantile(0.99), Some(Duration::from_millis(100)));
+
+        // A few slow responses only move the tail.
+        for _ in 0..10 {
+            histogram.record(Duration::from_millis(1_200));
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.quantile(0.5), Some(Duration::from_millis(75)));
+        assert_eq!(snapshot.quantile(0.99), Some(Duration::from_millis(1_500)));
+
+        histogram.record(Duration::from_secs(600));
+        assert_eq!(
+            histogram.snapshot().quantile(1.0),
+            Some(Duration::from_secs(30))
+        );
+
+        histogram.reset();
+        assert_eq!(histogram.snapshot().count(), 0);
+    }
+
+    #[test]
+    fn query_outcomes() {
+        let recorder = QueryRecorder::default();
+        let timeout: Error = ErrorKind::TransactionTimeout {
+            transaction_id: 1,
+            elapsed: Duration::from_secs(1),
+        }
+        .into();
+
+        for _ in 0..3 {
+            recorder.record_sent(QueryKind::FindNode);
+        }
+        recorder.record_result(QueryKind::FindNode, &Ok(()), Duration::from_millis(40));
+        recorder.record_result::<()>(QueryKind::FindNode, &Err(timeout), Duration::from_secs(1));
+        recorder.record_result::<()>(
+            QueryKind::FindNode,
+            &Err(ErrorKind::ShuttingDown.into()),
+            Duration::from_millis(1),
+        );
+
+        let stats = recorder.snapshot();
+        let find_node = &stats[&QueryKind::FindNode];
+        assert_eq!(
+            (
+                find_node.sent,
+                find_node.responded,
+                find_node.timed_out,
+                find_node.errored
+            ),
+            (3, 1, 1, 1)
+        );
+        assert_eq!(find_node.latency.count(), 1);
+        assert_eq!(
+            find_node.to_string(),
+            "sent=3 responded=1 timed_out=1 errored=1 p50=50ms p90=50ms p99=50ms"
+        );
+        assert_eq!(stats[&QueryKind::Ping].sent, 0);
+
+        recorder.reset();
+        assert_eq!(recorder.snapshot()[&QueryKind::FindNode].sent, 0);
+    }
+}
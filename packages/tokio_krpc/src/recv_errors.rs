@@ -3,9 +3,14 @@ use failure::{
     Context,
     Fail,
 };
+use krpc_encoding::{
+    KRPCError,
+    NodeID,
+};
 use std::{
     fmt,
     io,
+    net::SocketAddr,
 };
 
 // TODO: Review ErrorKinds
@@ -17,11 +22,25 @@ pub enum ErrorKind {
         cause: io::Error,
     },
 
+    #[fail(display = "socket can no longer receive messages")]
+    FatalSocketError {
+        #[fail(cause)]
+        cause: io::Error,
+    },
+
+    #[fail(display = "Expected a {} message, got {}", expected, got)]
+    UnexpectedMessageType {
+        expected: &'static str,
+        got: &'static str,
+    },
+
     #[fail(display = "Invalid transaction id")]
     InvalidResponseTransactionId,
 
-    #[fail(display = "Failed to parse inbound message")]
+    #[fail(display = "Failed to parse {} byte message from {}", len, from)]
     ParseInboundMessageError {
+        from: SocketAddr,
+        len: usize,
         #[fail(cause)]
         cause: krpc_encoding::errors::Error,
     },
@@ -31,6 +50,26 @@ pub enum ErrorKind {
         transaction_id
     )]
     UnknownTransactionReceived { transaction_id: u32 },
+
+    #[fail(
+        display = "Response for transaction_id={} sent to {} came from {}",
+        transaction_id, expected, got
+    )]
+    ResponseAddressMismatch {
+        transaction_id: u32,
+        expected: SocketAddr,
+        got: SocketAddr,
+    },
+
+    #[fail(
+        display = "Response for transaction_id={} expected from node {} came from node {}",
+        transaction_id, expected, got
+    )]
+    ResponseNodeIDMismatch {
+        transaction_id: u32,
+        expected: NodeID,
+        got: NodeID,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -50,12 +89,63 @@ impl Fail for Error {
     }
 }
 
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        self.inner.get_context()
+    }
+
+    /// Whether the error means no more messages will be received.
+    pub fn is_fatal(&self) -> bool {
+        match self.kind() {
+            ErrorKind::FatalSocketError { .. } => true,
+            _ => false,
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Display::fmt(&self.inner, f)
     }
 }
 
+/// Why an inbound query which had a transaction id and a method name
+/// couldn't be decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum InvalidQueryReason {
+    /// Rejected by [`SendTransportConfig::strict_query_decoding`].
+    ///
+    /// [`SendTransportConfig::strict_query_decoding`]: crate::SendTransportConfig::strict_query_decoding
+    NonCanonical,
+
+    /// Arguments are missing or invalid, like an info-hash of the wrong
+    /// length.
+    InvalidArguments,
+
+    UnknownMethod,
+}
+
+impl InvalidQueryReason {
+    pub(crate) const ALL: [InvalidQueryReason; 3] = [
+        InvalidQueryReason::NonCanonical,
+        InvalidQueryReason::InvalidArguments,
+        InvalidQueryReason::UnknownMethod,
+    ];
+
+    /// Error code and message sent back for the query.
+    pub fn error(self) -> (u8, &'static str) {
+        match self {
+            InvalidQueryReason::NonCanonical => {
+                (KRPCError::PROTOCOL_ERROR, "Non-canonical bencode")
+            }
+            InvalidQueryReason::InvalidArguments => {
+                (KRPCError::PROTOCOL_ERROR, "Invalid arguments")
+            }
+            InvalidQueryReason::UnknownMethod => (KRPCError::METHOD_UNKNOWN, "Method Unknown"),
+        }
+    }
+}
+
 impl From<ErrorKind> for Error {
     fn from(kind: ErrorKind) -> Error {
         Error {
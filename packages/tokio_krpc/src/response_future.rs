@@ -1,9 +1,13 @@
 use crate::{
-    active_transactions::ActiveTransactions,
+    active_transactions::{
+        ActiveTransactions,
+        TransactionSlot,
+    },
     inbound_response_envelope::{
         InboundResponseEnvelope,
         ResponseType,
     },
+    responses::ResponseEnvelope,
     send_errors::{
         Error,
         ErrorKind,
@@ -17,39 +21,100 @@ use futures::{
 };
 
 use krpc_encoding as proto;
-use std::pin::Pin;
-use tokio::prelude::{
-    task::Context,
-    *,
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::{
+    prelude::{
+        task::Context,
+        *,
+    },
+    timer::Delay,
 };
 
+/// Details of a response other than its contents.
+pub struct ResponseDetails {
+    /// Fields of the envelope the response arrived in.
+    pub envelope: ResponseEnvelope,
+
+    /// Whether the response came from another address than the query was
+    /// sent to.
+    pub address_mismatch: bool,
+}
+
 /// A future which resolves when the response for a transaction appears in a
 /// peer's transaction map.
 pub struct ResponseFuture {
     transaction_id: TransactionId,
     transactions: ActiveTransactions,
+    started: Instant,
+
+    /// Fires when the transaction should be given up on.
+    timeout: Option<Delay>,
+
+    /// Released when the future is dropped.
+    _slot: TransactionSlot,
 }
 
 impl ResponseFuture {
-    pub async fn wait_for_tx(
-        transaction_id: TransactionId,
+    /// Starts tracking a new transaction for a query to `destination`.
+    /// Queries should be sent with its [`transaction_id`] after this so their
+    /// responses aren't dropped as unknown.
+    pub fn register(
+        slot: TransactionSlot,
         transactions: ActiveTransactions,
-    ) -> Result<proto::Response> {
-        transactions.add_transaction(transaction_id);
-        let envelope = ResponseFuture::new(transaction_id, transactions)
-            .into_future()
-            .await?;
+        destination: SocketAddr,
+    ) -> ResponseFuture {
+        let transaction_id = transactions.next_unique_transaction_id(destination);
 
-        match envelope.response {
-            ResponseType::Response { response } => Ok(response),
-            ResponseType::Error { error } => Err(ErrorKind::ReceivedKRPCError { error })?,
-        }
-    }
-
-    fn new(transaction_id: TransactionId, transactions: ActiveTransactions) -> ResponseFuture {
         ResponseFuture {
             transaction_id,
             transactions,
+            started: Instant::now(),
+            timeout: None,
+            _slot: slot,
+        }
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    /// Fails with [`ErrorKind::TransactionTimeout`] if no response arrives
+    /// within `timeout` of registering.
+    pub fn with_timeout(mut self, timeout: Duration) -> ResponseFuture {
+        self.timeout = Some(Delay::new(self.started + timeout));
+        self
+    }
+
+    pub async fn wait(self) -> Result<proto::Response> {
+        let (response, _) = self.wait_with_details().await?;
+
+        Ok(response)
+    }
+
+    /// Like [`wait`] but also returns the envelope fields of the response and
+    /// whether it came from the address queried.
+    pub async fn wait_with_details(self) -> Result<(proto::Response, ResponseDetails)> {
+        let envelope = self.into_future().await?;
+        let details = ResponseDetails {
+            envelope: ResponseEnvelope {
+                transaction_id: envelope.transaction_id,
+                ip: envelope.ip,
+                version: envelope.version,
+                read_only: envelope.read_only,
+            },
+            address_mismatch: envelope.address_mismatch,
+        };
+
+        match envelope.response {
+            ResponseType::Response { response } => Ok((response, details)),
+            ResponseType::Error { error } => Err(ErrorKind::ReceivedKRPCError { error })?,
         }
     }
 }
@@ -58,9 +123,28 @@ impl TryFuture for ResponseFuture {
     type Ok = InboundResponseEnvelope;
     type Error = Error;
 
-    fn try_poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Self::Ok>> {
-        self.transactions
-            .poll_response(self.transaction_id, cx.waker())
+    fn try_poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Self::Ok>> {
+        let response = self
+            .transactions
+            .poll_response(self.transaction_id, cx.waker());
+        if response.is_ready() {
+            return response;
+        }
+
+        let timed_out = match &mut self.timeout {
+            Some(timeout) => Pin::new(timeout).poll(cx).is_ready(),
+            None => false,
+        };
+
+        if timed_out {
+            return Poll::Ready(Err(ErrorKind::TransactionTimeout {
+                transaction_id: self.transaction_id,
+                elapsed: self.started.elapsed(),
+            }
+            .into()));
+        }
+
+        Poll::Pending
     }
 }
 
@@ -69,3 +153,155 @@ impl Drop for ResponseFuture {
         self.transactions.drop_transaction(self.transaction_id);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ResponseFuture;
+    use crate::{
+        active_transactions::ActiveTransactions,
+        inbound_response_envelope::{
+            InboundResponseEnvelope,
+            ResponseType,
+        },
+        send_errors::ErrorKind,
+    };
+    use failure::Error;
+    use futures::{
+        future,
+        task::noop_waker,
+        Future,
+    };
+    use krpc_encoding::{
+        self as proto,
+        NodeID,
+    };
+    use std::{
+        pin::Pin,
+        time::Duration,
+    };
+    use tokio::{
+        prelude::{
+            task::Context,
+            FutureExt,
+            Poll,
+        },
+        runtime::current_thread::Runtime,
+    };
+
+    /// Returns [`Poll::Pending`] once, asking to be polled again straight
+    /// away.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn repolled_after_response() -> Result<(), Error> {
+        let transactions = ActiveTransactions::new(1);
+        let slot = transactions.try_acquire_slot()?;
+        let destination = "10.0.0.1:6881".parse()?;
+        let response_future = ResponseFuture::register(slot, transactions.clone(), destination);
+        let transaction_id = response_future.transaction_id();
+
+        // A waker left over from a poll by some other task shouldn't be the
+        // one woken.
+        assert!(transactions
+            .poll_response(transaction_id, &noop_waker())
+            .is_pending());
+
+        let id = NodeID::random();
+        let expected = id.clone();
+        let responder = async move {
+            // Lets the response future return pending before the response
+            // arrives.
+            YieldOnce(false).await;
+
+            transactions
+                .handle_response(InboundResponseEnvelope {
+                    transaction_id: transaction_id.to_be_bytes().to_vec(),
+                    source: destination,
+                    address_mismatch: false,
+                    version: None,
+                    ip: None,
+                    read_only: false,
+                    response: ResponseType::Response {
+                        response: proto::Response::OnlyID { id },
+                    },
+                })
+                .unwrap();
+        };
+
+        let mut runtime = Runtime::new()?;
+        let (response, ()) = runtime.block_on(
+            future::join(response_future.wait(), responder).timeout(Duration::from_secs(1)),
+        )?;
+
+        match response? {
+            proto::Response::OnlyID { id } => assert_eq!(id, expected),
+            response => panic!("unexpected response {:?}", response),
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn timeout() -> Result<(), Error> {
+        let transactions = ActiveTransactions::new(1);
+        let slot = transactions.try_acquire_slot()?;
+        let response_future =
+            ResponseFuture::register(slot, transactions, "10.0.0.1:6881".parse()?)
+                .with_timeout(Duration::from_millis(20));
+        let expected_id = response_future.transaction_id();
+
+        let mut runtime = Runtime::new()?;
+        let err = runtime.block_on(response_future.wait()).unwrap_err();
+
+        match err.kind() {
+            ErrorKind::TransactionTimeout {
+                transaction_id,
+                elapsed,
+            } => {
+                assert_eq!(*transaction_id, expected_id);
+                assert!(*elapsed >= Duration::from_millis(20));
+            }
+            kind => panic!("unexpected error {}", kind),
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_not_found() -> Result<(), Error> {
+        let transactions = ActiveTransactions::new(1);
+        let slot = transactions.try_acquire_slot()?;
+        let response_future =
+            ResponseFuture::register(slot, transactions.clone(), "10.0.0.1:6881".parse()?)
+                .with_timeout(Duration::from_secs(1));
+        let expected_id = response_future.transaction_id();
+        transactions.drop_transaction(expected_id);
+
+        let mut runtime = Runtime::new()?;
+        let err = runtime.block_on(response_future.wait()).unwrap_err();
+
+        match err.kind() {
+            ErrorKind::UnknownTransactionPolled { transaction_id } => {
+                assert_eq!(*transaction_id, expected_id)
+            }
+            kind => panic!("unexpected error {}", kind),
+        };
+
+        Ok(())
+    }
+}
@@ -1,6 +1,11 @@
-use crate::send_errors::{
-    ErrorKind,
-    Result,
+use super::ResponseEnvelope;
+use crate::{
+    response_future::ResponseDetails,
+    send_errors::{
+        Error,
+        ErrorKind,
+        Result,
+    },
 };
 
 use krpc_encoding::{
@@ -8,20 +13,90 @@ use krpc_encoding::{
     NodeID,
     NodeInfo,
 };
+use std::convert::TryFrom;
 
 pub struct FindNodeResponse {
     pub id: NodeID,
     pub nodes: Vec<NodeInfo>,
+
+    /// Sent by some nodes even though only `get_peers` responses need one.
+    pub token: Option<Vec<u8>>,
+
+    envelope: ResponseEnvelope,
+
+    address_mismatch: bool,
 }
 
 impl FindNodeResponse {
-    pub fn from_response(response: proto::Response) -> Result<FindNodeResponse> {
+    /// Duplicate nodes are dropped, see [`Response::deduplicate_nodes`].
+    ///
+    /// [`Response::deduplicate_nodes`]: krpc_encoding::Response::deduplicate_nodes
+    pub fn from_response(mut response: proto::Response) -> Result<FindNodeResponse> {
+        response.deduplicate_nodes();
+
         Ok(match response {
-            proto::Response::NextHop { id, nodes, .. } => FindNodeResponse { id, nodes },
+            proto::Response::NextHop { id, token, nodes } => FindNodeResponse {
+                id,
+                nodes,
+                token,
+                envelope: ResponseEnvelope::default(),
+                address_mismatch: false,
+            },
+            got @ proto::Response::OnlyID { .. } => Err(ErrorKind::MissingResponseFields {
+                expected: "FindNodeResponse",
+                missing: "nodes",
+                got,
+            })?,
             got => Err(ErrorKind::InvalidResponseType {
                 expected: "FindNodeResponse (NextHop)",
                 got,
             })?,
         })
     }
+
+    /// Client version string sent by the responding node, if any. Not
+    /// necessarily 4 bytes or valid UTF-8.
+    pub fn version(&self) -> Option<&[u8]> {
+        self.envelope.version.as_ref().map(Vec::as_slice)
+    }
+
+    /// Fields of the envelope the response arrived in.
+    pub fn envelope(&self) -> &ResponseEnvelope {
+        &self.envelope
+    }
+
+    /// Whether the response came from another address than the query was
+    /// sent to, like from nodes behind some NATs. Such responses can be
+    /// spoofed, so the address queried should be trusted instead.
+    pub fn address_mismatch(&self) -> bool {
+        self.address_mismatch
+    }
+
+    pub(crate) fn with_details(mut self, details: ResponseDetails) -> Self {
+        self.envelope = details.envelope;
+        self.address_mismatch = details.address_mismatch;
+        self
+    }
+}
+
+impl TryFrom<proto::Envelope> for FindNodeResponse {
+    type Error = Error;
+
+    fn try_from(envelope: proto::Envelope) -> Result<FindNodeResponse> {
+        let (response, envelope) = ResponseEnvelope::split(envelope)?;
+        let mut response = FindNodeResponse::from_response(response)?;
+        response.envelope = envelope;
+
+        Ok(response)
+    }
+}
+
+impl From<FindNodeResponse> for proto::Envelope {
+    fn from(response: FindNodeResponse) -> proto::Envelope {
+        response.envelope.join(proto::Response::NextHop {
+            id: response.id,
+            token: response.token,
+            nodes: response.nodes,
+        })
+    }
 }
@@ -1,6 +1,11 @@
-use crate::send_errors::{
-    ErrorKind,
-    Result,
+use super::ResponseEnvelope;
+use crate::{
+    response_future::ResponseDetails,
+    send_errors::{
+        Error,
+        ErrorKind,
+        Result,
+    },
 };
 
 use krpc_encoding::{
@@ -9,39 +14,210 @@ use krpc_encoding::{
     NodeID,
     NodeInfo,
 };
-use std::net::SocketAddrV4;
+use std::{
+    convert::TryFrom,
+    net::SocketAddrV4,
+};
 
+/// Response to a `get_peers` query. Nodes return peers, nodes closer to the
+/// info-hash or both.
 pub struct GetPeersResponse {
     pub id: NodeID,
+
+    /// Needed to announce to the responding node.
     pub token: Option<Vec<u8>>,
-    pub message_type: GetPeersResponseType,
+
+    /// Peers for the info-hash.
+    pub peers: Vec<SocketAddrV4>,
+
+    /// Nodes closer to the info-hash, used to continue a lookup.
+    pub nodes: Vec<NodeInfo>,
+
+    envelope: ResponseEnvelope,
+
+    address_mismatch: bool,
 }
 
 impl GetPeersResponse {
-    pub fn from_response(response: proto::Response) -> Result<GetPeersResponse> {
+    /// Duplicate nodes are dropped, see [`Response::deduplicate_nodes`].
+    ///
+    /// [`Response::deduplicate_nodes`]: krpc_encoding::Response::deduplicate_nodes
+    pub fn from_response(mut response: proto::Response) -> Result<GetPeersResponse> {
+        response.deduplicate_nodes();
+
         Ok(match response {
-            proto::Response::GetPeers { id, token, peers } => GetPeersResponse {
+            proto::Response::GetPeers {
+                id,
+                token,
+                peers,
+                nodes,
+            } => GetPeersResponse {
                 id,
                 token,
-                message_type: GetPeersResponseType::Peers(
-                    peers.into_iter().map(Addr::into).collect(),
-                ),
+                peers: peers.into_iter().map(Addr::into).collect(),
+                nodes,
+                envelope: ResponseEnvelope::default(),
+                address_mismatch: false,
             },
             proto::Response::NextHop { id, token, nodes } => GetPeersResponse {
                 id,
                 token,
-                message_type: GetPeersResponseType::NextHop(nodes),
+                peers: Vec::new(),
+                nodes,
+                envelope: ResponseEnvelope::default(),
+                address_mismatch: false,
             },
+            got @ proto::Response::OnlyID { .. } => Err(ErrorKind::MissingResponseFields {
+                expected: "GetPeersResponse",
+                missing: "values and nodes",
+                got,
+            })?,
             got => Err(ErrorKind::InvalidResponseType {
-                // TODO: Pass In Expected
                 expected: "GetPeersResponse (GetPeers or NextHop)",
                 got,
             })?,
         })
     }
+
+    /// Client version string sent by the responding node, if any. Not
+    /// necessarily 4 bytes or valid UTF-8.
+    pub fn version(&self) -> Option<&[u8]> {
+        self.envelope.version.as_ref().map(Vec::as_slice)
+    }
+
+    /// Fields of the envelope the response arrived in.
+    pub fn envelope(&self) -> &ResponseEnvelope {
+        &self.envelope
+    }
+
+    /// Whether the response came from another address than the query was
+    /// sent to. See [`FindNodeResponse::address_mismatch`].
+    ///
+    /// [`FindNodeResponse::address_mismatch`]: super::FindNodeResponse::address_mismatch
+    pub fn address_mismatch(&self) -> bool {
+        self.address_mismatch
+    }
+
+    pub(crate) fn with_details(mut self, details: ResponseDetails) -> Self {
+        self.envelope = details.envelope;
+        self.address_mismatch = details.address_mismatch;
+        self
+    }
+}
+
+impl TryFrom<proto::Envelope> for GetPeersResponse {
+    type Error = Error;
+
+    fn try_from(envelope: proto::Envelope) -> Result<GetPeersResponse> {
+        let (response, envelope) = ResponseEnvelope::split(envelope)?;
+        let mut response = GetPeersResponse::from_response(response)?;
+        response.envelope = envelope;
+
+        Ok(response)
+    }
 }
 
-pub enum GetPeersResponseType {
-    Peers(Vec<SocketAddrV4>),
-    NextHop(Vec<NodeInfo>),
+/// Responses without peers become [`Response::NextHop`], like they would
+/// have been sent.
+///
+/// [`Response::NextHop`]: krpc_encoding::Response::NextHop
+impl From<GetPeersResponse> for proto::Envelope {
+    fn from(response: GetPeersResponse) -> proto::Envelope {
+        let GetPeersResponse {
+            id,
+            token,
+            peers,
+            nodes,
+            envelope,
+            ..
+        } = response;
+
+        if peers.is_empty() {
+            return envelope.join(proto::Response::NextHop { id, token, nodes });
+        }
+
+        envelope.join(proto::Response::GetPeers {
+            id,
+            token,
+            peers: peers.into_iter().map(Addr::from).collect(),
+            nodes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GetPeersResponse;
+    use crate::send_errors::ErrorKind;
+    use failure::Error;
+    use krpc_encoding::{
+        Envelope,
+        Message,
+        NodeID,
+        Response,
+    };
+
+    fn decode_response(raw: &[u8]) -> Result<Response, Error> {
+        match Envelope::decode(raw)?.message_type {
+            Message::Response { response } => Ok(response),
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn peers_and_nodes() -> Result<(), Error> {
+        let raw = b"d1:rd2:id20:abcdefghij01234567895:nodes26:mnopqrstuvwxyz123456\x7f\x00\x00\x01\x1a\xe15:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+
+        let response = GetPeersResponse::from_response(decode_response(raw)?)?;
+
+        assert_eq!(response.token, Some(b"aoeusnth".to_vec()));
+        assert_eq!(
+            response.peers,
+            vec![
+                "97.120.106.101:11893".parse()?,
+                "105.100.104.116:28269".parse()?,
+            ]
+        );
+        assert_eq!(response.nodes.len(), 1);
+        assert_eq!(
+            response.nodes[0].node_id,
+            NodeID::from(b"mnopqrstuvwxyz123456")
+        );
+        assert_eq!(response.nodes[0].address, "127.0.0.1:6881".parse()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_nodes() -> Result<(), Error> {
+        let raw = b"d1:rd2:id20:abcdefghij01234567895:nodes26:mnopqrstuvwxyz123456\x7f\x00\x00\x01\x1a\xe15:token8:aoeusnthe1:t2:aa1:y1:re";
+
+        let response = GetPeersResponse::from_response(decode_response(raw)?)?;
+
+        assert_eq!(response.token, Some(b"aoeusnth".to_vec()));
+        assert!(response.peers.is_empty());
+        assert_eq!(response.nodes.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_fields() -> Result<(), Error> {
+        let raw = b"d1:rd2:id20:abcdefghij0123456789e1:t2:aa1:y1:re";
+
+        let err = match GetPeersResponse::from_response(decode_response(raw)?) {
+            Err(err) => err,
+            Ok(_) => panic!("converted response without peers or nodes"),
+        };
+
+        match err.kind() {
+            ErrorKind::MissingResponseFields { missing, .. } => {
+                assert_eq!(*missing, "values and nodes")
+            }
+            kind => panic!("unexpected error {}", kind),
+        };
+        assert!(err.to_string().contains("values and nodes"));
+
+        Ok(())
+    }
 }
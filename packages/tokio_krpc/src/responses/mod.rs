@@ -1,7 +1,13 @@
 mod find_node_response;
 mod get_peers_response;
 mod node_id_response;
+mod response_envelope;
+mod sample_infohashes_response;
 
 pub use find_node_response::FindNodeResponse;
 pub use get_peers_response::GetPeersResponse;
 pub use node_id_response::NodeIDResponse;
+pub use response_envelope::ResponseEnvelope;
+
+pub(crate) use response_envelope::message_type;
+pub use sample_infohashes_response::SampleInfoHashesResponse;
@@ -0,0 +1,229 @@
+use crate::send_errors::{
+    ErrorKind,
+    Result,
+};
+
+use krpc_encoding::{
+    self as proto,
+    Addr,
+};
+
+/// Fields of the envelope a response arrived in, kept by the typed responses
+/// so converting them back to an [`Envelope`] loses nothing.
+///
+/// [`Envelope`]: krpc_encoding::Envelope
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResponseEnvelope {
+    pub transaction_id: Vec<u8>,
+
+    /// Our address as seen by the responding node, see [BEP-0042].
+    ///
+    /// [BEP-0042]: http://www.bittorrent.org/beps/bep_0042.html
+    pub ip: Option<Addr>,
+
+    /// Client version string sent by the responding node.
+    pub version: Option<Vec<u8>>,
+
+    /// Whether the responding node is read-only, see [BEP-0043].
+    ///
+    /// [BEP-0043]: http://www.bittorrent.org/beps/bep_0043.html
+    pub read_only: bool,
+}
+
+impl ResponseEnvelope {
+    /// Splits `envelope` into the response it holds and its other fields.
+    /// Error messages fail with [`ErrorKind::ReceivedKRPCError`] and queries
+    /// with [`ErrorKind::UnexpectedMessageType`].
+    pub fn split(envelope: proto::Envelope) -> Result<(proto::Response, ResponseEnvelope)> {
+        let response = match envelope.message_type {
+            proto::Message::Response { response } => response,
+            proto::Message::Error { error } => Err(ErrorKind::ReceivedKRPCError { error })?,
+            message => Err(ErrorKind::UnexpectedMessageType {
+                expected: "response",
+                got: message_type(&message),
+            })?,
+        };
+
+        let fields = ResponseEnvelope {
+            transaction_id: envelope.transaction_id,
+            ip: envelope.ip,
+            version: envelope.version.map(|version| version.to_vec()),
+            read_only: envelope.read_only,
+        };
+
+        Ok((response, fields))
+    }
+
+    /// Envelope holding `response` with these fields.
+    pub fn join(self, response: proto::Response) -> proto::Envelope {
+        proto::Envelope {
+            ip: self.ip,
+            transaction_id: self.transaction_id,
+            version: self.version.map(Into::into),
+            message_type: proto::Message::Response { response },
+            read_only: self.read_only,
+        }
+    }
+}
+
+/// Name of the type of `message` used in errors.
+pub(crate) fn message_type(message: &proto::Message) -> &'static str {
+    match message {
+        proto::Message::Query { .. } => "query",
+        proto::Message::Response { .. } => "response",
+        proto::Message::Error { .. } => "error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResponseEnvelope;
+    use crate::{
+        responses::{
+            FindNodeResponse,
+            GetPeersResponse,
+            SampleInfoHashesResponse,
+        },
+        send_errors::ErrorKind,
+    };
+    use failure::Error;
+    use krpc_encoding::{
+        Envelope,
+        KRPCError,
+        Message,
+        NodeID,
+        NodeInfo,
+        Query,
+        Response,
+    };
+    use std::{
+        convert::TryFrom,
+        net::SocketAddrV4,
+    };
+
+    /// Envelope with every optional field set.
+    fn envelope(response: Response) -> Envelope {
+        Envelope {
+            ip: Some("124.31.75.21:6881".parse::<SocketAddrV4>().unwrap().into()),
+            transaction_id: b"aa".to_vec(),
+            version: Some(b"LT\x01\x02".to_vec().into()),
+            message_type: Message::Response { response },
+            read_only: true,
+        }
+    }
+
+    fn nodes() -> Vec<NodeInfo> {
+        vec![NodeInfo::new(
+            NodeID::random(),
+            "10.0.0.1:6881".parse().unwrap(),
+        )]
+    }
+
+    #[test]
+    fn find_node_round_trip() -> Result<(), Error> {
+        let original = envelope(Response::NextHop {
+            id: NodeID::random(),
+            token: Some(b"token".to_vec()),
+            nodes: nodes(),
+        });
+
+        let response = FindNodeResponse::try_from(original.clone())?;
+        assert_eq!(response.version(), Some(&b"LT\x01\x02"[..]));
+        assert_eq!(response.envelope().ip, original.ip);
+        assert!(response.envelope().read_only);
+        assert_eq!(Envelope::from(response), original);
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_peers_round_trip() -> Result<(), Error> {
+        let with_peers = envelope(Response::GetPeers {
+            id: NodeID::random(),
+            token: Some(b"token".to_vec()),
+            peers: vec!["10.0.0.2:51413".parse::<SocketAddrV4>()?.into()],
+            nodes: nodes(),
+        });
+        let only_nodes = envelope(Response::NextHop {
+            id: NodeID::random(),
+            token: Some(b"token".to_vec()),
+            nodes: nodes(),
+        });
+
+        for original in vec![with_peers, only_nodes] {
+            let response = GetPeersResponse::try_from(original.clone())?;
+            assert_eq!(response.envelope().transaction_id, b"aa".to_vec());
+            assert_eq!(Envelope::from(response), original);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn samples_round_trip() -> Result<(), Error> {
+        let original = envelope(Response::Samples {
+            id: NodeID::random(),
+            interval: Some(60),
+            nodes: nodes(),
+            num: Some(100),
+            samples: vec![NodeID::random(), NodeID::random()],
+        });
+
+        let response = SampleInfoHashesResponse::try_from(original.clone())?;
+        assert_eq!(Envelope::from(response), original);
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_id_round_trip() -> Result<(), Error> {
+        let original = envelope(Response::OnlyID {
+            id: NodeID::random(),
+        });
+
+        let (response, fields) = ResponseEnvelope::split(original.clone())?;
+        assert_eq!(fields.join(response), original);
+
+        Ok(())
+    }
+
+    #[test]
+    fn mismatched_messages() {
+        let mut error = envelope(Response::OnlyID {
+            id: NodeID::random(),
+        });
+        error.message_type = Message::Error {
+            error: KRPCError::new(KRPCError::GENERIC_ERROR, "nope"),
+        };
+        match FindNodeResponse::try_from(error).err().unwrap().kind() {
+            ErrorKind::ReceivedKRPCError { .. } => {}
+            kind => panic!("unexpected error {}", kind),
+        };
+
+        let query = Envelope::query(
+            b"aa".to_vec(),
+            Query::Ping {
+                id: NodeID::random(),
+            },
+        );
+        let err = GetPeersResponse::try_from(query).err().unwrap();
+        match err.kind() {
+            ErrorKind::UnexpectedMessageType { expected, got } => {
+                assert_eq!((*expected, *got), ("response", "query"))
+            }
+            kind => panic!("unexpected error {}", kind),
+        };
+
+        let only_id = envelope(Response::OnlyID {
+            id: NodeID::random(),
+        });
+        match SampleInfoHashesResponse::try_from(only_id)
+            .err()
+            .unwrap()
+            .kind()
+        {
+            ErrorKind::InvalidResponseType { .. } => {}
+            kind => panic!("unexpected error {}", kind),
+        };
+    }
+}
@@ -0,0 +1,103 @@
+use super::ResponseEnvelope;
+use crate::{
+    response_future::ResponseDetails,
+    send_errors::{
+        Error,
+        ErrorKind,
+        Result,
+    },
+};
+
+use krpc_encoding::{
+    self as proto,
+    NodeID,
+    NodeInfo,
+};
+use std::convert::TryFrom;
+
+pub struct SampleInfoHashesResponse {
+    pub id: NodeID,
+    pub interval: Option<u16>,
+    pub nodes: Vec<NodeInfo>,
+    pub num: Option<u32>,
+    pub samples: Vec<NodeID>,
+
+    envelope: ResponseEnvelope,
+
+    address_mismatch: bool,
+}
+
+impl SampleInfoHashesResponse {
+    pub fn from_response(response: proto::Response) -> Result<SampleInfoHashesResponse> {
+        Ok(match response {
+            proto::Response::Samples {
+                id,
+                interval,
+                nodes,
+                num,
+                samples,
+            } => SampleInfoHashesResponse {
+                id,
+                interval,
+                nodes,
+                num,
+                samples,
+                envelope: ResponseEnvelope::default(),
+                address_mismatch: false,
+            },
+            got => Err(ErrorKind::InvalidResponseType {
+                expected: "SampleInfoHashesResponse (Samples)",
+                got,
+            })?,
+        })
+    }
+
+    /// Client version string sent by the responding node, if any. Not
+    /// necessarily 4 bytes or valid UTF-8.
+    pub fn version(&self) -> Option<&[u8]> {
+        self.envelope.version.as_ref().map(Vec::as_slice)
+    }
+
+    /// Fields of the envelope the response arrived in.
+    pub fn envelope(&self) -> &ResponseEnvelope {
+        &self.envelope
+    }
+
+    /// Whether the response came from another address than the query was
+    /// sent to. See [`FindNodeResponse::address_mismatch`].
+    ///
+    /// [`FindNodeResponse::address_mismatch`]: super::FindNodeResponse::address_mismatch
+    pub fn address_mismatch(&self) -> bool {
+        self.address_mismatch
+    }
+
+    pub(crate) fn with_details(mut self, details: ResponseDetails) -> Self {
+        self.envelope = details.envelope;
+        self.address_mismatch = details.address_mismatch;
+        self
+    }
+}
+
+impl TryFrom<proto::Envelope> for SampleInfoHashesResponse {
+    type Error = Error;
+
+    fn try_from(envelope: proto::Envelope) -> Result<SampleInfoHashesResponse> {
+        let (response, envelope) = ResponseEnvelope::split(envelope)?;
+        let mut response = SampleInfoHashesResponse::from_response(response)?;
+        response.envelope = envelope;
+
+        Ok(response)
+    }
+}
+
+impl From<SampleInfoHashesResponse> for proto::Envelope {
+    fn from(response: SampleInfoHashesResponse) -> proto::Envelope {
+        response.envelope.join(proto::Response::Samples {
+            id: response.id,
+            interval: response.interval,
+            nodes: response.nodes,
+            num: response.num,
+            samples: response.samples,
+        })
+    }
+}
@@ -7,6 +7,8 @@ use krpc_encoding as proto;
 use std::{
     fmt,
     io,
+    net::SocketAddr,
+    time::Duration,
 };
 
 // TODO: Review ErrorKinds
@@ -21,12 +23,32 @@ pub enum ErrorKind {
         got: krpc_encoding::Response,
     },
 
+    #[fail(display = "{} is missing {}, got {:?}", expected, missing, got)]
+    MissingResponseFields {
+        expected: &'static str,
+        missing: &'static str,
+        got: krpc_encoding::Response,
+    },
+
+    #[fail(display = "Expected a {} message, got {}", expected, got)]
+    UnexpectedMessageType {
+        expected: &'static str,
+        got: &'static str,
+    },
+
     #[fail(display = "Failed to send")]
     SendError {
         #[fail(cause)]
         cause: io::Error,
     },
 
+    #[fail(display = "Destination {} is unreachable", address)]
+    Unreachable {
+        address: SocketAddr,
+        #[fail(cause)]
+        cause: io::Error,
+    },
+
     #[fail(display = "Failed to encode message for sending")]
     SendEncodingError {
         #[fail(cause)]
@@ -38,6 +60,36 @@ pub enum ErrorKind {
         transaction_id
     )]
     UnknownTransactionPolled { transaction_id: u32 },
+
+    #[fail(
+        display = "Transaction timed out transaction_id={} elapsed={:?}",
+        transaction_id, elapsed
+    )]
+    TransactionTimeout {
+        transaction_id: u32,
+        elapsed: Duration,
+    },
+
+    #[fail(display = "Read only nodes can't announce")]
+    ReadOnlyNode,
+
+    #[fail(display = "Failed to get local address of socket")]
+    LocalAddressUnavailable {
+        #[fail(cause)]
+        cause: io::Error,
+    },
+
+    #[fail(display = "Too many transactions in flight max={}", max)]
+    TooManyTransactions { max: usize },
+
+    #[fail(display = "Outbound queue isn't being sent anymore")]
+    SenderStopped,
+
+    #[fail(display = "Transport is shutting down")]
+    ShuttingDown,
+
+    #[fail(display = "Association with the proxy was lost")]
+    ProxyAssociationLost,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -57,6 +109,12 @@ impl Fail for Error {
     }
 }
 
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        self.inner.get_context()
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Display::fmt(&self.inner, f)
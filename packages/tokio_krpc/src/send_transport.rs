@@ -1,19 +1,58 @@
 use crate::{
-    active_transactions::ActiveTransactions,
+    active_transactions::{
+        ActiveTransactions,
+        DEFAULT_MAX_TRANSACTIONS,
+    },
+    inbound::RecvCounters,
+    outbound::{
+        self,
+        FlowId,
+        Outbound,
+        RateLimiter,
+    },
     port_type::PortType,
-    response_future::ResponseFuture,
+    proxy::{
+        self,
+        Association,
+    },
+    query_stats::{
+        QueryKind,
+        QueryRecorder,
+        QueryStats,
+    },
+    recv_errors::InvalidQueryReason,
+    response_future::{
+        ResponseDetails,
+        ResponseFuture,
+    },
     responses::{
         FindNodeResponse,
         GetPeersResponse,
         NodeIDResponse,
+        SampleInfoHashesResponse,
     },
     send_errors::{
+        Error,
         ErrorKind,
         Result,
     },
+    socket_errors::{
+        self,
+        SocketErrorKind,
+        SocketErrorPolicy,
+    },
+    tap::{
+        Direction,
+        PacketTap,
+    },
     transaction_id::TransactionId,
 };
-use futures::lock::Mutex;
+use futures::{
+    future::BoxFuture,
+    lock::Mutex,
+    Future,
+    FutureExt,
+};
 use krpc_encoding::{
     self as proto,
     Envelope,
@@ -21,31 +60,287 @@ use krpc_encoding::{
     NodeID,
     Query,
 };
-use rand;
 use std::{
     self,
+    collections::BTreeMap,
+    io,
     net::SocketAddr,
+    sync::Arc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::{
+    net::{
+        udp::split::UdpSocketSendHalf,
+        UdpSocket,
+    },
+    timer::Delay,
 };
-use tokio::net::udp::split::UdpSocketSendHalf;
+
+/// Client version sent in the `v` field of outgoing messages unless
+/// configured otherwise.
+pub const DEFAULT_VERSION: [u8; 4] = *b"RC\x00\x01";
+
+/// Time to wait for a response unless configured otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Options controlling how a [`SendTransport`] builds outgoing queries.
+#[derive(Clone, Debug)]
+pub struct SendTransportConfig {
+    /// Marks outgoing queries as coming from a read-only node as defined in
+    /// [BEP-0043]. Read-only nodes aren't added to other nodes' routing tables
+    /// and never announce.
+    ///
+    /// [BEP-0043]: http://www.bittorrent.org/beps/bep_0043.html
+    pub read_only: bool,
+
+    /// Maximum number of requests awaiting a response at once.
+    pub max_transactions: usize,
+
+    /// When `max_transactions` requests are in flight, fail new requests with
+    /// [`ErrorKind::TooManyTransactions`] instead of waiting for one of them
+    /// to finish.
+    pub fail_when_full: bool,
+
+    /// Limits the rate at which queries are sent. Responses to queries from
+    /// other nodes aren't limited.
+    pub max_queries_per_second: Option<u32>,
+
+    /// How long to wait for a response before failing a request with
+    /// [`ErrorKind::TransactionTimeout`]. Requests wait forever when `None`.
+    /// Overridden by the `*_with_options` methods.
+    pub query_timeout: Option<Duration>,
+
+    /// Client version sent in the `v` field of outgoing queries and of
+    /// messages sent with [`SendTransport::send`] which don't set one.
+    pub version: Option<[u8; 4]>,
+
+    /// Rejects responses coming from another address than the query was
+    /// sent to, failing the receive with
+    /// [`recv_errors::ErrorKind::ResponseAddressMismatch`] while the query
+    /// keeps waiting. Otherwise they are accepted and marked, see
+    /// [`FindNodeResponse::address_mismatch`].
+    ///
+    /// [`recv_errors::ErrorKind::ResponseAddressMismatch`]: crate::recv_errors::ErrorKind::ResponseAddressMismatch
+    pub strict_response_addresses: bool,
+
+    /// Rejects inbound queries which aren't canonically encoded, see
+    /// [`krpc_encoding::check_canonical`], failing the receive with
+    /// [`recv_errors::ErrorKind::ParseInboundMessageError`]. Responses are
+    /// always decoded leniently so nothing a queried node returns is lost.
+    ///
+    /// [`recv_errors::ErrorKind::ParseInboundMessageError`]: crate::recv_errors::ErrorKind::ParseInboundMessageError
+    pub strict_query_decoding: bool,
+
+    /// Rejects responses to queries made with a
+    /// [`QueryOptions::expected_id`] which come from a node with another id,
+    /// failing the receive with
+    /// [`recv_errors::ErrorKind::ResponseNodeIDMismatch`] while the query
+    /// keeps waiting. Otherwise they are accepted and only counted in
+    /// [`TransportStats::mismatched_node_ids`], for nodes which changed
+    /// their id when restarting.
+    ///
+    /// [`recv_errors::ErrorKind::ResponseNodeIDMismatch`]: crate::recv_errors::ErrorKind::ResponseNodeIDMismatch
+    pub strict_node_ids: bool,
+
+    /// What happens when the socket fails to send a message, for queries as
+    /// well as responses. Defaults to retrying transient errors right away,
+    /// see [`SocketErrorPolicy::default`].
+    pub socket_error_policy: SocketErrorPolicy,
+}
+
+impl Default for SendTransportConfig {
+    fn default() -> SendTransportConfig {
+        SendTransportConfig {
+            read_only: false,
+            max_transactions: DEFAULT_MAX_TRANSACTIONS,
+            fail_when_full: false,
+            max_queries_per_second: None,
+            query_timeout: Some(DEFAULT_TIMEOUT),
+            version: Some(DEFAULT_VERSION),
+            strict_response_addresses: false,
+            strict_query_decoding: true,
+            strict_node_ids: true,
+            socket_error_policy: SocketErrorPolicy::default(),
+        }
+    }
+}
+
+/// Options for a single query, overriding the [`SendTransportConfig`].
+#[derive(Clone, Debug)]
+pub struct QueryOptions {
+    /// How long to wait for a response before failing with
+    /// [`ErrorKind::TransactionTimeout`].
+    pub timeout: Duration,
+
+    /// Id of the queried node, when it's known like for nodes from a routing
+    /// table. A spoofed response then has to guess it too. See
+    /// [`SendTransportConfig::strict_node_ids`].
+    pub expected_id: Option<NodeID>,
+}
+
+impl Default for QueryOptions {
+    fn default() -> QueryOptions {
+        QueryOptions {
+            timeout: DEFAULT_TIMEOUT,
+            expected_id: None,
+        }
+    }
+}
+
+/// Point in time statistics about a [`SendTransport`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransportStats {
+    /// Number of requests awaiting a response.
+    pub in_flight_transactions: usize,
+
+    /// Number of requests failed because they were awaiting a response for
+    /// five minutes, usually because their future was leaked.
+    pub evicted_transactions: usize,
+
+    /// Number of responses received from another address than their query
+    /// was sent to, including rejected ones.
+    pub mismatched_responses: usize,
+
+    /// Number of responses from another node than their query expected,
+    /// including rejected ones.
+    pub mismatched_node_ids: usize,
+
+    /// Number of inbound messages which couldn't be decoded.
+    pub decode_errors: usize,
+
+    /// Number of inbound messages which couldn't be decoded but had what a
+    /// query needs to be answered, by the reason they didn't decode. They
+    /// were answered with an error unless the node is read-only.
+    pub invalid_queries: BTreeMap<InvalidQueryReason, usize>,
+
+    /// Outcomes and latencies of the queries sent since the transport was
+    /// created or [`SendTransport::reset_query_stats`] was called.
+    pub queries: BTreeMap<QueryKind, QueryStats>,
+}
 
 pub struct SendTransport {
-    socket: Mutex<UdpSocketSendHalf>,
+    socket: Arc<Mutex<SendSocket>>,
+
+    /// Queries waiting to be sent.
+    outbound: Outbound,
+
+    /// Address the DHT socket is bound to.
+    local_addr: Option<SocketAddr>,
+
     transactions: ActiveTransactions,
+    config: SendTransportConfig,
+
+    /// Inbound messages which couldn't be decoded, counted while receiving.
+    recv_counters: Arc<RecvCounters>,
+
+    query_stats: QueryRecorder,
+}
+
+/// Socket along with a buffer re-used to encode outgoing messages.
+pub(crate) struct SendSocket<S = UdpSocketSendHalf> {
+    socket: S,
+    buffer: Vec<u8>,
+    tap: Option<PacketTap>,
+
+    /// Proxy every message is relayed through, if any.
+    proxy: Option<Association>,
+
+    error_policy: SocketErrorPolicy,
+}
+
+/// Sends datagrams. Implemented by the socket, and by mocks in tests to make
+/// it fail on purpose.
+pub(crate) trait SendDatagram {
+    fn send_datagram<'a>(
+        &'a mut self,
+        buffer: &'a [u8],
+        target: &'a SocketAddr,
+    ) -> BoxFuture<'a, io::Result<usize>>;
+}
+
+impl SendDatagram for UdpSocketSendHalf {
+    fn send_datagram<'a>(
+        &'a mut self,
+        buffer: &'a [u8],
+        target: &'a SocketAddr,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        self.send_to(buffer, target).boxed()
+    }
 }
 
 impl SendTransport {
+    /// Creates a transport along with the future sending its queries. The
+    /// future finishes after the transport is dropped.
     pub(crate) fn new(
         socket: UdpSocketSendHalf,
+        local_addr: Option<SocketAddr>,
         transactions: ActiveTransactions,
-    ) -> SendTransport {
-        SendTransport {
-            socket: Mutex::new(socket),
+        config: SendTransportConfig,
+        tap: Option<PacketTap>,
+        proxy: Option<Association>,
+        recv_counters: Arc<RecvCounters>,
+    ) -> (SendTransport, impl Future<Output = ()> + Send + 'static) {
+        let socket = Arc::new(Mutex::new(SendSocket {
+            socket,
+            buffer: Vec::with_capacity(1024),
+            tap,
+            proxy,
+            error_policy: config.socket_error_policy,
+        }));
+        let outbound = Outbound::new();
+        let limiter = config
+            .max_queries_per_second
+            .map(|per_second| RateLimiter::new(per_second, Instant::now()));
+        let sender = outbound::run_sender(outbound.clone(), socket.clone(), limiter);
+
+        let send_transport = SendTransport {
+            socket,
+            outbound,
+            local_addr,
             transactions,
-        }
+            config,
+            recv_counters,
+            query_stats: QueryRecorder::default(),
+        };
+
+        (send_transport, sender)
     }
 
     pub async fn ping(&self, id: NodeID, address: SocketAddr) -> Result<NodeID> {
-        let response = self.request(address, Query::Ping { id }).await?;
+        self.ping_with_timeout(id, address, self.config.query_timeout, None)
+            .await
+    }
+
+    /// Like [`ping`] with options for this query only.
+    pub async fn ping_with_options(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        options: QueryOptions,
+    ) -> Result<NodeID> {
+        self.ping_with_timeout(id, address, Some(options.timeout), options.expected_id)
+            .await
+    }
+
+    async fn ping_with_timeout(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        timeout: Option<Duration>,
+        expected_id: Option<NodeID>,
+    ) -> Result<NodeID> {
+        let (response, _) = self
+            .request_with_details(
+                FlowId::DEFAULT,
+                address,
+                Query::Ping { id },
+                timeout,
+                expected_id,
+            )
+            .await?;
 
         Ok(NodeIDResponse::from_response(response)?)
     }
@@ -56,11 +351,47 @@ impl SendTransport {
         address: SocketAddr,
         target: NodeID,
     ) -> Result<FindNodeResponse> {
-        let response = self
-            .request(address, Query::FindNode { id, target })
+        self.find_node_with_timeout(id, address, target, self.config.query_timeout, None)
+            .await
+    }
+
+    /// Like [`find_node`] with options for this query only.
+    pub async fn find_node_with_options(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+        options: QueryOptions,
+    ) -> Result<FindNodeResponse> {
+        self.find_node_with_timeout(
+            id,
+            address,
+            target,
+            Some(options.timeout),
+            options.expected_id,
+        )
+        .await
+    }
+
+    async fn find_node_with_timeout(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+        timeout: Option<Duration>,
+        expected_id: Option<NodeID>,
+    ) -> Result<FindNodeResponse> {
+        let (response, details) = self
+            .request_with_details(
+                FlowId::DEFAULT,
+                address,
+                Query::FindNode { id, target },
+                timeout,
+                expected_id,
+            )
             .await?;
 
-        Ok(FindNodeResponse::from_response(response)?)
+        Ok(FindNodeResponse::from_response(response)?.with_details(details))
     }
 
     pub async fn get_peers(
@@ -69,11 +400,47 @@ impl SendTransport {
         address: SocketAddr,
         info_hash: NodeID,
     ) -> Result<GetPeersResponse> {
-        let response = self
-            .request(address, Query::GetPeers { id, info_hash })
+        self.get_peers_with_timeout(id, address, info_hash, self.config.query_timeout, None)
+            .await
+    }
+
+    /// Like [`get_peers`] with options for this query only.
+    pub async fn get_peers_with_options(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        info_hash: NodeID,
+        options: QueryOptions,
+    ) -> Result<GetPeersResponse> {
+        self.get_peers_with_timeout(
+            id,
+            address,
+            info_hash,
+            Some(options.timeout),
+            options.expected_id,
+        )
+        .await
+    }
+
+    async fn get_peers_with_timeout(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        info_hash: NodeID,
+        timeout: Option<Duration>,
+        expected_id: Option<NodeID>,
+    ) -> Result<GetPeersResponse> {
+        let (response, details) = self
+            .request_with_details(
+                FlowId::DEFAULT,
+                address,
+                Query::GetPeers { id, info_hash },
+                timeout,
+                expected_id,
+            )
             .await?;
 
-        Ok(GetPeersResponse::from_response(response)?)
+        Ok(GetPeersResponse::from_response(response)?.with_details(details))
     }
 
     pub async fn announce_peer(
@@ -84,13 +451,62 @@ impl SendTransport {
         info_hash: NodeID,
         port_type: PortType,
     ) -> Result<NodeID> {
+        self.announce_peer_with_timeout(
+            id,
+            token,
+            address,
+            info_hash,
+            port_type,
+            self.config.query_timeout,
+            None,
+        )
+        .await
+    }
+
+    /// Like [`announce_peer`] with options for this query only.
+    pub async fn announce_peer_with_options(
+        &self,
+        id: NodeID,
+        token: Vec<u8>,
+        address: SocketAddr,
+        info_hash: NodeID,
+        port_type: PortType,
+        options: QueryOptions,
+    ) -> Result<NodeID> {
+        self.announce_peer_with_timeout(
+            id,
+            token,
+            address,
+            info_hash,
+            port_type,
+            Some(options.timeout),
+            options.expected_id,
+        )
+        .await
+    }
+
+    async fn announce_peer_with_timeout(
+        &self,
+        id: NodeID,
+        token: Vec<u8>,
+        address: SocketAddr,
+        info_hash: NodeID,
+        port_type: PortType,
+        timeout: Option<Duration>,
+        expected_id: Option<NodeID>,
+    ) -> Result<NodeID> {
+        if self.config.read_only {
+            Err(ErrorKind::ReadOnlyNode)?;
+        }
+
         let (port, implied_port) = match port_type {
             PortType::Implied => (None, true),
             PortType::Port(port) => (Some(port), false),
         };
 
-        let response = self
-            .request(
+        let (response, _) = self
+            .request_with_details(
+                FlowId::DEFAULT,
                 address,
                 Query::AnnouncePeer {
                     id,
@@ -99,44 +515,1294 @@ impl SendTransport {
                     port,
                     implied_port,
                 },
+                timeout,
+                expected_id,
             )
             .await?;
 
         Ok(NodeIDResponse::from_response(response)?)
     }
 
-    pub async fn send(&self, address: SocketAddr, message: Envelope) -> Result<()> {
-        let encoded = message
-            .encode()
-            .map_err(|cause| ErrorKind::SendEncodingError { cause })?;
+    /// Announces the port `local_socket` is bound to. If it is the same port
+    /// the DHT socket is bound to, the remote node is asked to use the source
+    /// port of the query instead. This keeps the announced port correct behind
+    /// a NAT.
+    pub async fn announce_peer_auto<'a>(
+        &'a self,
+        id: NodeID,
+        token: Vec<u8>,
+        address: SocketAddr,
+        info_hash: NodeID,
+        local_socket: &'a UdpSocket,
+    ) -> Result<NodeID> {
+        let local_port = local_socket
+            .local_addr()
+            .map_err(|cause| ErrorKind::LocalAddressUnavailable { cause })?
+            .port();
 
-        let mut socket = self.socket.lock().await;
+        let port_type = match self.local_addr {
+            Some(dht_addr) if dht_addr.port() == local_port => PortType::Implied,
+            _ => PortType::Port(local_port),
+        };
 
-        socket
-            .send_to(&encoded, &address)
+        self.announce_peer(id, token, address, info_hash, port_type)
             .await
-            .map_err(|cause| ErrorKind::SendError { cause })?;
+    }
 
-        Ok(())
+    pub async fn sample_infohashes(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+    ) -> Result<SampleInfoHashesResponse> {
+        let (response, details) = self
+            .request_with_details(
+                FlowId::DEFAULT,
+                address,
+                Query::SampleInfoHashes { id, target },
+                self.config.query_timeout,
+                None,
+            )
+            .await?;
+
+        Ok(SampleInfoHashesResponse::from_response(response)?.with_details(details))
+    }
+
+    /// Sends `message` right away, skipping the queue used for queries. The
+    /// configured version is filled in if `message` doesn't have one.
+    pub async fn send(&self, address: SocketAddr, mut message: Envelope) -> Result<()> {
+        if message.version.is_none() {
+            message.version = self.config.version.map(|version| version.to_vec().into());
+        }
+
+        send_on(&self.socket, address, &message).await
     }
 
     pub async fn request(&self, address: SocketAddr, query: Query) -> Result<proto::Response> {
-        let transaction_id = Self::random_transaction_id();
+        self.request_in_flow(FlowId::DEFAULT, address, query).await
+    }
+
+    /// Sends a query as part of `flow`. Flows take turns sending queries.
+    pub async fn request_in_flow(
+        &self,
+        flow: FlowId,
+        address: SocketAddr,
+        query: Query,
+    ) -> Result<proto::Response> {
+        let (response, _) = self
+            .request_with_details(flow, address, query, self.config.query_timeout, None)
+            .await?;
+
+        Ok(response)
+    }
+
+    /// Like [`request_in_flow`] but waits `timeout` for the response and also
+    /// returns the details of the response. The response has to come from
+    /// the node with `expected_id` if set, see
+    /// [`SendTransportConfig::strict_node_ids`].
+    async fn request_with_details(
+        &self,
+        flow: FlowId,
+        address: SocketAddr,
+        query: Query,
+        timeout: Option<Duration>,
+        expected_id: Option<NodeID>,
+    ) -> Result<(proto::Response, ResponseDetails)> {
+        let slot = if self.config.fail_when_full {
+            self.transactions.try_acquire_slot()?
+        } else {
+            self.transactions.acquire_slot().await
+        };
+
+        if self.transactions.is_shut_down() {
+            Err(ErrorKind::ShuttingDown)?;
+        }
+
+        // Registered before sending so a quick response isn't missed.
+        let mut response = ResponseFuture::register(slot, self.transactions.clone(), address);
+        if let Some(expected_id) = expected_id {
+            self.transactions
+                .expect_node_id(response.transaction_id(), expected_id);
+        }
+        #[cfg(feature = "trace")]
+        let (transaction_id, query_name) = (response.transaction_id(), query.name());
+        let kind = QueryKind::of(&query);
+        let envelope = self.build_request(response.transaction_id(), query);
+        if let Some(timeout) = timeout {
+            response = response.with_timeout(timeout);
+        }
+
+        if let Some(kind) = kind {
+            self.query_stats.record_sent(kind);
+        }
+        let started = Instant::now();
+        let result = self.send_and_wait(flow, address, envelope, response).await;
+        if let Some(kind) = kind {
+            self.query_stats
+                .record_result(kind, &result, started.elapsed());
+        }
+        #[cfg(feature = "trace")]
+        crate::trace::transaction(
+            transaction_id,
+            address,
+            query_name,
+            started.elapsed(),
+            &result,
+        );
+
+        result
+    }
+
+    async fn send_and_wait(
+        &self,
+        flow: FlowId,
+        address: SocketAddr,
+        envelope: Envelope,
+        response: ResponseFuture,
+    ) -> Result<(proto::Response, ResponseDetails)> {
+        if let Err(err) = self.outbound.send(flow, address, envelope).await {
+            if self.transactions.is_shut_down() {
+                Err(ErrorKind::ShuttingDown)?;
+            }
+
+            return Err(err);
+        }
+
+        Ok(response.wait_with_details().await?)
+    }
+
+    /// Stops sending and fails every request in flight, and every later
+    /// one, with [`ErrorKind::ShuttingDown`]. Responses which already
+    /// arrived are still returned.
+    pub fn shutdown(&self) {
+        self.outbound.close();
+        self.transactions.shutdown();
+    }
+
+    pub(crate) fn socket(&self) -> Arc<Mutex<SendSocket>> {
+        self.socket.clone()
+    }
 
-        let envelope = Envelope {
+    /// Allocates a new flow. Queries in different flows are sent in turns
+    /// rather than in the order they were made.
+    pub fn new_flow(&self) -> FlowId {
+        self.outbound.new_flow()
+    }
+
+    pub fn stats(&self) -> TransportStats {
+        TransportStats {
+            in_flight_transactions: self.transactions.in_flight(),
+            evicted_transactions: self.transactions.evicted(),
+            mismatched_responses: self.transactions.mismatched(),
+            mismatched_node_ids: self.transactions.id_mismatched(),
+            decode_errors: self.recv_counters.decode_errors(),
+            invalid_queries: self.recv_counters.invalid_queries(),
+            queries: self.query_stats.snapshot(),
+        }
+    }
+
+    /// Starts counting [`TransportStats::queries`] from zero again, like
+    /// between runs of a crawl with different settings.
+    pub fn reset_query_stats(&self) {
+        self.query_stats.reset();
+    }
+
+    fn build_request(&self, transaction_id: TransactionId, query: Query) -> Envelope {
+        Envelope {
             ip: None,
             transaction_id: transaction_id.to_be_bytes().to_vec(),
-            version: None,
+            version: self.config.version.map(|version| version.to_vec().into()),
             message_type: Message::Query { query },
+            read_only: self.config.read_only,
+        }
+    }
+}
+
+impl Drop for SendTransport {
+    fn drop(&mut self) {
+        self.outbound.close();
+    }
+}
+
+/// Sends `message` to `address`, retrying as the [`SocketErrorPolicy`] of
+/// `socket` says. The socket is unlocked while waiting to retry, so other
+/// messages are sent in the meantime.
+pub(crate) async fn send_on<'a, S: SendDatagram>(
+    socket: &'a Mutex<SendSocket<S>>,
+    address: SocketAddr,
+    message: &'a Envelope,
+) -> Result<()> {
+    let mut attempts = 0;
+
+    loop {
+        let mut guard = socket.lock().await;
+        let cause = match guard.send_once(address, message).await? {
+            Ok(()) => return Ok(()),
+            Err(cause) => cause,
+        };
+
+        attempts += 1;
+        let backoff = match guard.error_policy.retry_after(attempts, &cause) {
+            Some(backoff) => backoff,
+            None => return Err(guard.send_error(address, cause)),
+        };
+
+        drop(guard);
+        if backoff > Duration::from_secs(0) {
+            Delay::new(Instant::now() + backoff).await;
+        }
+    }
+}
+
+impl<S: SendDatagram> SendSocket<S> {
+    /// Encodes `message` and sends it to `address` once. Fails when it can't
+    /// be encoded or relayed, otherwise returns what the socket returned.
+    async fn send_once<'a>(
+        &'a mut self,
+        address: SocketAddr,
+        message: &'a Envelope,
+    ) -> Result<io::Result<()>> {
+        let SendSocket {
+            socket,
+            buffer,
+            tap,
+            proxy,
+            ..
+        } = self;
+
+        buffer.clear();
+
+        // Relayed messages start with a header telling the relay where to send
+        // them.
+        let send_to = match proxy {
+            Some(proxy) => {
+                let relay = proxy.relay().ok_or(ErrorKind::ProxyAssociationLost)?;
+                proxy::write_header(&address, buffer);
+                relay
+            }
+            None => address,
+        };
+        let message_start = buffer.len();
+
+        message
+            .encode_into(buffer)
+            .map_err(|cause| ErrorKind::SendEncodingError { cause })?;
+
+        if let Err(cause) = socket.send_datagram(&buffer[..], &send_to).await {
+            return Ok(Err(cause));
+        }
+
+        if let Some(tap) = tap {
+            tap(Direction::Outbound, &buffer[message_start..], address);
+        }
+
+        Ok(Ok(()))
+    }
+
+    /// Error for a message to `address` which the socket failed to send.
+    fn send_error(&self, address: SocketAddr, cause: io::Error) -> Error {
+        match socket_errors::classify(&cause) {
+            // When relaying, it's the relay which can't be reached rather than
+            // the node.
+            SocketErrorKind::Unreachable if self.proxy.is_none() => {
+                ErrorKind::Unreachable { address, cause }.into()
+            }
+            _ => ErrorKind::SendError { cause }.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        send_on,
+        QueryOptions,
+        SendDatagram,
+        SendSocket,
+        SendTransportConfig,
+        TransportStats,
+    };
+    use crate::{
+        external_ip::ExternalIpObserver,
+        query_stats::QueryKind,
+        recv_errors::{
+            Error as RecvError,
+            ErrorKind as RecvErrorKind,
+            InvalidQueryReason,
+        },
+        send_errors::ErrorKind,
+        InboundQuery,
+        KRPCNode,
+        PortType,
+        SendTransport,
+        SocketErrorPolicy,
+    };
+    use failure::Error;
+    use futures::{
+        future::{
+            self,
+            BoxFuture,
+        },
+        StreamExt,
+        TryStreamExt,
+    };
+    use krpc_encoding::{
+        errors::ErrorKind as EncodingErrorKind,
+        Addr,
+        Envelope,
+        KRPCError,
+        Message,
+        NodeID,
+        Query,
+        Response,
+    };
+    use std::{
+        io,
+        net::{
+            self,
+            SocketAddr,
+        },
+        sync::{
+            Arc,
+            Mutex,
+        },
+        thread,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+    use tokio::{
+        net::UdpSocket,
+        prelude::FutureExt,
+        runtime::current_thread::Runtime,
+        timer::Delay,
+    };
+
+    /// Creates a transport along with a runtime which serves it while
+    /// blocking on a future.
+    fn make_transport(config: SendTransportConfig) -> Result<(SendTransport, Runtime), Error> {
+        let bind: SocketAddr = "127.0.0.1:0".parse()?;
+        let socket = UdpSocket::bind(&bind)?;
+        let (send_transport, inbound) = KRPCNode::with_config(socket, config).serve();
+
+        let mut runtime = Runtime::new()?;
+        runtime.spawn(
+            inbound
+                .map_err(|err| println!("Error in Inbound Requests: {}", err))
+                .for_each(|_| future::ready(())),
+        );
+
+        Ok((send_transport, runtime))
+    }
+
+    /// Announces with `announce_peer_auto` to a node which never responds and
+    /// returns the `(implied_port, port)` of the query it received.
+    fn auto_announce(
+        send_transport: &SendTransport,
+        runtime: &mut Runtime,
+        local_socket: &UdpSocket,
+    ) -> Result<(bool, Option<u16>), Error> {
+        let node = net::UdpSocket::bind("127.0.0.1:0")?;
+        node.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+        let _ = runtime.block_on(
+            send_transport
+                .announce_peer_auto(
+                    NodeID::random(),
+                    b"token".to_vec(),
+                    node.local_addr()?,
+                    NodeID::random(),
+                    local_socket,
+                )
+                .timeout(Duration::from_millis(50)),
+        );
+
+        let mut buf = [0u8; 1024];
+        let (size, _) = node.recv_from(&mut buf)?;
+
+        match Envelope::decode(&buf[..size])?.message_type {
+            Message::Query {
+                query:
+                    Query::AnnouncePeer {
+                        implied_port, port, ..
+                    },
+            } => Ok((implied_port, port)),
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn read_only_flag_encoded() -> Result<(), Error> {
+        let config = SendTransportConfig {
+            read_only: true,
+            ..SendTransportConfig::default()
+        };
+        let (send_transport, _runtime) = make_transport(config)?;
+
+        let envelope = send_transport.build_request(
+            0,
+            Query::Ping {
+                id: b"abcdefghij0123456789".into(),
+            },
+        );
+        let encoded = envelope.encode()?;
+
+        assert!(encoded.windows(7).any(|window| window == b"2:roi1e"));
+
+        Ok(())
+    }
+
+    /// Answers a single `find_node` query with a response carrying
+    /// `version`. Returns the address of the node and a handle resolving to
+    /// the query it received.
+    fn answer_find_node(
+        version: Option<Vec<u8>>,
+    ) -> Result<(SocketAddr, thread::JoinHandle<Vec<u8>>), Error> {
+        let node = net::UdpSocket::bind("127.0.0.1:0")?;
+        node.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let addr = node.local_addr()?;
+
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (size, from) = node.recv_from(&mut buf).unwrap();
+            let query = Envelope::decode(&buf[..size]).unwrap();
+
+            let response = Envelope {
+                ip: None,
+                transaction_id: query.transaction_id,
+                version: version.map(Into::into),
+                message_type: Message::Response {
+                    response: Response::NextHop {
+                        id: NodeID::random(),
+                        token: None,
+                        nodes: Vec::new(),
+                    },
+                },
+                read_only: false,
+            };
+            node.send_to(&response.encode().unwrap(), from).unwrap();
+
+            buf[..size].to_vec()
+        });
+
+        Ok((addr, handle))
+    }
+
+    fn has_default_version(encoded: &[u8]) -> bool {
+        encoded
+            .windows(9)
+            .any(|window| window == b"1:v4:RC\x00\x01")
+    }
+
+    #[test]
+    fn version_encoded() -> Result<(), Error> {
+        let (send_transport, _runtime) = make_transport(SendTransportConfig::default())?;
+        let envelope = send_transport.build_request(
+            0,
+            Query::Ping {
+                id: b"abcdefghij0123456789".into(),
+            },
+        );
+
+        let mut encoded = Vec::new();
+        envelope.encode_into(&mut encoded)?;
+        assert!(has_default_version(&encoded));
+        assert_eq!(Envelope::decode(&encoded)?, envelope);
+
+        let config = SendTransportConfig {
+            version: None,
+            ..SendTransportConfig::default()
+        };
+        let (send_transport, _runtime) = make_transport(config)?;
+        let envelope = send_transport.build_request(
+            0,
+            Query::Ping {
+                id: b"abcdefghij0123456789".into(),
+            },
+        );
+
+        assert_eq!(envelope.version, None);
+        assert!(!envelope.encode()?.windows(3).any(|window| window == b"1:v"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn response_version() -> Result<(), Error> {
+        let config = SendTransportConfig {
+            query_timeout: Some(Duration::from_secs(1)),
+            ..SendTransportConfig::default()
+        };
+        let (send_transport, mut runtime) = make_transport(config)?;
+
+        let versions = vec![
+            None,
+            Some(b"LT\x01\x02".to_vec()),
+            Some(b"\xff\xfe longer than four bytes".to_vec()),
+        ];
+
+        for version in versions {
+            let (addr, node) = answer_find_node(version.clone())?;
+            let response = runtime.block_on(send_transport.find_node(
+                NodeID::random(),
+                addr,
+                NodeID::random(),
+            ))?;
+
+            assert_eq!(response.version(), version.as_ref().map(Vec::as_slice));
+            assert!(has_default_version(&node.join().unwrap()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn spoofed_response_ignored() -> Result<(), Error> {
+        let (send_transport, mut runtime) = make_transport(SendTransportConfig::default())?;
+        let node = net::UdpSocket::bind("127.0.0.1:0")?;
+        node.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let spoofer = net::UdpSocket::bind("127.0.0.1:0")?;
+        let addr = node.local_addr()?;
+        let id = NodeID::random();
+        let node_id = id.clone();
+
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (size, from) = node.recv_from(&mut buf).unwrap();
+            let query = Envelope::decode(&buf[..size]).unwrap();
+            let response = |id| Envelope {
+                ip: None,
+                transaction_id: query.transaction_id.clone(),
+                version: None,
+                message_type: Message::Response {
+                    response: Response::OnlyID { id },
+                },
+                read_only: false,
+            };
+
+            // Someone who guessed the transaction id answers first.
+            spoofer
+                .send_to(&response(NodeID::random()).encode().unwrap(), from)
+                .unwrap();
+            thread::sleep(Duration::from_millis(20));
+            node.send_to(&response(node_id).encode().unwrap(), from)
+                .unwrap();
+        });
+
+        let options = QueryOptions {
+            expected_id: Some(id.clone()),
+            ..QueryOptions::default()
+        };
+        let response =
+            runtime.block_on(send_transport.ping_with_options(NodeID::random(), addr, options))?;
+        handle.join().unwrap();
+
+        assert_eq!(response, id);
+        assert_eq!(send_transport.stats().mismatched_node_ids, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_options_timeout() -> Result<(), Error> {
+        let (send_transport, mut runtime) = make_transport(SendTransportConfig::default())?;
+        let node = net::UdpSocket::bind("127.0.0.1:0")?;
+        node.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let addr = node.local_addr()?;
+
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (size, from) = node.recv_from(&mut buf).unwrap();
+            let query = Envelope::decode(&buf[..size]).unwrap();
+            thread::sleep(Duration::from_millis(10));
+
+            let response = Envelope {
+                ip: None,
+                transaction_id: query.transaction_id,
+                version: None,
+                message_type: Message::Response {
+                    response: Response::OnlyID {
+                        id: NodeID::random(),
+                    },
+                },
+                read_only: false,
+            };
+            node.send_to(&response.encode().unwrap(), from).unwrap();
+        });
+
+        let options = QueryOptions {
+            timeout: Duration::from_millis(1),
+            ..QueryOptions::default()
+        };
+        let result =
+            runtime.block_on(send_transport.ping_with_options(NodeID::random(), addr, options));
+        handle.join().unwrap();
+
+        match result {
+            Err(err) => match err.kind() {
+                ErrorKind::TransactionTimeout { .. } => {}
+                kind => panic!("unexpected error {}", kind),
+            },
+            Ok(_) => panic!("response arrived after timeout"),
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn send_fills_in_version() -> Result<(), Error> {
+        let (send_transport, mut runtime) = make_transport(SendTransportConfig::default())?;
+        let node = net::UdpSocket::bind("127.0.0.1:0")?;
+        node.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+        let response = |version: Option<Vec<u8>>| Envelope {
+            ip: None,
+            transaction_id: b"aa".to_vec(),
+            version: version.map(Into::into),
+            message_type: Message::Response {
+                response: Response::OnlyID {
+                    id: NodeID::random(),
+                },
+            },
             read_only: false,
         };
 
-        self.send(address, envelope).await?;
+        let mut buf = [0u8; 1024];
+
+        runtime.block_on(send_transport.send(node.local_addr()?, response(None)))?;
+        let (size, _) = node.recv_from(&mut buf)?;
+        assert!(has_default_version(&buf[..size]));
 
-        Ok(ResponseFuture::wait_for_tx(transaction_id, self.transactions.clone()).await?)
+        runtime
+            .block_on(send_transport.send(node.local_addr()?, response(Some(b"XX".to_vec()))))?;
+        let (size, _) = node.recv_from(&mut buf)?;
+        assert_eq!(
+            Envelope::decode(&buf[..size])?.version,
+            Some(b"XX".to_vec().into())
+        );
+
+        Ok(())
     }
 
-    fn random_transaction_id() -> TransactionId {
-        rand::random::<TransactionId>()
+    #[test]
+    fn not_read_only_by_default() -> Result<(), Error> {
+        let (send_transport, _runtime) = make_transport(SendTransportConfig::default())?;
+
+        let envelope = send_transport.build_request(
+            0,
+            Query::Ping {
+                id: b"abcdefghij0123456789".into(),
+            },
+        );
+
+        assert!(!envelope.read_only);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_cannot_announce() -> Result<(), Error> {
+        let config = SendTransportConfig {
+            read_only: true,
+            ..SendTransportConfig::default()
+        };
+        let (send_transport, mut runtime) = make_transport(config)?;
+
+        let result = runtime.block_on(send_transport.announce_peer(
+            NodeID::random(),
+            b"token".to_vec(),
+            "127.0.0.1:6881".parse()?,
+            NodeID::random(),
+            PortType::Implied,
+        ));
+
+        match result {
+            Err(err) => match err.kind() {
+                ErrorKind::ReadOnlyNode => {}
+                kind => panic!("unexpected error {}", kind),
+            },
+            Ok(_) => panic!("read only node announced"),
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn in_flight_transactions_capped() -> Result<(), Error> {
+        const MAX_TRANSACTIONS: usize = 10;
+        const FIRST_TIMEOUT: Duration = Duration::from_millis(100);
+
+        let config = SendTransportConfig {
+            max_transactions: MAX_TRANSACTIONS,
+            ..SendTransportConfig::default()
+        };
+        let (send_transport, mut runtime) = make_transport(config)?;
+
+        // Node which never responds. Records when each query arrives.
+        let silent = net::UdpSocket::bind("127.0.0.1:0")?;
+        let silent_addr = silent.local_addr()?;
+        silent.set_read_timeout(Some(Duration::from_millis(500)))?;
+        let start = Instant::now();
+        let receiver = thread::spawn(move || {
+            let mut arrivals = Vec::new();
+            let mut buf = [0u8; 1024];
+            while silent.recv_from(&mut buf).is_ok() {
+                arrivals.push(start.elapsed());
+            }
+
+            arrivals
+        });
+
+        let id = NodeID::random();
+        let first = (0..MAX_TRANSACTIONS).map(|_| {
+            send_transport
+                .ping(id.clone(), silent_addr)
+                .timeout(FIRST_TIMEOUT)
+        });
+        let extra = (0..100).map(|_| {
+            send_transport
+                .ping(id.clone(), silent_addr)
+                .timeout(FIRST_TIMEOUT * 3)
+        });
+
+        let (first, extra) = runtime.block_on(future::join(
+            future::join_all(first),
+            future::join_all(extra),
+        ));
+        assert!(first
+            .iter()
+            .chain(extra.iter())
+            .all(|result| result.is_err()));
+        assert_eq!(send_transport.stats().in_flight_transactions, 0);
+
+        let arrivals = receiver.join().unwrap();
+        let early = arrivals
+            .iter()
+            .filter(|arrival| **arrival < FIRST_TIMEOUT)
+            .count();
+
+        // Extra requests waited for the first ones to time out before sending.
+        assert_eq!(early, MAX_TRANSACTIONS);
+        assert!(arrivals.len() > MAX_TRANSACTIONS);
+        assert!(arrivals.len() < MAX_TRANSACTIONS + 100);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fail_when_full() -> Result<(), Error> {
+        let config = SendTransportConfig {
+            max_transactions: 0,
+            fail_when_full: true,
+            ..SendTransportConfig::default()
+        };
+        let (send_transport, mut runtime) = make_transport(config)?;
+
+        let result =
+            runtime.block_on(send_transport.ping(NodeID::random(), "127.0.0.1:6881".parse()?));
+
+        match result {
+            Err(err) => match err.kind() {
+                ErrorKind::TooManyTransactions { max: 0 } => {}
+                kind => panic!("unexpected error {}", kind),
+            },
+            Ok(_) => panic!("request sent while full"),
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn announce_auto_explicit_port() -> Result<(), Error> {
+        let (send_transport, mut runtime) = make_transport(SendTransportConfig::default())?;
+        let peer_socket = UdpSocket::bind(&"127.0.0.1:0".parse()?)?;
+        let peer_port = peer_socket.local_addr()?.port();
+
+        assert_eq!(
+            auto_announce(&send_transport, &mut runtime, &peer_socket)?,
+            (false, Some(peer_port))
+        );
+
+        Ok(())
+    }
+
+    /// Uses a second loopback address to bind another socket on the DHT port.
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn announce_auto_implied_port() -> Result<(), Error> {
+        let (send_transport, mut runtime) = make_transport(SendTransportConfig::default())?;
+        let dht_port = send_transport.local_addr.unwrap().port();
+        let peer_socket = UdpSocket::bind(&SocketAddr::from(([127, 0, 0, 2], dht_port)))?;
+
+        let (implied_port, _) = auto_announce(&send_transport, &mut runtime, &peer_socket)?;
+        assert!(implied_port);
+
+        Ok(())
+    }
+
+    #[test]
+    fn queries_rate_limited() -> Result<(), Error> {
+        let config = SendTransportConfig {
+            max_queries_per_second: Some(20),
+            ..SendTransportConfig::default()
+        };
+        let (send_transport, mut runtime) = make_transport(config)?;
+
+        let silent = net::UdpSocket::bind("127.0.0.1:0")?;
+        let silent_addr = silent.local_addr()?;
+        silent.set_read_timeout(Some(Duration::from_millis(300)))?;
+        let receiver = thread::spawn(move || {
+            let mut arrivals = Vec::new();
+            let mut buf = [0u8; 1024];
+            while silent.recv_from(&mut buf).is_ok() {
+                arrivals.push(Instant::now());
+            }
+
+            arrivals
+        });
+
+        let id = NodeID::random();
+        let pings = (0..3).map(|_| {
+            send_transport
+                .ping(id.clone(), silent_addr)
+                .timeout(Duration::from_millis(500))
+        });
+        runtime.block_on(future::join_all(pings));
+
+        let arrivals = receiver.join().unwrap();
+        assert_eq!(arrivals.len(), 3);
+        assert!(arrivals[2] - arrivals[0] >= Duration::from_millis(90));
+
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_queries_unique_transaction_ids() -> Result<(), Error> {
+        let (send_transport, mut runtime) = make_transport(SendTransportConfig::default())?;
+
+        // Node which never responds. Records the transaction id of each query.
+        let silent = net::UdpSocket::bind("127.0.0.1:0")?;
+        let silent_addr = silent.local_addr()?;
+        silent.set_read_timeout(Some(Duration::from_millis(300)))?;
+        let receiver = thread::spawn(move || {
+            let mut transaction_ids = Vec::new();
+            let mut buf = [0u8; 1024];
+            while let Ok((size, _)) = silent.recv_from(&mut buf) {
+                transaction_ids.push(Envelope::decode(&buf[..size]).unwrap().transaction_id);
+            }
+
+            transaction_ids
+        });
+
+        let id = NodeID::random();
+        runtime.block_on(future::join(
+            send_transport
+                .ping(id.clone(), silent_addr)
+                .timeout(Duration::from_millis(100)),
+            send_transport
+                .ping(id, silent_addr)
+                .timeout(Duration::from_millis(100)),
+        ));
+
+        let transaction_ids = receiver.join().unwrap();
+        assert_eq!(transaction_ids.len(), 2);
+        assert_ne!(transaction_ids[0], transaction_ids[1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_messages_counted() -> Result<(), Error> {
+        let bind: SocketAddr = "127.0.0.1:0".parse()?;
+        let socket = UdpSocket::bind(&bind)?;
+        let local_addr = socket.local_addr()?;
+        let (send_transport, inbound) = KRPCNode::new(socket).serve();
+
+        let peer = net::UdpSocket::bind("127.0.0.1:0")?;
+        peer.send_to(b"d1:y1:qe", local_addr)?;
+
+        let mut runtime = Runtime::new()?;
+        let mut inbound = Box::pin(inbound.into_stream());
+        let err = runtime
+            .block_on(inbound.next().timeout(Duration::from_secs(1)))?
+            .expect("stream ended")
+            .unwrap_err();
+
+        match err.kind() {
+            RecvErrorKind::ParseInboundMessageError { from, len, .. } => {
+                assert_eq!(*from, peer.local_addr()?);
+                assert_eq!(*len, 8);
+            }
+            kind => panic!("unexpected error {}", kind),
+        };
+        assert_eq!(send_transport.stats().decode_errors, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_stats_recorded() -> Result<(), Error> {
+        let (send_transport, mut runtime) = make_transport(SendTransportConfig::default())?;
+        let silent = net::UdpSocket::bind("127.0.0.1:0")?;
+        let options = QueryOptions {
+            timeout: Duration::from_millis(50),
+            ..QueryOptions::default()
+        };
+
+        for _ in 0..2 {
+            let result = runtime.block_on(send_transport.ping_with_options(
+                NodeID::random(),
+                silent.local_addr()?,
+                options.clone(),
+            ));
+            assert!(result.is_err());
+        }
+
+        let stats = send_transport.stats();
+        let ping = &stats.queries[&QueryKind::Ping];
+        assert_eq!((ping.sent, ping.timed_out, ping.responded), (2, 2, 0));
+        assert_eq!(ping.latency.count(), 0);
+        assert_eq!(stats.queries[&QueryKind::FindNode].sent, 0);
+
+        send_transport.reset_query_stats();
+        assert_eq!(send_transport.stats().queries[&QueryKind::Ping].sent, 0);
+
+        Ok(())
+    }
+
+    /// Sends `message` to a node with `config` and returns what its inbound
+    /// stream yields first.
+    fn receive_one(
+        config: SendTransportConfig,
+        message: &[u8],
+    ) -> Result<std::result::Result<(InboundQuery, SocketAddr), RecvError>, Error> {
+        let bind: SocketAddr = "127.0.0.1:0".parse()?;
+        let socket = UdpSocket::bind(&bind)?;
+        let local_addr = socket.local_addr()?;
+        let (_send_transport, inbound) = KRPCNode::with_config(socket, config).serve();
+
+        let peer = net::UdpSocket::bind("127.0.0.1:0")?;
+        peer.send_to(message, local_addr)?;
+
+        let mut runtime = Runtime::new()?;
+        let mut inbound = Box::pin(inbound.into_stream());
+
+        Ok(runtime
+            .block_on(inbound.next().timeout(Duration::from_secs(1)))?
+            .expect("stream ended"))
+    }
+
+    #[test]
+    fn non_canonical_queries_rejected() -> Result<(), Error> {
+        // A ping with `q` after `t`.
+        let unsorted = b"d1:ad2:id20:abcdefghij0123456789e1:t2:aa1:q4:ping1:y1:qe";
+
+        let err = receive_one(SendTransportConfig::default(), unsorted)?.unwrap_err();
+        match err.kind() {
+            RecvErrorKind::ParseInboundMessageError { cause, .. } => match cause.kind() {
+                EncodingErrorKind::NonCanonicalEncoding { .. } => {}
+                kind => panic!("unexpected error {}", kind),
+            },
+            kind => panic!("unexpected error {}", kind),
+        };
+
+        let config = SendTransportConfig {
+            strict_query_decoding: false,
+            ..SendTransportConfig::default()
+        };
+        let (query, _) = receive_one(config, unsorted)??;
+        assert_eq!(query.transaction_id, b"aa".to_vec());
+
+        Ok(())
+    }
+
+    /// Sends a get_peers with a 19 byte info-hash, then an unknown query from
+    /// another address, to a node with `config` and returns its replies
+    /// along with its stats.
+    fn send_invalid_queries(
+        config: SendTransportConfig,
+    ) -> Result<(Vec<Envelope>, TransportStats), Error> {
+        let bind: SocketAddr = "127.0.0.1:0".parse()?;
+        let socket = UdpSocket::bind(&bind)?;
+        let local_addr = socket.local_addr()?;
+        let (send_transport, inbound) = KRPCNode::with_config(socket, config).serve();
+        let mut inbound = Box::pin(inbound.into_stream());
+
+        let short_info_hash = b"d1:ad2:id20:abcdefghij01234567899:info_hash19:mnopqrstuvwxyz12345e1:q9:get_peers1:t2:aa1:y1:qe";
+        let unknown = b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:bb1:y1:qe";
+
+        let mut runtime = Runtime::new()?;
+        let mut replies = Vec::new();
+        for message in &[&short_info_hash[..], &unknown[..]] {
+            let peer = net::UdpSocket::bind("127.0.0.1:0")?;
+            peer.set_read_timeout(Some(Duration::from_millis(200)))?;
+            peer.send_to(message, local_addr)?;
+            assert!(runtime
+                .block_on(inbound.next().timeout(Duration::from_secs(1)))?
+                .expect("stream ended")
+                .is_err());
+
+            let mut buf = [0u8; 1024];
+            if let Ok((size, _)) = peer.recv_from(&mut buf) {
+                replies.push(Envelope::decode(&buf[..size])?);
+            }
+        }
+
+        Ok((replies, send_transport.stats()))
+    }
+
+    #[test]
+    fn invalid_queries_answered() -> Result<(), Error> {
+        let (replies, stats) = send_invalid_queries(SendTransportConfig::default())?;
+        let errors: Vec<(Vec<u8>, KRPCError)> = replies
+            .into_iter()
+            .map(|reply| match reply.message_type {
+                Message::Error { error } => (reply.transaction_id, error),
+                message => panic!("unexpected message {:?}", message),
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (b"aa".to_vec(), KRPCError::new(203, "Invalid arguments")),
+                (b"bb".to_vec(), KRPCError::new(204, "Method Unknown")),
+            ]
+        );
+        assert_eq!(
+            stats.invalid_queries[&InvalidQueryReason::InvalidArguments],
+            1
+        );
+        assert_eq!(stats.invalid_queries[&InvalidQueryReason::UnknownMethod], 1);
+        assert_eq!(stats.decode_errors, 2);
+
+        // Read-only nodes only count them.
+        let config = SendTransportConfig {
+            read_only: true,
+            ..SendTransportConfig::default()
+        };
+        let (replies, stats) = send_invalid_queries(config)?;
+        assert!(replies.is_empty());
+        assert_eq!(
+            stats.invalid_queries[&InvalidQueryReason::InvalidArguments],
+            1
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn external_ip_votes() -> Result<(), Error> {
+        let bind: SocketAddr = "127.0.0.1:0".parse()?;
+        let socket = UdpSocket::bind(&bind)?;
+        let local_addr = socket.local_addr()?;
+
+        let observer = Arc::new(Mutex::new(ExternalIpObserver::new(2)));
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let (_send_transport, inbound) = {
+            let changes = changes.clone();
+            KRPCNode::new(socket)
+                .with_external_ip_observer(observer.clone(), move |ip| {
+                    changes.lock().unwrap().push(ip)
+                })
+                .serve()
+        };
+        let mut inbound = Box::pin(inbound.into_stream());
+        let mut runtime = Runtime::new()?;
+
+        // Nodes in the same /24 subnet only count once, so the votes come
+        // from loopback addresses in different ones.
+        let ip: Addr = "124.31.75.21:6881".parse()?;
+        for (subnet, transaction_id) in [b"aa", b"bb", b"cc"].iter().enumerate() {
+            let ping = Envelope {
+                ip: Some(ip),
+                transaction_id: transaction_id.to_vec(),
+                version: None,
+                message_type: Message::Query {
+                    query: Query::Ping {
+                        id: NodeID::random(),
+                    },
+                },
+                read_only: false,
+            };
+            let peer = net::UdpSocket::bind((net::Ipv4Addr::new(127, 0, subnet as u8, 1), 0))?;
+            peer.send_to(&ping.encode()?, local_addr)?;
+
+            runtime
+                .block_on(inbound.next().timeout(Duration::from_secs(1)))?
+                .expect("stream ended")?;
+        }
+
+        assert_eq!(observer.lock().unwrap().consensus(), Some(ip));
+        assert_eq!(*changes.lock().unwrap(), vec![ip]);
+
+        Ok(())
+    }
+
+    /// Fails the first `failures` sends with `WouldBlock`.
+    struct FlakySocket {
+        failures: usize,
+        attempts: usize,
+    }
+
+    impl SendDatagram for FlakySocket {
+        fn send_datagram<'a>(
+            &'a mut self,
+            buffer: &'a [u8],
+            _target: &'a SocketAddr,
+        ) -> BoxFuture<'a, io::Result<usize>> {
+            self.attempts += 1;
+            let result = if self.attempts <= self.failures {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"))
+            } else {
+                Ok(buffer.len())
+            };
+
+            Box::pin(future::ready(result))
+        }
+    }
+
+    /// Socket failing its first `failures` sends and retrying as `policy`
+    /// says.
+    fn flaky_socket(
+        failures: usize,
+        policy: SocketErrorPolicy,
+    ) -> futures::lock::Mutex<SendSocket<FlakySocket>> {
+        futures::lock::Mutex::new(SendSocket {
+            socket: FlakySocket {
+                failures,
+                attempts: 0,
+            },
+            buffer: Vec::new(),
+            tap: None,
+            proxy: None,
+            error_policy: policy,
+        })
+    }
+
+    fn ping() -> Envelope {
+        Envelope::query(
+            b"aa".to_vec(),
+            Query::Ping {
+                id: NodeID::random(),
+            },
+        )
+    }
+
+    #[test]
+    fn transient_errors_retried() -> Result<(), Error> {
+        let mut runtime = Runtime::new()?;
+        let target: SocketAddr = "127.0.0.1:6881".parse()?;
+        let backoff = Duration::from_millis(20);
+        let socket = flaky_socket(
+            2,
+            SocketErrorPolicy::RetryOnTransient {
+                max_retries: 2,
+                backoff,
+            },
+        );
+
+        let started = Instant::now();
+        runtime.block_on(send_on(&socket, target, &ping()))?;
+
+        assert_eq!(runtime.block_on(socket.lock()).socket.attempts, 3);
+        assert!(started.elapsed() >= backoff * 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn transient_errors_propagated() -> Result<(), Error> {
+        let mut runtime = Runtime::new()?;
+        let target: SocketAddr = "127.0.0.1:6881".parse()?;
+        let policy = SocketErrorPolicy::RetryOnTransient {
+            max_retries: 1,
+            backoff: Duration::from_millis(1),
+        };
+
+        for (policy, attempts) in &[(policy, 2), (SocketErrorPolicy::Fail, 1)] {
+            let socket = flaky_socket(2, *policy);
+            let err = runtime
+                .block_on(send_on(&socket, target, &ping()))
+                .unwrap_err();
+
+            match err.kind() {
+                ErrorKind::SendError { cause } => {
+                    assert_eq!(cause.kind(), io::ErrorKind::WouldBlock)
+                }
+                kind => panic!("unexpected error {}", kind),
+            }
+            assert_eq!(runtime.block_on(socket.lock()).socket.attempts, *attempts);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn socket_unlocked_during_backoff() -> Result<(), Error> {
+        let mut runtime = Runtime::new()?;
+        let target: SocketAddr = "127.0.0.1:6881".parse()?;
+        let socket = flaky_socket(
+            1,
+            SocketErrorPolicy::RetryOnTransient {
+                max_retries: 1,
+                backoff: Duration::from_millis(200),
+            },
+        );
+
+        // The first message fails and waits to be sent again, the second is
+        // sent in the meantime.
+        let retried = async {
+            send_on(&socket, target, &ping())
+                .await
+                .map(|_| Instant::now())
+        };
+        let sent = async {
+            Delay::new(Instant::now() + Duration::from_millis(20)).await;
+            send_on(&socket, target, &ping())
+                .await
+                .map(|_| Instant::now())
+        };
+        let (retried, sent) = runtime.block_on(future::join(retried, sent));
+
+        assert!(sent? < retried?);
+        assert_eq!(runtime.block_on(socket.lock()).socket.attempts, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn socket_error_policy_set() -> Result<(), Error> {
+        let (send_transport, mut runtime) = make_transport(SendTransportConfig {
+            socket_error_policy: SocketErrorPolicy::RetryOnTransient {
+                max_retries: 5,
+                backoff: Duration::from_millis(10),
+            },
+            ..SendTransportConfig::default()
+        })?;
+
+        let peer = net::UdpSocket::bind("127.0.0.1:0")?;
+        peer.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let ping = Envelope {
+            ip: None,
+            transaction_id: b"aa".to_vec(),
+            version: None,
+            message_type: Message::Query {
+                query: Query::Ping {
+                    id: NodeID::random(),
+                },
+            },
+            read_only: false,
+        };
+        runtime.block_on(send_transport.send(peer.local_addr()?, ping))?;
+
+        let mut buf = [0u8; 1024];
+        let (len, _) = peer.recv_from(&mut buf)?;
+        assert_eq!(
+            Envelope::decode(&buf[..len])?.transaction_id,
+            b"aa".to_vec()
+        );
+
+        Ok(())
     }
 }
@@ -0,0 +1,179 @@
+//! Classification of errors returned by the UDP socket.
+
+use std::{
+    io,
+    time::Duration,
+};
+
+/// Number of times sending a message is retried after a transient socket
+/// error unless configured otherwise.
+pub const DEFAULT_MAX_TRANSIENT_RETRIES: usize = 3;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SocketErrorKind {
+    /// The operation may succeed if retried.
+    Transient,
+
+    /// The remote host or port can't be reached. Usually caused by an ICMP
+    /// destination unreachable message from an earlier datagram.
+    Unreachable,
+
+    /// The socket can't be used anymore.
+    Fatal,
+
+    /// Any other error. The socket is still usable.
+    Other,
+}
+
+/// What to do when sending a message fails, see
+/// [`SendTransportConfig::socket_error_policy`].
+///
+/// [`SendTransportConfig::socket_error_policy`]: crate::SendTransportConfig::socket_error_policy
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SocketErrorPolicy {
+    /// Fails on the first error.
+    Fail,
+
+    /// Sends again after a [`SocketErrorKind::Transient`] error, up to
+    /// `max_retries` times, waiting `backoff` before each retry. Some
+    /// platforms return `EAGAIN` from a send when their buffers are full.
+    RetryOnTransient {
+        max_retries: usize,
+        backoff: Duration,
+    },
+}
+
+impl Default for SocketErrorPolicy {
+    /// Retries [`DEFAULT_MAX_TRANSIENT_RETRIES`] times right away.
+    fn default() -> SocketErrorPolicy {
+        SocketErrorPolicy::RetryOnTransient {
+            max_retries: DEFAULT_MAX_TRANSIENT_RETRIES,
+            backoff: Duration::from_secs(0),
+        }
+    }
+}
+
+impl SocketErrorPolicy {
+    /// How long to wait before sending again after the `attempts`th attempt
+    /// failed with `err`, or `None` to give up.
+    pub(crate) fn retry_after(&self, attempts: usize, err: &io::Error) -> Option<Duration> {
+        match self {
+            SocketErrorPolicy::RetryOnTransient {
+                max_retries,
+                backoff,
+            } if attempts <= *max_retries && classify(err) == SocketErrorKind::Transient => {
+                Some(*backoff)
+            }
+            _ => None,
+        }
+    }
+}
+
+pub fn classify(err: &io::Error) -> SocketErrorKind {
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => {
+            return SocketErrorKind::Transient;
+        }
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => {
+            return SocketErrorKind::Unreachable;
+        }
+        _ => {}
+    };
+
+    classify_os_error(err.raw_os_error())
+}
+
+#[cfg(unix)]
+fn classify_os_error(code: Option<i32>) -> SocketErrorKind {
+    match code {
+        Some(libc::ENOBUFS) | Some(libc::EAGAIN) => SocketErrorKind::Transient,
+        Some(libc::EHOSTUNREACH) | Some(libc::ENETUNREACH) => SocketErrorKind::Unreachable,
+        Some(libc::EBADF) | Some(libc::ENOTSOCK) => SocketErrorKind::Fatal,
+        _ => SocketErrorKind::Other,
+    }
+}
+
+#[cfg(not(unix))]
+fn classify_os_error(_code: Option<i32>) -> SocketErrorKind {
+    SocketErrorKind::Other
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        classify,
+        SocketErrorKind,
+        SocketErrorPolicy,
+    };
+    use std::{
+        io,
+        time::Duration,
+    };
+
+    #[test]
+    fn transient() {
+        let err = io::Error::new(io::ErrorKind::WouldBlock, "would block");
+        assert_eq!(classify(&err), SocketErrorKind::Transient);
+
+        let err = io::Error::new(io::ErrorKind::Interrupted, "interrupted");
+        assert_eq!(classify(&err), SocketErrorKind::Transient);
+    }
+
+    #[test]
+    fn refused_is_unreachable() {
+        let err = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        assert_eq!(classify(&err), SocketErrorKind::Unreachable);
+    }
+
+    #[test]
+    fn other() {
+        let err = io::Error::new(io::ErrorKind::Other, "something");
+        assert_eq!(classify(&err), SocketErrorKind::Other);
+    }
+
+    #[test]
+    fn policy_retries_transient() {
+        let would_block = io::Error::new(io::ErrorKind::WouldBlock, "would block");
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        let backoff = Duration::from_millis(10);
+        let policy = SocketErrorPolicy::RetryOnTransient {
+            max_retries: 2,
+            backoff,
+        };
+
+        assert_eq!(policy.retry_after(1, &would_block), Some(backoff));
+        assert_eq!(policy.retry_after(2, &would_block), Some(backoff));
+        assert_eq!(policy.retry_after(3, &would_block), None);
+        assert_eq!(policy.retry_after(1, &refused), None);
+        assert_eq!(SocketErrorPolicy::Fail.retry_after(1, &would_block), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn os_errors() {
+        let classified = |code| classify(&io::Error::from_raw_os_error(code));
+
+        assert_eq!(classified(libc::ENOBUFS), SocketErrorKind::Transient);
+        assert_eq!(classified(libc::EHOSTUNREACH), SocketErrorKind::Unreachable);
+        assert_eq!(classified(libc::EBADF), SocketErrorKind::Fatal);
+    }
+
+    /// Linux reports ICMP port unreachable messages on connected UDP sockets.
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn icmp_port_unreachable() -> Result<(), io::Error> {
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let closed_addr = closed.local_addr()?;
+        drop(closed);
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        socket.connect(closed_addr)?;
+        socket.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
+        socket.send(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe")?;
+
+        let err = socket.recv(&mut [0u8; 64]).unwrap_err();
+        assert_eq!(classify(&err), SocketErrorKind::Unreachable);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+use failure::{
+    Backtrace,
+    Context,
+    Fail,
+};
+use std::{
+    fmt,
+    io,
+};
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "failed to send binding request")]
+    FailedToSend {
+        #[fail(cause)]
+        cause: io::Error,
+    },
+
+    #[fail(display = "failed to receive binding response")]
+    FailedToReceive {
+        #[fail(cause)]
+        cause: io::Error,
+    },
+
+    #[fail(display = "STUN server rejected binding request")]
+    BindingFailed,
+
+    #[fail(display = "Malformed binding response")]
+    InvalidResponse,
+
+    #[fail(display = "Binding response didn't include XOR-MAPPED-ADDRESS")]
+    MissingMappedAddress,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub struct Error {
+    inner: Context<ErrorKind>,
+}
+
+impl Fail for Error {
+    fn cause(&self) -> Option<&dyn Fail> {
+        self.inner.cause()
+    }
+
+    fn backtrace(&self) -> Option<&Backtrace> {
+        self.inner.backtrace()
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        self.inner.get_context()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error {
+            inner: Context::new(kind),
+        }
+    }
+}
+
+impl From<Context<ErrorKind>> for Error {
+    fn from(inner: Context<ErrorKind>) -> Error {
+        Error { inner }
+    }
+}
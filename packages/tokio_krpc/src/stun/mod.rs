@@ -0,0 +1,378 @@
+//! External IP discovery using a minimal STUN binding request. BEP-0042
+//! derives node ids from the external IP, which isn't known behind a NAT.
+//!
+//! Only the parts of RFC-5389 needed to learn the mapped address are
+//! implemented.
+
+pub mod errors;
+
+use self::errors::{
+    ErrorKind,
+    Result,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+    ReadBytesExt,
+};
+use failure::ResultExt;
+use rand::Rng;
+use std::{
+    io::Read,
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        Ipv6Addr,
+        SocketAddr,
+    },
+};
+use tokio::net::UdpSocket;
+
+const HEADER_LEN: usize = 20;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS_RESPONSE: u16 = 0x0101;
+const BINDING_ERROR_RESPONSE: u16 = 0x0111;
+
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+type TransactionId = [u8; 12];
+
+pub struct ExternalIpDiscovery;
+
+impl ExternalIpDiscovery {
+    /// Asks `stun_server` which address `local_socket` appears to send from.
+    ///
+    /// Datagrams which aren't a response to the binding request are ignored,
+    /// so the future doesn't resolve if the response is lost. Callers should
+    /// add a timeout.
+    pub async fn discover<'a>(
+        local_socket: &'a mut UdpSocket,
+        stun_server: SocketAddr,
+    ) -> Result<IpAddr> {
+        let mut transaction_id: TransactionId = [0u8; 12];
+        rand::thread_rng().fill(&mut transaction_id[..]);
+
+        let request = binding_request(&transaction_id);
+        local_socket
+            .send_to(&request[..], &stun_server)
+            .await
+            .map_err(|cause| ErrorKind::FailedToSend { cause })?;
+
+        let mut recv_buffer = [0u8; 576];
+        loop {
+            let (size, from) = local_socket
+                .recv_from(&mut recv_buffer[..])
+                .await
+                .map_err(|cause| ErrorKind::FailedToReceive { cause })?;
+
+            if from != stun_server {
+                continue;
+            }
+
+            if let Some(addr) = parse_binding_response(&transaction_id, &recv_buffer[..size])? {
+                return Ok(addr.ip());
+            }
+        }
+    }
+}
+
+fn binding_request(transaction_id: &TransactionId) -> [u8; HEADER_LEN] {
+    let mut request = [0u8; HEADER_LEN];
+    NetworkEndian::write_u16(&mut request[0..2], BINDING_REQUEST);
+    NetworkEndian::write_u32(&mut request[4..8], MAGIC_COOKIE);
+    request[8..].copy_from_slice(transaction_id);
+
+    request
+}
+
+/// Extracts the mapped address from a binding response. Returns `None` if
+/// `bytes` isn't a response to the request with `transaction_id`.
+fn parse_binding_response(
+    transaction_id: &TransactionId,
+    bytes: &[u8],
+) -> Result<Option<SocketAddr>> {
+    if bytes.len() < HEADER_LEN
+        || NetworkEndian::read_u32(&bytes[4..8]) != MAGIC_COOKIE
+        || &bytes[8..HEADER_LEN] != transaction_id
+    {
+        return Ok(None);
+    }
+
+    match NetworkEndian::read_u16(&bytes[0..2]) {
+        BINDING_SUCCESS_RESPONSE => {}
+        BINDING_ERROR_RESPONSE => Err(ErrorKind::BindingFailed)?,
+        _ => return Ok(None),
+    };
+
+    let length = NetworkEndian::read_u16(&bytes[2..4]) as usize;
+    let body = &bytes[HEADER_LEN..];
+    if body.len() < length {
+        Err(ErrorKind::InvalidResponse)?;
+    }
+
+    let mut attributes = &body[..length];
+    while !attributes.is_empty() {
+        let attribute_type = attributes
+            .read_u16::<NetworkEndian>()
+            .context(ErrorKind::InvalidResponse)?;
+        let attribute_len = attributes
+            .read_u16::<NetworkEndian>()
+            .context(ErrorKind::InvalidResponse)? as usize;
+
+        if attributes.len() < attribute_len {
+            Err(ErrorKind::InvalidResponse)?;
+        }
+
+        if attribute_type == XOR_MAPPED_ADDRESS {
+            let addr = parse_xor_mapped_address(transaction_id, &attributes[..attribute_len])?;
+            return Ok(Some(addr));
+        }
+
+        // Attribute values are padded to a multiple of four bytes.
+        let padded_len = (attribute_len + 3) & !3;
+        attributes = &attributes[padded_len.min(attributes.len())..];
+    }
+
+    Err(ErrorKind::MissingMappedAddress.into())
+}
+
+fn parse_xor_mapped_address(
+    transaction_id: &TransactionId,
+    mut value: &[u8],
+) -> Result<SocketAddr> {
+    let _reserved = value.read_u8().context(ErrorKind::InvalidResponse)?;
+    let family = value.read_u8().context(ErrorKind::InvalidResponse)?;
+    let port = value
+        .read_u16::<NetworkEndian>()
+        .context(ErrorKind::InvalidResponse)?
+        ^ (MAGIC_COOKIE >> 16) as u16;
+
+    let ip = match family {
+        FAMILY_IPV4 => {
+            let ip = value
+                .read_u32::<NetworkEndian>()
+                .context(ErrorKind::InvalidResponse)?
+                ^ MAGIC_COOKIE;
+
+            IpAddr::V4(Ipv4Addr::from(ip))
+        }
+        FAMILY_IPV6 => {
+            let mut mask = [0u8; 16];
+            NetworkEndian::write_u32(&mut mask[..4], MAGIC_COOKIE);
+            mask[4..].copy_from_slice(transaction_id);
+
+            let mut octets = [0u8; 16];
+            value
+                .read_exact(&mut octets)
+                .context(ErrorKind::InvalidResponse)?;
+
+            for (octet, mask) in octets.iter_mut().zip(mask.iter()) {
+                *octet ^= mask;
+            }
+
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => Err(ErrorKind::InvalidResponse)?,
+    };
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        binding_request,
+        parse_binding_response,
+        ExternalIpDiscovery,
+        TransactionId,
+        BINDING_REQUEST,
+        BINDING_SUCCESS_RESPONSE,
+        MAGIC_COOKIE,
+        XOR_MAPPED_ADDRESS,
+    };
+    use byteorder::{
+        ByteOrder,
+        NetworkEndian,
+        WriteBytesExt,
+    };
+    use failure::Error;
+    use std::{
+        net::{
+            self,
+            IpAddr,
+            SocketAddr,
+        },
+        thread,
+    };
+    use tokio::{
+        net::UdpSocket,
+        runtime::current_thread::Runtime,
+    };
+
+    const TRANSACTION_ID: TransactionId = *b"abcdefghijkl";
+
+    fn attribute(buf: &mut Vec<u8>, attribute_type: u16, value: &[u8]) {
+        buf.write_u16::<NetworkEndian>(attribute_type).unwrap();
+        buf.write_u16::<NetworkEndian>(value.len() as u16).unwrap();
+        buf.extend_from_slice(value);
+        while buf.len() % 4 != 0 {
+            buf.push(0);
+        }
+    }
+
+    fn xor_mapped_address(addr: &SocketAddr, transaction_id: &TransactionId) -> Vec<u8> {
+        let mut value = vec![0u8];
+        let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+        mask.extend_from_slice(transaction_id);
+
+        let octets = match addr.ip() {
+            IpAddr::V4(ip) => {
+                value.push(0x01);
+                ip.octets().to_vec()
+            }
+            IpAddr::V6(ip) => {
+                value.push(0x02);
+                ip.octets().to_vec()
+            }
+        };
+
+        value
+            .write_u16::<NetworkEndian>(addr.port() ^ (MAGIC_COOKIE >> 16) as u16)
+            .unwrap();
+        value.extend(octets.iter().zip(mask.iter()).map(|(a, b)| a ^ b));
+
+        value
+    }
+
+    fn binding_response(transaction_id: &TransactionId, attributes: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (attribute_type, value) in attributes {
+            attribute(&mut body, *attribute_type, value);
+        }
+
+        let mut response = vec![0u8; 20];
+        NetworkEndian::write_u16(&mut response[0..2], BINDING_SUCCESS_RESPONSE);
+        NetworkEndian::write_u16(&mut response[2..4], body.len() as u16);
+        NetworkEndian::write_u32(&mut response[4..8], MAGIC_COOKIE);
+        response[8..20].copy_from_slice(transaction_id);
+        response.extend(body);
+
+        response
+    }
+
+    #[test]
+    fn request_header() {
+        let request = binding_request(&TRANSACTION_ID);
+
+        assert_eq!(NetworkEndian::read_u16(&request[0..2]), BINDING_REQUEST);
+        assert_eq!(NetworkEndian::read_u16(&request[2..4]), 0);
+        assert_eq!(NetworkEndian::read_u32(&request[4..8]), MAGIC_COOKIE);
+        assert_eq!(&request[8..], &TRANSACTION_ID);
+    }
+
+    #[test]
+    fn parse_ipv4() -> Result<(), Error> {
+        let addr: SocketAddr = "203.0.113.7:4242".parse()?;
+        let response = binding_response(
+            &TRANSACTION_ID,
+            &[
+                // SOFTWARE, padded to eight bytes.
+                (0x8022, b"tests".to_vec()),
+                (
+                    XOR_MAPPED_ADDRESS,
+                    xor_mapped_address(&addr, &TRANSACTION_ID),
+                ),
+            ],
+        );
+
+        assert_eq!(
+            parse_binding_response(&TRANSACTION_ID, &response)?,
+            Some(addr)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_ipv6() -> Result<(), Error> {
+        let addr: SocketAddr = "[2001:db8::7]:4242".parse()?;
+        let response = binding_response(
+            &TRANSACTION_ID,
+            &[(
+                XOR_MAPPED_ADDRESS,
+                xor_mapped_address(&addr, &TRANSACTION_ID),
+            )],
+        );
+
+        assert_eq!(
+            parse_binding_response(&TRANSACTION_ID, &response)?,
+            Some(addr)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_other_transactions() -> Result<(), Error> {
+        let addr: SocketAddr = "203.0.113.7:4242".parse()?;
+        let other_id = *b"lkjihgfedcba";
+        let response = binding_response(
+            &other_id,
+            &[(XOR_MAPPED_ADDRESS, xor_mapped_address(&addr, &other_id))],
+        );
+
+        assert_eq!(parse_binding_response(&TRANSACTION_ID, &response)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_mapped_address() {
+        let response = binding_response(&TRANSACTION_ID, &[(0x8022, b"tests".to_vec())]);
+
+        assert!(parse_binding_response(&TRANSACTION_ID, &response).is_err());
+    }
+
+    #[test]
+    fn discover() -> Result<(), Error> {
+        let mapped: SocketAddr = "203.0.113.7:4242".parse()?;
+
+        // Mock STUN server answering a single binding request with `mapped`.
+        let server = net::UdpSocket::bind("127.0.0.1:0")?;
+        let server_addr = server.local_addr()?;
+        let handle = thread::spawn(move || -> Result<(), Error> {
+            let mut buf = [0u8; 576];
+            let (size, from) = server.recv_from(&mut buf)?;
+            assert_eq!(size, 20);
+            assert_eq!(NetworkEndian::read_u16(&buf[0..2]), BINDING_REQUEST);
+
+            let mut transaction_id: TransactionId = [0u8; 12];
+            transaction_id.copy_from_slice(&buf[8..20]);
+
+            let response = binding_response(
+                &transaction_id,
+                &[(
+                    XOR_MAPPED_ADDRESS,
+                    xor_mapped_address(&mapped, &transaction_id),
+                )],
+            );
+            server.send_to(&response, from)?;
+
+            Ok(())
+        });
+
+        let mut rt = Runtime::new()?;
+        let mut socket = UdpSocket::bind(&"127.0.0.1:0".parse()?)?;
+        let discovered = rt.block_on(ExternalIpDiscovery::discover(&mut socket, server_addr))?;
+
+        handle.join().unwrap()?;
+        assert_eq!(discovered, mapped.ip());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,429 @@
+//! Observing raw datagrams sent and received by a [`KRPCNode`]. Useful for
+//! capturing messages from clients whose messages fail to decode and
+//! replaying them later.
+
+use byteorder::{
+    NetworkEndian,
+    ReadBytesExt,
+    WriteBytesExt,
+};
+use krpc_encoding::{
+    errors::Error as DecodeError,
+    Envelope,
+};
+use std::{
+    fs::File,
+    io::{
+        self,
+        BufReader,
+        BufWriter,
+        Read,
+        Write,
+    },
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        Ipv6Addr,
+        SocketAddr,
+    },
+    path::Path,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        mpsc::{
+            self,
+            Receiver,
+            SyncSender,
+        },
+        Arc,
+        Mutex,
+    },
+    thread::{
+        self,
+        JoinHandle,
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Hook installed with [`KRPCNode::on_packet`].
+pub(crate) type PacketTap = Arc<dyn Fn(Direction, &[u8], SocketAddr) + Send + Sync>;
+
+/// A datagram captured by a [`PacketRecorder`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    pub address: SocketAddr,
+    pub bytes: Vec<u8>,
+}
+
+impl Record {
+    /// Writes the record as a timestamp in microseconds since the unix epoch,
+    /// the direction, the address and the length prefixed datagram.
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let micros = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_micros() as u64)
+            .unwrap_or(0);
+        writer.write_u64::<NetworkEndian>(micros)?;
+
+        writer.write_u8(match self.direction {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        })?;
+
+        match self.address.ip() {
+            IpAddr::V4(ip) => {
+                writer.write_u8(4)?;
+                writer.write_all(&ip.octets())?;
+            }
+            IpAddr::V6(ip) => {
+                writer.write_u8(6)?;
+                writer.write_all(&ip.octets())?;
+            }
+        };
+        writer.write_u16::<NetworkEndian>(self.address.port())?;
+
+        writer.write_u32::<NetworkEndian>(self.bytes.len() as u32)?;
+        writer.write_all(&self.bytes)
+    }
+
+    /// Reads the next record. Returns `None` at the end of the file.
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Record>> {
+        let micros = match reader.read_u64::<NetworkEndian>() {
+            Ok(micros) => micros,
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let direction = match reader.read_u8()? {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            _ => return Err(invalid_data("unknown direction")),
+        };
+
+        let ip = match reader.read_u8()? {
+            4 => {
+                let mut octets = [0u8; 4];
+                reader.read_exact(&mut octets)?;
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            6 => {
+                let mut octets = [0u8; 16];
+                reader.read_exact(&mut octets)?;
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return Err(invalid_data("unknown address family")),
+        };
+        let port = reader.read_u16::<NetworkEndian>()?;
+
+        let len = reader.read_u32::<NetworkEndian>()?;
+        let mut bytes = vec![0u8; len as usize];
+        reader.read_exact(&mut bytes)?;
+
+        Ok(Some(Record {
+            timestamp: UNIX_EPOCH + Duration::from_micros(micros),
+            direction,
+            address: SocketAddr::new(ip, port),
+            bytes,
+        }))
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Appends datagrams to a file from a background thread. Datagrams are
+/// dropped instead of waiting when the thread falls behind.
+#[derive(Clone)]
+pub struct PacketRecorder {
+    sender: Arc<Mutex<SyncSender<Record>>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+/// Thread writing records for [`PacketRecorder`]s.
+pub struct RecorderThread {
+    handle: JoinHandle<io::Result<()>>,
+}
+
+impl PacketRecorder {
+    /// Creates or truncates the file at `path` and starts writing to it.
+    /// Up to `capacity` datagrams are buffered.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        capacity: usize,
+    ) -> io::Result<(PacketRecorder, RecorderThread)> {
+        let file = File::create(path)?;
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let handle = thread::spawn(move || write_records(receiver, BufWriter::new(file)));
+
+        let recorder = PacketRecorder {
+            sender: Arc::new(Mutex::new(sender)),
+            dropped: Arc::new(AtomicUsize::new(0)),
+        };
+
+        Ok((recorder, RecorderThread { handle }))
+    }
+
+    /// Queues a datagram to be written. Never blocks.
+    pub fn record(&self, direction: Direction, bytes: &[u8], address: SocketAddr) {
+        let record = Record {
+            timestamp: SystemTime::now(),
+            direction,
+            address,
+            bytes: bytes.to_vec(),
+        };
+
+        let sent = match self.sender.lock() {
+            Ok(sender) => sender.try_send(record).is_ok(),
+            Err(_) => false,
+        };
+
+        if !sent {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of datagrams dropped because the writer fell behind.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Creates a hook for [`KRPCNode::on_packet`] recording every datagram.
+    pub fn tap(&self) -> impl Fn(Direction, &[u8], SocketAddr) + Send + Sync + 'static {
+        let recorder = self.clone();
+
+        move |direction, bytes, address| recorder.record(direction, bytes, address)
+    }
+}
+
+impl RecorderThread {
+    /// Waits until every [`PacketRecorder`] is dropped and the remaining
+    /// records are written.
+    pub fn join(self) -> io::Result<()> {
+        self.handle
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "recorder panicked")))
+    }
+}
+
+fn write_records(receiver: Receiver<Record>, mut writer: BufWriter<File>) -> io::Result<()> {
+    while let Ok(record) = receiver.recv() {
+        record.write_to(&mut writer)?;
+
+        // Flush while idle so the file is mostly up to date.
+        while let Ok(record) = receiver.try_recv() {
+            record.write_to(&mut writer)?;
+        }
+        writer.flush()?;
+    }
+
+    writer.flush()
+}
+
+/// Reads every record from a file written by a [`PacketRecorder`].
+pub fn read_records<P: AsRef<Path>>(path: P) -> io::Result<Vec<Record>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+
+    while let Some(record) = Record::read_from(&mut reader)? {
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+/// Result of decoding recorded inbound datagrams.
+#[derive(Debug)]
+pub struct ReplayReport {
+    /// Number of datagrams decoded successfully.
+    pub decoded: usize,
+
+    /// Datagrams which failed to decode along with the reason.
+    pub failures: Vec<(Record, DecodeError)>,
+}
+
+/// Decodes every inbound datagram in a recording.
+pub fn replay<P: AsRef<Path>>(path: P) -> io::Result<ReplayReport> {
+    let mut report = ReplayReport {
+        decoded: 0,
+        failures: Vec::new(),
+    };
+
+    for record in read_records(path)? {
+        if record.direction != Direction::Inbound {
+            continue;
+        }
+
+        match Envelope::decode(&record.bytes) {
+            Ok(_) => report.decoded += 1,
+            Err(err) => report.failures.push((record, err)),
+        };
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_records,
+        replay,
+        Direction,
+        PacketRecorder,
+    };
+    use crate::KRPCNode;
+    use failure::Error;
+    use futures::{
+        future,
+        StreamExt,
+        TryStreamExt,
+    };
+    use krpc_encoding::{
+        Envelope,
+        Message,
+        NodeID,
+        Response,
+    };
+    use std::{
+        env,
+        net::{
+            self,
+            SocketAddr,
+        },
+        path::PathBuf,
+        process,
+        thread,
+        time::Duration,
+    };
+    use tokio::{
+        net::UdpSocket,
+        prelude::FutureExt,
+        runtime::current_thread::Runtime,
+    };
+
+    fn recording_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("tokio_krpc-{}-{}.rec", name, process::id()))
+    }
+
+    /// Answers a single ping. Returns the query received and the response
+    /// sent.
+    fn answer_ping(peer: net::UdpSocket) -> thread::JoinHandle<(Vec<u8>, Vec<u8>)> {
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (size, from) = peer.recv_from(&mut buf).unwrap();
+            let query = Envelope::decode(&buf[..size]).unwrap();
+
+            let response = Envelope {
+                ip: None,
+                transaction_id: query.transaction_id,
+                version: None,
+                message_type: Message::Response {
+                    response: Response::OnlyID {
+                        id: NodeID::random(),
+                    },
+                },
+                read_only: false,
+            }
+            .encode()
+            .unwrap();
+            peer.send_to(&response, from).unwrap();
+
+            (buf[..size].to_vec(), response)
+        })
+    }
+
+    #[test]
+    fn record_and_replay() -> Result<(), Error> {
+        let path = recording_path("record_and_replay");
+        let (recorder, recorder_thread) = PacketRecorder::create(&path, 16)?;
+
+        let peer = net::UdpSocket::bind("127.0.0.1:0")?;
+        peer.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let peer_addr = peer.local_addr()?;
+        let peer_thread = answer_ping(peer);
+
+        {
+            let bind: SocketAddr = "127.0.0.1:0".parse()?;
+            let node = KRPCNode::new(UdpSocket::bind(&bind)?).on_packet(recorder.tap());
+            let (send_transport, inbound) = node.serve();
+
+            let mut runtime = Runtime::new()?;
+            runtime.spawn(
+                inbound
+                    .map_err(|err| println!("Error in Inbound Requests: {}", err))
+                    .for_each(|_| future::ready(())),
+            );
+
+            runtime.block_on(
+                send_transport
+                    .ping(NodeID::random(), peer_addr)
+                    .timeout(Duration::from_secs(1)),
+            )??;
+        }
+
+        assert_eq!(recorder.dropped(), 0);
+        drop(recorder);
+        recorder_thread.join()?;
+
+        let (query, response) = peer_thread.join().unwrap();
+        let records = read_records(&path)?;
+        assert_eq!(records.len(), 2);
+
+        assert_eq!(records[0].direction, Direction::Outbound);
+        assert_eq!(records[0].address, peer_addr);
+        assert_eq!(records[0].bytes, query);
+
+        assert_eq!(records[1].direction, Direction::Inbound);
+        assert_eq!(records[1].address, peer_addr);
+        assert_eq!(records[1].bytes, response);
+
+        let report = replay(&path)?;
+        assert_eq!(report.decoded, 1);
+        assert!(report.failures.is_empty());
+
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn replay_reports_failures() -> Result<(), Error> {
+        let path = recording_path("replay_reports_failures");
+        let (recorder, recorder_thread) = PacketRecorder::create(&path, 16)?;
+        let from: SocketAddr = "[::1]:6881".parse()?;
+
+        recorder.record(Direction::Inbound, b"d1:y1:qe", from);
+        recorder.record(
+            Direction::Inbound,
+            b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re",
+            from,
+        );
+        drop(recorder);
+        recorder_thread.join()?;
+
+        let report = replay(&path)?;
+        assert_eq!(report.decoded, 1);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].0.bytes, b"d1:y1:qe".to_vec());
+        assert_eq!(report.failures[0].0.address, from);
+
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+//! Events describing transactions and malformed inbound messages, emitted
+//! through `tracing` when the `trace` feature is enabled.
+
+use crate::{
+    send_errors::{
+        ErrorKind,
+        Result,
+    },
+    transaction_id::TransactionId,
+};
+use krpc_encoding::errors::Error as EncodingError;
+use std::{
+    net::SocketAddr,
+    time::Duration,
+};
+use tracing::{
+    debug,
+    warn,
+};
+
+/// Emits an event describing a transaction which finished with `result`
+/// after `latency`. The event belongs to whichever span the query was made
+/// in, like a lookup.
+pub(crate) fn transaction<T>(
+    transaction_id: TransactionId,
+    destination: SocketAddr,
+    query: &'static str,
+    latency: Duration,
+    result: &Result<T>,
+) {
+    let outcome = match result {
+        Ok(_) => "response",
+        Err(err) => match err.kind() {
+            ErrorKind::TransactionTimeout { .. } => "timeout",
+            _ => "error",
+        },
+    };
+    let error = result
+        .as_ref()
+        .err()
+        .map(ToString::to_string)
+        .unwrap_or_default();
+    let transaction_id = format!("{:08x}", transaction_id);
+
+    debug!(
+        transaction_id = transaction_id.as_str(),
+        destination = %destination,
+        query = query,
+        outcome = outcome,
+        latency_ms = latency.as_millis() as u64,
+        error = error.as_str(),
+        "transaction finished"
+    );
+}
+
+/// Emits a warning about a `len` byte message from `from` which couldn't be
+/// decoded.
+pub(crate) fn decode_error(from: SocketAddr, len: usize, error: &EncodingError) {
+    warn!(
+        from = %from,
+        bytes = len as u64,
+        error = %error,
+        "failed to decode KRPC message"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::KRPCNode;
+    use failure::Error;
+    use futures::{
+        StreamExt,
+        TryStreamExt,
+    };
+    use std::{
+        collections::HashMap,
+        fmt,
+        net::{
+            self,
+            SocketAddr,
+        },
+        sync::{
+            Arc,
+            Mutex,
+        },
+        time::Duration,
+    };
+    use tokio::{
+        net::UdpSocket,
+        prelude::FutureExt,
+        runtime::current_thread::Runtime,
+    };
+    use tracing::{
+        field::{
+            Field,
+            Visit,
+        },
+        span,
+        Event,
+        Level,
+        Metadata,
+        Subscriber,
+    };
+
+    /// Fields of an event, formatted.
+    #[derive(Default)]
+    struct Fields(HashMap<String, String>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    /// Subscriber which keeps the fields of every warning.
+    #[derive(Clone, Default)]
+    struct CaptureWarnings {
+        warnings: Arc<Mutex<Vec<Fields>>>,
+    }
+
+    impl Subscriber for CaptureWarnings {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            if *event.metadata().level() == Level::WARN {
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                self.warnings.lock().unwrap().push(fields);
+            }
+        }
+
+        fn enter(&self, _span: &span::Id) {}
+
+        fn exit(&self, _span: &span::Id) {}
+    }
+
+    #[test]
+    fn decode_error_warned() -> Result<(), Error> {
+        let capture = CaptureWarnings::default();
+        let warnings = capture.warnings.clone();
+
+        let peer = tracing::subscriber::with_default(capture, || -> Result<SocketAddr, Error> {
+            let bind: SocketAddr = "127.0.0.1:0".parse()?;
+            let socket = UdpSocket::bind(&bind)?;
+            let local_addr = socket.local_addr()?;
+            let (_send_transport, inbound) = KRPCNode::new(socket).serve();
+
+            let peer = net::UdpSocket::bind("127.0.0.1:0")?;
+            peer.send_to(b"d1:y1:qe", local_addr)?;
+
+            let mut runtime = Runtime::new()?;
+            let mut inbound = Box::pin(inbound.into_stream());
+            runtime.block_on(inbound.next().timeout(Duration::from_secs(1)))?;
+
+            Ok(peer.local_addr()?)
+        })?;
+
+        let warnings = warnings.lock().unwrap();
+        assert_eq!(warnings.len(), 1);
+        let Fields(fields) = &warnings[0];
+        assert_eq!(fields["message"], "failed to decode KRPC message");
+        assert_eq!(fields["from"], peer.to_string());
+        assert_eq!(fields["bytes"], "8");
+        assert!(fields.contains_key("error"));
+
+        Ok(())
+    }
+}
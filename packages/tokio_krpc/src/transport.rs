@@ -0,0 +1,181 @@
+use crate::{
+    responses::{
+        FindNodeResponse,
+        GetPeersResponse,
+        SampleInfoHashesResponse,
+    },
+    send_errors::Result,
+    PortType,
+    QueryOptions,
+    SendTransport,
+};
+use futures::future::{
+    BoxFuture,
+    FutureExt,
+};
+use krpc_encoding::{
+    Envelope,
+    NodeID,
+};
+use std::net::SocketAddr;
+
+/// Queries other nodes. Implemented by [`SendTransport`] for nodes reached
+/// over UDP. Other implementations let code built on top of a transport run
+/// over something else, like a simulated network.
+pub trait Transport: Send + Sync {
+    fn ping(&self, id: NodeID, address: SocketAddr) -> BoxFuture<'_, Result<NodeID>>;
+
+    /// Like [`Transport::ping`] with options for this query only, like the
+    /// id the node is known by. Transports which can't honor the options
+    /// ignore them.
+    fn ping_with_options(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        _options: QueryOptions,
+    ) -> BoxFuture<'_, Result<NodeID>> {
+        self.ping(id, address)
+    }
+
+    fn find_node(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+    ) -> BoxFuture<'_, Result<FindNodeResponse>>;
+
+    /// Like [`Transport::find_node`] with options for this query only, see
+    /// [`Transport::ping_with_options`].
+    fn find_node_with_options(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+        _options: QueryOptions,
+    ) -> BoxFuture<'_, Result<FindNodeResponse>> {
+        self.find_node(id, address, target)
+    }
+
+    fn get_peers(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        info_hash: NodeID,
+    ) -> BoxFuture<'_, Result<GetPeersResponse>>;
+
+    /// Like [`Transport::get_peers`] with options for this query only, see
+    /// [`Transport::ping_with_options`].
+    fn get_peers_with_options(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        info_hash: NodeID,
+        _options: QueryOptions,
+    ) -> BoxFuture<'_, Result<GetPeersResponse>> {
+        self.get_peers(id, address, info_hash)
+    }
+
+    fn announce_peer(
+        &self,
+        id: NodeID,
+        token: Vec<u8>,
+        address: SocketAddr,
+        info_hash: NodeID,
+        port_type: PortType,
+    ) -> BoxFuture<'_, Result<NodeID>>;
+
+    fn sample_infohashes(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+    ) -> BoxFuture<'_, Result<SampleInfoHashesResponse>>;
+
+    /// Sends `message` to `address` without waiting for a response, like a
+    /// response to an inbound query.
+    fn send(&self, address: SocketAddr, message: Envelope) -> BoxFuture<'_, Result<()>>;
+
+    /// Fails every query in flight and every later one.
+    fn shutdown(&self);
+}
+
+impl Transport for SendTransport {
+    fn ping(&self, id: NodeID, address: SocketAddr) -> BoxFuture<'_, Result<NodeID>> {
+        SendTransport::ping(self, id, address).boxed()
+    }
+
+    fn ping_with_options(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        options: QueryOptions,
+    ) -> BoxFuture<'_, Result<NodeID>> {
+        SendTransport::ping_with_options(self, id, address, options).boxed()
+    }
+
+    fn find_node(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+    ) -> BoxFuture<'_, Result<FindNodeResponse>> {
+        SendTransport::find_node(self, id, address, target).boxed()
+    }
+
+    fn find_node_with_options(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+        options: QueryOptions,
+    ) -> BoxFuture<'_, Result<FindNodeResponse>> {
+        SendTransport::find_node_with_options(self, id, address, target, options).boxed()
+    }
+
+    fn get_peers(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        info_hash: NodeID,
+    ) -> BoxFuture<'_, Result<GetPeersResponse>> {
+        SendTransport::get_peers(self, id, address, info_hash).boxed()
+    }
+
+    fn get_peers_with_options(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        info_hash: NodeID,
+        options: QueryOptions,
+    ) -> BoxFuture<'_, Result<GetPeersResponse>> {
+        SendTransport::get_peers_with_options(self, id, address, info_hash, options).boxed()
+    }
+
+    fn announce_peer(
+        &self,
+        id: NodeID,
+        token: Vec<u8>,
+        address: SocketAddr,
+        info_hash: NodeID,
+        port_type: PortType,
+    ) -> BoxFuture<'_, Result<NodeID>> {
+        SendTransport::announce_peer(self, id, token, address, info_hash, port_type).boxed()
+    }
+
+    fn sample_infohashes(
+        &self,
+        id: NodeID,
+        address: SocketAddr,
+        target: NodeID,
+    ) -> BoxFuture<'_, Result<SampleInfoHashesResponse>> {
+        SendTransport::sample_infohashes(self, id, address, target).boxed()
+    }
+
+    fn send(&self, address: SocketAddr, message: Envelope) -> BoxFuture<'_, Result<()>> {
+        SendTransport::send(self, address, message).boxed()
+    }
+
+    fn shutdown(&self) {
+        SendTransport::shutdown(self)
+    }
+}